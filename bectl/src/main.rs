@@ -87,8 +87,10 @@ enum DbMode {
     Init,
     ListDatasets,
     Space,
+    SpaceReport,
     DumpSuperblock,
     ListRoot,
+    Upgrade,
 }
 
 #[derive(StructOpt)]
@@ -133,6 +135,12 @@ enum ObjMode {
         #[structopt(subcommand)]
         mode: ObjMetaMode,
     },
+    /// Check the object metadata against the stored chunks
+    Fsck {
+        /// Delete orphaned chunks and truncate inconsistent objects
+        #[structopt(long)]
+        repair: bool,
+    },
 }
 
 #[derive(StructOpt)]
@@ -192,7 +200,9 @@ fn bectl_main() -> Result<(), Error> {
         },
 
         Mode::Db { mode } => match mode {
-            DbMode::Init => Database::create(cfg.storage)?.sync()?,
+            DbMode::Init => {
+                Database::create(cfg.storage)?.sync()?;
+            }
 
             DbMode::ListDatasets => {
                 let db = open_db(cfg)?;
@@ -211,12 +221,32 @@ fn bectl_main() -> Result<(), Error> {
                 println!("{:?}", space);
             }
 
+            DbMode::SpaceReport => {
+                let db = open_db(cfg)?;
+                let stdout = io::stdout();
+                let mut stdout_lock = stdout.lock();
+
+                let _ = serde_json::to_writer_pretty(&mut stdout_lock, &db.space_report()?);
+            }
+
             DbMode::DumpSuperblock => {
                 let spu = cfg.new_spu()?;
                 let superblock = Superblock::fetch_superblocks(&spu);
                 println!("{:#?}", superblock);
             }
 
+            DbMode::Upgrade => {
+                let mut db = open_db(cfg)?;
+                let old_version = db.format_version();
+                let rewritten = db.upgrade()?;
+                println!(
+                    "Upgraded format version {} to {}, rewrote {} nodes",
+                    old_version,
+                    db.format_version(),
+                    rewritten
+                );
+            }
+
             DbMode::ListRoot => {
                 let db = open_db(cfg)?;
                 let root = db.root_tree();
@@ -294,12 +324,16 @@ fn bectl_main() -> Result<(), Error> {
 
                 for (obj, info) in os.list_objects::<_, &[u8]>(..)? {
                     let mtime = DateTime::<Utc>::from(info.mtime);
-                    println!(
-                        "{} ({} bytes, modified {})",
+                    print!(
+                        "{} ({} bytes, modified {}",
                         PseudoAscii(obj.object.key()),
                         info.size,
                         mtime.to_rfc3339()
                     );
+                    if let Some(atime) = info.atime {
+                        print!(", accessed {}", DateTime::<Utc>::from(atime).to_rfc3339());
+                    }
+                    println!(")");
 
                     if with_custom {
                         for (k, v) in obj.iter_metadata()?.flatten() {
@@ -393,6 +427,27 @@ fn bectl_main() -> Result<(), Error> {
 
                 db.sync()?;
             }
+
+            ObjMode::Fsck { repair } => {
+                let mut db = open_db(cfg)?;
+                let os = db.open_named_object_store(namespace.as_bytes(), storage_preference.0)?;
+
+                let report = os.fsck(repair)?;
+                for problem in report.problems.iter() {
+                    println!("{problem}");
+                }
+                println!(
+                    "checked {} objects with {} chunks, {} problems{}",
+                    report.objects,
+                    report.chunks,
+                    report.problems.len(),
+                    if report.repaired { " repaired" } else { "" }
+                );
+
+                if repair {
+                    db.sync()?;
+                }
+            }
         },
     }
 
@@ -1,14 +1,30 @@
 use betree_storage_stack::{
-    cache::{Cache, ClockCache},
+    cache::{Cache, Classify, ClockCache, EntryKind},
+    size::{Size, StaticSize},
     storage_pool::DiskOffset,
     vdev::Block,
 };
 use criterion::{black_box, criterion_group, criterion_main, Bencher, Criterion};
 
+/// A cache value which stands in for a leaf node.
+struct Value(DiskOffset);
+
+impl Size for Value {
+    fn size(&self) -> usize {
+        DiskOffset::static_size()
+    }
+}
+
+impl Classify for Value {
+    fn entry_kind(&mut self) -> EntryKind {
+        EntryKind::Leaf
+    }
+}
+
 fn get_and_pin(b: &mut Bencher) {
     let five = DiskOffset::new(0, 0, Block(5));
     let mut c = ClockCache::new(5);
-    c.insert(five, five, 1);
+    c.insert(five, Value(five), DiskOffset::static_size());
     b.iter(|| {
         black_box(c.get(&five, true));
     });
@@ -50,6 +50,10 @@ impl Control {
             enabled: true,
             interval_ms: 500,
             output_path: PathBuf::from("betree-metrics.jsonl"),
+            format: metrics::MetricsFormat::Jsonl,
+            rotate_size: None,
+            rotated_files: 0,
+            sink: None,
         });
 
         modify_cfg(&mut cfg);
@@ -124,7 +128,8 @@ pub struct Client {
 
 impl Client {
     pub fn sync(&self) -> database::Result<()> {
-        self.database.write().sync()
+        self.database.write().sync()?;
+        Ok(())
     }
 }
 
@@ -29,11 +29,22 @@ impl SegmentAllocator {
     /// Allocates a block of the given `size`.
     /// Returns `None` if the allocation request cannot be satisfied.
     pub fn allocate(&mut self, size: u32) -> Option<u32> {
+        self.allocate_from(size, 0)
+    }
+
+    /// Allocates a block of the given `size` at or after `hint`, so that
+    /// related blocks are placed close to each other.
+    /// Returns `None` if there is no free range of `size` after `hint`.
+    pub fn allocate_near(&mut self, size: u32, hint: u32) -> Option<u32> {
+        self.allocate_from(size, hint)
+    }
+
+    fn allocate_from(&mut self, size: u32, start: u32) -> Option<u32> {
         if size == 0 {
-            return Some(0);
+            return Some(start);
         }
         let offset = {
-            let mut idx = 0;
+            let mut idx = start;
             loop {
                 loop {
                     if idx + size > SEGMENT_SIZE as u32 {
@@ -87,6 +98,23 @@ impl SegmentAllocator {
         self.mark(offset, size, Action::Deallocate);
     }
 
+    /// Returns the sizes of all runs of free blocks within the first `len`
+    /// blocks of the segment.
+    pub fn free_extents(&self, len: usize) -> Vec<u32> {
+        let bits = &self.data[..len.min(SEGMENT_SIZE)];
+        let mut extents = Vec::new();
+        let mut idx = 0;
+        while let Some(start) = bits[idx..].first_zero() {
+            let start = idx + start;
+            let end = bits[start..]
+                .first_one()
+                .map_or(bits.len(), |size| start + size);
+            extents.push((end - start) as u32);
+            idx = end;
+        }
+        extents
+    }
+
     fn mark(&mut self, offset: u32, size: u32, action: Action) {
         let start_idx = offset as usize;
         let end_idx = (offset + size) as usize;
@@ -200,4 +228,27 @@ mod tests {
         );
         assert_eq!(SegmentId::get_block_offset(offset), 1);
     }
+
+    #[test]
+    fn free_extents() {
+        let mut allocator = SegmentAllocator::new([0; SEGMENT_SIZE_BYTES]);
+        assert_eq!(
+            allocator.free_extents(SEGMENT_SIZE),
+            vec![SEGMENT_SIZE as u32]
+        );
+        assert!(allocator.allocate_at(4, 0));
+        assert!(allocator.allocate_at(2, 10));
+        assert_eq!(allocator.free_extents(20), vec![6, 8]);
+        assert_eq!(allocator.free_extents(12), vec![6]);
+    }
+
+    #[test]
+    fn allocate_near() {
+        let mut allocator = SegmentAllocator::new([0; SEGMENT_SIZE_BYTES]);
+        assert!(allocator.allocate_at(4, 100));
+        assert_eq!(allocator.allocate_near(2, 100), Some(104));
+        assert_eq!(allocator.allocate_near(2, 90), Some(90));
+        assert_eq!(allocator.allocate(2), Some(0));
+        assert_eq!(allocator.allocate_near(2, SEGMENT_SIZE as u32 - 1), None);
+    }
 }
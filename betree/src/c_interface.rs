@@ -62,18 +62,19 @@ pub struct byte_slice_t {
 
 impl From<CowBytes> for byte_slice_t {
     fn from(x: CowBytes) -> Self {
-        let ptr = &x[..] as *const [u8] as *const u8 as *const c_char;
-        let len = x.len() as c_uint;
-        let arc = Arc::into_raw(x.inner) as *const byte_slice_rc_t;
-        byte_slice_t { ptr, len, arc }
+        SlicedCowBytes::from(x).into()
     }
 }
 
 impl From<SlicedCowBytes> for byte_slice_t {
     fn from(x: SlicedCowBytes) -> Self {
-        let ptr = &x[..] as *const [u8] as *const u8 as *const c_char;
-        let len = x.len() as c_uint;
-        let arc = Arc::into_raw(x.data.inner) as *const byte_slice_rc_t;
+        // Inline data has to be moved to the heap, as the pointer has to stay
+        // valid after `x` is gone.
+        let (data, pos, len) = x.into_raw_parts();
+        let data = data.into_arc();
+        let ptr = data[pos as usize..].as_ptr() as *const c_char;
+        let len = len as c_uint;
+        let arc = Arc::into_raw(data) as *const byte_slice_rc_t;
         byte_slice_t { ptr, len, arc }
     }
 }
@@ -363,6 +364,7 @@ pub unsafe extern "C" fn betree_configuration_set_disks(
             Vdev::Leaf(LeafVdev::FileWithOpts {
                 path: p.to_str().unwrap().into(),
                 direct: Some(false),
+                failure_domain: None,
             })
         })
         .collect();
@@ -428,7 +430,7 @@ pub unsafe extern "C" fn betree_open_or_create_db(
 #[no_mangle]
 pub unsafe extern "C" fn betree_sync_db(db: *mut db_t, err: *mut *mut err_t) -> c_int {
     let db = &mut (*db).0;
-    db.sync().handle_result(err)
+    db.sync().map(drop).handle_result(err)
 }
 
 /// Closes a database.
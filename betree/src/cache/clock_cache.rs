@@ -6,11 +6,14 @@
 //! as CLOCK does not have to move the cache entry to the MRU position like LRU
 //! does.
 
-use super::{clock::Clock, AddSize, Cache, ChangeKeyError, RemoveError, Stats};
-use crate::size::SizeMut;
+use super::{
+    clock::Clock, AddSize, Cache, ChangeKeyError, Classify, EntryKind, EvictionReason, RemoveError,
+    Stats,
+};
+use crate::{database::DatasetId, size::SizeMut};
 use stable_deref_trait::StableDeref;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt,
     hash::Hash,
     ops::Deref,
@@ -32,11 +35,21 @@ pub struct ClockCache<K, V> {
     insertions: u64,
     evictions: u64,
     removals: u64,
+    hits_by_kind: [AtomicU64; NUM_ENTRY_KINDS],
+    misses_by_kind: [u64; NUM_ENTRY_KINDS],
+    evictions_by_reason: [u64; NUM_EVICTION_REASONS],
 }
 
+const NUM_ENTRY_KINDS: usize = 2;
+const NUM_EVICTION_REASONS: usize = 4;
+
 struct CacheEntry<V> {
     value: V,
     referenced: AtomicBool,
+    kind: EntryKind,
+    dataset: Option<DatasetId>,
+    // Tracked separately from the global size to account bytes per dataset.
+    size: AtomicUsize,
 }
 
 /// Pinned cache entry
@@ -56,7 +69,10 @@ impl<V> Deref for PinnedEntry<V> {
 unsafe impl<V> StableDeref for PinnedEntry<V> {}
 
 /// Cache statistics
-#[derive(Debug, Clone, Copy, serde::Serialize)]
+///
+/// The serialized representation is meant to be consumed by external tools,
+/// fields are only ever added to it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct CacheStats {
     capacity: usize,
     size: usize,
@@ -66,6 +82,78 @@ pub struct CacheStats {
     insertions: u64,
     evictions: u64,
     removals: u64,
+    #[serde(default)]
+    hits_by_kind: KindStats,
+    #[serde(default)]
+    misses_by_kind: KindStats,
+    #[serde(default)]
+    evictions_by_reason: EvictionStats,
+    #[serde(default)]
+    bytes_by_dataset: BTreeMap<DatasetId, usize>,
+}
+
+/// Counters per [EntryKind].
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+pub struct KindStats {
+    leaf: u64,
+    internal: u64,
+}
+
+impl KindStats {
+    fn new(counters: [u64; NUM_ENTRY_KINDS]) -> Self {
+        KindStats {
+            leaf: counters[EntryKind::Leaf as usize],
+            internal: counters[EntryKind::Internal as usize],
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.leaf += other.leaf;
+        self.internal += other.internal;
+    }
+
+    fn get(&self, kind: EntryKind) -> u64 {
+        match kind {
+            EntryKind::Leaf => self.leaf,
+            EntryKind::Internal => self.internal,
+        }
+    }
+}
+
+/// Counters per [EvictionReason].
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+pub struct EvictionStats {
+    capacity: u64,
+    write_back: u64,
+    freed: u64,
+    dropped: u64,
+}
+
+impl EvictionStats {
+    fn new(counters: [u64; NUM_EVICTION_REASONS]) -> Self {
+        EvictionStats {
+            capacity: counters[EvictionReason::Capacity as usize],
+            write_back: counters[EvictionReason::WriteBack as usize],
+            freed: counters[EvictionReason::Freed as usize],
+            dropped: counters[EvictionReason::Dropped as usize],
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.capacity += other.capacity;
+        self.write_back += other.write_back;
+        self.freed += other.freed;
+        self.dropped += other.dropped;
+    }
+
+    fn get(&self, reason: EvictionReason) -> u64 {
+        match reason {
+            EvictionReason::Capacity => self.capacity,
+            EvictionReason::WriteBack => self.write_back,
+            EvictionReason::Freed => self.freed,
+            EvictionReason::Dropped => self.dropped,
+        }
+    }
 }
 
 impl fmt::Display for CacheStats {
@@ -83,9 +171,17 @@ Average entry size: {avg_e:.2}
   Hits: {h:>8} ({h_p:>6.2}%)
 Misses: {m:>8} ({m_p:>6.2}%)
 
+Leaf hits/misses: {l_h:>8}/{l_m:<8}
+Internal hits/misses: {in_h:>8}/{in_m:<8}
+
 Insertions: {i:>8}
  Evictions: {e:>8}
-  Removals: {r:>8}",
+  Removals: {r:>8}
+
+Evicted for capacity: {e_c:>8}
+  Removed written back: {e_w:>8}
+         Removed freed: {e_f:>8}
+       Removed dropped: {e_d:>8}",
             s = self.size,
             c = self.capacity,
             s_p = 100.0 * self.size as f32 / self.capacity as f32,
@@ -95,9 +191,17 @@ Insertions: {i:>8}
             h_p = 100.0 * self.hits as f32 / total as f32,
             m = self.misses,
             m_p = 100.0 * self.misses as f32 / total as f32,
+            l_h = self.hits_by_kind.leaf,
+            l_m = self.misses_by_kind.leaf,
+            in_h = self.hits_by_kind.internal,
+            in_m = self.misses_by_kind.internal,
             i = self.insertions,
             e = self.evictions,
-            r = self.removals
+            r = self.removals,
+            e_c = self.evictions_by_reason.capacity,
+            e_w = self.evictions_by_reason.write_back,
+            e_f = self.evictions_by_reason.freed,
+            e_d = self.evictions_by_reason.dropped,
         )
     }
 }
@@ -134,14 +238,53 @@ impl Stats for CacheStats {
     fn removals(&self) -> u64 {
         self.removals
     }
+
+    fn hits_by_kind(&self, kind: EntryKind) -> u64 {
+        self.hits_by_kind.get(kind)
+    }
+
+    fn misses_by_kind(&self, kind: EntryKind) -> u64 {
+        self.misses_by_kind.get(kind)
+    }
+
+    fn evictions_by_reason(&self, reason: EvictionReason) -> u64 {
+        self.evictions_by_reason.get(reason)
+    }
+
+    fn bytes_by_dataset(&self) -> &BTreeMap<DatasetId, usize> {
+        &self.bytes_by_dataset
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.capacity += other.capacity;
+        self.size += other.size;
+        self.len += other.len;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.insertions += other.insertions;
+        self.evictions += other.evictions;
+        self.removals += other.removals;
+        self.hits_by_kind.merge(&other.hits_by_kind);
+        self.misses_by_kind.merge(&other.misses_by_kind);
+        self.evictions_by_reason.merge(&other.evictions_by_reason);
+        for (&dataset, &bytes) in &other.bytes_by_dataset {
+            *self.bytes_by_dataset.entry(dataset).or_insert(0) += bytes;
+        }
+    }
 }
 
 impl<V> AddSize for PinnedEntry<V> {
     fn add_size(&self, size_delta: isize) {
         if size_delta >= 0 {
             self.size.fetch_add(size_delta as usize, Ordering::Relaxed);
+            self.entry
+                .size
+                .fetch_add(size_delta as usize, Ordering::Relaxed);
         } else {
             self.size.fetch_sub(-size_delta as usize, Ordering::Relaxed);
+            self.entry
+                .size
+                .fetch_sub(-size_delta as usize, Ordering::Relaxed);
         }
     }
 }
@@ -159,12 +302,44 @@ impl<K: Hash + Eq, V: SizeMut> ClockCache<K, V> {
             insertions: 0,
             evictions: 0,
             removals: 0,
+            hits_by_kind: Default::default(),
+            misses_by_kind: [0; NUM_ENTRY_KINDS],
+            evictions_by_reason: [0; NUM_EVICTION_REASONS],
         }
     }
+
+    /// Inserts a new entry and returns its kind.
+    fn insert_entry(&mut self, key: K, mut value: V, size: usize) -> EntryKind
+    where
+        K: Clone,
+        V: Classify,
+    {
+        debug_assert_eq!(value.size(), size);
+
+        let kind = value.entry_kind();
+        let dataset = value.dataset();
+        let old_value = self.map.insert(
+            key.clone(),
+            Arc::new(CacheEntry {
+                value,
+                referenced: AtomicBool::new(false),
+                kind,
+                dataset,
+                size: AtomicUsize::new(size),
+            }),
+        );
+        assert!(old_value.is_none());
+        self.clock.push_back(key);
+        self.insertions += 1;
+        self.size.fetch_add(size, Ordering::Relaxed);
+        kind
+    }
 }
 
-impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 'static> Cache
-    for ClockCache<K, V>
+impl<
+        K: Clone + Eq + Hash + Sync + Send + 'static,
+        V: Sync + Send + SizeMut + Classify + 'static,
+    > Cache for ClockCache<K, V>
 {
     type Key = K;
     type Value = V;
@@ -179,9 +354,20 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         self.map.contains_key(key)
     }
 
+    fn dataset(&self, key: &K) -> Option<DatasetId> {
+        self.map.get(key).and_then(|entry| entry.dataset)
+    }
+
+    fn size_of(&self, key: &K) -> Option<usize> {
+        self.map
+            .get(key)
+            .map(|entry| entry.size.load(Ordering::Relaxed))
+    }
+
     fn get(&self, key: &K, count_miss: bool) -> Option<Self::ValueRef> {
         if let Some(entry) = self.map.get(key).cloned() {
             self.hits.fetch_add(1, Ordering::Relaxed);
+            self.hits_by_kind[entry.kind as usize].fetch_add(1, Ordering::Relaxed);
             entry.referenced.store(true, Ordering::Relaxed);
             Some(PinnedEntry {
                 size: self.size,
@@ -195,7 +381,14 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         }
     }
 
-    fn remove<F>(&mut self, key: &K, f: F) -> Result<V, RemoveError>
+    fn peek(&self, key: &K) -> Option<Self::ValueRef> {
+        self.map.get(key).cloned().map(|entry| PinnedEntry {
+            size: self.size,
+            entry,
+        })
+    }
+
+    fn remove<F>(&mut self, key: &K, reason: EvictionReason, f: F) -> Result<V, RemoveError>
     where
         F: FnOnce(&mut V) -> usize,
     {
@@ -209,18 +402,20 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         let mut value = Arc::try_unwrap(entry).ok().unwrap().value;
         let size = f(&mut value);
         self.removals += 1;
+        self.evictions_by_reason[reason as usize] += 1;
         self.size.fetch_sub(size, Ordering::Relaxed);
         self.verify();
         Ok(value)
     }
 
-    fn force_remove(&mut self, key: &Self::Key, size: usize) -> bool {
+    fn force_remove(&mut self, key: &Self::Key, size: usize, reason: EvictionReason) -> bool {
         self.verify();
         self.clock.retain(|entry| entry != key);
         if self.map.remove(key).is_none() {
             return false;
         }
         self.removals += 1;
+        self.evictions_by_reason[reason as usize] += 1;
         self.size.fetch_sub(size, Ordering::Relaxed);
         self.verify();
         true
@@ -260,6 +455,26 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         true
     }
 
+    fn force_move(&mut self, key: &K, other: &mut Self, new_key: K) -> bool {
+        self.verify();
+        let entry = match self.map.remove(key) {
+            None => return false,
+            Some(entry) => entry,
+        };
+        self.clock.retain(|entry| entry != key);
+        // Pins taken before keep accounting to this cache, they may only
+        // read the entry.
+        let size = entry.size.load(Ordering::Relaxed);
+        self.size.fetch_sub(size, Ordering::Relaxed);
+        other.size.fetch_add(size, Ordering::Relaxed);
+        let old_value = other.map.insert(new_key.clone(), entry);
+        assert!(old_value.is_none());
+        other.clock.push_back(new_key);
+        self.verify();
+        other.verify();
+        true
+    }
+
     fn evict<F>(&mut self, mut f: F) -> Option<(K, V)>
     where
         F: FnMut(&K, &mut V, &dyn Fn(&K) -> bool) -> Option<usize>,
@@ -311,6 +526,7 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
                 }
 
                 self.evictions += 1;
+                self.evictions_by_reason[EvictionReason::Capacity as usize] += 1;
                 self.size.fetch_sub(size, Ordering::Relaxed);
                 let value = Arc::try_unwrap(entry).ok().unwrap().value;
                 break Some((key, value));
@@ -328,23 +544,22 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         ret
     }
 
-    fn insert(&mut self, key: K, mut value: V, size: usize) {
-        debug_assert_eq!(value.size(), size);
+    fn insert(&mut self, key: K, value: V, size: usize) {
+        self.insert_entry(key, value, size);
+    }
 
-        let old_value = self.map.insert(
-            key.clone(),
-            Arc::new(CacheEntry {
-                value,
-                referenced: AtomicBool::new(false),
-            }),
-        );
-        assert!(old_value.is_none());
-        self.clock.push_back(key);
-        self.insertions += 1;
-        self.size.fetch_add(size, Ordering::Relaxed);
+    fn insert_fetched(&mut self, key: K, value: V, size: usize) {
+        let kind = self.insert_entry(key, value, size);
+        self.misses_by_kind[kind as usize] += 1;
     }
 
     fn stats(&self) -> Self::Stats {
+        let mut bytes_by_dataset = BTreeMap::new();
+        for entry in self.map.values() {
+            if let Some(dataset) = entry.dataset {
+                *bytes_by_dataset.entry(dataset).or_insert(0) += entry.size.load(Ordering::Relaxed);
+            }
+        }
         CacheStats {
             capacity: self.capacity,
             size: self.size.load(Ordering::Relaxed),
@@ -354,6 +569,12 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
             insertions: self.insertions,
             evictions: self.evictions,
             removals: self.removals,
+            hits_by_kind: KindStats::new(std::array::from_fn(|idx| {
+                self.hits_by_kind[idx].load(Ordering::Relaxed)
+            })),
+            misses_by_kind: KindStats::new(self.misses_by_kind),
+            evictions_by_reason: EvictionStats::new(self.evictions_by_reason),
+            bytes_by_dataset,
         }
     }
 
@@ -369,6 +590,10 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         self.capacity
     }
 
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
     // This is wildly unsafe, because it was hacked on top of a cache design which assumed interior
     // mutability, but it's only a debugging feature to locate faulty size adjustments, and if you
     // only run it without optimisations, the nasal demons might leave you alone.
@@ -396,7 +621,7 @@ impl<K: Clone + Eq + Hash + Sync + Send + 'static, V: Sync + Send + SizeMut + 's
         }
     }
 
-    #[cfg(not(feature = "cacha-paranoia"))]
+    #[cfg(not(feature = "cache-paranoia"))]
     #[inline(always)]
     fn verify(&mut self) {}
 }
@@ -1,7 +1,10 @@
 //! This module provides a cache interface and a CLOCK cache implementation.
 
+use crate::database::DatasetId;
+use serde::{Deserialize, Serialize};
 use stable_deref_trait::StableDeref;
 use std::{
+    collections::BTreeMap,
     fmt::{Debug, Display},
     hash::Hash,
 };
@@ -32,6 +35,44 @@ pub enum RemoveError {
     Pinned,
 }
 
+/// Kind of a cache entry, used to break down cache statistics.
+///
+/// Message buffers are part of their internal node and are accounted as such.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    /// A leaf node, packed or unpacked.
+    Leaf,
+    /// An internal node including its message buffers.
+    Internal,
+}
+
+/// Reason for which an entry left the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionReason {
+    /// The entry was chosen by the replacement strategy to make room.
+    Capacity,
+    /// The entry was removed after it has been written back. This follows an
+    /// eviction of a modified entry for capacity reasons.
+    WriteBack,
+    /// The object has been freed.
+    Freed,
+    /// The cache has been dropped explicitly.
+    Dropped,
+}
+
+/// A cache value which can be classified to break down cache statistics.
+pub trait Classify {
+    /// Returns the kind of this entry.
+    fn entry_kind(&mut self) -> EntryKind;
+
+    /// Returns the dataset this entry belongs to, if any.
+    fn dataset(&self) -> Option<DatasetId> {
+        None
+    }
+}
+
 /// Cache that supports
 ///
 /// - pinned entries (short-lived only)
@@ -53,21 +94,37 @@ pub trait Cache: Send + Sync {
     /// Returns whether a cache entry is present.
     fn contains_key(&self, key: &Self::Key) -> bool;
 
+    /// Returns the dataset of a cache entry, without counting an access.
+    fn dataset(&self, key: &Self::Key) -> Option<DatasetId>;
+
+    /// Returns the size of a cache entry in bytes, without counting an
+    /// access.
+    fn size_of(&self, key: &Self::Key) -> Option<usize>;
+
     /// Returns a cache entry if present.
     /// The cache entry will be pinned while the return value is in scope.
     /// See `Self::ValueRef` for more information.
     fn get(&self, key: &Self::Key, count_miss: bool) -> Option<Self::ValueRef>;
 
+    /// Returns a cache entry like [Cache::get], but without counting an
+    /// access, so that inspecting the cache does not affect eviction.
+    fn peek(&self, key: &Self::Key) -> Option<Self::ValueRef>;
+
     /// Removes a cache entry if present and not pinned.
     /// `f` shall return the size of the cache entry in bytes.
-    fn remove<F>(&mut self, key: &Self::Key, f: F) -> Result<Self::Value, RemoveError>
+    fn remove<F>(
+        &mut self,
+        key: &Self::Key,
+        reason: EvictionReason,
+        f: F,
+    ) -> Result<Self::Value, RemoveError>
     where
         F: FnOnce(&mut Self::Value) -> usize;
 
     /// Removes a cache entry.
     /// Will forcefully remove entry if pinned.
     /// Returns true iff cache entry was present.
-    fn force_remove(&mut self, key: &Self::Key, size: usize) -> bool;
+    fn force_remove(&mut self, key: &Self::Key, size: usize, reason: EvictionReason) -> bool;
 
     /// Changes the key of a cache entry if present and not pinned.
     /// `f` provides the new key.
@@ -85,6 +142,11 @@ pub trait Cache: Send + Sync {
     /// Returns whether the key was present.
     fn force_change_key(&mut self, key: &Self::Key, new_key: Self::Key) -> bool;
 
+    /// Moves a cache entry to `other` under `new_key` if present, even if it
+    /// is pinned.  `new_key` must not be present in `other` beforehand.
+    /// Returns whether the key was present.
+    fn force_move(&mut self, key: &Self::Key, other: &mut Self, new_key: Self::Key) -> bool;
+
     /// Evicts a cache entry.
     /// `f` may return `None` if an entry cannot be evicted.
     fn evict<F>(&mut self, f: F) -> Option<(Self::Key, Self::Value)>
@@ -99,6 +161,13 @@ pub trait Cache: Send + Sync {
     /// if the cache should not grow beyond the capacity bound.
     fn insert(&mut self, key: Self::Key, value: Self::Value, size: usize);
 
+    /// Inserts a new cache entry which has been fetched after a cache miss.
+    /// Behaves like `insert`, but attributes the miss to the kind of the
+    /// entry.
+    fn insert_fetched(&mut self, key: Self::Key, value: Self::Value, size: usize) {
+        self.insert(key, value, size)
+    }
+
     /// Returns an iterator that iterates over the cache entry keys in order
     /// from old to new.
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Self::Key> + 'a>;
@@ -109,6 +178,10 @@ pub trait Cache: Send + Sync {
     /// Returns the capacity.
     fn capacity(&self) -> usize;
 
+    /// Changes the capacity.  Entries beyond a smaller capacity are not
+    /// evicted by this call.
+    fn set_capacity(&mut self, capacity: usize);
+
     /// The value returned by `stats`.
     type Stats: Stats;
 
@@ -148,6 +221,22 @@ pub trait Stats: Display + Debug + serde::Serialize {
     fn evictions(&self) -> u64;
     /// Returns the number of removals.
     fn removals(&self) -> u64;
+    /// Returns the number of cache hits on entries of the given kind.
+    fn hits_by_kind(&self, kind: EntryKind) -> u64;
+    /// Returns the number of cache misses which were resolved by fetching an
+    /// entry of the given kind.
+    fn misses_by_kind(&self, kind: EntryKind) -> u64;
+    /// Returns the number of entries which left the cache for the given
+    /// reason.
+    fn evictions_by_reason(&self, reason: EvictionReason) -> u64;
+    /// Returns the size in bytes of the cached entries of each dataset.
+    fn bytes_by_dataset(&self) -> &BTreeMap<DatasetId, usize>;
+
+    /// Adds the statistics of `other`, e.g. of another shard of the same
+    /// cache.
+    fn merge(&mut self, other: &Self)
+    where
+        Self: Sized;
 }
 
 mod clock;
@@ -3,7 +3,7 @@
 
 use crate::size::Size;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{error::Error, fmt, iter::once};
+use std::{error::Error, fmt, hash::Hash, iter::once};
 
 mod fxhash;
 mod gxhash;
@@ -15,7 +15,7 @@ pub use xxhash::{XxHash, XxHashBuilder};
 
 /// A checksum to verify data integrity.
 pub trait Checksum:
-    Serialize + DeserializeOwned + Size + Clone + Send + Sync + fmt::Debug + 'static
+    Serialize + DeserializeOwned + Size + Clone + Eq + Hash + Send + Sync + fmt::Debug + 'static
 {
     /// Builds a new `Checksum`.
     type Builder: Builder<Self>;
@@ -34,6 +34,11 @@ pub trait Checksum:
 
     /// Create a valid empty builder for this checksum type.
     fn builder() -> Self::Builder;
+
+    /// Returns a checksum which is not computed from any data and therefore
+    /// verifies any data.  It is stored for data written to storage classes
+    /// with [ChecksumPolicy::Disabled](crate::storage_pool::ChecksumPolicy).
+    fn unchecked() -> Self;
 }
 
 /// A checksum builder
@@ -0,0 +1,170 @@
+//! Time as seen by the background threads of a [Database](crate::Database).
+//!
+//! Periodic syncs, the reclamation of orphaned object chunks and the migration
+//! policies wait on the [Clock] of the
+//! [DatabaseConfiguration](crate::DatabaseConfiguration).  By default this is
+//! the system clock, while a [VirtualClock] allows to step through grace and
+//! update periods deterministically and without actually waiting, e.g. in
+//! tests of migration policies.
+
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    thread::{self, ThreadId},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The source of time of the background threads.
+#[derive(Debug, Clone, Default)]
+pub enum Clock {
+    /// The time of the operating system.
+    #[default]
+    System,
+    /// Time which only passes by [VirtualClock::advance].
+    Virtual(Arc<VirtualClock>),
+}
+
+impl Clock {
+    /// Returns the current time.
+    pub fn now(&self) -> SystemTime {
+        match self {
+            Clock::System => SystemTime::now(),
+            Clock::Virtual(clock) => UNIX_EPOCH + clock.elapsed(),
+        }
+    }
+
+    /// Blocks the current thread until `duration` has passed.
+    pub fn sleep(&self, duration: Duration) {
+        match self {
+            Clock::System => thread::sleep(duration),
+            Clock::Virtual(clock) => clock.sleep(duration),
+        }
+    }
+
+    /// Registers the current thread as running on the clock until the
+    /// returned guard is dropped.
+    ///
+    /// A [VirtualClock] only moves on while none of its threads is running,
+    /// so a loop sleeping on the clock should hold the guard for as long as it
+    /// runs.  Otherwise a thread which returns or panics after being woken
+    /// blocks [VirtualClock::advance] forever.
+    pub fn register(&self) -> ClockGuard {
+        match self {
+            Clock::System => ClockGuard(None),
+            Clock::Virtual(clock) => {
+                clock.state.lock().running.insert(thread::current().id());
+                ClockGuard(Some(Arc::clone(clock)))
+            }
+        }
+    }
+}
+
+/// Deregisters a thread from its [Clock] when dropped, see [Clock::register].
+#[must_use]
+#[derive(Debug)]
+pub struct ClockGuard(Option<Arc<VirtualClock>>);
+
+impl Drop for ClockGuard {
+    fn drop(&mut self) {
+        if let Some(clock) = &self.0 {
+            let id = thread::current().id();
+            let mut state = clock.state.lock();
+            state.running.remove(&id);
+            state.sleeping.remove(&id);
+            clock.changed.notify_all();
+        }
+    }
+}
+
+/// A clock which is advanced manually.
+///
+/// Threads sleeping on the clock are woken in the order of their deadlines
+/// when time is advanced past them, and time only moves on once all woken
+/// threads have gone to sleep again.  So each thread sleeping on a virtual
+/// clock has to come back to it, and the thread calling [Self::advance] must
+/// not hold any lock the woken threads need, like the one of a shared
+/// [Database](crate::Database).  Threads which stop using the clock have to
+/// deregister from it, see [Clock::register].
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    elapsed: Duration,
+    sleeping: HashMap<ThreadId, Duration>,
+    running: HashSet<ThreadId>,
+}
+
+impl VirtualClock {
+    /// Creates a clock starting at the unix epoch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the time which has passed since the creation of the clock.
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().elapsed
+    }
+
+    /// Returns the number of threads currently sleeping on the clock.
+    pub fn sleepers(&self) -> usize {
+        self.state.lock().sleeping.len()
+    }
+
+    /// Blocks until at least `count` threads sleep on the clock, e.g. to make
+    /// sure that the background threads of a database have been started.
+    pub fn wait_for_sleepers(&self, count: usize) {
+        let mut state = self.state.lock();
+        while state.sleeping.len() < count {
+            self.changed.wait(&mut state);
+        }
+    }
+
+    /// Advances the time by `duration`.  Returns once all threads whose
+    /// deadlines have passed have been woken and are sleeping again.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock();
+        let target = state.elapsed + duration;
+        loop {
+            while !state.running.is_empty() {
+                self.changed.wait(&mut state);
+            }
+            let next = state
+                .sleeping
+                .values()
+                .copied()
+                .filter(|&deadline| deadline <= target)
+                .min();
+            match next {
+                Some(deadline) => {
+                    state.elapsed = state.elapsed.max(deadline);
+                    self.changed.notify_all();
+                    while state.sleeping.values().any(|&d| d <= state.elapsed) {
+                        self.changed.wait(&mut state);
+                    }
+                }
+                None => break,
+            }
+        }
+        state.elapsed = target;
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let id = thread::current().id();
+        let mut state = self.state.lock();
+        state.running.remove(&id);
+        let deadline = state.elapsed + duration;
+        state.sleeping.insert(id, deadline);
+        self.changed.notify_all();
+        while state.elapsed < deadline {
+            self.changed.wait(&mut state);
+        }
+        state.sleeping.remove(&id);
+        state.running.insert(id);
+        self.changed.notify_all();
+    }
+}
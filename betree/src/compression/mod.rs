@@ -15,7 +15,7 @@ pub use errors::*;
 
 const DEFAULT_BUFFER_SIZE: Block<u32> = Block(1);
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum CompressionConfiguration {
     None,
     // Lz4,
@@ -25,7 +25,7 @@ use zstd_safe::{FrameFormat, InBuffer, OutBuffer, WriteBuf};
 // TODO: investigate pre-created dictionary payoff
 
 /// Zstd compression. (<https://github.com/facebook/zstd>)
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub struct Zstd {
     /// The compression level which describes the trade-off between
     /// compression ratio and compression speed.
@@ -1,22 +1,78 @@
 //! This module provides `CowBytes` which is a Copy-on-Write smart pointer
 //! similar to `std::borrow::Cow`.
+//!
+//! Byte buffers of up to [INLINE_CAPACITY] bytes are stored inline and do not
+//! require a heap allocation, which is the common case for keys and values of
+//! metadata trees.
 
 use crate::size::Size;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use stable_deref_trait::StableDeref;
 use std::{
     borrow::Borrow,
-    cmp,
+    cmp, fmt,
+    hash::{Hash, Hasher},
     ops::{Deref, DerefMut},
     sync::Arc,
 };
 
+/// Maximum number of bytes which are stored inline in a `CowBytes`.
+pub const INLINE_CAPACITY: usize = 22;
+
 /// Copy-on-Write smart pointer which supports cheap cloning as it is
 /// reference-counted.
-#[derive(Hash, Debug, Clone, Eq, Ord, Default)]
+///
+/// Note that small buffers are stored inline, so the address of the
+/// referenced data is not stable when a `CowBytes` is moved.
+#[derive(Clone)]
 pub struct CowBytes {
-    // TODO Replace by own implementation
-    pub(super) inner: Arc<Vec<u8>>,
+    inner: Repr,
+}
+
+#[derive(Clone)]
+enum Repr {
+    Inline { len: u8, buf: [u8; INLINE_CAPACITY] },
+    Heap(Arc<Vec<u8>>),
+}
+
+impl Repr {
+    fn inline(x: &[u8]) -> Self {
+        debug_assert!(x.len() <= INLINE_CAPACITY);
+        let mut buf = [0; INLINE_CAPACITY];
+        buf[..x.len()].copy_from_slice(x);
+        Repr::Inline {
+            len: x.len() as u8,
+            buf,
+        }
+    }
+}
+
+impl Default for CowBytes {
+    fn default() -> Self {
+        CowBytes {
+            inner: Repr::inline(&[]),
+        }
+    }
+}
+
+impl fmt::Debug for CowBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CowBytes").field("inner", &&**self).finish()
+    }
+}
+
+impl Hash for CowBytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Has to be consistent with `Borrow<[u8]>`.
+        (**self).hash(state)
+    }
+}
+
+impl Eq for CowBytes {}
+
+impl Ord for CowBytes {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (**self).cmp(&**other)
+    }
 }
 
 impl<T: AsRef<[u8]>> PartialEq<T> for CowBytes {
@@ -80,29 +136,35 @@ impl<'de> Deserialize<'de> for CowBytes {
 
 impl Size for CowBytes {
     fn size(&self) -> usize {
-        8 + self.inner.len()
+        8 + self.len()
     }
 }
 
 impl<'a> From<&'a [u8]> for CowBytes {
     fn from(x: &'a [u8]) -> Self {
-        CowBytes {
-            inner: Arc::new(x.to_vec()),
-        }
+        let inner = if x.len() <= INLINE_CAPACITY {
+            Repr::inline(x)
+        } else {
+            Repr::Heap(Arc::new(x.to_vec()))
+        };
+        CowBytes { inner }
     }
 }
 
 impl From<Box<[u8]>> for CowBytes {
     fn from(x: Box<[u8]>) -> Self {
-        CowBytes {
-            inner: Arc::new(x.into_vec()),
-        }
+        CowBytes::from(x.into_vec())
     }
 }
 
 impl From<Vec<u8>> for CowBytes {
     fn from(x: Vec<u8>) -> Self {
-        CowBytes { inner: Arc::new(x) }
+        let inner = if x.len() <= INLINE_CAPACITY {
+            Repr::inline(&x)
+        } else {
+            Repr::Heap(Arc::new(x))
+        };
+        CowBytes { inner }
     }
 }
 
@@ -118,18 +180,22 @@ impl AsRef<[u8]> for CowBytes {
     }
 }
 
-unsafe impl StableDeref for CowBytes {}
-
 impl Deref for CowBytes {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
-        &self.inner
+        match self.inner {
+            Repr::Inline { len, ref buf } => &buf[..len as usize],
+            Repr::Heap(ref data) => data,
+        }
     }
 }
 
 impl DerefMut for CowBytes {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut Arc::make_mut(&mut self.inner)[..]
+        match self.inner {
+            Repr::Inline { len, ref mut buf } => &mut buf[..len as usize],
+            Repr::Heap(ref mut data) => &mut Arc::make_mut(data)[..],
+        }
     }
 }
 
@@ -151,27 +217,63 @@ impl CowBytes {
     /// Returns the length of the byte buffer.
     #[inline]
     pub fn len(&self) -> usize {
-        self.inner.len()
+        match self.inner {
+            Repr::Inline { len, .. } => len as usize,
+            Repr::Heap(ref data) => data.len(),
+        }
     }
 
     /// Returns whether this buffer is empty.
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.inner.is_empty()
+        self.len() == 0
+    }
+
+    /// Returns whether the data is stored inline without a heap allocation.
+    #[inline]
+    pub fn is_inline(&self) -> bool {
+        matches!(self.inner, Repr::Inline { .. })
     }
 
     /// Create a new, empty `CowBytes` with the given capacity.
     #[inline]
     pub fn with_capacity(cap: usize) -> Self {
-        CowBytes {
-            inner: Arc::new(Vec::with_capacity(cap)),
+        if cap <= INLINE_CAPACITY {
+            CowBytes::new()
+        } else {
+            CowBytes {
+                inner: Repr::Heap(Arc::new(Vec::with_capacity(cap))),
+            }
         }
     }
 
     /// Pushes a byte slice onto the end of the byte buffer.
     #[inline]
     pub fn push_slice(&mut self, v: &[u8]) {
-        Arc::make_mut(&mut self.inner).extend_from_slice(v)
+        if let Repr::Inline {
+            ref mut len,
+            ref mut buf,
+        } = self.inner
+        {
+            let old_len = *len as usize;
+            if old_len + v.len() <= INLINE_CAPACITY {
+                buf[old_len..old_len + v.len()].copy_from_slice(v);
+                *len += v.len() as u8;
+                return;
+            }
+        }
+        self.make_vec_mut().extend_from_slice(v)
+    }
+
+    /// Moves inline data to the heap and returns a mutable reference to it.
+    fn make_vec_mut(&mut self) -> &mut Vec<u8> {
+        if let Repr::Inline { len, ref buf } = self.inner {
+            self.inner = Repr::Heap(Arc::new(buf[..len as usize].to_vec()));
+        }
+        match self.inner {
+            Repr::Heap(ref mut data) => Arc::make_mut(data),
+            Repr::Inline { .. } => unreachable!(),
+        }
     }
 
     /// Fills the buffer with zeros up to `size`.
@@ -187,16 +289,28 @@ impl CowBytes {
     /// Returns the size (number of bytes) that this object would have
     /// if serialized using `bincode`.
     pub fn size(&self) -> usize {
-        8 + self.inner.len()
+        8 + self.len()
     }
 
     /// Returns the underlying data as `Vec<u8>`.
     /// If this object is the only reference to the data,
     /// this functions avoids copying the underlying data.
     pub fn into_vec(self) -> Vec<u8> {
-        match Arc::try_unwrap(self.inner) {
-            Ok(v) => v,
-            Err(this) => Vec::clone(&this),
+        match self.inner {
+            Repr::Inline { len, buf } => buf[..len as usize].to_vec(),
+            Repr::Heap(data) => match Arc::try_unwrap(data) {
+                Ok(v) => v,
+                Err(this) => Vec::clone(&this),
+            },
+        }
+    }
+
+    /// Returns the underlying data as a shared heap allocation, moving inline
+    /// data to the heap if necessary.
+    pub(crate) fn into_arc(self) -> Arc<Vec<u8>> {
+        match self.inner {
+            Repr::Inline { len, buf } => Arc::new(buf[..len as usize].to_vec()),
+            Repr::Heap(data) => data,
         }
     }
 
@@ -214,14 +328,33 @@ impl CowBytes {
 
 impl<'a> Extend<&'a u8> for CowBytes {
     fn extend<T: IntoIterator<Item = &'a u8>>(&mut self, iter: T) {
-        Arc::make_mut(&mut self.inner).extend(iter)
+        let mut iter = iter.into_iter().peekable();
+        if let Repr::Inline {
+            ref mut len,
+            ref mut buf,
+        } = self.inner
+        {
+            while (*len as usize) < INLINE_CAPACITY {
+                match iter.next() {
+                    Some(&byte) => {
+                        buf[*len as usize] = byte;
+                        *len += 1;
+                    }
+                    None => return,
+                }
+            }
+            if iter.peek().is_none() {
+                return;
+            }
+        }
+        self.make_vec_mut().extend(iter)
     }
 }
 
 /// Reference-counted pointer which points to a subslice of the referenced data.
 #[derive(Debug, Default, Clone)]
 pub struct SlicedCowBytes {
-    pub(super) data: CowBytes,
+    data: CowBytes,
     pos: u32,
     len: u32,
 }
@@ -261,26 +394,47 @@ impl Size for SlicedCowBytes {
 }
 
 impl SlicedCowBytes {
+    /// Constructs a slice which points to `data[pos..pos+len]`.
+    ///
+    /// Small slices of heap allocated data are copied into an inline buffer,
+    /// so that they do not keep a potentially large allocation alive.
+    fn new(data: CowBytes, pos: u32, len: u32) -> Self {
+        if len as usize <= INLINE_CAPACITY && !data.is_inline() {
+            let start = pos as usize;
+            let data = CowBytes::from(&data[start..start + len as usize]);
+            return SlicedCowBytes { data, pos: 0, len };
+        }
+        SlicedCowBytes { data, pos, len }
+    }
+
     /// Returns a new subslice which points to `self[pos..pos+len]`.
     pub fn subslice(self, pos: u32, len: u32) -> Self {
-        let pos = self.pos + pos;
         assert!(pos + len <= self.len);
-        SlicedCowBytes {
-            data: self.data,
-            pos,
-            len,
-        }
+        SlicedCowBytes::new(self.data, self.pos + pos, len)
     }
 
     /// Returns a new subslice which points to `self[pos..]`.
     pub fn slice_from(self, pos: u32) -> Self {
         assert!(pos <= self.len);
-        SlicedCowBytes {
-            data: self.data,
-            pos: self.pos + pos,
-            len: self.len - pos,
+        SlicedCowBytes::new(self.data, self.pos + pos, self.len - pos)
+    }
+
+    /// Converts this slice into an owned `CowBytes`.
+    /// If this slice covers the whole underlying buffer, the buffer is reused
+    /// and only copied once it is modified while still being shared.
+    pub fn into_cow_bytes(self) -> CowBytes {
+        if self.pos == 0 && self.len as usize == self.data.len() {
+            self.data
+        } else {
+            CowBytes::from(&self[..])
         }
     }
+
+    /// Returns the underlying buffer together with the position and length of
+    /// this slice.
+    pub(crate) fn into_raw_parts(self) -> (CowBytes, u32, u32) {
+        (self.data, self.pos, self.len)
+    }
 }
 
 impl From<CowBytes> for SlicedCowBytes {
@@ -304,7 +458,7 @@ impl Deref for SlicedCowBytes {
 
 #[cfg(test)]
 mod tests {
-    use super::{Arc, CowBytes};
+    use super::{CowBytes, SlicedCowBytes, INLINE_CAPACITY};
     use crate::arbitrary::GenExt;
     use quickcheck::{Arbitrary, Gen};
     use rand::{Rng, RngCore};
@@ -315,13 +469,48 @@ mod tests {
             let len = rng.gen_range(0..128);
             let mut bytes = vec![0; len];
             rng.fill_bytes(&mut bytes);
-            CowBytes {
-                inner: Arc::new(bytes),
-            }
+            CowBytes::from(bytes)
         }
 
         fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
-            Box::new(self.inner.shrink().map(|inner| CowBytes { inner }))
+            Box::new(self.to_vec().shrink().map(CowBytes::from))
         }
     }
+
+    #[quickcheck]
+    fn check_inline_representation(bytes: Vec<u8>, extension: Vec<u8>) {
+        let mut cow = CowBytes::from(&bytes[..]);
+        assert_eq!(cow.is_inline(), bytes.len() <= INLINE_CAPACITY);
+        assert_eq!(&cow[..], &bytes[..]);
+        assert_eq!(cow, CowBytes::from(bytes.clone()));
+
+        cow.push_slice(&extension);
+        let mut expected = bytes.clone();
+        expected.extend_from_slice(&extension);
+        assert_eq!(&cow[..], &expected[..]);
+        assert_eq!(cow.is_inline(), expected.len() <= INLINE_CAPACITY);
+
+        let mut extended = CowBytes::from(bytes);
+        extended.extend(&extension);
+        assert_eq!(extended, cow);
+        assert_eq!(extended.into_vec(), expected);
+    }
+
+    #[quickcheck]
+    fn check_subslice(cow: CowBytes, pos: u8, len: u8) {
+        let pos = (pos as usize).min(cow.len());
+        let len = (len as usize).min(cow.len() - pos);
+        let sliced = SlicedCowBytes::from(cow.clone()).subslice(pos as u32, len as u32);
+        assert_eq!(&sliced[..], &cow[pos..pos + len]);
+
+        let inner = (len / 2) as u32;
+        assert_eq!(
+            &sliced.clone().slice_from(inner)[..],
+            &cow[pos + inner as usize..pos + len]
+        );
+        assert_eq!(
+            &sliced.subslice(inner, len as u32 / 4)[..],
+            &cow[pos + inner as usize..pos + inner as usize + len / 4]
+        );
+    }
 }
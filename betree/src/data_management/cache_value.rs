@@ -1,14 +1,23 @@
-use crate::{cache::AddSize, size::SizeMut};
+use crate::{
+    cache::{AddSize, Classify, EntryKind},
+    database::DatasetId,
+    size::SizeMut,
+    tree::PivotKey,
+};
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use stable_deref_trait::StableDeref;
 use std::{
     mem::{transmute, ManuallyDrop},
     ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 pub struct CacheValueRef<T, U> {
     head: T,
     guard: ManuallyDrop<U>,
+    // Only writable references are handed out for modified entries, whose
+    // size changes are also counted here.
+    dirty_size: Option<&'static AtomicUsize>,
 }
 
 impl<T, U> Drop for CacheValueRef<T, U> {
@@ -21,6 +30,13 @@ impl<T, U> Drop for CacheValueRef<T, U> {
 
 impl<T: AddSize, U> AddSize for CacheValueRef<T, U> {
     fn add_size(&self, size_delta: isize) {
+        if let Some(dirty_size) = self.dirty_size {
+            if size_delta >= 0 {
+                dirty_size.fetch_add(size_delta as usize, Ordering::Relaxed);
+            } else {
+                dirty_size.fetch_sub(-size_delta as usize, Ordering::Relaxed);
+            }
+        }
         self.head.add_size(size_delta)
     }
 }
@@ -34,6 +50,7 @@ where
         CacheValueRef {
             head,
             guard: ManuallyDrop::new(guard),
+            dirty_size: None,
         }
     }
 }
@@ -42,11 +59,12 @@ impl<T, U, I> CacheValueRef<T, RwLockWriteGuard<'static, U>>
 where
     T: StableDeref<Target = TaggedCacheValue<RwLock<U>, I>>,
 {
-    pub(super) fn write(head: T) -> Self {
+    pub(super) fn write(head: T, dirty_size: &'static AtomicUsize) -> Self {
         let guard = unsafe { transmute(RwLock::write(&head.value)) };
         CacheValueRef {
             head,
             guard: ManuallyDrop::new(guard),
+            dirty_size: Some(dirty_size),
         }
     }
 }
@@ -113,3 +131,13 @@ impl<Val: SizeMut, Tag> SizeMut for TaggedCacheValue<Val, Tag> {
         self.value.size()
     }
 }
+
+impl<Val: Classify> Classify for TaggedCacheValue<RwLock<Val>, PivotKey> {
+    fn entry_kind(&mut self) -> EntryKind {
+        self.value.get_mut().entry_kind()
+    }
+
+    fn dataset(&self) -> Option<DatasetId> {
+        Some(self.tag.d_id())
+    }
+}
@@ -0,0 +1,231 @@
+//! A small cache of decompressed node data, separate from the object cache.
+//!
+//! Under memory pressure, nodes are evicted from the object cache and fetched
+//! again shortly after, which costs a full decompression each time.  Nodes
+//! shared by snapshots are hit especially often.  This cache keeps the
+//! decompressed bytes of recently fetched compressed objects within its own
+//! budget, so that such refetches only have to unpack the node.
+//!
+//! Entries are keyed by the location, the generation and the checksum of their
+//! object, so a block which is freed and rewritten does not hit the entry of
+//! its previous contents, which simply ages out.  Objects on tiers without
+//! checksums are not cached, as a rewrite within the same generation could not
+//! be told apart there.
+//!
+//! Lookups, insertions and evictions take constant time, and data is shared
+//! by reference, so that callers can copy it after releasing their lock.
+
+use crate::storage_pool::DiskOffset;
+use std::{collections::HashMap, hash::Hash, sync::Arc};
+
+/// Marks the end of the list of entries.
+const NIL: usize = usize::MAX;
+
+type Key<G, C> = (DiskOffset, Option<u8>, G, C);
+
+#[derive(Debug)]
+struct Entry<G, C> {
+    key: Key<G, C>,
+    data: Arc<[u8]>,
+    prev: usize,
+    next: usize,
+}
+
+/// The decompressed data of objects by `(offset, slot, generation, checksum)`.
+///
+/// Entries are kept in a doubly linked list through their slots in `entries`,
+/// least recently used first.  Slots of evicted entries are reused.
+#[derive(Debug)]
+pub(super) struct DecompressedCache<G, C> {
+    index: HashMap<Key<G, C>, usize>,
+    entries: Vec<Option<Entry<G, C>>>,
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+    size: usize,
+    capacity: usize,
+}
+
+impl<G: Hash + Eq + Clone, C: Hash + Eq + Clone> DecompressedCache<G, C> {
+    /// Returns an empty cache which holds up to `capacity` bytes, none if it
+    /// is zero.
+    pub(super) fn new(capacity: usize) -> Self {
+        DecompressedCache {
+            index: HashMap::new(),
+            entries: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            size: 0,
+            capacity,
+        }
+    }
+
+    fn entry(&mut self, idx: usize) -> &mut Entry<G, C> {
+        self.entries[idx].as_mut().unwrap()
+    }
+
+    /// Removes the entry in slot `idx` from the list.
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = {
+            let entry = self.entry(idx);
+            (entry.prev, entry.next)
+        };
+        match prev {
+            NIL => self.head = next,
+            prev => self.entry(prev).next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.entry(next).prev = prev,
+        }
+    }
+
+    /// Appends the entry in slot `idx` to the list as the most recently used.
+    fn push_back(&mut self, idx: usize) {
+        let tail = self.tail;
+        {
+            let entry = self.entry(idx);
+            entry.prev = tail;
+            entry.next = NIL;
+        }
+        match tail {
+            NIL => self.head = idx,
+            tail => self.entry(tail).next = idx,
+        }
+        self.tail = idx;
+    }
+
+    /// Removes the entry in slot `idx` from the cache.
+    fn remove(&mut self, idx: usize) {
+        self.unlink(idx);
+        let entry = self.entries[idx].take().unwrap();
+        self.index.remove(&entry.key);
+        self.free.push(idx);
+        self.size -= entry.data.len();
+    }
+
+    /// Returns the data of the given object, if it is cached.
+    pub(super) fn get(
+        &mut self,
+        offset: DiskOffset,
+        slot: Option<u8>,
+        generation: G,
+        checksum: C,
+    ) -> Option<Arc<[u8]>> {
+        let idx = *self.index.get(&(offset, slot, generation, checksum))?;
+        self.unlink(idx);
+        self.push_back(idx);
+        Some(Arc::clone(&self.entry(idx).data))
+    }
+
+    /// Caches the data of the given object, evicting the least recently used
+    /// entries if the budget is exceeded.  Data larger than the whole budget
+    /// is not cached.
+    pub(super) fn insert(
+        &mut self,
+        offset: DiskOffset,
+        slot: Option<u8>,
+        generation: G,
+        checksum: C,
+        data: Arc<[u8]>,
+    ) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let key = (offset, slot, generation, checksum);
+        if let Some(&idx) = self.index.get(&key) {
+            self.remove(idx);
+        }
+        self.size += data.len();
+        let entry = Entry {
+            key: key.clone(),
+            data,
+            prev: NIL,
+            next: NIL,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.entries[idx] = Some(entry);
+                idx
+            }
+            None => {
+                self.entries.push(Some(entry));
+                self.entries.len() - 1
+            }
+        };
+        self.index.insert(key, idx);
+        self.push_back(idx);
+        while self.size > self.capacity {
+            self.remove(self.head);
+        }
+    }
+
+    /// Returns the number of bytes cached.
+    pub(super) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecompressedCache;
+    use crate::storage_pool::DiskOffset;
+    use std::sync::Arc;
+
+    fn offset(block: u64) -> DiskOffset {
+        DiskOffset::new(0, 0, crate::vdev::Block(block))
+    }
+
+    fn data(bytes: &[u8]) -> Arc<[u8]> {
+        Arc::from(bytes)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = DecompressedCache::new(8);
+        cache.insert(offset(1), None, 1u64, 1u64, data(&[1; 4]));
+        cache.insert(offset(2), None, 1, 2, data(&[2; 4]));
+        assert!(cache.get(offset(1), None, 1, 1).is_some());
+        cache.insert(offset(3), None, 1, 3, data(&[3; 4]));
+        assert_eq!(cache.size(), 8);
+        assert!(cache.get(offset(2), None, 1, 2).is_none());
+        assert_eq!(&cache.get(offset(1), None, 1, 1).unwrap()[..], &[1; 4]);
+    }
+
+    #[test]
+    fn distinguishes_rewritten_blocks() {
+        let mut cache = DecompressedCache::new(8);
+        cache.insert(offset(1), None, 1u64, 1u64, data(&[1; 4]));
+        assert!(cache.get(offset(1), None, 1, 2).is_none());
+        assert!(cache.get(offset(1), None, 2, 1).is_none());
+        assert!(cache.get(offset(1), Some(0), 1, 1).is_none());
+    }
+
+    #[test]
+    fn skips_data_larger_than_budget() {
+        let mut cache = DecompressedCache::new(4);
+        cache.insert(offset(1), None, 1u64, 1u64, data(&[1; 5]));
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn replaces_and_reuses_slots() {
+        let mut cache = DecompressedCache::new(8);
+        for round in 0..64u64 {
+            cache.insert(offset(round % 3), None, 1u64, 1u64, data(&[round as u8; 3]));
+            assert!(cache.size() <= 8);
+        }
+        // only the last two writes fit
+        assert_eq!(cache.size(), 6);
+        assert_eq!(&cache.get(offset(2), None, 1, 1).unwrap()[..], &[62; 3]);
+        assert_eq!(&cache.get(offset(0), None, 1, 1).unwrap()[..], &[63; 3]);
+        assert!(cache.get(offset(1), None, 1, 1).is_none());
+        assert!(cache.entries.len() <= 3);
+
+        cache.insert(offset(2), None, 1, 1, data(&[2; 2]));
+        assert_eq!(cache.size(), 5);
+        assert_eq!(&cache.get(offset(2), None, 1, 1).unwrap()[..], &[2; 2]);
+        assert_eq!(&cache.get(offset(0), None, 1, 1).unwrap()[..], &[63; 3]);
+    }
+}
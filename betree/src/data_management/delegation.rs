@@ -1,6 +1,6 @@
-use crate::{database::DatasetId, tree::PivotKey};
+use crate::{database::DatasetId, tree::PivotKey, StoragePreference};
 
-use super::{Dml, Error};
+use super::{CacheReport, Dml, Error, NodeEvent};
 use std::ops::{Deref, DerefMut};
 
 impl<T> Dml for T
@@ -61,14 +61,22 @@ where
         (**self).get_and_remove(or)
     }
 
+    fn hint_storage_preference(&self, or: &Self::ObjectRef, pref: StoragePreference) {
+        (**self).hint_storage_preference(or, pref)
+    }
+
     fn evict(&self) -> Result<(), Error> {
         (**self).evict()
     }
 
-    fn verify_cache(&self) {
+    fn verify_cache(&self) -> CacheReport {
         (**self).verify_cache()
     }
 
+    fn emit_node_event<F: FnOnce() -> NodeEvent>(&self, event: F) {
+        (**self).emit_node_event(event)
+    }
+
     fn root_ref_from_ptr(r: Self::ObjectPointer) -> Self::ObjectRef {
         <T::Target as Dml>::root_ref_from_ptr(r)
     }
@@ -98,4 +106,8 @@ where
     fn drop_cache(&self) {
         (**self).drop_cache();
     }
+
+    fn drop_cache_for(&self, dataset: DatasetId) {
+        (**self).drop_cache_for(dataset);
+    }
 }
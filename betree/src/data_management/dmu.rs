@@ -1,65 +1,122 @@
 use super::{
     cache_value::{CacheValueRef, TaggedCacheValue},
+    decompressed::DecompressedCache,
     errors::*,
+    events::{NodeEvent, NodeEventKind, NodeEvents},
     impls::{ModifiedObjectId, ObjRef, ObjectKey},
-    object_ptr::ObjectPointer,
-    CopyOnWriteEvent, Dml, HasStoragePreference, Object, ObjectReference,
+    object_ptr::{ditto_offset, ObjectPointer},
+    replica::{Replica, Replicas},
+    slab::{self, Slab, MAX_PACKED_SIZE},
+    storage_hints::{HintSource, StorageHints},
+    write_budget::{self, WriteBudgetInfo, WriteBudgets},
+    CacheProblem, CacheReport, CopyOnWriteEvent, Dml, HasStoragePreference, Object,
+    ObjectReference,
 };
 use crate::{
     allocator::{Action, SegmentAllocator, SegmentId},
     buffer::Buf,
-    cache::{Cache, ChangeKeyError, RemoveError},
-    checksum::{Builder, Checksum, State},
-    compression::CompressionBuilder,
-    data_management::CopyOnWriteReason,
-    database::{DatasetId, Generation, Handler},
+    cache::{Cache, ChangeKeyError, EvictionReason, RemoveError, Stats},
+    checksum::{Builder, Checksum, ChecksumError, State},
+    clock::Clock,
+    compression::{CompressionBuilder, DecompressionTag},
+    data_management::{
+        numa::{NumaSharding, NumaTopology},
+        prefetch::{Prefetch, PrefetchQueue},
+        CopyOnWriteReason,
+    },
+    database::{
+        DatasetId, Generation, Handler, NodeRead, DITTO_VERSION, POINTER_PREFERENCE_VERSION,
+        ROOT_DATASET_ID, SLAB_VERSION, VERSIONED_POINTER_VERSION, WIDE_DISK_ID_VERSION,
+    },
     migration::DmlMsg,
     size::{Size, SizeMut, StaticSize},
-    storage_pool::{DiskOffset, StoragePoolLayer, NUM_STORAGE_CLASSES},
+    storage_pool::{
+        ChecksumPolicy, DiskOffset, StoragePoolLayer, LEGACY_MAX_DISKS_PER_CLASS,
+        MAX_DISKS_PER_CLASS, NUM_STORAGE_CLASSES,
+    },
     tree::{Node, PivotKey},
-    vdev::{Block, BLOCK_SIZE},
+    vdev::{Block, Error as VdevError, BLOCK_SIZE},
     StoragePreference,
 };
-use crossbeam_channel::Sender;
-use futures::{executor::block_on, future::ok, prelude::*};
+use crossbeam_channel::{Receiver, Sender};
+use futures::{future::ok, prelude::*};
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::{
     collections::HashMap,
     mem::replace,
     ops::DerefMut,
-    pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     thread::yield_now,
 };
 
+/// The lowest level of internal nodes which are written twice if
+/// [Dmu::with_ditto_metadata] is enabled, where leaves are of level zero.
+const DITTO_MIN_LEVEL: u32 = 2;
+
+/// Returns the capacity of shard `idx` of a cache of `capacity` bytes split
+/// into `shards`, where the first shard also takes the remainder.
+fn shard_capacity(capacity: usize, shards: usize, idx: usize) -> usize {
+    capacity / shards + if idx == 0 { capacity % shards } else { 0 }
+}
+
 /// The Data Management Unit.
 pub struct Dmu<E: 'static, SPL: StoragePoolLayer>
 where
     SPL::Checksum: StaticSize,
 {
     default_compression: Box<dyn CompressionBuilder>,
+    // The slowest storage class and the compression used for it instead.
+    cold_compression: Option<(u8, Box<dyn CompressionBuilder>)>,
     // NOTE: Why was this included in the first place? Delayed Compression? Streaming Compression?
     // default_compression_state: C::CompressionState,
     default_storage_class: u8,
     default_checksum_builder: <SPL::Checksum as Checksum>::Builder,
     alloc_strategy: [[Option<u8>; NUM_STORAGE_CLASSES]; NUM_STORAGE_CLASSES],
     pool: SPL,
-    cache: RwLock<E>,
+    // The object cache with one shard per NUMA shard.  Modified nodes and
+    // nodes in write back are only kept in the first shard, so that their
+    // keys can be changed and their children looked up under a single lock.
+    // Fetched nodes are inserted into the shard of the fetching thread and
+    // searched for in all shards, starting with the local one, but are only
+    // present in one of them.  Locks of several shards are taken in order.
+    caches: Box<[RwLock<E>]>,
     written_back: Mutex<HashMap<ModifiedObjectId, ObjectPointer<SPL::Checksum>>>,
     modified_info: Mutex<HashMap<ModifiedObjectId, DatasetId>>,
-    storage_hints: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
+    storage_hints: Arc<Mutex<StorageHints>>,
     handler: Handler<ObjRef<ObjectPointer<SPL::Checksum>>>,
     // NOTE: The semantic structure of this looks as this
-    // Storage Pool Layers:
-    //      Layer Disks:
-    //          Tuple of SegmentIDs and their according Allocators
-    allocation_data: Box<[Box<[Mutex<Option<SegmentId>>]>]>,
+    // NUMA Shards:
+    //      Storage Pool Layers:
+    //          Layer Disks:
+    //              Tuple of SegmentIDs and their according Allocators
+    allocation_data: Box<[Box<[Box<[Mutex<Option<SegmentId>>]>]>]>,
+    numa: NumaTopology,
+    prefetch_queue: Arc<PrefetchQueue>,
     next_modified_node_id: AtomicU64,
     next_disk_id: AtomicU64,
     report_tx: Option<Sender<DmlMsg>>,
+    events: NodeEvents,
+    // The slab block per storage class which small objects of the given
+    // generation are packed into.  It is only written once it is full or the
+    // generation ends, see `Dmu::write_slabs`.
+    slabs: Mutex<[Option<(Generation, Slab)>; NUM_STORAGE_CLASSES]>,
+    // Percentage of each storage class which new inserts may not claim.
+    space_reserve_percent: u8,
+    // Percentage of the cache which modified nodes may fill before inserts
+    // asking for backpressure are refused.
+    dirty_watermark_percent: u8,
+    // Bytes of the cache held by modified nodes, see `Dmu::dirty_size`.  It
+    // is leaked like the size of the cache, as writable cache references
+    // update it when their nodes grow or shrink.
+    dirty_size: &'static AtomicUsize,
+    // Whether upper nodes are written twice, see `Dmu::with_ditto_metadata`.
+    ditto_metadata: bool,
+    replicas: Mutex<Replicas>,
+    write_budgets: WriteBudgets,
+    decompressed: Mutex<DecompressedCache<Generation, SPL::Checksum>>,
 }
 
 impl<E, SPL> Dmu<E, SPL>
@@ -76,11 +133,33 @@ where
         alloc_strategy: [[Option<u8>; NUM_STORAGE_CLASSES]; NUM_STORAGE_CLASSES],
         cache: E,
         handler: Handler<ObjRef<ObjectPointer<SPL::Checksum>>>,
-    ) -> Self {
-        let allocation_data = (0..pool.storage_class_count())
-            .map(|class| {
-                (0..pool.disk_count(class))
-                    .map(|_| Mutex::new(None))
+        numa_sharding: NumaSharding,
+        prefetch_queue_depth: usize,
+    ) -> Self
+    where
+        E: Cache,
+    {
+        let numa = NumaTopology::new(numa_sharding);
+        let capacity = cache.capacity();
+        let caches = std::iter::once(cache)
+            .chain((1..numa.shards()).map(|_| E::new(0)))
+            .map(RwLock::new)
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        for (idx, cache) in caches.iter().enumerate() {
+            cache
+                .write()
+                .set_capacity(shard_capacity(capacity, caches.len(), idx));
+        }
+        let allocation_data = (0..numa.shards())
+            .map(|_| {
+                (0..pool.storage_class_count())
+                    .map(|class| {
+                        (0..pool.disk_count(class))
+                            .map(|_| Mutex::new(None))
+                            .collect::<Vec<_>>()
+                            .into_boxed_slice()
+                    })
                     .collect::<Vec<_>>()
                     .into_boxed_slice()
             })
@@ -90,36 +169,151 @@ where
         Dmu {
             // default_compression_state: default_compression.new_compression().expect("Can't create compression state"),
             default_compression,
+            cold_compression: None,
             default_storage_class,
             default_checksum_builder,
             alloc_strategy,
             pool,
-            cache: RwLock::new(cache),
+            caches,
             written_back: Mutex::new(HashMap::new()),
             modified_info: Mutex::new(HashMap::new()),
-            storage_hints: Arc::new(Mutex::new(HashMap::new())),
+            storage_hints: Arc::new(Mutex::new(StorageHints::default())),
             handler,
             allocation_data,
+            numa,
+            prefetch_queue: PrefetchQueue::new(prefetch_queue_depth),
             next_modified_node_id: AtomicU64::new(1),
             next_disk_id: AtomicU64::new(0),
             report_tx: None,
+            events: NodeEvents::default(),
+            slabs: Mutex::new(Default::default()),
+            space_reserve_percent: 0,
+            dirty_watermark_percent: 100,
+            dirty_size: Box::leak(Default::default()),
+            ditto_metadata: false,
+            replicas: Mutex::new(Replicas::default()),
+            write_budgets: WriteBudgets::default(),
+            decompressed: Mutex::new(DecompressedCache::new(0)),
+        }
+    }
+
+    /// Compresses nodes written to the slowest storage class with
+    /// `compression` instead of the default compression.
+    pub fn with_cold_compression(mut self, compression: Box<dyn CompressionBuilder>) -> Self {
+        let slowest = (0..self.pool.storage_class_count())
+            .rev()
+            .find(|&class| self.pool.disk_count(class) > 0);
+        self.cold_compression = slowest.map(|class| (class, compression));
+        self
+    }
+
+    /// Keeps `percent` of each storage class as an emergency reserve, see
+    /// [Dmu::out_of_space].
+    pub fn with_space_reserve(mut self, percent: u8) -> Self {
+        self.space_reserve_percent = percent;
+        self
+    }
+
+    /// Lets modified nodes fill `percent` of the cache, see
+    /// [Dmu::dirty_watermark].
+    pub fn with_dirty_watermark(mut self, percent: u8) -> Self {
+        self.dirty_watermark_percent = percent;
+        self
+    }
+
+    /// Writes a second copy of the nodes of the root tree, of root nodes and of
+    /// internal nodes of level two and above.  Reads fall back to the copy if
+    /// the first one can not be read.  Storage classes with
+    /// mirror or parity vdevs can already recover from bad blocks and are
+    /// written to only once.
+    pub fn with_ditto_metadata(mut self, ditto_metadata: bool) -> Self {
+        self.ditto_metadata = ditto_metadata;
+        self
+    }
+
+    /// Limits the bytes written to each storage class per day of `clock`,
+    /// see [super::write_budget].
+    pub fn with_write_budgets(
+        mut self,
+        budgets: [Option<u64>; NUM_STORAGE_CLASSES],
+        clock: Clock,
+    ) -> Self {
+        self.write_budgets = WriteBudgets::new(budgets, clock);
+        self
+    }
+
+    /// Keeps up to `size` bytes of decompressed node data apart from the
+    /// object cache, see [super::decompressed].
+    pub fn with_decompressed_cache(mut self, size: usize) -> Self {
+        self.decompressed = Mutex::new(DecompressedCache::new(size));
+        self
+    }
+
+    /// Returns whether all storage classes which allocations of
+    /// `storage_class` may fall back to consist of single-disk vdevs only.
+    fn without_redundancy(&self, storage_class: u8) -> bool {
+        self.alloc_strategy[storage_class as usize]
+            .iter()
+            .flatten()
+            .all(|&class| {
+                (0..self.pool.disk_count(class))
+                    .all(|disk_id| self.pool.num_disks(class, disk_id) == 1)
+            })
+    }
+
+    /// Returns the storage class requested for `storage_preference` if none of
+    /// the classes its allocations may fall back to has free space beyond its
+    /// emergency reserve.  Allocations themselves may use the reserve, so
+    /// callers check this before accepting new data.
+    pub fn out_of_space(&self, storage_preference: StoragePreference) -> Option<u8> {
+        let class = storage_preference
+            .preferred_class()
+            .unwrap_or(self.default_storage_class);
+        let has_space = self.alloc_strategy[class as usize]
+            .iter()
+            .flatten()
+            .any(|&class| {
+                self.handler.free_space_tier(class).map_or(false, |info| {
+                    info.free.as_u64() * 100
+                        > info.total.as_u64() * u64::from(self.space_reserve_percent)
+                })
+            });
+        if has_space {
+            None
+        } else {
+            Some(class)
         }
     }
 
+    /// Returns the bytes of the cache held by nodes which have been modified
+    /// since they have been written last, including those being written back.
+    pub fn dirty_size(&self) -> usize {
+        self.dirty_size.load(Ordering::Relaxed)
+    }
+
+    fn add_dirty_size(&self, size: usize) {
+        self.dirty_size.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn sub_dirty_size(&self, size: usize) {
+        self.dirty_size.fetch_sub(size, Ordering::Relaxed);
+    }
+
     /// Returns the underlying handler.
     pub fn handler(&self) -> &Handler<ObjRef<ObjectPointer<SPL::Checksum>>> {
         &self.handler
     }
 
-    /// Returns the underlying cache.
-    pub fn cache(&self) -> &RwLock<E> {
-        &self.cache
-    }
-
     /// Returns the underlying storage pool.
     pub fn pool(&self) -> &SPL {
         &self.pool
     }
+
+    /// Returns a stream of all nodes evicted, written back, stolen, flushed
+    /// to, split or merged from now on.
+    pub fn subscribe_node_events(&self) -> Receiver<NodeEvent> {
+        self.events.subscribe()
+    }
 }
 
 impl<E, SPL> Dmu<E, SPL>
@@ -131,6 +325,118 @@ where
     SPL: StoragePoolLayer,
     SPL::Checksum: StaticSize,
 {
+    /// Returns the bytes of the cache modified nodes may fill.  Nothing
+    /// prevents them from filling more, as they are written back on sync or
+    /// once the cache is full, but callers which would rather shed load may
+    /// compare it to [Dmu::dirty_size] first.
+    pub fn dirty_watermark(&self) -> usize {
+        self.cache_capacity() / 100 * self.dirty_watermark_percent as usize
+    }
+
+    /// Returns the capacity of all shards of the cache.
+    pub fn cache_capacity(&self) -> usize {
+        self.caches
+            .iter()
+            .map(|cache| cache.read().capacity())
+            .sum()
+    }
+
+    /// Returns the bytes held by all shards of the cache.
+    pub fn cache_size(&self) -> usize {
+        self.caches.iter().map(|cache| cache.read().size()).sum()
+    }
+
+    /// Changes the capacity of the cache, which is split evenly across its
+    /// shards.  If it shrinks below the bytes cached, entries are evicted
+    /// right away as far as they may be, the others are left to the
+    /// evictions after later modifications.
+    pub fn set_cache_capacity(&self, capacity: usize) -> Result<(), Error> {
+        for (idx, cache) in self.caches.iter().enumerate() {
+            cache
+                .write()
+                .set_capacity(shard_capacity(capacity, self.caches.len(), idx));
+        }
+        loop {
+            let size = self.cache_size();
+            match self.overfull_cache() {
+                None => return Ok(()),
+                Some(cache) => self.evict(cache)?,
+            }
+            if self.cache_size() >= size {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The shard of the cache which holds modified nodes and nodes in write
+    /// back.
+    fn modified_cache(&self) -> &RwLock<E> {
+        &self.caches[0]
+    }
+
+    /// Returns the indices of the cache shards which may hold `key`, starting
+    /// with the shard of the current NUMA node.
+    fn shards_of(&self, key: &ObjectKey<Generation>) -> impl Iterator<Item = usize> {
+        let shards = match key {
+            ObjectKey::Unmodified { .. } => self.caches.len(),
+            ObjectKey::Modified(_) | ObjectKey::InWriteback(_) => 1,
+        };
+        let local = if shards == 1 {
+            0
+        } else {
+            self.numa.current_shard()
+        };
+        (0..shards).map(move |idx| (local + idx) % shards)
+    }
+
+    /// Looks up `key` in the cache shards which may hold it.  A miss is only
+    /// counted in the last shard searched.
+    fn cache_get(&self, key: &ObjectKey<Generation>, count_miss: bool) -> Option<E::ValueRef> {
+        let mut shards = self.shards_of(key).peekable();
+        while let Some(shard) = shards.next() {
+            let last = shards.peek().is_none();
+            if let Some(entry) = self.caches[shard].read().get(key, count_miss && last) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    /// Returns whether any cache shard holds `key`.
+    fn cache_contains_key(&self, key: &ObjectKey<Generation>) -> bool {
+        self.shards_of(key)
+            .any(|shard| self.caches[shard].read().contains_key(key))
+    }
+
+    /// Returns the cache shard to evict from if the cache holds more than its
+    /// capacity, which is the one exceeding its share the most.
+    fn overfull_cache(&self) -> Option<RwLockWriteGuard<E>> {
+        if self.caches.len() == 1 {
+            let cache = self.caches[0].write();
+            return if cache.size() > cache.capacity() {
+                Some(cache)
+            } else {
+                None
+            };
+        }
+        let (mut size, mut capacity, mut fullest, mut excess) = (0, 0, 0, isize::MIN);
+        for (idx, cache) in self.caches.iter().enumerate() {
+            let cache = cache.read();
+            size += cache.size();
+            capacity += cache.capacity();
+            let shard_excess = cache.size() as isize - cache.capacity() as isize;
+            if shard_excess > excess {
+                fullest = idx;
+                excess = shard_excess;
+            }
+        }
+        if size > capacity {
+            Some(self.caches[fullest].write())
+        } else {
+            None
+        }
+    }
+
     /// Stealing an [ObjectRef] can have multiple effects.  First, the
     /// corresponding node is moved in cache to the [ObjectKey::Modified] state.
     /// Second, the passed [ObjectRef] is moved to the [ObjectRef::Modified]
@@ -151,15 +457,34 @@ where
             pref: or.correct_preference(),
         };
         let entry = {
-            let mut cache = self.cache.write();
-            let was_present = cache.force_change_key(&or.as_key(), ObjectKey::Modified(mid));
+            // Unmodified nodes may be held by any shard, they are moved to
+            // the shard of modified nodes.
+            let mut caches: Vec<_> = self.caches.iter().map(|cache| cache.write()).collect();
+            let (cache, others) = caches.split_first_mut().unwrap();
+            let key = or.as_key();
+            let was_present = cache.force_change_key(&key, ObjectKey::Modified(mid))
+                || others
+                    .iter_mut()
+                    .any(|other| other.force_move(&key, cache, ObjectKey::Modified(mid)));
             if !was_present {
                 return Ok(None);
             }
             self.modified_info.lock().insert(mid, info);
+            self.add_dirty_size(cache.size_of(&ObjectKey::Modified(mid)).unwrap());
             cache.get(&ObjectKey::Modified(mid), false).unwrap()
         };
-        let obj = CacheValueRef::write(entry);
+        let obj = CacheValueRef::write(entry, self.dirty_size);
+        self.events.emit(|| NodeEvent {
+            kind: NodeEventKind::Stolen,
+            dataset: info,
+            size: Size::size(&*obj) as u64,
+            storage_class: match or {
+                ObjRef::Unmodified(ptr, ..) => Some(ptr.offset().storage_class()),
+                _ => None,
+            },
+            level: None,
+            duration: None,
+        });
 
         if let ObjRef::Unmodified(ptr, ..) = replace(or, ObjRef::Modified(mid, pk)) {
             self.copy_on_write(ptr, CopyOnWriteReason::Steal, or.index().clone());
@@ -196,21 +521,33 @@ where
         steal: CopyOnWriteReason,
         pivot_key: PivotKey,
     ) {
-        let actual_size = self.pool.actual_size(
-            obj_ptr.offset().storage_class(),
-            obj_ptr.offset().disk_id(),
-            obj_ptr.size(),
-        );
-        if let (CopyOnWriteEvent::Removed, Some(tx), CopyOnWriteReason::Remove) = (
+        let event = if let Some(slot) = obj_ptr.slot() {
+            self.handler
+                .free_slot(obj_ptr.offset(), slot, obj_ptr.generation());
+            CopyOnWriteEvent::Removed
+        } else {
+            let replica = self
+                .replicas
+                .lock()
+                .remove(obj_ptr.offset(), obj_ptr.generation());
+            if let Some(replica) = replica {
+                self.free_replica(replica);
+            }
+            let actual_size = self.pool.actual_size(
+                obj_ptr.offset().storage_class(),
+                obj_ptr.offset().disk_id(),
+                obj_ptr.allocated_size(),
+            );
             self.handler.copy_on_write(
                 obj_ptr.offset(),
                 actual_size,
                 obj_ptr.generation(),
                 obj_ptr.info(),
-            ),
-            &self.report_tx,
-            steal,
-        ) {
+            )
+        };
+        if let (CopyOnWriteEvent::Removed, Some(tx), CopyOnWriteReason::Remove) =
+            (event, &self.report_tx, steal)
+        {
             let _ = tx
                 .send(DmlMsg::remove(obj_ptr.offset(), obj_ptr.size(), pivot_key))
                 .map_err(|_| warn!("Channel Receiver has been dropped."));
@@ -220,25 +557,150 @@ where
     /// Fetches synchronously an object from disk and inserts it into the
     /// cache.
     fn fetch(&self, op: &<Self as Dml>::ObjectPointer, pivot_key: PivotKey) -> Result<(), Error> {
-        // FIXME: reuse decompression_state
         debug!("Fetching {op:?}");
-        let mut decompression_state = op.decompression_tag().new_decompression()?;
         let offset = op.offset();
         let generation = op.generation();
 
-        let compressed_data = self
-            .pool
-            .read(op.size(), op.offset(), op.checksum().clone())?;
-
-        let object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> = {
-            let data = decompression_state.decompress(compressed_data)?;
-            Object::unpack_at(op.offset(), op.info(), data.into_boxed_slice())?
+        let data = match self.cached_decompressed(op) {
+            Some(data) => data,
+            None => {
+                let data = self.read_for_fetch(op)?;
+                let compressed_data = self.unpack_slot(op, data)?;
+                self.decompress(op, compressed_data)?
+            }
+        };
+        let mut object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> =
+            Object::unpack_at(op.offset(), op.info(), data)?;
+        object.restore_storage_preference(op.system_storage_preference);
+        let key = ObjectKey::Unmodified {
+            offset,
+            slot: op.slot(),
+            generation,
         };
-        let key = ObjectKey::Unmodified { offset, generation };
         self.insert_object_into_cache(key, TaggedCacheValue::new(RwLock::new(object), pivot_key));
         Ok(())
     }
 
+    /// Reads the blocks of `op` for a synchronous fetch, from its replica if
+    /// there is one, and replicates the object if this has been requested.
+    fn read_for_fetch(&self, op: &<Self as Dml>::ObjectPointer) -> Result<Buf, Error> {
+        let offset = op.offset();
+        Ok(match self.read_replica(op) {
+            Some(data) => {
+                self.handler.io_accounting.physical_read(
+                    op.info(),
+                    0,
+                    op.size().to_bytes() as u64,
+                    NodeRead::Fetch,
+                );
+                data
+            }
+            None => {
+                let data = self.read_object(op)?;
+                self.account_read(op, NodeRead::Fetch);
+                if op.slot().is_none() && self.replicas.lock().take_request(offset) {
+                    if let Err(e) = self.write_replica(op, data.clone()) {
+                        warn!("Replicating {op:?} failed: {e}");
+                    }
+                }
+                data
+            }
+        })
+    }
+
+    /// Returns the decompressed data of `op` if it has been kept by the
+    /// decompressed cache.
+    fn cached_decompressed(&self, op: &<Self as Dml>::ObjectPointer) -> Option<Box<[u8]>> {
+        if !self.is_decompressed_cacheable(op) {
+            return None;
+        }
+        let data = self.decompressed.lock().get(
+            op.offset(),
+            op.slot(),
+            op.generation(),
+            op.checksum().clone(),
+        )?;
+        Some(Box::from(&data[..]))
+    }
+
+    /// Returns whether the decompressed data of `op` may be kept.  Without
+    /// a checksum, a block rewritten within the generation it has been freed
+    /// in would hit the entry of its previous contents.
+    fn is_decompressed_cacheable(&self, op: &<Self as Dml>::ObjectPointer) -> bool {
+        op.decompression_tag() != DecompressionTag::None
+            && self.pool.checksum_policy(op.offset().storage_class()) != ChecksumPolicy::Disabled
+    }
+
+    /// Decompresses the data of `op` and keeps a copy in the decompressed
+    /// cache if it has been compressed.
+    fn decompress(
+        &self,
+        op: &<Self as Dml>::ObjectPointer,
+        compressed_data: Buf,
+    ) -> Result<Box<[u8]>, Error> {
+        let data = op
+            .decompression_tag()
+            .new_decompression()?
+            .decompress(compressed_data)?
+            .into_boxed_slice();
+        if self.is_decompressed_cacheable(op) {
+            let shared = Arc::from(&data[..]);
+            self.decompressed.lock().insert(
+                op.offset(),
+                op.slot(),
+                op.generation(),
+                op.checksum().clone(),
+                shared,
+            );
+        }
+        Ok(data)
+    }
+
+    /// Reads all objects below `root` which have been written in the same
+    /// generation as `root`, bypassing the cache, and fails if any of them
+    /// does not match its checksum or can not be decoded.  Objects of older
+    /// generations have been completed by an earlier sync and are skipped.
+    pub fn verify_generation(&self, root: &<Self as Dml>::ObjectPointer) -> Result<(), Error> {
+        let generation = root.generation();
+        self.walk_objects(root, |op| op.generation() == generation)
+    }
+
+    /// Reads `root` and all objects below it, bypassing the cache, and fails
+    /// if any of them does not match its checksum or can not be decoded.
+    /// `visit` is called with every pointer before its object is read, an
+    /// object and the objects below it are skipped if it returns `false`.
+    pub fn walk_objects<F>(
+        &self,
+        root: &<Self as Dml>::ObjectPointer,
+        mut visit: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&<Self as Dml>::ObjectPointer) -> bool,
+    {
+        let mut pending = vec![root.clone()];
+        while let Some(op) = pending.pop() {
+            if !visit(&op) {
+                continue;
+            }
+            let data = self.read_object(&op)?;
+            let compressed_data = self.unpack_slot(&op, data)?;
+            let data = op
+                .decompression_tag()
+                .new_decompression()?
+                .decompress(compressed_data)?;
+            let mut object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> =
+                Object::unpack_at(op.offset(), op.info(), data.into_boxed_slice())
+                    .map_err(|_| Error::DeserializationError)?;
+            object.for_each_child::<Error, _>(|child| {
+                if let Some(ptr) = child.get_unmodified() {
+                    pending.push(ptr.clone());
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
     /// Fetches asynchronously an object from disk and inserts it into the
     /// cache.
     fn try_fetch_async(
@@ -260,17 +722,295 @@ where
 
         Ok(self
             .pool
-            .read_async(op.size(), op.offset(), op.checksum().clone())?
+            .read_async(op.size(), op.offset(), self.read_checksum(op))?
             .map_err(Error::from)
             .and_then(move |data| ok((ptr, data, pivot_key))))
     }
 
-    fn insert_object_into_cache(&self, key: ObjectKey<Generation>, mut object: E::Value) {
+    /// Reads the blocks of `op`, falling back to its second copy if the first
+    /// one can not be read or does not match its checksum.
+    fn read_object(&self, op: &<Self as Dml>::ObjectPointer) -> Result<Buf, Error> {
+        if let Some(data) = self.unwritten_slab(op) {
+            return Ok(data);
+        }
+        let err = match self
+            .pool
+            .read(op.size(), op.offset(), self.read_checksum(op))
+        {
+            Ok(data) => return Ok(data),
+            Err(err) => err,
+        };
+        match op.ditto_offset() {
+            Some(ditto_offset) => {
+                warn!("Reading {op:?} failed, trying its second copy: {err}");
+                Ok(self
+                    .pool
+                    .read(op.size(), ditto_offset, self.read_checksum(op))?)
+            }
+            None => Err(err.into()),
+        }
+    }
+
+    /// Reads both copies of `op` if it has been written twice, see
+    /// [Self::with_ditto_metadata], and fails if either of them can not be
+    /// read or does not match its checksum.  Regular reads only fall back to
+    /// the second copy, so they neither check it nor report the first one.
+    pub fn verify_copies(&self, op: &<Self as Dml>::ObjectPointer) -> Result<(), Error> {
+        if let Some(ditto_offset) = op.ditto_offset() {
+            for offset in [op.offset(), ditto_offset] {
+                self.pool.read(op.size(), offset, self.read_checksum(op))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the blocks of `op` from its replica, if there is one.  Replicas
+    /// which can not be read or do not match the checksum of `op` are dropped.
+    fn read_replica(&self, op: &<Self as Dml>::ObjectPointer) -> Option<Buf> {
+        let replica = self.replicas.lock().get(op.offset(), op.generation())?;
+        match self
+            .pool
+            .read(op.size(), replica.offset, op.checksum().clone())
+        {
+            Ok(data) => Some(data),
+            Err(err) => {
+                warn!("Reading the replica of {op:?} failed: {err}");
+                let replica = self.replicas.lock().remove(op.offset(), op.generation());
+                if let Some(replica) = replica {
+                    self.free_replica(replica);
+                }
+                None
+            }
+        }
+    }
+
+    /// Writes the blocks `data` of `op` to the fastest storage class.  Objects
+    /// whose checksums are disabled are not replicated, as their replicas
+    /// could not be verified.
+    fn write_replica(&self, op: &<Self as Dml>::ObjectPointer, data: Buf) -> Result<(), Error> {
+        let class = op.offset().storage_class();
+        if class == 0
+            || self.pool.checksum_policy(class) == ChecksumPolicy::Disabled
+            || self.out_of_space(StoragePreference::FASTEST).is_some()
+            || self.write_budgets.is_exhausted(0)
+            || self
+                .replicas
+                .lock()
+                .get(op.offset(), op.generation())
+                .is_some()
+        {
+            return Ok(());
+        }
+        let replica = Replica {
+            offset: self.allocate(0, op.size(), None)?,
+            size: op.size(),
+            generation: op.generation(),
+        };
+        self.handler.record_replica(
+            replica.offset,
+            self.pool.actual_size(
+                replica.offset.storage_class(),
+                replica.offset.disk_id(),
+                replica.size,
+            ),
+        );
+        // Allocations may fall back to slower classes.
+        if replica.offset.storage_class() != 0 {
+            self.free_replica(replica);
+            return Ok(());
+        }
+        if let Err(err) = self.pool.begin_write(data, replica.offset) {
+            self.free_replica(replica);
+            return Err(err.into());
+        }
+        self.handler
+            .io_accounting
+            .physical_write(op.info(), 0, op.size().to_bytes() as u64);
+        self.write_budgets.record(0, op.size().to_bytes() as u64);
+        let old = self.replicas.lock().insert(op.offset(), replica);
+        if let Some(old) = old {
+            self.free_replica(old);
+        }
+        Ok(())
+    }
+
+    fn free_replica(&self, replica: Replica) {
+        let offset = replica.offset;
+        let size = self
+            .pool
+            .actual_size(offset.storage_class(), offset.disk_id(), replica.size);
+        self.free_replica_blocks(offset, size);
+    }
+
+    /// Frees the `size` allocated blocks of the replica at `offset`, which
+    /// may have been left over when the pool has been closed.
+    pub(crate) fn free_replica_blocks(&self, offset: DiskOffset, size: Block<u32>) {
+        if let Err(err) =
+            self.handler
+                .update_allocation_bitmap(offset, size, Action::Deallocate, self)
+        {
+            warn!("Freeing the replica at {offset:?} failed: {err}");
+            return;
+        }
+        self.handler.forget_replica(offset);
+    }
+
+    /// Requests a replica of the object at `offset` on the fastest storage
+    /// class, which is written when the object is fetched the next time, see
+    /// [super::replica].  Returns whether the object has neither been
+    /// replicated nor requested before.  Objects on the fastest class are not
+    /// replicated.
+    pub fn request_replica(&self, offset: DiskOffset) -> bool {
+        offset.storage_class() != 0 && self.replicas.lock().request(offset)
+    }
+
+    /// Drops the oldest replicas until at least `desired` blocks have been
+    /// freed, returns the number of freed blocks.
+    pub fn drop_replicas(&self, desired: Block<u64>) -> Block<u64> {
+        let mut freed = Block(0);
+        while freed < desired {
+            let replica = self.replicas.lock().pop_oldest();
+            match replica {
+                Some(replica) => {
+                    freed += replica.size.as_u64();
+                    self.free_replica(replica);
+                }
+                None => break,
+            }
+        }
+        freed
+    }
+
+    /// Returns the number of blocks occupied by replicas.
+    pub fn replicated_size(&self) -> Block<u64> {
+        self.replicas.lock().size()
+    }
+
+    /// Returns the bytes written to `class` on the current day and its daily
+    /// budget.
+    pub fn write_budget_info(&self, class: u8) -> WriteBudgetInfo {
+        self.write_budgets.info(class)
+    }
+
+    /// Returns whether the daily write budget of `class` has been used up.
+    pub fn write_budget_exhausted(&self, class: u8) -> bool {
+        self.write_budgets.is_exhausted(class)
+    }
+
+    /// Returns the write budget counters of the current day packed for the
+    /// root tree.
+    pub(crate) fn pack_write_budgets(&self) -> [u8; write_budget::PACKED_LEN] {
+        self.write_budgets.pack()
+    }
+
+    /// Restores write budget counters returned by
+    /// [Dmu::pack_write_budgets].
+    pub(crate) fn unpack_write_budgets(&self, buf: &[u8]) {
+        self.write_budgets.unpack(buf)
+    }
+
+    /// Returns the checksum to verify the blocks of `op` with.  The blocks of
+    /// packed objects are not verified as a whole, but the object itself, see
+    /// [Self::unpack_slot].
+    fn read_checksum(&self, op: &<Self as Dml>::ObjectPointer) -> SPL::Checksum {
+        match op.slot() {
+            Some(_) => <SPL::Checksum as Checksum>::unchecked(),
+            None => op.checksum().clone(),
+        }
+    }
+
+    /// Extracts the object of `op` from the `data` read from its blocks.
+    fn unpack_slot(&self, op: &<Self as Dml>::ObjectPointer, data: Buf) -> Result<Buf, Error> {
+        let slot = match op.slot() {
+            Some(slot) => slot,
+            None => return Ok(data),
+        };
+        let object = slab::slot(&data, slot).ok_or_else(|| VdevError::from(ChecksumError))?;
+        op.checksum().verify(object).map_err(VdevError::from)?;
+        Ok(slab::pad(object))
+    }
+
+    /// Packs the compressed `data` of an object written in `generation` into
+    /// the slab block of `storage_class`, returns the offset of the block and
+    /// the slot of the object.  The previous slab block is written once it is
+    /// full or of a past generation.
+    fn write_packed(
+        &self,
+        storage_class: u8,
+        generation: Generation,
+        data: &[u8],
+    ) -> Result<(DiskOffset, u8), Error> {
+        let mut slabs = self.slabs.lock();
+        let current = &mut slabs[storage_class as usize];
+        loop {
+            if let Some((slab_generation, slab)) = current.as_mut() {
+                if *slab_generation == generation {
+                    if let Some(slot) = slab.push(data) {
+                        self.handler.allocate_slot(slab.offset(), slot);
+                        return Ok((slab.offset(), slot));
+                    }
+                }
+                self.pool.begin_write(slab.encode(), slab.offset())?;
+            }
+            let offset = self.allocate(storage_class, Block(1), None)?;
+            *current = Some((generation, Slab::new(offset)));
+        }
+    }
+
+    /// Writes the slab blocks which are still being filled.  This has to be
+    /// done before a superblock is written, which may refer to objects packed
+    /// into them.
+    pub(crate) fn write_slabs(&self) -> Result<(), Error> {
+        let mut slabs = self.slabs.lock();
+        for current in slabs.iter_mut() {
+            if let Some((_, slab)) = current.as_ref() {
+                self.pool.begin_write(slab.encode(), slab.offset())?;
+            }
+            *current = None;
+        }
+        Ok(())
+    }
+
+    /// Returns the slab block `op` is packed into, if it has not been written
+    /// yet.
+    fn unwritten_slab(&self, op: &<Self as Dml>::ObjectPointer) -> Option<Buf> {
+        op.slot()?;
+        self.slabs.lock()[op.offset().storage_class() as usize]
+            .as_ref()
+            .filter(|(_, slab)| slab.offset() == op.offset())
+            .map(|(_, slab)| slab.encode())
+    }
+
+    /// Inserts a fetched object into the cache, returns whether it has not
+    /// been cached already.
+    fn insert_object_into_cache(&self, key: ObjectKey<Generation>, mut object: E::Value) -> bool {
         let size = object.value_mut().get_mut().size();
-        let mut cache = self.cache.write();
-        if !cache.contains_key(&key) {
-            cache.insert(key, object, size);
+        // The entry is inserted into the local shard, all others are locked
+        // as well so that it is not inserted into another one meanwhile.
+        let (before, rest) = self.caches.split_at(self.numa.current_shard());
+        let (cache, after) = rest.split_first().unwrap();
+        let before: Vec<_> = before.iter().map(|other| other.read()).collect();
+        let mut cache = cache.write();
+        let after: Vec<_> = after.iter().map(|other| other.read()).collect();
+        if cache.contains_key(&key)
+            || before
+                .iter()
+                .chain(&after)
+                .any(|other| other.contains_key(&key))
+        {
+            return false;
         }
+        cache.insert_fetched(key, object, size);
+        true
+    }
+
+    fn account_read(&self, op: &<Self as Dml>::ObjectPointer, kind: NodeRead) {
+        self.handler.io_accounting.physical_read(
+            op.info(),
+            op.offset().storage_class(),
+            op.size().to_bytes() as u64,
+            kind,
+        );
     }
 
     fn evict(&self, mut cache: RwLockWriteGuard<E>) -> Result<(), Error> {
@@ -329,7 +1069,17 @@ where
 
         let mid = match key {
             ObjectKey::InWriteback(_) => unreachable!(),
-            ObjectKey::Unmodified { .. } => return Ok(()),
+            ObjectKey::Unmodified { offset, .. } => {
+                self.events.emit(|| NodeEvent {
+                    kind: NodeEventKind::Evicted,
+                    dataset: object.tag().d_id(),
+                    size: object.value_mut().get_mut().size() as u64,
+                    storage_class: Some(offset.storage_class()),
+                    level: None,
+                    duration: None,
+                });
+                return Ok(());
+            }
             ObjectKey::Modified(mid) => mid,
         };
 
@@ -339,18 +1089,69 @@ where
 
         let pk = entry.tag().clone();
         drop(cache);
-        let object = CacheValueRef::write(entry);
+        let object = CacheValueRef::write(entry, self.dirty_size);
 
-        self.handle_write_back(object, mid, true, pk)?;
+        self.handle_write_back(object, mid, true, pk, None)?;
         Ok(())
     }
 
+    /// Drops the unmodified cache entries whose dataset matches `filter`.
+    fn drop_unmodified<F: Fn(Option<DatasetId>) -> bool>(&self, filter: F) {
+        for cache in self.caches.iter() {
+            let mut cache = cache.write();
+            let keys: Vec<_> = cache
+                .iter()
+                .cloned()
+                .filter(|key| {
+                    matches!(key, ObjectKey::Unmodified { .. }) && filter(cache.dataset(key))
+                })
+                .collect();
+            for key in keys {
+                let removed = cache.remove(&key, EvictionReason::Dropped, |obj| obj.size());
+                if let (Ok(mut object), ObjectKey::Unmodified { offset, .. }) = (removed, key) {
+                    self.events.emit(|| NodeEvent {
+                        kind: NodeEventKind::Evicted,
+                        dataset: object.tag().d_id(),
+                        size: object.value_mut().get_mut().size() as u64,
+                        storage_class: Some(offset.storage_class()),
+                        level: None,
+                        duration: None,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Removes the cache entry of a freed object from the shard holding it.
+    fn remove_freed(&self, or: &<Self as Dml>::ObjectRef) -> Option<E::Value> {
+        let key = or.as_key();
+        for shard in self.shards_of(&key) {
+            let mut cache = self.caches[shard].write();
+            let size = cache.size_of(&key);
+            match cache.remove(&key, EvictionReason::Freed, |obj| obj.size()) {
+                Ok(obj) => {
+                    if !matches!(or, ObjRef::Unmodified(..)) {
+                        self.sub_dirty_size(size.unwrap());
+                    }
+                    return Some(obj);
+                }
+                Err(RemoveError::NotPresent) => {}
+                // TODO
+                Err(RemoveError::Pinned) => unimplemented!(),
+            }
+        }
+        None
+    }
+
+    /// Writes back `object`.  If given, the object is placed after the block
+    /// at `near` if possible, which holds a related object.
     fn handle_write_back(
         &self,
         mut object: <Self as Dml>::CacheValueRefMut,
         mid: ModifiedObjectId,
         evict: bool,
         pivot_key: PivotKey,
+        near: Option<DiskOffset>,
     ) -> Result<<Self as Dml>::ObjectPointer, Error> {
         let object_size = {
             #[cfg(debug_assertions)]
@@ -369,23 +1170,38 @@ where
         }
 
         debug!("Estimated object size is {object_size} bytes");
-        debug!("Using compression {:?}", &self.default_compression);
         let generation = self.handler.current_generation();
         // Use storage hints if available
-        if let Some(pref) = self.storage_hints.lock().remove(&pivot_key) {
+        if let Some(pref) = self.storage_hints.lock().take(&pivot_key, generation) {
             object.set_system_storage_preference(pref);
         }
         let storage_preference = object.correct_preference();
         let storage_class = storage_preference
             .preferred_class()
             .unwrap_or(self.default_storage_class);
-
-        let compression = &self.default_compression;
+        let ditto = self.ditto_metadata
+            && self.handler.format_version.load(Ordering::Acquire) >= DITTO_VERSION
+            && (pivot_key.d_id() == ROOT_DATASET_ID
+                || matches!(pivot_key, PivotKey::Root(_))
+                || object.level() >= DITTO_MIN_LEVEL)
+            && self.without_redundancy(storage_class);
+
+        let compression = match &self.cold_compression {
+            Some((class, compression)) if *class == storage_class => compression,
+            _ => &self.default_compression,
+        };
+        debug!("Using compression {:?}", compression);
+        // Pools of older format versions can not read preferences from pointers.
+        let mut system_storage_preference = StoragePreference::NONE;
         let compressed_data = {
             // FIXME: cache this
             let mut state = compression.new_compression()?;
             let mut buf = crate::buffer::BufWrite::with_capacity(Block(128));
             {
+                if self.handler.format_version.load(Ordering::Acquire) >= POINTER_PREFERENCE_VERSION
+                {
+                    system_storage_preference = object.pointer_storage_preference();
+                }
                 object.pack(&mut buf)?;
                 drop(object);
             }
@@ -393,28 +1209,73 @@ where
         };
 
         assert!(compressed_data.len() <= u32::max_value() as usize);
-        let size = compressed_data.len();
-        debug!("Compressed object size is {size} bytes");
-        let size = Block(((size + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32);
-        assert!(size.to_bytes() as usize >= compressed_data.len());
-        let offset = self.allocate(storage_class, size)?;
-        assert_eq!(size.to_bytes() as usize, compressed_data.len());
-        /*if size.to_bytes() as usize != compressed_data.len() {
-            let mut v = compressed_data.into_vec();
-            v.resize(size.to_bytes() as usize, 0);
-            compressed_data = v.into_boxed_slice();
-        }*/
+
+        // Small nodes of the root tree are packed into shared blocks.  The
+        // root node is not, so that the final write of a sync, after which the
+        // root tree is not updated anymore, never occupies an unrecorded slot.
+        // Nodes which are written twice are not packed.
+        let packable = self.handler.format_version.load(Ordering::Acquire) >= SLAB_VERSION
+            && !ditto
+            && pivot_key.d_id() == ROOT_DATASET_ID
+            && !matches!(pivot_key, PivotKey::Root(_));
+        let packed = Some(slab::trim(&compressed_data))
+            .filter(|data| packable && data.len() <= MAX_PACKED_SIZE);
+
+        let (offset, size, slot, written) = match packed {
+            Some(data) => {
+                debug!("Packing object of {} bytes", data.len());
+                let (offset, slot) = self.write_packed(storage_class, generation, data)?;
+                (offset, Block(1), Some(slot), data)
+            }
+            None => {
+                let size = compressed_data.len();
+                debug!("Compressed object size is {size} bytes");
+                let size = Block(((size + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32);
+                assert!(size.to_bytes() as usize >= compressed_data.len());
+                let copies = if ditto { 2 } else { 1 };
+                let offset = self.allocate(storage_class, size * copies, near)?;
+                assert_eq!(size.to_bytes() as usize, compressed_data.len());
+                /*if size.to_bytes() as usize != compressed_data.len() {
+                    let mut v = compressed_data.into_vec();
+                    v.resize(size.to_bytes() as usize, 0);
+                    compressed_data = v.into_boxed_slice();
+                }*/
+                (offset, size, None, compressed_data.as_ref())
+            }
+        };
 
         let info = self.modified_info.lock().remove(&mid).unwrap();
 
-        let checksum = {
-            let mut state = self.default_checksum_builder.build();
-            state.ingest(compressed_data.as_ref());
-            state.finish()
+        let checksum = match self.pool.checksum_policy(offset.storage_class()) {
+            ChecksumPolicy::Full => {
+                let mut state = self.default_checksum_builder.build();
+                state.ingest(written);
+                state.finish()
+            }
+            ChecksumPolicy::Disabled => <SPL::Checksum as Checksum>::unchecked(),
         };
 
-        self.pool.begin_write(compressed_data, offset)?;
+        // Packed objects only take their share of the slab block.
+        let written_bytes = match slot {
+            Some(_) => written.len() as u64,
+            None if ditto => (size * 2).to_bytes() as u64,
+            None => size.to_bytes() as u64,
+        };
+        self.handler
+            .io_accounting
+            .physical_write(info, storage_class, written_bytes);
+        self.write_budgets
+            .record(offset.storage_class(), written_bytes);
+        if slot.is_none() {
+            if ditto {
+                self.pool
+                    .begin_write(compressed_data.clone(), ditto_offset(offset, size))?;
+            }
+            self.pool.begin_write(compressed_data, offset)?;
+        }
 
+        // Pools of older format versions can not read the version from pointers.
+        let format_version = self.handler.format_version.load(Ordering::Acquire);
         let obj_ptr = ObjectPointer {
             offset,
             size,
@@ -422,20 +1283,36 @@ where
             decompression_tag: compression.decompression_tag(),
             generation,
             info,
+            system_storage_preference,
+            slot,
+            ditto,
+            format_version: if format_version >= VERSIONED_POINTER_VERSION {
+                format_version as u8
+            } else {
+                0
+            },
         };
 
         let was_present;
         {
-            let mut cache = self.cache.write();
+            let mut cache = self.modified_cache().write();
+            if let Some(size) = cache.size_of(&ObjectKey::InWriteback(mid)) {
+                self.sub_dirty_size(size);
+            }
             // We can safely ignore pins.
             // If it's pinned, it must be a readonly request.
             was_present = if evict {
-                cache.force_remove(&ObjectKey::InWriteback(mid), object_size)
+                cache.force_remove(
+                    &ObjectKey::InWriteback(mid),
+                    object_size,
+                    EvictionReason::WriteBack,
+                )
             } else {
                 cache.force_change_key(
                     &ObjectKey::InWriteback(mid),
                     ObjectKey::Unmodified {
                         offset: obj_ptr.offset(),
+                        slot: obj_ptr.slot(),
                         generation: obj_ptr.generation(),
                     },
                 )
@@ -445,6 +1322,24 @@ where
                 self.written_back.lock().insert(mid, obj_ptr.clone());
             }
         }
+        self.events.emit(|| NodeEvent {
+            kind: NodeEventKind::WrittenBack,
+            dataset: info,
+            size: size.to_bytes() as u64,
+            storage_class: Some(offset.storage_class()),
+            level: None,
+            duration: None,
+        });
+        if evict && was_present {
+            self.events.emit(|| NodeEvent {
+                kind: NodeEventKind::Evicted,
+                dataset: info,
+                size: object_size as u64,
+                storage_class: Some(offset.storage_class()),
+                level: None,
+                duration: None,
+            });
+        }
 
         if !was_present {
             // The object has been `stolen`.  Notify the handler.
@@ -470,7 +1365,12 @@ where
         Ok(obj_ptr)
     }
 
-    fn allocate(&self, storage_preference: u8, size: Block<u32>) -> Result<DiskOffset, Error> {
+    fn allocate(
+        &self,
+        storage_preference: u8,
+        size: Block<u32>,
+        near: Option<DiskOffset>,
+    ) -> Result<DiskOffset, Error> {
         assert!(storage_preference < NUM_STORAGE_CLASSES as u8);
         if size >= Block(2048) {
             warn!("Very large allocation requested: {:?}", size);
@@ -482,10 +1382,26 @@ where
             size
         );
 
+        // Classes whose write budget is used up are only tried last.
+        let exhausted: [bool; NUM_STORAGE_CLASSES] =
+            std::array::from_fn(|class| self.write_budgets.is_exhausted(class as u8));
         let strategy = self.alloc_strategy[storage_preference as usize];
+        let strategy = strategy.iter().flatten().copied();
+        let classes = strategy
+            .clone()
+            .filter(|&class| !exhausted[class as usize])
+            .chain(strategy.filter(|&class| exhausted[class as usize]));
+
+        // Older format versions can not address more than 1024 disks per class.
+        let max_disks =
+            if self.handler.format_version.load(Ordering::Acquire) >= WIDE_DISK_ID_VERSION {
+                MAX_DISKS_PER_CLASS
+            } else {
+                LEGACY_MAX_DISKS_PER_CLASS
+            };
 
-        'class: for &class in strategy.iter().flatten() {
-            let disks_in_class = self.pool.disk_count(class);
+        'class: for class in classes {
+            let disks_in_class = self.pool.disk_count(class).min(max_disks as u16);
             if disks_in_class == 0 {
                 continue;
             }
@@ -506,30 +1422,60 @@ where
                 continue;
             }
 
-            let start_disk_id = (self.next_disk_id.fetch_add(1, Ordering::Relaxed)
-                % u64::from(disks_in_class)) as u16;
-            let disk_id = (start_disk_id..disks_in_class)
-                .chain(0..start_disk_id)
-                .max_by_key(|&disk_id| {
-                    self.pool.effective_free_size(
-                        class,
-                        disk_id,
-                        self.handler
-                            .free_space_disk(DiskOffset::construct_disk_id(class, disk_id))
-                            .expect("We can be sure that this disk id exists.")
-                            .free,
-                    )
-                })
-                .unwrap();
+            // Related objects are kept on the same disk, so that they can be
+            // read sequentially.
+            let near = near
+                .filter(|near| near.storage_class() == class && near.disk_id() < disks_in_class);
+            let disk_id = match near {
+                Some(near) => near.disk_id(),
+                None => {
+                    let start_disk_id = (self.next_disk_id.fetch_add(1, Ordering::Relaxed)
+                        % u64::from(disks_in_class)) as u16;
+                    (start_disk_id..disks_in_class)
+                        .chain(0..start_disk_id)
+                        .max_by_key(|&disk_id| {
+                            self.pool.effective_free_size(
+                                class,
+                                disk_id,
+                                self.handler
+                                    .free_space_disk(DiskOffset::construct_disk_id(class, disk_id))
+                                    .expect("We can be sure that this disk id exists.")
+                                    .free,
+                            )
+                        })
+                        .unwrap()
+                }
+            };
             let size = self.pool.actual_size(class, disk_id, size);
             let disk_size = self.pool.size_in_blocks(class, disk_id);
 
-            let disk_offset = {
-                let mut last_seg_id = self.allocation_data[class as usize][disk_id as usize].lock();
+            // Held for the near attempt as well, so that it does not race with
+            // the allocations on this disk below.
+            let shard = self.numa.current_shard();
+            let mut last_seg_id =
+                self.allocation_data[shard][class as usize][disk_id as usize].lock();
+            let near_offset = match near {
+                Some(near) => {
+                    let segment_id = SegmentId::get(near);
+                    self.handler
+                        .get_allocation_bitmap(segment_id, self)?
+                        .access()
+                        .allocate_near(size.as_u32(), SegmentId::get_block_offset(near))
+                        .map(|segment_offset| segment_id.disk_offset(segment_offset))
+                }
+                None => None,
+            };
+
+            let disk_offset = if let Some(disk_offset) = near_offset {
+                disk_offset
+            } else {
                 let segment_id = if last_seg_id.is_some() {
                     last_seg_id.as_mut().unwrap()
                 } else {
-                    let segment_id = SegmentId::get(DiskOffset::new(class, disk_id, Block(0)));
+                    // Start each shard in a different region of the disk, so
+                    // that shards rarely contend for the same segment.
+                    let start = disk_size.as_u64() * shard as u64 / self.numa.shards() as u64;
+                    let segment_id = SegmentId::get(DiskOffset::new(class, disk_id, Block(start)));
                     *last_seg_id = Some(segment_id);
                     last_seg_id.as_mut().unwrap()
                 };
@@ -563,6 +1509,7 @@ where
                     *segment_id = next_segment_id;
                 }
             };
+            drop(last_seg_id);
 
             info!("Allocated {:?} at {:?}", size, disk_offset);
             debug!(
@@ -579,7 +1526,9 @@ where
             "No available layer can provide enough free storage {:?}",
             size
         );
-        Err(Error::OutOfSpaceError)
+        Err(Error::OutOfSpaceError {
+            class: storage_preference,
+        })
     }
 
     /// Tries to allocate `size` blocks at `disk_offset`.  Might fail if
@@ -589,8 +1538,9 @@ where
         let num_disks = self.pool.num_disks(disk_offset.storage_class(), disk_id);
         let size = size * num_disks as u32;
         let segment_id = SegmentId::get(disk_offset);
-        let mut x =
-            self.allocation_data[disk_offset.storage_class() as usize][disk_id as usize].lock();
+        let mut x = self.allocation_data[self.numa.current_shard()]
+            [disk_offset.storage_class() as usize][disk_id as usize]
+            .lock();
         let allocator = self.handler.get_allocation_bitmap(segment_id, self)?;
         if allocator
             .access()
@@ -621,7 +1571,7 @@ where
         trace!("prepare_write_back: Enter");
         loop {
             // trace!("prepare_write_back: Trying to acquire cache write lock");
-            let mut cache = self.cache.write();
+            let mut cache = self.modified_cache().write();
             // trace!("prepare_write_back: Acquired");
             if cache.contains_key(&ObjectKey::InWriteback(mid)) {
                 // TODO wait
@@ -660,7 +1610,7 @@ where
                 Ok(()) => Ok(Some(
                     cache
                         .get(&ObjectKey::InWriteback(mid), false)
-                        .map(CacheValueRef::write)
+                        .map(|entry| CacheValueRef::write(entry, self.dirty_size))
                         .unwrap(),
                 )),
                 Err(ChangeKeyError::NotPresent) => Ok(None),
@@ -698,36 +1648,27 @@ where
     }
 
     fn try_get(&self, or: &Self::ObjectRef) -> Option<Self::CacheValueRef> {
-        let result = {
-            // Drop order important
-            let cache = self.cache.read();
-            cache.get(&or.as_key(), false)
-        };
-        result.map(CacheValueRef::read)
+        self.cache_get(&or.as_key(), false).map(CacheValueRef::read)
     }
 
     fn try_get_mut(&self, or: &Self::ObjectRef) -> Option<Self::CacheValueRefMut> {
         if let ObjRef::Modified(..) = *or {
             let result = {
-                let cache = self.cache.read();
+                let cache = self.modified_cache().read();
                 cache.get(&or.as_key(), true)
             };
-            result.map(CacheValueRef::write)
+            result.map(|entry| CacheValueRef::write(entry, self.dirty_size))
         } else {
             None
         }
     }
 
     fn get(&self, or: &mut Self::ObjectRef) -> Result<Self::CacheValueRef, Error> {
-        let mut cache = self.cache.read();
         loop {
-            if let Some(entry) = cache.get(&or.as_key(), true) {
-                drop(cache);
+            if let Some(entry) = self.cache_get(&or.as_key(), true) {
                 return Ok(CacheValueRef::read(entry));
             }
             if let ObjRef::Unmodified(ref ptr, ref pk) = *or {
-                drop(cache);
-
                 self.fetch(ptr, pk.clone())?;
                 if let Some(report_tx) = &self.report_tx {
                     let _ = report_tx
@@ -736,12 +1677,15 @@ where
                 }
                 // Check if any storage hints are available and update the node.
                 // This moves the object reference into the modified state.
-                if let Some(pref) = self.storage_hints.lock().remove(pk) {
+                let hint = self
+                    .storage_hints
+                    .lock()
+                    .take(pk, self.handler.current_generation());
+                if let Some(pref) = hint {
                     if let Some(mut obj) = self.steal(or, ptr.info())? {
                         obj.set_system_storage_preference(pref)
                     }
                 }
-                cache = self.cache.read();
             } else {
                 self.fix_or(or);
             }
@@ -776,7 +1720,8 @@ where
         self.modified_info.lock().insert(mid, info);
         let key = ObjectKey::Modified(mid);
         let size = object.size();
-        self.cache.write().insert(
+        self.add_dirty_size(size);
+        self.modified_cache().write().insert(
             key,
             TaggedCacheValue::new(RwLock::new(object), pk.clone()),
             size,
@@ -797,8 +1742,9 @@ where
         self.modified_info.lock().insert(mid, info);
         let key = ObjectKey::Modified(mid);
         let size = object.size();
+        self.add_dirty_size(size);
         let entry = {
-            let mut cache = self.cache.write();
+            let mut cache = self.modified_cache().write();
             cache.insert(
                 key,
                 TaggedCacheValue::new(RwLock::new(object), pk.clone()),
@@ -806,15 +1752,15 @@ where
             );
             cache.get(&key, false).unwrap()
         };
-        (CacheValueRef::write(entry), ObjRef::Modified(mid, pk))
+        (
+            CacheValueRef::write(entry, self.dirty_size),
+            ObjRef::Modified(mid, pk),
+        )
     }
 
     fn remove(&self, or: Self::ObjectRef) {
-        match self.cache.write().remove(&or.as_key(), |obj| obj.size()) {
-            Ok(_) | Err(RemoveError::NotPresent) => {}
-            // TODO
-            Err(RemoveError::Pinned) => unimplemented!(),
-        };
+        self.remove_freed(&or);
+        self.storage_hints.lock().remove(or.index());
         if let ObjRef::Unmodified(ref ptr, ..) = or {
             self.copy_on_write(ptr.clone(), CopyOnWriteReason::Remove, or.index().clone());
         }
@@ -826,12 +1772,9 @@ where
     ) -> Result<Node<ObjRef<ObjectPointer<SPL::Checksum>>>, Error> {
         let obj = loop {
             self.get(&mut or)?;
-            match self.cache.write().remove(&or.as_key(), |obj| obj.size()) {
-                Ok(obj) => break obj,
-                Err(RemoveError::NotPresent) => {}
-                // TODO
-                Err(RemoveError::Pinned) => unimplemented!(),
-            };
+            if let Some(obj) = self.remove_freed(&or) {
+                break obj;
+            }
         };
         if let ObjRef::Unmodified(ref ptr, ..) = or {
             self.copy_on_write(ptr.clone(), CopyOnWriteReason::Remove, or.index().clone());
@@ -839,21 +1782,101 @@ where
         Ok(obj.into_value().into_inner())
     }
 
+    fn hint_storage_preference(&self, or: &Self::ObjectRef, pref: StoragePreference) {
+        let class = match or {
+            ObjRef::Unmodified(ptr, ..) => ptr.offset().storage_class(),
+            _ => return,
+        };
+        if pref
+            .preferred_class()
+            .map_or(false, |target| target < class)
+        {
+            self.storage_hints.lock().insert(
+                or.index().clone(),
+                pref,
+                HintSource::Application,
+                self.handler.current_generation(),
+            );
+        }
+    }
+
     fn root_ref_from_ptr(r: Self::ObjectPointer) -> Self::ObjectRef {
         Self::ObjectRef::root_ref_from_obj_ptr(r)
     }
 
     fn evict(&self) -> Result<(), Error> {
         // TODO shortcut without locking cache
-        let cache = self.cache.write();
-        if cache.size() > cache.capacity() {
+        if let Some(cache) = self.overfull_cache() {
             self.evict(cache)?;
         }
         Ok(())
     }
 
-    fn verify_cache(&self) {
-        self.cache.write().verify();
+    fn emit_node_event<F: FnOnce() -> NodeEvent>(&self, event: F) {
+        self.events.emit(event)
+    }
+
+    fn verify_cache(&self) -> CacheReport {
+        let mut caches: Vec<_> = self.caches.iter().map(|cache| cache.write()).collect();
+        for cache in caches.iter_mut() {
+            cache.verify();
+        }
+        let modified = &caches[0];
+        let written_back = self.written_back.lock();
+        let mut report = CacheReport::default();
+        for cache in caches.iter() {
+            let keys: Vec<_> = cache.iter().cloned().collect();
+            for key in keys {
+                let entry = cache.peek(&key).unwrap();
+                let dataset = entry.tag().d_id();
+                // Nodes locked for modification may be inconsistent for now.
+                let node = match entry.value().try_read() {
+                    Some(node) => node,
+                    None => {
+                        report.skipped += 1;
+                        continue;
+                    }
+                };
+                if let Err((recorded, actual)) = node.checked_size() {
+                    report.problems.push(CacheProblem::SizeMismatch {
+                        dataset,
+                        recorded,
+                        actual,
+                    });
+                }
+                let mut problems = Vec::new();
+                let complete = node.try_for_each_child_ref(|child| {
+                    let found = match *child {
+                        ObjRef::Unmodified(..) | ObjRef::Incomplete(..) => return,
+                        ObjRef::Modified(mid, _) => {
+                            modified.contains_key(&ObjectKey::Modified(mid))
+                                || modified.contains_key(&ObjectKey::InWriteback(mid))
+                                || written_back.contains_key(&mid)
+                        }
+                        ObjRef::InWriteback(mid, _) => {
+                            modified.contains_key(&ObjectKey::InWriteback(mid))
+                                || written_back.contains_key(&mid)
+                        }
+                    };
+                    if !found {
+                        problems.push(CacheProblem::DanglingChild { dataset });
+                    }
+                    if let ObjectKey::Unmodified { .. } = key {
+                        problems.push(CacheProblem::ModifiedChildOfUnmodified { dataset });
+                    }
+                });
+                if complete {
+                    report.checked += 1;
+                    report.problems.append(&mut problems);
+                } else {
+                    report.skipped += 1;
+                }
+            }
+        }
+        for problem in &report.problems {
+            log::error!("Cache verification failed: {:?}", problem);
+        }
+        report
     }
 
     /// Trigger a write back of an entire subtree.  This is intended for use
@@ -867,6 +1890,8 @@ where
         FO: DerefMut<Target = Self::ObjectRef>,
     {
         trace!("write_back: Enter");
+        // The last written child, after which the next object is placed.
+        let mut near = None;
         let (object, mid, mid_pk) = loop {
             trace!("write_back: Trying to acquire lock");
             let mut or = acquire_or_lock();
@@ -893,17 +1918,20 @@ where
                             Ok(None) => {}
                             Ok(Some(object)) => {
                                 trace!("write_back: Was Ok Some");
-                                self.handle_write_back(object, mid, false, mid_pk).map_err(
-                                    |err| {
-                                        let mut cache = self.cache.write();
+                                let ptr = self
+                                    .handle_write_back(object, mid, false, mid_pk, near)
+                                    .map_err(|err| {
+                                        let mut cache = self.modified_cache().write();
                                         let _ = cache.change_key::<(), _>(
                                             &ObjectKey::InWriteback(mid),
                                             // Has to have been in the modified state before
                                             |_, _, _| Ok(ObjectKey::Modified(mid)),
                                         );
                                         err
-                                    },
-                                )?;
+                                    })?;
+                                if ptr.slot().is_none() {
+                                    near = Some(ptr.offset());
+                                }
                             }
                             Err(()) => continue,
                         };
@@ -914,43 +1942,61 @@ where
         };
         trace!("write_back: Leave");
 
-        self.handle_write_back(object, mid, false, mid_pk)
+        self.handle_write_back(object, mid, false, mid_pk, near)
     }
 
-    type Prefetch = Pin<
-        Box<
-            dyn Future<Output = Result<(<Self as Dml>::ObjectPointer, Buf, PivotKey), Error>>
-                + Send
-                + 'static,
-        >,
-    >;
+    type Prefetch = Prefetch<Result<(<Self as Dml>::ObjectPointer, Buf, PivotKey), Error>>;
     fn prefetch(&self, or: &Self::ObjectRef) -> Result<Option<Self::Prefetch>, Error> {
-        if self.cache.read().contains_key(&or.as_key()) {
+        if self.cache_contains_key(&or.as_key()) {
             return Ok(None);
         }
         Ok(match *or {
             ObjRef::Modified(..) | ObjRef::InWriteback(..) => None,
+            // Only synchronous fetches fall back to the second copy of an
+            // object, so these are fetched on access.
+            ObjRef::Unmodified(ref p, _) if p.ditto_offset().is_some() => None,
+            // Objects in slab blocks which have not been written yet are read
+            // from memory.
+            ObjRef::Unmodified(ref p, _) if self.unwritten_slab(p).is_some() => None,
             ObjRef::Unmodified(ref p, ref pk) => {
-                Some(Box::pin(self.try_fetch_async(p, pk.clone())?.into_future()))
+                // Prefetching is only a hint, if too many are in flight the
+                // object is fetched on access instead.
+                let slot = match self.prefetch_queue.try_acquire() {
+                    Some(slot) => slot,
+                    None => {
+                        trace!("Prefetch queue is full, skipping prefetch");
+                        return Ok(None);
+                    }
+                };
+                let future = self.try_fetch_async(p, pk.clone())?.into_future();
+                self.account_read(p, NodeRead::Prefetch);
+                Some(Prefetch::new(Box::pin(future), slot))
             }
             ObjRef::Incomplete(..) => unreachable!(),
         })
     }
 
     fn finish_prefetch(&self, p: Self::Prefetch) -> Result<(), Error> {
-        let (ptr, compressed_data, pk) = block_on(p)?;
-        let object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> = {
-            let data = ptr
-                .decompression_tag()
-                .new_decompression()?
-                .decompress(compressed_data)?;
-            Object::unpack_at(ptr.offset(), ptr.info(), data.into_boxed_slice())?
+        let (ptr, compressed_data, pk) = p.wait()?;
+        let data = match self.cached_decompressed(&ptr) {
+            Some(data) => data,
+            None => {
+                let compressed_data = self.unpack_slot(&ptr, compressed_data)?;
+                self.decompress(&ptr, compressed_data)?
+            }
         };
+        let mut object: Node<ObjRef<ObjectPointer<SPL::Checksum>>> =
+            Object::unpack_at(ptr.offset(), ptr.info(), data)?;
+        object.restore_storage_preference(ptr.system_storage_preference);
         let key = ObjectKey::Unmodified {
             offset: ptr.offset(),
+            slot: ptr.slot(),
             generation: ptr.generation(),
         };
-        self.insert_object_into_cache(key, TaggedCacheValue::new(RwLock::new(object), pk.clone()));
+        let value = TaggedCacheValue::new(RwLock::new(object), pk.clone());
+        if self.insert_object_into_cache(key, value) {
+            self.account_read(&ptr, NodeRead::PrefetchCached);
+        }
         if let Some(report_tx) = &self.report_tx {
             let _ = report_tx
                 .send(DmlMsg::fetch(ptr.offset(), ptr.size(), pk))
@@ -963,19 +2009,19 @@ where
     type CacheStats = E::Stats;
 
     fn cache_stats(&self) -> Self::CacheStats {
-        self.cache.read().stats()
+        let mut stats = self.caches[0].read().stats();
+        for cache in self.caches[1..].iter() {
+            stats.merge(&cache.read().stats());
+        }
+        stats
     }
 
     fn drop_cache(&self) {
-        let mut cache = self.cache.write();
-        let keys: Vec<_> = cache
-            .iter()
-            .cloned()
-            .filter(|&key| matches!(key, ObjectKey::Unmodified { .. }))
-            .collect();
-        for key in keys {
-            let _ = cache.remove(&key, |obj| obj.size());
-        }
+        self.drop_unmodified(|_| true);
+    }
+
+    fn drop_cache_for(&self, dataset: DatasetId) {
+        self.drop_unmodified(|entry_dataset| entry_dataset == Some(dataset));
     }
 }
 
@@ -1004,7 +2050,7 @@ where
     SPL: StoragePoolLayer,
     SPL::Checksum: StaticSize,
 {
-    fn storage_hints(&self) -> Arc<Mutex<HashMap<PivotKey, StoragePreference>>> {
+    fn storage_hints(&self) -> Arc<Mutex<StorageHints>> {
         Arc::clone(&self.storage_hints)
     }
 
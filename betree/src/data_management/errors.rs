@@ -1,5 +1,5 @@
 #![allow(missing_docs, unused_doc_comments)]
-use crate::{storage_pool::DiskOffset, vdev::Block};
+use crate::{database::ErrorCategory, storage_pool::DiskOffset, vdev::Block};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -21,24 +21,46 @@ pub enum Error {
     #[error("Serialization failed.")]
     SerializationError,
     #[error("The allocation handler encountered an error.")]
-    HandlerError(String),
+    HandlerError {
+        #[source]
+        source: Box<crate::database::Error>,
+    },
     #[error("Input/Output procedure encountered an error.")]
     IoError {
         #[from]
         source: std::io::Error,
     },
-    #[error("Could not find fitting space to allocate data.")]
-    OutOfSpaceError,
+    #[error("Could not find fitting space to allocate data in storage class {class}.")]
+    OutOfSpaceError { class: u8 },
     #[error("A callback function to the cache has errored.")]
     CallbackError,
     #[error("A raw allocation has failed.")]
     RawAllocationError { at: DiskOffset, size: Block<u32> },
 }
 
-// To avoid recursive error types here, define a simple translation from
-// database to Error.
+impl Error {
+    /// Returns the cause of this error, see [ErrorCategory].
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::VdevError { source } => source.category(),
+            Error::HandlerError { source } => source.category(),
+            Error::IoError { .. } => ErrorCategory::Io { vdev: None },
+            Error::OutOfSpaceError { class } => ErrorCategory::OutOfSpace {
+                class: Some(*class),
+            },
+            Error::RawAllocationError { at, .. } => ErrorCategory::OutOfSpace {
+                class: Some(at.storage_class()),
+            },
+            _ => ErrorCategory::Other,
+        }
+    }
+}
+
+// The database error is boxed to avoid a recursive error type.
 impl From<crate::database::Error> for Error {
     fn from(value: crate::database::Error) -> Self {
-        Error::HandlerError(format!("{value:?}"))
+        Error::HandlerError {
+            source: Box::new(value),
+        }
     }
 }
@@ -0,0 +1,90 @@
+//! Notifications about nodes changing their state in the cache of the
+//! [Dmu](super::Dmu) or being restructured by their tree, for instrumentation
+//! and caching or migration policies built outside of this crate.
+//!
+//! Events are only produced while someone is subscribed, and are delivered
+//! over unbounded channels, so subscribers have to keep up with them.
+
+use crate::database::DatasetId;
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::Mutex;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// What has happened to a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeEventKind {
+    /// The node has been removed from the cache.  Modified nodes are written
+    /// back before, which is reported separately.
+    Evicted,
+    /// The node has been written to a storage tier, either to evict it or
+    /// during a sync.
+    WrittenBack,
+    /// A node which has been unmodified or in write back has been modified
+    /// again, so its previous location is not used anymore.
+    Stolen,
+    /// The messages buffered for a child of an internal node have been
+    /// applied to the child, which is the node reported.
+    Flushed,
+    /// The node has grown too large and has been split in two, the size
+    /// reported is the one before the split.
+    Split,
+    /// A sibling has been merged into the node, the size reported is the one
+    /// after the merge.
+    Merged,
+}
+
+/// A change of the state of a node, see [NodeEventKind].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeEvent {
+    /// What has happened to the node.
+    pub kind: NodeEventKind,
+    /// The dataset the node belongs to.
+    pub dataset: DatasetId,
+    /// The size of the node in memory in bytes, or for
+    /// [NodeEventKind::WrittenBack] the size written to the storage tier.
+    pub size: u64,
+    /// The storage class the node has been written to, or for
+    /// [NodeEventKind::Evicted] and [NodeEventKind::Stolen] the class of its
+    /// previous location.  `None` if the node has not been written yet, and
+    /// for the restructuring events.
+    pub storage_class: Option<u8>,
+    /// The level of the node in its tree, leaves have level zero.  Only
+    /// reported for the restructuring events [NodeEventKind::Flushed],
+    /// [NodeEventKind::Split] and [NodeEventKind::Merged].
+    pub level: Option<u32>,
+    /// How long the restructuring has taken, including fetching the nodes
+    /// involved.  Only reported for the restructuring events.
+    pub duration: Option<Duration>,
+}
+
+#[derive(Default)]
+pub(super) struct NodeEvents {
+    subscribers: Mutex<Vec<Sender<NodeEvent>>>,
+    active: AtomicBool,
+}
+
+impl NodeEvents {
+    pub(super) fn subscribe(&self) -> Receiver<NodeEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.subscribers.lock().push(tx);
+        self.active.store(true, Ordering::Release);
+        rx
+    }
+
+    /// Delivers the event built by `event` to all subscribers, dropping
+    /// those which have gone away.
+    pub(super) fn emit<F: FnOnce() -> NodeEvent>(&self, event: F) {
+        if !self.active.load(Ordering::Acquire) {
+            return;
+        }
+        let event = event();
+        let mut subscribers = self.subscribers.lock();
+        subscribers.retain(|tx| tx.send(event).is_ok());
+        if subscribers.is_empty() {
+            self.active.store(false, Ordering::Release);
+        }
+    }
+}
@@ -18,7 +18,11 @@ pub struct ModifiedObjectId {
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum ObjectKey<G> {
-    Unmodified { offset: DiskOffset, generation: G },
+    Unmodified {
+        offset: DiskOffset,
+        slot: Option<u8>,
+        generation: G,
+    },
     Modified(ModifiedObjectId),
     InWriteback(ModifiedObjectId),
 }
@@ -79,6 +83,7 @@ impl<D> ObjRef<ObjectPointer<D>> {
         match *self {
             ObjRef::Unmodified(ref ptr, ..) => ObjectKey::Unmodified {
                 offset: ptr.offset(),
+                slot: ptr.slot(),
                 generation: ptr.generation(),
             },
             ObjRef::Modified(mid, ..) => ObjectKey::Modified(mid),
@@ -26,7 +26,6 @@ use parking_lot::Mutex;
 use serde::{de::DeserializeOwned, Serialize};
 use stable_deref_trait::StableDeref;
 use std::{
-    collections::HashMap,
     fmt::Debug,
     hash::Hash,
     io::{self, Write},
@@ -128,6 +127,13 @@ pub trait Object<R>: Size + Sized + HasStoragePreference {
     fn for_each_child<E, F>(&mut self, f: F) -> Result<(), E>
     where
         F: FnMut(&mut R) -> Result<(), E>;
+
+    /// Calls a closure on each child `ObjectRef` of this object without
+    /// modifying it.  Returns `false` if a child is locked for modification,
+    /// in which case the remaining children are not visited.
+    fn try_for_each_child_ref<F>(&self, f: F) -> bool
+    where
+        F: FnMut(&R);
 }
 
 /// The standard interface for the `Data Management Layer`. This layer *always*
@@ -196,6 +202,12 @@ pub trait Dml: Sized {
     /// Removes the object referenced by `or` and returns it.
     fn get_and_remove(&self, or: Self::ObjectRef) -> Result<Self::Object, Error>;
 
+    /// Moves the object referenced by `or` to the storage class of `pref`
+    /// when it is written the next time, or when it is fetched if it is not
+    /// cached.  The hint is ignored if the object is not stored on a slower
+    /// class.
+    fn hint_storage_preference(&self, or: &Self::ObjectRef, pref: StoragePreference);
+
     /// Turns an ObjectPointer into an ObjectReference.
     fn root_ref_from_ptr(r: Self::ObjectPointer) -> Self::ObjectRef;
 
@@ -221,12 +233,21 @@ pub trait Dml: Sized {
     type CacheStats: serde::Serialize;
     /// Cache-dependent statistics.
     fn cache_stats(&self) -> Self::CacheStats;
-    /// Drops the cache entries.
+    /// Drops all unmodified cache entries.  Modified entries are kept, as
+    /// they have not been written back yet.
     fn drop_cache(&self);
-    /// Run cache-internal self-validation.
-    fn verify_cache(&self);
+    /// Drops the unmodified cache entries of the dataset `dataset`.
+    fn drop_cache_for(&self, dataset: DatasetId);
+    /// Validates the cache and the cached nodes: that the tracked sizes of
+    /// nodes match their contents, that modified children can be found, and
+    /// that unmodified nodes have no modified children.  Nodes which are
+    /// locked for modification are skipped.
+    fn verify_cache(&self) -> CacheReport;
     /// Evicts excessive cache entries.
     fn evict(&self) -> Result<(), Error>;
+    /// Reports a change of a node to the subscribers of node events.  `event`
+    /// is only called if there are any.
+    fn emit_node_event<F: FnOnce() -> NodeEvent>(&self, event: F);
 }
 
 /// Legible result of a copy-on-write call. This describes wether the given
@@ -263,7 +284,7 @@ pub trait DmlWithHandler {
 /// by the migration policies.
 pub trait DmlWithStorageHints {
     /// Returns a handle to the storage hint data structure.
-    fn storage_hints(&self) -> Arc<Mutex<HashMap<PivotKey, StoragePreference>>>;
+    fn storage_hints(&self) -> Arc<Mutex<StorageHints>>;
     /// Returns the default storage class used when [StoragePreference] is `None`.
     fn default_storage_class(&self) -> StoragePreference;
 }
@@ -279,12 +300,31 @@ pub trait DmlWithReport {
 }
 
 mod cache_value;
+mod decompressed;
 mod delegation;
 mod dmu;
 pub(crate) mod errors;
+mod events;
 pub(crate) mod impls;
+mod numa;
 mod object_ptr;
+mod prefetch;
+mod replica;
+mod slab;
+mod storage_hints;
+mod verification;
+mod write_budget;
 
 pub(crate) use self::cache_value::TaggedCacheValue;
 
-pub use self::{dmu::Dmu, errors::Error, object_ptr::ObjectPointer};
+pub use self::{
+    dmu::Dmu,
+    errors::Error,
+    events::{NodeEvent, NodeEventKind},
+    numa::NumaSharding,
+    object_ptr::ObjectPointer,
+    prefetch::Prefetch,
+    storage_hints::{HintSource, StorageHints, MAX_HINT_AGE},
+    verification::{CacheProblem, CacheReport},
+    write_budget::WriteBudgetInfo,
+};
@@ -0,0 +1,138 @@
+//! Detection of the NUMA topology, used to keep allocation state and cached
+//! nodes local to the node a thread is currently running on.
+
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+const SYSFS_NODE_PATH: &str = "/sys/devices/system/node";
+
+/// How the allocation state and the cache of the [super::Dmu] are split
+/// across NUMA nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NumaSharding {
+    /// Use a single shard shared by all threads.
+    Disabled,
+    /// Use one shard per NUMA node reported by the operating system.
+    Auto,
+    /// Use the given number of shards, threads are mapped onto them by their
+    /// current NUMA node.
+    Fixed(usize),
+}
+
+impl Default for NumaSharding {
+    fn default() -> Self {
+        NumaSharding::Disabled
+    }
+}
+
+/// Maps the calling thread to one of a fixed number of shards.
+pub(crate) struct NumaTopology {
+    cpu_to_node: Box<[usize]>,
+    shards: usize,
+}
+
+impl NumaTopology {
+    /// Reads the topology of the system as configured by `sharding`.  Falls
+    /// back to a single shard if the topology cannot be determined.
+    pub(crate) fn new(sharding: NumaSharding) -> Self {
+        let (cpu_to_node, nodes) = match sharding {
+            NumaSharding::Disabled => (Vec::new(), 1),
+            NumaSharding::Auto | NumaSharding::Fixed(_) => {
+                read_cpu_to_node(Path::new(SYSFS_NODE_PATH)).unwrap_or_else(|| {
+                    warn!("Could not determine NUMA topology, disabling sharding");
+                    (Vec::new(), 1)
+                })
+            }
+        };
+        let shards = match sharding {
+            NumaSharding::Fixed(n) => n.max(1),
+            _ => nodes,
+        };
+        NumaTopology {
+            cpu_to_node: cpu_to_node.into_boxed_slice(),
+            shards,
+        }
+    }
+
+    /// Number of shards.
+    pub(crate) fn shards(&self) -> usize {
+        self.shards
+    }
+
+    /// The shard of the NUMA node the calling thread is currently running on.
+    pub(crate) fn current_shard(&self) -> usize {
+        if self.shards == 1 {
+            return 0;
+        }
+        let cpu = unsafe { libc::sched_getcpu() };
+        if cpu < 0 {
+            return 0;
+        }
+        self.cpu_to_node
+            .get(cpu as usize)
+            .map_or(0, |node| node % self.shards)
+    }
+}
+
+/// Returns a table mapping cpu ids to node ids and the number of nodes.
+fn read_cpu_to_node(root: &Path) -> Option<(Vec<usize>, usize)> {
+    let mut cpu_to_node = Vec::new();
+    let mut nodes = 0;
+    for entry in fs::read_dir(root).ok()? {
+        let entry = entry.ok()?;
+        let name = entry.file_name();
+        let node = match name.to_str().and_then(|n| n.strip_prefix("node")) {
+            Some(id) => match id.parse::<usize>() {
+                Ok(id) => id,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        nodes = nodes.max(node + 1);
+        let cpulist = fs::read_to_string(entry.path().join("cpulist")).ok()?;
+        for cpu in parse_cpulist(cpulist.trim())? {
+            if cpu_to_node.len() <= cpu {
+                cpu_to_node.resize(cpu + 1, 0);
+            }
+            cpu_to_node[cpu] = node;
+        }
+    }
+    if nodes == 0 {
+        return None;
+    }
+    Some((cpu_to_node, nodes))
+}
+
+/// Parses a kernel cpu list like `0-3,8,10-11`.
+fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => cpus.extend(start.parse::<usize>().ok()?..=end.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpulist() {
+        assert_eq!(parse_cpulist(""), Some(vec![]));
+        assert_eq!(
+            parse_cpulist("0-3,8,10-11"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpulist("1-x"), None);
+    }
+
+    #[test]
+    fn disabled_is_single_shard() {
+        let topology = NumaTopology::new(NumaSharding::Disabled);
+        assert_eq!(topology.shards(), 1);
+        assert_eq!(topology.current_shard(), 0);
+    }
+}
@@ -1,7 +1,7 @@
 use super::HasStoragePreference;
 use crate::{
     compression::DecompressionTag,
-    database::{DatasetId, Generation},
+    database::{DatasetId, Generation, FORMAT_VERSION},
     size::StaticSize,
     storage_pool::DiskOffset,
     vdev::Block,
@@ -10,6 +10,14 @@ use crate::{
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(
+    bound(
+        serialize = "D: Serialize + Clone",
+        deserialize = "D: Deserialize<'de>"
+    ),
+    into = "PackedObjectPointer<D>",
+    try_from = "PackedObjectPointer<D>"
+)]
 /// A pointer to an on-disk serialized object.
 pub struct ObjectPointer<D> {
     pub(super) decompression_tag: DecompressionTag,
@@ -18,6 +26,101 @@ pub struct ObjectPointer<D> {
     pub(super) size: Block<u32>,
     pub(super) info: DatasetId,
     pub(super) generation: Generation,
+    /// The system storage preference of the object when it was written, e.g.
+    /// assigned by a migration policy.  Only recorded by pools of format
+    /// version 5 and later, otherwise [StoragePreference::NONE].
+    pub(super) system_storage_preference: StoragePreference,
+    /// The slot of the object within a slab block, if it has been packed
+    /// together with other small objects, see [super::slab].
+    pub(super) slot: Option<u8>,
+    /// Whether a second copy of the object directly follows the first one,
+    /// see [ObjectPointer::ditto_offset].
+    pub(super) ditto: bool,
+    /// The format version the object has been written with, see
+    /// [ObjectPointer::format_version].
+    pub(super) format_version: u8,
+}
+
+/// The serialized layout of an [ObjectPointer].  The decompression tag used to
+/// be serialized on its own as an enum variant index of four bytes, of which
+/// only the lowest byte was in use.  The second byte now holds the system
+/// storage preference, where zero stands for [StoragePreference::NONE], and
+/// the following nine bits the slot of a packed object plus one, where zero
+/// stands for an unpacked object, the next bit whether the object has a ditto
+/// copy, and the remaining six bits the format version the object has been
+/// written with, so that pointers of older format versions remain readable.
+#[derive(Serialize, Deserialize)]
+struct PackedObjectPointer<D> {
+    tag: u32,
+    checksum: D,
+    offset: DiskOffset,
+    size: Block<u32>,
+    info: DatasetId,
+    generation: Generation,
+}
+
+impl<D> From<ObjectPointer<D>> for PackedObjectPointer<D> {
+    fn from(ptr: ObjectPointer<D>) -> Self {
+        let pref = ptr
+            .system_storage_preference
+            .preferred_class()
+            .map_or(0, |class| class + 1);
+        let slot = ptr.slot.map_or(0, |slot| u32::from(slot) + 1);
+        let ditto = u32::from(ptr.ditto);
+        debug_assert!(ptr.format_version < 64);
+        PackedObjectPointer {
+            tag: ptr.decompression_tag as u32
+                | (u32::from(pref) << 8)
+                | (slot << 16)
+                | (ditto << 25)
+                | (u32::from(ptr.format_version) << 26),
+            checksum: ptr.checksum,
+            offset: ptr.offset,
+            size: ptr.size,
+            info: ptr.info,
+            generation: ptr.generation,
+        }
+    }
+}
+
+impl<D> TryFrom<PackedObjectPointer<D>> for ObjectPointer<D> {
+    type Error = String;
+
+    fn try_from(packed: PackedObjectPointer<D>) -> Result<Self, Self::Error> {
+        let decompression_tag = match packed.tag & 0xFF {
+            0 => DecompressionTag::None,
+            1 => DecompressionTag::Lz4,
+            2 => DecompressionTag::Zstd,
+            tag => return Err(format!("invalid decompression tag {tag}")),
+        };
+        let system_storage_preference = match (packed.tag >> 8) & 0xFF {
+            0 => StoragePreference::NONE,
+            pref @ 1..=4 => StoragePreference::new(pref as u8 - 1),
+            pref => return Err(format!("invalid storage preference {pref}")),
+        };
+        let slot = match (packed.tag >> 16) & 0x1FF {
+            0 => None,
+            slot @ 1..=0x100 => Some((slot - 1) as u8),
+            slot => return Err(format!("invalid slot {slot}")),
+        };
+        let ditto = (packed.tag >> 25) & 1 == 1;
+        let format_version = match packed.tag >> 26 {
+            version if version <= FORMAT_VERSION => version as u8,
+            version => return Err(format!("unsupported format version {version}")),
+        };
+        Ok(ObjectPointer {
+            decompression_tag,
+            checksum: packed.checksum,
+            offset: packed.offset,
+            size: packed.size,
+            info: packed.info,
+            generation: packed.generation,
+            system_storage_preference,
+            slot,
+            ditto,
+            format_version,
+        })
+    }
 }
 
 impl<D> HasStoragePreference for ObjectPointer<D> {
@@ -33,13 +136,12 @@ impl<D> HasStoragePreference for ObjectPointer<D> {
         StoragePreference::new(self.offset.storage_class())
     }
 
-    /// There is no support in encoding storage preference right now.
     fn system_storage_preference(&self) -> StoragePreference {
-        unimplemented!()
+        self.system_storage_preference
     }
 
-    fn set_system_storage_preference(&mut self, _pref: StoragePreference) {
-        unimplemented!()
+    fn set_system_storage_preference(&mut self, pref: StoragePreference) {
+        self.system_storage_preference = pref;
     }
 }
 
@@ -80,4 +182,157 @@ impl<D> ObjectPointer<D> {
     pub fn info(&self) -> DatasetId {
         self.info
     }
+    /// Get the slot of the object in a slab block, `None` if the object is
+    /// stored in whole blocks.
+    pub fn slot(&self) -> Option<u8> {
+        self.slot
+    }
+    /// Get the location of the second copy of the object, if it has been
+    /// written twice.
+    pub fn ditto_offset(&self) -> Option<DiskOffset> {
+        self.ditto.then(|| ditto_offset(self.offset, self.size))
+    }
+    /// Get the format version the object has been written with, zero if it
+    /// has been written by a pool of an older format version than 11, which
+    /// did not record it.  [Database::upgrade](crate::Database::upgrade)
+    /// rewrites all objects of older format versions.
+    pub fn format_version(&self) -> u32 {
+        u32::from(self.format_version)
+    }
+    /// Get the number of blocks allocated for the object, including its
+    /// second copy.
+    pub fn allocated_size(&self) -> Block<u32> {
+        if self.ditto {
+            self.size * 2
+        } else {
+            self.size
+        }
+    }
+}
+
+/// Returns the location of the second copy of an object of `size` blocks at
+/// `offset`, which directly follows the first one.
+pub(super) fn ditto_offset(offset: DiskOffset, size: Block<u32>) -> DiskOffset {
+    DiskOffset::new(
+        offset.storage_class(),
+        offset.disk_id(),
+        offset.block_offset() + size.as_u64(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct LegacyObjectPointer {
+        decompression_tag: DecompressionTag,
+        checksum: u64,
+        offset: DiskOffset,
+        size: Block<u32>,
+        info: u64,
+        generation: u64,
+    }
+
+    #[test]
+    fn storage_preference_roundtrip() {
+        let legacy = bincode::serialize(&LegacyObjectPointer {
+            decompression_tag: DecompressionTag::Zstd,
+            checksum: 42,
+            offset: DiskOffset::from_u64(1234),
+            size: Block(3),
+            info: 7,
+            generation: 9,
+        })
+        .unwrap();
+        let mut ptr: ObjectPointer<u64> = bincode::deserialize(&legacy).unwrap();
+        assert_eq!(ptr.decompression_tag(), DecompressionTag::Zstd);
+        assert_eq!(ptr.system_storage_preference(), StoragePreference::NONE);
+        assert_eq!(bincode::serialize(&ptr).unwrap(), legacy);
+
+        ptr.set_system_storage_preference(StoragePreference::SLOWEST);
+        let data = bincode::serialize(&ptr).unwrap();
+        assert_eq!(data.len(), legacy.len());
+        let ptr: ObjectPointer<u64> = bincode::deserialize(&data).unwrap();
+        assert_eq!(ptr.decompression_tag(), DecompressionTag::Zstd);
+        assert_eq!(ptr.system_storage_preference(), StoragePreference::SLOWEST);
+        assert_eq!(ptr.offset().as_u64(), 1234);
+        assert_eq!(ptr.info().as_u64(), 7);
+        assert_eq!(ptr.slot(), None);
+    }
+
+    #[test]
+    fn slot_roundtrip() {
+        let data = bincode::serialize(&LegacyObjectPointer {
+            decompression_tag: DecompressionTag::Lz4,
+            checksum: 42,
+            offset: DiskOffset::from_u64(1234),
+            size: Block(1),
+            info: 0,
+            generation: 9,
+        })
+        .unwrap();
+        let mut ptr: ObjectPointer<u64> = bincode::deserialize(&data).unwrap();
+        for slot in [0, 63, 255] {
+            ptr.slot = Some(slot);
+            ptr.set_system_storage_preference(StoragePreference::FAST);
+            let data = bincode::serialize(&ptr).unwrap();
+            let ptr: ObjectPointer<u64> = bincode::deserialize(&data).unwrap();
+            assert_eq!(ptr.slot(), Some(slot));
+            assert_eq!(ptr.decompression_tag(), DecompressionTag::Lz4);
+            assert_eq!(ptr.system_storage_preference(), StoragePreference::FAST);
+        }
+    }
+
+    #[test]
+    fn ditto_roundtrip() {
+        let data = bincode::serialize(&LegacyObjectPointer {
+            decompression_tag: DecompressionTag::None,
+            checksum: 42,
+            offset: DiskOffset::new(1, 2, Block(100)),
+            size: Block(3),
+            info: 0,
+            generation: 9,
+        })
+        .unwrap();
+        let mut ptr: ObjectPointer<u64> = bincode::deserialize(&data).unwrap();
+        assert_eq!(ptr.ditto_offset(), None);
+        assert_eq!(ptr.allocated_size(), Block(3));
+
+        ptr.ditto = true;
+        ptr.slot = Some(255);
+        let data = bincode::serialize(&ptr).unwrap();
+        let ptr: ObjectPointer<u64> = bincode::deserialize(&data).unwrap();
+        assert_eq!(ptr.slot(), Some(255));
+        assert_eq!(ptr.ditto_offset(), Some(DiskOffset::new(1, 2, Block(103))));
+        assert_eq!(ptr.allocated_size(), Block(6));
+    }
+
+    #[test]
+    fn format_version_roundtrip() {
+        let data = bincode::serialize(&LegacyObjectPointer {
+            decompression_tag: DecompressionTag::Lz4,
+            checksum: 42,
+            offset: DiskOffset::from_u64(1234),
+            size: Block(3),
+            info: 0,
+            generation: 9,
+        })
+        .unwrap();
+        let mut ptr: ObjectPointer<u64> = bincode::deserialize(&data).unwrap();
+        assert_eq!(ptr.format_version(), 0);
+
+        ptr.format_version = FORMAT_VERSION as u8;
+        ptr.ditto = true;
+        let data = bincode::serialize(&ptr).unwrap();
+        let mut ptr: ObjectPointer<u64> = bincode::deserialize(&data).unwrap();
+        assert_eq!(ptr.format_version(), FORMAT_VERSION);
+        assert!(ptr.ditto_offset().is_some());
+        assert_eq!(ptr.decompression_tag(), DecompressionTag::Lz4);
+
+        // Objects written by newer versions are refused.
+        ptr.format_version = FORMAT_VERSION as u8 + 1;
+        let data = bincode::serialize(&ptr).unwrap();
+        assert!(bincode::deserialize::<ObjectPointer<u64>>(&data).is_err());
+    }
 }
@@ -0,0 +1,102 @@
+//! Bounded prefetching of objects.
+
+use futures::{executor::block_on, future::BoxFuture};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Limits the number of prefetches which are in flight at the same time.
+pub(crate) struct PrefetchQueue {
+    in_flight: AtomicUsize,
+    capacity: usize,
+}
+
+impl PrefetchQueue {
+    pub(crate) fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(PrefetchQueue {
+            in_flight: AtomicUsize::new(0),
+            capacity,
+        })
+    }
+
+    /// Reserves a slot for a new prefetch, returns `None` if the queue is
+    /// full.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<PrefetchSlot> {
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.capacity).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| PrefetchSlot {
+                queue: Arc::clone(self),
+            })
+    }
+
+    /// Number of prefetches currently in flight.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
+
+/// An occupied slot of a [PrefetchQueue], freed on drop.
+pub(crate) struct PrefetchSlot {
+    queue: Arc<PrefetchQueue>,
+}
+
+impl Drop for PrefetchSlot {
+    fn drop(&mut self) {
+        self.queue.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// An in-flight prefetch.  Dropping it without waiting for it, e.g. together
+/// with an abandoned range iterator, cancels the prefetch and frees its slot
+/// in the queue.
+pub struct Prefetch<T> {
+    future: BoxFuture<'static, T>,
+    _slot: PrefetchSlot,
+}
+
+impl<T> Prefetch<T> {
+    pub(crate) fn new(future: BoxFuture<'static, T>, slot: PrefetchSlot) -> Self {
+        Prefetch {
+            future,
+            _slot: slot,
+        }
+    }
+
+    /// Waits for the prefetch to complete.
+    pub(crate) fn wait(self) -> T {
+        block_on(self.future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn queue_is_bounded() {
+        let queue = PrefetchQueue::new(2);
+        let a = queue.try_acquire().unwrap();
+        let _b = queue.try_acquire().unwrap();
+        assert!(queue.try_acquire().is_none());
+        drop(a);
+        assert_eq!(queue.in_flight(), 1);
+        assert!(queue.try_acquire().is_some());
+    }
+
+    #[test]
+    fn dropped_prefetch_frees_slot() {
+        let queue = PrefetchQueue::new(1);
+        let prefetch = Prefetch::new(async { 42 }.boxed(), queue.try_acquire().unwrap());
+        drop(prefetch);
+        assert_eq!(queue.in_flight(), 0);
+
+        let prefetch = Prefetch::new(async { 42 }.boxed(), queue.try_acquire().unwrap());
+        assert_eq!(prefetch.wait(), 42);
+        assert_eq!(queue.in_flight(), 0);
+    }
+}
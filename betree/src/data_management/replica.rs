@@ -0,0 +1,90 @@
+//! Read-only replicas of hot objects on the fastest storage class.
+//!
+//! A migration policy may request a replica of an object which is stored on a
+//! slower class, see [Dmu::request_replica](super::Dmu::request_replica).  The
+//! next time the object is fetched from its authoritative location, a copy of
+//! its blocks is written to storage class 0, and later fetches are served from
+//! this copy.  The object pointers in the trees are never changed, so the
+//! authoritative copy stays where it is and a replica can be dropped at any
+//! time to reclaim its space.
+//!
+//! A replica belongs to an object, which is identified by its offset and its
+//! generation, and is freed once the object is rewritten or removed.  Replicas
+//! are read with the checksum of the object and dropped if they do not match
+//! it, in which case the object is read from its authoritative location.
+//!
+//! Replicas only exist in memory.  Their blocks are allocated like those of
+//! any other object, and the allocation is recorded in the root tree, so that
+//! the blocks of replicas which have not been dropped when the database is
+//! closed or crashes are freed when it is opened again, see
+//! [Database::drop_replicas](crate::Database::drop_replicas).
+
+use crate::{database::Generation, storage_pool::DiskOffset, vdev::Block};
+use indexmap::IndexMap;
+use std::collections::HashSet;
+
+/// The copy of an object on the fastest storage class.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Replica {
+    pub(super) offset: DiskOffset,
+    pub(super) size: Block<u32>,
+    /// The generation of the replicated object.
+    pub(super) generation: Generation,
+}
+
+/// The requested and existing replicas of a [Dmu](super::Dmu).
+#[derive(Debug, Default)]
+pub(super) struct Replicas {
+    /// Offsets of objects to replicate on their next fetch.
+    requested: HashSet<DiskOffset>,
+    /// Existing replicas by the offset of their object, oldest first.
+    replicas: IndexMap<DiskOffset, Replica>,
+}
+
+impl Replicas {
+    /// Requests a replica of the object at `offset`, returns whether it has
+    /// neither been replicated nor requested before.
+    pub(super) fn request(&mut self, offset: DiskOffset) -> bool {
+        !self.replicas.contains_key(&offset) && self.requested.insert(offset)
+    }
+
+    /// Returns whether a replica of the object at `offset` has been requested
+    /// and withdraws the request.
+    pub(super) fn take_request(&mut self, offset: DiskOffset) -> bool {
+        self.requested.remove(&offset)
+    }
+
+    pub(super) fn get(&self, offset: DiskOffset, generation: Generation) -> Option<Replica> {
+        self.replicas
+            .get(&offset)
+            .filter(|replica| replica.generation == generation)
+            .copied()
+    }
+
+    /// Records `replica` of the object at `offset`, returns a previous
+    /// replica at the same offset.
+    pub(super) fn insert(&mut self, offset: DiskOffset, replica: Replica) -> Option<Replica> {
+        self.replicas.insert(offset, replica)
+    }
+
+    pub(super) fn remove(&mut self, offset: DiskOffset, generation: Generation) -> Option<Replica> {
+        self.get(offset, generation)?;
+        self.replicas.shift_remove(&offset)
+    }
+
+    /// Removes the oldest replica.
+    pub(super) fn pop_oldest(&mut self) -> Option<Replica> {
+        let offset = *self.replicas.keys().next()?;
+        self.replicas.shift_remove(&offset)
+    }
+
+    /// Returns the number of blocks occupied by all replicas.
+    pub(super) fn size(&self) -> Block<u64> {
+        Block(
+            self.replicas
+                .values()
+                .map(|replica| replica.size.as_u64())
+                .sum(),
+        )
+    }
+}
@@ -0,0 +1,156 @@
+//! Packing of small objects into shared blocks.
+//!
+//! Objects whose compressed encoding, without its zero padding, takes at most
+//! [MAX_PACKED_SIZE] bytes may be written to a slab block together with other
+//! small objects of the same generation and storage class.  A slab block
+//! starts with an index of the objects it contains, each addressed by its slot
+//! in this index, and the objects are placed from the end of the block
+//! towards the index.
+//!
+//! A slab block is kept in memory while objects are added, and written once
+//! it is full or, at the latest, before the superblock of its generation.
+//! Slabs are never continued in a later generation, so each slab block is
+//! written once.  The slots in use are recorded in the root tree, and a slab
+//! block is freed once all of its slots have been freed.
+
+use crate::{
+    buffer::{Buf, BufWrite},
+    storage_pool::DiskOffset,
+    vdev::{Block, BLOCK_SIZE},
+};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::Write;
+
+/// The maximum size of a packed object in bytes.
+pub(super) const MAX_PACKED_SIZE: usize = BLOCK_SIZE / 4;
+/// The maximum number of objects in a slab block.
+const MAX_SLOTS: usize = 64;
+const HEADER_SIZE: usize = 2;
+const INDEX_ENTRY_SIZE: usize = 4;
+
+/// A slab block which still accepts objects.
+pub(super) struct Slab {
+    offset: DiskOffset,
+    /// Number of objects in the block.
+    count: usize,
+    /// Start of the objects at the end of the block.
+    start: usize,
+    block: Box<[u8]>,
+}
+
+impl Slab {
+    /// Starts a new, empty slab at the block `offset`.
+    pub(super) fn new(offset: DiskOffset) -> Self {
+        Slab {
+            offset,
+            count: 0,
+            start: BLOCK_SIZE,
+            block: vec![0; BLOCK_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Returns the offset of the slab block.
+    pub(super) fn offset(&self) -> DiskOffset {
+        self.offset
+    }
+
+    /// Adds `data` to the slab and returns its slot, or `None` if it does not
+    /// fit anymore.
+    pub(super) fn push(&mut self, data: &[u8]) -> Option<u8> {
+        let index_end = HEADER_SIZE + (self.count + 1) * INDEX_ENTRY_SIZE;
+        if self.count == MAX_SLOTS || index_end + data.len() > self.start {
+            return None;
+        }
+        self.start -= data.len();
+        self.block[self.start..self.start + data.len()].copy_from_slice(data);
+        let entry = &mut self.block[index_end - INDEX_ENTRY_SIZE..index_end];
+        LittleEndian::write_u16(&mut entry[..2], self.start as u16);
+        LittleEndian::write_u16(&mut entry[2..], data.len() as u16);
+
+        let slot = self.count as u8;
+        self.count += 1;
+        LittleEndian::write_u16(&mut self.block[..HEADER_SIZE], self.count as u16);
+        Some(slot)
+    }
+
+    /// Returns the encoded slab block.
+    pub(super) fn encode(&self) -> Buf {
+        let mut buf = BufWrite::with_capacity(Block(1));
+        buf.write_all(&self.block)
+            .expect("Writing to a buffer can not fail");
+        buf.into_buf()
+    }
+}
+
+/// Returns the object in `slot` of an encoded slab `block`, or `None` if the
+/// index of the block is malformed.
+pub(super) fn slot(block: &[u8], slot: u8) -> Option<&[u8]> {
+    let count = LittleEndian::read_u16(block.get(..HEADER_SIZE)?) as usize;
+    if slot as usize >= count.min(MAX_SLOTS) {
+        return None;
+    }
+    let entry_start = HEADER_SIZE + slot as usize * INDEX_ENTRY_SIZE;
+    let entry = block.get(entry_start..entry_start + INDEX_ENTRY_SIZE)?;
+    let start = LittleEndian::read_u16(&entry[..2]) as usize;
+    let len = LittleEndian::read_u16(&entry[2..]) as usize;
+    block.get(start..start + len)
+}
+
+/// Returns `data` without its trailing zeros, which are restored by [pad].
+pub(super) fn trim(data: &[u8]) -> &[u8] {
+    let len = data
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |pos| pos + 1);
+    &data[..len]
+}
+
+/// Pads a packed object with zeros to a whole block, as it has been encoded.
+pub(super) fn pad(data: &[u8]) -> Buf {
+    let mut buf = BufWrite::with_capacity(Block::round_up_from_bytes(data.len() as u32));
+    buf.write_all(data)
+        .expect("Writing to a buffer can not fail");
+    buf.into_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_and_read_slots() {
+        let mut slab = Slab::new(DiskOffset::from_u64(0));
+        let objects: Vec<Vec<u8>> = (1..=5u8).map(|n| vec![n; 100 * n as usize]).collect();
+        for (idx, object) in objects.iter().enumerate() {
+            assert_eq!(slab.push(object), Some(idx as u8));
+        }
+        let block = slab.encode();
+        assert_eq!(block.len(), BLOCK_SIZE);
+        for (idx, object) in objects.iter().enumerate() {
+            assert_eq!(slot(&block, idx as u8), Some(&object[..]));
+        }
+        assert_eq!(slot(&block, objects.len() as u8), None);
+
+        assert_eq!(slab.push(&[1; BLOCK_SIZE / 2]), None);
+        assert_eq!(slab.push(&[1; 100]), Some(objects.len() as u8));
+    }
+
+    #[test]
+    fn slot_limit() {
+        let mut slab = Slab::new(DiskOffset::from_u64(0));
+        for idx in 0..MAX_SLOTS {
+            assert_eq!(slab.push(&[7]), Some(idx as u8));
+        }
+        assert_eq!(slab.push(&[7]), None);
+    }
+
+    #[test]
+    fn trim_and_pad() {
+        let data = [1, 0, 2, 0, 0];
+        assert_eq!(trim(&data), &[1, 0, 2]);
+        let padded = pad(trim(&data));
+        assert_eq!(padded.len(), BLOCK_SIZE);
+        assert_eq!(&padded[..data.len()], &data);
+        assert!(padded[data.len()..].iter().all(|byte| *byte == 0));
+    }
+}
@@ -0,0 +1,122 @@
+//! Pending moves of nodes to other storage classes, see
+//! [DmlWithStorageHints](super::DmlWithStorageHints).
+//!
+//! A hint is applied when its node is fetched or written back the next time,
+//! or by [Database::apply_storage_hints](crate::Database::apply_storage_hints),
+//! by setting the system storage preference of the node.  Conflicting
+//! preferences are resolved in this order:
+//!
+//! 1. The storage preferences of the entries of a node, as given by the
+//!    application, are lower bounds.  A hint can move a node to a faster
+//!    class than its entries prefer, but never to a slower one.
+//! 2. Hints of the application, given by reads with a storage preference,
+//!    replace hints of the migration policies for the same node, while hints
+//!    of the migration policies do not replace those of the application.
+//! 3. Otherwise, a newer hint replaces an older one.
+//!
+//! Hints expire after [MAX_HINT_AGE] generations, so that hints of nodes
+//! which have been merged into others, or whose datasets have been removed,
+//! do not accumulate.  Hints of nodes which are removed by the tree are
+//! dropped right away.
+
+use crate::{database::Generation, tree::PivotKey, StoragePreference};
+use std::collections::HashMap;
+
+/// The number of generations after which an unapplied hint is dropped.
+pub const MAX_HINT_AGE: u64 = 16;
+
+/// The origin of a storage hint, in ascending precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HintSource {
+    /// A migration policy.
+    Policy,
+    /// A read of the application, see
+    /// [Dataset::get_with_pref](crate::database::Dataset::get_with_pref).
+    Application,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Hint {
+    pref: StoragePreference,
+    source: HintSource,
+    generation: Generation,
+}
+
+impl Hint {
+    fn is_expired(&self, current: Generation) -> bool {
+        current.as_u64().saturating_sub(self.generation.as_u64()) > MAX_HINT_AGE
+    }
+}
+
+/// The storage hints of a [Dmu](super::Dmu) by the pivot keys of their nodes.
+#[derive(Debug, Default)]
+pub struct StorageHints {
+    hints: HashMap<PivotKey, Hint>,
+}
+
+impl StorageHints {
+    /// Hints the node at `pivot` to be moved to `pref`, unless a hint with a
+    /// higher precedence exists, see the [module docs](self).
+    pub(crate) fn insert(
+        &mut self,
+        pivot: PivotKey,
+        pref: StoragePreference,
+        source: HintSource,
+        generation: Generation,
+    ) {
+        let hint = Hint {
+            pref,
+            source,
+            generation,
+        };
+        match self.hints.get(&pivot) {
+            Some(old) if old.source > source && !old.is_expired(generation) => {}
+            _ => {
+                self.hints.insert(pivot, hint);
+            }
+        }
+    }
+
+    /// Removes the hint of the node at `pivot` and returns it, unless it has
+    /// expired.
+    pub(crate) fn take(
+        &mut self,
+        pivot: &PivotKey,
+        current: Generation,
+    ) -> Option<StoragePreference> {
+        self.hints
+            .remove(pivot)
+            .filter(|hint| !hint.is_expired(current))
+            .map(|hint| hint.pref)
+    }
+
+    /// Removes any hint and returns it, skipping expired ones.
+    pub(crate) fn pop(&mut self, current: Generation) -> Option<(PivotKey, StoragePreference)> {
+        loop {
+            let pivot = self.hints.keys().next()?.clone();
+            if let Some(pref) = self.take(&pivot, current) {
+                return Some((pivot, pref));
+            }
+        }
+    }
+
+    /// Drops the hint of a removed node.
+    pub(crate) fn remove(&mut self, pivot: &PivotKey) {
+        self.hints.remove(pivot);
+    }
+
+    /// Drops all expired hints.
+    pub(crate) fn expire(&mut self, current: Generation) {
+        self.hints.retain(|_, hint| !hint.is_expired(current));
+    }
+
+    /// Returns the number of pending hints.
+    pub fn len(&self) -> usize {
+        self.hints.len()
+    }
+
+    /// Returns whether no hints are pending.
+    pub fn is_empty(&self) -> bool {
+        self.hints.is_empty()
+    }
+}
@@ -0,0 +1,49 @@
+//! Results of [Dml::verify_cache](super::Dml::verify_cache).
+
+use crate::database::DatasetId;
+use serde::Serialize;
+
+/// A violated invariant of a cached node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum CacheProblem {
+    /// The size tracked for a node differs from the size of its contents.
+    SizeMismatch {
+        /// The dataset of the node.
+        dataset: DatasetId,
+        /// The size tracked for the node.
+        recorded: usize,
+        /// The size computed from the contents of the node.
+        actual: usize,
+    },
+    /// A node refers to a modified child which is neither cached nor has been
+    /// written back.
+    DanglingChild {
+        /// The dataset of the node.
+        dataset: DatasetId,
+    },
+    /// A node which is unmodified refers to a modified child, so the new
+    /// location of the child would never be written with the node.
+    ModifiedChildOfUnmodified {
+        /// The dataset of the node.
+        dataset: DatasetId,
+    },
+}
+
+/// The outcome of verifying all cached nodes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheReport {
+    /// The number of nodes which have been checked.
+    pub checked: usize,
+    /// The number of nodes which have been skipped, as they or some of their
+    /// child pointers were locked for modification.
+    pub skipped: usize,
+    /// All violations found.
+    pub problems: Vec<CacheProblem>,
+}
+
+impl CacheReport {
+    /// Returns whether no violations have been found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
@@ -0,0 +1,121 @@
+//! Daily limits of the bytes written to storage classes, see
+//! [TierConfiguration::write_budget](crate::storage_pool::TierConfiguration::write_budget).
+//!
+//! Days are counted on the [Clock] of the database and start at midnight UTC.
+//! Once the budget of a class has been used up, allocations prefer the other
+//! classes they may fall back to and the migration policies stop moving data
+//! to it.  Allocations which fit nowhere else still use the class, so a budget
+//! may be exceeded rather than failing writes.
+//!
+//! The counters of the current day are stored in the root tree on every sync
+//! and restored when the database is opened on the same day.  Writes of the
+//! root tree itself during the sync are only counted after a restart.
+
+use crate::{clock::Clock, storage_pool::NUM_STORAGE_CLASSES};
+use byteorder::{BigEndian, ByteOrder};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::time::UNIX_EPOCH;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The length of the packed counters, the day followed by the bytes written
+/// to each storage class.
+pub(crate) const PACKED_LEN: usize = 8 * (1 + NUM_STORAGE_CLASSES);
+
+/// The bytes written to a storage class on the current day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBudgetInfo {
+    /// Bytes written since the start of the day.
+    pub written: u64,
+    /// The configured daily budget in bytes, if any.
+    pub budget: Option<u64>,
+}
+
+impl WriteBudgetInfo {
+    /// Returns whether the budget of the current day has been used up.
+    pub fn is_exhausted(&self) -> bool {
+        self.budget.map_or(false, |budget| self.written >= budget)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Day {
+    index: u64,
+    written: [u64; NUM_STORAGE_CLASSES],
+}
+
+#[derive(Debug, Default)]
+pub(super) struct WriteBudgets {
+    budgets: [Option<u64>; NUM_STORAGE_CLASSES],
+    clock: Clock,
+    day: Mutex<Day>,
+}
+
+impl WriteBudgets {
+    pub(super) fn new(budgets: [Option<u64>; NUM_STORAGE_CLASSES], clock: Clock) -> Self {
+        WriteBudgets {
+            budgets,
+            clock,
+            day: Mutex::default(),
+        }
+    }
+
+    /// Runs `f` on the counters of the current day.
+    fn with_day<T>(&self, f: impl FnOnce(&mut Day) -> T) -> T {
+        let index = self
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / SECS_PER_DAY);
+        let mut day = self.day.lock();
+        if day.index != index {
+            *day = Day {
+                index,
+                ..Day::default()
+            };
+        }
+        f(&mut day)
+    }
+
+    /// Counts `bytes` written to `class`.
+    pub(super) fn record(&self, class: u8, bytes: u64) {
+        self.with_day(|day| day.written[class as usize] += bytes)
+    }
+
+    pub(super) fn info(&self, class: u8) -> WriteBudgetInfo {
+        WriteBudgetInfo {
+            written: self.with_day(|day| day.written[class as usize]),
+            budget: self.budgets[class as usize],
+        }
+    }
+
+    pub(super) fn is_exhausted(&self, class: u8) -> bool {
+        self.budgets[class as usize].is_some() && self.info(class).is_exhausted()
+    }
+
+    /// Packs the counters of the current day as big endian integers.
+    pub(super) fn pack(&self) -> [u8; PACKED_LEN] {
+        let mut buf = [0; PACKED_LEN];
+        self.with_day(|day| {
+            BigEndian::write_u64(&mut buf[..8], day.index);
+            BigEndian::write_u64_into(&day.written, &mut buf[8..]);
+        });
+        buf
+    }
+
+    /// Restores counters packed by [WriteBudgets::pack].  Counters of a past
+    /// day are discarded on their next use.
+    pub(super) fn unpack(&self, buf: &[u8]) {
+        if buf.len() != PACKED_LEN {
+            warn!("Ignoring stored write budget counters of unexpected length");
+            return;
+        }
+        let mut day = Day {
+            index: BigEndian::read_u64(&buf[..8]),
+            ..Day::default()
+        };
+        BigEndian::read_u64_into(&buf[8..], &mut day.written);
+        *self.day.lock() = day;
+    }
+}
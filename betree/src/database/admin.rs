@@ -0,0 +1,231 @@
+//! A minimal HTTP interface to inspect and administrate a running database.
+//! It is served by [Database::build_threaded] if
+//! [DatabaseConfiguration::admin_address] is set, or by an [AdminServer].
+//!
+//! Each connection carries a single request, and all responses are JSON:
+//!
+//! | Request                     | Response                                             |
+//! |-----------------------------|------------------------------------------------------|
+//! | `GET /tiers`                | free and total blocks of each storage tier           |
+//! | `GET /cache`                | statistics of the node cache                         |
+//! | `GET /datasets`             | names and ids of all datasets                        |
+//! | `GET /migration`            | the configured migration policy and whether it runs  |
+//! | `PUT /migration/threshold`  | `null`, see [Database::set_migration_threshold]      |
+//! | `POST /sync`                | `null` once the database has been synced             |
+//! | `POST /scrub`               | the [ScrubReport](super::ScrubReport)                |
+//!
+//! A threshold is overridden with a body like `{"class": 1, "threshold": 0.8}`.
+//! Failed requests are answered with `{"error": "..."}`.
+//!
+//! Clients which do not send their request within [REQUEST_TIMEOUT] are
+//! disconnected.  Once the database is dropped, requests are answered with
+//! status 503 and [AdminServer::serve] returns.
+//!
+//! There is no authentication, so the interface must only be bound to
+//! addresses which can not be reached by untrusted parties.
+//!
+//! [DatabaseConfiguration::admin_address]: super::DatabaseConfiguration::admin_address
+
+use super::{root_tree_msg::DATASET_NAME_TO_ID, Database, DatasetId, Error, ErrorCategory, Result};
+use crate::{data_management::Dml, tree::TreeLayer};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
+
+/// Requests with larger bodies are rejected.
+const MAX_BODY_SIZE: usize = 4096;
+
+/// How long reading a request or writing its response may take.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often [AdminServer::serve] checks whether the database is still open
+/// while no client connects.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Deserialize)]
+struct ThresholdOverride {
+    class: u8,
+    threshold: f32,
+}
+
+/// Answers requests to the admin interface of a database.  The server does not
+/// keep the database open.
+pub struct AdminServer {
+    db: Weak<RwLock<Database>>,
+}
+
+impl AdminServer {
+    /// Creates a server for `db`.
+    pub fn new(db: &Arc<RwLock<Database>>) -> Self {
+        AdminServer {
+            db: Arc::downgrade(db),
+        }
+    }
+
+    /// Accepts connections on `listener` and answers them one after another,
+    /// until the database is dropped.  Failed connections are logged.
+    pub fn serve(&self, listener: &TcpListener) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        while self.db.strong_count() > 0 {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let Err(e) = self.handle(stream) {
+                log::warn!("Admin connection failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the request of a single connection.
+    pub fn handle(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (status, body) = match read_request(&mut reader)? {
+            Some((method, path, body)) => self.route(&method, &path, &body),
+            None => (400, json!({ "error": "malformed request" })),
+        };
+        write_response(stream, status, &body)
+    }
+
+    fn route(&self, method: &str, path: &str, body: &[u8]) -> (u16, Value) {
+        let path = path.split('?').next().unwrap_or(path);
+        let db = match self.db.upgrade() {
+            Some(db) => db,
+            None => return (503, json!({ "error": "database closed" })),
+        };
+        let result = match (method, path) {
+            ("GET", "/tiers") => to_json(db.read().free_space_tier()),
+            ("GET", "/cache") => to_json(db.read().root_tree.dmu().cache_stats()),
+            ("GET", "/datasets") => datasets(&db.read()),
+            ("GET", "/migration") => {
+                let db = db.read();
+                to_json(&db.builder.migration_policy).map(|policy| {
+                    json!({
+                        "policy": policy,
+                        "running": db.migration_thresholds.is_some(),
+                    })
+                })
+            }
+            ("PUT", "/migration/threshold") => {
+                match serde_json::from_slice::<ThresholdOverride>(body) {
+                    Ok(req) => db
+                        .write()
+                        .set_migration_threshold(req.class, req.threshold)
+                        .map(|()| Value::Null),
+                    Err(e) => return (400, json!({ "error": e.to_string() })),
+                }
+            }
+            ("POST", "/sync") => Database::sync_shared(&db).map(|_| Value::Null),
+            ("POST", "/scrub") => db.read().scrub().and_then(to_json),
+            (
+                _,
+                "/tiers"
+                | "/cache"
+                | "/datasets"
+                | "/migration"
+                | "/migration/threshold"
+                | "/sync"
+                | "/scrub",
+            ) => return (405, json!({ "error": "method not allowed" })),
+            _ => return (404, json!({ "error": "not found" })),
+        };
+        match result {
+            Ok(value) => (200, value),
+            Err(e) => {
+                let status = match e {
+                    Error::MigrationNotPossible => 409,
+                    _ if e.category() == ErrorCategory::Configuration => 400,
+                    _ => 500,
+                };
+                (status, json!({ "error": e.to_string() }))
+            }
+        }
+    }
+}
+
+fn datasets(db: &Database) -> Result<Value> {
+    let mut datasets = Vec::new();
+    let low = &[DATASET_NAME_TO_ID][..];
+    let high = &[DATASET_NAME_TO_ID + 1][..];
+    for result in db.root_tree.range(low..high)? {
+        let (key, id) = result?;
+        datasets.push(json!({
+            "name": String::from_utf8_lossy(&key[1..]),
+            "id": DatasetId::unpack(&id),
+        }));
+    }
+    Ok(Value::Array(datasets))
+}
+
+fn to_json<T: Serialize>(value: T) -> Result<Value> {
+    Ok(serde_json::to_value(value)?)
+}
+
+/// Reads the method, path and body of a request, `None` if it is malformed.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<(String, String, Vec<u8>)>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request = line.split_whitespace();
+    let (method, path) = match (request.next(), request.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Ok(None),
+    };
+
+    let mut length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                match value.trim().parse() {
+                    Ok(len) if len <= MAX_BODY_SIZE => length = len,
+                    _ => return Ok(None),
+                }
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some((method, path, body)))
+}
+
+fn write_response(mut stream: TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    Ok(())
+}
@@ -0,0 +1,238 @@
+//! Accounting of the bytes written and read by users in relation to the bytes
+//! written to and read from the storage tiers, to judge the write and read
+//! amplification of the tree.
+//!
+//! Logical bytes are counted when a message is inserted into a dataset, as
+//! the size of its key and message, and when a value is returned by a `get` or
+//! a key-value pair by a `range`.  Physical bytes are counted as the size of
+//! the compressed and block aligned encoding of a node, whenever it is written
+//! back or read from a tier.  Writes include every rewrite of a node caused by
+//! copy-on-write, by flushing buffers down the tree and by migrating it to
+//! another tier.  Redundancy added by the vdevs themselves, e.g. by mirrors or
+//! parity, is not included.
+//!
+//! Nodes are always read as a whole, so physical reads are split into nodes
+//! fetched on access and nodes read ahead by prefetches, which may be wasted.
+
+use super::DatasetId;
+use crate::storage_pool::NUM_STORAGE_CLASSES;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+fn ratio(physical: u64, logical: u64) -> Option<f64> {
+    (logical > 0).then(|| physical as f64 / logical as f64)
+}
+
+/// Written bytes of a dataset or of a storage tier.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteAmplification {
+    /// Bytes of keys and messages inserted by users.
+    pub logical_written: u64,
+    /// Bytes of nodes written to the storage tiers.
+    pub physical_written: u64,
+    /// The ratio of physical to logical bytes, `None` if nothing has been
+    /// written by users yet.
+    pub ratio: Option<f64>,
+}
+
+/// Read bytes of a dataset or of a storage tier.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReadAmplification {
+    /// Bytes of keys and values returned to users.
+    pub logical_read: u64,
+    /// Bytes of nodes read from the storage tiers, the sum of `fetched` and
+    /// `prefetched`.
+    pub physical_read: u64,
+    /// Bytes of nodes fetched on access, because they were not cached.
+    pub fetched: u64,
+    /// Bytes of nodes read ahead by prefetches.
+    pub prefetched: u64,
+    /// Bytes of prefetched nodes which have not been put into the cache,
+    /// because the prefetch has been dropped, the node has been cached by a
+    /// concurrent fetch, or the prefetch is still in flight.
+    pub prefetch_wasted: u64,
+    /// The ratio of physical to logical bytes, `None` if nothing has been
+    /// returned to users yet.
+    pub ratio: Option<f64>,
+}
+
+/// Write and read amplification of a dataset or of a storage tier.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Amplification {
+    /// Amplification of writes.
+    pub write: WriteAmplification,
+    /// Amplification of reads.
+    pub read: ReadAmplification,
+}
+
+/// Amplification since the database has been opened, see
+/// [Database::amplification_report](super::Database::amplification_report).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AmplificationReport {
+    /// Amplification of all datasets, including the root tree.
+    pub total: Amplification,
+    /// Amplification per dataset.  The physical bytes of the root tree, which
+    /// holds the metadata of all datasets, are reported for its own id.
+    pub datasets: BTreeMap<DatasetId, Amplification>,
+    /// Amplification per storage tier, indexed by storage class.  As user
+    /// writes and reads are not bound to a tier, the physical bytes of each
+    /// tier are related to all logical bytes, so that the ratios of all tiers
+    /// add up to the total ratios.
+    pub tiers: Vec<Amplification>,
+}
+
+#[derive(Default)]
+struct Counters {
+    logical_written: AtomicU64,
+    physical_written: AtomicU64,
+    logical_read: AtomicU64,
+    fetched: AtomicU64,
+    prefetched: AtomicU64,
+    prefetch_cached: AtomicU64,
+}
+
+impl Counters {
+    fn accumulate(&self, other: &Counters) {
+        for (sum, value) in [
+            (&self.logical_written, &other.logical_written),
+            (&self.physical_written, &other.physical_written),
+            (&self.logical_read, &other.logical_read),
+            (&self.fetched, &other.fetched),
+            (&self.prefetched, &other.prefetched),
+            (&self.prefetch_cached, &other.prefetch_cached),
+        ] {
+            sum.fetch_add(value.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    fn node_read(&self, kind: NodeRead) -> &AtomicU64 {
+        match kind {
+            NodeRead::Fetch => &self.fetched,
+            NodeRead::Prefetch => &self.prefetched,
+            NodeRead::PrefetchCached => &self.prefetch_cached,
+        }
+    }
+
+    fn amplification(&self, logical_written: u64, logical_read: u64) -> Amplification {
+        let physical_written = self.physical_written.load(Ordering::Relaxed);
+        let fetched = self.fetched.load(Ordering::Relaxed);
+        let prefetched = self.prefetched.load(Ordering::Relaxed);
+        let prefetch_cached = self.prefetch_cached.load(Ordering::Relaxed);
+        Amplification {
+            write: WriteAmplification {
+                logical_written,
+                physical_written,
+                ratio: ratio(physical_written, logical_written),
+            },
+            read: ReadAmplification {
+                logical_read,
+                physical_read: fetched + prefetched,
+                fetched,
+                prefetched,
+                prefetch_wasted: prefetched.saturating_sub(prefetch_cached),
+                ratio: ratio(fetched + prefetched, logical_read),
+            },
+        }
+    }
+}
+
+/// How a node has been read from a storage tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NodeRead {
+    /// Fetched on access.
+    Fetch,
+    /// Read ahead by a prefetch.
+    Prefetch,
+    /// A prefetched node which has been put into the cache.
+    PrefetchCached,
+}
+
+/// Counters of logical and physical bytes, shared by the datasets and the
+/// data management layer through the [Handler](super::Handler).
+#[derive(Default)]
+pub(crate) struct IoAccounting {
+    datasets: RwLock<HashMap<DatasetId, Arc<Counters>>>,
+    tiers: [Counters; NUM_STORAGE_CLASSES],
+}
+
+impl IoAccounting {
+    fn dataset(&self, id: DatasetId) -> Arc<Counters> {
+        if let Some(counters) = self.datasets.read().get(&id) {
+            return Arc::clone(counters);
+        }
+        Arc::clone(self.datasets.write().entry(id).or_default())
+    }
+
+    /// Counts `bytes` inserted into the dataset `id` by a user.
+    pub(crate) fn logical_write(&self, id: DatasetId, bytes: u64) {
+        self.dataset(id)
+            .logical_written
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` written back for a node of the dataset `id` to the
+    /// storage tier `class`.
+    pub(crate) fn physical_write(&self, id: DatasetId, class: u8, bytes: u64) {
+        self.dataset(id)
+            .physical_written
+            .fetch_add(bytes, Ordering::Relaxed);
+        self.tiers[class as usize]
+            .physical_written
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` returned to a user from the dataset `id`.
+    pub(crate) fn logical_read(&self, id: DatasetId, bytes: u64) {
+        self.dataset(id)
+            .logical_read
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` read for a node of the dataset `id` from the storage
+    /// tier `class`.
+    pub(crate) fn physical_read(&self, id: DatasetId, class: u8, bytes: u64, kind: NodeRead) {
+        self.dataset(id)
+            .node_read(kind)
+            .fetch_add(bytes, Ordering::Relaxed);
+        self.tiers[class as usize]
+            .node_read(kind)
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn report(&self) -> AmplificationReport {
+        let datasets = self.datasets.read();
+        let total = Counters::default();
+        for counters in datasets.values() {
+            total.accumulate(counters);
+        }
+        let logical_written = total.logical_written.load(Ordering::Relaxed);
+        let logical_read = total.logical_read.load(Ordering::Relaxed);
+        AmplificationReport {
+            total: total.amplification(logical_written, logical_read),
+            datasets: datasets
+                .iter()
+                .map(|(id, counters)| {
+                    (
+                        *id,
+                        counters.amplification(
+                            counters.logical_written.load(Ordering::Relaxed),
+                            counters.logical_read.load(Ordering::Relaxed),
+                        ),
+                    )
+                })
+                .collect(),
+            tiers: self
+                .tiers
+                .iter()
+                .map(|tier| tier.amplification(logical_written, logical_read))
+                .collect(),
+        }
+    }
+}
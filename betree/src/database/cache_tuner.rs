@@ -0,0 +1,263 @@
+//! Resizing of the cache between configured bounds, see
+//! [AdaptiveCacheConfiguration].
+//!
+//! The tuner climbs the hit rate: after growing the cache it grows it further
+//! only if the hit rate has improved, after shrinking it shrinks it further
+//! only if the hit rate has not dropped, and otherwise it turns around.  So
+//! the capacity settles around the point where more memory stops paying off,
+//! and follows it when the workload changes.  Memory the system, the cgroup of
+//! the process or its address space limit can not spare is never claimed, and
+//! is handed back once it becomes scarce.
+
+use super::{Database, RootDmu};
+use crate::{cache::Stats, clock::Clock, data_management::Dml};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+/// The number of steps between the smallest and the largest capacity.
+const STEPS: usize = 16;
+
+/// Changes of the hit rate smaller than this are considered noise.
+const HIT_RATE_EPSILON: f64 = 0.005;
+
+/// Bounds and pace of the cache resizing, see
+/// [DatabaseConfiguration::adaptive_cache](super::DatabaseConfiguration::adaptive_cache).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveCacheConfiguration {
+    /// The smallest capacity of the cache in bytes.
+    pub min_size: usize,
+    /// The largest capacity of the cache in bytes.
+    pub max_size: usize,
+    /// The interval in milliseconds between two adjustments, over which the
+    /// hit rate is measured.
+    pub interval_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct Tuner {
+    hits: u64,
+    misses: u64,
+    // The capacity and the hit rate measured with it in the last interval.
+    last: Option<(usize, f64)>,
+}
+
+impl Tuner {
+    fn tick(&mut self, dmu: &RootDmu, config: &AdaptiveCacheConfiguration) -> super::Result<()> {
+        let (capacity, size, hits, misses) = {
+            let stats = dmu.cache_stats();
+            (stats.capacity(), stats.size(), stats.hits(), stats.misses())
+        };
+        let accesses = (
+            hits.saturating_sub(self.hits),
+            misses.saturating_sub(self.misses),
+        );
+        self.hits = hits;
+        self.misses = misses;
+        if accesses == (0, 0) {
+            // Nothing to learn from an idle cache.
+            return Ok(());
+        }
+        let hit_rate = accesses.0 as f64 / (accesses.0 + accesses.1) as f64;
+        let next = self.next_capacity(config, capacity, size, hit_rate, memory_headroom());
+        self.last = Some((capacity, hit_rate));
+        if next != capacity {
+            log::debug!(
+                "resizing cache from {} to {} bytes at a hit rate of {:.3}",
+                capacity,
+                next,
+                hit_rate
+            );
+            dmu.set_cache_capacity(next)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the capacity for the next interval, given the hit rate of the
+    /// last one and the bytes of memory which may still be allocated.
+    fn next_capacity(
+        &self,
+        config: &AdaptiveCacheConfiguration,
+        capacity: usize,
+        size: usize,
+        hit_rate: f64,
+        headroom: Option<u64>,
+    ) -> usize {
+        let step = (config.max_size.saturating_sub(config.min_size) / STEPS).max(1);
+        let headroom = headroom.map_or(usize::MAX, |bytes| bytes as usize);
+        let shrink = capacity.saturating_sub(step).max(config.min_size);
+        if headroom < step {
+            return shrink;
+        }
+        let grow = match self.last {
+            Some((last, last_rate)) if last < capacity => hit_rate > last_rate + HIT_RATE_EPSILON,
+            Some((last, last_rate)) if last > capacity => hit_rate < last_rate - HIT_RATE_EPSILON,
+            // Without a gradient, grow only if the cache is full and misses.
+            _ => hit_rate < 1.0 - HIT_RATE_EPSILON && size >= capacity / 10 * 9,
+        };
+        if grow {
+            // Keep half of the remaining memory to others.
+            (capacity + step.min(headroom / 2)).min(config.max_size)
+        } else {
+            shrink
+        }
+    }
+}
+
+/// Adjusts the capacity of the cache every `config.interval_ms` until the
+/// database is dropped.
+pub fn cache_tuner(config: AdaptiveCacheConfiguration, clock: Clock, db: Weak<RwLock<Database>>) {
+    let _guard = clock.register();
+    let interval = Duration::from_millis(config.interval_ms);
+    let mut tuner = Tuner::default();
+
+    loop {
+        clock.sleep(interval);
+        let dmu = match db.upgrade() {
+            Some(db) => Arc::clone(db.read().root_tree.dmu()),
+            None => return,
+        };
+
+        if let Err(err) = tuner.tick(&dmu, &config) {
+            log::error!("couldn't resize cache: {}", err);
+        }
+    }
+}
+
+/// Returns the bytes this process may still allocate before the system, its
+/// cgroup or its address space limit runs out, if any of them is known.
+fn memory_headroom() -> Option<u64> {
+    [
+        available_memory(),
+        cgroup_headroom(),
+        address_space_headroom(),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+}
+
+/// Returns the value of a field of `/proc/meminfo` or `/proc/self/status` in
+/// bytes.
+fn proc_field(path: &str, field: &str) -> Option<u64> {
+    let contents = fs::read_to_string(path).ok()?;
+    let line = contents.lines().find(|line| line.starts_with(field))?;
+    let kib: u64 = line[field.len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+fn available_memory() -> Option<u64> {
+    proc_field("/proc/meminfo", "MemAvailable:")
+}
+
+fn read_bytes(path: &Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Returns the limit of the memory cgroup of this process minus its usage,
+/// for cgroup v2 and v1.  Unlimited cgroups have no headroom of their own.
+fn cgroup_headroom() -> Option<u64> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let (dir, limit, usage) = match controllers {
+            "" => (
+                PathBuf::from("/sys/fs/cgroup"),
+                "memory.max",
+                "memory.current",
+            ),
+            c if c.split(',').any(|c| c == "memory") => (
+                PathBuf::from("/sys/fs/cgroup/memory"),
+                "memory.limit_in_bytes",
+                "memory.usage_in_bytes",
+            ),
+            _ => continue,
+        };
+        // Inside a cgroup namespace, the cgroup of the process is the root.
+        for dir in [dir.join(path.trim_start_matches('/')), dir] {
+            if let (Some(limit), Some(usage)) =
+                (read_bytes(&dir.join(limit)), read_bytes(&dir.join(usage)))
+            {
+                return Some(limit.saturating_sub(usage));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the address space limit of this process minus its size.
+fn address_space_headroom() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    let size = proc_field("/proc/self/status", "VmSize:")?;
+    Some(limit.rlim_cur.saturating_sub(size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AdaptiveCacheConfiguration = AdaptiveCacheConfiguration {
+        min_size: 1600,
+        max_size: 3200,
+        interval_ms: 1000,
+    };
+
+    #[test]
+    fn grows_full_cache_with_misses() {
+        let tuner = Tuner::default();
+        assert_eq!(tuner.next_capacity(&CONFIG, 2000, 2000, 0.5, None), 2100);
+        assert_eq!(tuner.next_capacity(&CONFIG, 2000, 1000, 0.5, None), 1900);
+        assert_eq!(tuner.next_capacity(&CONFIG, 2000, 2000, 1.0, None), 1900);
+    }
+
+    #[test]
+    fn follows_hit_rate_gradient() {
+        let grown = Tuner {
+            last: Some((1900, 0.5)),
+            ..Tuner::default()
+        };
+        assert_eq!(grown.next_capacity(&CONFIG, 2000, 0, 0.6, None), 2100);
+        assert_eq!(grown.next_capacity(&CONFIG, 2000, 0, 0.5, None), 1900);
+        let shrunk = Tuner {
+            last: Some((2100, 0.6)),
+            ..Tuner::default()
+        };
+        assert_eq!(shrunk.next_capacity(&CONFIG, 2000, 0, 0.6, None), 1900);
+        assert_eq!(shrunk.next_capacity(&CONFIG, 2000, 0, 0.5, None), 2100);
+    }
+
+    #[test]
+    fn respects_bounds_and_memory_pressure() {
+        let tuner = Tuner::default();
+        assert_eq!(tuner.next_capacity(&CONFIG, 3200, 3200, 0.5, None), 3200);
+        assert_eq!(tuner.next_capacity(&CONFIG, 1600, 0, 1.0, None), 1600);
+        assert_eq!(
+            tuner.next_capacity(&CONFIG, 2000, 2000, 0.5, Some(120)),
+            2060
+        );
+        assert_eq!(
+            tuner.next_capacity(&CONFIG, 2000, 2000, 0.5, Some(50)),
+            1900
+        );
+    }
+}
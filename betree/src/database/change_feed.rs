@@ -0,0 +1,184 @@
+//! Streams of committed mutations per dataset, see [Dataset::subscribe].
+//!
+//! While a dataset has subscribers, its mutations are recorded in the order in
+//! which they are applied to the tree.  They are delivered once a sync has
+//! made them durable, tagged with the generation of that sync.
+
+use super::{Database, Dataset, DatasetId, Generation, Result};
+use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    mem,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// A single mutation of a dataset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Mutation {
+    /// A message inserted for a key, e.g. by an insert, upsert or delete.
+    Message {
+        /// The modified key.
+        key: CowBytes,
+        /// The inserted message, to be interpreted by the message action of
+        /// the dataset.
+        msg: SlicedCowBytes,
+    },
+    /// Removal of all keys within the given range.
+    RangeDelete {
+        /// Start of the removed range.
+        start: Bound<CowBytes>,
+        /// End of the removed range.
+        end: Bound<CowBytes>,
+    },
+}
+
+/// A committed mutation, as delivered by [Dataset::subscribe].
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// The generation of the sync which has committed the mutation at the
+    /// latest.  A mutation applied concurrently to a sync may already be part
+    /// of the preceding generation.
+    pub generation: Generation,
+    /// The mutation itself.
+    pub mutation: Mutation,
+}
+
+/// The mutations committed by a sync of the generation, see
+/// [Dataset::subscribe_batches].
+pub(super) type Batch = (Generation, Vec<Mutation>);
+
+#[derive(Default)]
+pub(crate) struct ChangeFeed {
+    /// Mutations not yet committed by a sync.  The lock is held while a
+    /// mutation is applied, so that the order of the recorded mutations
+    /// matches the order in which they have been applied.
+    pending: Mutex<Vec<Mutation>>,
+    subscribers: Mutex<Vec<Sender<Change>>>,
+    batch_subscribers: Mutex<Vec<Sender<Batch>>>,
+    active: AtomicBool,
+}
+
+impl ChangeFeed {
+    /// Whether mutations have to be recorded.
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Applies a mutation with `apply` and records it as `mutation` if
+    /// successful.
+    pub(crate) fn record<F>(&self, mutation: Mutation, apply: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let mut pending = self.pending.lock();
+        apply()?;
+        pending.push(mutation);
+        Ok(())
+    }
+
+    fn subscribe(&self) -> Receiver<Change> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.subscribers.lock().push(tx);
+        self.active.store(true, Ordering::Release);
+        rx
+    }
+
+    fn subscribe_batches(&self) -> Receiver<Batch> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.batch_subscribers.lock().push(tx);
+        self.active.store(true, Ordering::Release);
+        rx
+    }
+
+    fn publish(&self, mutations: Vec<Mutation>, generation: Generation) {
+        let mut subscribers = self.subscribers.lock();
+        let mut batch_subscribers = self.batch_subscribers.lock();
+        if !mutations.is_empty() {
+            batch_subscribers.retain(|tx| tx.send((generation, mutations.clone())).is_ok());
+        }
+        for mutation in mutations {
+            subscribers.retain(|tx| {
+                tx.send(Change {
+                    generation,
+                    mutation: mutation.clone(),
+                })
+                .is_ok()
+            });
+        }
+        if subscribers.is_empty() && batch_subscribers.is_empty() {
+            self.active.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl<Message> Dataset<Message> {
+    /// Returns a stream of all mutations of this dataset which are committed
+    /// from now on, in the order in which they have been applied.  Mutations
+    /// are buffered until a sync has made them durable and are then delivered
+    /// with the generation of that sync.  The stream ends once the dataset has
+    /// been closed and the following sync has delivered its last mutations.
+    pub fn subscribe(&self) -> Receiver<Change> {
+        self.change_feed().subscribe()
+    }
+
+    /// Like [Dataset::subscribe], but delivers the mutations committed by a
+    /// sync at once, so that the end of a generation is known.  Syncs without
+    /// mutations of this dataset deliver nothing.
+    pub(super) fn subscribe_batches(&self) -> Receiver<Batch> {
+        self.change_feed().subscribe_batches()
+    }
+}
+
+/// Mutations taken from the change feeds for a sync.
+pub(super) struct PendingChanges(Vec<(Arc<ChangeFeed>, Vec<Mutation>)>);
+
+impl Database {
+    /// Returns the change feed of the dataset `id`, which is kept as long as
+    /// the dataset is open.
+    pub(super) fn change_feed(&mut self, id: DatasetId) -> Arc<ChangeFeed> {
+        Arc::clone(self.change_feeds.entry(id).or_default())
+    }
+
+    /// Takes all recorded mutations which will be committed by the upcoming
+    /// sync.
+    pub(super) fn take_pending_changes(&self) -> PendingChanges {
+        PendingChanges(
+            self.change_feeds
+                .values()
+                .filter(|feed| feed.is_active())
+                .map(|feed| (Arc::clone(feed), mem::take(&mut *feed.pending.lock())))
+                .collect(),
+        )
+    }
+
+    /// Delivers the mutations committed by a sync of `generation`, or returns
+    /// them to their feeds if the sync has failed.  Feeds of closed datasets
+    /// are dropped afterwards, which ends their streams.
+    pub(super) fn finish_pending_changes(
+        &mut self,
+        changes: PendingChanges,
+        generation: Option<Generation>,
+    ) {
+        for (feed, mutations) in changes.0 {
+            match generation {
+                Some(generation) => feed.publish(mutations, generation),
+                None => {
+                    let mut pending = feed.pending.lock();
+                    let newer = mem::replace(&mut *pending, mutations);
+                    pending.extend(newer);
+                }
+            }
+        }
+        if generation.is_some() {
+            let open_datasets = &self.open_datasets;
+            self.change_feeds
+                .retain(|id, _| open_datasets.contains_key(id));
+        }
+    }
+}
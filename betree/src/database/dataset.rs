@@ -1,21 +1,66 @@
 use super::root_tree_msg::dataset;
 use super::{
-    errors::*, fetch_ds_data, Database, DatasetData, DatasetId, DatasetTree, Generation,
-    MessageTree, StorageInfo, RootDmu,
+    change_feed::{ChangeFeed, Mutation},
+    errors::*,
+    fetch_ds_data,
+    resume::{ResumableRange, ResumeToken},
+    watch::Watchers,
+    Database, DatasetData, DatasetId, DatasetTree, Generation, MessageTree, RootDmu, StorageInfo,
+    CONDITIONAL_MESSAGE_VERSION, RANGE_TOMBSTONE_VERSION,
 };
 use crate::{
+    checksum::{Builder, Checksum, State, XxHash},
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::Dml,
     migration::DatabaseMsg,
-    tree::{self, DefaultMessageAction, MessageAction, PivotKey, Tree, TreeLayer},
+    tree::{
+        self, DefaultMessageAction, MessageAction, PivotKey, Tree, TreeConfiguration, TreeLayer,
+    },
     StoragePreference,
 };
 
 #[cfg(feature = "internal-api")]
 use crate::tree::NodeInfo;
+#[cfg(feature = "profiling-counters")]
+use crate::tree::TreeCounters;
 
+use futures::executor::ThreadPool;
 use parking_lot::RwLock;
-use std::{borrow::Borrow, collections::HashSet, ops::RangeBounds, sync::Arc};
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    ops::{Bound, RangeBounds},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Number of key-value pairs which may be buffered by a [Dataset::par_range]
+/// iterator before its workers block.
+const PAR_RANGE_BUFFER: usize = 4096;
+
+/// How long [Database::flush_buffers] holds the locks of a tree at once.
+const DEFAULT_FLUSH_LOCK_BUDGET: Duration = Duration::from_millis(10);
+
+/// How often [Dataset::insert_with_deadline] checks whether the database has
+/// caught up.
+const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+fn to_owned_bound<K: Borrow<[u8]>>(bound: Bound<&K>) -> Bound<CowBytes> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.borrow().into()),
+        Bound::Excluded(key) => Bound::Excluded(key.borrow().into()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn to_vec_bound<K: Borrow<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.borrow().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.borrow().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
 
 /// The internal data set type.  This is the non-user facing variant which is
 /// then wrapped in the [Dataset] type.
@@ -25,9 +70,20 @@ pub struct DatasetInner<Message = DefaultMessageAction> {
     name: Box<[u8]>,
     pub(super) open_snapshots: HashSet<Generation>,
     storage_preference: StoragePreference,
+    change_feed: Arc<ChangeFeed>,
+    watchers: Arc<Watchers>,
+    // Runs the scans of `Dataset::par_range`.
+    background_pool: ThreadPool,
 }
 
 /// The data set type.
+///
+/// All handles of a data set share the same tree, and reads apply the
+/// messages still buffered in internal nodes on their way to the leaves.  A
+/// completed write is therefore observed by every following `get` and by every
+/// `range` created afterwards, from any handle and regardless of whether it
+/// has been flushed or synced yet.  A range iterator reads the tree leaf by
+/// leaf, so writes made while iterating may or may not be observed by it.
 pub struct Dataset<Message = DefaultMessageAction> {
     inner: Arc<RwLock<DatasetInner<Message>>>,
 }
@@ -101,13 +157,27 @@ impl Database {
             return Err(Error::InUse);
         }
         let storage_preference = StoragePreference::NONE;
-        let ds_tree = Tree::open(
-            id,
-            ds_data.ptr,
-            M::default(),
-            Arc::clone(self.root_tree.dmu()),
-            storage_preference,
-        );
+        let config = match self.root_tree.get(&dataset::config_key(id) as &[_])? {
+            Some(data) => bincode::deserialize(&data)?,
+            None => TreeConfiguration::default(),
+        };
+        let ds_tree = if self.is_read_only() {
+            Tree::open_ro(
+                ds_data.ptr,
+                M::default(),
+                Arc::clone(self.root_tree.dmu()),
+                storage_preference,
+            )
+        } else {
+            Tree::open_with_config(
+                id,
+                ds_data.ptr,
+                M::default(),
+                Arc::clone(self.root_tree.dmu()),
+                storage_preference,
+                config,
+            )
+        };
 
         if let Some(ss_id) = ds_data.previous_snapshot {
             self.root_tree
@@ -126,6 +196,9 @@ impl Database {
             name: Box::from(name),
             open_snapshots: Default::default(),
             storage_preference,
+            change_feed: self.change_feed(id),
+            watchers: Default::default(),
+            background_pool: self.background_pool.clone(),
         }
         .into();
 
@@ -196,6 +269,34 @@ impl Database {
         }
     }
 
+    /// Sets the tunables of the tree of the dataset `name`.  The configuration
+    /// is persisted and applies whenever the dataset is opened, so it can not
+    /// be changed while the dataset is open.
+    pub fn set_tree_configuration(&mut self, name: &[u8], config: TreeConfiguration) -> Result<()> {
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Err(Error::InvalidConfiguration(problems));
+        }
+        let id = self.lookup_dataset_id(name)?;
+        if self.open_datasets.contains_key(&id) {
+            return Err(Error::InUse);
+        }
+        self.root_tree.insert(
+            &dataset::config_key(id) as &[_],
+            DefaultMessageAction::insert_msg(&bincode::serialize(&config)?),
+            StoragePreference::NONE,
+        )?;
+        Ok(())
+    }
+
+    /// Drops the unmodified cache entries of the dataset `name`, leaving the
+    /// cached nodes of all other datasets in place.
+    pub fn drop_cache_for(&self, name: &[u8]) -> Result<()> {
+        let id = self.lookup_dataset_id(name)?;
+        self.root_tree.dmu().drop_cache_for(id);
+        Ok(())
+    }
+
     fn allocate_ds_id(&mut self) -> Result<DatasetId> {
         let key = &dataset::id_counter() as &[_];
         let last_ds_id = self
@@ -256,6 +357,32 @@ impl Database {
         drop(ds);
         Ok(())
     }
+
+    /// Flushes all messages buffered in the internal nodes of the given data
+    /// set down to its leaves, without writing anything back.
+    ///
+    /// Use this before read-heavy phases, e.g. of benchmarks, which should
+    /// neither pay for applying buffered messages on every read nor for
+    /// flushes caused by writes in between.
+    pub fn flush_buffers<Message: MessageAction + 'static>(
+        &self,
+        ds: &Dataset<Message>,
+    ) -> Result<()> {
+        self.flush_buffers_with_budget(ds, Some(DEFAULT_FLUSH_LOCK_BUDGET))
+    }
+
+    /// Flushes all messages buffered in the internal nodes of the given data
+    /// set like [Database::flush_buffers], but holds the locks of its nodes
+    /// for at most about `budget` at once before letting concurrent reads and
+    /// writes of the data set in.  With `None`, the locks are held until the
+    /// whole data set has been flushed, which is the fastest.
+    pub fn flush_buffers_with_budget<Message: MessageAction + 'static>(
+        &self,
+        ds: &Dataset<Message>,
+        budget: Option<Duration>,
+    ) -> Result<()> {
+        Ok(ds.inner.read().tree.flush_buffers(budget)?)
+    }
 }
 
 impl<Message: MessageAction + 'static> DatasetInner<Message> {
@@ -276,14 +403,113 @@ impl<Message: MessageAction + 'static> DatasetInner<Message> {
         msg: SlicedCowBytes,
         storage_preference: StoragePreference,
     ) -> Result<()> {
-        Ok(self
-            .tree
-            .insert(key, msg, storage_preference.or(self.storage_preference))?)
+        let storage_preference = storage_preference.or(self.storage_preference);
+        self.tree
+            .dmu()
+            .handler()
+            .io_accounting
+            .logical_write(self.id, (key.borrow().len() + msg.len()) as u64);
+        if !self.change_feed.is_active() && !self.watchers.is_active() {
+            return Ok(self.tree.insert(key, msg, storage_preference)?);
+        }
+        let key = key.into();
+        if self.change_feed.is_active() {
+            let mutation = Mutation::Message {
+                key: key.clone(),
+                msg: msg.clone(),
+            };
+            self.change_feed.record(mutation, || {
+                Ok(self.tree.insert(key.clone(), msg, storage_preference)?)
+            })?;
+        } else {
+            self.tree.insert(key.clone(), msg, storage_preference)?;
+        }
+        if self.watchers.is_active() {
+            self.watchers.notify(&key);
+        }
+        Ok(())
     }
 
     /// Returns the value for the given key if existing.
     pub fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>> {
-        Ok(self.tree.get(key)?)
+        self.get_with_pref(key, StoragePreference::NONE)
+    }
+
+    /// Returns the value for the given key if existing, and moves it to
+    /// `storage_preference` if it is stored on a slower tier.
+    pub fn get_with_pref<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+        storage_preference: StoragePreference,
+    ) -> Result<Option<SlicedCowBytes>> {
+        let value = self
+            .tree
+            .get_with_info(key, storage_preference)?
+            .map(|(_info, data)| data);
+        if let Some(value) = &value {
+            self.tree
+                .dmu()
+                .handler()
+                .io_accounting
+                .logical_read(self.id, value.len() as u64);
+        }
+        Ok(value)
+    }
+
+    /// Returns the length of the value for the given key if existing.
+    pub fn value_len<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<usize>> {
+        Ok(self.tree.value_len(key)?)
+    }
+
+    /// Returns whether a value exists for the given key.
+    pub fn contains_key<K: Borrow<[u8]>>(&self, key: K) -> Result<bool> {
+        Ok(self.value_len(key)?.is_some())
+    }
+
+    /// Returns the hash of the value for the given key if existing.
+    pub fn content_hash<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<XxHash>> {
+        Ok(self.tree.get(key)?.map(|value| {
+            let mut state = XxHash::builder().build();
+            state.ingest(&value);
+            state.finish()
+        }))
+    }
+
+    /// Prefetches the leaves which may contain keys within `start..=end`.
+    pub(crate) fn prefetch_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        Ok(self.tree.prefetch_range(start, end)?)
+    }
+
+    /// Flushes the messages buffered for keys within `start..=end` down to
+    /// the leaves.
+    pub(crate) fn flush_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        Ok(self.tree.flush_range(start, end)?)
+    }
+
+    /// Returns up to `count` sorted keys which split `start..end` into parts
+    /// of similar size.
+    pub(crate) fn split_points(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        count: usize,
+    ) -> Result<Vec<CowBytes>> {
+        Ok(self.tree.split_points(start, end, count)?)
+    }
+
+    /// Returns the leaves which may contain keys within `start..=end`, see
+    /// [Dataset::leaf_classes].
+    pub(crate) fn leaf_classes(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<Vec<(Option<CowBytes>, Option<u8>)>> {
+        Ok(self
+            .tree
+            .leaf_pointers(start, end)?
+            .into_iter()
+            .map(|(last_key, ptr)| (last_key, ptr.map(|ptr| ptr.offset().storage_class())))
+            .collect())
     }
 
     /// Immutably fetch a given node by its pivot key.
@@ -313,6 +539,19 @@ impl<Message: MessageAction + 'static> DatasetInner<Message> {
         Ok(self.tree.get_node_pivot(pk)?)
     }
 
+    /// For tests only: Returns the storage preference of the newest entry of
+    /// `key`, e.g. to check the effect of a migration.
+    #[cfg(feature = "internal-api")]
+    pub fn test_storage_preference<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<StoragePreference>> {
+        Ok(self
+            .tree
+            .get_with_info(key, StoragePreference::NONE)?
+            .map(|(info, _)| *info.storage_preference()))
+    }
+
     /// Iterates over all key-value pairs in the given key range.
     pub fn range<R, K>(
         &self,
@@ -322,7 +561,33 @@ impl<Message: MessageAction + 'static> DatasetInner<Message> {
         R: RangeBounds<K>,
         K: Borrow<[u8]> + Into<CowBytes>,
     {
-        Ok(Box::new(self.tree.range(range)?.map(|r| Ok(r?))))
+        let dmu = Arc::clone(self.tree.dmu());
+        let id = self.id;
+        Ok(Box::new(self.tree.range(range)?.map(move |r| {
+            let (key, value) = r?;
+            dmu.handler()
+                .io_accounting
+                .logical_read(id, (key.len() + value.len()) as u64);
+            Ok((key, value))
+        })))
+    }
+
+    /// Iterates over all keys in the given key range, without reading their
+    /// values where possible.
+    pub fn range_keys<R, K>(&self, range: R) -> Result<Box<dyn Iterator<Item = Result<CowBytes>>>>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        let dmu = Arc::clone(self.tree.dmu());
+        let id = self.id;
+        Ok(Box::new(self.tree.range_keys(range)?.map(move |r| {
+            let key = r?;
+            dmu.handler()
+                .io_accounting
+                .logical_read(id, key.len() as u64);
+            Ok(key)
+        })))
     }
 
     /// Returns the name of the data set.
@@ -335,6 +600,19 @@ impl<Message: MessageAction + 'static> DatasetInner<Message> {
     pub fn tree_dump(&self) -> Result<NodeInfo> {
         Ok(self.tree.tree_dump()?)
     }
+
+    /// Returns the profiling counters of this data set since it has been
+    /// opened or [Dataset::reset_counters] has been called.
+    #[cfg(feature = "profiling-counters")]
+    pub fn counters(&self) -> TreeCounters {
+        self.tree.counters()
+    }
+
+    /// Resets the profiling counters of this data set to zero.
+    #[cfg(feature = "profiling-counters")]
+    pub fn reset_counters(&self) {
+        self.tree.reset_counters()
+    }
 }
 
 // Member access on internal type
@@ -343,6 +621,19 @@ impl<Message> Dataset<Message> {
         self.inner.read().id
     }
 
+    pub(super) fn change_feed(&self) -> Arc<ChangeFeed> {
+        Arc::clone(&self.inner.read().change_feed)
+    }
+
+    pub(super) fn watchers(&self) -> Arc<Watchers> {
+        Arc::clone(&self.inner.read().watchers)
+    }
+
+    /// The generation which will be committed by the next sync.
+    pub(super) fn current_generation(&self) -> Generation {
+        self.inner.read().tree.dmu().handler().current_generation()
+    }
+
     pub(super) fn call_open_snapshots<F, R>(&self, call: F) -> R
     where
         F: FnOnce(&HashSet<Generation>) -> R,
@@ -394,6 +685,72 @@ impl<Message: MessageAction + 'static> Dataset<Message> {
         self.inner.read().get(key)
     }
 
+    /// Returns the value for the given key if existing.  If the leaf holding
+    /// the value is stored on a slower tier than `storage_preference`, it is
+    /// moved there the next time it is written or fetched, like the nodes a
+    /// migration policy promotes.  This allows applications to promote data
+    /// they know to be hot on access, without rewriting it.
+    pub fn get_with_pref<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+        storage_preference: StoragePreference,
+    ) -> Result<Option<SlicedCowBytes>> {
+        self.inner.read().get_with_pref(key, storage_preference)
+    }
+
+    /// Returns the length of the value for the given key if existing.  Unlike
+    /// [Self::get], this does not read the value if a buffered message
+    /// overwrites it or its length is known without it.
+    pub fn value_len<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<usize>> {
+        self.inner.read().value_len(key)
+    }
+
+    /// Returns whether a value exists for the given key.
+    pub fn contains_key<K: Borrow<[u8]>>(&self, key: K) -> Result<bool> {
+        self.inner.read().contains_key(key)
+    }
+
+    /// Returns the [XxHash] of the value for the given key if existing.
+    ///
+    /// The hash only depends on the value, so it can be compared to one
+    /// computed by the application from its own copy of the value, or to the
+    /// hashes of other values to find duplicates, without transferring the
+    /// value itself.
+    pub fn content_hash<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<XxHash>> {
+        self.inner.read().content_hash(key)
+    }
+
+    /// Prefetches the leaves which may contain keys within `start..=end`.
+    pub(crate) fn prefetch_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.inner.read().prefetch_range(start, end)
+    }
+
+    /// For tests only: Prefetches the leaves which may contain keys within
+    /// `start..=end`.
+    #[cfg(feature = "internal-api")]
+    pub fn test_prefetch_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.prefetch_range(start, end)
+    }
+
+    /// Flushes the messages buffered for keys within `start..=end` down to
+    /// the leaves, see [Database::flush_buffers].
+    pub(crate) fn flush_range(&self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.inner.read().flush_range(start, end)
+    }
+
+    /// Returns the leaves which may contain keys within `start..=end` in key
+    /// order, with the last key each of them may contain, `None` for the
+    /// rightmost leaf, and the storage class they reside on.  Leaves which have
+    /// been modified since they have been written have no storage class yet.
+    /// Messages still buffered in internal nodes are not considered.
+    pub(crate) fn leaf_classes(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<Vec<(Option<CowBytes>, Option<u8>)>> {
+        self.inner.read().leaf_classes(start, end)
+    }
+
     /// Iterates over all key-value pairs in the given key range.
     pub fn range<R, K>(
         &self,
@@ -406,6 +763,104 @@ impl<Message: MessageAction + 'static> Dataset<Message> {
         self.inner.read().range(range)
     }
 
+    /// Iterates over all key-value pairs in the given key range like
+    /// [Dataset::range], but the iteration can be continued later from its
+    /// [ResumeToken] by [Dataset::resume_range].
+    pub fn resumable_range<R, K>(&self, range: R) -> Result<ResumableRange<Message>>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]>,
+    {
+        ResumableRange::new(
+            &self.inner.read().tree,
+            self.id(),
+            to_vec_bound(range.start_bound()),
+            to_vec_bound(range.end_bound()),
+        )
+    }
+
+    /// Continues an iteration of [Dataset::resumable_range] after the last
+    /// pair it had returned when `token` was taken.
+    pub fn resume_range(&self, token: &ResumeToken) -> Result<ResumableRange<Message>> {
+        ResumableRange::resume(&self.inner.read().tree, self.id(), token)
+    }
+
+    /// Iterates over all keys in the given key range.  In contrast to
+    /// [Dataset::range], values are only read if buffered messages have to be
+    /// applied to them, which saves the cost of fetching and copying them for
+    /// counting entries or building indices.
+    pub fn range_keys<R, K>(&self, range: R) -> Result<Box<dyn Iterator<Item = Result<CowBytes>>>>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        self.inner.read().range_keys(range)
+    }
+
+    /// Iterates over all key-value pairs in the given key range like
+    /// [Dataset::range], but splits the range at pivots of the tree into up to
+    /// `shards` parts which are scanned concurrently by the background pool
+    /// of the database, see [super::ThreadConfiguration::scan_threads].
+    /// Pairs of the same part are yielded in order, but pairs of different
+    /// parts are interleaved arbitrarily.  Dropping the iterator stops the
+    /// scans.
+    pub fn par_range<R, K>(
+        &self,
+        range: R,
+        shards: usize,
+    ) -> Result<Box<dyn Iterator<Item = Result<(CowBytes, SlicedCowBytes)>>>>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]>,
+    {
+        let start = to_owned_bound(range.start_bound());
+        let end = to_owned_bound(range.end_bound());
+
+        let start_key = match start {
+            Bound::Included(ref key) | Bound::Excluded(ref key) => &key[..],
+            Bound::Unbounded => &[],
+        };
+        let end_key = match end {
+            Bound::Included(ref key) | Bound::Excluded(ref key) => Some(&key[..]),
+            Bound::Unbounded => None,
+        };
+        let splits =
+            self.inner
+                .read()
+                .split_points(start_key, end_key, shards.saturating_sub(1))?;
+
+        let mut parts = Vec::with_capacity(splits.len() + 1);
+        let mut lower = start.clone();
+        for split in splits.into_iter().filter(|split| &split[..] > start_key) {
+            parts.push((lower, Bound::Included(split.clone())));
+            lower = Bound::Excluded(split);
+        }
+        parts.push((lower, end));
+
+        let (tx, rx) = crossbeam_channel::bounded(PAR_RANGE_BUFFER);
+        let pool = self.inner.read().background_pool.clone();
+        for part in parts {
+            let ds = self.clone();
+            let tx = tx.clone();
+            pool.spawn_ok(async move {
+                let iter = match ds.range::<_, CowBytes>(part) {
+                    Ok(iter) => iter,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                };
+                for item in iter {
+                    // The receiving iterator has been dropped.
+                    if tx.send(item).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(Box::new(rx.into_iter()))
+    }
+
     /// Returns the name of the data set.
     pub fn name(&self) -> Box<[u8]> {
         self.inner.read().name.clone()
@@ -416,6 +871,19 @@ impl<Message: MessageAction + 'static> Dataset<Message> {
     pub fn tree_dump(&self) -> Result<NodeInfo> {
         self.inner.read().tree_dump()
     }
+
+    /// Returns the profiling counters of this data set since it has been
+    /// opened or [Dataset::reset_counters] has been called.
+    #[cfg(feature = "profiling-counters")]
+    pub fn counters(&self) -> TreeCounters {
+        self.inner.read().counters()
+    }
+
+    /// Resets the profiling counters of this data set to zero.
+    #[cfg(feature = "profiling-counters")]
+    pub fn reset_counters(&self) {
+        self.inner.read().reset_counters()
+    }
 }
 
 impl DatasetInner<DefaultMessageAction> {
@@ -431,6 +899,7 @@ impl DatasetInner<DefaultMessageAction> {
         if data.len() > tree::MAX_MESSAGE_SIZE {
             return Err(Error::MessageTooLarge);
         }
+        self.check_space(storage_preference)?;
         self.insert_msg_with_pref(
             key,
             DefaultMessageAction::insert_msg(data),
@@ -445,6 +914,57 @@ impl DatasetInner<DefaultMessageAction> {
         self.insert_with_pref(key, data, StoragePreference::NONE)
     }
 
+    /// Inserts the given key-value pair unless modified nodes fill the cache
+    /// beyond the dirty watermark, in which case it fails with
+    /// [Error::Backpressure], or the storage classes are filled up to their
+    /// reserve, in which case it fails with [Error::OutOfSpace].
+    pub fn try_insert_with_pref<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        data: &[u8],
+        storage_preference: StoragePreference,
+    ) -> Result<()> {
+        self.check_backpressure()?;
+        self.insert_with_pref(key, data, storage_preference)
+    }
+
+    /// Inserts the given key-value pair, see [Self::try_insert_with_pref].
+    pub fn try_insert<K: Borrow<[u8]> + Into<CowBytes>>(&self, key: K, data: &[u8]) -> Result<()> {
+        self.try_insert_with_pref(key, data, StoragePreference::NONE)
+    }
+
+    /// Inserts the given key-value pair like [Self::try_insert_with_pref],
+    /// but waits until `deadline` for the cache or the storage classes to
+    /// make room before failing.
+    ///
+    /// Modified nodes are only written back by syncs and once the cache is
+    /// full, so waiting for the dirty watermark is futile without a periodic
+    /// sync or a concurrent writer.
+    pub fn insert_with_deadline<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        data: &[u8],
+        storage_preference: StoragePreference,
+        deadline: Instant,
+    ) -> Result<()> {
+        loop {
+            let res = self
+                .check_backpressure()
+                .and_then(|()| self.check_space(storage_preference));
+            match res {
+                Err(e @ (Error::Backpressure { .. } | Error::OutOfSpace { .. })) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(e);
+                    }
+                    thread::sleep(BACKPRESSURE_POLL_INTERVAL.min(deadline - now));
+                }
+                Err(e) => return Err(e),
+                Ok(()) => return self.insert_with_pref(key, data, storage_preference),
+            }
+        }
+    }
+
     /// Upserts the value for the given key at the given offset.
     ///
     /// Note that the value will be zeropadded as needed.
@@ -458,9 +978,7 @@ impl DatasetInner<DefaultMessageAction> {
         if offset as usize + data.len() > tree::MAX_MESSAGE_SIZE {
             return Err(Error::MessageTooLarge);
         }
-        // TODO: In case of overfilling the underlying storage we should notify in _any_ case that the writing is not successfull, for this
-        // we need to know wether the space to write out has been expanded. For this we need further information which we ideally do not want
-        // to read out from the disk here.
+        self.check_space(storage_preference)?;
         self.insert_msg_with_pref(
             key,
             DefaultMessageAction::upsert_msg(offset, data),
@@ -480,6 +998,81 @@ impl DatasetInner<DefaultMessageAction> {
         self.upsert_with_pref(key, data, offset, StoragePreference::NONE)
     }
 
+    /// Inserts the given key-value pair if the key does not exist yet and
+    /// returns whether it has been inserted.
+    ///
+    /// The caller has to exclude other writes to the data set between the
+    /// check and the write, see [Dataset::insert_if_absent].  The condition
+    /// is evaluated again when the message is applied to the value of the key.
+    pub fn insert_if_absent<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        data: &[u8],
+    ) -> Result<bool> {
+        if data.len() > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        let current = self.get::<&[u8]>(key.borrow())?;
+        if current.is_some() {
+            return Ok(false);
+        }
+        self.check_space(StoragePreference::NONE)?;
+        let msg = if self.conditional_messages() {
+            DefaultMessageAction::insert_if_absent_msg(data)
+        } else {
+            DefaultMessageAction::insert_msg(data)
+        };
+        self.insert_msg_with_pref(key, msg, StoragePreference::NONE)?;
+        Ok(true)
+    }
+
+    /// Replaces the value of the given key by `new` if it is equal to
+    /// `expected`, where `None` stands for an absent key, and returns whether
+    /// it has been replaced.  A `new` value of `None` deletes the key.
+    ///
+    /// Like [Self::insert_if_absent], the caller has to exclude other writes
+    /// to the data set.
+    pub fn compare_and_swap<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let len = expected.map_or(0, <[u8]>::len) + new.map_or(0, <[u8]>::len);
+        if len > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        let current = self.get::<&[u8]>(key.borrow())?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        if new.is_some() {
+            self.check_space(StoragePreference::NONE)?;
+        }
+        let msg = if self.conditional_messages() {
+            DefaultMessageAction::compare_and_swap_msg(expected, new)
+        } else {
+            new.map_or_else(
+                DefaultMessageAction::delete_msg,
+                DefaultMessageAction::insert_msg,
+            )
+        };
+        self.insert_msg_with_pref(key, msg, StoragePreference::NONE)?;
+        Ok(true)
+    }
+
+    /// Returns whether conditional messages may be written, which pools of an
+    /// older format version than [CONDITIONAL_MESSAGE_VERSION] can not read.
+    /// Their plain counterparts are written instead.
+    fn conditional_messages(&self) -> bool {
+        self.tree
+            .dmu()
+            .handler()
+            .format_version
+            .load(Ordering::Acquire)
+            >= CONDITIONAL_MESSAGE_VERSION
+    }
+
     /// Given a key and storage preference notify for this entry to be moved to a new storage level.
     /// If the key is already located on this layer no operation is performed and success is returned.
     ///
@@ -507,6 +1100,29 @@ impl DatasetInner<DefaultMessageAction> {
         )
     }
 
+    /// Refuses new data once the storage classes it would be written to are
+    /// filled up to their emergency reserve.  Deletions are not checked, as
+    /// they are needed to free space again.
+    fn check_space(&self, storage_preference: StoragePreference) -> Result<()> {
+        let storage_preference = storage_preference.or(self.storage_preference);
+        match self.tree.dmu().out_of_space(storage_preference) {
+            Some(class) => Err(Error::OutOfSpace { class }),
+            None => Ok(()),
+        }
+    }
+
+    /// Refuses new data once modified nodes fill the cache beyond the dirty
+    /// watermark, see [super::DatabaseConfiguration::dirty_watermark_percent].
+    fn check_backpressure(&self) -> Result<()> {
+        let dmu = self.tree.dmu();
+        let (dirty, watermark) = (dmu.dirty_size(), dmu.dirty_watermark());
+        if dirty > watermark {
+            Err(Error::Backpressure { dirty, watermark })
+        } else {
+            Ok(())
+        }
+    }
+
     pub(crate) fn free_space_tier(&self, pref: StoragePreference) -> Result<StorageInfo> {
         if let Some(info) = self.tree.dmu().handler().free_space_tier(pref.as_u8()) {
             Ok(info)
@@ -521,17 +1137,57 @@ impl DatasetInner<DefaultMessageAction> {
         R: RangeBounds<K>,
         K: Borrow<[u8]> + Into<CowBytes>,
     {
-        let mut res = Ok(());
+        if !self.change_feed.is_active() && !self.watchers.is_active() {
+            return self.delete_range(range);
+        }
+        let start = to_owned_bound(range.start_bound());
+        let end = to_owned_bound(range.end_bound());
+        if self.change_feed.is_active() {
+            let mutation = Mutation::RangeDelete {
+                start: start.clone(),
+                end: end.clone(),
+            };
+            self.change_feed
+                .record(mutation, || self.delete_range(range))?;
+        } else {
+            self.delete_range(range)?;
+        }
+        if self.watchers.is_active() {
+            self.watchers.notify_range(&start, &end);
+        }
+        Ok(())
+    }
 
+    /// Buffers a range tombstone for the given range.  Pools of an older
+    /// format version than [RANGE_TOMBSTONE_VERSION] can not read range
+    /// tombstones, there each key of the range is deleted on its own.
+    fn delete_range<R, K>(&self, range: R) -> Result<()>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        let format_version = self
+            .tree
+            .dmu()
+            .handler()
+            .format_version
+            .load(Ordering::Acquire);
+        if format_version >= RANGE_TOMBSTONE_VERSION {
+            return Ok(self.tree.range_delete(range)?);
+        }
+        let mut res = Ok(());
         for (k, _v) in self.tree.range(range)?.flatten() {
             // keep going even on errors, return earliest Err
-            let del_res = self.delete(k);
+            let del_res = self.tree.insert(
+                k,
+                DefaultMessageAction::delete_msg(),
+                StoragePreference::NONE,
+            );
             if del_res.is_err() && res.is_ok() {
                 res = del_res;
             }
         }
-
-        res
+        Ok(res?)
     }
 
     /// Migrate a complete range of keys to another storage preference.
@@ -551,6 +1207,15 @@ impl DatasetInner<DefaultMessageAction> {
 
 // Mirroring the [DatasetInner] API
 impl Dataset<DefaultMessageAction> {
+    /// Fails like [Self::insert] would for a value of `len` bytes, without
+    /// inserting anything.
+    pub(crate) fn check_insert(&self, len: usize) -> Result<()> {
+        if len > tree::MAX_MESSAGE_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        self.inner.read().check_space(StoragePreference::NONE)
+    }
+
     /// Inserts the given key-value pair.
     ///
     /// Note that any existing value will be overwritten.
@@ -572,6 +1237,45 @@ impl Dataset<DefaultMessageAction> {
         self.inner.read().insert(key, data)
     }
 
+    /// Inserts the given key-value pair unless modified nodes fill the cache
+    /// beyond the dirty watermark, in which case it fails with
+    /// [Error::Backpressure], or the storage classes are filled up to their
+    /// reserve, in which case it fails with [Error::OutOfSpace].
+    pub fn try_insert_with_pref<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        data: &[u8],
+        storage_preference: StoragePreference,
+    ) -> Result<()> {
+        self.inner
+            .read()
+            .try_insert_with_pref(key, data, storage_preference)
+    }
+
+    /// Inserts the given key-value pair, see [Self::try_insert_with_pref].
+    pub fn try_insert<K: Borrow<[u8]> + Into<CowBytes>>(&self, key: K, data: &[u8]) -> Result<()> {
+        self.inner.read().try_insert(key, data)
+    }
+
+    /// Inserts the given key-value pair like [Self::try_insert_with_pref],
+    /// but waits until `deadline` for the cache or the storage classes to
+    /// make room before failing.
+    ///
+    /// Modified nodes are only written back by syncs and once the cache is
+    /// full, so waiting for the dirty watermark is futile without a periodic
+    /// sync or a concurrent writer.
+    pub fn insert_with_deadline<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        data: &[u8],
+        storage_preference: StoragePreference,
+        deadline: Instant,
+    ) -> Result<()> {
+        self.inner
+            .read()
+            .insert_with_deadline(key, data, storage_preference, deadline)
+    }
+
     /// Upserts the value for the given key at the given offset.
     ///
     /// Note that the value will be zeropadded as needed.
@@ -599,6 +1303,33 @@ impl Dataset<DefaultMessageAction> {
         self.inner.read().upsert(key, data, offset)
     }
 
+    /// Inserts the given key-value pair if the key does not exist yet and
+    /// returns whether it has been inserted.
+    ///
+    /// The check and the write hold the data set exclusively, so no other
+    /// operation through any of its handles comes in between.
+    pub fn insert_if_absent<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        data: &[u8],
+    ) -> Result<bool> {
+        self.inner.write().insert_if_absent(key, data)
+    }
+
+    /// Replaces the value of the given key by `new` if it is equal to
+    /// `expected`, where `None` stands for an absent key, and returns whether
+    /// it has been replaced.  A `new` value of `None` deletes the key.
+    ///
+    /// Like [Self::insert_if_absent], the data set is held exclusively.
+    pub fn compare_and_swap<K: Borrow<[u8]> + Into<CowBytes>>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.inner.write().compare_and_swap(key, expected, new)
+    }
+
     /// Immutably fetch a given node by its pivot key.
     pub(crate) fn get_node_pivot(
         &self,
@@ -623,6 +1354,16 @@ impl Dataset<DefaultMessageAction> {
         self.inner.read().test_get_node_pivot(pk)
     }
 
+    /// For tests only: Returns the storage preference of the newest entry of
+    /// `key`, e.g. to check the effect of a migration.
+    #[cfg(feature = "internal-api")]
+    pub fn test_storage_preference<K: Borrow<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<StoragePreference>> {
+        self.inner.read().test_storage_preference(key)
+    }
+
     /// Given a key and storage preference notify for this entry to be moved to a new storage level.
     /// If the key is already located on this layer no operation is performed and success is returned.
     ///
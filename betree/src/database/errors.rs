@@ -1,10 +1,40 @@
 #![allow(missing_docs, unused_doc_comments)]
 
+use super::ConfigurationProblem;
 use crate::vdev::Block;
+use itertools::Itertools;
 use thiserror::Error;
 
 pub type Result<R> = std::result::Result<R, Error>;
 
+/// The cause of an [Error], independent of the layer it has been raised in,
+/// so that callers can react to it programmatically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// No storage class could provide the requested space.
+    OutOfSpace {
+        /// The storage class which has been requested, if known.
+        class: Option<u8>,
+    },
+    /// Reading from or writing to a storage device has failed.
+    Io {
+        /// The id of the failing vdev, if known.
+        vdev: Option<String>,
+    },
+    /// Data read from a storage device does not match its checksum.
+    ChecksumMismatch,
+    /// The configuration of the database or the storage pool is invalid.
+    Configuration,
+    /// A modification has been attempted on a read-only tree, e.g. a
+    /// snapshot.
+    ReadOnly,
+    /// The write has been refused to shed load, it may succeed once the
+    /// database has caught up.
+    Backpressure,
+    /// Any other error, including usage errors and internal errors.
+    Other,
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Virtual device encountered an error.")]
@@ -46,6 +76,12 @@ pub enum Error {
     Closed,
     #[error("Superblock corrupted.")]
     InvalidSuperblock,
+    #[error("The pool uses the unsupported on-disk format version {0}.")]
+    UnsupportedFormatVersion(u32),
+    #[error("The pool is in use by host {host_id:#x}. Export it there or set `force_open`.")]
+    PoolInUse { host_id: u64 },
+    #[error("The database has been opened read-only.")]
+    ReadOnly,
     #[error("Key does not exist.")]
     DoesNotExist,
     #[error("Dataset name already occupied. Try to `.open()` the dataset instead.")]
@@ -63,10 +99,58 @@ pub enum Error {
     },
     #[error("Migration is not possible as {1:?} blocks are not available in tier {0}.")]
     MigrationWouldExceedStorage(u8, Block<u64>),
+    #[error("Storage class {class} is out of space, its reserve is kept for syncs and deletions.")]
+    OutOfSpace { class: u8 },
+    #[error("The cache holds {dirty} modified bytes, above the watermark of {watermark}.")]
+    Backpressure { dirty: usize, watermark: usize },
     #[error("Migration is not possible as the given tier does not exist.")]
     MigrationNotPossible,
+    #[error("Storage class {0} has no vdev with id {1}.")]
+    VdevNotFound(u8, u16),
+    #[error("The transaction conflicts with a concurrent modification.")]
+    TransactionConflict,
+    #[error("The replication peer sent an unexpected message.")]
+    UnexpectedReplicationMessage,
     #[error("Null bytes are disallowed in keys.")]
     KeyContainsNullByte,
+    #[error("The object store already contains data.")]
+    NotEmpty,
+    #[error("The write would exceed the quota of {quota} bytes, of which {used} are used.")]
+    QuotaExceeded { quota: u64, used: u64 },
+    #[error("Two different chunks have the same content id.")]
+    ChunkHashCollision,
+    #[error("The configuration is invalid: {}", .0.iter().join("; "))]
+    InvalidConfiguration(Vec<ConfigurationProblem>),
     #[error("{0}")]
     Generic(String),
 }
+
+impl Error {
+    /// Returns the cause of this error, looking through the errors of the
+    /// lower layers it wraps.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::VdevError { source } => source.category(),
+            Error::StoragePoolError { source } => match source.kind() {
+                crate::storage_pool::ErrorKind::Configuration(_) => ErrorCategory::Configuration,
+                crate::storage_pool::ErrorKind::Io(_) => ErrorCategory::Io { vdev: None },
+                _ => ErrorCategory::Other,
+            },
+            Error::TreeError { source } => source.category(),
+            Error::DmlError { source } => source.category(),
+            Error::IoError { .. } => ErrorCategory::Io { vdev: None },
+            Error::ConfigurationError { .. }
+            | Error::InvalidConfiguration(_)
+            | Error::VdevNotFound(..)
+            | Error::PoolInUse { .. } => ErrorCategory::Configuration,
+            Error::ReadOnly => ErrorCategory::ReadOnly,
+            Error::Backpressure { .. } => ErrorCategory::Backpressure,
+            Error::OutOfSpace { class } | Error::MigrationWouldExceedStorage(class, _) => {
+                ErrorCategory::OutOfSpace {
+                    class: Some(*class),
+                }
+            }
+            _ => ErrorCategory::Other,
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use super::Database;
+use crate::clock::Clock;
+use parking_lot::RwLock;
+use std::{sync::Weak, time::Duration};
+
+pub fn gc_timer(interval_ms: u64, clock: Clock, db: Weak<RwLock<Database>>) {
+    let _guard = clock.register();
+    let interval = Duration::from_millis(interval_ms);
+
+    loop {
+        clock.sleep(interval);
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => return,
+        };
+
+        log::debug!("collecting orphaned object chunks");
+        match db.write().collect_orphaned_chunks() {
+            Ok(0) => {}
+            Ok(reclaimed) => log::info!("reclaimed chunks of {} orphaned objects", reclaimed),
+            Err(err) => log::error!("couldn't collect orphaned chunks: {}", err),
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use super::{
+    amplification::IoAccounting,
     errors::*,
-    root_tree_msg::{deadlist, segment, space_accounting},
+    root_tree_msg::{deadlist, replica, segment, slab, space_accounting},
+    storage_info::SpaceWatchers,
     AtomicStorageInfo, DatasetId, DeadListData, Generation, StorageInfo, TreeInner,
+    ROOT_DATASET_ID,
 };
 use crate::{
-    allocator::{Action, SegmentAllocator, SegmentId, SEGMENT_SIZE_BYTES},
+    allocator::{Action, SegmentAllocator, SegmentId, SEGMENT_SIZE, SEGMENT_SIZE_BYTES},
     atomic_option::AtomicOption,
     cow_bytes::SlicedCowBytes,
     data_management::{CopyOnWriteEvent, Dml, HasStoragePreference, ObjectReference},
@@ -16,9 +19,9 @@ use owning_ref::OwningRef;
 use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use seqlock::SeqLock;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU32, AtomicU64, Ordering},
         Arc,
     },
 };
@@ -40,6 +43,17 @@ pub fn update_storage_info(info: &StorageInfo) -> Option<SlicedCowBytes> {
         .map(|bytes| DefaultMessageAction::upsert_msg(0, bytes))
 }
 
+/// A message releasing blocks of a dataset, which must not be committed
+/// before its root pointer, see `Database::sync_datasets`.
+pub(crate) struct ReleasedBlocks {
+    pub(crate) dataset_id: DatasetId,
+    // The disk and the number of blocks given back to it, if they have not
+    // been moved to a dead list.
+    pub(crate) freed: Option<(GlobalDiskId, Block<u64>)>,
+    pub(crate) key: Box<[u8]>,
+    pub(crate) msg: SlicedCowBytes,
+}
+
 /// The database handler, holding management data for interactions
 /// between the database and data management layers.
 pub struct Handler<OR: ObjectReference> {
@@ -51,16 +65,34 @@ pub struct Handler<OR: ObjectReference> {
     // Free Space counted as blocks
     pub(crate) free_space: HashMap<GlobalDiskId, AtomicStorageInfo>,
     pub(crate) free_space_tier: Vec<AtomicStorageInfo>,
+    // Receivers of changes of the fill level of the tiers.
+    pub(crate) space_watchers: SpaceWatchers,
     pub(crate) delayed_messages: Mutex<Vec<(Box<[u8]>, SlicedCowBytes)>>,
+    pub(crate) released_blocks: Mutex<Vec<ReleasedBlocks>>,
     pub(crate) last_snapshot_generation: RwLock<HashMap<DatasetId, Generation>>,
+    // The generations pinned by savepoints, with the number of views opened
+    // of each savepoint.
+    pub(crate) savepoints: RwLock<BTreeMap<Generation, usize>>,
     // Cache for allocators which have been in use since the last sync. This is
     // done to avoid cyclical updates on evictions.
     // NOTE: This map needs to be updated/emptied on sync's as the internal
     // representation is not updated on deallocation to avoid overwriting
     // potentially valid fallback data.
-    pub(crate) allocators: RwLock<HashMap<SegmentId, RwLock<SegmentAllocator>>>,
+    // The map is sharded by segment, so that loading a bitmap does not stall
+    // allocations in the segments of other shards.
+    pub(crate) allocators: [AllocatorShard; ALLOCATOR_SHARDS],
     pub(crate) allocations: AtomicU64,
     pub(crate) old_root_allocation: SeqLock<Option<(DiskOffset, Block<u32>)>>,
+    pub(crate) io_accounting: IoAccounting,
+    // The on-disk format version of the pool, which determines what may be
+    // recorded in newly written nodes.
+    pub(crate) format_version: AtomicU32,
+    // Slab blocks of which slots have been freed, by the generation of their
+    // objects.  They are freed by a sync once all of their slots are free.
+    pub(crate) freed_slabs: Mutex<HashMap<DiskOffset, Generation>>,
+    // Number of updates of the allocation bitmap of each segment since it has
+    // last been checkpointed.  Counting starts over when the pool is opened.
+    pub(crate) segment_deltas: Mutex<HashMap<SegmentId, u32>>,
 }
 
 impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
@@ -90,11 +122,22 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
     }
 
     pub(super) fn bump_generation(&self) {
-        self.allocators.write().clear();
+        for shard in self.allocators.iter() {
+            shard.write().clear();
+        }
         self.current_generation.lock_write().0 += 1;
     }
 }
 
+/// Number of shards of [Handler::allocators].
+pub(crate) const ALLOCATOR_SHARDS: usize = 16;
+
+pub(crate) type AllocatorShard = RwLock<HashMap<SegmentId, RwLock<SegmentAllocator>>>;
+
+fn allocator_shard(id: SegmentId) -> usize {
+    (id.0 / SEGMENT_SIZE as u64) as usize % ALLOCATOR_SHARDS
+}
+
 pub struct SegmentAllocatorGuard<'a> {
     inner: RwLockReadGuard<'a, HashMap<SegmentId, RwLock<SegmentAllocator>>>,
     id: SegmentId,
@@ -124,6 +167,7 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
         self.allocations.fetch_add(1, Ordering::Release);
         let id = SegmentId::get(offset);
         let key = segment::id_to_key(id);
+        self.record_segment_delta(id);
         let disk_key = offset.class_disk_id();
         let msg = update_allocation_bitmap_msg(offset, size, action);
         // NOTE: We perform double the amount of atomics here than necessary, but we do this for now to avoid reiteration
@@ -149,6 +193,7 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
                     .fetch_sub(size.as_u64(), Ordering::Relaxed);
             }
         };
+        self.tier_changed(offset.storage_class());
 
         let mut delayed_msgs = self.delayed_messages.lock();
         delayed_msgs.push((key.into(), msg));
@@ -159,13 +204,33 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
         Ok(())
     }
 
+    /// Accounts for `grown` blocks which have been added to the end of a
+    /// disk.  The new blocks are implicitly free, as the allocation bitmaps
+    /// of segments which have never been written are empty.
+    pub(crate) fn grow_disk(&self, disk_id: GlobalDiskId, class: u8, grown: Block<u64>) {
+        let disk_info = self
+            .free_space
+            .get(&disk_id)
+            .expect("Could not find disk id in storage class");
+        for info in [disk_info, &self.free_space_tier[class as usize]] {
+            info.free.fetch_add(grown.as_u64(), Ordering::Relaxed);
+            info.total.fetch_add(grown.as_u64(), Ordering::Relaxed);
+        }
+        self.tier_changed(class);
+        self.delayed_messages.lock().push((
+            space_accounting::key(disk_id).into(),
+            update_storage_info(&disk_info.into()).unwrap(),
+        ));
+    }
+
     pub fn get_allocation_bitmap<X>(&self, id: SegmentId, dmu: &X) -> Result<SegmentAllocatorGuard>
     where
         X: Dml<Object = Node<OR>, ObjectRef = OR, ObjectPointer = OR::ObjectPointer>,
     {
+        let shard = &self.allocators[allocator_shard(id)];
         {
             // Test if bitmap is already in cache
-            let foo = self.allocators.read();
+            let foo = shard.read();
             if foo.contains_key(&id) {
                 return Ok(SegmentAllocatorGuard { inner: foo, id });
             }
@@ -204,22 +269,95 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
 
         log::info!("requested allocation bitmap, took {:?}", now.elapsed());
 
-        self.allocators.write().insert(id, RwLock::new(allocator));
+        // Another thread may have loaded the bitmap meanwhile and allocated
+        // from it already, its copy must not be replaced.
+        let mut foo = shard.write();
+        foo.entry(id).or_insert_with(|| RwLock::new(allocator));
+        Ok(SegmentAllocatorGuard {
+            inner: RwLockWriteGuard::downgrade(foo),
+            id,
+        })
+    }
+
+    fn record_segment_delta(&self, id: SegmentId) {
+        *self.segment_deltas.lock().entry(id).or_default() += 1;
+    }
+
+    /// Records `slot` of the slab block at `offset` as in use.
+    pub(crate) fn allocate_slot(&self, offset: DiskOffset, slot: u8) {
+        self.allocations.fetch_add(1, Ordering::Release);
+        self.delayed_messages.lock().push((
+            Box::new(slab::key(offset)),
+            DefaultMessageAction::upsert_bits_msg(u32::from(slot), 1, true),
+        ));
+    }
+
+    /// Frees `slot` of the slab block at `offset`, whose objects have been
+    /// written in `generation`.  Only nodes of the root tree are packed,
+    /// which are never preserved by snapshots.
+    pub(crate) fn free_slot(&self, offset: DiskOffset, slot: u8, generation: Generation) {
+        self.delayed_messages.lock().push((
+            Box::new(slab::key(offset)),
+            DefaultMessageAction::upsert_bits_msg(u32::from(slot), 1, false),
+        ));
+        self.freed_slabs.lock().insert(offset, generation);
+    }
 
-        let foo = self.allocators.read();
-        Ok(SegmentAllocatorGuard { inner: foo, id })
+    /// Records the replica at `offset` of `size` allocated blocks, so that
+    /// they are freed when the pool is opened the next time, unless the
+    /// replica is forgotten before.  Replicas only live in memory.
+    pub(crate) fn record_replica(&self, offset: DiskOffset, size: Block<u32>) {
+        self.delayed_messages.lock().push((
+            Box::new(replica::key(offset)),
+            DefaultMessageAction::insert_msg(&size.as_u32().to_be_bytes()),
+        ));
+    }
+
+    /// Forgets the replica at `offset` once its blocks have been freed.
+    pub(crate) fn forget_replica(&self, offset: DiskOffset) {
+        self.delayed_messages.lock().push((
+            Box::new(replica::key(offset)),
+            DefaultMessageAction::delete_msg(),
+        ));
+    }
+
+    /// Returns the message recording the space information of `disk_id`, with
+    /// `held_back` of its free blocks still counted as used.
+    pub(crate) fn space_accounting_msg(
+        &self,
+        disk_id: GlobalDiskId,
+        held_back: Block<u64>,
+    ) -> (Box<[u8]>, SlicedCowBytes) {
+        let mut info: StorageInfo = self.free_space.get(&disk_id).unwrap().into();
+        info.free = Block(info.free.as_u64().saturating_sub(held_back.as_u64()));
+        (
+            Box::new(space_accounting::key(disk_id)),
+            update_storage_info(&info).unwrap(),
+        )
     }
 
     pub fn free_space_disk(&self, disk_id: GlobalDiskId) -> Option<StorageInfo> {
         self.free_space.get(&disk_id).map(|elem| elem.into())
     }
 
+    fn tier_changed(&self, class: u8) {
+        if let Some(info) = self.free_space_tier.get(class as usize) {
+            self.space_watchers.notify(class, info);
+        }
+    }
+
     pub fn free_space_tier(&self, class: u8) -> Option<StorageInfo> {
         self.free_space_tier
             .get(class as usize)
             .map(|elem| elem.into())
     }
 
+    /// Returns whether an object written in `birth` and freed in `death` is
+    /// part of a savepoint.
+    pub(crate) fn preserved_by_savepoint(&self, birth: Generation, death: Generation) -> bool {
+        birth < death && self.savepoints.read().range(birth..death).next().is_some()
+    }
+
     /// Marks blocks from removed objects to be removed if they are no longer needed.
     /// Checks for the existence of snapshots which included this data, if snapshots are found continue to hold this key as "dead" key.
     // copy on write is a bit of an unlucky name
@@ -236,10 +374,14 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             .get(&dataset_id)
             .cloned()
             < Some(generation)
+            // The root tree is not part of savepoints.
+            && (dataset_id == ROOT_DATASET_ID
+                || !self.preserved_by_savepoint(generation, self.current_generation.read()))
         {
             // Deallocate
             let id = SegmentId::get(offset);
             let key = &segment::id_to_key(id) as &[_];
+            self.record_segment_delta(id);
             log::debug!(
                 "Marked a block range {{ offset: {:?}, size: {:?} }} for deallocation",
                 offset,
@@ -255,13 +397,15 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             self.free_space_tier[offset.storage_class() as usize]
                 .free
                 .fetch_add(size.as_u64(), Ordering::Relaxed);
-            let mut delayed_msgs = self.delayed_messages.lock();
-            delayed_msgs.push((key.into(), msg));
-            delayed_msgs.push((
-                Box::new(space_accounting::key(offset.class_disk_id())),
-                update_storage_info(&self.free_space.get(&offset.class_disk_id()).unwrap().into())
-                    .unwrap(),
-            ));
+            self.tier_changed(offset.storage_class());
+            // The space information of the disk is recorded along with the
+            // release, see `Database::flush_delayed_messages`.
+            self.released_blocks.lock().push(ReleasedBlocks {
+                dataset_id,
+                freed: Some((offset.class_disk_id(), size.into())),
+                key: key.into(),
+                msg,
+            });
             CopyOnWriteEvent::Removed
         } else {
             // Add to dead list
@@ -275,7 +419,12 @@ impl<OR: ObjectReference + HasStoragePreference> Handler<OR> {
             .unwrap();
 
             let msg = DefaultMessageAction::insert_msg(&data);
-            self.delayed_messages.lock().push((key.into(), msg));
+            self.released_blocks.lock().push(ReleasedBlocks {
+                dataset_id,
+                freed: None,
+                key: key.into(),
+                msg,
+            });
             CopyOnWriteEvent::Preserved
         }
     }
@@ -1,19 +1,22 @@
 //! This module provides the Database Layer.
 use crate::{
+    allocator::Action,
     atomic_option::AtomicOption,
     cache::ClockCache,
     checksum::GxHash,
+    clock::Clock,
     compression::CompressionConfiguration,
     cow_bytes::SlicedCowBytes,
     data_management::{
-        self, Dml, DmlWithHandler, DmlWithReport, DmlWithStorageHints, Dmu, TaggedCacheValue,
+        self, CacheReport, Dml, DmlWithHandler, DmlWithReport, DmlWithStorageHints, Dmu, NodeEvent,
+        NumaSharding, TaggedCacheValue, WriteBudgetInfo,
     },
-    metrics::{metrics_init, MetricsConfiguration},
-    migration::{DatabaseMsg, DmlMsg, GlobalObjectId, MigrationPolicies},
+    metrics::{metrics_init, MetricsConfiguration, MetricsSnapshot},
+    migration::{DatabaseMsg, DmlMsg, GlobalObjectId, MigrationPolicies, MigrationThresholds},
     size::StaticSize,
     storage_pool::{
-        DiskOffset, StoragePoolConfiguration, StoragePoolLayer, StoragePoolUnit,
-        NUM_STORAGE_CLASSES,
+        DiskOffset, LeafVdev, StoragePoolConfiguration, StoragePoolLayer, StoragePoolUnit,
+        TierConfiguration, Vdev, NUM_STORAGE_CLASSES,
     },
     tree::{
         DefaultMessageAction, ErasedTreeSync, Inner as TreeInner, Node, PivotKey, Tree, TreeLayer,
@@ -23,49 +26,98 @@ use crate::{
 };
 use bincode::{deserialize, serialize_into};
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, Sender};
+use futures::executor::ThreadPool;
 use itertools::Itertools;
 use parking_lot::{Mutex, RwLock};
 use seqlock::SeqLock;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     iter::FromIterator,
+    net::TcpListener,
     path::Path,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU32, AtomicU64, Ordering},
         Arc,
     },
-    thread,
 };
 
+mod admin;
+mod amplification;
+mod cache_tuner;
+mod change_feed;
 mod dataset;
 pub(crate) mod errors;
+mod gc_timer;
 mod handler;
+mod repair;
+mod replication;
+mod resume;
 pub(crate) mod root_tree_msg;
+mod savepoint;
+mod scrub;
 mod snapshot;
+mod space_report;
 mod storage_info;
 mod superblock;
+mod sync_scheduler;
 mod sync_timer;
-
-use root_tree_msg::{dataset as dataset_key, snapshot as snapshot_key, space_accounting};
+mod threads;
+mod transaction;
+mod validation;
+mod watch;
+
+pub(crate) use amplification::NodeRead;
+use change_feed::ChangeFeed;
+use root_tree_msg::{
+    dataset as dataset_key, replica as replica_key, segment, slab, snapshot as snapshot_key,
+    space_accounting, write_budget as write_budget_key,
+};
 use storage_info::AtomicStorageInfo;
-pub use storage_info::StorageInfo;
+pub use storage_info::{StorageInfo, StorageInfoChange};
 
 #[cfg(feature = "figment_config")]
 mod figment;
 
 pub use self::{
+    admin::AdminServer,
+    amplification::{Amplification, AmplificationReport, ReadAmplification, WriteAmplification},
+    cache_tuner::AdaptiveCacheConfiguration,
+    change_feed::{Change, Mutation},
     dataset::Dataset,
     errors::*,
     handler::{update_allocation_bitmap_msg, Handler},
+    repair::RepairReport,
+    replication::{ReplicationReceiver, ReplicationSender, REPLICATION_CURSORS},
+    resume::{ResumableRange, ResumeToken},
+    scrub::{ScrubFailure, ScrubReport},
     snapshot::Snapshot,
-    superblock::Superblock,
+    space_report::{FreeExtentHistogram, SpaceReport, TierSpaceReport},
+    superblock::{Superblock, FORMAT_VERSION, MIN_FORMAT_VERSION},
+    threads::ThreadConfiguration,
+    transaction::Transaction,
+    validation::ConfigurationProblem,
+    watch::WatchEvent,
+};
+pub(crate) use self::{
+    superblock::{
+        CONDITIONAL_MESSAGE_VERSION, DITTO_VERSION, POINTER_PREFERENCE_VERSION,
+        RANGE_TOMBSTONE_VERSION, SLAB_VERSION, VERSIONED_POINTER_VERSION, WIDE_DISK_ID_VERSION,
+    },
+    sync_scheduler::SyncScheduler,
 };
-const ROOT_DATASET_ID: DatasetId = DatasetId(0);
+pub(crate) const ROOT_DATASET_ID: DatasetId = DatasetId(0);
 const ROOT_TREE_STORAGE_PREFERENCE: StoragePreference = StoragePreference::FASTEST;
 const DEFAULT_CACHE_SIZE: usize = 256 * 1024 * 1024;
+const DEFAULT_DECOMPRESSED_CACHE_SIZE: usize = 16 * 1024 * 1024;
 const DEFAULT_SYNC_INTERVAL_MS: u64 = 1000;
+const DEFAULT_PREFETCH_QUEUE_DEPTH: usize = 64;
+const DEFAULT_SPACE_RESERVE_PERCENT: u8 = 2;
+const DEFAULT_DIRTY_WATERMARK_PERCENT: u8 = 50;
+/// Number of updates after which the allocation bitmap of a segment is
+/// checkpointed, see [Database::checkpoint_allocation_bitmaps].
+const SEGMENT_CHECKPOINT_DELTAS: u32 = 256;
 
 // This is the hash used overall in the entire database. For reconfiguration
 // recompilation is necessary and this type changed.
@@ -101,6 +153,17 @@ pub enum AccessMode {
     AlwaysCreateNew,
     /// Use an existing database if found, create a new one otherwise.
     OpenOrCreate,
+    /// Read the last synced state of an existing database without modifying
+    /// it, abort if none is found.
+    ///
+    /// The pool may be opened this way while another process writes to it,
+    /// e.g. for backups.  The state read is the one committed by the newest
+    /// superblock at the time of opening, objects written afterwards are
+    /// never read.  As the writer reuses the space of objects freed by later
+    /// syncs, reads may fail with checksum errors once the state is a few
+    /// syncs old.  States which are read for a longer time should be pinned
+    /// with a savepoint by the writer, see [Database::open_savepoint].
+    ReadOnly,
 }
 
 /// Determines when sync is called
@@ -136,6 +199,16 @@ pub struct DatabaseConfiguration {
     pub compression: CompressionConfiguration,
     /// Size of cache in TODO
     pub cache_size: usize,
+    /// Bytes of decompressed node data kept apart from the cache, so that
+    /// compressed nodes which are evicted and fetched again are not
+    /// decompressed again.  Zero disables it, it has no effect without
+    /// compression.
+    pub decompressed_cache_size: usize,
+    /// When set, the capacity of the cache is adjusted periodically within
+    /// the given bounds, following the hit rate and the free memory of the
+    /// system.  `cache_size` is the capacity to start with.  Only databases
+    /// opened with [Database::build_threaded] adjust it.
+    pub adaptive_cache: Option<AdaptiveCacheConfiguration>,
     /// Whether to check for and open an existing database, or overwrite it
     pub access_mode: AccessMode,
 
@@ -147,6 +220,63 @@ pub struct DatabaseConfiguration {
 
     /// If and how to log database metrics
     pub metrics: Option<MetricsConfiguration>,
+
+    /// Naming and sizing of the internal threads
+    pub threads: ThreadConfiguration,
+
+    /// Whether to keep separate allocation state and cache shards per NUMA
+    /// node
+    pub numa_sharding: NumaSharding,
+
+    /// Maximum number of prefetches in flight at the same time, further
+    /// prefetches are skipped. Zero disables prefetching.
+    pub prefetch_queue_depth: usize,
+
+    /// When set, reclaim chunks of deleted objects which were left behind
+    /// every `object_gc_interval_ms` milliseconds
+    pub object_gc_interval_ms: Option<u64>,
+
+    /// Percentage of each storage class kept as an emergency reserve.  Once
+    /// no class a write may be allocated in has more free space than its
+    /// reserve, new inserts fail with [Error::OutOfSpace], while deletions and
+    /// the write back of data already accepted may still use the reserve.
+    pub space_reserve_percent: u8,
+
+    /// Percentage of the cache which nodes modified since the last sync may
+    /// fill before [Dataset::try_insert] and [Dataset::insert_with_deadline]
+    /// refuse new data with [Error::Backpressure].  Plain inserts are not
+    /// affected.
+    pub dirty_watermark_percent: u8,
+
+    /// Whether to write a second copy of the nodes of the root tree and of
+    /// the upper levels of all trees, so that a single bad block does not
+    /// make the pool unreadable.  Only storage classes without redundant
+    /// vdevs are affected.  Nodes of the root tree which are written twice
+    /// are not packed into shared blocks anymore, which is why it is
+    /// disabled by default.
+    pub ditto_metadata: bool,
+
+    /// When set, serve the HTTP admin interface on this address, e.g.
+    /// `"127.0.0.1:8080"`.  Only databases opened with
+    /// [Database::build_threaded] serve it, see [AdminServer].
+    pub admin_address: Option<String>,
+
+    /// The clock the periodic sync, the reclamation of orphaned object chunks
+    /// and the migration policy wait on.  A [Clock::Virtual] lets tests step
+    /// through these deterministically.  It can not be serialized.
+    #[serde(skip)]
+    pub clock: Clock,
+
+    /// The id of this host, recorded in every superblock written.  Opening a
+    /// pool whose last superblock has been written by another host fails with
+    /// [Error::PoolInUse], unless the pool has been released there with
+    /// [Database::export].  Defaults to the id returned by `gethostid(3)`.
+    pub host_id: Option<u64>,
+
+    /// Whether to open a pool even if it is in use by another host, e.g. after
+    /// that host has crashed.  Opening a pool which is still written to by
+    /// another host corrupts it.
+    pub force_open: bool,
 }
 
 impl Default for DatabaseConfiguration {
@@ -158,15 +288,50 @@ impl Default for DatabaseConfiguration {
             default_storage_class: 0,
             compression: CompressionConfiguration::None,
             cache_size: DEFAULT_CACHE_SIZE,
+            decompressed_cache_size: DEFAULT_DECOMPRESSED_CACHE_SIZE,
+            adaptive_cache: None,
             access_mode: AccessMode::OpenIfExists,
             sync_interval_ms: Some(DEFAULT_SYNC_INTERVAL_MS),
             metrics: None,
             migration_policy: None,
+            threads: ThreadConfiguration::default(),
+            numa_sharding: NumaSharding::default(),
+            prefetch_queue_depth: DEFAULT_PREFETCH_QUEUE_DEPTH,
+            object_gc_interval_ms: None,
+            space_reserve_percent: DEFAULT_SPACE_RESERVE_PERCENT,
+            dirty_watermark_percent: DEFAULT_DIRTY_WATERMARK_PERCENT,
+            ditto_metadata: false,
+            admin_address: None,
+            clock: Clock::System,
+            host_id: None,
+            force_open: false,
         }
     }
 }
 
 impl DatabaseConfiguration {
+    /// Returns the configuration of a database which is kept in `size` bytes
+    /// of memory, see [Database::in_memory].
+    pub fn in_memory(size: usize) -> Self {
+        DatabaseConfiguration {
+            storage: StoragePoolConfiguration {
+                tiers: vec![TierConfiguration::new(vec![Vdev::Leaf(LeafVdev::Memory {
+                    mem: size,
+                })])],
+                ..Default::default()
+            },
+            access_mode: AccessMode::AlwaysCreateNew,
+            sync_interval_ms: None,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the configured host id, or the one of this machine.
+    pub fn host_id(&self) -> u64 {
+        self.host_id
+            .unwrap_or_else(|| unsafe { libc::gethostid() } as u32 as u64)
+    }
+
     /// Serialize the configuration to a given path in the json format.
     pub fn write_to_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let file = std::fs::OpenOptions::new()
@@ -181,6 +346,13 @@ impl DatabaseConfiguration {
 
 impl DatabaseConfiguration {
     pub fn new_spu(&self) -> Result<RootSpu> {
+        if self.access_mode == AccessMode::ReadOnly && !self.storage.read_only {
+            let storage = StoragePoolConfiguration {
+                read_only: true,
+                ..self.storage.clone()
+            };
+            return Ok(StoragePoolUnit::<Checksum>::new(&storage)?);
+        }
         Ok(StoragePoolUnit::<Checksum>::new(&self.storage)?)
     }
 
@@ -190,7 +362,13 @@ impl DatabaseConfiguration {
             root_tree_snapshot: RwLock::new(None),
             current_generation: SeqLock::new(Generation(1)),
             delayed_messages: Mutex::new(Vec::new()),
+            released_blocks: Mutex::new(Vec::new()),
+            format_version: AtomicU32::new(FORMAT_VERSION),
+            io_accounting: Default::default(),
+            freed_slabs: Mutex::new(HashMap::new()),
+            segment_deltas: Mutex::new(HashMap::new()),
             last_snapshot_generation: RwLock::new(HashMap::new()),
+            savepoints: RwLock::new(BTreeMap::new()),
             free_space: HashMap::from_iter((0..spu.storage_class_count()).flat_map(|class| {
                 (0..spu.disk_count(class)).map(move |disk_id| {
                     let free = spu
@@ -208,9 +386,10 @@ impl DatabaseConfiguration {
             free_space_tier: (0..NUM_STORAGE_CLASSES)
                 .map(|_| AtomicStorageInfo::default())
                 .collect_vec(),
+            space_watchers: Default::default(),
             allocations: AtomicU64::new(0),
             old_root_allocation: SeqLock::new(None),
-            allocators: RwLock::new(HashMap::new()),
+            allocators: Default::default(),
         }
     }
 
@@ -229,44 +408,95 @@ impl DatabaseConfiguration {
             }
         }
 
-        Dmu::new(
+        let mut write_budgets = [None; NUM_STORAGE_CLASSES];
+        for (budget, tier) in write_budgets.iter_mut().zip(&self.storage.tiers) {
+            *budget = tier.write_budget;
+        }
+
+        let dmu = Dmu::new(
             self.compression.to_builder(),
             <Checksum as crate::checksum::Checksum>::builder(),
             self.default_storage_class,
             spu,
             strategy,
-            ClockCache::new(self.cache_size),
+            ClockCache::new(match &self.adaptive_cache {
+                Some(bounds) => self.cache_size.clamp(bounds.min_size, bounds.max_size),
+                None => self.cache_size,
+            }),
             handler,
+            self.numa_sharding,
+            self.prefetch_queue_depth,
         )
+        .with_space_reserve(self.space_reserve_percent)
+        .with_dirty_watermark(self.dirty_watermark_percent)
+        .with_ditto_metadata(self.ditto_metadata)
+        .with_write_budgets(write_budgets, self.clock.clone())
+        .with_decompressed_cache(self.decompressed_cache_size);
+        match self
+            .migration_policy
+            .as_ref()
+            .and_then(MigrationPolicies::cold_compression)
+        {
+            Some(compression) => dmu.with_cold_compression(compression.to_builder()),
+            None => dmu,
+        }
     }
 
-    fn select_root_tree(&self, dmu: Arc<RootDmu>) -> Result<(RootTree<RootDmu>, ObjectPointer)> {
+    fn select_root_tree(
+        &self,
+        dmu: Arc<RootDmu>,
+        background_pool: &ThreadPool,
+    ) -> Result<(RootTree<RootDmu>, ObjectPointer, u32)> {
         if let Some(cfg) = &self.metrics {
-            metrics_init::<Self>(cfg, dmu.clone())?;
+            metrics_init::<Self>(cfg, dmu.clone(), self.clock.clone(), background_pool)?;
         }
 
-        let root_ptr = if let AccessMode::OpenIfExists | AccessMode::OpenOrCreate = self.access_mode
-        {
-            match Superblock::<ObjectPointer>::fetch_superblocks(dmu.pool()) {
-                Ok(None) if self.access_mode == AccessMode::OpenIfExists => {
-                    return Err(Error::InvalidSuperblock)
-                }
-                Ok(ptr) => ptr,
-                Err(e) => return Err(e),
+        let read_only = self.access_mode == AccessMode::ReadOnly;
+        let root_ptr = if self.access_mode != AccessMode::AlwaysCreateNew {
+            let superblocks = Superblock::<ObjectPointer>::fetch_all_superblocks(dmu.pool())?;
+            if superblocks.is_empty() && self.access_mode != AccessMode::OpenOrCreate {
+                return Err(Error::InvalidSuperblock);
             }
+            newest_complete_superblock(&dmu, superblocks)?
         } else {
             None
         };
 
         if let Some(sb) = root_ptr {
             let root_ptr = sb.root_ptr;
-            let tree = RootTree::open(
-                ROOT_DATASET_ID,
-                root_ptr,
-                DefaultMessageAction,
-                dmu,
-                ROOT_TREE_STORAGE_PREFERENCE,
-            );
+            if !read_only && sb.host_id != 0 && sb.host_id != self.host_id() {
+                if !self.force_open {
+                    return Err(Error::PoolInUse {
+                        host_id: sb.host_id,
+                    });
+                }
+                warn!(
+                    "Forcing open a pool which is in use by host {:#x}",
+                    sb.host_id
+                );
+            }
+            if sb.format_version < FORMAT_VERSION {
+                info!(
+                    "Opening pool of format version {}, use `Database::upgrade` to upgrade to version {}",
+                    sb.format_version, FORMAT_VERSION
+                );
+            }
+            let tree = if read_only {
+                RootTree::open_ro(
+                    root_ptr,
+                    DefaultMessageAction,
+                    dmu,
+                    ROOT_TREE_STORAGE_PREFERENCE,
+                )
+            } else {
+                RootTree::open(
+                    ROOT_DATASET_ID,
+                    root_ptr,
+                    DefaultMessageAction,
+                    dmu,
+                    ROOT_TREE_STORAGE_PREFERENCE,
+                )
+            };
 
             // Update space accounting from last execution
             for (class, info) in sb.tiers.iter().enumerate() {
@@ -285,7 +515,7 @@ impl DatabaseConfiguration {
             }
 
             *tree.dmu().handler().old_root_allocation.lock_write() =
-                Some((root_ptr.offset(), root_ptr.size()));
+                Some((root_ptr.offset(), root_ptr.allocated_size()));
             tree.dmu()
                 .handler()
                 .root_tree_inner
@@ -311,7 +541,11 @@ impl DatabaseConfiguration {
                     .store(stored_info.total.as_u64(), Ordering::Relaxed);
             }
 
-            Ok((tree, root_ptr))
+            if let Some(counters) = tree.get(&write_budget_key::key()[..])? {
+                tree.dmu().unpack_write_budgets(&counters);
+            }
+
+            Ok((tree, root_ptr, sb.format_version))
         } else {
             Superblock::<ObjectPointer>::clear_superblock(dmu.pool())?;
             let tree = RootTree::empty_tree(
@@ -350,12 +584,14 @@ impl DatabaseConfiguration {
                 }
             }
             let root_ptr = tree.sync()?;
-            Ok((tree, root_ptr))
+            Ok((tree, root_ptr, FORMAT_VERSION))
         }
     }
 
     fn sync_mode(&self) -> SyncMode {
-        if let Some(interval_ms) = self.sync_interval_ms {
+        if self.access_mode == AccessMode::ReadOnly {
+            SyncMode::Explicit
+        } else if let Some(interval_ms) = self.sync_interval_ms {
             SyncMode::Periodic { interval_ms }
         } else {
             SyncMode::Explicit
@@ -365,6 +601,22 @@ impl DatabaseConfiguration {
     fn migration_policy(&self) -> Option<MigrationPolicies> {
         self.migration_policy.clone()
     }
+
+    /// Returns the number of long-running background services enabled by
+    /// this configuration.
+    fn background_services(&self) -> usize {
+        [
+            self.metrics.is_some(),
+            self.migration_policy.is_some(),
+            matches!(self.sync_mode(), SyncMode::Periodic { .. }),
+            self.object_gc_interval_ms.is_some(),
+            self.admin_address.is_some(),
+            self.adaptive_cache.is_some(),
+        ]
+        .iter()
+        .filter(|&&enabled| enabled)
+        .count()
+    }
 }
 
 type ErasedTree = dyn ErasedTreeSync<Pointer = ObjectPointer, ObjectRef = ObjectRef> + Send + Sync;
@@ -374,7 +626,15 @@ pub struct Database {
     pub(crate) root_tree: RootTree<RootDmu>,
     builder: DatabaseConfiguration,
     open_datasets: HashMap<DatasetId, Box<ErasedTree>>,
+    change_feeds: HashMap<DatasetId, Arc<ChangeFeed>>,
     pub(crate) db_tx: Option<Sender<DatabaseMsg>>,
+    background_pool: ThreadPool,
+    migration_thresholds: Option<Arc<MigrationThresholds>>,
+    // Held while committing a transaction and while syncing.
+    commit_lock: Arc<Mutex<()>>,
+    sync_scheduler: Arc<SyncScheduler>,
+    // Recorded in superblocks, zero once the pool has been exported.
+    host_id: u64,
 }
 
 impl Database {
@@ -398,6 +658,24 @@ impl Database {
         })
     }
 
+    /// Creates a database which is kept in `size` bytes of memory in a single
+    /// storage tier, and is lost when it is dropped.  Nothing is synced
+    /// unless [Database::sync] is called, which is only needed to test
+    /// committing.  Meant for tests and for embedding a temporary store.
+    pub fn in_memory(size: usize) -> Result<Self> {
+        Self::build(DatabaseConfiguration::in_memory(size))
+    }
+
+    /// Opens a database given by the storage pool configuration read-only, see
+    /// [AccessMode::ReadOnly].
+    pub fn open_read_only(cfg: StoragePoolConfiguration) -> Result<Self> {
+        Self::build(DatabaseConfiguration {
+            storage: cfg,
+            access_mode: AccessMode::ReadOnly,
+            ..Default::default()
+        })
+    }
+
     /// Opens or creates a database given by the storage pool configuration.
     pub fn open_or_create(cfg: StoragePoolConfiguration) -> Result<Self> {
         Self::build(DatabaseConfiguration {
@@ -414,6 +692,10 @@ impl Database {
 
     /// Opens or creates a database given by the storage pool configuration and
     /// sets the given cache size.
+    ///
+    /// The configuration is checked with [DatabaseConfiguration::validate]
+    /// first, all problems found are returned as
+    /// [Error::InvalidConfiguration].
     pub fn build(builder: DatabaseConfiguration) -> Result<Self> {
         Self::build_internal(builder, None, None)
     }
@@ -425,6 +707,10 @@ impl Database {
         dml_tx: Option<Sender<DmlMsg>>,
         db_tx: Option<Sender<DatabaseMsg>>,
     ) -> Result<Self> {
+        let problems = builder.validate();
+        if !problems.is_empty() {
+            return Err(Error::InvalidConfiguration(problems));
+        }
         let spl = builder.new_spu()?;
         let handler = builder.new_handler(&spl);
         let mut dmu = builder.new_dmu(spl, handler);
@@ -432,24 +718,81 @@ impl Database {
             dmu.set_report(tx.clone());
         }
 
-        let (tree, root_ptr) = builder.select_root_tree(Arc::new(dmu))?;
+        let background_pool = builder
+            .threads
+            .background_pool(builder.background_services())?;
+        let (tree, root_ptr, format_version) =
+            builder.select_root_tree(Arc::new(dmu), &background_pool)?;
+        let db = Self::with_root_tree(
+            builder,
+            tree,
+            root_ptr,
+            format_version,
+            db_tx,
+            background_pool,
+        );
+        db.load_savepoints()?;
+        db.free_stale_replicas()?;
+        Ok(db)
+    }
+
+    /// Frees the blocks of the replicas which have still existed when the
+    /// pool has been closed, see [Self::drop_replicas].  The space is
+    /// accounted for right away and committed by the next sync.
+    fn free_stale_replicas(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let (low, high) = (replica_key::min_key(), replica_key::max_key());
+        for result in self.root_tree.range(&low[..]..&high[..])? {
+            let (key, size) = result?;
+            self.root_tree.dmu().free_replica_blocks(
+                replica_key::offset_from_key(&key),
+                Block(BigEndian::read_u32(&size)),
+            );
+        }
+        Ok(())
+    }
 
+    /// Completes opening a database once its root tree has been selected,
+    /// continuing after the generation of `root_ptr`.
+    fn with_root_tree(
+        builder: DatabaseConfiguration,
+        tree: RootTree<RootDmu>,
+        root_ptr: ObjectPointer,
+        format_version: u32,
+        db_tx: Option<Sender<DatabaseMsg>>,
+        background_pool: ThreadPool,
+    ) -> Self {
         *tree.dmu().handler().current_generation.lock_write() = root_ptr.generation().next();
+        tree.dmu()
+            .handler()
+            .format_version
+            .store(format_version, Ordering::Release);
         *tree.dmu().handler().root_tree_snapshot.write() = Some(TreeInner::new_ro(
             RootDmu::root_ref_from_ptr(root_ptr),
             DefaultMessageAction,
         ));
 
-        Ok(Database {
+        Database {
             root_tree: tree,
+            host_id: builder.host_id(),
             builder,
             open_datasets: Default::default(),
+            change_feeds: Default::default(),
             db_tx,
-        })
+            background_pool,
+            migration_thresholds: None,
+            commit_lock: Arc::new(Mutex::new(())),
+            sync_scheduler: Arc::default(),
+        }
     }
 
     /// Opens or create a database given by the storage pool configuration, sets the given cache size and spawns threads to periodically perform
-    /// sync (if configured with [SyncMode::Periodic]) and auto migration (if configured with [MigrationPolicies]).
+    /// sync (if configured with [SyncMode::Periodic]), auto migration (if configured with [MigrationPolicies]),
+    /// garbage collection of orphaned object chunks (if `object_gc_interval_ms` is set),
+    /// cache resizing (if `adaptive_cache` is set) and the admin interface (if `admin_address`
+    /// is set).
     pub fn build_threaded(builder: DatabaseConfiguration) -> Result<Arc<RwLock<Self>>> {
         let db = match builder.migration_policy() {
             Some(pol) => {
@@ -480,10 +823,15 @@ impl Database {
                     db.write().close_object_store(os);
                 }
 
+                let thresholds = Arc::new(MigrationThresholds::new(pol.migration_threshold()));
+                db.write().migration_thresholds = Some(Arc::clone(&thresholds));
+
                 let other = db.clone();
-                thread::spawn(move || {
+                db.read().background_pool.spawn_ok(async move {
                     let hints = other.read().root_tree.dmu().storage_hints();
-                    let mut policy = pol.construct(dml_rx, db_rx, other, hints);
+                    let clock = other.read().builder.clock.clone();
+                    let _guard = clock.register();
+                    let mut policy = pol.construct(dml_rx, db_rx, other, hints, thresholds, clock);
                     loop {
                         if let Err(e) = policy.thread_loop() {
                             error!("Automatic Migration Policy encountered {:?}", e);
@@ -495,7 +843,9 @@ impl Database {
             }
             None => Arc::new(RwLock::new(Self::build_internal(builder, None, None)?)),
         };
-        Ok(Self::with_sync(db))
+        Ok(Self::with_cache_tuner(Self::with_object_gc(
+            Self::with_sync(Self::with_admin(db)?),
+        )))
     }
 
     /// If this [Database] was created with a [SyncMode::Periodic], this function
@@ -506,15 +856,60 @@ impl Database {
     /// periodic syncing.
     fn with_sync(this: Arc<RwLock<Self>>) -> Arc<RwLock<Self>> {
         if let SyncMode::Periodic { interval_ms } = this.read().builder.sync_mode() {
-            thread::spawn({
-                let db = this.clone();
-                move || sync_timer::sync_timer(interval_ms, db)
-            });
+            let db = Arc::downgrade(&this);
+            let clock = this.read().builder.clock.clone();
+            this.read()
+                .background_pool
+                .spawn_ok(async move { sync_timer::sync_timer(interval_ms, clock, db) });
         }
         this
     }
 
+    /// Starts a thread to periodically reclaim orphaned object chunks, if
+    /// configured.
+    fn with_object_gc(this: Arc<RwLock<Self>>) -> Arc<RwLock<Self>> {
+        if let Some(interval_ms) = this.read().builder.object_gc_interval_ms {
+            let db = Arc::downgrade(&this);
+            let clock = this.read().builder.clock.clone();
+            this.read()
+                .background_pool
+                .spawn_ok(async move { gc_timer::gc_timer(interval_ms, clock, db) });
+        }
+        this
+    }
+
+    /// Starts a thread to periodically resize the cache, if configured.
+    fn with_cache_tuner(this: Arc<RwLock<Self>>) -> Arc<RwLock<Self>> {
+        if let Some(config) = this.read().builder.adaptive_cache.clone() {
+            let db = Arc::downgrade(&this);
+            let clock = this.read().builder.clock.clone();
+            this.read()
+                .background_pool
+                .spawn_ok(async move { cache_tuner::cache_tuner(config, clock, db) });
+        }
+        this
+    }
+
+    /// Starts serving the admin interface, if an address is configured.
+    fn with_admin(this: Arc<RwLock<Self>>) -> Result<Arc<RwLock<Self>>> {
+        let address = this.read().builder.admin_address.clone();
+        if let Some(address) = address {
+            let listener = TcpListener::bind(address)?;
+            let server = AdminServer::new(&this);
+            this.read().background_pool.spawn_ok(async move {
+                if let Err(e) = server.serve(&listener) {
+                    error!("The admin interface has failed: {}", e);
+                }
+            });
+        }
+        Ok(this)
+    }
+
     fn sync_ds(&self, ds_id: DatasetId, ds_tree: &ErasedTree) -> Result<()> {
+        if self.is_read_only() {
+            // Nothing can have been modified.
+            return Ok(());
+        }
         trace!("sync_ds: Enter");
         let ptr = ds_tree.erased_sync()?;
         trace!("sync_ds: erased_sync");
@@ -524,12 +919,38 @@ impl Database {
         Ok(())
     }
 
-    fn flush_delayed_messages(&self) -> Result<()> {
+    /// Inserts the messages collected by the handler into the root tree.  The
+    /// messages releasing blocks of datasets which are not among `datasets`,
+    /// `None` for all, are kept for a later commit, as their committed trees
+    /// may still refer to these blocks.  Until then, the recorded space
+    /// information of the disks counts these blocks as used.
+    fn flush_delayed_messages(&self, datasets: Option<&[DatasetId]>) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
         loop {
-            let v = std::mem::take(&mut *self.root_tree.dmu().handler().delayed_messages.lock());
+            let mut v = std::mem::take(&mut *handler.delayed_messages.lock());
+            let released = std::mem::take(&mut *handler.released_blocks.lock());
+            let (due, kept): (Vec<_>, Vec<_>) = released.into_iter().partition(|r| {
+                r.dataset_id == ROOT_DATASET_ID
+                    || datasets.map_or(true, |ids| ids.contains(&r.dataset_id))
+            });
+            let mut held_back = HashMap::new();
+            for (disk_id, _) in due.iter().filter_map(|r| r.freed) {
+                held_back.entry(disk_id).or_insert(Block(0));
+            }
+            for (disk_id, size) in kept.iter().filter_map(|r| r.freed) {
+                *held_back.entry(disk_id).or_insert(Block(0)) += size;
+            }
+            handler.released_blocks.lock().extend(kept);
+            v.extend(due.into_iter().map(|r| (r.key, r.msg)));
             if v.is_empty() {
                 break;
             }
+            // Supersedes the space information recorded since the last flush.
+            v.extend(
+                held_back
+                    .into_iter()
+                    .map(|(disk_id, size)| handler.space_accounting_msg(disk_id, size)),
+            );
             for (key, msg) in v {
                 self.root_tree.insert(key, msg, StoragePreference::NONE)?;
             }
@@ -537,10 +958,175 @@ impl Database {
         Ok(())
     }
 
-    /// Synchronizes the database.
-    pub fn sync(&mut self) -> Result<()> {
+    /// Rewrites the allocation bitmaps of segments, which have been updated
+    /// at least [SEGMENT_CHECKPOINT_DELTAS] times, as a whole.
+    ///
+    /// Bitmaps are updated by small messages, which pile up in the buffers of
+    /// the root tree and have to be applied whenever a bitmap is loaded,
+    /// especially on the first allocations after opening the pool.  A
+    /// checkpoint replaces the messages preceding it, so that only the updates
+    /// since then remain to be applied.
+    fn checkpoint_allocation_bitmaps(&self, datasets: Option<&[DatasetId]>) -> Result<()> {
+        self.flush_delayed_messages(datasets)?;
+        let handler = self.root_tree.dmu().handler();
+        let mut due = Vec::new();
+        handler.segment_deltas.lock().retain(|id, deltas| {
+            if *deltas < SEGMENT_CHECKPOINT_DELTAS {
+                return true;
+            }
+            due.push(*id);
+            false
+        });
+        for id in due {
+            let key = &segment::id_to_key(id) as &[_];
+            if let Some(bitmap) = self.root_tree.get(key)? {
+                debug!("Checkpointing allocation bitmap of {:?}", id);
+                self.root_tree.insert(
+                    key,
+                    DefaultMessageAction::insert_msg(&bitmap),
+                    StoragePreference::NONE,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Frees the slab blocks of past generations of which all slots have been
+    /// freed.  Slots freed while syncing the root tree are only considered by
+    /// the next sync.
+    fn collect_slabs(&self) -> Result<()> {
+        let handler = self.root_tree.dmu().handler();
+        let generation = handler.current_generation();
+        let mut freed = Vec::new();
+        handler
+            .freed_slabs
+            .lock()
+            .retain(|offset, slab_generation| {
+                if *slab_generation == generation {
+                    return true;
+                }
+                freed.push(*offset);
+                false
+            });
+        for offset in freed {
+            let key = &slab::key(offset) as &[_];
+            if let Some(slots) = self.root_tree.get(key)? {
+                if slots.iter().any(|byte| *byte != 0) {
+                    continue;
+                }
+            }
+            let size = self.root_tree.dmu().spl().actual_size(
+                offset.storage_class(),
+                offset.disk_id(),
+                Block(1),
+            );
+            handler.update_allocation_bitmap(
+                offset,
+                size,
+                Action::Deallocate,
+                self.root_tree.dmu(),
+            )?;
+            self.root_tree.insert(
+                key,
+                DefaultMessageAction::delete_msg(),
+                StoragePreference::NONE,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Synchronizes a database which is shared with its background threads,
+    /// see [Database::build_threaded].  Unlike [Database::sync], this waits
+    /// for a running migration batch to finish first, so that its nodes are
+    /// written only once, and holds off new batches until the sync is done.
+    pub fn sync_shared(db: &RwLock<Self>) -> Result<Generation> {
+        let scheduler = Arc::clone(&db.read().sync_scheduler);
+        let _fence = scheduler.sync_fence();
+        db.write().sync()
+    }
+
+    /// Returns the scheduler fencing migration batches around syncs.
+    pub(crate) fn sync_scheduler(&self) -> Arc<SyncScheduler> {
+        Arc::clone(&self.sync_scheduler)
+    }
+
+    /// Returns the generation which the next sync will commit.  All
+    /// modifications made from now on until that sync belong to it.
+    pub fn current_generation(&self) -> Generation {
+        self.root_tree.dmu().handler().current_generation()
+    }
+
+    /// Synchronizes the database and returns the generation which has been
+    /// committed.  Once this returns, all modifications made before the call
+    /// are durable and recovered with this generation or a later one.
+    pub fn sync(&mut self) -> Result<Generation> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        // Transactions must not be committed partially before a sync.
+        let commit_lock = Arc::clone(&self.commit_lock);
+        let _commit_guard = commit_lock.lock();
+        // Taken before locking the datasets, as recording a mutation holds
+        // the lock of its change feed while modifying the tree.
+        let changes = self.take_pending_changes();
+        let generation = self.root_tree.dmu().handler().current_generation();
+        let result = self.commit(None);
+        self.finish_pending_changes(changes, result.is_ok().then_some(generation));
+        self.root_tree
+            .dmu()
+            .storage_hints()
+            .lock()
+            .expire(self.root_tree.dmu().handler().current_generation());
+        result.map(|()| generation)
+    }
+
+    /// Synchronizes only the given open datasets, like [Database::sync] does
+    /// for all of them, and returns the generation which has been committed.
+    /// Modifications of other datasets stay in memory and are not recovered
+    /// with this generation, but the space they have allocated stays in use
+    /// after a crash until the pool is repaired, see
+    /// [Database::open_with_repair].  Their changes are not delivered to
+    /// change feeds before the next full sync.
+    pub(crate) fn sync_datasets(&mut self, datasets: &[DatasetId]) -> Result<Generation> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+        let commit_lock = Arc::clone(&self.commit_lock);
+        let _commit_guard = commit_lock.lock();
+        let generation = self.root_tree.dmu().handler().current_generation();
+        self.commit(Some(datasets))?;
+        self.root_tree
+            .dmu()
+            .storage_hints()
+            .lock()
+            .expire(self.root_tree.dmu().handler().current_generation());
+        Ok(generation)
+    }
+
+    /// Returns whether the database has been opened with
+    /// [AccessMode::ReadOnly], in which case all modifications fail with an
+    /// error of the category [ErrorCategory::ReadOnly].
+    pub fn is_read_only(&self) -> bool {
+        self.builder.access_mode == AccessMode::ReadOnly
+    }
+
+    /// Syncs and closes the database, marking the pool as not in use by any
+    /// host, so that it can be opened on another one, see
+    /// [DatabaseConfiguration::host_id].
+    pub fn export(mut self) -> Result<()> {
+        self.host_id = 0;
+        self.sync()?;
+        Ok(())
+    }
+
+    /// Writes back the open datasets among `datasets`, `None` for all, and the
+    /// root tree and commits them with a new superblock.
+    fn commit(&mut self, datasets: Option<&[DatasetId]>) -> Result<()> {
         let mut ds_locks = Vec::with_capacity(self.open_datasets.len());
         for (&ds_id, ds_tree) in &self.open_datasets {
+            if datasets.map_or(false, |ids| !ids.contains(&ds_id)) {
+                continue;
+            }
             loop {
                 if let Some(lock) = ds_tree.erased_try_lock_root() {
                     ds_locks.push(lock);
@@ -550,8 +1136,16 @@ impl Database {
                 self.sync_ds(ds_id, ds_tree.as_ref())?;
             }
         }
+        self.flush_delayed_messages(datasets)?;
+        self.collect_slabs()?;
+        self.checkpoint_allocation_bitmaps(datasets)?;
+        self.root_tree.insert(
+            &write_budget_key::key()[..],
+            DefaultMessageAction::insert_msg(&self.root_tree.dmu().pack_write_budgets()),
+            StoragePreference::NONE,
+        )?;
         let root_ptr = loop {
-            self.flush_delayed_messages()?;
+            self.flush_delayed_messages(datasets)?;
             let allocations_before = self
                 .root_tree
                 .dmu()
@@ -574,6 +1168,7 @@ impl Database {
                 info!("Sync: resyncing -- seen {} allocations", allocations);
             }
         };
+        self.root_tree.dmu().write_slabs()?;
         let pool = self.root_tree.dmu().spl();
         pool.flush()?;
         let mut info = [StorageInfo {
@@ -588,10 +1183,17 @@ impl Database {
                 .free_space_tier(idx as u8)
                 .expect("Class hat to exist");
         }
-        Superblock::<ObjectPointer>::write_superblock(pool, &root_ptr, &info)?;
+        Superblock::<ObjectPointer>::write_superblock(
+            pool,
+            &root_ptr,
+            &info,
+            self.format_version(),
+            self.host_id,
+        )?;
         pool.flush()?;
         let handler = self.root_tree.dmu().handler();
-        *handler.old_root_allocation.lock_write() = Some((root_ptr.offset(), root_ptr.size()));
+        *handler.old_root_allocation.lock_write() =
+            Some((root_ptr.offset(), root_ptr.allocated_size()));
         handler.bump_generation();
         handler
             .root_tree_snapshot
@@ -602,6 +1204,158 @@ impl Database {
         Ok(())
     }
 
+    /// Returns the on-disk format version of the opened pool.
+    pub fn format_version(&self) -> u32 {
+        self.root_tree
+            .dmu()
+            .handler()
+            .format_version
+            .load(Ordering::Acquire)
+    }
+
+    /// Upgrades the pool to the current on-disk format version
+    /// [FORMAT_VERSION] and returns the number of rewritten nodes.
+    ///
+    /// The new format version is persisted with a sync first.  Features of
+    /// newer format versions, such as range tombstones, are used from then
+    /// on, so older versions of the storage stack may not be able to open the
+    /// pool afterwards.  Then all nodes of the datasets and the root tree
+    /// which have been written with an older format version, see
+    /// [ObjectPointer::format_version](crate::data_management::ObjectPointer::format_version),
+    /// are written again, followed by a sync after each dataset.  Nodes which
+    /// are only referenced by snapshots keep their format until the snapshots
+    /// are deleted.  An interrupted upgrade is resumed by calling this again.
+    pub fn upgrade(&mut self) -> Result<usize> {
+        let format_version = self.format_version();
+        if format_version < FORMAT_VERSION {
+            self.upgrade_format_version(format_version)?;
+        }
+
+        let outdated = |ptr: &ObjectPointer| ptr.format_version() < FORMAT_VERSION;
+        let mut ids = Vec::new();
+        let low = &dataset_key::data_key(DatasetId::default()) as &[_];
+        let high = &dataset_key::data_key_max() as &[_];
+        for result in self.root_tree.range(low..high)? {
+            let (key, _) = result?;
+            ids.push(dataset_key::id_from_data_key(&key));
+        }
+        let mut rewritten = 0;
+        for id in ids {
+            let opened = if self.open_datasets.contains_key(&id) {
+                None
+            } else {
+                match self.open_dataset_with_id::<DefaultMessageAction>(id) {
+                    Ok(ds) => Some(ds),
+                    Err(Error::DoesNotExist) => continue,
+                    Err(e) => return Err(e),
+                }
+            };
+            let result = self.open_datasets[&id].erased_rewrite_nodes(&outdated);
+            if let Some(ds) = opened {
+                self.close_dataset(ds)?;
+            }
+            rewritten += result?;
+            self.sync()?;
+        }
+        rewritten += self.root_tree.rewrite_nodes(outdated)?;
+        self.sync()?;
+        info!("Rewrote {rewritten} nodes of older format versions");
+        Ok(rewritten)
+    }
+
+    fn upgrade_format_version(&mut self, format_version: u32) -> Result<()> {
+        debug_assert!(format_version >= MIN_FORMAT_VERSION);
+        info!(
+            "Upgrading pool from format version {} to {}",
+            format_version, FORMAT_VERSION
+        );
+        // Version 4 only changed the superblock layout, version 5 only added
+        // the system storage preference to object pointers, which reads as
+        // none from older pointers, and version 6 only widened the disk ids of
+        // disk offsets in a compatible way.  Version 7 added packed objects,
+        // which are only written from now on, as are the second copies of
+        // upper nodes added by version 8, the internal nodes with buffered
+        // range tombstones added by version 9 and the conditional messages
+        // added by version 10.  Internal nodes without range
+        // tombstones keep the layout of version 3, so nodes written by older
+        // versions remain readable until they are rewritten with pointers
+        // recording their format version, added by version 11.  Disks beyond
+        // the 1024th of a storage class are used from now on.
+        self.root_tree
+            .dmu()
+            .handler()
+            .format_version
+            .store(FORMAT_VERSION, Ordering::Release);
+        self.sync()?;
+        Ok(())
+    }
+
+    /// Picks up the new size of the top-level vdev `disk_id` of
+    /// `storage_class` after its backing files or devices have been enlarged,
+    /// and adds the additional space to the free space of the disk and its
+    /// tier.  This can be done while the database is in use.  The new size is
+    /// persisted with the next sync.
+    ///
+    /// For redundant vdevs only the space available on all children is
+    /// added, and no vdev grows beyond [MAX_DISK_BLOCKS](crate::storage_pool::MAX_DISK_BLOCKS).
+    /// Returns the updated storage information of the vdev.
+    pub fn grow_vdev(&self, storage_class: u8, disk_id: u16) -> Result<StorageInfo> {
+        let dmu = self.root_tree.dmu();
+        let spl = dmu.spl();
+        if storage_class >= spl.storage_class_count() || disk_id >= spl.disk_count(storage_class) {
+            return Err(Error::VdevNotFound(storage_class, disk_id));
+        }
+        let old_size = spl.size_in_blocks(storage_class, disk_id);
+        let new_size = spl.refresh_size(storage_class, disk_id)?;
+        let global_id = DiskOffset::construct_disk_id(storage_class, disk_id);
+        if new_size > old_size {
+            let grown =
+                spl.effective_free_size(storage_class, disk_id, new_size - old_size.as_u64());
+            info!(
+                "Vdev {} in storage class {} grew by {:?}",
+                disk_id, storage_class, grown
+            );
+            dmu.handler().grow_disk(global_id, storage_class, grown);
+        }
+        Ok(dmu
+            .handler()
+            .free_space_disk(global_id)
+            .expect("Disk has to exist"))
+    }
+
+    /// Changes the migration threshold of `storage_class` used by the running
+    /// migration policy, e.g. to free up a tier which is filling up faster
+    /// than expected.  The policy uses the new value from its next update on,
+    /// without being restarted.  The change is also reflected in the
+    /// configuration returned by [Database::write_config_json].
+    ///
+    /// Fails if no migration policy is running or the threshold is invalid.
+    pub fn set_migration_threshold(&mut self, storage_class: u8, threshold: f32) -> Result<()> {
+        let thresholds = self
+            .migration_thresholds
+            .as_ref()
+            .ok_or(Error::MigrationNotPossible)?;
+        let class = storage_class as usize;
+        if class >= NUM_STORAGE_CLASSES {
+            return Err(Error::InvalidConfiguration(vec![
+                ConfigurationProblem::InvalidStorageClass {
+                    option: "migration_threshold",
+                    class: storage_class,
+                },
+            ]));
+        }
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(Error::InvalidConfiguration(vec![
+                ConfigurationProblem::ThresholdOutOfRange { class, threshold },
+            ]));
+        }
+        thresholds.set(class, threshold);
+        if let Some(policy) = self.builder.migration_policy.as_mut() {
+            policy.set_migration_threshold(class, threshold);
+        }
+        Ok(())
+    }
+
     /// Drops the entire cache. This is useful when considering performance
     /// measurements regarding "cold" environments.
     pub fn drop_cache(&self) -> Result<()> {
@@ -609,6 +1363,94 @@ impl Database {
         Ok(())
     }
 
+    /// Drops all replicas of nodes on the fastest storage tier, see
+    /// [LfuMode::Replicate](crate::migration::LfuMode::Replicate).  Replicas
+    /// which still exist when the database is closed keep their space until
+    /// the pool is opened again, call this before the last sync to release it
+    /// right away.
+    pub fn drop_replicas(&self) {
+        self.root_tree.dmu().drop_replicas(Block(u64::MAX));
+    }
+
+    /// Moves the nodes which the migration policy has hinted to be moved to
+    /// another tier, up to a total of `budget` blocks, and syncs the database.
+    /// Returns the number of blocks of the moved nodes.
+    ///
+    /// Hints are otherwise only applied when their node is read or written
+    /// the next time, so nodes which are not accessed anymore, like those
+    /// picked for demotion, would stay where they are.  Hints of nodes which
+    /// do not exist anymore are dropped.
+    pub fn apply_storage_hints(&mut self, budget: Block<u64>) -> Result<Block<u64>> {
+        let mut opened = Vec::new();
+        let result = self.apply_storage_hints_inner(budget, &mut opened);
+        for ds in opened {
+            self.close_dataset(ds)?;
+        }
+        let applied = result?;
+        self.sync()?;
+        Ok(applied)
+    }
+
+    fn apply_storage_hints_inner(
+        &mut self,
+        budget: Block<u64>,
+        opened: &mut Vec<Dataset>,
+    ) -> Result<Block<u64>> {
+        let hints = self.root_tree.dmu().storage_hints();
+        let generation = self.root_tree.dmu().handler().current_generation();
+        let mut applied = Block(0);
+        while applied < budget {
+            let (pivot, pref) = match hints.lock().pop(generation) {
+                Some(hint) => hint,
+                None => break,
+            };
+            let id = pivot.d_id();
+            let size = if id == ROOT_DATASET_ID {
+                self.root_tree
+                    .erased_set_system_storage_preference(&pivot, pref)?
+            } else {
+                if !self.open_datasets.contains_key(&id) {
+                    match self.open_dataset_with_id(id) {
+                        Ok(ds) => opened.push(ds),
+                        Err(Error::DoesNotExist) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                self.open_datasets[&id].erased_set_system_storage_preference(&pivot, pref)?
+            };
+            if let Some(size) = size {
+                applied += Block::round_up_from_bytes(size as u64).as_u64();
+            }
+        }
+        Ok(applied)
+    }
+
+    /// Validates the cached nodes of all datasets, see [Dml::verify_cache].
+    pub fn verify_cache(&self) -> CacheReport {
+        self.root_tree.dmu().verify_cache()
+    }
+
+    /// Returns the write and read amplification of all datasets and storage
+    /// tiers since the database has been opened.
+    pub fn amplification_report(&self) -> AmplificationReport {
+        self.root_tree.dmu().handler().io_accounting.report()
+    }
+
+    /// Returns a snapshot of the statistics which are periodically written
+    /// if [DatabaseConfiguration::metrics] is set, e.g. to pass them to a
+    /// custom [MetricsSink](crate::metrics::MetricsSink).
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot::take(self.root_tree.dmu())
+    }
+
+    /// Returns a stream of the nodes of all datasets which are evicted from
+    /// the cache, written back, modified again after having been written, or
+    /// flushed to, split or merged by their tree, from now on.  The stream
+    /// ends once the database is dropped.
+    pub fn subscribe_node_events(&self) -> Receiver<NodeEvent> {
+        self.root_tree.dmu().subscribe_node_events()
+    }
+
     /// Storage tier information for all available tiers. These are in order as in `storage_prefernce.as_u8()`
     pub fn free_space_tier(&self) -> Vec<StorageInfo> {
         (0..self.root_tree.dmu().spl().storage_class_count())
@@ -622,6 +1464,41 @@ impl Database {
             .collect()
     }
 
+    /// Returns a receiver which gets a [StorageInfoChange] whenever the fill
+    /// level of a tier has changed by at least `granularity`, a fraction of
+    /// its total size, since the last change sent to it.  Changes are sent
+    /// right after blocks have been allocated or freed, so policies and
+    /// dashboards can react to them instead of polling
+    /// [Database::free_space_tier].  Dropping the receiver ends the watch.
+    pub fn watch_free_space(&self, granularity: f32) -> Receiver<StorageInfoChange> {
+        self.root_tree
+            .dmu()
+            .handler()
+            .space_watchers
+            .watch(granularity, self.free_space_tier())
+    }
+
+    /// Returns the bytes written to each tier on the current day together
+    /// with its [TierConfiguration::write_budget].
+    pub fn write_budgets(&self) -> Vec<WriteBudgetInfo> {
+        (0..self.root_tree.dmu().spl().storage_class_count())
+            .map(|class| self.root_tree.dmu().write_budget_info(class))
+            .collect()
+    }
+
+    /// For tests only: Writes all objects from now on like a pool of the
+    /// older `format_version`, which is persisted with the next sync, e.g. to
+    /// test [Self::upgrade].
+    #[cfg(feature = "internal-api")]
+    pub fn set_format_version(&self, format_version: u32) {
+        assert!((MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&format_version));
+        self.root_tree
+            .dmu()
+            .handler()
+            .format_version
+            .store(format_version, Ordering::Release);
+    }
+
     #[allow(missing_docs)]
     #[cfg(feature = "internal-api")]
     pub fn root_tree(&self) -> &RootTree<RootDmu> {
@@ -629,6 +1506,85 @@ impl Database {
     }
 }
 
+/// Returns the newest superblock whose sync has been written completely.  A
+/// crash during a sync can leave its superblock on disk while some of its
+/// objects are torn, in which case the root of the preceding sync is used.
+/// Any other error, e.g. of an unavailable device, is returned, as the next
+/// sync would overwrite the newest root otherwise.
+fn newest_complete_superblock(
+    dmu: &Arc<RootDmu>,
+    superblocks: Vec<Superblock<ObjectPointer>>,
+) -> Result<Option<Superblock<ObjectPointer>>> {
+    let mut torn = None;
+    for sb in superblocks {
+        match verify_sync(dmu, &sb.root_ptr) {
+            Ok(()) => {
+                if torn.is_some() {
+                    warn!(
+                        "Falling back to the root of generation {:?}",
+                        sb.root_ptr.generation()
+                    );
+                }
+                return Ok(Some(sb));
+            }
+            Err(e) if is_torn(&e) => {
+                warn!(
+                    "The sync of generation {:?} is incomplete: {e}",
+                    sb.root_ptr.generation()
+                );
+                // Nodes of the root tree may have been cached while the
+                // datasets were checked.
+                dmu.drop_cache();
+                torn = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    torn.map_or(Ok(None), Err)
+}
+
+/// Returns whether `err` has been caused by an object which does not match its
+/// checksum or can not be decoded.
+fn is_torn(err: &Error) -> bool {
+    match err {
+        Error::BinarySerializationError { .. } => true,
+        Error::DmlError { source }
+        | Error::TreeError {
+            source: crate::tree::Error::DmuError { source },
+        } => match source {
+            data_management::Error::CompressionError { .. }
+            | data_management::Error::DecompressionError
+            | data_management::Error::DeserializationError => true,
+            data_management::Error::HandlerError { source } => is_torn(source),
+            _ => source.category() == ErrorCategory::ChecksumMismatch,
+        },
+        _ => err.category() == ErrorCategory::ChecksumMismatch,
+    }
+}
+
+/// Reads all objects written by the sync which has produced `root_ptr`, those
+/// of the root tree as well as those of the datasets.
+fn verify_sync(dmu: &Arc<RootDmu>, root_ptr: &ObjectPointer) -> Result<()> {
+    dmu.verify_generation(root_ptr)?;
+    let root_tree = RootTree::open(
+        ROOT_DATASET_ID,
+        *root_ptr,
+        DefaultMessageAction,
+        Arc::clone(dmu),
+        ROOT_TREE_STORAGE_PREFERENCE,
+    );
+    let low = &dataset_key::data_key(DatasetId::default()) as &[_];
+    let high = &dataset_key::data_key_max() as &[_];
+    for result in root_tree.range(low..high)? {
+        let (_, data) = result?;
+        let ds_data = DatasetData::<ObjectPointer>::unpack(&data)?;
+        if ds_data.ptr.generation() == root_ptr.generation() {
+            dmu.verify_generation(&ds_data.ptr)?;
+        }
+    }
+    Ok(())
+}
+
 fn fetch_ds_data<T>(root_tree: &T, id: DatasetId) -> Result<DatasetData<ObjectPointer>>
 where
     T: TreeLayer<DefaultMessageAction>,
@@ -788,4 +1744,9 @@ impl Generation {
     fn next(self) -> Self {
         Generation(self.0 + 1)
     }
+
+    /// Returns the number of this generation, which grows with each sync.
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
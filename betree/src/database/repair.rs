@@ -0,0 +1,342 @@
+//! Opening a database whose latest state can not be read, see
+//! [Database::open_with_repair].
+//!
+//! The allocation bitmaps, the slots of slab blocks and the space accounting
+//! are derived from the objects of the datasets and the root tree.  When the
+//! pool is repaired, they are rebuilt from all objects reachable from the
+//! newest root whose objects can all be read, so that space leaked or
+//! released by a torn sync is recovered.
+
+use super::{
+    root_tree_msg::{
+        dataset as dataset_key, replica, savepoint, segment, slab, space_accounting, SLAB,
+        SNAPSHOT_DATA,
+    },
+    savepoint::savepoint_roots,
+    AccessMode, Database, DatabaseConfiguration, DatasetData, DatasetId, Error, ErrorCategory,
+    Generation, ObjectPointer, Result, RootDmu, RootSpu, RootTree, StorageInfo, Superblock,
+    ROOT_DATASET_ID, ROOT_TREE_STORAGE_PREFERENCE,
+};
+use crate::{
+    allocator::{SegmentId, SEGMENT_SIZE, SEGMENT_SIZE_BYTES},
+    data_management::Dml,
+    storage_pool::{DiskOffset, StoragePoolLayer},
+    tree::{DefaultMessageAction, TreeLayer},
+    vdev::Block,
+    StoragePreference,
+};
+use bitvec::{order::Lsb0, view::BitView};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+};
+
+/// What has been discarded or corrected by [Database::open_with_repair].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepairReport {
+    /// The error by which opening the database has failed, `None` if it has
+    /// been opened without repairing it.
+    pub failure: Option<String>,
+    /// The generation the database has been restored to.
+    pub restored_generation: Option<Generation>,
+    /// Newer generations which have been discarded, as some of their objects
+    /// could not be read.
+    pub discarded_generations: Vec<Generation>,
+    /// Blocks which have been marked as allocated without being used by any
+    /// reachable object, and which have been freed.
+    pub leaked_blocks: u64,
+    /// Blocks which are used by reachable objects, but have been marked as
+    /// free.
+    pub missing_blocks: u64,
+    /// Slots of slab blocks which have been marked as in use without holding
+    /// any reachable object, and which have been freed.
+    pub freed_slots: u64,
+}
+
+impl Database {
+    /// Opens the database given by `builder`, repairing it if the regular
+    /// open fails.
+    ///
+    /// To repair the database, all objects reachable from the superblocks
+    /// are read, starting with the newest one.  The newest generation whose
+    /// objects can all be read is restored, and the allocation bitmaps, slab
+    /// slots and space accounting are rebuilt from its objects and committed
+    /// by a sync before the database is opened again.  Newer generations are
+    /// lost.  As every object is read, repairing takes time proportional to
+    /// the size of the stored data.
+    ///
+    /// Configuration errors are returned right away, as they can not be
+    /// repaired.
+    pub fn open_with_repair(builder: DatabaseConfiguration) -> Result<(Self, RepairReport)> {
+        let builder = DatabaseConfiguration {
+            access_mode: AccessMode::OpenIfExists,
+            ..builder
+        };
+        let failure = match Self::build(builder.clone()) {
+            Ok(db) => return Ok((db, RepairReport::default())),
+            Err(e) if e.category() == ErrorCategory::Configuration => return Err(e),
+            Err(e) => e,
+        };
+        warn!("Opening the database has failed, repairing it: {failure}");
+        let mut report = RepairReport {
+            failure: Some(failure.to_string()),
+            ..Default::default()
+        };
+        Self::repair(builder.clone(), &mut report)?;
+        Ok((Self::build(builder)?, report))
+    }
+
+    fn repair(builder: DatabaseConfiguration, report: &mut RepairReport) -> Result<()> {
+        let spl = builder.new_spu()?;
+        let handler = builder.new_handler(&spl);
+        let dmu = Arc::new(builder.new_dmu(spl, handler));
+        let mut last_error = Error::InvalidSuperblock;
+        for sb in Superblock::<ObjectPointer>::fetch_all_superblocks(dmu.pool())? {
+            let tree = RootTree::open(
+                ROOT_DATASET_ID,
+                sb.root_ptr,
+                DefaultMessageAction,
+                Arc::clone(&dmu),
+                ROOT_TREE_STORAGE_PREFERENCE,
+            );
+            match Usage::collect(&tree, &sb.root_ptr) {
+                Ok(usage) => {
+                    info!("Restoring generation {:?}", sb.root_ptr.generation());
+                    report.restored_generation = Some(sb.root_ptr.generation());
+                    return Self::rebuild(builder, tree, sb, usage, report);
+                }
+                Err(e) => {
+                    warn!("Discarding generation {:?}: {e}", sb.root_ptr.generation());
+                    report.discarded_generations.push(sb.root_ptr.generation());
+                    drop(tree);
+                    dmu.drop_cache();
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Replaces the derived structures of the root tree of `sb` by those
+    /// rebuilt from `usage` and commits them.
+    fn rebuild(
+        builder: DatabaseConfiguration,
+        tree: RootTree<RootDmu>,
+        sb: Superblock<ObjectPointer>,
+        usage: Usage,
+        report: &mut RepairReport,
+    ) -> Result<()> {
+        let root_ptr = sb.root_ptr;
+        let handler = tree.dmu().handler();
+        *handler.old_root_allocation.lock_write() =
+            Some((root_ptr.offset(), root_ptr.allocated_size()));
+        handler.root_tree_inner.set(Arc::clone(tree.inner()));
+        usage.apply(&tree, report)?;
+
+        let background_pool = builder.threads.background_pool(0)?;
+        let mut db = Self::with_root_tree(
+            builder,
+            tree,
+            root_ptr,
+            sb.format_version,
+            None,
+            background_pool,
+        );
+        db.sync()?;
+        Ok(())
+    }
+}
+
+/// The blocks and slab slots used by all objects reachable from a root.
+#[derive(Default)]
+struct Usage {
+    visited: HashSet<(DiskOffset, Option<u8>)>,
+    /// Allocation bitmaps by segment.
+    segments: HashMap<SegmentId, Vec<u8>>,
+    /// Slots in use by slab block.
+    slabs: HashMap<DiskOffset, u64>,
+}
+
+impl Usage {
+    /// Reads all objects of the root tree and of all datasets and snapshots
+    /// referenced by it.
+    fn collect(tree: &RootTree<RootDmu>, root_ptr: &ObjectPointer) -> Result<Self> {
+        let mut usage = Usage::default();
+        let spl = tree.dmu().spl();
+        for class in 0..spl.storage_class_count() {
+            for disk_id in 0..spl.disk_count(class) {
+                // The superblocks, as allocated when the pool has been created.
+                let size = Block(2) * spl.num_disks(class, disk_id) as u32;
+                usage.mark(DiskOffset::new(class, disk_id, Block(0)), size);
+            }
+        }
+        usage.walk(tree, root_ptr)?;
+
+        let ds_low = dataset_key::data_key(DatasetId::default());
+        let ds_high = dataset_key::data_key_max();
+        let ranges = [
+            &ds_low[..]..&ds_high[..],
+            &[SNAPSHOT_DATA][..]..&[SNAPSHOT_DATA + 1][..],
+        ];
+        for range in ranges {
+            for result in tree.range(range)? {
+                let (_, data) = result?;
+                let ds_data = DatasetData::<ObjectPointer>::unpack(&data)?;
+                usage.walk(tree, &ds_data.ptr)?;
+            }
+        }
+        let (sp_low, sp_high) = (savepoint::min_key(), savepoint::max_key());
+        for result in tree.range(&sp_low[..]..&sp_high[..])? {
+            let (_, data) = result?;
+            for ptr in savepoint_roots(&data)? {
+                usage.walk(tree, &ptr)?;
+            }
+        }
+        Ok(usage)
+    }
+
+    fn walk(&mut self, tree: &RootTree<RootDmu>, root: &ObjectPointer) -> Result<()> {
+        let spl = tree.dmu().spl();
+        tree.dmu().walk_objects(root, |op| self.visit(spl, op))?;
+        Ok(())
+    }
+
+    /// Records the space used by `op`, returns whether it has not been
+    /// visited before.  Subtrees may be shared by snapshots and datasets.
+    fn visit(&mut self, spl: &RootSpu, op: &ObjectPointer) -> bool {
+        if !self.visited.insert((op.offset(), op.slot())) {
+            return false;
+        }
+        let offset = op.offset();
+        let (class, disk_id) = (offset.storage_class(), offset.disk_id());
+        match op.slot() {
+            Some(slot) => {
+                let slots = self.slabs.entry(offset).or_default();
+                let first = *slots == 0;
+                *slots |= 1u64.checked_shl(u32::from(slot)).unwrap_or(0);
+                if first {
+                    self.mark(offset, spl.actual_size(class, disk_id, Block(1)));
+                }
+            }
+            None => self.mark(offset, spl.actual_size(class, disk_id, op.allocated_size())),
+        }
+        true
+    }
+
+    fn mark(&mut self, offset: DiskOffset, size: Block<u32>) {
+        let start = SegmentId::get_block_offset(offset) as usize;
+        let end = (start + size.as_u32() as usize).min(SEGMENT_SIZE);
+        self.segments
+            .entry(SegmentId::get(offset))
+            .or_insert_with(|| vec![0; SEGMENT_SIZE_BYTES])
+            .view_bits_mut::<Lsb0>()[start..end]
+            .fill(true);
+    }
+
+    /// Overwrites the allocation bitmaps, slab slots and space accounting
+    /// stored in `tree` where they differ from the rebuilt ones.
+    fn apply(mut self, tree: &RootTree<RootDmu>, report: &mut RepairReport) -> Result<()> {
+        let dmu = tree.dmu();
+        let spl = dmu.spl();
+        let handler = dmu.handler();
+        let empty = vec![0; SEGMENT_SIZE_BYTES];
+
+        for class in 0..spl.storage_class_count() {
+            let mut tier = StorageInfo {
+                free: Block(0),
+                total: Block(0),
+            };
+            for disk_id in 0..spl.disk_count(class) {
+                let size = spl.size_in_blocks(class, disk_id);
+                let mut used = 0;
+                for start in (0..size.as_u64()).step_by(SEGMENT_SIZE) {
+                    let id = SegmentId::get(DiskOffset::new(class, disk_id, Block(start)));
+                    let key = &segment::id_to_key(id) as &[_];
+                    let bitmap = self.segments.get(&id).unwrap_or(&empty);
+                    let stored = tree.get(key)?;
+                    let stored = stored.as_deref().unwrap_or(&[]);
+                    let (mut leaked, mut missing) = (0, 0);
+                    for (idx, byte) in bitmap.iter().enumerate() {
+                        let old = stored.get(idx).copied().unwrap_or(0);
+                        leaked += (old & !byte).count_ones() as u64;
+                        missing += (byte & !old).count_ones() as u64;
+                    }
+                    if leaked + missing > 0 {
+                        tree.insert(
+                            key,
+                            DefaultMessageAction::insert_msg(bitmap),
+                            StoragePreference::NONE,
+                        )?;
+                    }
+                    report.leaked_blocks += leaked;
+                    report.missing_blocks += missing;
+                    used += bitmap.view_bits::<Lsb0>().count_ones() as u64;
+                }
+
+                let total = spl.effective_free_size(class, disk_id, size);
+                let info = StorageInfo {
+                    free: Block(total.as_u64().saturating_sub(used)),
+                    total,
+                };
+                let disk = DiskOffset::construct_disk_id(class, disk_id);
+                let disk_info = &handler.free_space[&disk];
+                disk_info.free.store(info.free.as_u64(), Ordering::Relaxed);
+                disk_info
+                    .total
+                    .store(info.total.as_u64(), Ordering::Relaxed);
+                tree.insert(
+                    &space_accounting::key(disk)[..],
+                    DefaultMessageAction::insert_msg(&bincode::serialize(&info)?),
+                    StoragePreference::NONE,
+                )?;
+                tier.free += info.free.as_u64();
+                tier.total += info.total.as_u64();
+            }
+            let tier_info = &handler.free_space_tier[class as usize];
+            tier_info.free.store(tier.free.as_u64(), Ordering::Relaxed);
+            tier_info
+                .total
+                .store(tier.total.as_u64(), Ordering::Relaxed);
+        }
+
+        let mut updates = Vec::new();
+        for result in tree.range(&[SLAB][..]..&[SLAB + 1][..])? {
+            let (key, value) = result?;
+            let offset = slab::offset_from_key(&key);
+            let mut stored = [0; 8];
+            let len = value.len().min(stored.len());
+            stored[..len].copy_from_slice(&value[..len]);
+            let stored = u64::from_le_bytes(stored);
+            let slots = self.slabs.remove(&offset).unwrap_or(0);
+            report.freed_slots += u64::from((stored & !slots).count_ones());
+            if stored != slots {
+                updates.push((offset, slots));
+            }
+        }
+        updates.extend(self.slabs.drain());
+        for (offset, slots) in updates {
+            let msg = if slots == 0 {
+                DefaultMessageAction::delete_msg()
+            } else {
+                DefaultMessageAction::insert_msg(&slots.to_le_bytes())
+            };
+            tree.insert(&slab::key(offset)[..], msg, StoragePreference::NONE)?;
+        }
+        // The blocks of left over replicas have been freed with the bitmaps.
+        // The records are deleted one by one, as pools of older format
+        // versions can not store range tombstones.
+        let replicas = tree
+            .range(&replica::min_key()[..]..&replica::max_key()[..])?
+            .map(|result| result.map(|(key, _)| key))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for key in replicas {
+            tree.insert(
+                key,
+                DefaultMessageAction::delete_msg(),
+                StoragePreference::NONE,
+            )?;
+        }
+        Ok(())
+    }
+}
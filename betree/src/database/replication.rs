@@ -0,0 +1,307 @@
+//! Replication of datasets to a remote database over TCP.
+//!
+//! A [ReplicationSender] follows the change stream of a dataset, see
+//! [Dataset::subscribe], and ships it to a [ReplicationReceiver], which
+//! applies the changes to the dataset of the same name in its own database.
+//! The sender marks the end of every shipped generation with a commit, on
+//! which the replica stores the next generation as its cursor and syncs, so
+//! that the cursor is committed together with the replicated data.  On
+//! reconnect the replica reports its cursor, and the sender resumes from there
+//! if it still retains the changes since, otherwise the dataset is copied in
+//! full.
+//!
+//! A copy is read while the dataset is modified, so changes of the generation
+//! in which it has been taken are shipped again afterwards.  This is harmless
+//! for the inserts, deletes and upserts of [DefaultMessageAction], but datasets
+//! with message actions whose messages are not idempotent, like those of
+//! [crate::tree::GCounterMessageAction], would count them twice.  Senders and
+//! receivers therefore only accept datasets of [DefaultMessageAction].
+//!
+//! [DefaultMessageAction]: crate::tree::DefaultMessageAction
+
+use super::{change_feed::Batch, Database, Dataset, Error, Generation, Mutation, Result};
+use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+use crossbeam_channel::Receiver;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+};
+
+/// Name of the dataset in which a replica stores its cursors, by the name of
+/// the replicated dataset.
+pub const REPLICATION_CURSORS: &[u8] = b"replication-cursors";
+
+#[derive(Serialize, Deserialize)]
+enum Frame {
+    Hello {
+        dataset: Vec<u8>,
+    },
+    Cursor(Option<Generation>),
+    Reset,
+    Put {
+        key: CowBytes,
+        value: SlicedCowBytes,
+    },
+    Change(Mutation),
+    /// All changes of generations before the given one have been shipped.
+    Commit(Generation),
+    Ack(Generation),
+}
+
+fn send<W: Write>(writer: &mut W, frame: &Frame) -> Result<()> {
+    Ok(bincode::serialize_into(writer, frame)?)
+}
+
+/// Receives the next frame, `None` if the peer has closed the connection.
+fn recv<R: Read>(reader: &mut R) -> Result<Option<Frame>> {
+    match bincode::deserialize_from(reader) {
+        Ok(frame) => Ok(Some(frame)),
+        Err(e) => {
+            if let bincode::ErrorKind::Io(ref io) = *e {
+                if io.kind() == io::ErrorKind::UnexpectedEof {
+                    return Ok(None);
+                }
+            }
+            Err(e.into())
+        }
+    }
+}
+
+/// Ships the changes of a dataset to a [ReplicationReceiver].
+pub struct ReplicationSender {
+    dataset: Dataset,
+    name: Box<[u8]>,
+    changes: Receiver<Batch>,
+    /// Shipped generations which may not be committed by the replica yet.
+    retained: VecDeque<Batch>,
+    /// All changes of this and later generations are retained, `None` if the
+    /// replica has to be copied in full.
+    retained_since: Option<Generation>,
+}
+
+impl ReplicationSender {
+    /// Starts following the changes of `dataset`.  Changes are retained until
+    /// a replica has committed them, so a sender should be connected soon.
+    pub fn new(dataset: Dataset) -> Self {
+        ReplicationSender {
+            changes: dataset.subscribe_batches(),
+            name: dataset.name(),
+            dataset,
+            retained: VecDeque::new(),
+            retained_since: None,
+        }
+    }
+
+    /// Connects to the replica at `addr` and keeps shipping changes as they
+    /// are committed, until the connection fails or the dataset has been
+    /// closed.  After a failure this can be called again to resume.
+    pub fn replicate<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        self.ship(addr, true)
+    }
+
+    /// Connects to the replica at `addr`, ships all changes committed so far
+    /// and returns once the replica has committed them.
+    pub fn catch_up<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        self.ship(addr, false)
+    }
+
+    fn ship<A: ToSocketAddrs>(&mut self, addr: A, follow: bool) -> Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        send(
+            &mut writer,
+            &Frame::Hello {
+                dataset: self.name.to_vec(),
+            },
+        )?;
+        writer.flush()?;
+        let cursor = match recv(&mut reader)? {
+            Some(Frame::Cursor(cursor)) => cursor,
+            _ => return Err(Error::UnexpectedReplicationMessage),
+        };
+
+        // The first generation of which changes are still to be shipped.
+        let mut next = match (cursor, self.retained_since) {
+            (Some(cursor), Some(since)) if since <= cursor => cursor,
+            _ => {
+                // Changes of earlier generations have been synced before and
+                // are contained in the copy.  Changes of this generation may
+                // be missing from it and are shipped afterwards.
+                let copied = self.dataset.current_generation();
+                send(&mut writer, &Frame::Reset)?;
+                for entry in self.dataset.range::<_, &[u8]>(..)? {
+                    let (key, value) = entry?;
+                    send(&mut writer, &Frame::Put { key, value })?;
+                }
+                copied
+            }
+        };
+        for (generation, mutations) in self.retained.iter() {
+            if *generation >= next {
+                for mutation in mutations {
+                    send(&mut writer, &Frame::Change(mutation.clone()))?;
+                }
+                next = generation.next();
+            }
+        }
+
+        let mut committed = None;
+        loop {
+            let (generation, mutations) = match self.changes.try_recv() {
+                Ok(batch) => batch,
+                Err(e) => {
+                    // All generations delivered so far have been shipped.
+                    if committed != Some(next) {
+                        self.commit(&mut reader, &mut writer, next)?;
+                        committed = Some(next);
+                    }
+                    if !follow || e.is_disconnected() {
+                        return Ok(());
+                    }
+                    match self.changes.recv() {
+                        Ok(batch) => batch,
+                        Err(_) => return Ok(()),
+                    }
+                }
+            };
+            if generation >= next {
+                for mutation in &mutations {
+                    send(&mut writer, &Frame::Change(mutation.clone()))?;
+                }
+                next = generation.next();
+            }
+            self.retained.push_back((generation, mutations));
+        }
+    }
+
+    fn commit<R: Read, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        generation: Generation,
+    ) -> Result<()> {
+        send(writer, &Frame::Commit(generation))?;
+        writer.flush()?;
+        let acked = match recv(reader)? {
+            Some(Frame::Ack(acked)) => acked,
+            _ => return Err(Error::UnexpectedReplicationMessage),
+        };
+        while self
+            .retained
+            .front()
+            .map_or(false, |(generation, _)| *generation < acked)
+        {
+            self.retained.pop_front();
+        }
+        self.retained_since = Some(acked);
+        Ok(())
+    }
+}
+
+/// Applies the changes shipped by [ReplicationSender]s to a database.
+pub struct ReplicationReceiver {
+    db: Arc<RwLock<Database>>,
+}
+
+impl ReplicationReceiver {
+    /// Creates a receiver replicating into `db`.
+    pub fn new(db: Arc<RwLock<Database>>) -> Self {
+        ReplicationReceiver { db }
+    }
+
+    /// Accepts senders on `listener` and serves each of them on its own
+    /// thread, so that senders which keep following their datasets do not
+    /// block others.  Failed connections are logged, senders are expected to
+    /// reconnect.  Returns once accepting fails and all connections have
+    /// ended.
+    pub fn serve(&self, listener: &TcpListener) -> Result<()> {
+        let cursors = self
+            .db
+            .write()
+            .open_or_create_dataset(REPLICATION_CURSORS)?;
+        let result = thread::scope(|scope| -> Result<()> {
+            for stream in listener.incoming() {
+                let stream = stream?;
+                let cursors = &cursors;
+                scope.spawn(move || {
+                    if let Err(e) = self.receive_with(stream, cursors) {
+                        log::warn!("Replication connection failed: {}", e);
+                    }
+                });
+            }
+            Ok(())
+        });
+        self.db.write().close_dataset(cursors)?;
+        result
+    }
+
+    /// Serves a single sender until it closes the connection.  This can not
+    /// run alongside [ReplicationReceiver::serve], which keeps the cursors
+    /// open.
+    pub fn receive(&self, stream: TcpStream) -> Result<()> {
+        let cursors = self
+            .db
+            .write()
+            .open_or_create_dataset(REPLICATION_CURSORS)?;
+        let result = self.receive_with(stream, &cursors);
+        self.db.write().close_dataset(cursors)?;
+        result
+    }
+
+    fn receive_with(&self, stream: TcpStream, cursors: &Dataset) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let name = match recv(&mut reader)? {
+            Some(Frame::Hello { dataset }) => dataset,
+            _ => return Err(Error::UnexpectedReplicationMessage),
+        };
+
+        let ds = self.db.write().open_or_create_dataset(&name)?;
+        let result = self.apply(&mut reader, &mut writer, &name, &ds, cursors);
+        self.db.write().close_dataset(ds)?;
+        result
+    }
+
+    fn apply<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        name: &[u8],
+        ds: &Dataset,
+        cursors: &Dataset,
+    ) -> Result<()> {
+        let cursor = cursors.get(name)?.map(|value| Generation::unpack(&value));
+        send(writer, &Frame::Cursor(cursor))?;
+        writer.flush()?;
+
+        while let Some(frame) = recv(reader)? {
+            match frame {
+                Frame::Reset => {
+                    // Without a cursor, an interrupted copy is started over.
+                    cursors.delete(name)?;
+                    ds.range_delete::<_, &[u8]>(..)?;
+                }
+                Frame::Put { key, value } => ds.insert(key, &value)?,
+                Frame::Change(Mutation::Message { key, msg }) => ds.insert_msg(key, msg)?,
+                Frame::Change(Mutation::RangeDelete { start, end }) => {
+                    ds.range_delete((start, end))?
+                }
+                Frame::Commit(generation) => {
+                    cursors.insert(name, &generation.pack())?;
+                    Database::sync_shared(&self.db)?;
+                    send(writer, &Frame::Ack(generation))?;
+                    writer.flush()?;
+                }
+                _ => return Err(Error::UnexpectedReplicationMessage),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Iterations over a range of a dataset which can be interrupted and continued
+//! later, see [Dataset::resumable_range](super::Dataset::resumable_range).
+//!
+//! A paginated API built on a dataset can hand a [ResumeToken] to its client
+//! instead of keeping an iterator, and with it the nodes it has pinned in the
+//! cache, alive until the next page is requested.
+
+use super::{errors::*, DatasetId, Generation, MessageTree, ObjectRef, RootDmu};
+use crate::{
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    data_management::Dml,
+    range_validation::is_inclusive_non_empty,
+    tree::{Inner, MessageAction, RangeIterator, TreeLayer},
+};
+use serde::{Deserialize, Serialize};
+use std::{ops::Bound, sync::Arc};
+
+/// The position of a [ResumableRange], from which
+/// [Dataset::resume_range](super::Dataset::resume_range) continues.
+///
+/// The continued iteration reads the dataset as it is when resuming, so pairs
+/// inserted before the position in the meantime are not returned, and pairs
+/// returned before may have been changed since.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeToken {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    generation: Generation,
+}
+
+impl ResumeToken {
+    /// Returns the generation which was current when the token was taken.  If
+    /// it differs from [Database::current_generation](super::Database::current_generation)
+    /// when resuming, syncs have happened in between.
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
+
+    /// Encodes the token, e.g. to hand it to a client.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Decodes a token encoded by [ResumeToken::to_bytes].
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+/// An iterator over the key-value pairs of a range of a dataset, whose
+/// position can be taken as a [ResumeToken].
+pub struct ResumableRange<Message> {
+    /// `None` if a resumed range has nothing left.
+    iter: Option<RangeIterator<Arc<RootDmu>, Message, Arc<Inner<ObjectRef, Message>>>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    dmu: Arc<RootDmu>,
+    id: DatasetId,
+}
+
+impl<Message: MessageAction> ResumableRange<Message> {
+    pub(super) fn new(
+        tree: &MessageTree<RootDmu, Message>,
+        id: DatasetId,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Result<Self> {
+        Ok(ResumableRange {
+            iter: Some(tree.range((start.clone(), end.clone()))?),
+            start,
+            end,
+            dmu: Arc::clone(tree.dmu()),
+            id,
+        })
+    }
+
+    pub(super) fn resume(
+        tree: &MessageTree<RootDmu, Message>,
+        id: DatasetId,
+        token: &ResumeToken,
+    ) -> Result<Self> {
+        let range = (token.start.clone(), token.end.clone());
+        if is_inclusive_non_empty(&range) {
+            return Self::new(tree, id, range.0, range.1);
+        }
+        Ok(ResumableRange {
+            iter: None,
+            start: range.0,
+            end: range.1,
+            dmu: Arc::clone(tree.dmu()),
+            id,
+        })
+    }
+
+    /// Returns the position after the last pair returned so far.
+    pub fn resume_token(&self) -> ResumeToken {
+        let start = match self.iter.as_ref().and_then(|iter| iter.last_key()) {
+            Some(key) => Bound::Excluded(key.to_vec()),
+            None => self.start.clone(),
+        };
+        ResumeToken {
+            start,
+            end: self.end.clone(),
+            generation: self.dmu.handler().current_generation(),
+        }
+    }
+}
+
+impl<Message: MessageAction> Iterator for ResumableRange<Message> {
+    type Item = Result<(CowBytes, SlicedCowBytes)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.iter.as_mut()?.next()?;
+        Some(result.map_err(Error::from).map(|(key, value)| {
+            self.dmu
+                .handler()
+                .io_accounting
+                .logical_read(self.id, (key.len() + value.len()) as u64);
+            (key, value)
+        }))
+    }
+}
@@ -19,6 +19,11 @@ pub(crate) const OBJECT_STORE_ID_COUNTER_PREFIX: u8 = 6;
 pub(crate) const OBJECT_STORE_NAME_TO_ID_PREFIX: u8 = 7;
 pub(crate) const OBJECT_STORE_DATA_PREFIX: u8 = 8;
 pub(super) const DISK_SPACE: u8 = 9;
+pub(super) const SLAB: u8 = 10;
+pub(super) const DATASET_CONFIGURATION: u8 = 11;
+pub(super) const SAVEPOINT: u8 = 12;
+pub(super) const REPLICA: u8 = 13;
+pub(super) const WRITE_BUDGET: u8 = 14;
 
 // DATASETS
 
@@ -28,7 +33,7 @@ pub(super) mod dataset {
     //! functions, byte-wise handling is discouraged.
     use crate::database::DatasetId;
 
-    use super::{DATASET_DATA, DATASET_ID_COUNTER, DATASET_NAME_TO_ID};
+    use super::{DATASET_CONFIGURATION, DATASET_DATA, DATASET_ID_COUNTER, DATASET_NAME_TO_ID};
 
     const DS_ID_OFFSET: usize = 1;
     const DATA_FULL: usize = 9;
@@ -57,6 +62,18 @@ pub(super) mod dataset {
     pub fn data_key_max() -> [u8; 1] {
         [DATASET_DATA + 1]
     }
+
+    pub fn id_from_data_key(key: &[u8]) -> DatasetId {
+        DatasetId::unpack(&key[DS_ID_OFFSET..DATA_FULL])
+    }
+
+    // Full Key for the id to tree configuration mapping
+    pub fn config_key(id: DatasetId) -> [u8; DATA_FULL] {
+        let mut key = [0; DATA_FULL];
+        key[0] = DATASET_CONFIGURATION;
+        key[DS_ID_OFFSET..].copy_from_slice(&id.pack());
+        key
+    }
 }
 
 // SEGMENTS
@@ -110,6 +127,13 @@ pub(super) mod snapshot {
         key
     }
 
+    pub fn ids_from_data_key(key: &[u8]) -> (DatasetId, Generation) {
+        (
+            DatasetId::unpack(&key[DS_ID_OFFSET..SS_ID_OFFSET]),
+            Generation::unpack(&key[SS_ID_OFFSET..FULL]),
+        )
+    }
+
     // Partial Key
     pub fn data_key_max(mut ds_id: DatasetId) -> [u8; SS_ID_OFFSET] {
         ds_id.0 += 1;
@@ -120,7 +144,7 @@ pub(super) mod snapshot {
     }
 }
 
-// DEADLIST - snapshot only objects
+// DEADLIST - objects only preserved by snapshots or savepoints
 
 pub(super) mod deadlist {
     //! The required definitions and helpers to handle slices representing a
@@ -175,6 +199,14 @@ pub(super) mod deadlist {
     pub fn offset_from_key(key: &[u8]) -> DiskOffset {
         DiskOffset::from_u64(BigEndian::read_u64(&key[DO_OFFSET..]))
     }
+
+    // The dataset and the generation in which the object has been freed
+    pub fn ids_from_key(key: &[u8]) -> (DatasetId, Generation) {
+        (
+            DatasetId::unpack(&key[DS_ID_OFFSET..SS_ID_OFFSET]),
+            Generation::unpack(&key[SS_ID_OFFSET..DO_OFFSET]),
+        )
+    }
 }
 
 // SPACE ACCOUNTING
@@ -215,3 +247,108 @@ pub(super) mod space_accounting {
         [DISK_SPACE + 1]
     }
 }
+
+// SLABS
+
+pub(super) mod slab {
+    //! Each slab entry is characterized by the 1 byte prefix followed by the
+    //! disk offset of the slab block.  Its value is a bitmap of the slots in
+    //! use.
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use crate::storage_pool::DiskOffset;
+
+    use super::SLAB;
+
+    const FULL: usize = 9;
+    const OFFSET_OFFSET: usize = 1;
+
+    pub fn key(offset: DiskOffset) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[0] = SLAB;
+        BigEndian::write_u64(&mut key[OFFSET_OFFSET..], offset.as_u64());
+        key
+    }
+
+    pub fn offset_from_key(key: &[u8]) -> DiskOffset {
+        DiskOffset::from_u64(BigEndian::read_u64(&key[OFFSET_OFFSET..]))
+    }
+}
+
+// SAVEPOINTS
+
+pub(super) mod savepoint {
+    //! Each savepoint entry is characterized by the 1 byte prefix followed by
+    //! the name of the savepoint.
+
+    use super::SAVEPOINT;
+
+    pub fn key(name: &[u8]) -> Vec<u8> {
+        let mut key = Vec::with_capacity(1 + name.len());
+        key.push(SAVEPOINT);
+        key.extend_from_slice(name);
+        key
+    }
+
+    pub fn name_from_key(key: &[u8]) -> &[u8] {
+        &key[1..]
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [SAVEPOINT]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [SAVEPOINT + 1]
+    }
+}
+
+// REPLICAS
+
+pub(super) mod replica {
+    //! Each replica entry is characterized by the 1 byte prefix followed by
+    //! the disk offset of the replica.  Its value is the 32-bit BE number of
+    //! blocks allocated for it.
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use crate::storage_pool::DiskOffset;
+
+    use super::REPLICA;
+
+    const FULL: usize = 9;
+    const OFFSET_OFFSET: usize = 1;
+
+    pub fn key(offset: DiskOffset) -> [u8; FULL] {
+        let mut key = [0; FULL];
+        key[0] = REPLICA;
+        BigEndian::write_u64(&mut key[OFFSET_OFFSET..], offset.as_u64());
+        key
+    }
+
+    pub fn offset_from_key(key: &[u8]) -> DiskOffset {
+        DiskOffset::from_u64(BigEndian::read_u64(&key[OFFSET_OFFSET..]))
+    }
+
+    pub fn min_key() -> [u8; 1] {
+        [REPLICA]
+    }
+
+    pub fn max_key() -> [u8; 1] {
+        [REPLICA + 1]
+    }
+}
+
+// WRITE BUDGETS
+
+pub(super) mod write_budget {
+    //! The write budget counters of the current day are stored in a single
+    //! entry characterized by the 1 byte prefix.
+
+    use super::WRITE_BUDGET;
+
+    pub fn key() -> [u8; 1] {
+        [WRITE_BUDGET]
+    }
+}
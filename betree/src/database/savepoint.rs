@@ -0,0 +1,216 @@
+//! Named savepoints of all datasets, see [Database::create_savepoint].
+//!
+//! A savepoint records the roots of all datasets as committed by a sync and
+//! pins the generation of this sync.  Objects written up to this generation
+//! are not freed while the savepoint exists, but are put on the dead list of
+//! their dataset, like the objects preserved by snapshots.  Unlike snapshots,
+//! which are taken of a single dataset each, a savepoint covers the whole
+//! database with one entry in the root tree.  The root tree itself is not
+//! preserved.
+//!
+//! An object on a dead list is freed once no snapshot of its dataset and no
+//! savepoint has been taken between the generation which has written it and
+//! the one in which it has been freed.
+
+use super::{
+    errors::*,
+    root_tree_msg::{
+        dataset as dataset_key, deadlist, savepoint, snapshot as snapshot_key, DEADLIST,
+    },
+    Database, Dataset, DatasetData, DatasetId, DeadListData, Generation, ObjectPointer, Snapshot,
+};
+use crate::{
+    allocator::Action,
+    tree::{DefaultMessageAction, Tree, TreeLayer},
+    StoragePreference,
+};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavepointData {
+    generation: Generation,
+    roots: Vec<(DatasetId, ObjectPointer)>,
+}
+
+/// Returns the dataset roots recorded by a savepoint entry of the root tree.
+pub(super) fn savepoint_roots(data: &[u8]) -> Result<Vec<ObjectPointer>> {
+    let data: SavepointData = deserialize(data)?;
+    Ok(data.roots.into_iter().map(|(_, ptr)| ptr).collect())
+}
+
+impl Database {
+    /// Syncs the database and records the committed state of all datasets as
+    /// a savepoint with the given name, returns the committed generation.
+    ///
+    /// Until the savepoint is released with [Database::release_savepoint],
+    /// no space used by this state is reclaimed, and the state of each dataset
+    /// can be read with [Database::open_savepoint].  A savepoint is cheaper
+    /// than snapshots of all datasets, but can not be kept per dataset.
+    pub fn create_savepoint(&mut self, name: &[u8]) -> Result<Generation> {
+        let key = savepoint::key(name);
+        if self.root_tree.get(&key[..])?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        // Pinned before syncing, so that no object of the committed state can
+        // be freed before the savepoint exists.
+        let generation = self.current_generation();
+        let handler = self.root_tree.dmu().handler();
+        handler.savepoints.write().insert(generation, 0);
+        if let Err(e) = self.sync() {
+            let handler = self.root_tree.dmu().handler();
+            handler.savepoints.write().remove(&generation);
+            return Err(e);
+        }
+
+        let low = dataset_key::data_key(DatasetId::default());
+        let high = dataset_key::data_key_max();
+        let mut roots = Vec::new();
+        for result in self.root_tree.range(&low[..]..&high[..])? {
+            let (key, data) = result?;
+            let ptr = DatasetData::<ObjectPointer>::unpack(&data)?.ptr;
+            roots.push((dataset_key::id_from_data_key(&key), ptr));
+        }
+        let data = serialize(&SavepointData { generation, roots })?;
+        self.root_tree.insert(
+            &key[..],
+            DefaultMessageAction::insert_msg(&data),
+            StoragePreference::NONE,
+        )?;
+        self.sync()?;
+        Ok(generation)
+    }
+
+    /// Returns the names of all savepoints with their generations.
+    pub fn iter_savepoints(&self) -> Result<Vec<(Box<[u8]>, Generation)>> {
+        let low = savepoint::min_key();
+        let high = savepoint::max_key();
+        let mut savepoints = Vec::new();
+        for result in self.root_tree.range(&low[..]..&high[..])? {
+            let (key, data) = result?;
+            let data: SavepointData = deserialize(&data)?;
+            savepoints.push((Box::from(savepoint::name_from_key(&key)), data.generation));
+        }
+        Ok(savepoints)
+    }
+
+    fn lookup_savepoint(&self, name: &[u8]) -> Result<SavepointData> {
+        let data = self
+            .root_tree
+            .get(savepoint::key(name))?
+            .ok_or(Error::DoesNotExist)?;
+        Ok(deserialize(&data)?)
+    }
+
+    /// Opens the state of the given dataset as recorded by a savepoint.  The
+    /// savepoint can not be released while the view is open.
+    ///
+    /// Fails with [Error::DoesNotExist] if the dataset has been created after
+    /// the savepoint.
+    pub fn open_savepoint<M>(&self, ds: &Dataset<M>, name: &[u8]) -> Result<Snapshot> {
+        let data = self.lookup_savepoint(name)?;
+        let ptr = data
+            .roots
+            .into_iter()
+            .find(|(id, _)| *id == ds.id())
+            .ok_or(Error::DoesNotExist)?
+            .1;
+        *self
+            .root_tree
+            .dmu()
+            .handler()
+            .savepoints
+            .write()
+            .entry(data.generation)
+            .or_default() += 1;
+        let tree = Tree::open(
+            ds.id(),
+            ptr,
+            DefaultMessageAction,
+            Arc::clone(self.root_tree.dmu()),
+            StoragePreference::NONE,
+        );
+        Ok(Snapshot::new(tree, name, Some(data.generation)))
+    }
+
+    /// Removes a savepoint and frees all objects which have only been
+    /// preserved by it.
+    ///
+    /// Fails with [Error::InUse] while a view opened by
+    /// [Database::open_savepoint] exists.
+    pub fn release_savepoint(&mut self, name: &[u8]) -> Result<()> {
+        let data = self.lookup_savepoint(name)?;
+        let handler = self.root_tree.dmu().handler();
+        {
+            let mut savepoints = handler.savepoints.write();
+            if savepoints
+                .get(&data.generation)
+                .map_or(false, |&views| views > 0)
+            {
+                return Err(Error::InUse);
+            }
+            savepoints.remove(&data.generation);
+        }
+        self.root_tree.insert(
+            savepoint::key(name),
+            DefaultMessageAction::delete_msg(),
+            StoragePreference::NONE,
+        )?;
+
+        // Only objects written up to the savepoint and freed after it may
+        // have been preserved by it.
+        let mut snapshots: HashMap<DatasetId, Vec<Generation>> = HashMap::new();
+        let mut freed = Vec::new();
+        for result in self.root_tree.range(&[DEADLIST][..]..&[DEADLIST + 1][..])? {
+            let (key, value) = result?;
+            let entry = DeadListData::unpack(&value)?;
+            let (ds_id, death) = deadlist::ids_from_key(&key);
+            if entry.birth > data.generation || death <= data.generation {
+                continue;
+            }
+            if !snapshots.contains_key(&ds_id) {
+                snapshots.insert(ds_id, self.snapshot_generations(ds_id)?);
+            }
+            let preserved_by_snapshot = snapshots[&ds_id]
+                .iter()
+                .any(|ss_id| entry.birth <= *ss_id && *ss_id < death);
+            if !preserved_by_snapshot && !handler.preserved_by_savepoint(entry.birth, death) {
+                freed.push((key, entry));
+            }
+        }
+        for (key, entry) in freed {
+            handler.update_allocation_bitmap(
+                deadlist::offset_from_key(&key),
+                entry.size,
+                Action::Deallocate,
+                self.root_tree.dmu(),
+            )?;
+            self.root_tree.insert(
+                key,
+                DefaultMessageAction::delete_msg(),
+                StoragePreference::NONE,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn snapshot_generations(&self, ds_id: DatasetId) -> Result<Vec<Generation>> {
+        let low = snapshot_key::data_key(ds_id, Generation(0));
+        let high = snapshot_key::data_key_max(ds_id);
+        self.root_tree
+            .range(&low[..]..&high[..])?
+            .map(|result| Ok(snapshot_key::ids_from_data_key(&result?.0).1))
+            .collect()
+    }
+
+    /// Pins the generations of all savepoints of an opened database.
+    pub(super) fn load_savepoints(&self) -> Result<()> {
+        let savepoints = self.iter_savepoints()?;
+        let mut pinned = self.root_tree.dmu().handler().savepoints.write();
+        for (_, generation) in savepoints {
+            pinned.insert(generation, 0);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Verification of all committed objects, see [Database::scrub].
+
+use super::{
+    root_tree_msg::{dataset as dataset_key, snapshot as snapshot_key, SNAPSHOT_DATA},
+    Database, DatasetData, DatasetId, Generation, ObjectPointer, Result, RootDmu, RootTree,
+    ROOT_DATASET_ID, ROOT_TREE_STORAGE_PREFERENCE,
+};
+use crate::{
+    storage_pool::DiskOffset,
+    tree::{DefaultMessageAction, TreeLayer},
+};
+use serde::Serialize;
+use std::{collections::HashSet, sync::Arc};
+
+/// A tree of which some objects could not be read.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrubFailure {
+    /// The dataset of the tree, `None` for the root tree.
+    pub dataset: Option<DatasetId>,
+    /// The snapshot of the dataset, `None` for its current state.
+    pub snapshot: Option<Generation>,
+    /// The first error encountered, objects below the failed one have not
+    /// been checked.  Damaged copies of objects which have been written twice
+    /// are reported separately, the objects below them are still checked.
+    pub error: String,
+}
+
+/// The outcome of [Database::scrub].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubReport {
+    /// The number of objects which have been read.
+    pub objects: u64,
+    /// The number of bytes which have been read.
+    pub bytes: u64,
+    /// All trees which could not be read completely.
+    pub failures: Vec<ScrubFailure>,
+}
+
+impl ScrubReport {
+    /// Returns whether all objects have been read successfully.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl Database {
+    /// Reads every object of the last committed state of the root tree, all
+    /// datasets and all snapshots, and reports those which do not match their
+    /// checksum or can not be decoded.  Objects shared by snapshots are only
+    /// read once.  Objects are read from the storage tiers directly, so this
+    /// takes time proportional to the size of the stored data.
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        let dmu = self.root_tree.dmu();
+        let root_ptr = dmu
+            .handler()
+            .root_tree_snapshot
+            .read()
+            .as_ref()
+            .and_then(|inner| inner.root_ptr())
+            .expect("The root tree has been committed when opening the database");
+        let mut scrubber = Scrubber {
+            dmu,
+            visited: HashSet::new(),
+            report: ScrubReport::default(),
+        };
+        scrubber.check(None, None, &root_ptr);
+
+        let root_tree = RootTree::open(
+            ROOT_DATASET_ID,
+            root_ptr,
+            DefaultMessageAction,
+            Arc::clone(dmu),
+            ROOT_TREE_STORAGE_PREFERENCE,
+        );
+        let low = &dataset_key::data_key(DatasetId::default()) as &[_];
+        let high = &dataset_key::data_key_max() as &[_];
+        for result in root_tree.range(low..high)? {
+            let (key, data) = result?;
+            let ds_data = DatasetData::<ObjectPointer>::unpack(&data)?;
+            let dataset = dataset_key::id_from_data_key(&key);
+            scrubber.check(Some(dataset), None, &ds_data.ptr);
+        }
+        for result in root_tree.range(&[SNAPSHOT_DATA][..]..&[SNAPSHOT_DATA + 1][..])? {
+            let (key, data) = result?;
+            let ss_data = DatasetData::<ObjectPointer>::unpack(&data)?;
+            let (dataset, snapshot) = snapshot_key::ids_from_data_key(&key);
+            scrubber.check(Some(dataset), Some(snapshot), &ss_data.ptr);
+        }
+        Ok(scrubber.report)
+    }
+}
+
+struct Scrubber<'a> {
+    dmu: &'a Arc<RootDmu>,
+    visited: HashSet<(DiskOffset, Option<u8>)>,
+    report: ScrubReport,
+}
+
+impl<'a> Scrubber<'a> {
+    fn check(
+        &mut self,
+        dataset: Option<DatasetId>,
+        snapshot: Option<Generation>,
+        root: &ObjectPointer,
+    ) {
+        let Scrubber {
+            dmu,
+            visited,
+            report,
+        } = self;
+        let result = dmu.walk_objects(root, |op| {
+            if !visited.insert((op.offset(), op.slot())) {
+                return false;
+            }
+            report.objects += 1;
+            report.bytes += op.size().to_bytes() as u64;
+            // The walk reads either copy, a damaged one would go unnoticed.
+            if op.ditto_offset().is_some() {
+                report.bytes += op.size().to_bytes() as u64;
+                if let Err(e) = dmu.verify_copies(op) {
+                    warn!("Scrubbing a copy of {op:?} failed: {e}");
+                    report.failures.push(ScrubFailure {
+                        dataset,
+                        snapshot,
+                        error: e.to_string(),
+                    });
+                }
+            }
+            true
+        });
+        if let Err(e) = result {
+            warn!("Scrubbing {dataset:?} {snapshot:?} failed: {e}");
+            report.failures.push(ScrubFailure {
+                dataset,
+                snapshot,
+                error: e.to_string(),
+            });
+        }
+    }
+}
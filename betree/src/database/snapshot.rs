@@ -18,6 +18,9 @@ pub struct Snapshot {
     tree: DatasetTree<RootDmu>,
     #[allow(dead_code)]
     name: Box<[u8]>,
+    // The generation of the savepoint this view has been opened from, see
+    // [Database::open_savepoint].
+    pub(super) savepoint: Option<Generation>,
 }
 
 impl Database {
@@ -37,6 +40,7 @@ impl Database {
                 StoragePreference::NONE,
             ),
             name: Box::from(name),
+            savepoint: None,
         })
     }
 
@@ -59,21 +63,91 @@ impl Database {
         };
 
         let data = fetch_ds_data(&self.root_tree, ds.id())?;
+        self.insert_snapshot(ds.id(), name, data)?;
+        self.sync()?;
+        Ok(())
+    }
+
+    /// Syncs the database and creates a snapshot with the given name of every
+    /// dataset, including the datasets of object stores, returns the
+    /// committed generation.
+    ///
+    /// All snapshots show the state of this single sync, so that they are
+    /// consistent with each other.  Datasets which have not been modified
+    /// since their last snapshot share it, it is only released once all its
+    /// names have been deleted.  The creation fails without creating any
+    /// snapshot if a dataset has a snapshot with this name already.
+    pub fn snapshot_all(&mut self, name: &[u8]) -> Result<Generation> {
+        let generation = self.sync()?;
+
+        let low = dataset::data_key(DatasetId::default());
+        let high = dataset::data_key_max();
+        let mut datasets = Vec::new();
+        for result in self.root_tree.range(&low[..]..&high[..])? {
+            let (key, data) = result?;
+            datasets.push((
+                dataset::id_from_data_key(&key),
+                DatasetData::<ObjectPointer>::unpack(&data)?,
+            ));
+        }
+        for (ds_id, _) in &datasets {
+            match self.lookup_snapshot_id(*ds_id, name) {
+                Ok(_) => return Err(Error::AlreadyExists),
+                Err(Error::DoesNotExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        for (ds_id, data) in datasets {
+            self.insert_snapshot(ds_id, name, data)?;
+        }
+        self.sync()?;
+        Ok(generation)
+    }
+
+    /// Records the committed state `data` of a dataset as its snapshot `name`.
+    fn insert_snapshot(
+        &self,
+        ds_id: DatasetId,
+        name: &[u8],
+        data: DatasetData<ObjectPointer>,
+    ) -> Result<()> {
         let ss_id = data.ptr.generation();
-        let key = &snapshot::data_key(ds.id(), ss_id) as &[_];
+        self.root_tree.insert(
+            snapshot::key(ds_id, name),
+            DefaultMessageAction::insert_msg(&ss_id.pack()),
+            StoragePreference::NONE,
+        )?;
+        // An unmodified dataset has been committed with the same generation.
+        if data.previous_snapshot == Some(ss_id) {
+            return Ok(());
+        }
+        let key = &snapshot::data_key(ds_id, ss_id) as &[_];
         let data = data.pack()?;
         self.root_tree.insert(
             key,
             DefaultMessageAction::insert_msg(&data),
             StoragePreference::NONE,
         )?;
-        let key = &dataset::data_key(ds.id()) as &[_];
+        let key = &dataset::data_key(ds_id) as &[_];
         self.root_tree.insert(
             key,
             DatasetData::<ObjectPointer>::update_previous_snapshot(Some(ss_id)),
             StoragePreference::NONE,
         )?;
-        self.sync()
+        Ok(())
+    }
+
+    /// Returns whether a snapshot of a dataset has other names than `name`.
+    fn has_other_names(&self, ds_id: DatasetId, name: &[u8], ss_id: Generation) -> Result<bool> {
+        let low = snapshot::key(ds_id, &[]);
+        let high = snapshot::key(DatasetId(ds_id.0 + 1), &[]);
+        for result in self.root_tree.range(&low[..]..&high[..])? {
+            let (key, data) = result?;
+            if key[low.len()..] != *name && Generation::unpack(&data) == ss_id {
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
     /// Iterate over all snapshots for the given data set.
@@ -107,6 +181,9 @@ impl Database {
             DefaultMessageAction::delete_msg(),
             StoragePreference::NONE,
         )?;
+        if self.has_other_names(ds.id(), name, ss_id)? {
+            return Ok(());
+        }
 
         let previous_ss_id = fetch_ss_data(&self.root_tree, ds.id(), ss_id)?.previous_snapshot;
         let update_previous_ss_msg =
@@ -133,13 +210,17 @@ impl Database {
             &max_key_dataset as &[_]
         };
         let min_key = &deadlist::min_key(ds.id(), ss_id.next()) as &[_];
+        let handler = self.root_tree.dmu().handler();
 
         for result in self.root_tree.range(min_key..max_key)? {
             let (key, value) = result?;
             let entry = DeadListData::unpack(&value)?;
-            if previous_ss_id < Some(entry.birth) {
+            let (_, death) = deadlist::ids_from_key(&key);
+            if previous_ss_id < Some(entry.birth)
+                && !handler.preserved_by_savepoint(entry.birth, death)
+            {
                 let offset = deadlist::offset_from_key(&key);
-                self.root_tree.dmu().handler().update_allocation_bitmap(
+                handler.update_allocation_bitmap(
                     offset,
                     entry.size,
                     Action::Deallocate,
@@ -171,6 +252,18 @@ impl Database {
 }
 
 impl Snapshot {
+    pub(super) fn new(
+        tree: DatasetTree<RootDmu>,
+        name: &[u8],
+        savepoint: Option<Generation>,
+    ) -> Self {
+        Snapshot {
+            tree,
+            name: Box::from(name),
+            savepoint,
+        }
+    }
+
     /// Returns the value for the given key if existing.
     pub fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>> {
         Ok(self.tree.get(key)?)
@@ -188,3 +281,14 @@ impl Snapshot {
         Ok(Box::new(self.tree.range(range)?.map(|r| Ok(r?))))
     }
 }
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Some(generation) = self.savepoint {
+            let handler = self.tree.dmu().handler();
+            if let Some(views) = handler.savepoints.write().get_mut(&generation) {
+                *views -= 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Summary of the space usage of a [Database] per storage tier, to judge
+//! whether deleting snapshots or compacting data would free up space.
+
+use super::{
+    root_tree_msg::{deadlist, DEADLIST},
+    Database, DeadListData, Result,
+};
+use crate::{
+    allocator::{SegmentId, SEGMENT_SIZE},
+    storage_pool::{DiskOffset, StoragePoolLayer},
+    tree::TreeLayer,
+    vdev::Block,
+};
+use serde::{Deserialize, Serialize};
+
+/// Free extents smaller than this can not hold the largest nodes written by
+/// the tree, so their space is only usable by smaller nodes.
+const COMPACTION_EXTENT_THRESHOLD: Block<u32> = Block(1024);
+
+/// Number of free extents by their size.  Bucket `i` counts extents with a
+/// size of `2^i` up to `2^(i+1) - 1` blocks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FreeExtentHistogram {
+    /// Extent counts per bucket.
+    pub buckets: Vec<u64>,
+}
+
+impl FreeExtentHistogram {
+    fn insert(&mut self, size: u32) {
+        let bucket = (u32::BITS - 1 - size.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// Total number of free extents.
+    pub fn extents(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+/// Space usage of a single storage tier.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TierSpaceReport {
+    /// Total size of the tier.
+    pub total: Block<u64>,
+    /// Free space of the tier.
+    pub free: Block<u64>,
+    /// Space used by data reachable from the current state of the datasets.
+    pub live: Block<u64>,
+    /// Space of data which has been overwritten or deleted, but is still
+    /// referenced by snapshots.  It is reclaimed when these snapshots are
+    /// deleted.
+    pub dead: Block<u64>,
+    /// Sizes of the free extents in the allocation bitmaps.
+    pub free_extents: FreeExtentHistogram,
+    /// Free space in extents too small for the largest nodes, which could be
+    /// turned into usable space by compacting the tier.
+    pub reclaimable_by_compaction: Block<u64>,
+}
+
+/// Space usage of all storage tiers, see [Database::space_report].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpaceReport {
+    /// Reports indexed by storage class.
+    pub tiers: Vec<TierSpaceReport>,
+}
+
+impl Database {
+    /// Summarizes the space usage of every storage tier.  This reads the
+    /// allocation bitmaps of all segments and the dead lists of all datasets,
+    /// so it is too expensive to be called frequently.
+    pub fn space_report(&self) -> Result<SpaceReport> {
+        let dmu = self.root_tree.dmu();
+        let spl = dmu.spl();
+        let handler = dmu.handler();
+
+        let mut tiers = (0..spl.storage_class_count())
+            .map(|class| {
+                let info = handler.free_space_tier(class).unwrap();
+                TierSpaceReport {
+                    total: info.total,
+                    free: info.free,
+                    live: Block(0),
+                    dead: Block(0),
+                    free_extents: FreeExtentHistogram::default(),
+                    reclaimable_by_compaction: Block(0),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for result in self.root_tree.range(&[DEADLIST][..]..&[DEADLIST + 1][..])? {
+            let (key, value) = result?;
+            let entry = DeadListData::unpack(&value)?;
+            let class = deadlist::offset_from_key(&key).storage_class();
+            if let Some(tier) = tiers.get_mut(class as usize) {
+                tier.dead += entry.size.as_u64();
+            }
+        }
+
+        for (class, tier) in tiers.iter_mut().enumerate() {
+            let class = class as u8;
+            let used = tier.total.as_u64().saturating_sub(tier.free.as_u64());
+            tier.live = Block(used.saturating_sub(tier.dead.as_u64()));
+            for disk_id in 0..spl.disk_count(class) {
+                let size = spl.size_in_blocks(class, disk_id).as_u64();
+                for start in (0..size).step_by(SEGMENT_SIZE) {
+                    let id = SegmentId::get(DiskOffset::new(class, disk_id, Block(start)));
+                    let allocator = handler.get_allocation_bitmap(id, &**dmu)?;
+                    let len = (size - start).min(SEGMENT_SIZE as u64) as usize;
+                    for extent in allocator.access().free_extents(len) {
+                        tier.free_extents.insert(extent);
+                        if extent < COMPACTION_EXTENT_THRESHOLD.as_u32() {
+                            tier.reclaimable_by_compaction += extent as u64;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(SpaceReport { tiers })
+    }
+}
@@ -1,6 +1,8 @@
 use crate::vdev::Block;
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 /// Space information representation for a singular storage tier.
@@ -66,3 +68,76 @@ impl From<&StorageInfo> for AtomicStorageInfo {
         }
     }
 }
+
+/// A change of the fill level of a storage tier, see
+/// [Database::watch_free_space](super::Database::watch_free_space).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StorageInfoChange {
+    /// The storage class of the tier.
+    pub class: u8,
+    /// The space information last sent to this receiver.
+    pub previous: StorageInfo,
+    /// The current space information.
+    pub current: StorageInfo,
+}
+
+struct SpaceWatcher {
+    granularity: f32,
+    reported: Vec<StorageInfo>,
+    tx: Sender<StorageInfoChange>,
+}
+
+#[derive(Default)]
+pub(crate) struct SpaceWatchers {
+    watchers: Mutex<Vec<SpaceWatcher>>,
+    active: AtomicBool,
+}
+
+impl SpaceWatchers {
+    pub(crate) fn watch(
+        &self,
+        granularity: f32,
+        current: Vec<StorageInfo>,
+    ) -> Receiver<StorageInfoChange> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.watchers.lock().push(SpaceWatcher {
+            granularity,
+            reported: current,
+            tx,
+        });
+        self.active.store(true, Ordering::Release);
+        rx
+    }
+
+    /// Notifies all watchers for which the fill level of `class` has changed
+    /// by at least their granularity since they have last been notified.
+    pub(crate) fn notify(&self, class: u8, info: &AtomicStorageInfo) {
+        if !self.active.load(Ordering::Acquire) {
+            return;
+        }
+        let current = StorageInfo::from(info);
+        if current.total == Block(0) {
+            return;
+        }
+        let mut watchers = self.watchers.lock();
+        watchers.retain_mut(|watcher| {
+            let previous = match watcher.reported.get_mut(class as usize) {
+                Some(previous) => previous,
+                None => return true,
+            };
+            if (current.percent_full() - previous.percent_full()).abs() < watcher.granularity {
+                return true;
+            }
+            let change = StorageInfoChange {
+                class,
+                previous: *previous,
+                current,
+            };
+            *previous = current;
+            watcher.tx.send(change).is_ok()
+        });
+        if watchers.is_empty() {
+            self.active.store(false, Ordering::Release);
+        }
+    }
+}
@@ -10,15 +10,59 @@ use bincode::{deserialize, serialize_into};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::io::{self, Seek};
 
-static MAGIC: &[u8] = b"HEAFSv3\0\n";
+static MAGIC: &[u8] = b"HEAFSv4\0\n";
+static MAGIC_V3: &[u8] = b"HEAFSv3\0\n";
+
+/// The on-disk format version written by this version of the storage stack.
+pub const FORMAT_VERSION: u32 = 11;
+/// The first format version whose object pointers record the system storage
+/// preference of their objects.
+pub(crate) const POINTER_PREFERENCE_VERSION: u32 = 5;
+/// The first format version which addresses more than 1024 disks per storage
+/// class, see [DiskOffset](crate::storage_pool::DiskOffset).
+pub(crate) const WIDE_DISK_ID_VERSION: u32 = 6;
+/// The first format version which packs small nodes of the root tree into
+/// shared blocks.
+pub(crate) const SLAB_VERSION: u32 = 7;
+/// The first format version which writes second copies of the upper nodes of
+/// trees, see [ObjectPointer::ditto_offset](crate::data_management::ObjectPointer::ditto_offset).
+pub(crate) const DITTO_VERSION: u32 = 8;
+/// The first format version whose internal nodes may buffer range tombstones,
+/// older pools delete the keys of a range one by one.
+pub(crate) const RANGE_TOMBSTONE_VERSION: u32 = 9;
+/// The first format version which may store conditional messages, see
+/// [DefaultMessageAction::insert_if_absent_msg](crate::tree::DefaultMessageAction::insert_if_absent_msg).
+pub(crate) const CONDITIONAL_MESSAGE_VERSION: u32 = 10;
+/// The first format version whose object pointers record the format version
+/// their objects have been written with, see
+/// [ObjectPointer::format_version](crate::data_management::ObjectPointer::format_version).
+pub(crate) const VERSIONED_POINTER_VERSION: u32 = 11;
+/// The oldest on-disk format version which can still be opened. Pools of an
+/// older version than [FORMAT_VERSION] keep their version until they are
+/// upgraded explicitly with [super::Database::upgrade].
+pub const MIN_FORMAT_VERSION: u32 = 3;
 
 /// A superblock contains the location of the root tree,
 /// and is read during database initialisation.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Superblock<P> {
     magic: [u8; 9],
+    pub(crate) format_version: u32,
     pub(crate) root_ptr: P,
     pub(crate) tiers: [StorageInfo; NUM_STORAGE_CLASSES],
+    /// The host which has written this superblock, zero if the pool has been
+    /// exported or has been written by a version which did not record it.
+    /// Older versions ignore it, as it is stored in the padding of the block.
+    pub(crate) host_id: u64,
+}
+
+/// The superblock layout of format version 3, which did not yet contain an
+/// explicit format version.
+#[derive(Serialize, Deserialize)]
+struct SuperblockV3<P> {
+    magic: [u8; 9],
+    root_ptr: P,
+    tiers: [StorageInfo; NUM_STORAGE_CLASSES],
 }
 
 fn checksum(b: &[u8]) -> DbChecksum {
@@ -30,9 +74,13 @@ fn checksum(b: &[u8]) -> DbChecksum {
 impl<P: DeserializeOwned> Superblock<P> {
     /// Interpret a byte slice as a database superblock.
     /// Errors if the supposed superblock doesn't begin with
-    /// a specific version byte sequence (currently `b"HEAFSv3\0\n", but
+    /// a specific version byte sequence (currently `b"HEAFSv4\0\n", but
     /// this sequence is explicitly not part of the stability guarantees),
     /// or the contained checksum doesn't match the actual checksum of the superblock.
+    ///
+    /// Superblocks of format version 3 are converted on the fly, superblocks
+    /// of an unknown format version are rejected with
+    /// [Error::UnsupportedFormatVersion].
     pub fn unpack(b: &[u8]) -> Result<Superblock<P>> {
         let checksum_size = DbChecksum::static_size();
         let correct_checksum = checksum(&b[..b.len() - checksum_size]);
@@ -40,36 +88,90 @@ impl<P: DeserializeOwned> Superblock<P> {
         if correct_checksum != actual_checksum {
             return Err(Error::InvalidSuperblock);
         }
-        let this: Self = deserialize(b)?;
-        if this.magic != MAGIC {
+        let magic = &b[..MAGIC.len()];
+        if magic == MAGIC_V3 {
+            let legacy: SuperblockV3<P> = deserialize(b)?;
+            return Ok(Superblock {
+                magic: legacy.magic,
+                format_version: 3,
+                root_ptr: legacy.root_ptr,
+                tiers: legacy.tiers,
+                host_id: 0,
+            });
+        }
+        if magic != MAGIC {
             return Err(Error::InvalidSuperblock);
         }
+        let this: Self = deserialize(b)?;
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&this.format_version) {
+            return Err(Error::UnsupportedFormatVersion(this.format_version));
+        }
         Ok(this)
     }
+
+    /// Returns the on-disk format version of the pool.
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Returns the id of the host which has written this superblock, see
+    /// [DatabaseConfiguration::host_id](super::DatabaseConfiguration::host_id).
+    pub fn host_id(&self) -> u64 {
+        self.host_id
+    }
 }
 
 impl Superblock<super::ObjectPointer> {
     /// Try to find a superblock among the first two blocks
     /// of each top-level vdev, returning the newest one if multiple are found.
+    ///
+    /// Fails if any superblock has been written by a newer, incompatible
+    /// version, as the pool must not be mistaken for an empty one.
     pub fn fetch_superblocks<S: StoragePoolLayer>(
         pool: &S,
     ) -> Result<Option<Superblock<super::ObjectPointer>>> {
+        Ok(Self::fetch_all_superblocks(pool)?.into_iter().next())
+    }
+
+    /// Returns all valid superblocks, ordered from the newest to the oldest
+    /// generation.  Superblocks of the same generation are only returned
+    /// once.
+    ///
+    /// Superblocks are written to alternating locations, so older entries
+    /// refer to the roots of preceding syncs, which can be used if the
+    /// objects of the newest sync have not been written completely.
+    pub fn fetch_all_superblocks<S: StoragePoolLayer>(
+        pool: &S,
+    ) -> Result<Vec<Superblock<super::ObjectPointer>>> {
         let v1 = pool.read_raw(Block(1), Block(0))?;
         let v2 = pool.read_raw(Block(1), Block(1))?;
-        Ok(v1
-            .into_iter()
-            .chain(v2)
-            .filter_map(|sb_data| Self::unpack(&sb_data).ok())
-            .max_by_key(|sb| sb.root_ptr.generation()))
+        let mut superblocks: Vec<Self> = Vec::new();
+        for sb_data in v1.into_iter().chain(v2) {
+            let sb = match Self::unpack(&sb_data) {
+                Ok(sb) => sb,
+                Err(e @ Error::UnsupportedFormatVersion(_)) => return Err(e),
+                Err(_) => continue,
+            };
+            if superblocks
+                .iter()
+                .all(|other| other.root_ptr.generation() != sb.root_ptr.generation())
+            {
+                superblocks.push(sb);
+            }
+        }
+        superblocks.sort_by_key(|sb| std::cmp::Reverse(sb.root_ptr.generation()));
+        Ok(superblocks)
     }
 
-    /// Write a superblock to each top-level vdev.
+    /// Write a superblock of the given format version to each top-level vdev.
     pub fn write_superblock<S: StoragePoolLayer>(
         pool: &S,
         ptr: &super::ObjectPointer,
         tiers: &[StorageInfo; NUM_STORAGE_CLASSES],
+        format_version: u32,
+        host_id: u64,
     ) -> Result<()> {
-        let sb_data = Self::pack(ptr, tiers)?;
+        let sb_data = Self::pack(ptr, tiers, format_version, host_id)?;
         let sb_offset = if ptr.generation().0 & 1 == 0 {
             Block(0)
         } else {
@@ -89,13 +191,28 @@ impl Superblock<super::ObjectPointer> {
 }
 
 impl<P: Serialize> Superblock<P> {
-    fn pack(p: &P, tiers: &[StorageInfo; NUM_STORAGE_CLASSES]) -> Result<Buf> {
+    fn pack(
+        p: &P,
+        tiers: &[StorageInfo; NUM_STORAGE_CLASSES],
+        format_version: u32,
+        host_id: u64,
+    ) -> Result<Buf> {
         let mut data = BufWrite::with_capacity(Block(1));
-        {
+        if format_version == 3 {
+            let mut this = SuperblockV3 {
+                magic: [0; 9],
+                root_ptr: p,
+                tiers: *tiers,
+            };
+            this.magic.copy_from_slice(MAGIC_V3);
+            serialize_into(&mut data, &this)?;
+        } else {
             let mut this = Superblock {
                 magic: [0; 9],
+                format_version,
                 root_ptr: p,
                 tiers: *tiers,
+                host_id,
             };
             this.magic.copy_from_slice(MAGIC);
             serialize_into(&mut data, &this)?;
@@ -107,3 +224,30 @@ impl<P: Serialize> Superblock<P> {
         Ok(data.into_buf())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpack_format_versions() {
+        let tiers = [StorageInfo {
+            free: Block(1),
+            total: Block(2),
+        }; NUM_STORAGE_CLASSES];
+        for version in MIN_FORMAT_VERSION..=FORMAT_VERSION {
+            let data = Superblock::pack(&42u64, &tiers, version, 7).unwrap();
+            let sb = Superblock::<u64>::unpack(&data).unwrap();
+            assert_eq!(sb.format_version(), version);
+            assert_eq!(sb.root_ptr, 42);
+            // Superblocks of version 3 have no room for a host id.
+            assert_eq!(sb.host_id(), if version == 3 { 0 } else { 7 });
+        }
+
+        let data = Superblock::pack(&42u64, &tiers, FORMAT_VERSION + 1, 7).unwrap();
+        assert!(matches!(
+            Superblock::<u64>::unpack(&data),
+            Err(Error::UnsupportedFormatVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+    }
+}
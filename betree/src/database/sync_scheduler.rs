@@ -0,0 +1,125 @@
+//! Coordination of syncs with the batches of a migration policy.
+//!
+//! A migration batch promotes and demotes many nodes and objects at once,
+//! which dirties their nodes.  If a sync interrupts a batch, the nodes touched
+//! so far are written out, only to be dirtied and written again by the rest of
+//! the batch.  To avoid this, syncs of a shared database, see
+//! [Database::sync_shared](super::Database::sync_shared), wait for a running
+//! batch to finish, and no new batch starts while a sync is waiting or
+//! running.  Each batch is therefore written out by a single sync.
+//!
+//! Syncs take precedence: a batch waits for all pending syncs, while a sync
+//! only waits for the one batch which is running already.
+
+use parking_lot::{Condvar, Mutex};
+
+#[derive(Debug, Default)]
+struct State {
+    /// Syncs which are waiting for a batch to finish, or running.
+    syncs: usize,
+    batch_running: bool,
+}
+
+/// Fences migration batches around the syncs of a database.
+#[derive(Debug, Default)]
+pub(crate) struct SyncScheduler {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl SyncScheduler {
+    /// Waits for a running migration batch to finish and holds off new ones
+    /// until the returned fence is dropped.
+    pub(crate) fn sync_fence(&self) -> SyncFence<'_> {
+        let mut state = self.state.lock();
+        state.syncs += 1;
+        while state.batch_running {
+            self.changed.wait(&mut state);
+        }
+        SyncFence(self)
+    }
+
+    /// Waits until no sync is pending and marks a migration batch as running
+    /// until the returned guard is dropped.
+    pub(crate) fn migration_batch(&self) -> MigrationBatch<'_> {
+        let mut state = self.state.lock();
+        while state.syncs > 0 || state.batch_running {
+            self.changed.wait(&mut state);
+        }
+        state.batch_running = true;
+        MigrationBatch(self)
+    }
+}
+
+/// A sync in progress, see [SyncScheduler::sync_fence].
+pub(crate) struct SyncFence<'a>(&'a SyncScheduler);
+
+impl Drop for SyncFence<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().syncs -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+/// A migration batch in progress, see [SyncScheduler::migration_batch].
+pub(crate) struct MigrationBatch<'a>(&'a SyncScheduler);
+
+impl Drop for MigrationBatch<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().batch_running = false;
+        self.0.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SyncScheduler;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn sync_waits_for_running_batch() {
+        let scheduler = Arc::new(SyncScheduler::default());
+        let batch_done = Arc::new(AtomicBool::new(false));
+        let batch = scheduler.migration_batch();
+
+        let sync = {
+            let scheduler = Arc::clone(&scheduler);
+            let batch_done = Arc::clone(&batch_done);
+            thread::spawn(move || {
+                let _fence = scheduler.sync_fence();
+                assert!(batch_done.load(Ordering::SeqCst));
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        batch_done.store(true, Ordering::SeqCst);
+        drop(batch);
+        sync.join().unwrap();
+    }
+
+    #[test]
+    fn batch_waits_for_pending_sync() {
+        let scheduler = Arc::new(SyncScheduler::default());
+        let sync_done = Arc::new(AtomicBool::new(false));
+        let fence = scheduler.sync_fence();
+
+        let batch = {
+            let scheduler = Arc::clone(&scheduler);
+            let sync_done = Arc::clone(&sync_done);
+            thread::spawn(move || {
+                let _batch = scheduler.migration_batch();
+                assert!(sync_done.load(Ordering::SeqCst));
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        sync_done.store(true, Ordering::SeqCst);
+        drop(fence);
+        batch.join().unwrap();
+    }
+}
@@ -1,15 +1,23 @@
 use super::Database;
+use crate::clock::Clock;
 use parking_lot::RwLock;
-use std::{sync::Arc, thread, time::Duration};
+use std::{sync::Weak, time::Duration};
 
-pub fn sync_timer(timeout_ms: u64, db: Arc<RwLock<Database>>) {
+/// Syncs the database periodically until it is dropped, which releases its
+/// vdevs for the next opener.
+pub fn sync_timer(timeout_ms: u64, clock: Clock, db: Weak<RwLock<Database>>) {
+    let _guard = clock.register();
     let timeout = Duration::from_millis(timeout_ms);
 
     loop {
-        thread::sleep(timeout);
+        clock.sleep(timeout);
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => return,
+        };
 
         log::debug!("syncing db");
-        if let Err(err) = db.write().sync() {
+        if let Err(err) = Database::sync_shared(&db) {
             log::error!("couldn't sync db: {}", err);
         }
     }
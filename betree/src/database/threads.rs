@@ -0,0 +1,65 @@
+//! Configuration and construction of the thread pool running background work
+//! of a [super::Database], like periodic syncing, migration policies and
+//! metrics reporting, and the scans of [super::Dataset::par_range].
+
+use futures::executor::ThreadPool;
+use serde::{Deserialize, Serialize};
+use std::{io, thread};
+
+/// Configuration of the internal threads of a database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ThreadConfiguration {
+    /// Prefix of the names of all background threads.
+    pub name_prefix: String,
+    /// Number of threads in the background pool. Each enabled background
+    /// service (periodic sync, migration policy, metrics) occupies one thread
+    /// permanently, the others scan the parts of
+    /// [super::Dataset::par_range] iterators.  The pool is never made smaller
+    /// than the number of enabled services plus one. Defaults to that number
+    /// plus [Self::scan_threads].
+    pub background_pool_size: Option<usize>,
+    /// Number of threads of the background pool which are not occupied by
+    /// services, unless [Self::background_pool_size] is given.  Defaults to
+    /// the number of available CPUs.
+    pub scan_threads: Option<usize>,
+}
+
+impl Default for ThreadConfiguration {
+    fn default() -> Self {
+        Self {
+            name_prefix: String::from("haura"),
+            background_pool_size: None,
+            scan_threads: None,
+        }
+    }
+}
+
+impl ThreadConfiguration {
+    /// Creates the background pool for the given number of long-running
+    /// services.
+    pub(crate) fn background_pool(&self, services: usize) -> io::Result<ThreadPool> {
+        // Scans must not wait for services, which never finish.
+        let min_size = services + 1;
+        let size = match self.background_pool_size {
+            Some(size) if size < min_size => {
+                warn!(
+                    "Background pool size {} is too small for {} services, using {} threads",
+                    size, services, min_size
+                );
+                min_size
+            }
+            Some(size) => size,
+            None => {
+                let scan_threads = self.scan_threads.unwrap_or_else(|| {
+                    thread::available_parallelism().map_or(1, |threads| threads.get())
+                });
+                services + scan_threads.max(1)
+            }
+        };
+        ThreadPool::builder()
+            .name_prefix(format!("{}-background-", self.name_prefix))
+            .pool_size(size)
+            .create()
+    }
+}
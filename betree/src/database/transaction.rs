@@ -0,0 +1,146 @@
+//! Optimistic transactions over one or more datasets.
+//!
+//! A [Transaction] buffers its writes and remembers the values it has read.
+//! On commit the read values are compared with the current ones while holding
+//! the commit lock of the database, which is also held by
+//! [Database::sync](super::Database::sync).  If none has changed, all writes
+//! are applied, so that a sync either contains all or none of them.  Should a
+//! write fail, the ones already applied are reverted to the values they
+//! replaced before the lock is released.
+//!
+//! The trees do not record in which generation a key has been written, so
+//! unlike a validation by generations, comparing the values can not detect a
+//! key which has been changed and changed back in the meantime.
+
+use super::{Database, Dataset, DatasetId, Error, Result};
+use crate::{
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    tree::DefaultMessageAction,
+    StoragePreference,
+};
+use parking_lot::Mutex;
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// An optimistic transaction, created by [Database::transaction].
+///
+/// Reads observe the writes of this transaction.  Conflicts are only detected
+/// on [Transaction::commit], which fails with [Error::TransactionConflict] if
+/// any value read by this transaction has changed since.  Only the values are
+/// compared, so changes which restore the value read go unnoticed.  Plain
+/// writes to a dataset do not take the commit lock, so they are only detected
+/// if they happen before the commit compares the values, and datasets which
+/// are also written outside of transactions are not isolated.  Dropping a
+/// transaction discards it.
+#[must_use]
+pub struct Transaction {
+    commit_lock: Arc<Mutex<()>>,
+    reads: HashMap<(DatasetId, CowBytes), (Dataset, Option<SlicedCowBytes>)>,
+    writes: BTreeMap<(DatasetId, CowBytes), (Dataset, Option<Vec<u8>>)>,
+}
+
+impl Database {
+    /// Starts a new optimistic transaction.
+    pub fn transaction(&self) -> Transaction {
+        Transaction {
+            commit_lock: Arc::clone(&self.commit_lock),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+}
+
+impl Transaction {
+    /// Returns the value for the given key in `ds`, including uncommitted
+    /// writes of this transaction.
+    pub fn get<K: Borrow<[u8]>>(&mut self, ds: &Dataset, key: K) -> Result<Option<SlicedCowBytes>> {
+        let key = CowBytes::from(key.borrow());
+        let id = (ds.id(), key);
+        if let Some((_, value)) = self.writes.get(&id) {
+            return Ok(value.as_deref().map(|v| CowBytes::from(v).into()));
+        }
+        if let Some((_, value)) = self.reads.get(&id) {
+            return Ok(value.clone());
+        }
+        let value = ds.get(&id.1[..])?;
+        self.reads.insert(id, (ds.clone(), value.clone()));
+        Ok(value)
+    }
+
+    /// Inserts the given key-value pair into `ds` on commit.
+    pub fn insert<K: Borrow<[u8]>>(&mut self, ds: &Dataset, key: K, data: &[u8]) {
+        let key = CowBytes::from(key.borrow());
+        self.writes
+            .insert((ds.id(), key), (ds.clone(), Some(data.to_vec())));
+    }
+
+    /// Deletes the given key from `ds` on commit.
+    pub fn delete<K: Borrow<[u8]>>(&mut self, ds: &Dataset, key: K) {
+        let key = CowBytes::from(key.borrow());
+        self.writes.insert((ds.id(), key), (ds.clone(), None));
+    }
+
+    /// Number of buffered writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Whether this transaction has no buffered writes.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Validates the values read by this transaction and applies all writes.
+    /// Returns [Error::TransactionConflict] without applying anything if a
+    /// read value has changed.  Writes which [Dataset::insert] would refuse,
+    /// e.g. with [Error::OutOfSpace], fail the commit before any is applied.
+    pub fn commit(self) -> Result<()> {
+        let _guard = self.commit_lock.lock();
+        for ((_, key), (ds, value)) in self.reads.iter() {
+            if ds.get(&key[..])? != *value {
+                return Err(Error::TransactionConflict);
+            }
+        }
+        for (ds, value) in self.writes.values() {
+            if let Some(data) = value {
+                ds.check_insert(data.len())?;
+            }
+        }
+        let mut previous = Vec::with_capacity(self.writes.len());
+        for (id, (ds, _)) in self.writes.iter() {
+            previous.push(match self.reads.get(id) {
+                Some((_, value)) => value.clone(),
+                None => ds.get(&id.1[..])?,
+            });
+        }
+        for (idx, ((_, key), (ds, value))) in self.writes.iter().enumerate() {
+            if let Err(e) = apply(ds, key, value.as_deref()) {
+                for (((_, key), (ds, _)), value) in
+                    self.writes.iter().zip(&previous).take(idx).rev()
+                {
+                    if let Err(e) = apply(ds, key, value.as_deref()) {
+                        error!("Reverting a write of a failed transaction failed: {e}");
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets `key` in `ds` to `value`, or deletes it.  The space has been checked
+/// before, deletions and reverted writes may use the reserve.
+fn apply(ds: &Dataset, key: &CowBytes, value: Option<&[u8]>) -> Result<()> {
+    match value {
+        Some(data) => ds.insert_msg_with_pref(
+            key.clone(),
+            DefaultMessageAction::insert_msg(data),
+            StoragePreference::NONE,
+        ),
+        None => ds.delete(key.clone()),
+    }
+}
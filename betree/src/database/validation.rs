@@ -0,0 +1,485 @@
+//! Validation of a [DatabaseConfiguration] before any device is opened, and of
+//! the [TreeConfiguration] of a dataset.
+
+use super::{AccessMode, DatabaseConfiguration};
+use crate::{
+    migration::MigrationPolicies,
+    storage_pool::{FailureDomainPolicy, LeafVdev, Vdev, MAX_DISKS_PER_CLASS, NUM_STORAGE_CLASSES},
+    tree::TreeConfiguration,
+};
+use itertools::Itertools;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// A single problem found in a [DatabaseConfiguration].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigurationProblem {
+    /// More tiers are configured than storage classes are available.
+    TooManyTiers(usize),
+    /// No tier contains any vdev.
+    NoVdevs,
+    /// A tier contains more top-level vdevs than disks can be addressed.
+    TooManyVdevs {
+        /// Index of the tier.
+        tier: usize,
+        /// Number of configured top-level vdevs.
+        found: usize,
+    },
+    /// The same file or device is used more than once.
+    DuplicatePath(PathBuf),
+    /// A file or device is also configured as output of the metrics or a
+    /// migration policy.
+    PathCollision(PathBuf),
+    /// A leaf vdev has a size of zero.
+    ZeroSizeVdev {
+        /// Index of the tier.
+        tier: usize,
+        /// Index of the top-level vdev in the tier.
+        vdev: usize,
+    },
+    /// A mirror or parity1 vdev has fewer leaves than it requires.
+    TooFewLeaves {
+        /// Index of the tier.
+        tier: usize,
+        /// Index of the top-level vdev in the tier.
+        vdev: usize,
+        /// Minimum number of leaves of this kind of vdev.
+        required: usize,
+        /// Number of configured leaves.
+        found: usize,
+    },
+    /// Multiple leaves of a redundant vdev share a failure domain while the
+    /// [FailureDomainPolicy] is `Refuse`.
+    SharedFailureDomain {
+        /// Index of the tier.
+        tier: usize,
+        /// Index of the top-level vdev in the tier.
+        vdev: usize,
+        /// The shared failure domain.
+        domain: String,
+    },
+    /// A storage class referred to by the named option does not exist.
+    InvalidStorageClass {
+        /// The option referring to the storage class.
+        option: &'static str,
+        /// The storage class.
+        class: u8,
+    },
+    /// A migration threshold is not within 0 and 1.
+    ThresholdOutOfRange {
+        /// The storage class of the threshold.
+        class: usize,
+        /// The configured threshold.
+        threshold: f32,
+    },
+    /// The named option must not be zero.
+    Zero(&'static str),
+    /// The named option writes to the pool, which is opened with
+    /// [AccessMode::ReadOnly].
+    WritesReadOnlyPool(&'static str),
+    /// The named option is not within the given bounds.
+    OutOfRange {
+        /// The option.
+        option: &'static str,
+        /// The configured value.
+        value: usize,
+        /// The smallest allowed value.
+        min: usize,
+        /// The largest allowed value.
+        max: usize,
+    },
+}
+
+impl fmt::Display for ConfigurationProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigurationProblem::TooManyTiers(n) => write!(
+                f,
+                "{n} tiers are configured, but at most {NUM_STORAGE_CLASSES} are supported"
+            ),
+            ConfigurationProblem::NoVdevs => write!(f, "no tier contains any vdev"),
+            ConfigurationProblem::TooManyVdevs { tier, found } => write!(
+                f,
+                "tier {tier} has {found} vdevs, but at most {MAX_DISKS_PER_CLASS} are supported"
+            ),
+            ConfigurationProblem::DuplicatePath(path) => {
+                write!(f, "{} is used by multiple vdevs", path.display())
+            }
+            ConfigurationProblem::PathCollision(path) => write!(
+                f,
+                "{} is used as a vdev and as an output file",
+                path.display()
+            ),
+            ConfigurationProblem::ZeroSizeVdev { tier, vdev } => {
+                write!(f, "vdev {vdev} of tier {tier} has a size of zero")
+            }
+            ConfigurationProblem::TooFewLeaves {
+                tier,
+                vdev,
+                required,
+                found,
+            } => write!(
+                f,
+                "vdev {vdev} of tier {tier} has {found} leaves, but requires at least {required}"
+            ),
+            ConfigurationProblem::SharedFailureDomain { tier, vdev, domain } => write!(
+                f,
+                "vdev {vdev} of tier {tier} has multiple leaves in failure domain {domain:?}"
+            ),
+            ConfigurationProblem::InvalidStorageClass { option, class } => write!(
+                f,
+                "{option} refers to storage class {class}, but only {NUM_STORAGE_CLASSES} exist"
+            ),
+            ConfigurationProblem::ThresholdOutOfRange { class, threshold } => write!(
+                f,
+                "migration threshold {threshold} of storage class {class} is not within 0 and 1"
+            ),
+            ConfigurationProblem::Zero(option) => write!(f, "{option} must not be zero"),
+            ConfigurationProblem::WritesReadOnlyPool(option) => {
+                write!(f, "{option} writes to the pool, which is opened read-only")
+            }
+            ConfigurationProblem::OutOfRange {
+                option,
+                value,
+                min,
+                max,
+            } => write!(
+                f,
+                "{option} is {value}, but has to be within {min} and {max}"
+            ),
+        }
+    }
+}
+
+/// The largest emergency reserve of a storage class in percent, so that at
+/// least half of each class remains usable.
+const MAX_SPACE_RESERVE_PERCENT: u8 = 50;
+
+impl DatabaseConfiguration {
+    /// Checks the whole configuration for problems which would otherwise only
+    /// surface while the storage pool is set up, and returns all of them at
+    /// once.
+    pub fn validate(&self) -> Vec<ConfigurationProblem> {
+        let mut problems = Vec::new();
+        let storage = &self.storage;
+
+        if storage.tiers.len() > NUM_STORAGE_CLASSES {
+            problems.push(ConfigurationProblem::TooManyTiers(storage.tiers.len()));
+        }
+        if storage
+            .tiers
+            .iter()
+            .all(|tier| tier.top_level_vdevs.is_empty())
+        {
+            problems.push(ConfigurationProblem::NoVdevs);
+        }
+        if storage.queue_depth_factor == 0 {
+            problems.push(ConfigurationProblem::Zero("queue_depth_factor"));
+        }
+        if self.cache_size == 0 {
+            problems.push(ConfigurationProblem::Zero("cache_size"));
+        }
+        if let Some(bounds) = &self.adaptive_cache {
+            if bounds.min_size == 0 {
+                problems.push(ConfigurationProblem::Zero("adaptive_cache.min_size"));
+            }
+            if bounds.max_size < bounds.min_size {
+                problems.push(ConfigurationProblem::OutOfRange {
+                    option: "adaptive_cache.max_size",
+                    value: bounds.max_size,
+                    min: bounds.min_size,
+                    max: usize::MAX,
+                });
+            }
+            if bounds.interval_ms == 0 {
+                problems.push(ConfigurationProblem::Zero("adaptive_cache.interval_ms"));
+            }
+        }
+        if self.object_gc_interval_ms == Some(0) {
+            problems.push(ConfigurationProblem::Zero("object_gc_interval_ms"));
+        }
+        if self.space_reserve_percent > MAX_SPACE_RESERVE_PERCENT {
+            problems.push(ConfigurationProblem::OutOfRange {
+                option: "space_reserve_percent",
+                value: self.space_reserve_percent as usize,
+                min: 0,
+                max: MAX_SPACE_RESERVE_PERCENT as usize,
+            });
+        }
+        if !(1..=100).contains(&self.dirty_watermark_percent) {
+            problems.push(ConfigurationProblem::OutOfRange {
+                option: "dirty_watermark_percent",
+                value: self.dirty_watermark_percent as usize,
+                min: 1,
+                max: 100,
+            });
+        }
+        if self.access_mode == AccessMode::ReadOnly {
+            if self.migration_policy.is_some() {
+                problems.push(ConfigurationProblem::WritesReadOnlyPool("migration_policy"));
+            }
+            if self.object_gc_interval_ms.is_some() {
+                problems.push(ConfigurationProblem::WritesReadOnlyPool(
+                    "object_gc_interval_ms",
+                ));
+            }
+        }
+
+        let mut paths = Vec::new();
+        for (tier_id, tier) in storage.tiers.iter().enumerate() {
+            if tier.top_level_vdevs.len() > MAX_DISKS_PER_CLASS {
+                problems.push(ConfigurationProblem::TooManyVdevs {
+                    tier: tier_id,
+                    found: tier.top_level_vdevs.len(),
+                });
+            }
+            for (vdev_id, vdev) in tier.top_level_vdevs.iter().enumerate() {
+                let (leaves, required) = match vdev {
+                    Vdev::Leaf(leaf) => (std::slice::from_ref(leaf), 1),
+                    Vdev::Mirror { mirror } => (&mirror[..], 1),
+                    Vdev::Parity1 { parity1 } => (&parity1[..], 3),
+                };
+                if leaves.len() < required {
+                    problems.push(ConfigurationProblem::TooFewLeaves {
+                        tier: tier_id,
+                        vdev: vdev_id,
+                        required,
+                        found: leaves.len(),
+                    });
+                }
+                for leaf in leaves {
+                    if leaf_is_empty(leaf) {
+                        problems.push(ConfigurationProblem::ZeroSizeVdev {
+                            tier: tier_id,
+                            vdev: vdev_id,
+                        });
+                    }
+                    paths.extend(leaf_paths(leaf));
+                }
+            }
+            if storage.failure_domain_policy == FailureDomainPolicy::Refuse {
+                for (vdev_id, domain) in tier.shared_failure_domains() {
+                    problems.push(ConfigurationProblem::SharedFailureDomain {
+                        tier: tier_id,
+                        vdev: vdev_id,
+                        domain,
+                    });
+                }
+            }
+        }
+        for path in paths.iter().duplicates() {
+            problems.push(ConfigurationProblem::DuplicatePath(path.to_path_buf()));
+        }
+        for output in self.output_paths() {
+            if paths.contains(&output) {
+                problems.push(ConfigurationProblem::PathCollision(output.to_path_buf()));
+            }
+        }
+
+        let classes = self
+            .alloc_strategy
+            .iter()
+            .flatten()
+            .map(|class| ("alloc_strategy", *class))
+            .chain(Some(("default_storage_class", self.default_storage_class)));
+        for (option, class) in classes {
+            if class as usize >= NUM_STORAGE_CLASSES {
+                problems.push(ConfigurationProblem::InvalidStorageClass { option, class });
+            }
+        }
+
+        let thresholds = match &self.migration_policy {
+            Some(MigrationPolicies::Lfu(config)) => Some(config.migration_threshold),
+            Some(MigrationPolicies::ReinforcementLearning(config)) => {
+                Some(config.migration_threshold)
+            }
+            None => None,
+        };
+        for (class, &threshold) in thresholds.iter().flatten().enumerate() {
+            if !(0.0..=1.0).contains(&threshold) {
+                problems.push(ConfigurationProblem::ThresholdOutOfRange { class, threshold });
+            }
+        }
+
+        problems
+    }
+
+    /// Files written by the metrics and migration policies.
+    fn output_paths(&self) -> Vec<&Path> {
+        let mut paths = Vec::new();
+        if let Some(metrics) = self.metrics.as_ref().filter(|m| m.enabled) {
+            paths.push(metrics.output_path.as_path());
+        }
+        match &self.migration_policy {
+            Some(MigrationPolicies::Lfu(config)) => {
+                let lfu = &config.policy_config;
+                paths.extend(lfu.path_state.as_deref());
+                paths.extend(lfu.path_delta.as_deref());
+            }
+            Some(MigrationPolicies::ReinforcementLearning(config)) => {
+                if let Some(rl) = &config.policy_config {
+                    paths.push(rl.path_state.as_path());
+                    paths.push(rl.path_delta.as_path());
+                }
+            }
+            None => {}
+        }
+        paths
+    }
+}
+
+/// The largest minimal fanout of a tree.  Internal nodes have to be split into
+/// two nodes of at least this fanout, which limits the size of their buffers.
+const MAX_MIN_FANOUT: usize = 64;
+
+impl TreeConfiguration {
+    /// Checks the configuration for values which would prevent the tree from
+    /// flushing or splitting its internal nodes.
+    pub fn validate(&self) -> Vec<ConfigurationProblem> {
+        let mut problems = Vec::new();
+        if !(2..=MAX_MIN_FANOUT).contains(&self.min_fanout) {
+            problems.push(ConfigurationProblem::OutOfRange {
+                option: "min_fanout",
+                value: self.min_fanout,
+                min: 2,
+                max: MAX_MIN_FANOUT,
+            });
+        }
+        let max_flush_size = TreeConfiguration::max_flush_size(self.min_fanout);
+        if !(1..=max_flush_size).contains(&self.min_flush_size) {
+            problems.push(ConfigurationProblem::OutOfRange {
+                option: "min_flush_size",
+                value: self.min_flush_size,
+                min: 1,
+                max: max_flush_size,
+            });
+        }
+        problems
+    }
+}
+
+/// All files used by a leaf vdev.
+fn leaf_paths(leaf: &LeafVdev) -> Vec<&Path> {
+    match leaf {
+        LeafVdev::File(path) | LeafVdev::FileWithOpts { path, .. } => vec![path.as_path()],
+        LeafVdev::Memory { .. } => vec![],
+        LeafVdev::MemoryWithOpts { persist, .. } => persist.iter().map(PathBuf::as_path).collect(),
+        #[cfg(feature = "nvm")]
+        LeafVdev::PMemFile { path, .. } => vec![path.as_path()],
+    }
+}
+
+/// Whether a leaf vdev is known to have a size of zero.  Files which do not
+/// exist yet are not considered empty, as they may be created later.
+fn leaf_is_empty(leaf: &LeafVdev) -> bool {
+    match leaf {
+        LeafVdev::File(path) | LeafVdev::FileWithOpts { path, .. } => std::fs::metadata(path)
+            .map(|meta| meta.is_file() && meta.len() == 0)
+            .unwrap_or(false),
+        LeafVdev::Memory { mem } | LeafVdev::MemoryWithOpts { mem, .. } => *mem == 0,
+        #[cfg(feature = "nvm")]
+        LeafVdev::PMemFile { len, .. } => *len == 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage_pool::TierConfiguration;
+
+    fn memory(mem: usize) -> Vdev {
+        Vdev::Leaf(LeafVdev::Memory { mem })
+    }
+
+    #[test]
+    fn default_with_vdev_is_valid() {
+        let config = DatabaseConfiguration {
+            storage: crate::storage_pool::StoragePoolConfiguration {
+                tiers: vec![TierConfiguration::new(vec![memory(1024 * 1024)])],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), vec![]);
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let file = || LeafVdev::File("/dev/sda".into());
+        let config = DatabaseConfiguration {
+            storage: crate::storage_pool::StoragePoolConfiguration {
+                tiers: vec![TierConfiguration::new(vec![
+                    memory(0),
+                    Vdev::Parity1 {
+                        parity1: vec![file(), file()],
+                    },
+                ])],
+                ..Default::default()
+            },
+            default_storage_class: 7,
+            cache_size: 0,
+            space_reserve_percent: 80,
+            ..Default::default()
+        };
+        let problems = config.validate();
+        assert_eq!(
+            problems,
+            vec![
+                ConfigurationProblem::Zero("cache_size"),
+                ConfigurationProblem::OutOfRange {
+                    option: "space_reserve_percent",
+                    value: 80,
+                    min: 0,
+                    max: 50
+                },
+                ConfigurationProblem::ZeroSizeVdev { tier: 0, vdev: 0 },
+                ConfigurationProblem::TooFewLeaves {
+                    tier: 0,
+                    vdev: 1,
+                    required: 3,
+                    found: 2
+                },
+                ConfigurationProblem::DuplicatePath("/dev/sda".into()),
+                ConfigurationProblem::InvalidStorageClass {
+                    option: "default_storage_class",
+                    class: 7
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tree_configuration() {
+        assert_eq!(TreeConfiguration::default().validate(), vec![]);
+        let write_heavy = TreeConfiguration {
+            min_fanout: 2,
+            min_flush_size: 512 * 1024,
+            access_aware_splits: true,
+        };
+        assert_eq!(write_heavy.validate(), vec![]);
+        let config = TreeConfiguration {
+            min_fanout: 1,
+            min_flush_size: 0,
+            access_aware_splits: false,
+        };
+        assert_eq!(
+            config.validate(),
+            vec![
+                ConfigurationProblem::OutOfRange {
+                    option: "min_fanout",
+                    value: 1,
+                    min: 2,
+                    max: MAX_MIN_FANOUT
+                },
+                ConfigurationProblem::OutOfRange {
+                    option: "min_flush_size",
+                    value: 0,
+                    min: 1,
+                    max: TreeConfiguration::max_flush_size(1)
+                },
+            ]
+        );
+    }
+}
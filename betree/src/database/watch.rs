@@ -0,0 +1,91 @@
+//! Notifications about modifications of keys with a given prefix, see
+//! [Dataset::watch].
+
+use super::Dataset;
+use crate::cow_bytes::CowBytes;
+use crossbeam_channel::{Receiver, Sender};
+use parking_lot::Mutex;
+use std::{
+    ops::Bound,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A modification observed by a watch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// A message has been inserted for the given key.
+    Modified(CowBytes),
+    /// A range deletion has removed some keys with the watched prefix.
+    RangeDeleted,
+}
+
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Mutex<Vec<(Box<[u8]>, Sender<WatchEvent>)>>,
+    active: AtomicBool,
+}
+
+impl Watchers {
+    /// Whether modifications have to be reported.
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Receiver<WatchEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.watchers.lock().push((Box::from(prefix), tx));
+        self.active.store(true, Ordering::Release);
+        rx
+    }
+
+    fn notify_matching<F>(&self, matches: F, event: WatchEvent)
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut watchers = self.watchers.lock();
+        watchers.retain(|(prefix, tx)| !matches(prefix) || tx.send(event.clone()).is_ok());
+        if watchers.is_empty() {
+            self.active.store(false, Ordering::Release);
+        }
+    }
+
+    /// Notifies all watchers of a prefix of `key`.
+    pub(crate) fn notify(&self, key: &CowBytes) {
+        self.notify_matching(
+            |prefix| key.starts_with(prefix),
+            WatchEvent::Modified(key.clone()),
+        )
+    }
+
+    /// Notifies all watchers of a prefix which may have keys within the given
+    /// range.
+    pub(crate) fn notify_range(&self, start: &Bound<CowBytes>, end: &Bound<CowBytes>) {
+        self.notify_matching(
+            |prefix| {
+                let ends_before = match end {
+                    Bound::Included(end) => &end[..] < prefix,
+                    Bound::Excluded(end) => &end[..] <= prefix,
+                    Bound::Unbounded => false,
+                };
+                let starts_after = match start {
+                    Bound::Included(start) | Bound::Excluded(start) => {
+                        &start[..] > prefix && !start.starts_with(prefix)
+                    }
+                    Bound::Unbounded => false,
+                };
+                !ends_before && !starts_after
+            },
+            WatchEvent::RangeDeleted,
+        )
+    }
+}
+
+impl<Message> Dataset<Message> {
+    /// Returns a receiver which gets an event whenever a key starting with
+    /// `prefix` is modified through any handle of this dataset.  Events are
+    /// sent right after the modification has been applied to the tree, before
+    /// it is synced.  Dropping the receiver ends the watch.
+    pub fn watch(&self, prefix: &[u8]) -> Receiver<WatchEvent> {
+        self.watchers().watch(prefix)
+    }
+}
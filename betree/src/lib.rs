@@ -21,6 +21,7 @@ pub mod buffer;
 pub mod c_interface;
 pub mod cache;
 pub mod checksum;
+pub mod clock;
 pub mod compression;
 pub mod cow_bytes;
 pub mod data_management;
@@ -39,6 +40,9 @@ pub mod migration;
 #[cfg(feature = "init_env_logger")]
 pub mod env_logger;
 
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
 #[cfg(test)]
 mod arbitrary;
 
@@ -1,18 +1,28 @@
-//! A naive metrics system, logging newline-delimited JSON to a configurable file.
+//! A naive metrics system, periodically passing snapshots of the database
+//! state to a [MetricsSink].
+//!
+//! Databases configured with [MetricsConfiguration] write snapshots to a file,
+//! either as newline-delimited JSON or as CSV, see [MetricsFormat], or pass
+//! them to the [MetricsSink] of [MetricsConfiguration::sink].  Snapshots can
+//! also be taken on demand with [Database::metrics_snapshot](crate::Database::metrics_snapshot).
 
 use crate::{
-    data_management::{Dml, DmlWithHandler},
-    database::{RootDmu, StorageInfo},
+    clock::Clock,
+    data_management::{Dml, DmlWithHandler, DmlWithStorageHints, WriteBudgetInfo},
+    database::{AmplificationReport, RootDmu, StorageInfo},
     storage_pool::{StoragePoolLayer, NUM_STORAGE_CLASSES},
 };
+use futures::executor::ThreadPool;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    fs,
+    collections::BTreeMap,
+    fmt, fs,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
-    thread,
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 /// Configuration bundle of the [crate::metrics] module.
@@ -25,65 +35,272 @@ pub struct MetricsConfiguration {
     pub interval_ms: u32,
     /// The file to write reports to
     pub output_path: PathBuf,
+    /// The format reports are written in
+    #[serde(default)]
+    pub format: MetricsFormat,
+    /// When set, the output file is rotated once it would grow beyond this
+    /// many bytes, see [FileSink]
+    #[serde(default)]
+    pub rotate_size: Option<u64>,
+    /// The number of rotated files to keep, older ones are removed
+    #[serde(default)]
+    pub rotated_files: usize,
+    /// The sink to pass reports to instead of writing them to `output_path`.
+    /// It can not be serialized.
+    #[serde(skip)]
+    pub sink: Option<SharedSink>,
 }
 
-pub(crate) fn metrics_init<Config>(
-    cfg: &MetricsConfiguration,
-    dmu: Arc<RootDmu>,
-) -> io::Result<thread::JoinHandle<()>> {
-    let cfg = cfg.clone();
-
-    let file = fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(&cfg.output_path)?;
-
-    thread::Builder::new()
-        .name(String::from("metrics"))
-        .spawn(move || metrics_loop::<Config>(cfg, file, dmu))
+/// The file format of a [FileSink].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MetricsFormat {
+    /// One JSON object per line and snapshot.
+    #[default]
+    Jsonl,
+    /// One row per snapshot.  Nested values are flattened into columns named
+    /// by their path, e.g. `usage.0.free`.
+    Csv,
 }
 
+/// A snapshot of the state of a database.
 #[derive(Serialize)]
-struct Metrics {
-    epoch_ms: u128,
-    cache: <RootDmu as Dml>::CacheStats,
-    storage: <<RootDmu as Dml>::Spl as StoragePoolLayer>::Metrics,
-    usage: Vec<StorageInfo>,
+pub struct MetricsSnapshot {
+    /// Milliseconds since the unix epoch at which the snapshot was taken.
+    pub epoch_ms: u128,
+    /// Statistics of the node cache.
+    pub cache: <RootDmu as Dml>::CacheStats,
+    /// Statistics of all vdevs.
+    pub storage: <<RootDmu as Dml>::Spl as StoragePoolLayer>::Metrics,
+    /// Free and total blocks of each storage tier.
+    pub usage: Vec<StorageInfo>,
+    /// Read and write amplification.
+    pub amplification: AmplificationReport,
+    /// Bytes written to each storage tier on the current day.
+    pub write_budgets: Vec<WriteBudgetInfo>,
+    /// The number of nodes the migration policy wants to be moved to another
+    /// tier on their next write.
+    pub pending_migration_hints: usize,
 }
 
-fn metrics_loop<Config>(cfg: MetricsConfiguration, output: fs::File, dmu: Arc<RootDmu>) {
-    let mut output = io::BufWriter::new(output);
-    let sleep_duration = Duration::from_millis(cfg.interval_ms as u64);
-    loop {
-        log::info!("gathering metrics");
-        let now = Instant::now();
-
-        let spu = dmu.spl();
-
-        let metrics: Metrics = Metrics {
+impl MetricsSnapshot {
+    pub(crate) fn take(dmu: &RootDmu) -> Self {
+        MetricsSnapshot {
             epoch_ms: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .map(|d| d.as_millis())
                 .unwrap_or(u128::MAX),
             cache: dmu.cache_stats(),
-            storage: spu.metrics(),
+            storage: dmu.spl().metrics(),
             // We can be sure that the following is always correct
             usage: (0..NUM_STORAGE_CLASSES as u8)
                 .map(|tier| dmu.handler().free_space_tier(tier).unwrap())
                 .collect(),
-        };
+            amplification: dmu.handler().io_accounting.report(),
+            write_budgets: (0..dmu.spl().storage_class_count())
+                .map(|tier| dmu.write_budget_info(tier))
+                .collect(),
+            pending_migration_hints: dmu.storage_hints().lock().len(),
+        }
+    }
+}
+
+/// A destination of metrics snapshots.
+pub trait MetricsSink: Send {
+    /// Records a single snapshot.
+    fn record(&mut self, snapshot: &MetricsSnapshot) -> io::Result<()>;
+}
+
+/// A [MetricsSink] which can be put into a [MetricsConfiguration].
+#[derive(Clone)]
+pub struct SharedSink(Arc<Mutex<dyn MetricsSink>>);
 
-        let mut res = || -> io::Result<()> {
-            serde_json::to_writer(&mut output, &metrics)?;
-            writeln!(&mut output)?;
-            output.flush()?;
-            Ok(())
-        };
+impl SharedSink {
+    /// Shares `sink` with the databases built from a configuration.
+    pub fn new(sink: impl MetricsSink + 'static) -> Self {
+        SharedSink(Arc::new(Mutex::new(sink)))
+    }
+}
+
+impl fmt::Debug for SharedSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedSink")
+    }
+}
+
+impl MetricsSink for SharedSink {
+    fn record(&mut self, snapshot: &MetricsSnapshot) -> io::Result<()> {
+        self.0.lock().record(snapshot)
+    }
+}
+
+/// Writes snapshots to a file in a [MetricsFormat].
+///
+/// With rotation enabled, a file which would grow beyond the configured size
+/// is renamed to `<path>.1`, previously rotated files are shifted to
+/// `<path>.2` and so on, and a new file is started.  Each CSV file starts with
+/// a header whose columns are taken from the first snapshot written to it, so
+/// values which only appear later, like those of datasets created afterwards,
+/// are left out until the next rotation.
+pub struct FileSink {
+    path: PathBuf,
+    format: MetricsFormat,
+    rotate_size: Option<u64>,
+    rotated_files: usize,
+    output: io::BufWriter<fs::File>,
+    written: u64,
+    columns: Option<Vec<String>>,
+}
+
+impl FileSink {
+    /// Creates the output file of `cfg`, replacing an existing one.
+    pub fn new(cfg: &MetricsConfiguration) -> io::Result<Self> {
+        Ok(FileSink {
+            path: cfg.output_path.clone(),
+            format: cfg.format,
+            rotate_size: cfg.rotate_size,
+            rotated_files: cfg.rotated_files,
+            output: io::BufWriter::new(fs::File::create(&cfg.output_path)?),
+            written: 0,
+            columns: None,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.output.flush()?;
+        if self.rotated_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for idx in (1..self.rotated_files).rev() {
+                let from = rotated_path(&self.path, idx);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, idx + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.output = io::BufWriter::new(fs::File::create(&self.path)?);
+        self.written = 0;
+        self.columns = None;
+        Ok(())
+    }
+
+    fn encode(&mut self, snapshot: &MetricsSnapshot) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self.format {
+            MetricsFormat::Jsonl => {
+                serde_json::to_writer(&mut buf, snapshot)?;
+                writeln!(&mut buf)?;
+            }
+            MetricsFormat::Csv => {
+                let mut values = BTreeMap::new();
+                flatten(String::new(), serde_json::to_value(snapshot)?, &mut values);
+                let columns = self.columns.get_or_insert_with(|| {
+                    let columns: Vec<String> = values.keys().cloned().collect();
+                    writeln!(&mut buf, "{}", columns.join(",")).unwrap();
+                    columns
+                });
+                let row: Vec<&str> = columns
+                    .iter()
+                    .map(|column| values.get(column).map_or("", String::as_str))
+                    .collect();
+                writeln!(&mut buf, "{}", row.join(","))?;
+            }
+        }
+        Ok(buf)
+    }
+}
+
+impl MetricsSink for FileSink {
+    fn record(&mut self, snapshot: &MetricsSnapshot) -> io::Result<()> {
+        let mut buf = self.encode(snapshot)?;
+        if let Some(max) = self.rotate_size {
+            if self.written > 0 && self.written + buf.len() as u64 > max {
+                self.rotate()?;
+                // Starts the new file with a header.
+                buf = self.encode(snapshot)?;
+            }
+        }
+        self.output.write_all(&buf)?;
+        self.output.flush()?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, idx: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{idx}"));
+    PathBuf::from(name)
+}
+
+/// Collects the scalar values of `value` by their dot-separated path.
+fn flatten(path: String, value: Value, out: &mut BTreeMap<String, String>) {
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten(child(&key), value, out);
+            }
+        }
+        Value::Array(values) => {
+            for (idx, value) in values.into_iter().enumerate() {
+                flatten(child(&idx.to_string()), value, out);
+            }
+        }
+        Value::Null => {
+            out.insert(path, String::new());
+        }
+        Value::String(s) if s.contains([',', '"', '\n']) => {
+            out.insert(path, format!("\"{}\"", s.replace('"', "\"\"")));
+        }
+        Value::String(s) => {
+            out.insert(path, s);
+        }
+        other => {
+            out.insert(path, other.to_string());
+        }
+    }
+}
+
+pub(crate) fn metrics_init<Config>(
+    cfg: &MetricsConfiguration,
+    dmu: Arc<RootDmu>,
+    clock: Clock,
+    pool: &ThreadPool,
+) -> io::Result<()> {
+    let sink: Box<dyn MetricsSink> = match &cfg.sink {
+        Some(sink) => Box::new(sink.clone()),
+        None => Box::new(FileSink::new(cfg)?),
+    };
+    let interval_ms = cfg.interval_ms;
+
+    pool.spawn_ok(async move { metrics_loop::<Config>(interval_ms, sink, dmu, clock) });
+    Ok(())
+}
+
+fn metrics_loop<Config>(
+    interval_ms: u32,
+    mut sink: Box<dyn MetricsSink>,
+    dmu: Arc<RootDmu>,
+    clock: Clock,
+) {
+    let _guard = clock.register();
+    let sleep_duration = Duration::from_millis(interval_ms as u64);
+    loop {
+        log::info!("gathering metrics");
+        let start = clock.now();
 
-        if let Err(e) = res() {
+        if let Err(e) = sink.record(&MetricsSnapshot::take(&dmu)) {
             log::error!("metrics: {}", e);
         }
 
-        thread::sleep(sleep_duration.saturating_sub(now.elapsed()));
+        let elapsed = clock.now().duration_since(start).unwrap_or_default();
+        clock.sleep(sleep_duration.saturating_sub(elapsed));
     }
 }
@@ -9,11 +9,12 @@ use std::{
 };
 
 use crate::{
+    clock::Clock,
     cow_bytes::CowBytes,
-    data_management::{DmlWithStorageHints, HasStoragePreference},
+    data_management::{DmlWithStorageHints, HasStoragePreference, HintSource, StorageHints},
     database::RootDmu,
     object::{ObjectStore, ObjectStoreId},
-    storage_pool::NUM_STORAGE_CLASSES,
+    storage_pool::{DiskOffset, NUM_STORAGE_CLASSES},
     tree::PivotKey,
     vdev::Block,
     Database, StoragePreference,
@@ -22,24 +23,28 @@ use crate::{
 use super::{
     errors::{Error, Result},
     reinforcment_learning::open_file_buf_write,
-    DatabaseMsg, DmlMsg, GlobalObjectId, MigrationConfig,
+    DatabaseMsg, DmlMsg, GlobalObjectId, MigrationConfig, MigrationThresholds,
 };
 
 /// Implementation of Least Frequently Used
 pub struct Lfu {
     nodes: [LfuCache<PivotKey, Block<u32>>; NUM_STORAGE_CLASSES],
+    // The last known location of each node, to request replicas of.
+    node_offsets: HashMap<PivotKey, DiskOffset>,
     dml_rx: Receiver<DmlMsg>,
     db_rx: Receiver<DatabaseMsg>,
     db: Arc<RwLock<Database>>,
     dmu: Arc<RootDmu>,
     config: MigrationConfig<LfuConfig>,
+    thresholds: Arc<MigrationThresholds>,
     // Store open object stores to move inactive objects within.
     object_stores: HashMap<ObjectStoreId, Option<ObjectStore>>,
     objects: [LfuCache<GlobalObjectId, (CowBytes, Block<u64>)>; NUM_STORAGE_CLASSES],
     default_storage_class: StoragePreference,
-    /// HashMap accessible by the DML, resolution is not guaranteed but always
+    /// Hints accessible by the DML, resolution is not guaranteed but always
     /// used when a object is written.
-    storage_hint_dml: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
+    storage_hint_dml: Arc<Mutex<StorageHints>>,
+    clock: Clock,
 }
 
 /// Least frequently used (LFU) specific configuration details.
@@ -83,6 +88,14 @@ pub enum LfuMode {
     /// data needs to be migrated downwards for longer term storage and ranges
     /// of objects should be upgraded to optimize for latency.
     Both,
+    /// Keep read-only replicas of the most frequently used nodes of the slower
+    /// tiers on the fastest tier, while the nodes themselves stay where they
+    /// are.  Reads of these nodes are served from their replicas, which are
+    /// dropped when the nodes are modified.  When the fastest tier fills up
+    /// beyond its threshold, the oldest replicas are dropped instead of
+    /// demoting any data.  Replicas only exist in memory, see
+    /// [Database::drop_replicas](crate::Database::drop_replicas).
+    Replicate,
 }
 
 impl Default for LfuConfig {
@@ -123,16 +136,21 @@ impl<'lfu> Lfu {
                         }
                     }
 
+                    self.node_offsets
+                        .insert(info.pivot_key.clone(), info.offset);
                     *self.nodes[info.offset.storage_class() as usize]
                         .entry(info.pivot_key)
                         .or_insert(info.size) = info.size;
                 }
                 DmlMsg::Fetch(info) => {
+                    self.node_offsets
+                        .insert(info.pivot_key.clone(), info.offset);
                     *self.nodes[info.offset.storage_class() as usize]
                         .entry(info.pivot_key)
                         .or_insert(info.size) = info.size;
                 }
                 DmlMsg::Remove(info) => {
+                    self.node_offsets.remove(&info.pivot_key);
                     self.nodes[info.offset.storage_class() as usize].remove(&info.pivot_key);
                 }
             }
@@ -200,21 +218,26 @@ impl<'lfu> Lfu {
         db_rx: Receiver<DatabaseMsg>,
         db: Arc<RwLock<Database>>,
         config: MigrationConfig<LfuConfig>,
-        storage_hint_dml: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
+        storage_hint_dml: Arc<Mutex<StorageHints>>,
+        thresholds: Arc<MigrationThresholds>,
+        clock: Clock,
     ) -> Self {
         let dmu = Arc::clone(db.read().root_tree.dmu());
         let default_storage_class = dmu.default_storage_class();
         Self {
             nodes: [(); NUM_STORAGE_CLASSES].map(|_| LfuCache::unbounded()),
+            node_offsets: Default::default(),
             dml_rx,
             db_rx,
             dmu,
             db,
             config,
+            thresholds,
             storage_hint_dml,
             object_stores: Default::default(),
             objects: [(); NUM_STORAGE_CLASSES].map(|_| LfuCache::unbounded()),
             default_storage_class,
+            clock,
         }
     }
 
@@ -308,7 +331,12 @@ impl super::MigrationPolicy for Lfu {
                         };
                         if up_freq < freq || !tight_space {
                             // MOVE DATA UPWARDS
-                            self.storage_hint_dml.lock().insert(key.clone(), target);
+                            self.storage_hint_dml.lock().insert(
+                                key.clone(),
+                                target,
+                                HintSource::Policy,
+                                self.dmu.handler().current_generation(),
+                            );
                             moved += lower_size.as_u64();
 
                             // In case enough data has been moved; rate limited.
@@ -325,6 +353,35 @@ impl super::MigrationPolicy for Lfu {
                     break;
                 }
             }
+            LfuMode::Replicate => {
+                let fastest_full = self.dmu.handler().free_space_tier(0).map_or(true, |info| {
+                    info.percent_full() >= self.config.migration_threshold[0]
+                });
+                if fastest_full {
+                    return Ok(moved);
+                }
+                // The nodes keep their place in the cache of their tier, as
+                // replicas do not move them.
+                let mut visited = Vec::new();
+                while moved < desired
+                    && (visited.len() as u32) < self.config.policy_config.promote_num
+                {
+                    let (key, size, freq) =
+                        match self.nodes[storage_tier as usize].pop_mfu_key_value_frequency() {
+                            Some(entry) => entry,
+                            None => break,
+                        };
+                    if let Some(&offset) = self.node_offsets.get(&key) {
+                        if self.dmu.request_replica(offset) {
+                            moved += size.as_u64();
+                        }
+                    }
+                    visited.push((key, size, freq));
+                }
+                for (key, size, freq) in visited {
+                    self.nodes[storage_tier as usize].insert_with_frequency(key, size, freq);
+                }
+            }
             LfuMode::Both => unimplemented!(),
         }
 
@@ -385,6 +442,11 @@ impl super::MigrationPolicy for Lfu {
                     }
                 }
             }
+            LfuMode::Replicate => {
+                if storage_tier == 0 {
+                    moved = self.dmu.drop_replicas(desired);
+                }
+            }
             LfuMode::Both => unimplemented!(),
         }
 
@@ -399,7 +461,12 @@ impl super::MigrationPolicy for Lfu {
         self.config.clone().erased()
     }
 
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
     fn update(&mut self) -> Result<()> {
+        self.config.migration_threshold = self.thresholds.get();
         self.update_dml()?;
         self.update_db()
     }
@@ -79,11 +79,19 @@ pub(crate) use msg::*;
 use parking_lot::{Mutex, RwLock};
 pub use reinforcment_learning::RlConfig;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
 
 use crate::{
-    data_management::DmlWithHandler, database::RootDmu, storage_pool::NUM_STORAGE_CLASSES,
-    tree::PivotKey, vdev::Block, Database, StoragePreference,
+    clock::Clock,
+    compression::CompressionConfiguration,
+    data_management::{DmlWithHandler, StorageHints},
+    database::RootDmu,
+    storage_pool::NUM_STORAGE_CLASSES,
+    vdev::Block,
+    Database,
 };
 
 use self::{lfu::Lfu, reinforcment_learning::ZhangHellanderToor};
@@ -131,19 +139,73 @@ impl MigrationPolicies {
         dml_rx: Receiver<DmlMsg>,
         db_rx: Receiver<DatabaseMsg>,
         db: Arc<RwLock<Database>>,
-        storage_hint_sink: Arc<Mutex<HashMap<PivotKey, StoragePreference>>>,
+        storage_hint_sink: Arc<Mutex<StorageHints>>,
+        thresholds: Arc<MigrationThresholds>,
+        clock: Clock,
     ) -> Box<dyn MigrationPolicy> {
         match self {
-            MigrationPolicies::Lfu(config) => {
-                Box::new(Lfu::build(dml_rx, db_rx, db, config, storage_hint_sink))
-            }
+            MigrationPolicies::Lfu(config) => Box::new(Lfu::build(
+                dml_rx,
+                db_rx,
+                db,
+                config,
+                storage_hint_sink,
+                thresholds,
+                clock,
+            )),
+            MigrationPolicies::ReinforcementLearning(config) => Box::new(
+                ZhangHellanderToor::build(dml_rx, db_rx, db, config, thresholds, clock),
+            ),
+        }
+    }
+
+    /// The configured migration thresholds of the policy.
+    pub(crate) fn migration_threshold(&self) -> [f32; NUM_STORAGE_CLASSES] {
+        match self {
+            MigrationPolicies::Lfu(config) => config.migration_threshold,
+            MigrationPolicies::ReinforcementLearning(config) => config.migration_threshold,
+        }
+    }
+
+    /// The compression configured for the slowest storage tier.
+    pub(crate) fn cold_compression(&self) -> Option<CompressionConfiguration> {
+        match self {
+            MigrationPolicies::Lfu(config) => config.cold_compression,
+            MigrationPolicies::ReinforcementLearning(config) => config.cold_compression,
+        }
+    }
+
+    /// Updates the configured migration threshold of `class`.
+    pub(crate) fn set_migration_threshold(&mut self, class: usize, threshold: f32) {
+        match self {
+            MigrationPolicies::Lfu(config) => config.migration_threshold[class] = threshold,
             MigrationPolicies::ReinforcementLearning(config) => {
-                Box::new(ZhangHellanderToor::build(dml_rx, db_rx, db, config))
+                config.migration_threshold[class] = threshold
             }
         }
     }
 }
 
+/// Migration thresholds shared between a [Database] and its running policy,
+/// so that they can be changed without restarting the policy.  Policies pick
+/// up changes at the start of their next update.
+#[derive(Debug)]
+pub(crate) struct MigrationThresholds([AtomicU32; NUM_STORAGE_CLASSES]);
+
+impl MigrationThresholds {
+    pub(crate) fn new(thresholds: [f32; NUM_STORAGE_CLASSES]) -> Self {
+        Self(thresholds.map(|t| AtomicU32::new(t.to_bits())))
+    }
+
+    pub(crate) fn get(&self) -> [f32; NUM_STORAGE_CLASSES] {
+        std::array::from_fn(|class| f32::from_bits(self.0[class].load(Ordering::Relaxed)))
+    }
+
+    pub(crate) fn set(&self, class: usize, threshold: f32) {
+        self.0[class].store(threshold.to_bits(), Ordering::Relaxed);
+    }
+}
+
 use std::time::Duration;
 
 /// Configuration type for [MigrationPolicies]
@@ -155,6 +217,13 @@ pub struct MigrationConfig<Config> {
     pub migration_threshold: [f32; NUM_STORAGE_CLASSES],
     /// Duration between consumption of operational messages. Enlarging this leads to greater memory usage, but reduces ongoing computational load.
     pub update_period: Duration,
+    /// Compression used for data written to the slowest storage tier, e.g.
+    /// when it is demoted there, instead of the default compression of the
+    /// database.  A stronger compression trades CPU time during migrations
+    /// for space on the largest tier.  Data which is written to the slowest
+    /// tier directly is compressed the same way.
+    #[serde(default)]
+    pub cold_compression: Option<CompressionConfiguration>,
     /// Policy dependent configuration.
     pub policy_config: Config,
 }
@@ -168,6 +237,7 @@ impl<Config> MigrationConfig<Config> {
             grace_period: self.grace_period,
             migration_threshold: self.migration_threshold,
             update_period: self.update_period,
+            cold_compression: self.cold_compression,
         }
     }
 }
@@ -178,6 +248,7 @@ impl<Config: Default> Default for MigrationConfig<Config> {
             grace_period: Duration::from_secs(300),
             migration_threshold: [0.95; NUM_STORAGE_CLASSES],
             update_period: Duration::from_secs(30),
+            cold_compression: None,
             policy_config: Default::default(),
         }
     }
@@ -219,17 +290,25 @@ pub(crate) trait MigrationPolicy {
     /// Return the cleaned configuration.
     fn config(&self) -> MigrationConfig<()>;
 
+    /// Return the clock on which grace and update periods are waited.
+    fn clock(&self) -> &Clock;
+
     /// The main loop of the migration policy.
     ///
     /// We provide a basic default implementation which may be used or discarded
-    /// if desired.
+    /// if desired.  The promotions and demotions of each iteration form one
+    /// batch, which is not interrupted by [Database::sync_shared].
     fn thread_loop(&mut self) -> Result<()> {
-        std::thread::sleep(self.config().grace_period);
+        self.clock().sleep(self.config().grace_period);
         loop {
             // PAUSE
-            std::thread::sleep(self.config().update_period);
+            self.clock().sleep(self.config().update_period);
             // Consuming all messages and updating internal state.
             self.update()?;
+            // Syncs of the shared database wait until all migrations of this
+            // iteration are done, see `Database::sync_shared`.
+            let scheduler = self.db().read().sync_scheduler();
+            let batch = scheduler.migration_batch();
 
             use crate::database::StorageInfo;
 
@@ -248,10 +327,12 @@ pub(crate) trait MigrationPolicy {
                 })
                 .collect();
 
+            // Tiers whose daily write budget is used up receive no data.
             for ((high_tier, high_info), (low_tier, _low_info)) in infos
                 .iter()
                 .tuple_windows()
                 .filter(|(_, (_, low_info))| low_info.total != Block(0))
+                .filter(|((high_tier, _), _)| !self.dmu().write_budget_exhausted(*high_tier))
             {
                 self.promote(
                     *low_tier,
@@ -275,6 +356,7 @@ pub(crate) trait MigrationPolicy {
                 .filter(|((high_tier, high_info), (low_tier, low_info))| {
                     high_info.percent_full() > threshold[*high_tier as usize]
                         && low_info.percent_full() < threshold[*low_tier as usize]
+                        && !self.dmu().write_budget_exhausted(*low_tier)
                 })
             {
                 let desired: Block<u64> = Block(
@@ -283,6 +365,7 @@ pub(crate) trait MigrationPolicy {
                 ) - high_info.free.as_u64();
                 self.demote(*high_tier, desired)?;
             }
+            drop(batch);
             self.metrics()?;
         }
     }
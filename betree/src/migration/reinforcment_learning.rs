@@ -2,6 +2,7 @@ use crossbeam_channel::Receiver;
 use parking_lot::RwLock;
 
 use crate::{
+    clock::Clock,
     cow_bytes::CowBytes,
     data_management::{DmlWithHandler, DmlWithStorageHints},
     database::{RootDmu, StorageInfo},
@@ -10,12 +11,7 @@ use crate::{
     Database, StoragePreference,
 };
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    io::Write,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{collections::HashMap, io::Write, sync::Arc, time::UNIX_EPOCH};
 
 use super::{DatabaseMsg, DmlMsg, GlobalObjectId, MigrationConfig, MigrationPolicy};
 // This file contains a migration policy based on reinforcement learning.
@@ -585,10 +581,12 @@ pub(crate) struct ZhangHellanderToor {
     objects: HashMap<GlobalObjectId, ObjectInfo>,
     default_storage_class: StoragePreference,
     config: MigrationConfig<Option<RlConfig>>,
+    thresholds: Arc<super::MigrationThresholds>,
     dml_rx: Receiver<DmlMsg>,
     db_rx: Receiver<DatabaseMsg>,
     delta_moved: Vec<(GlobalObjectId, u64, u8, u8)>,
     state: DatabaseState,
+    clock: Clock,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -979,6 +977,8 @@ impl ZhangHellanderToor {
         db_rx: crossbeam_channel::Receiver<super::DatabaseMsg>,
         db: std::sync::Arc<parking_lot::RwLock<crate::Database>>,
         config: super::MigrationConfig<Option<RlConfig>>,
+        thresholds: Arc<super::MigrationThresholds>,
+        clock: Clock,
     ) -> Self {
         // We do not provide single node hints in this policy
         let dmu = Arc::clone(db.read().root_tree.dmu());
@@ -1032,6 +1032,7 @@ impl ZhangHellanderToor {
             tiers,
             default_storage_class,
             config,
+            thresholds,
             objects: Default::default(),
             delta_moved: Vec::new(),
             state: DatabaseState {
@@ -1040,6 +1041,7 @@ impl ZhangHellanderToor {
                 active_storage_classes,
                 object_stores: Default::default(),
             },
+            clock,
         }
     }
 }
@@ -1047,6 +1049,7 @@ impl ZhangHellanderToor {
 impl MigrationPolicy for ZhangHellanderToor {
     // One update call represents one epoch
     fn update(&mut self) -> super::errors::Result<()> {
+        self.config.migration_threshold = self.thresholds.get();
         // FIXME: This is an inefficient way to get rid of the accumulated
         // messages it would be better to actively close this channel. Fix some
         // behavior in the DML for this.
@@ -1143,14 +1146,17 @@ impl MigrationPolicy for ZhangHellanderToor {
     }
 
     fn thread_loop(&mut self) -> super::errors::Result<()> {
-        std::thread::sleep(self.config.grace_period);
+        self.clock.sleep(self.config.grace_period);
         loop {
-            std::thread::sleep(self.config.update_period);
+            self.clock.sleep(self.config.update_period);
             let start = std::time::Instant::now();
             debug!("Update");
             self.update()?;
             debug!("Timestep");
+            let scheduler = self.state.db.read().sync_scheduler();
+            let batch = scheduler.migration_batch();
             self.timestep()?;
+            drop(batch);
             debug!("Metrics");
             self.metrics()?;
             debug!("Cleanup");
@@ -1171,6 +1177,10 @@ impl MigrationPolicy for ZhangHellanderToor {
         self.config.clone().erased()
     }
 
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
     fn metrics(&self) -> super::errors::Result<()> {
         if let Some(p_config) = &self.config.policy_config {
             // Open files
@@ -1192,7 +1202,9 @@ impl MigrationPolicy for ZhangHellanderToor {
             total_file.write_all(b"\n")?;
             // Write delta
             //
-            let time = SystemTime::now()
+            let time = self
+                .clock
+                .now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_secs();
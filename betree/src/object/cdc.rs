@@ -0,0 +1,479 @@
+//! Content-defined chunking of objects, see
+//! [ObjectStore::enable_content_defined_chunking].
+//!
+//! Fixed-size chunks shift with every byte inserted into or removed from an
+//! object, so a backup which differs from the previous one only by a small
+//! insertion shares almost no chunk with it.  Content-defined chunks end where
+//! a rolling hash of the last bytes matches a pattern, so their boundaries
+//! move with the data and all chunks apart from the ones around a change stay
+//! identical.  The boundaries are found with FastCDC (Xia et al., 2016).
+//!
+//! Content-defined stores are deduplicating stores, see the `dedup` module,
+//! whose chunks are numbered consecutively per object.  As their sizes vary,
+//! each chunk reference records the end of the chunk data within the object
+//! after the content id:
+//!
+//! ```text
+//! [0]"cdc" -> [32-bit BE min size][32-bit BE avg size][32-bit BE max size]
+//! [64-bit BE object id][32-bit BE chunk number] -> [content id][64-bit BE end offset]
+//! ```
+//!
+//! The chunks of an object are always those FastCDC yields for the whole
+//! object.  A write therefore chunks the object again from the start of the
+//! chunk it begins in, until a cut behind the written range falls on a cut of
+//! the previous chunks.  Objects are never sparse, gaps written over are
+//! stored as zeros.
+
+use super::{dedup, object_chunk_key, ObjectId, ObjectStore};
+use crate::{
+    database::{ConfigurationProblem, Error, Result},
+    Dataset, StoragePreference,
+};
+use std::{collections::VecDeque, convert::TryInto};
+
+const CHUNKING_KEY: &[u8] = b"\0cdc";
+
+/// The largest maximum chunk size, twice the size of fixed chunks.
+pub const MAX_CDC_CHUNK_SIZE: u32 = 2 * super::chunk::CHUNK_SIZE;
+
+/// The rolling hash considers the last 64 bytes, shorter chunks can not be
+/// cut by content.
+const MIN_CDC_CHUNK_SIZE: u32 = 64;
+
+/// Random values by byte of the rolling gear hash.  The chunks of all stores
+/// depend on them, so they must never change.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        // splitmix64
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// The chunk sizes of a content-defined object store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkingConfig {
+    /// No chunk but the last one of an object is smaller.
+    pub min_size: u32,
+    /// The chunk size aimed for, rounded down to a power of two.
+    pub avg_size: u32,
+    /// No chunk is larger, at most [MAX_CDC_CHUNK_SIZE].
+    pub max_size: u32,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        ChunkingConfig {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut check = |option, value: u32, min: u32, max: u32| {
+            if value < min || value > max {
+                problems.push(ConfigurationProblem::OutOfRange {
+                    option,
+                    value: value as usize,
+                    min: min as usize,
+                    max: max as usize,
+                });
+            }
+        };
+        check("max_size", self.max_size, 0, MAX_CDC_CHUNK_SIZE);
+        check("avg_size", self.avg_size, 0, self.max_size);
+        check("min_size", self.min_size, MIN_CDC_CHUNK_SIZE, self.avg_size);
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfiguration(problems))
+        }
+    }
+
+    fn pack(&self) -> [u8; 12] {
+        let mut b = [0; 12];
+        b[..4].copy_from_slice(&self.min_size.to_be_bytes());
+        b[4..8].copy_from_slice(&self.avg_size.to_be_bytes());
+        b[8..].copy_from_slice(&self.max_size.to_be_bytes());
+        b
+    }
+
+    fn unpack(b: &[u8]) -> Self {
+        ChunkingConfig {
+            min_size: u32::from_be_bytes(b[..4].try_into().unwrap()),
+            avg_size: u32::from_be_bytes(b[4..8].try_into().unwrap()),
+            max_size: u32::from_be_bytes(b[8..12].try_into().unwrap()),
+        }
+    }
+
+    /// Returns the length of the first chunk of `data`.
+    ///
+    /// Up to the average size, cut points have to match more bits of the hash
+    /// than after it, which narrows the distribution of chunk sizes.
+    pub(super) fn cut(&self, data: &[u8]) -> usize {
+        let min = self.min_size as usize;
+        if data.len() <= min {
+            return data.len();
+        }
+        let end = data.len().min(self.max_size as usize);
+        let normal = end.min(self.avg_size as usize);
+        let bits = 31 - self.avg_size.leading_zeros();
+        let mask_small = !(u64::MAX >> (bits + 1));
+        let mask_large = !(u64::MAX >> (bits - 1));
+
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(min) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal { mask_small } else { mask_large };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Returns the chunking of the object store with the data tree `data`.
+pub(super) fn load_config(data: &Dataset) -> Result<Option<ChunkingConfig>> {
+    Ok(data
+        .get(CHUNKING_KEY)?
+        .map(|value| ChunkingConfig::unpack(&value)))
+}
+
+/// Returns the end of the data of a chunk of a content-defined store.
+pub(super) fn chunk_end(reference: &[u8]) -> u64 {
+    u64::from_be_bytes(reference[reference.len() - 8..].try_into().unwrap())
+}
+
+impl<'os> ObjectStore {
+    /// Cuts the objects written from now on into chunks at content-defined
+    /// boundaries, whose sizes are given by `config`, instead of fixed-size
+    /// chunks.  This enables deduplication, see
+    /// [Self::enable_deduplication], and is meant for backup-style workloads,
+    /// where objects share most of their contents at shifted offsets.  Their
+    /// common chunks are stored once, and replicas which have a previous
+    /// version of an object only lack the chunks around the changes.
+    ///
+    /// Like deduplication, content-defined chunking can only be enabled before
+    /// any chunk has been written.  Writes which change the number of chunks of
+    /// an object renumber all its chunks behind them, so objects should be
+    /// written sequentially.
+    pub fn enable_content_defined_chunking(&'os self, config: ChunkingConfig) -> Result<()> {
+        config.validate()?;
+        if self.chunking() == Some(config) {
+            return Ok(());
+        }
+        if self.iter_chunks()?.next().is_some() {
+            return Err(Error::NotEmpty);
+        }
+        self.enable_deduplication()?;
+        self.data.insert(CHUNKING_KEY, &config.pack())?;
+        *self.chunking.write() = Some(config);
+        Ok(())
+    }
+
+    /// Returns the chunk sizes if chunks are cut at content-defined boundaries,
+    /// see [Self::enable_content_defined_chunking].
+    pub fn chunking(&self) -> Option<ChunkingConfig> {
+        *self.chunking.read()
+    }
+
+    fn stored_chunk_end(&'os self, object_id: ObjectId, chunk: u32) -> Result<Option<u64>> {
+        Ok(self
+            .data
+            .get(&object_chunk_key(object_id, chunk)[..])?
+            .map(|reference| chunk_end(&reference)))
+    }
+
+    /// Returns the first chunk of an object which ends at or after `offset`,
+    /// or the number of chunks if there is none, with the start of its data.
+    pub(super) fn find_chunk(&'os self, object_id: ObjectId, offset: u64) -> Result<(u32, u64)> {
+        let ends_after = |chunk| -> Result<bool> {
+            Ok(self
+                .stored_chunk_end(object_id, chunk)?
+                .map_or(true, |end| end >= offset))
+        };
+        // All chunks before `low` end before `offset`, `high` does not.
+        let mut low = 0;
+        let mut high = 0;
+        let mut step = 1;
+        while !ends_after(high)? {
+            low = high + 1;
+            high = high.saturating_add(step);
+            step = step.saturating_mul(2);
+        }
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if ends_after(mid)? {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        let start = match low.checked_sub(1) {
+            Some(previous) => self.stored_chunk_end(object_id, previous)?.unwrap_or(0),
+            None => 0,
+        };
+        Ok((low, start))
+    }
+
+    /// Writes `buf` at `offset` into an object of a content-defined store.
+    pub(super) fn write_content_defined(
+        &'os self,
+        object_id: ObjectId,
+        offset: u64,
+        buf: &[u8],
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let (first, start) = self.find_chunk(object_id, offset)?;
+        let end = offset + buf.len() as u64;
+        self.rechunk(object_id, first, start, end, pref, |data| {
+            let from = (offset - start) as usize;
+            let to = from + buf.len();
+            if data.len() < to {
+                data.resize(to, 0);
+            }
+            data[from..to].copy_from_slice(buf);
+        })
+    }
+
+    /// Cuts off an object of a content-defined store after `len` bytes.
+    ///
+    /// Cuts only depend on the data before them, so the chunks before `len`
+    /// are kept and the rest of the chunk `len` falls into becomes the last
+    /// chunk.
+    pub(super) fn truncate_content_defined(
+        &'os self,
+        object_id: ObjectId,
+        len: u64,
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let (first, start) = self.find_chunk(object_id, len)?;
+        let from = object_chunk_key(object_id, first);
+        let to = object_chunk_key(object_id, u32::MAX);
+        let references = self
+            .data
+            .range(&from[..]..&to[..])?
+            .map(|res| res.map(|(_, reference)| reference))
+            .collect::<Result<Vec<_>>>()?;
+        let mut chunk = first;
+        let mut released = &references[..];
+        if let Some(reference) = references.first() {
+            if chunk_end(reference) == len {
+                released = &references[1..];
+                chunk += 1;
+            } else if len > start {
+                let data = dedup::resolve_chunk(&self.data, reference)?;
+                let content = &data[..(len - start) as usize];
+                let id = dedup::content_id(content);
+                self.store_content(&id, content, pref)?;
+                let mut reference = id.to_vec();
+                reference.extend_from_slice(&len.to_be_bytes());
+                self.data.insert_with_pref(
+                    &object_chunk_key(object_id, chunk)[..],
+                    &reference,
+                    pref,
+                )?;
+                chunk += 1;
+            }
+        }
+        for reference in released {
+            self.release_reference(reference)?;
+        }
+        if !released.is_empty() {
+            let from = object_chunk_key(object_id, chunk);
+            self.data.range_delete(&from[..]..&to[..])?;
+        }
+        Ok(())
+    }
+
+    /// Modifies the data of an object from the chunk `first` onwards, which
+    /// starts at `start`, and replaces the chunks it changes by those of the
+    /// modified data.  `modify` is given the data of the chunks up to the one
+    /// `end` falls into, and must not shorten it.
+    ///
+    /// As cuts only depend on the data since the previous cut, the chunks are
+    /// the same as before once a new cut behind `end` falls on an old one.
+    /// The old chunks from there on are kept, they are only renumbered if the
+    /// number of chunks before them has changed.
+    fn rechunk<F>(
+        &'os self,
+        object_id: ObjectId,
+        first: u32,
+        start: u64,
+        end: u64,
+        pref: StoragePreference,
+        modify: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<u8>),
+    {
+        let config = self
+            .chunking()
+            .expect("Store has no content-defined chunks");
+        let from = object_chunk_key(object_id, first);
+        let to = object_chunk_key(object_id, u32::MAX);
+        let mut old_chunks = self.data.range(&from[..]..&to[..])?;
+        // The data from `start` on, and the old chunks read into it but not
+        // cut through yet with their ends.
+        let mut data = Vec::new();
+        let mut old = VecDeque::new();
+        let mut read_next = |data: &mut Vec<u8>, old: &mut VecDeque<_>| -> Result<bool> {
+            match old_chunks.next() {
+                Some(res) => {
+                    let (_, reference) = res?;
+                    data.extend_from_slice(&dedup::resolve_chunk(&self.data, &reference)?);
+                    old.push_back((chunk_end(&reference), reference));
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        };
+        while start + (data.len() as u64) < end && read_next(&mut data, &mut old)? {}
+        modify(&mut data);
+
+        let mut new = Vec::new();
+        let mut released = Vec::new();
+        let mut pos = 0;
+        let mut exhausted = false;
+        let resynchronized = loop {
+            // A cut depends on up to `max_size` bytes.
+            while !exhausted && data.len() - pos < config.max_size as usize {
+                exhausted = !read_next(&mut data, &mut old)?;
+            }
+            if pos == data.len() {
+                break false;
+            }
+            let len = config.cut(&data[pos..]);
+            let content = &data[pos..pos + len];
+            let id = dedup::content_id(content);
+            self.store_content(&id, content, pref)?;
+            pos += len;
+            let cut = start + pos as u64;
+            let mut reference = id.to_vec();
+            reference.extend_from_slice(&cut.to_be_bytes());
+            new.push(reference);
+
+            let mut resynchronized = false;
+            while let Some((old_end, _)) = old.front() {
+                if *old_end > cut {
+                    break;
+                }
+                resynchronized = *old_end == cut && cut >= end;
+                released.push(old.pop_front().unwrap().1);
+            }
+            if resynchronized {
+                break true;
+            }
+        };
+
+        // The kept chunks only have to be read if they have to be moved.
+        let tail = if resynchronized && new.len() != released.len() {
+            old.into_iter()
+                .map(|(_, reference)| Ok(reference))
+                .chain(old_chunks.map(|res| res.map(|(_, reference)| reference)))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        // New references are added before the old ones are released, so that
+        // contents kept by the modification are never unreferenced.
+        let mut chunk = first;
+        for reference in new
+            .iter()
+            .map(|reference| &reference[..])
+            .chain(tail.iter().map(|reference| &reference[..]))
+        {
+            self.data
+                .insert_with_pref(&object_chunk_key(object_id, chunk)[..], reference, pref)?;
+            chunk += 1;
+        }
+        for reference in released.iter() {
+            self.release_reference(reference)?;
+        }
+        if ((chunk - first) as usize) < released.len() + tail.len() {
+            let from = object_chunk_key(object_id, chunk);
+            self.data.range_delete(&from[..]..&to[..])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChunkingConfig;
+    use rand::{RngCore, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    fn chunks(config: &ChunkingConfig, mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let len = config.cut(data);
+            chunks.push(data[..len].to_vec());
+            data = &data[len..];
+        }
+        chunks
+    }
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        XorShiftRng::seed_from_u64(42).fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn chunk_sizes_within_bounds() {
+        let config = ChunkingConfig::default();
+        let data = random_data(8 * 1024 * 1024);
+        let chunks = chunks(&config, &data);
+        let (last, full) = chunks.split_last().unwrap();
+        assert!(last.len() <= config.max_size as usize);
+        for chunk in full {
+            assert!(chunk.len() >= config.min_size as usize);
+            assert!(chunk.len() <= config.max_size as usize);
+        }
+        let avg = data.len() / chunks.len();
+        assert!(avg > config.avg_size as usize / 2 && avg < config.avg_size as usize * 2);
+    }
+
+    #[test]
+    fn boundaries_follow_shifted_data() {
+        let config = ChunkingConfig::default();
+        let data = random_data(4 * 1024 * 1024);
+        let mut shifted = b"inserted in front".to_vec();
+        shifted.extend_from_slice(&data);
+
+        let original = chunks(&config, &data);
+        let shifted = chunks(&config, &shifted);
+        let shared = shifted.iter().filter(|c| original.contains(c)).count();
+        assert!(shared >= original.len() - 2);
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        let config = ChunkingConfig {
+            min_size: 8,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = ChunkingConfig {
+            max_size: 1024 * 1024,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Deduplication of object chunks, see [ObjectStore::enable_deduplication].
+//!
+//! In a deduplicating object store, each chunk of an object refers to its
+//! contents by their hash, and identical contents are stored only once:
+//!
+//! ```text
+//! [0]"dedup" -> marks the object store as deduplicating
+//! [64-bit unsigned big-endian object id][32-bit unsigned big-endian chunk ID] -> [content id]
+//! [0]"chunk"[content id] -> [chunk value]
+//! ```
+//!
+//! The content id is the 128-bit xxh3 hash of the chunk value.  Chunks of a
+//! content-defined store append the end of their data to the content id, see
+//! the `cdc` module.  The meta tree
+//! counts the references to each content, see [super::meta::MetaMessageAction],
+//! and contents without references are removed by
+//! [ObjectStore::collect_orphaned_chunks].
+//!
+//! Shared contents are never modified, so writing to a chunk reads its current
+//! contents and stores the modified copy under a new id.  Concurrent writes to
+//! the same chunk of an object may therefore overwrite each other.  This also
+//! makes copies of objects cheap, see [super::ObjectHandle::copy_to], as they
+//! only add references to the contents of the copied object.
+
+use super::{decode_object_chunk_key, meta, object_chunk_key, ObjectId, ObjectStore};
+use crate::{
+    cow_bytes::SlicedCowBytes,
+    database::{Error, Result},
+    Dataset, StoragePreference,
+};
+use std::{convert::TryInto, ops::Range, sync::atomic::Ordering};
+
+pub(super) const DEDUPLICATION_KEY: &[u8] = b"\0dedup";
+const SHARED_CHUNK_PREFIX: &[u8] = b"\0chunk";
+const SHARED_CHUNK_END: &[u8] = b"\0chunl";
+
+const CONTENT_ID_LEN: usize = 16;
+
+pub(super) fn content_id(data: &[u8]) -> [u8; CONTENT_ID_LEN] {
+    twox_hash::xxh3::hash128(data).to_le_bytes()
+}
+
+/// Returns the content id a chunk reference starts with.
+pub(super) fn content_ref(reference: &[u8]) -> &[u8] {
+    &reference[..CONTENT_ID_LEN]
+}
+
+fn shared_chunk_key(content_id: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(SHARED_CHUNK_PREFIX.len() + content_id.len());
+    v.extend_from_slice(SHARED_CHUNK_PREFIX);
+    v.extend_from_slice(content_id);
+    v
+}
+
+/// Returns the contents a chunk of a deduplicating object store refers to.
+pub(super) fn resolve_chunk(data: &Dataset, reference: &[u8]) -> Result<SlicedCowBytes> {
+    data.get(shared_chunk_key(content_ref(reference)))?
+        .ok_or(Error::DoesNotExist)
+}
+
+/// Returns the storage class of the leaf holding the contents a chunk of a
+/// deduplicating object store refers to, if it has been written.
+pub(super) fn content_class(data: &Dataset, reference: &[u8]) -> Result<Option<u8>> {
+    let key = shared_chunk_key(content_ref(reference));
+    Ok(data
+        .leaf_classes(&key, &key)?
+        .first()
+        .and_then(|(_, class)| *class))
+}
+
+impl<'os> ObjectStore {
+    /// Stores all chunks written from now on by their content, so that
+    /// identical chunks of any objects in this store occupy space only once.
+    /// This is useful for objects which largely share their contents, like
+    /// virtual machine images or backups.
+    ///
+    /// Deduplication can only be enabled before any chunk has been written,
+    /// and stays enabled for the lifetime of the store.  Writes become more
+    /// expensive, as partial writes of a chunk read its previous contents.
+    /// Small writes are therefore journaled and applied to a chunk together
+    /// later, see [ObjectStore::sync].
+    /// Migrations only move the chunk references of an object, the shared
+    /// contents keep the storage preference they have been written with.
+    pub fn enable_deduplication(&'os self) -> Result<()> {
+        if self.is_deduplicated() {
+            return Ok(());
+        }
+        if self.iter_chunks()?.next().is_some() {
+            return Err(Error::NotEmpty);
+        }
+        self.data.insert(DEDUPLICATION_KEY, &[])?;
+        self.deduplicated.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns whether chunks are stored by their content, see
+    /// [Self::enable_deduplication].
+    pub fn is_deduplicated(&self) -> bool {
+        self.deduplicated.load(Ordering::SeqCst)
+    }
+
+    /// Writes `buf` into a chunk, starting at `offset` within the chunk.
+    pub(super) fn write_chunk(
+        &'os self,
+        object_id: ObjectId,
+        chunk_id: u32,
+        offset: u32,
+        buf: &[u8],
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let key = object_chunk_key(object_id, chunk_id);
+        if !self.is_deduplicated() {
+            return self.data.upsert_with_pref(&key[..], buf, offset, pref);
+        }
+        let old = self.data.get(&key[..])?;
+        let mut content = match &old {
+            Some(reference) => resolve_chunk(&self.data, reference)?.to_vec(),
+            None => Vec::new(),
+        };
+        let start = offset as usize;
+        let end = start + buf.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[start..end].copy_from_slice(buf);
+        self.put_shared_chunk(object_id, chunk_id, old, &content, pref)
+    }
+
+    /// Cuts off a chunk after `len` bytes.
+    pub(super) fn truncate_chunk(
+        &'os self,
+        object_id: ObjectId,
+        chunk_id: u32,
+        len: usize,
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let key = object_chunk_key(object_id, chunk_id);
+        let value = match self.data.get(&key[..])? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        if !self.is_deduplicated() {
+            if value.len() > len {
+                self.data.insert_with_pref(&key[..], &value[..len], pref)?;
+            }
+            return Ok(());
+        }
+        let content = resolve_chunk(&self.data, &value)?;
+        if content.len() > len {
+            self.put_shared_chunk(object_id, chunk_id, Some(value), &content[..len], pref)?;
+        }
+        Ok(())
+    }
+
+    /// Points a chunk to `content`, which is stored unless it is shared
+    /// already, and releases the previous reference `old` of the chunk.
+    fn put_shared_chunk(
+        &'os self,
+        object_id: ObjectId,
+        chunk_id: u32,
+        old: Option<SlicedCowBytes>,
+        content: &[u8],
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let id = content_id(content);
+        if old.as_deref() == Some(&id[..]) {
+            return Ok(());
+        }
+        self.store_content(&id, content, pref)?;
+        self.data
+            .insert_with_pref(&object_chunk_key(object_id, chunk_id)[..], &id, pref)?;
+        if let Some(old) = old {
+            self.release_reference(&old)?;
+        }
+        Ok(())
+    }
+
+    /// Stores `content` under its id `id` unless it is shared already, and
+    /// adds a reference to it.
+    pub(super) fn store_content(
+        &'os self,
+        id: &[u8; CONTENT_ID_LEN],
+        content: &[u8],
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let content_key = shared_chunk_key(id);
+        match self.data.get(&content_key[..])? {
+            Some(existing) if existing[..] != *content => return Err(Error::ChunkHashCollision),
+            Some(_) => {}
+            None => self
+                .data
+                .insert_with_pref(&content_key[..], content, pref)?,
+        }
+        self.add_references(id, 1)
+    }
+
+    /// Releases the content a chunk reference refers to.
+    pub(super) fn release_reference(&'os self, reference: &[u8]) -> Result<()> {
+        self.add_references(content_ref(reference), -1)
+    }
+
+    fn add_references(&'os self, content_id: &[u8], delta: i64) -> Result<()> {
+        self.metadata.insert_msg(
+            &meta::refcount_key(content_id)[..],
+            meta::add_references(delta).into(),
+        )
+    }
+
+    /// Copies all chunks of an object to another one.  In a deduplicating
+    /// store, only the references to the shared contents are copied.
+    pub(super) fn copy_chunks(
+        &'os self,
+        from: ObjectId,
+        to: ObjectId,
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let start = object_chunk_key(from, 0);
+        let end = object_chunk_key(from, u32::MAX);
+        for res in self.data.range(&start[..]..&end[..])? {
+            let (key, value) = res?;
+            let key: &[u8; 8 + 4] = key[..].try_into().expect("Invalid key length");
+            let (_, chunk_id) = decode_object_chunk_key(key);
+            if self.is_deduplicated() {
+                self.add_references(content_ref(&value), 1)?;
+            }
+            self.data
+                .insert_with_pref(&object_chunk_key(to, chunk_id)[..], &value, pref)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the given chunks of an object, releasing their references to
+    /// shared contents.
+    pub(super) fn delete_chunk_range(
+        &'os self,
+        object_id: ObjectId,
+        chunks: Range<u32>,
+    ) -> Result<()> {
+        let start = object_chunk_key(object_id, chunks.start);
+        let end = object_chunk_key(object_id, chunks.end);
+        if self.is_deduplicated() {
+            for res in self.data.range(&start[..]..&end[..])? {
+                let (_, reference) = res?;
+                self.release_reference(&reference)?;
+            }
+        }
+        self.data.range_delete(&start[..]..&end[..])
+    }
+
+    /// Returns the size of all shared contents, referenced or not.
+    pub(super) fn shared_contents_size(&'os self) -> Result<u64> {
+        let mut size = 0;
+        for res in self.data.range(SHARED_CHUNK_PREFIX..SHARED_CHUNK_END)? {
+            size += res?.1.len() as u64;
+        }
+        Ok(size)
+    }
+
+    /// Deletes all shared contents which are not referenced anymore and
+    /// returns their number.
+    pub(super) fn collect_unreferenced_chunks(&'os self) -> Result<usize> {
+        let mut unreferenced = Vec::new();
+        for key in self
+            .data
+            .range_keys(SHARED_CHUNK_PREFIX..SHARED_CHUNK_END)?
+        {
+            let key = key?;
+            let content_id = &key[SHARED_CHUNK_PREFIX.len()..];
+            if self.metadata.get(meta::refcount_key(content_id))?.is_none() {
+                unreferenced.push(key);
+            }
+        }
+        for key in unreferenced.iter() {
+            self.data.delete(key.clone())?;
+        }
+        Ok(unreferenced.len())
+    }
+}
@@ -0,0 +1,341 @@
+//! Consistency check of an [ObjectStore], cross-checking the object metadata
+//! against the chunks stored in the data tree.
+//!
+//! Object data and metadata are stored in separate trees which are not
+//! updated atomically, so an interrupted write or deletion can leave chunks
+//! without an owning object, or an object whose recorded size does not match
+//! its stored chunks.  Orphaned chunks can also be reclaimed on their own,
+//! without a full check, which is cheap enough to run in the background.
+
+use super::{
+    cdc, chunk::CHUNK_SIZE, decode_object_chunk_key, dedup, meta::MetaMessage, object_chunk_key,
+    ObjectId, ObjectInfo, ObjectStore,
+};
+use crate::{
+    database::{Error, Result},
+    Database,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fmt,
+};
+
+/// A single inconsistency found by [ObjectStore::fsck].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// Chunks of an object id for which no object exists.
+    OrphanedChunks {
+        /// The object id the chunks belong to.
+        object_id: ObjectId,
+        /// Number of orphaned chunks.
+        chunks: u32,
+    },
+    /// The chunk containing the last byte of an object is missing, the stored
+    /// data ends at `stored`.
+    MissingChunk {
+        /// Key of the object.
+        key: Vec<u8>,
+        /// The missing chunk.
+        chunk_id: u32,
+        /// Recorded size of the object.
+        size: u64,
+        /// End of the stored data of the object.
+        stored: u64,
+    },
+    /// Data is stored beyond the recorded size of an object.
+    SizeMismatch {
+        /// Key of the object.
+        key: Vec<u8>,
+        /// Recorded size of the object.
+        size: u64,
+        /// End of the stored data of the object.
+        stored: u64,
+    },
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsckProblem::OrphanedChunks { object_id, chunks } => {
+                write!(f, "{chunks} chunks of object id {object_id} have no owner")
+            }
+            FsckProblem::MissingChunk {
+                key,
+                chunk_id,
+                size,
+                stored,
+            } => write!(
+                f,
+                "object {key:?} has a size of {size} bytes, but chunk {chunk_id} is missing \
+                 and the stored data ends at {stored}"
+            ),
+            FsckProblem::SizeMismatch { key, size, stored } => write!(
+                f,
+                "object {key:?} has a size of {size} bytes, but data is stored up to {stored}"
+            ),
+        }
+    }
+}
+
+/// Result of [ObjectStore::fsck].
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Number of checked objects.
+    pub objects: usize,
+    /// Number of checked chunks.
+    pub chunks: usize,
+    /// All found problems.
+    pub problems: Vec<FsckProblem>,
+    /// Whether the found problems have been repaired.
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// Whether no problems have been found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Stored data of a single object as found in the data tree.
+#[derive(Default)]
+struct StoredChunks {
+    count: u32,
+    /// End of the data in bytes.
+    end: u64,
+    last_chunk: Option<u32>,
+}
+
+impl<'os> ObjectStore {
+    /// Checks this object store for chunks without an owning object and objects
+    /// whose size does not match their stored chunks.
+    ///
+    /// With `repair`, orphaned chunks are deleted, data beyond the recorded
+    /// size of an object is cut off, and objects whose last chunk is missing
+    /// are truncated to their stored data.  The store should not be modified
+    /// concurrently while checking.  Journaled writes are applied to the
+    /// chunks before.
+    pub fn fsck(&'os self, repair: bool) -> Result<FsckReport> {
+        self.fold_journals()?;
+        let mut report = FsckReport::default();
+
+        let mut objects: HashMap<ObjectId, (Vec<u8>, ObjectInfo)> = HashMap::new();
+        for (handle, info) in self.list_objects::<_, &[u8]>(..)? {
+            objects.insert(info.object_id, (handle.object.key, info));
+        }
+        report.objects = objects.len();
+
+        let mut stored: HashMap<ObjectId, StoredChunks> = HashMap::new();
+        for chunk in self.iter_chunks()? {
+            let (object_id, chunk_id, end) = chunk?;
+            report.chunks += 1;
+            let chunks = stored.entry(object_id).or_default();
+            chunks.count += 1;
+            chunks.end = chunks.end.max(end);
+            chunks.last_chunk = chunks.last_chunk.max(Some(chunk_id));
+        }
+
+        for (object_id, chunks) in stored.iter() {
+            if !objects.contains_key(object_id) {
+                report.problems.push(FsckProblem::OrphanedChunks {
+                    object_id: *object_id,
+                    chunks: chunks.count,
+                });
+            }
+        }
+
+        for (object_id, (key, info)) in objects.iter() {
+            let chunks = stored.get(object_id);
+            let end = chunks.map_or(0, |c| c.end);
+            if end > info.size {
+                report.problems.push(FsckProblem::SizeMismatch {
+                    key: key.clone(),
+                    size: info.size,
+                    stored: end,
+                });
+            } else if info.size > 0 {
+                let last_chunk = chunks.and_then(|c| c.last_chunk);
+                let missing = if self.chunking().is_some() {
+                    // Content-defined chunks are never sparse.
+                    (end < info.size).then(|| last_chunk.map_or(0, |c| c + 1))
+                } else {
+                    // Sparse objects may lack any chunk but the one holding the
+                    // last byte, which is always written.
+                    let chunk_id = ((info.size - 1) / CHUNK_SIZE as u64) as u32;
+                    (last_chunk != Some(chunk_id)).then_some(chunk_id)
+                };
+                if let Some(chunk_id) = missing {
+                    report.problems.push(FsckProblem::MissingChunk {
+                        key: key.clone(),
+                        chunk_id,
+                        size: info.size,
+                        stored: end,
+                    });
+                }
+            }
+        }
+
+        if repair {
+            for problem in report.problems.iter() {
+                self.repair(problem)?;
+            }
+            report.repaired = true;
+        }
+        Ok(report)
+    }
+
+    /// Deletes the chunks of all object ids for which no object exists and
+    /// returns the number of these object ids.  Such chunks are left behind if
+    /// the deletion of an object is interrupted, or if an object is written
+    /// through a handle after it has been deleted.
+    ///
+    /// In a deduplicating store, shared contents which are not referenced by
+    /// any chunk anymore are deleted as well and included in the returned
+    /// number.  The store must not be written to concurrently, as contents
+    /// may be reclaimed between being stored and being referenced.
+    pub fn collect_orphaned_chunks(&'os self) -> Result<usize> {
+        // Objects are created before any of their chunks are written, so
+        // scanning the chunks first cannot mistake a newly created object for
+        // an orphan.
+        let owners = self.chunk_owners()?;
+        let live = self
+            .list_objects::<_, &[u8]>(..)?
+            .map(|(_, info)| info.object_id)
+            .collect::<HashSet<_>>();
+
+        let mut reclaimed = 0;
+        for object_id in owners.into_iter().filter(|id| !live.contains(id)) {
+            self.delete_chunks(object_id)?;
+            reclaimed += 1;
+        }
+        if self.is_deduplicated() {
+            reclaimed += self.collect_unreferenced_chunks()?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Returns all object ids which have at least one chunk.  Instead of
+    /// visiting every chunk, the scan skips to the next object id after the
+    /// first chunk of each object.
+    fn chunk_owners(&'os self) -> Result<Vec<ObjectId>> {
+        let mut owners = Vec::new();
+        let mut next = Some(0);
+        while let Some(id) = next {
+            let start = object_chunk_key(ObjectId(id), 0);
+            let mut owner = None;
+            for res in self.data.range(&start[..]..)? {
+                let (key, _) = res?;
+                // skip the object id counter
+                if let Ok(key) = key[..].try_into() {
+                    owner = Some(decode_object_chunk_key(key).0);
+                    break;
+                }
+            }
+            next = owner.and_then(|ObjectId(id)| id.checked_add(1));
+            owners.extend(owner);
+        }
+        Ok(owners)
+    }
+
+    /// Iterates over all chunks in the data tree, yielding their object id,
+    /// chunk id and the end of their data within the object.
+    pub(super) fn iter_chunks(
+        &'os self,
+    ) -> Result<impl Iterator<Item = Result<(ObjectId, u32, u64)>>> {
+        let shared = self.is_deduplicated().then(|| self.data.clone());
+        let content_defined = self.chunking().is_some();
+        Ok(self
+            .data
+            .range::<_, &[u8]>(..)?
+            .filter_map(move |res| match res {
+                Ok((key, value)) => {
+                    // skip the object id counter and shared contents
+                    let key: &[u8; 8 + 4] = key[..].try_into().ok()?;
+                    let (object_id, chunk_id) = decode_object_chunk_key(key);
+                    if content_defined {
+                        return Some(Ok((object_id, chunk_id, cdc::chunk_end(&value))));
+                    }
+                    let len = match &shared {
+                        Some(data) => match dedup::resolve_chunk(data, &value) {
+                            Ok(content) => content.len(),
+                            Err(e) => return Some(Err(e)),
+                        },
+                        None => value.len(),
+                    };
+                    Some(Ok((
+                        object_id,
+                        chunk_id,
+                        chunk_id as u64 * CHUNK_SIZE as u64 + len as u64,
+                    )))
+                }
+                Err(e) => Some(Err(e)),
+            }))
+    }
+
+    /// Deletes all chunks of the given object id.
+    pub(super) fn delete_chunks(&'os self, object_id: ObjectId) -> Result<()> {
+        self.discard_journal(object_id)?;
+        self.delete_chunk_range(object_id, 0..u32::MAX)
+    }
+
+    fn repair(&'os self, problem: &FsckProblem) -> Result<()> {
+        match problem {
+            FsckProblem::OrphanedChunks { object_id, .. } => self.delete_chunks(*object_id),
+            FsckProblem::MissingChunk { key, stored, .. } => {
+                let info = match self.read_object_info(key)? {
+                    Some(info) => info,
+                    None => return Ok(()),
+                };
+                let info = ObjectInfo {
+                    size: *stored,
+                    ..info
+                };
+                // A message setting all properties replaces the size instead of
+                // merging it with max.
+                self.update_object_info(key, &MetaMessage::set_info(&info))
+            }
+            FsckProblem::SizeMismatch { key, size, .. } => {
+                let info = match self.read_object_info(key)? {
+                    Some(info) => info,
+                    None => return Ok(()),
+                };
+                if self.chunking().is_some() {
+                    return self.truncate_content_defined(info.object_id, *size, info.pref);
+                }
+                let first_chunk = (size / CHUNK_SIZE as u64) as u32;
+                let offset = (size % CHUNK_SIZE as u64) as usize;
+                let start = if offset > 0 {
+                    // Cut off the chunk containing the end of the object.
+                    self.truncate_chunk(info.object_id, first_chunk, offset, info.pref)?;
+                    first_chunk + 1
+                } else {
+                    first_chunk
+                };
+                self.delete_chunk_range(info.object_id, start..u32::MAX)
+            }
+        }
+    }
+}
+
+impl Database {
+    /// Reclaims orphaned chunks in all object stores, see
+    /// [ObjectStore::collect_orphaned_chunks].  Object stores which are
+    /// currently open are skipped, so that no concurrent modification can
+    /// interfere.  Returns the number of reclaimed object ids.
+    pub fn collect_orphaned_chunks(&mut self) -> Result<usize> {
+        let ids = self.iter_object_stores()?.collect::<Result<Vec<_>>>()?;
+        let mut reclaimed = 0;
+        for id in ids {
+            let store = match self.open_object_store_with_id(id) {
+                Ok(store) => store,
+                Err(Error::InUse) => continue,
+                Err(e) => return Err(e),
+            };
+            let result = store.collect_orphaned_chunks();
+            self.close_object_store(store);
+            reclaimed += result?;
+        }
+        Ok(reclaimed)
+    }
+}
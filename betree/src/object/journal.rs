@@ -0,0 +1,254 @@
+//! Journal of small writes to deduplicating and content-defined object stores.
+//!
+//! Chunks of these stores are stored by their content, so writing a part of a
+//! chunk reads its previous contents and stores a modified copy, see the
+//! `dedup` and `cdc` modules.  Writes smaller than [JOURNAL_MAX_WRITE] are
+//! therefore appended to a journal in the data tree instead:
+//!
+//! ```text
+//! [0]"journal"[64-bit unsigned big-endian object id][64-bit unsigned big-endian sequence number]
+//!     -> [64-bit unsigned big-endian offset][8-bit storage preference][data]
+//! ```
+//!
+//! The journal of an object is folded into its chunks before they are read or
+//! written otherwise, on [ObjectStore::sync], and once it holds more than
+//! [JOURNAL_MAX_PENDING] bytes.  Overlapping and adjacent journaled writes are
+//! coalesced first, so that every chunk is rewritten once per fold instead of
+//! once per write.
+//!
+//! Plain stores do not need a journal, as their writes are upserts which the
+//! message buffers of the tree merge without reading the chunk.
+
+use super::{chunk::ChunkRange, ObjectId, ObjectStore};
+use crate::{database::Result, Dataset, StoragePreference};
+use std::{collections::HashMap, convert::TryInto};
+
+/// Writes of at least this many bytes are applied to the chunks directly.
+pub(super) const JOURNAL_MAX_WRITE: usize = 16 * 1024;
+/// Bytes which may be journaled for a single object before it is folded.
+pub(super) const JOURNAL_MAX_PENDING: usize = 1024 * 1024;
+
+const JOURNAL_PREFIX: &[u8] = b"\0journal";
+const JOURNAL_END: &[u8] = b"\0journam";
+
+fn journal_key(ObjectId(object_id): ObjectId, seq: u64) -> Vec<u8> {
+    let mut v = Vec::with_capacity(JOURNAL_PREFIX.len() + 8 + 8);
+    v.extend_from_slice(JOURNAL_PREFIX);
+    v.extend_from_slice(&object_id.to_be_bytes());
+    v.extend_from_slice(&seq.to_be_bytes());
+    v
+}
+
+fn decode_journal_key(key: &[u8]) -> (ObjectId, u64) {
+    let key = &key[JOURNAL_PREFIX.len()..];
+    let id = u64::from_be_bytes(key[..8].try_into().unwrap());
+    let seq = u64::from_be_bytes(key[8..].try_into().unwrap());
+    (ObjectId(id), seq)
+}
+
+/// In-memory state of the journal of a store.
+#[derive(Default)]
+pub(super) struct Journal {
+    /// Sequence number of the next journaled write.
+    next: u64,
+    /// Journaled bytes by object.
+    pending: HashMap<ObjectId, usize>,
+}
+
+impl Journal {
+    /// Recovers the state of the journal from the data tree of a store.
+    pub(super) fn load(data: &Dataset) -> Result<Self> {
+        let mut journal = Journal::default();
+        for res in data.range(JOURNAL_PREFIX..JOURNAL_END)? {
+            let (key, value) = res?;
+            let (object_id, seq) = decode_journal_key(&key);
+            journal.next = journal.next.max(seq + 1);
+            *journal.pending.entry(object_id).or_default() += value.len() - 9;
+        }
+        Ok(journal)
+    }
+}
+
+/// A coalesced range of journaled writes.
+struct Extent {
+    offset: u64,
+    pref: StoragePreference,
+    data: Vec<u8>,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+/// Adds a write to `extents`, which are sorted and neither overlap nor touch,
+/// merging it with the extents it overlaps or touches.
+fn coalesce(extents: &mut Vec<Extent>, offset: u64, pref: StoragePreference, buf: &[u8]) {
+    let end = offset + buf.len() as u64;
+    let first = extents.partition_point(|e| e.end() < offset);
+    let last = extents.partition_point(|e| e.offset <= end);
+    let mut merged = Extent {
+        offset,
+        pref,
+        data: Vec::new(),
+    };
+    if first < last {
+        merged.offset = merged.offset.min(extents[first].offset);
+        let merged_end = end.max(extents[last - 1].end());
+        merged.data.resize((merged_end - merged.offset) as usize, 0);
+        for extent in extents.drain(first..last) {
+            let from = (extent.offset - merged.offset) as usize;
+            merged.data[from..from + extent.data.len()].copy_from_slice(&extent.data);
+        }
+    } else {
+        merged.data.resize(buf.len(), 0);
+    }
+    let from = (offset - merged.offset) as usize;
+    merged.data[from..from + buf.len()].copy_from_slice(buf);
+    extents.insert(first, merged);
+}
+
+impl<'os> ObjectStore {
+    /// Whether small writes to this store are journaled.
+    pub(super) fn is_journaled(&self) -> bool {
+        self.is_deduplicated() || self.chunking().is_some()
+    }
+
+    /// Appends a write of `buf` at `offset` to the journal of an object, and
+    /// folds the journal if it has grown too large.
+    pub(super) fn journal_write(
+        &'os self,
+        object_id: ObjectId,
+        offset: u64,
+        buf: &[u8],
+        pref: StoragePreference,
+    ) -> Result<()> {
+        let mut journal = self.journal.lock();
+        let mut value = Vec::with_capacity(9 + buf.len());
+        value.extend_from_slice(&offset.to_be_bytes());
+        value.push(pref.as_u8());
+        value.extend_from_slice(buf);
+        self.data
+            .insert_with_pref(journal_key(object_id, journal.next), &value, pref)?;
+        journal.next += 1;
+        let pending = journal.pending.entry(object_id).or_default();
+        *pending += buf.len();
+        if *pending > JOURNAL_MAX_PENDING {
+            self.fold_locked(&mut journal, object_id)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the journaled writes of an object to its chunks.
+    pub(super) fn fold_journal(&'os self, object_id: ObjectId) -> Result<()> {
+        let mut journal = self.journal.lock();
+        self.fold_locked(&mut journal, object_id)
+    }
+
+    /// Applies the journaled writes of all objects to their chunks.
+    pub(super) fn fold_journals(&'os self) -> Result<()> {
+        let mut journal = self.journal.lock();
+        let objects = journal.pending.keys().copied().collect::<Vec<_>>();
+        for object_id in objects {
+            self.fold_locked(&mut journal, object_id)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the journaled writes of an object without applying them.
+    pub(super) fn discard_journal(&'os self, object_id: ObjectId) -> Result<()> {
+        let mut journal = self.journal.lock();
+        if journal.pending.remove(&object_id).is_some() {
+            self.data.range_delete(
+                &journal_key(object_id, 0)[..]..=&journal_key(object_id, u64::MAX)[..],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn fold_locked(&'os self, journal: &mut Journal, object_id: ObjectId) -> Result<()> {
+        if !journal.pending.contains_key(&object_id) {
+            return Ok(());
+        }
+        let from = journal_key(object_id, 0);
+        let to = journal_key(object_id, u64::MAX);
+        let mut extents = Vec::new();
+        for res in self.data.range(&from[..]..=&to[..])? {
+            let (_, value) = res?;
+            let offset = u64::from_be_bytes(value[..8].try_into().unwrap());
+            let pref = StoragePreference::from_u8(value[8]);
+            coalesce(&mut extents, offset, pref, &value[9..]);
+        }
+        for extent in extents.iter() {
+            self.write_extent(object_id, extent.offset, &extent.data, extent.pref)?;
+        }
+        // Only remove the journal once all writes have been applied, so that
+        // a failed fold can be repeated.
+        self.data.range_delete(&from[..]..=&to[..])?;
+        journal.pending.remove(&object_id);
+        Ok(())
+    }
+
+    /// Writes `buf` at `offset` into the chunks of an object.
+    fn write_extent(
+        &'os self,
+        object_id: ObjectId,
+        offset: u64,
+        mut buf: &[u8],
+        pref: StoragePreference,
+    ) -> Result<()> {
+        if self.chunking().is_some() {
+            return self.write_content_defined(object_id, offset, buf, pref);
+        }
+        let chunk_range = ChunkRange::from_byte_bounds(offset, buf.len() as u64);
+        for chunk in chunk_range.split_at_chunk_bounds() {
+            let len = chunk.single_chunk_len() as usize;
+            self.write_chunk(
+                object_id,
+                chunk.start.chunk_id,
+                chunk.start.offset,
+                &buf[..len],
+                pref,
+            )?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(extents: &[Extent]) -> Vec<u8> {
+        let mut v = Vec::new();
+        for extent in extents {
+            let end = extent.end() as usize;
+            if v.len() < end {
+                v.resize(end, 0);
+            }
+            v[extent.offset as usize..end].copy_from_slice(&extent.data);
+        }
+        v
+    }
+
+    #[test]
+    fn coalesce_keeps_latest_write() {
+        let mut extents = Vec::new();
+        let mut expected = vec![0; 64];
+        for (i, (offset, len)) in [(10, 5), (30, 4), (12, 20), (40, 2), (0, 3), (3, 7)]
+            .into_iter()
+            .enumerate()
+        {
+            let buf = vec![i as u8 + 1; len];
+            coalesce(&mut extents, offset, StoragePreference::NONE, &buf);
+            expected[offset as usize..offset as usize + len].copy_from_slice(&buf);
+        }
+        // all but the write at 40 have been merged into one extent
+        assert_eq!(extents.len(), 2);
+        assert!(extents.windows(2).all(|w| w[0].end() < w[1].offset));
+        let actual = apply(&extents);
+        assert_eq!(actual[..], expected[..actual.len()]);
+    }
+}
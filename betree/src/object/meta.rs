@@ -1,4 +1,4 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use speedy::{Endianness, Readable, Writable};
 
 use crate::{
@@ -31,10 +31,31 @@ pub struct ObjectInfo {
     pub pref: StoragePreference,
     /// The specified access pattern hint.
     pub access_pattern: PreferredAccessType,
+    /// Coarse timestamp of the last read of the object, only maintained if
+    /// access time tracking is enabled on the [super::ObjectStore].  It may
+    /// lag behind the actual last access by up to the configured interval.
+    pub atime: Option<SystemTime>,
+}
+
+impl ObjectInfo {
+    /// Decodes a stored [ObjectInfo].  Entries written before access times
+    /// were tracked lack the trailing `atime`, they are read as if it was
+    /// `None`.
+    pub(crate) fn unpack(buf: &[u8]) -> ObjectInfo {
+        ObjectInfo::read_from_buffer_with_ctx(ENDIAN, buf)
+            .or_else(|_| {
+                let mut extended = Vec::with_capacity(buf.len() + 1);
+                extended.extend_from_slice(buf);
+                // encoding of `None`
+                extended.push(0);
+                ObjectInfo::read_from_buffer_with_ctx(ENDIAN, &extended)
+            })
+            .unwrap()
+    }
 }
 
 /// Every message represents an overwrite or merge of a set of [ObjectInfo] properties.
-/// `size` and `mtime` are merged with `max`, whereas `object_id` is just overwritten.
+/// `size`, `mtime` and `atime` are merged with `max`, whereas `object_id` is just overwritten.
 ///
 /// The `max` merge is required to allow concurrent writes of mutually ignorant clients
 /// without writing over a larger `size` message.
@@ -45,6 +66,7 @@ pub(super) struct MetaMessage {
     pub(super) mtime: Option<SystemTime>,
     pub(super) pref: Option<StoragePreference>,
     pub(super) access_pattern: Option<PreferredAccessType>,
+    pub(super) atime: Option<SystemTime>,
 }
 
 const CONTENT_FLAG_NONE: u8 = MetaMessage::delete().to_content_flags();
@@ -54,8 +76,12 @@ const CONTENT_FLAG_ALL: u8 = (MetaMessage {
     mtime: Some(UNIX_EPOCH),
     pref: Some(StoragePreference::NONE),
     access_pattern: Some(PreferredAccessType::Unknown),
+    // The access time is optional, a message setting all other properties
+    // already overwrites an object entirely.
+    atime: None,
 })
 .to_content_flags();
+const CONTENT_FLAG_ATIME: u8 = 32;
 
 /// Following functions are closely coupled together, any change to [MetaMessage] must be reflected
 /// in all of them.
@@ -66,6 +92,7 @@ impl MetaMessage {
         mtime: Option<SystemTime>,
         pref: Option<StoragePreference>,
         access_pattern: Option<PreferredAccessType>,
+        atime: Option<SystemTime>,
     ) -> Self {
         MetaMessage {
             object_id,
@@ -73,11 +100,12 @@ impl MetaMessage {
             mtime,
             pref,
             access_pattern,
+            atime,
         }
     }
 
     pub const fn delete() -> MetaMessage {
-        MetaMessage::new(None, None, None, None, None)
+        MetaMessage::new(None, None, None, None, None, None)
     }
 
     pub fn set_info(info: &ObjectInfo) -> MetaMessage {
@@ -87,6 +115,7 @@ impl MetaMessage {
             Some(info.mtime),
             Some(info.pref),
             Some(info.access_pattern),
+            info.atime,
         )
     }
 
@@ -99,6 +128,11 @@ impl MetaMessage {
             | (if self.mtime.is_some() { 4 } else { 0 })
             | (if self.pref.is_some() { 8 } else { 0 })
             | (if self.access_pattern.is_some() { 16 } else { 0 })
+            | (if self.atime.is_some() {
+                CONTENT_FLAG_ATIME
+            } else {
+                0
+            })
     }
 
     const fn encoded_length(&self) -> usize {
@@ -106,7 +140,8 @@ impl MetaMessage {
             + (if self.size.is_some() { 8 } else { 0 })
             + (if self.mtime.is_some() { 8 } else { 0 })
             + (if self.pref.is_some() { 1 } else { 0 })
-            + (if self.access_pattern.is_some() { 16 } else { 0 })
+            + (if self.access_pattern.is_some() { 1 } else { 0 })
+            + (if self.atime.is_some() { 8 } else { 0 })
     }
 
     pub(crate) fn pack(&self) -> CowBytes {
@@ -120,15 +155,7 @@ impl MetaMessage {
             let _ = v.write_u64::<LittleEndian>(size);
         }
         if let Some(mtime) = self.mtime {
-            let us_since_epoch = mtime
-                .duration_since(UNIX_EPOCH)
-                .map_err(|_| ())
-                .expect("mtime is earlier than epoch")
-                .as_micros()
-                .try_into()
-                .expect("mtime is past u64 (us) range");
-
-            let _ = v.write_u64::<LittleEndian>(us_since_epoch);
+            let _ = v.write_u64::<LittleEndian>(us_since_epoch(mtime));
         }
         if let Some(pref) = self.pref {
             let _ = v.write_u8(pref.as_u8());
@@ -137,6 +164,9 @@ impl MetaMessage {
         if let Some(ap) = self.access_pattern {
             let _ = v.write_u8(ap.as_u8());
         }
+        if let Some(atime) = self.atime {
+            let _ = v.write_u64::<LittleEndian>(us_since_epoch(atime));
+        }
 
         CowBytes::from(v)
     }
@@ -164,11 +194,24 @@ impl MetaMessage {
             message.access_pattern =
                 Some(PreferredAccessType::try_from(cursor.read_u8()?).unwrap_or_default());
         }
+        if content_flags & CONTENT_FLAG_ATIME != 0 {
+            let us_since_epoch = cursor.read_u64::<LittleEndian>()?;
+            message.atime = Some(UNIX_EPOCH + Duration::from_micros(us_since_epoch));
+        }
 
         Ok(message)
     }
 }
 
+fn us_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_err(|_| ())
+        .expect("timestamp is earlier than epoch")
+        .as_micros()
+        .try_into()
+        .expect("timestamp is past u64 (us) range")
+}
+
 /// MetaMessageAction consists of two different message types, split by the key for which the
 /// message is intended:
 ///
@@ -194,16 +237,51 @@ impl MetaMessage {
 /// Fixed metadata messages have no Rust structure, their encoding is:
 /// - `[0]`, as a deletion message
 /// - `[1]<user-provided value>`, as a replacement message
+///
+/// Deduplicating object stores additionally keep the reference counts of shared chunks under
+/// `[0][0][content id]`, which no custom entry can occupy as their names must not contain null
+/// bytes.  A count is stored as LE i64 and changed by counter messages `[2]<LE i64 delta>`, which
+/// are merged by summing them up.  Counts which drop to zero are removed.
 #[derive(Debug, Default, Clone)]
 pub struct MetaMessageAction;
 
 const FIXED_DELETE: u8 = 0;
 const FIXED_REPLACE: u8 = 1;
+const COUNTER_ADD: u8 = 2;
 
 pub(super) fn is_fixed_key(key: &[u8]) -> bool {
     !key.contains(&0)
 }
 
+pub(super) fn is_refcount_key(key: &[u8]) -> bool {
+    key.starts_with(&[0, 0])
+}
+
+pub(super) fn refcount_key(content_id: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(2 + content_id.len());
+    v.extend_from_slice(&[0, 0]);
+    v.extend_from_slice(content_id);
+    v
+}
+
+pub(super) fn add_references(delta: i64) -> CowBytes {
+    let mut v = Vec::with_capacity(1 + 8);
+    v.push(COUNTER_ADD);
+    let _ = v.write_i64::<LittleEndian>(delta);
+    v.into()
+}
+
+/// Applies a counter message to a stored count.
+fn apply_counter(msg: &[u8], data: &mut Option<SlicedCowBytes>) {
+    let count =
+        data.as_ref().map_or(0, |d| LittleEndian::read_i64(d)) + LittleEndian::read_i64(&msg[1..]);
+    *data = if count > 0 {
+        Some(CowBytes::from(count.to_le_bytes().to_vec()).into())
+    } else {
+        None
+    };
+}
+
 pub(super) fn delete_custom() -> CowBytes {
     [FIXED_DELETE][..].into()
 }
@@ -227,6 +305,7 @@ impl MessageAction for MetaMessageAction {
                     mtime: Some(mtime),
                     pref: Some(pref),
                     access_pattern: Some(access_pattern),
+                    atime,
                 } => {
                     // message overwrites entirely, don't bother unpacking existing data
                     let info = ObjectInfo {
@@ -235,6 +314,7 @@ impl MessageAction for MetaMessageAction {
                         mtime,
                         pref,
                         access_pattern,
+                        atime,
                     };
                     *data =
                         Some(CowBytes::from(info.write_to_vec_with_ctx(ENDIAN).unwrap()).into());
@@ -245,6 +325,7 @@ impl MessageAction for MetaMessageAction {
                     mtime: None,
                     pref: None,
                     access_pattern: None,
+                    atime: None,
                 } => {
                     // message deletes entirely
                     *data = None;
@@ -255,9 +336,10 @@ impl MessageAction for MetaMessageAction {
                     mtime,
                     pref,
                     access_pattern,
+                    atime,
                 } => {
                     if let Some(d) = data {
-                        let mut info = ObjectInfo::read_from_buffer_with_ctx(ENDIAN, d).unwrap();
+                        let mut info = ObjectInfo::unpack(d);
 
                         if let Some(object_id) = object_id {
                             info.object_id = object_id;
@@ -274,6 +356,9 @@ impl MessageAction for MetaMessageAction {
                         if let Some(access_pattern) = access_pattern {
                             info.access_pattern = access_pattern;
                         }
+                        if let Some(atime) = atime {
+                            info.atime = info.atime.max(Some(atime));
+                        }
 
                         *data = Some(
                             CowBytes::from(info.write_to_vec_with_ctx(ENDIAN).unwrap()).into(),
@@ -286,6 +371,7 @@ impl MessageAction for MetaMessageAction {
             match msg[0] {
                 FIXED_DELETE => *data = None,
                 FIXED_REPLACE => *data = Some(msg.clone().slice_from(1)),
+                COUNTER_ADD => apply_counter(msg, data),
                 _ => unreachable!(),
             }
         }
@@ -310,7 +396,7 @@ impl MessageAction for MetaMessageAction {
 
             match upper.to_content_flags() {
                 // upper sets everything, lower has no influence
-                CONTENT_FLAG_ALL => upper_msg,
+                flags if flags & CONTENT_FLAG_ALL == CONTENT_FLAG_ALL => upper_msg,
                 // upper sets nothing, does not alter lower
                 CONTENT_FLAG_NONE => lower_msg,
                 // combine upper and lower, upper has priority for object_id
@@ -323,10 +409,27 @@ impl MessageAction for MetaMessageAction {
                         // Prefer newer if set
                         pref: upper.pref.or(lower.pref),
                         access_pattern: upper.access_pattern.or(lower.access_pattern),
+                        atime: or_max(upper.atime, lower.atime),
                     };
                     new.pack().into()
                 }
             }
+        } else if upper_msg[0] == COUNTER_ADD {
+            // this is a reference count, deltas are summed up and applied to
+            // a lower replacement or deletion
+            if lower_msg[0] == COUNTER_ADD {
+                let sum = LittleEndian::read_i64(&upper_msg[1..])
+                    .wrapping_add(LittleEndian::read_i64(&lower_msg[1..]));
+                add_references(sum).into()
+            } else {
+                let mut data = None;
+                self.apply(key, &lower_msg, &mut data);
+                apply_counter(&upper_msg, &mut data);
+                match data {
+                    Some(count) => set_custom(&count).into(),
+                    None => delete_custom().into(),
+                }
+            }
         } else {
             // this is a custom metadata entry, and the upper message always wins
             upper_msg
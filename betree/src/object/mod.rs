@@ -21,6 +21,12 @@
 //! [64-bit unsigned big-endian object id][32-bit unsigned big-endian chunk ID] -> [chunk value]
 //! ```
 //!
+//! Deduplicating object stores map chunks to shared contents instead, see the `dedup` module.
+//! Content-defined stores additionally cut chunks of varying size, see the `cdc` module.
+//! Small writes to both are journaled before they are applied to the chunks, see the `journal`
+//! module.
+//! The quota of a store is kept in the data tree as well, see the `usage` module.
+//!
 //! The object id counter must be in the data tree instead of the meta tree,
 //! because the value is not an ObjectInfo. Alternatively, a third tree could be created, but it'd
 //! be for just one value. In practice, this shouldn't be a problem, as any sync of the oid usually
@@ -46,12 +52,13 @@
 
 #![allow(missing_docs)]
 use crate::{
+    checksum::{Builder, Checksum, State, XxHash},
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::Dml,
     database::root_tree_msg::{
         OBJECT_STORE_DATA_PREFIX, OBJECT_STORE_ID_COUNTER_PREFIX, OBJECT_STORE_NAME_TO_ID_PREFIX,
     },
-    database::{DatasetId, Error, Result},
+    database::{DatasetId, Error, Generation, Result},
     migration::{DatabaseMsg, GlobalObjectId},
     size::StaticSize,
     storage_pool::StoragePoolLayer,
@@ -61,6 +68,7 @@ use crate::{
 };
 
 use crossbeam_channel::Sender;
+use parking_lot::{Mutex, RwLock};
 use speedy::{Readable, Writable};
 
 use std::{
@@ -71,10 +79,10 @@ use std::{
     ops::{Range, RangeBounds},
     result,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
-    time::{Instant, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 mod chunk;
@@ -82,8 +90,19 @@ mod meta;
 use self::{chunk::*, meta::*};
 pub use meta::ObjectInfo;
 
+mod cdc;
 mod cursor;
+mod dedup;
+mod fsck;
+mod journal;
+mod residency;
+mod usage;
+pub use cdc::{ChunkingConfig, MAX_CDC_CHUNK_SIZE};
 pub use cursor::ObjectCursor;
+pub use fsck::{FsckProblem, FsckReport};
+use journal::JOURNAL_MAX_WRITE;
+pub use residency::Residency;
+pub use usage::Usage;
 
 const OBJECT_ID_COUNTER_KEY: &[u8] = b"\0oid";
 
@@ -132,8 +151,13 @@ pub struct ObjectStore {
     data: Dataset,
     metadata: Dataset<MetaMessageAction>,
     object_id_counter: Arc<AtomicU64>,
+    deduplicated: Arc<AtomicBool>,
+    chunking: Arc<RwLock<Option<ChunkingConfig>>>,
+    quota: Arc<Mutex<Option<usage::Quota>>>,
+    journal: Arc<Mutex<journal::Journal>>,
     default_storage_preference: StoragePreference,
     report: Option<Sender<DatabaseMsg>>,
+    access_time_interval: Option<Duration>,
 }
 
 // A type alias to represent the on disk identifier for a specific object store.
@@ -373,11 +397,19 @@ impl<'os> ObjectStore {
                     Arc::new(AtomicU64::new(0))
                 }
             },
+            deduplicated: Arc::new(AtomicBool::new(
+                data.get(dedup::DEDUPLICATION_KEY)?.is_some(),
+            )),
+            chunking: Arc::new(RwLock::new(cdc::load_config(&data)?)),
+            quota: Arc::new(Mutex::new(None)),
+            journal: Arc::new(Mutex::new(journal::Journal::load(&data)?)),
             data,
             metadata,
             default_storage_preference,
             report: report.clone(),
+            access_time_interval: None,
         };
+        *store.quota.lock() = usage::Quota::load(&store)?;
         if let Some(tx) = report {
             let _ = tx
                 .send(DatabaseMsg::ObjectstoreOpen(store.id, store.clone()))
@@ -386,19 +418,25 @@ impl<'os> ObjectStore {
         Ok(store)
     }
 
+    /// Enables tracking of the last access time of objects in
+    /// [ObjectInfo::atime], or disables it with `None`.  To limit the amount of
+    /// metadata writes caused by reads, the access time of an object is only
+    /// updated if the previous one is older than `interval`.
+    ///
+    /// This only affects this instance, clones made before keep their setting.
+    pub fn set_access_time_interval(&mut self, interval: Option<Duration>) {
+        self.access_time_interval = interval;
+    }
+
     /// Return an iterator overall object names and metadata in this object store.
     pub fn iter_objects(&self) -> Result<impl Iterator<Item = (CowBytes, ObjectInfo)>> {
         // Iterate over the metadata and create tuples of object keys and ids.
         Ok(self
             .metadata
             .range(&[0u8] as &[_]..=&[u8::MAX] as &[_])?
-            .map(|res| {
-                let (k, v) = res.unwrap();
-                (
-                    k,
-                    ObjectInfo::read_from_buffer_with_ctx(meta::ENDIAN, &v).unwrap(),
-                )
-            }))
+            .map(|res| res.unwrap())
+            .filter(|(k, _v)| meta::is_fixed_key(k))
+            .map(|(k, v)| (k, ObjectInfo::unpack(&v))))
     }
 
     /// Create a new object handle and fit storage location to expected access
@@ -456,6 +494,7 @@ impl<'os> ObjectStore {
             mtime: SystemTime::now(),
             pref: storage_preference,
             access_pattern: access_type,
+            atime: self.access_time_interval.map(|_| SystemTime::now()),
         };
 
         self.update_object_info(key, &MetaMessage::set_info(&info))?;
@@ -589,19 +628,29 @@ impl<'os> ObjectStore {
     /// Delete an existing object.
     pub(crate) fn delete_object(&'os self, handle: &ObjectHandle) -> Result<()> {
         // FIXME: bad error handling, object can end up partially deleted
+        let size = if self.has_quota() {
+            self.read_object_info(&handle.object.key)?
+                .map(|info| info.size)
+        } else {
+            None
+        };
         // Delete metadata before data, otherwise object could be concurrently reopened,
         // rewritten, and deleted with a live handle.
         self.update_object_info(&handle.object.key[..], &MetaMessage::delete())?;
         let (start, end) = handle.object.metadata_bounds();
         let meta_delete = SlicedCowBytes::from(meta::delete_custom());
         for (k, _v) in self.metadata.range(start..end)?.flatten() {
-            let _ = self.metadata.insert_msg(k, meta_delete.clone());
+            // The custom metadata range of the empty key covers the reference
+            // counts of a deduplicating store.
+            if !meta::is_refcount_key(&k) {
+                let _ = self.metadata.insert_msg(k, meta_delete.clone());
+            }
         }
 
-        self.data.range_delete(
-            &object_chunk_key(handle.object.id, 0)[..]
-                ..&object_chunk_key(handle.object.id, u32::MAX)[..],
-        )?;
+        self.delete_chunks(handle.object.id)?;
+        if let Some(size) = size {
+            self.release_quota(size);
+        }
 
         Ok(())
     }
@@ -629,7 +678,7 @@ impl<'os> ObjectStore {
             })
             .filter(|(key, _value)| meta::is_fixed_key(key))
             .map(move |(key, value)| {
-                let info = ObjectInfo::read_from_buffer_with_ctx(meta::ENDIAN, &value).unwrap();
+                let info = ObjectInfo::unpack(&value);
                 (
                     ObjectHandle {
                         store: self,
@@ -648,9 +697,7 @@ impl<'os> ObjectStore {
 
     fn read_object_info(&'os self, key: &[u8]) -> Result<Option<ObjectInfo>> {
         if let Some(meta) = self.metadata.get(key)? {
-            Ok(Some(
-                ObjectInfo::read_from_buffer_with_ctx(meta::ENDIAN, &meta).unwrap(),
-            ))
+            Ok(Some(ObjectInfo::unpack(&meta)))
         } else {
             Ok(None)
         }
@@ -661,6 +708,24 @@ impl<'os> ObjectStore {
             .insert_msg_with_pref(key, info.pack().into(), StoragePreference::NONE)
     }
 
+    /// Makes all modifications of the objects in this store durable, like
+    /// [Database::sync], but only writes back its data and meta trees and
+    /// returns the generation which has been committed.  A store does not
+    /// keep the database it has been opened from, which has to be passed in,
+    /// as the commit needs exclusive access to it.
+    ///
+    /// Modifications of other datasets stay in memory and are not recovered
+    /// with this generation.  Space allocated for them in the meantime stays
+    /// in use after a crash until the pool is repaired, see
+    /// [Database::open_with_repair].
+    ///
+    /// Journaled writes, see [ObjectStore::enable_deduplication], are applied
+    /// to the chunks of their objects before.
+    pub fn sync(&self, db: &mut Database) -> Result<Generation> {
+        self.fold_journals()?;
+        db.sync_datasets(&[self.data.id(), self.metadata.id()])
+    }
+
     #[allow(missing_docs)]
     #[cfg(feature = "internal-api")]
     pub fn data_tree(&self) -> &Dataset {
@@ -762,6 +827,22 @@ impl<'ds> ObjectHandle<'ds> {
         Ok(())
     }
 
+    /// Makes all modifications of this object durable like
+    /// [ObjectStore::sync], after flushing the messages of its chunks and
+    /// metadata still buffered in internal nodes down to the leaves, so that
+    /// reads do not have to apply them anymore.  The shared contents of a
+    /// deduplicating store are written back, but not flushed.
+    pub fn fsync(&self, db: &mut Database) -> Result<Generation> {
+        self.store.fold_journal(self.object.id)?;
+        let from = object_chunk_key(self.object.id, 0);
+        let to = object_chunk_key(self.object.id, u32::MAX);
+        self.store.data.flush_range(&from, &to)?;
+        self.store
+            .metadata
+            .flush_range(self.object.key(), &self.object.metadata_end())?;
+        self.store.sync(db)
+    }
+
     /// Delete this object
     pub fn delete(self) -> Result<()> {
         self.store.delete_object(&self)
@@ -782,6 +863,7 @@ impl<'ds> ObjectHandle<'ds> {
             .metadata
             .range(old_key..self.object.metadata_end())?
             .flatten()
+            .filter(|(k, _v)| !meta::is_refcount_key(k))
         {
             if meta::is_fixed_key(&k) {
                 self.store
@@ -806,29 +888,77 @@ impl<'ds> ObjectHandle<'ds> {
         Ok(())
     }
 
+    /// Creates a copy of this object with its data and custom metadata under `new_key`, which
+    /// must not be in use yet.
+    ///
+    /// In a deduplicating store, see [ObjectStore::enable_deduplication], the copy shares all
+    /// chunks with this object and only their reference counts are increased.  Shared chunks are
+    /// copied on write, and reclaimed by [ObjectStore::collect_orphaned_chunks] once no object
+    /// refers to them anymore.  In other stores, the data is copied.
+    pub fn copy_to(&self, new_key: &[u8]) -> Result<ObjectHandle<'ds>> {
+        if self.store.read_object_info(new_key)?.is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        let info = self.info()?.ok_or(Error::DoesNotExist)?;
+        self.store.charge_quota(0, info.size)?;
+        let copy = self.copy_data_to(new_key, &info);
+        if copy.is_err() {
+            // The size of the copy is recorded last, so it has not grown.
+            self.store.release_quota(info.size);
+        }
+        copy
+    }
+
+    fn copy_data_to(&self, new_key: &[u8], info: &ObjectInfo) -> Result<ObjectHandle<'ds>> {
+        let (copy, _) = self.store.init_object_with_pref_and_access_type(
+            new_key,
+            self.object.storage_preference,
+            info.access_pattern,
+        )?;
+        self.store.fold_journal(self.object.id)?;
+        self.store
+            .copy_chunks(self.object.id, copy.object.id, info.pref)?;
+        for entry in self.iter_metadata()? {
+            let (name, value) = entry?;
+            copy.set_metadata(&name, &value)?;
+        }
+        let meta_change = MetaMessage {
+            size: Some(info.size),
+            pref: Some(info.pref),
+            ..MetaMessage::default()
+        };
+        self.store.update_object_info(new_key, &meta_change)?;
+        Ok(copy)
+    }
+
     /// Read object data into `buf`, starting at offset `offset`, and returning the amount of
     /// actually read bytes.
     pub fn read_at(&self, mut buf: &mut [u8], offset: u64) -> result::Result<u64, (u64, Error)> {
         let mut total_read = 0;
 
+        let info = self.info().map_err(|err| (total_read, err))?;
+        if let Some(info) = &info {
+            self.record_access(info).map_err(|err| (total_read, err))?;
+        }
+
         // Sparse object data below object size is zero-filled
-        let obj_size = self
-            .info()
-            .map_err(|err| (total_read, err))?
-            .map(|info| info.size)
-            .unwrap_or(0);
+        let obj_size = info.map(|info| info.size).unwrap_or(0);
 
         let remaining_data = obj_size.saturating_sub(offset);
         let to_be_read = (buf.len() as u64).min(remaining_data);
-        let chunk_range = ChunkRange::from_byte_bounds(offset, to_be_read);
+        // The chunk boundaries of content-defined stores change with journaled writes.
+        self.store
+            .fold_journal(self.object.id)
+            .map_err(|e| (total_read, e))?;
+        let chunks = self
+            .byte_range_to_chunks(offset, to_be_read)
+            .map_err(|e| (total_read, e))?;
 
         let start = Instant::now();
 
         let mut last_offset = offset;
 
-        let chunks = self
-            .read_chunk_range(chunk_range.start.chunk_id..chunk_range.end.chunk_id)
-            .map_err(|e| (total_read, e))?;
+        let chunks = self.read_chunk_range(chunks).map_err(|e| (total_read, e))?;
 
         for chunk in chunks {
             let chunk = chunk.map_err(|e| (total_read, e))?;
@@ -879,8 +1009,46 @@ impl<'ds> ObjectHandle<'ds> {
         Ok(total_read)
     }
 
+    /// Returns the chunks holding `len` bytes from `offset` on, as passed to
+    /// [Self::read_chunk_range], which includes the end of the range.
+    fn byte_range_to_chunks(&self, offset: u64, len: u64) -> Result<Range<u32>> {
+        if self.store.chunking().is_none() {
+            let chunk_range = ChunkRange::from_byte_bounds(offset, len);
+            return Ok(chunk_range.start.chunk_id..chunk_range.end.chunk_id);
+        }
+        let id = self.object.id;
+        let (first, _) = self.store.find_chunk(id, offset.saturating_add(1))?;
+        let (last, _) = self.store.find_chunk(id, offset.saturating_add(len))?;
+        Ok(first..last.max(first))
+    }
+
+    /// Updates the access time of this object if tracking is enabled and the
+    /// last recorded access is at least one interval old.
+    fn record_access(&self, info: &ObjectInfo) -> Result<()> {
+        let interval = match self.store.access_time_interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        let now = SystemTime::now();
+        let stale = info.atime.map_or(true, |atime| {
+            now.duration_since(atime)
+                .map_or(false, |elapsed| elapsed >= interval)
+        });
+        if stale {
+            let meta_change = MetaMessage {
+                atime: Some(now),
+                ..MetaMessage::default()
+            };
+            self.store
+                .update_object_info(&self.object.key, &meta_change)?;
+        }
+        Ok(())
+    }
+
     /// Read this object in chunk-aligned blocks. The iterator will contain any existing chunks
     /// within `chunk_range`, and specify the address range of each returned chunk in bytes.
+    /// Chunks of content-defined stores, see [ObjectStore::enable_content_defined_chunking],
+    /// are numbered consecutively.
     ///
     /// For sparse objects, this will not include unallocated chunks,
     /// and partially written chunks are not zero-filled.
@@ -890,21 +1058,41 @@ impl<'ds> ObjectHandle<'ds> {
     ) -> Result<impl Iterator<Item = Result<(Range<u64>, SlicedCowBytes)>>> {
         // FIXME: This is incorrect, correctly we shoud measure how long each individual fetch takes
         let start = Instant::now();
-        let iter = self.store.data.range(
-            &object_chunk_key(self.object.id, chunk_range.start)[..]
-                ..=&object_chunk_key(self.object.id, chunk_range.end),
-        )?;
+        self.store.fold_journal(self.object.id)?;
+        let start_key = object_chunk_key(self.object.id, chunk_range.start);
+        let end_key = object_chunk_key(self.object.id, chunk_range.end);
+        if chunk_range.end > chunk_range.start {
+            // Fetch all leaves holding the requested chunks concurrently,
+            // instead of one after another while iterating.
+            self.store.data.prefetch_range(&start_key, &end_key)?;
+        }
+        let iter = self.store.data.range(&start_key[..]..=&end_key[..])?;
+        let shared = self
+            .store
+            .is_deduplicated()
+            .then(|| self.store.data.clone());
+        let content_defined = self.store.chunking().is_some();
 
-        let with_chunks = iter.map(|res| match res {
+        let with_chunks = iter.map(move |res| match res {
             Ok((k, v)) => {
-                let k: &[u8; 8 + 4] = &k[..].try_into().expect("Invalid key length");
-                let (_oid, chunk) = decode_object_chunk_key(k);
-                let chunk = ChunkOffset {
-                    chunk_id: chunk,
-                    offset: 0,
+                let end = content_defined.then(|| cdc::chunk_end(&v));
+                let v = match &shared {
+                    Some(data) => dedup::resolve_chunk(data, &v)?,
+                    None => v,
+                };
+                let range = match end {
+                    Some(end) => end - v.len() as u64..end,
+                    None => {
+                        let k: &[u8; 8 + 4] = &k[..].try_into().expect("Invalid key length");
+                        let (_oid, chunk) = decode_object_chunk_key(k);
+                        let chunk = ChunkOffset {
+                            chunk_id: chunk,
+                            offset: 0,
+                        };
+                        let byte_offset = chunk.as_bytes();
+                        byte_offset..byte_offset + v.len() as u64
+                    }
                 };
-                let byte_offset = chunk.as_bytes();
-                let range = byte_offset..byte_offset + v.len() as u64;
                 Ok((range, v))
             }
             Err(e) => Err(e),
@@ -950,31 +1138,85 @@ impl<'ds> ObjectHandle<'ds> {
         let mut total_written = 0;
         log::trace!("Entered object::write_at_with_pref");
 
-        let start = Instant::now();
-        for chunk in chunk_range.split_at_chunk_bounds() {
-            let len = chunk.single_chunk_len() as usize;
-            let key = object_chunk_key(self.object.id, chunk.start.chunk_id);
+        let mut charged = None;
+        if self.store.has_quota() && !buf.is_empty() {
+            let size = self
+                .info()
+                .map_err(|err| (0, err))?
+                .map_or(0, |info| info.size);
+            let end = offset + buf.len() as u64;
+            self.store.charge_quota(size, end).map_err(|err| (0, err))?;
+            charged = Some((size, end));
+        }
+        // Refunds the charged growth beyond the size recorded for the object.
+        let refund = |reached: Option<u64>| {
+            if let Some((size, end)) = charged {
+                self.store.refund_quota(size, end, reached.unwrap_or(0));
+            }
+        };
 
-            self.store
-                .data
-                .upsert_with_pref(&key[..], &buf[..len], chunk.start.offset, storage_pref)
-                .map_err(|err| {
-                    // best-effort metadata update
-                    // this is called only when the original upsert errored,
-                    // there's not much we can do to handle an error during error handling
+        let start = Instant::now();
+        let journaled =
+            !buf.is_empty() && self.store.is_journaled() && buf.len() < JOURNAL_MAX_WRITE;
+        if !journaled {
+            // Journaled writes must not overwrite this one when they are applied later.
+            if let Err(err) = self.store.fold_journal(self.object.id) {
+                refund(None);
+                return Err((0, err));
+            }
+        }
+        if journaled || self.store.chunking().is_some() {
+            if !buf.is_empty() {
+                let written = if journaled {
+                    self.store
+                        .journal_write(self.object.id, offset, buf, storage_pref)
+                } else {
+                    self.store
+                        .write_content_defined(self.object.id, offset, buf, storage_pref)
+                };
+                if let Err(err) = written {
+                    // best-effort metadata update, as for fixed-size chunks
                     meta_change.mtime = Some(SystemTime::now());
                     let _ = self
                         .store
                         .update_object_info(&self.object.key, &meta_change);
-                    (total_written, err)
-                })?;
-            buf = &buf[len..];
-
-            total_written += len as u64;
+                    refund(None);
+                    return Err((0, err));
+                }
+                total_written = buf.len() as u64;
+                meta_change.size = Some(offset + total_written);
+            }
+        } else {
+            for chunk in chunk_range.split_at_chunk_bounds() {
+                let len = chunk.single_chunk_len() as usize;
 
-            // Can overwrite without checking previous value, offsets monotically increase during
-            // a single write_at invocation, and message merging combines sizes by max.
-            meta_change.size = Some(chunk.end.as_bytes());
+                self.store
+                    .write_chunk(
+                        self.object.id,
+                        chunk.start.chunk_id,
+                        chunk.start.offset,
+                        &buf[..len],
+                        storage_pref,
+                    )
+                    .map_err(|err| {
+                        // best-effort metadata update
+                        // this is called only when the original upsert errored,
+                        // there's not much we can do to handle an error during error handling
+                        meta_change.mtime = Some(SystemTime::now());
+                        let _ = self
+                            .store
+                            .update_object_info(&self.object.key, &meta_change);
+                        refund(meta_change.size);
+                        (total_written, err)
+                    })?;
+                buf = &buf[len..];
+
+                total_written += len as u64;
+
+                // Can overwrite without checking previous value, offsets monotically increase
+                // during a single write_at invocation, and message merging combines sizes by max.
+                meta_change.size = Some(chunk.end.as_bytes());
+            }
         }
 
         if let (Some(tx), Some(size)) = (&self.store.report, meta_change.size) {
@@ -992,7 +1234,10 @@ impl<'ds> ObjectHandle<'ds> {
         self.store
             .update_object_info(&self.object.key, &meta_change)
             .map(|()| total_written)
-            .map_err(|err| (total_written, err))
+            .map_err(|err| {
+                refund(None);
+                (total_written, err)
+            })
     }
 
     /// Write `buf.len()` bytes from `buf` to this objects data, starting at offset `offset`.
@@ -1012,6 +1257,42 @@ impl<'ds> ObjectHandle<'ds> {
         self.store.read_object_info(&self.object.key)
     }
 
+    /// Returns the [XxHash] of the object contents, as if they were read from
+    /// offset zero to its size, with unallocated ranges read as zeros.  This
+    /// allows to compare the object to a copy held by the application or to
+    /// other objects without transferring its contents.
+    ///
+    /// Ok(None) is only returned if the object was deleted concurrently.
+    pub fn content_hash(&self) -> Result<Option<XxHash>> {
+        let size = match self.info()? {
+            Some(info) => info.size,
+            None => return Ok(None),
+        };
+        fn ingest_zeros<S: State>(state: &mut S, mut len: u64) {
+            const ZEROS: [u8; 4096] = [0; 4096];
+            while len > 0 {
+                let step = len.min(ZEROS.len() as u64);
+                state.ingest(&ZEROS[..step as usize]);
+                len -= step;
+            }
+        }
+
+        let mut state = XxHash::builder().build();
+        let mut pos = 0;
+        for chunk in self.read_all_chunks()? {
+            let (range, data) = chunk?;
+            if range.start >= size {
+                break;
+            }
+            ingest_zeros(&mut state, range.start - pos);
+            let end = range.end.min(size);
+            state.ingest(&data[..(end - range.start) as usize]);
+            pos = end;
+        }
+        ingest_zeros(&mut state, size - pos);
+        Ok(Some(state.finish()))
+    }
+
     pub fn get_metadata(&self, name: &[u8]) -> Result<Option<SlicedCowBytes>> {
         if name.contains(&0) {
             return Err(Error::KeyContainsNullByte);
@@ -1053,6 +1334,7 @@ impl<'ds> ObjectHandle<'ds> {
             .store
             .metadata
             .range(start..end)?
+            .filter(|res| !matches!(res, Ok((k, _v)) if meta::is_refcount_key(k)))
             // strip key prefix, leave only metadata entry name
             .map(move |res| res.map(|(k, v)| (k.slice_from(prefix_len as u32), v)));
 
@@ -1128,15 +1410,25 @@ impl<'ds> ObjectHandle<'ds> {
         }
     }
 
-    /// Migrate a range of chunks to a new storage preference
+    /// Migrate a range of chunks to a new storage preference. Chunks which are
+    /// only partially covered by the range are migrated as a whole.
     pub fn migrate_range(&self, length: u64, offset: u64, pref: StoragePreference) -> Result<()> {
+        self.store.fold_journal(self.object.id)?;
         let chunk_range = ChunkRange::from_byte_bounds(offset, length);
-
-        self.store.data.migrate_range(
-            &object_chunk_key(self.object.id, chunk_range.start.chunk_id)[..]
-                ..&object_chunk_key(self.object.id, chunk_range.end.chunk_id),
-            pref,
-        )?;
+        let start = object_chunk_key(self.object.id, chunk_range.start.chunk_id);
+        let end = object_chunk_key(self.object.id, chunk_range.end.chunk_id);
+
+        if self.store.chunking().is_some() {
+            let chunks = self.byte_range_to_chunks(offset, length)?;
+            let start = object_chunk_key(self.object.id, chunks.start);
+            let end = object_chunk_key(self.object.id, chunks.end);
+            self.store.data.migrate_range(&start[..]..=&end[..], pref)?;
+        } else if chunk_range.end.offset == 0 {
+            self.store.data.migrate_range(&start[..]..&end[..], pref)?;
+        } else {
+            // The range ends within the last chunk, which has to be moved too.
+            self.store.data.migrate_range(&start[..]..=&end[..], pref)?;
+        }
         if let Some(tx) = &self.store.report {
             let _ = tx
                 .send(DatabaseMsg::ObjectMigrate(
@@ -0,0 +1,85 @@
+//! Where the chunks of objects reside, see [ObjectStore::objects_on_class].
+//!
+//! The storage class of a chunk is the one of the leaf holding it, which is
+//! read from the pointer in its parent node, so that the leaves themselves
+//! need not be fetched.  In a deduplicating store, the class of the leaf
+//! holding the shared contents is used instead.
+
+use super::{dedup, object_chunk_key, ObjectHandle, ObjectId, ObjectStore};
+use crate::{cow_bytes::CowBytes, database::Result};
+
+/// The chunks of an object residing on a storage class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Residency {
+    /// The number of chunks of the object.
+    pub chunks: u32,
+    /// The number of these chunks residing on the storage class.
+    pub resident: u32,
+}
+
+impl Residency {
+    /// Returns the fraction of chunks residing on the storage class, zero for
+    /// objects without chunks.
+    pub fn fraction(&self) -> f64 {
+        self.resident as f64 / self.chunks.max(1) as f64
+    }
+}
+
+impl<'os> ObjectStore {
+    /// Returns the keys of all objects with at least one chunk residing on
+    /// the storage class `class`, with how many of their chunks do.
+    ///
+    /// Chunks of leaves which have been modified since the last sync have no
+    /// storage class yet and never count as resident, while chunks still
+    /// buffered in internal nodes count for the leaf they belong to.  After a
+    /// sync, this shows where migrations have moved objects to.
+    pub fn objects_on_class(&'os self, class: u8) -> Result<Vec<(CowBytes, Residency)>> {
+        let mut objects = Vec::new();
+        for (handle, info) in self.list_objects::<_, &[u8]>(..)? {
+            let residency = self.residency(info.object_id, class)?;
+            if residency.resident > 0 {
+                objects.push((CowBytes::from(handle.object.key()), residency));
+            }
+        }
+        Ok(objects)
+    }
+
+    fn residency(&'os self, object_id: ObjectId, class: u8) -> Result<Residency> {
+        let classes = self.chunk_classes(object_id)?;
+        Ok(Residency {
+            chunks: classes.len() as u32,
+            resident: classes.iter().filter(|c| **c == Some(class)).count() as u32,
+        })
+    }
+
+    /// Returns the storage class of each chunk of an object in order, see
+    /// [Self::objects_on_class].
+    fn chunk_classes(&'os self, object_id: ObjectId) -> Result<Vec<Option<u8>>> {
+        let from = object_chunk_key(object_id, 0);
+        let to = object_chunk_key(object_id, u32::MAX);
+        let leaves = self.data.leaf_classes(&from, &to)?;
+        let mut leaf = 0;
+        let mut classes = Vec::new();
+        for res in self.data.range(&from[..]..=&to[..])? {
+            let (key, reference) = res?;
+            if self.is_deduplicated() {
+                classes.push(dedup::content_class(&self.data, &reference)?);
+                continue;
+            }
+            // The last leaf in the range ends at or after `to`.
+            while matches!(&leaves[leaf].0, Some(last_key) if last_key[..] < key[..]) {
+                leaf += 1;
+            }
+            classes.push(leaves[leaf].1);
+        }
+        Ok(classes)
+    }
+}
+
+impl<'ds> ObjectHandle<'ds> {
+    /// Returns how many chunks of this object reside on the storage class
+    /// `class`, see [ObjectStore::objects_on_class].
+    pub fn residency(&self, class: u8) -> Result<Residency> {
+        self.store.residency(self.object.id, class)
+    }
+}
@@ -0,0 +1,200 @@
+//! Usage reporting and quotas of object stores, see [ObjectStore::usage] and
+//! [ObjectStore::set_quota].
+//!
+//! The quota is kept in the data tree:
+//!
+//! ```text
+//! [0]"quota" -> [64-bit unsigned big-endian limit of logical bytes]
+//! ```
+//!
+//! The logical bytes in use are not stored, but summed up from the object
+//! sizes when the store is opened with a quota, and maintained in memory from
+//! then on.
+
+use super::{dedup, meta, object_chunk_key, ObjectId, ObjectInfo, ObjectStore};
+use crate::database::{Error, Result};
+use std::{collections::HashSet, convert::TryInto};
+
+const QUOTA_KEY: &[u8] = b"\0quota";
+
+/// The space used by the objects of a store or of a key prefix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The number of objects.
+    pub objects: u64,
+    /// The sum of the object sizes.  Sparse ranges of objects are included.
+    pub logical_bytes: u64,
+    /// The bytes of chunk data kept for the objects, after deduplication.
+    /// This is not the physical space they occupy, which also depends on
+    /// compression and on the nodes the chunks are packed into.
+    pub chunk_bytes: u64,
+}
+
+/// The limit of logical bytes of a store, and the bytes currently charged
+/// against it.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Quota {
+    limit: u64,
+    used: u64,
+}
+
+impl Quota {
+    /// Returns the stored quota of `store`.
+    pub(super) fn load(store: &ObjectStore) -> Result<Option<Quota>> {
+        let limit = match store.data.get(QUOTA_KEY)? {
+            Some(value) => u64::from_be_bytes(value[..].try_into().unwrap()),
+            None => return Ok(None),
+        };
+        Ok(Some(Quota {
+            limit,
+            used: store.logical_bytes()?,
+        }))
+    }
+}
+
+impl<'os> ObjectStore {
+    /// Returns the space used by all objects in this store.  The chunk bytes
+    /// include chunks of deleted objects and, in a deduplicating store, shared
+    /// contents which have not been collected yet, see
+    /// [Self::collect_orphaned_chunks].
+    ///
+    /// This scans the metadata and the chunks of the whole store.
+    pub fn usage(&'os self) -> Result<Usage> {
+        let mut usage = Usage::default();
+        for info in self.object_infos(&[])? {
+            usage.objects += 1;
+            usage.logical_bytes += info?.size;
+        }
+        if self.is_deduplicated() {
+            usage.chunk_bytes = self.shared_contents_size()?;
+        } else {
+            let from = object_chunk_key(ObjectId(0), 0);
+            for res in self.data.range(&from[..]..)? {
+                let (key, value) = res?;
+                // skip the object id counter and other store settings
+                if key.len() == 8 + 4 {
+                    usage.chunk_bytes += value.len() as u64;
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Returns the space used by the objects whose keys start with `prefix`,
+    /// e.g. the objects of a bucket if keys are of the form `bucket/name`.
+    ///
+    /// In a deduplicating store, shared contents are counted once for the
+    /// prefix, even if they are referenced by other objects outside of it.
+    pub fn usage_of_prefix(&'os self, prefix: &[u8]) -> Result<Usage> {
+        let mut usage = Usage::default();
+        let mut contents = HashSet::new();
+        for info in self.object_infos(prefix)? {
+            let info = info?;
+            usage.objects += 1;
+            usage.logical_bytes += info.size;
+            let from = object_chunk_key(info.object_id, 0);
+            let to = object_chunk_key(info.object_id, u32::MAX);
+            for res in self.data.range(&from[..]..=&to[..])? {
+                let (_, value) = res?;
+                usage.chunk_bytes += if self.is_deduplicated() {
+                    if !contents.insert(dedup::content_ref(&value).to_vec()) {
+                        continue;
+                    }
+                    dedup::resolve_chunk(&self.data, &value)?.len() as u64
+                } else {
+                    value.len() as u64
+                };
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Limits the sum of the object sizes in this store to `limit` bytes, or
+    /// removes the limit with `None`.  Writes which would grow objects beyond
+    /// the limit fail with [Error::QuotaExceeded] without writing any data.
+    /// Objects which already exceed a new limit are kept, but cannot grow.
+    ///
+    /// The quota persists until it is removed.  The bytes charged against it
+    /// are shared by clones of this instance, but not by other instances of
+    /// the same store.  Concurrent writes extending the same object may each
+    /// be charged for the growth, which overestimates the usage until the
+    /// store is opened again.
+    pub fn set_quota(&'os self, limit: Option<u64>) -> Result<()> {
+        let mut quota = self.quota.lock();
+        match limit {
+            Some(limit) => {
+                self.data.insert(QUOTA_KEY, &limit.to_be_bytes())?;
+                let used = match *quota {
+                    Some(Quota { used, .. }) => used,
+                    None => self.logical_bytes()?,
+                };
+                *quota = Some(Quota { limit, used });
+            }
+            None => {
+                self.data.delete(QUOTA_KEY)?;
+                *quota = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the quota of this store and the logical bytes charged against
+    /// it, see [Self::set_quota].
+    pub fn quota(&self) -> Option<(u64, u64)> {
+        self.quota.lock().map(|quota| (quota.limit, quota.used))
+    }
+
+    /// Charges the growth of an object of `size` bytes to `end` bytes against
+    /// the quota, if there is one.
+    pub(super) fn charge_quota(&self, size: u64, end: u64) -> Result<()> {
+        if let Some(quota) = self.quota.lock().as_mut() {
+            let growth = end.saturating_sub(size);
+            if growth > 0 && quota.used + growth > quota.limit {
+                return Err(Error::QuotaExceeded {
+                    quota: quota.limit,
+                    used: quota.used,
+                });
+            }
+            quota.used += growth;
+        }
+        Ok(())
+    }
+
+    /// Returns the growth charged by [Self::charge_quota] for an object of
+    /// `size` bytes to `end` bytes, as far as the object has not reached it.
+    pub(super) fn refund_quota(&self, size: u64, end: u64, reached: u64) {
+        self.release_quota(end.saturating_sub(size.max(reached)));
+    }
+
+    /// Returns the bytes of an object of `size` bytes to the quota, if there
+    /// is one.
+    pub(super) fn release_quota(&self, size: u64) {
+        if let Some(quota) = self.quota.lock().as_mut() {
+            quota.used = quota.used.saturating_sub(size);
+        }
+    }
+
+    /// Returns whether writes have to be charged against a quota.
+    pub(super) fn has_quota(&self) -> bool {
+        self.quota.lock().is_some()
+    }
+
+    fn logical_bytes(&'os self) -> Result<u64> {
+        let mut sum = 0;
+        for info in self.object_infos(&[])? {
+            sum += info?.size;
+        }
+        Ok(sum)
+    }
+
+    /// Iterates over the infos of all objects whose keys start with `prefix`.
+    fn object_infos(&'os self, prefix: &[u8]) -> Result<impl Iterator<Item = Result<ObjectInfo>>> {
+        let prefix = prefix.to_vec();
+        Ok(self
+            .metadata
+            .range(prefix.clone()..)?
+            .take_while(move |res| !matches!(res, Ok((k, _)) if !k.starts_with(&prefix)))
+            .filter(|res| !matches!(res, Ok((k, _)) if !meta::is_fixed_key(k)))
+            .map(|res| res.map(|(_, v)| ObjectInfo::unpack(&v))))
+    }
+}
@@ -2,7 +2,7 @@
 #[cfg(feature = "nvm")]
 use pmdk;
 
-use crate::vdev::{self, Dev, Leaf};
+use crate::vdev::{self, Dev, HugePageSize, Leaf};
 use itertools::Itertools;
 use libc;
 use serde::{Deserialize, Serialize};
@@ -63,6 +63,26 @@ impl Default for PreferredAccessType {
     }
 }
 
+/// Whether data written to a storage class is protected by checksums.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    /// Nodes are checksummed when written and verified when read.
+    Full,
+    /// Nodes are neither checksummed nor verified, which saves latency on
+    /// byte-addressable classes like memory or persistent memory, where
+    /// hashing makes up a large part of each access.  Corrupted data is not
+    /// detected, and mirror and parity1 vdevs can not repair it.  Nodes keep
+    /// the policy under which they have been written, until they are
+    /// rewritten.
+    Disabled,
+}
+
+impl Default for ChecksumPolicy {
+    fn default() -> Self {
+        ChecksumPolicy::Full
+    }
+}
+
 /// Configuration of a single storage class.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TierConfiguration {
@@ -71,6 +91,33 @@ pub struct TierConfiguration {
     /// Which storage access is preferred to be used with this tier. See
     /// [PreferredAccessType] for all variants.
     pub preferred_access_type: PreferredAccessType,
+    /// Whether nodes written to this tier are checksummed.
+    #[serde(default)]
+    pub checksum_policy: ChecksumPolicy,
+    /// The number of bytes which may be written to this tier per day, to
+    /// spread the limited write endurance of e.g. persistent memory or flash
+    /// over its lifetime.  Once the budget of a day has been used up, writes
+    /// go to other tiers where possible, see
+    /// [Dmu::with_write_budgets](crate::data_management::Dmu::with_write_budgets).
+    #[serde(default)]
+    pub write_budget: Option<u64>,
+}
+
+/// How to react to redundant vdevs whose leaves share a failure domain.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum FailureDomainPolicy {
+    /// Failure domains are not checked.
+    Ignore,
+    /// A warning is logged for each shared failure domain.
+    Warn,
+    /// The storage pool refuses to start if a failure domain is shared.
+    Refuse,
+}
+
+impl Default for FailureDomainPolicy {
+    fn default() -> Self {
+        FailureDomainPolicy::Warn
+    }
 }
 
 /// Configuration for the storage pool unit.
@@ -85,6 +132,12 @@ pub struct StoragePoolConfiguration {
     pub thread_pool_size: Option<u32>,
     /// Whether to pin each worker thread to a CPU core
     pub thread_pool_pinned: bool,
+    /// Whether mirror and parity1 vdevs may contain multiple leaves of the
+    /// same failure domain
+    pub failure_domain_policy: FailureDomainPolicy,
+    /// Whether to open files and devices read-only and without locking them,
+    /// see [AccessMode::ReadOnly](crate::database::AccessMode::ReadOnly)
+    pub read_only: bool,
 }
 
 impl Default for StoragePoolConfiguration {
@@ -94,7 +147,35 @@ impl Default for StoragePoolConfiguration {
             queue_depth_factor: 20,
             thread_pool_size: None,
             thread_pool_pinned: false,
+            failure_domain_policy: FailureDomainPolicy::default(),
+            read_only: false,
+        }
+    }
+}
+
+impl StoragePoolConfiguration {
+    /// Checks that no mirror or parity1 vdev contains multiple leaves of the
+    /// same failure domain, as a single failure could then take out more than
+    /// one leaf at once. The result depends on the configured
+    /// [FailureDomainPolicy].
+    pub fn check_failure_domains(&self) -> Result<()> {
+        if self.failure_domain_policy == FailureDomainPolicy::Ignore {
+            return Ok(());
+        }
+        for (tier_id, tier) in self.tiers.iter().enumerate() {
+            for (vdev_id, domain) in tier.shared_failure_domains() {
+                match self.failure_domain_policy {
+                    FailureDomainPolicy::Ignore => unreachable!(),
+                    FailureDomainPolicy::Warn => warn!(
+                        "Vdev {vdev_id} of tier {tier_id} has multiple leaves in failure domain {domain:?}"
+                    ),
+                    FailureDomainPolicy::Refuse => {
+                        bail!(ErrorKind::SharedFailureDomain(tier_id, vdev_id, domain))
+                    }
+                }
+            }
         }
+        Ok(())
     }
 }
 
@@ -136,18 +217,48 @@ pub enum LeafVdev {
         path: PathBuf,
         /// Whether to use direct IO for this file. Defaults to true.
         direct: Option<bool>,
+        /// Failure domain of this device, e.g. the controller or enclosure it
+        /// is attached to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        failure_domain: Option<String>,
     },
     /// Backed by a memory buffer.
     Memory {
         /// Size of memory vdev in bytes.
         mem: usize,
     },
+    /// Customisable memory vdev.
+    MemoryWithOpts {
+        /// Size of memory vdev in bytes.
+        mem: usize,
+        /// Allocate the buffer from explicit hugepages of this size instead
+        /// of the heap. The pages have to be reserved beforehand, e.g. via
+        /// `/sys/kernel/mm/hugepages`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hugepages: Option<HugePageSize>,
+        /// Snapshot the contents to this file when the vdev is dropped and
+        /// restore them from it on open, see
+        /// [Memory::persist_to](crate::vdev::Memory::persist_to).  Read-only
+        /// pools only restore them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        persist: Option<PathBuf>,
+    },
 }
 
 error_chain! {
     errors {
         #[allow(missing_docs)]
         InvalidKeyword
+        #[allow(missing_docs)]
+        SharedFailureDomain(tier: usize, vdev: usize, domain: String) {
+            description("multiple leaves of a redundant vdev share a failure domain")
+            display("vdev {} of tier {} has multiple leaves in failure domain {:?}", vdev, tier, domain)
+        }
+        #[allow(missing_docs)]
+        VdevTooLarge(tier: usize, vdev: usize, blocks: u64) {
+            description("a vdev is larger than disk offsets can address")
+            display("vdev {} of tier {} has {} blocks, but at most {} are supported", vdev, tier, blocks, super::MAX_DISK_BLOCKS)
+        }
     }
 }
 
@@ -157,15 +268,17 @@ impl TierConfiguration {
         TierConfiguration {
             top_level_vdevs,
             preferred_access_type: PreferredAccessType::Unknown,
+            checksum_policy: ChecksumPolicy::Full,
+            write_budget: None,
         }
     }
 
     /// Opens file and devices and constructs a `Vec<Vdev>`.
-    pub(crate) fn build(&self) -> io::Result<Vec<Dev>> {
+    pub(crate) fn build(&self, read_only: bool) -> io::Result<Vec<Dev>> {
         self.top_level_vdevs
             .iter()
             .enumerate()
-            .map(|(n, v)| v.build(n))
+            .map(|(n, v)| v.build(n, read_only))
             .collect()
     }
 
@@ -207,9 +320,28 @@ impl TierConfiguration {
         Ok(TierConfiguration {
             top_level_vdevs: v,
             preferred_access_type: PreferredAccessType::Unknown,
+            checksum_policy: ChecksumPolicy::Full,
+            write_budget: None,
         })
     }
 
+    /// Returns the index of each mirror or parity1 vdev together with every
+    /// failure domain which is shared by multiple of its leaves.
+    pub fn shared_failure_domains(&self) -> Vec<(usize, String)> {
+        let mut shared = Vec::new();
+        for (n, vdev) in self.top_level_vdevs.iter().enumerate() {
+            let leaves = match vdev {
+                Vdev::Leaf(_) => continue,
+                Vdev::Mirror { mirror: leaves } | Vdev::Parity1 { parity1: leaves } => leaves,
+            };
+            let domains = leaves.iter().filter_map(LeafVdev::failure_domain);
+            for domain in domains.duplicates() {
+                shared.push((n, domain.to_owned()));
+            }
+        }
+        shared
+    }
+
     /// Returns the configuration in a ZFS-like string representation.
     ///
     /// See `parse_zfs_like` for more information.
@@ -227,10 +359,12 @@ impl TierConfiguration {
             for leaf in leaves {
                 match leaf {
                     LeafVdev::File(path) => write!(s, "{} ", path.display()).unwrap(),
-                    LeafVdev::FileWithOpts { path, direct } => {
+                    LeafVdev::FileWithOpts { path, direct, .. } => {
                         write!(s, "{} (direct: {:?}) ", path.display(), direct).unwrap()
                     }
-                    LeafVdev::Memory { mem } => write!(s, "memory({mem}) ").unwrap(),
+                    LeafVdev::Memory { mem } | LeafVdev::MemoryWithOpts { mem, .. } => {
+                        write!(s, "memory({mem}) ").unwrap()
+                    }
                     #[cfg(feature = "nvm")]
                     LeafVdev::PMemFile { path, len } => {
                         write!(s, "{} {}", path.display(), len).unwrap()
@@ -252,16 +386,19 @@ impl FromIterator<Vdev> for TierConfiguration {
         TierConfiguration {
             top_level_vdevs: iter.into_iter().collect(),
             preferred_access_type: PreferredAccessType::Unknown,
+            checksum_policy: ChecksumPolicy::Full,
+            write_budget: None,
         }
     }
 }
 
 impl Vdev {
     /// Opens file and devices and constructs a `Vdev`.
-    fn build(&self, n: usize) -> io::Result<Dev> {
+    fn build(&self, n: usize, read_only: bool) -> io::Result<Dev> {
         match *self {
             Vdev::Mirror { mirror: ref vec } => {
-                let leaves: io::Result<Vec<Leaf>> = vec.iter().map(LeafVdev::build).collect();
+                let leaves: io::Result<Vec<Leaf>> =
+                    vec.iter().map(|leaf| leaf.build(read_only)).collect();
                 let leaves: Box<[Leaf]> = leaves?.into_boxed_slice();
                 Ok(Dev::Mirror(vdev::Mirror::new(
                     leaves,
@@ -269,39 +406,63 @@ impl Vdev {
                 )))
             }
             Vdev::Parity1 { parity1: ref vec } => {
-                let leaves: io::Result<Vec<_>> = vec.iter().map(LeafVdev::build).collect();
+                let leaves: io::Result<Vec<_>> =
+                    vec.iter().map(|leaf| leaf.build(read_only)).collect();
                 let leaves = leaves?.into_boxed_slice();
                 Ok(Dev::Parity1(vdev::Parity1::new(
                     leaves,
                     format!("parity-{n}"),
                 )))
             }
-            Vdev::Leaf(ref leaf) => leaf.build().map(Dev::Leaf),
+            Vdev::Leaf(ref leaf) => leaf.build(read_only).map(Dev::Leaf),
         }
     }
 }
 
 impl LeafVdev {
-    fn build(&self) -> io::Result<Leaf> {
+    /// Returns the failure domain of this leaf, if one is configured.
+    pub fn failure_domain(&self) -> Option<&str> {
+        match self {
+            LeafVdev::FileWithOpts { failure_domain, .. } => failure_domain.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn build(&self, read_only: bool) -> io::Result<Leaf> {
         use std::os::unix::fs::OpenOptionsExt;
 
         match *self {
             LeafVdev::File(_) | LeafVdev::FileWithOpts { .. } => {
                 let (path, direct) = match self {
                     LeafVdev::File(path) => (path, true),
-                    LeafVdev::FileWithOpts { path, direct } => (path, direct.unwrap_or(true)),
-                    LeafVdev::Memory { .. } => unreachable!(),
+                    LeafVdev::FileWithOpts { path, direct, .. } => (path, direct.unwrap_or(true)),
+                    LeafVdev::Memory { .. } | LeafVdev::MemoryWithOpts { .. } => unreachable!(),
                     #[cfg(feature = "nvm")]
                     LeafVdev::PMemFile { .. } => unreachable!(),
                 };
 
                 let mut file = OpenOptions::new();
-                file.read(true).write(true);
+                file.read(true).write(!read_only);
                 if direct {
                     file.custom_flags(libc::O_DIRECT);
                 }
                 let file = file.open(path)?;
 
+                // Guards against other processes on this host, which would
+                // overwrite each other's commits.  Readers do not interfere.
+                if !read_only
+                    && unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0
+                {
+                    let err = io::Error::last_os_error();
+                    return Err(match err.kind() {
+                        io::ErrorKind::WouldBlock => io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            format!("{} is in use by another process", path.display()),
+                        ),
+                        _ => err,
+                    });
+                }
+
                 if unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_RANDOM) }
                     != 0
                 {
@@ -317,12 +478,30 @@ impl LeafVdev {
                 mem,
                 format!("memory-{mem}"),
             )?)),
+            LeafVdev::MemoryWithOpts {
+                mem,
+                hugepages,
+                ref persist,
+            } => {
+                let id = format!("memory-{mem}");
+                let vdev = match hugepages {
+                    Some(page_size) => vdev::Memory::with_hugepages(mem, id, page_size)?,
+                    None => vdev::Memory::new(mem, id)?,
+                };
+                // Read-only openers may share the snapshot with a writer,
+                // which would be overwritten by theirs.
+                Ok(Leaf::Memory(match persist {
+                    Some(path) if read_only => vdev.restore_from(path)?,
+                    Some(path) => vdev.persist_to(path.clone())?,
+                    None => vdev,
+                }))
+            }
             #[cfg(feature = "nvm")]
             LeafVdev::PMemFile { .. } => {
                 let (path, len) = match self {
                     LeafVdev::File(path) => unreachable!(),
                     LeafVdev::FileWithOpts { .. } => unreachable!(),
-                    LeafVdev::Memory { .. } => unreachable!(),
+                    LeafVdev::Memory { .. } | LeafVdev::MemoryWithOpts { .. } => unreachable!(),
                     LeafVdev::PMemFile { path, len } => (path, len),
                 };
 
@@ -408,19 +587,41 @@ impl LeafVdev {
             LeafVdev::File(path) => {
                 writeln!(f, "{:indent$}{}", "", path.display(), indent = indent)
             }
-            LeafVdev::FileWithOpts { path, direct } => {
-                writeln!(
+            LeafVdev::FileWithOpts {
+                path,
+                direct,
+                failure_domain,
+            } => {
+                write!(
                     f,
-                    "{:indent$}{} (direct: {:?})",
+                    "{:indent$}{} (direct: {:?}",
                     "",
                     path.display(),
                     direct,
                     indent = indent
-                )
+                )?;
+                if let Some(domain) = failure_domain {
+                    write!(f, ", failure domain: {domain}")?;
+                }
+                writeln!(f, ")")
             }
             LeafVdev::Memory { mem } => {
                 writeln!(f, "{:indent$}memory({})", "", mem, indent = indent)
             }
+            LeafVdev::MemoryWithOpts {
+                mem,
+                hugepages,
+                persist,
+            } => {
+                write!(f, "{:indent$}memory({}", "", mem, indent = indent)?;
+                if let Some(page_size) = hugepages {
+                    write!(f, ", hugepages: {:?}", page_size)?;
+                }
+                if let Some(path) = persist {
+                    write!(f, ", persist: {}", path.display())?;
+                }
+                writeln!(f, ")")
+            }
             #[cfg(feature = "nvm")]
             LeafVdev::PMemFile { path, len: _ } => {
                 writeln!(f, "{:indent$}{}", "", path.display(), indent = indent)
@@ -428,3 +629,58 @@ impl LeafVdev {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(path: &str, failure_domain: Option<&str>) -> LeafVdev {
+        LeafVdev::FileWithOpts {
+            path: path.into(),
+            direct: None,
+            failure_domain: failure_domain.map(String::from),
+        }
+    }
+
+    #[test]
+    fn shared_failure_domains() {
+        let tier = TierConfiguration::from_iter([
+            Vdev::Mirror {
+                mirror: vec![leaf("/dev/sda", Some("a")), leaf("/dev/sdb", Some("b"))],
+            },
+            Vdev::Mirror {
+                mirror: vec![leaf("/dev/sdc", Some("a")), leaf("/dev/sdd", Some("a"))],
+            },
+            Vdev::Parity1 {
+                parity1: vec![
+                    leaf("/dev/sde", None),
+                    leaf("/dev/sdf", None),
+                    leaf("/dev/sdg", Some("b")),
+                ],
+            },
+        ]);
+        assert_eq!(tier.shared_failure_domains(), vec![(1, "a".to_owned())]);
+
+        let mut config = StoragePoolConfiguration {
+            tiers: vec![tier],
+            ..Default::default()
+        };
+        assert!(config.check_failure_domains().is_ok());
+        config.failure_domain_policy = FailureDomainPolicy::Refuse;
+        assert!(config.check_failure_domains().is_err());
+    }
+
+    #[test]
+    fn memory_with_hugepages() {
+        let leaf: LeafVdev =
+            serde_json::from_str(r#"{"mem": 4194304, "hugepages": "2M"}"#).unwrap();
+        assert!(matches!(
+            leaf,
+            LeafVdev::MemoryWithOpts {
+                mem: 4194304,
+                hugepages: Some(HugePageSize::Huge2M),
+                persist: None,
+            }
+        ));
+    }
+}
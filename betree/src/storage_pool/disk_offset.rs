@@ -2,15 +2,27 @@ use crate::{size::StaticSize, vdev::Block};
 use serde::{Deserialize, Serialize};
 use std::{fmt, mem};
 
-/// 2-bit storage class, 10-bit disk ID, 52-bit block offset (see
+/// 2-bit storage class, 14-bit disk ID, 48-bit block offset (see
 /// [`BLOCK_SIZE`](../vdev/constant.BLOCK_SIZE.html))
+///
+/// The lower 10 bits of the disk ID follow the storage class and the upper 4
+/// bits follow those, in front of the block offset.  Format versions before 6
+/// used a 10-bit disk ID and a 52-bit block offset, whose offsets are encoded
+/// the same way as long as their disks are smaller than 2^48 blocks.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DiskOffset(u64);
 
+/// The maximum number of disks per storage class.
+pub const MAX_DISKS_PER_CLASS: usize = 1 << 14;
+/// The maximum number of disks per storage class of format versions before 6.
+pub(crate) const LEGACY_MAX_DISKS_PER_CLASS: usize = 1 << 10;
+/// The maximum size of a disk in blocks.
+pub const MAX_DISK_BLOCKS: u64 = 1 << 48;
+
 const MASK_STORAGE_CLASS: u64 = ((1 << 2) - 1) << (10 + 52);
-const MASK_DISK_ID: u64 = ((1 << 10) - 1) << 52;
-const MASK_OFFSET: u64 = (1 << 52) - 1;
-const MASK_CLASS_DISK_ID_COMBINED: u64 = ((1 << 12) - 1) << 52;
+const MASK_DISK_ID_LOW: u64 = ((1 << 10) - 1) << 52;
+const MASK_DISK_ID_HIGH: u64 = ((1 << 4) - 1) << 48;
+const MASK_OFFSET: u64 = (1 << 48) - 1;
 
 /// An identifier containing the class id and disk id. Uniquely identifies a
 /// disk over all storage devices.
@@ -30,7 +42,13 @@ pub struct LocalDiskId(u16);
 
 impl DiskOffset {
     /// Constructs a new `DiskOffset`.
-    /// The given `block_offset` may not be larger than (1 << 52) - 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `disk_id` is not below [MAX_DISKS_PER_CLASS] or
+    /// `block_offset` is not below [MAX_DISK_BLOCKS].  Storage pools refuse
+    /// vdevs of more blocks and configurations of more vdevs, so offsets of
+    /// allocated blocks always fit.
     pub fn new(storage_class: u8, disk_id: u16, block_offset: Block<u64>) -> Self {
         let block_offset = block_offset.as_u64();
         assert_eq!(
@@ -38,19 +56,29 @@ impl DiskOffset {
             0,
             "the block offset is too large"
         );
-        DiskOffset(((storage_class as u64) << (52 + 10)) | ((disk_id as u64) << 52) | block_offset)
+        assert!(
+            (disk_id as usize) < MAX_DISKS_PER_CLASS,
+            "the disk id is too large"
+        );
+        let disk_id = disk_id as u64;
+        DiskOffset(
+            ((storage_class as u64) << (52 + 10))
+                | ((disk_id & 0x3FF) << 52)
+                | ((disk_id >> 10) << 48)
+                | block_offset,
+        )
     }
     /// Returns the 2-bit storage class.
     pub fn storage_class(&self) -> u8 {
         ((self.0 & MASK_STORAGE_CLASS) >> (52 + 10)) as u8
     }
-    /// Returns the 10-bit disk ID.
+    /// Returns the 14-bit disk ID.
     pub fn disk_id(&self) -> u16 {
-        ((self.0 & MASK_DISK_ID) >> 52) as u16
+        (((self.0 & MASK_DISK_ID_LOW) >> 52) | ((self.0 & MASK_DISK_ID_HIGH) >> (48 - 10))) as u16
     }
-    /// Returns the 12-bit storage class with attached disk ID.
+    /// Returns the storage class with attached disk ID.
     pub fn class_disk_id(&self) -> GlobalDiskId {
-        GlobalDiskId(((self.0 & MASK_CLASS_DISK_ID_COMBINED) >> 52) as u16)
+        Self::construct_disk_id(self.storage_class(), self.disk_id())
     }
     /// Returns the block offset.
     pub fn block_offset(&self) -> Block<u64> {
@@ -65,9 +93,11 @@ impl DiskOffset {
         DiskOffset(x)
     }
 
-    // Glue together a class identifier with a class depdendent disk_id.
+    // Glue together a class identifier with a class depdendent disk_id. The
+    // upper 4 bits of the disk id are put in front of the class, so that ids
+    // of disks below 1024 stay the same as in older format versions.
     pub fn construct_disk_id(class: u8, disk_id: u16) -> GlobalDiskId {
-        GlobalDiskId(((class as u16) << 10) | disk_id)
+        GlobalDiskId(((disk_id >> 10) << 12) | ((class as u16) << 10) | (disk_id & 0x3FF))
     }
 }
 
@@ -93,9 +123,13 @@ mod tests {
 
     #[test]
     fn masks() {
-        assert_eq!(!0u64, MASK_STORAGE_CLASS | MASK_DISK_ID | MASK_OFFSET);
-        assert_eq!(0, MASK_STORAGE_CLASS & MASK_DISK_ID);
-        assert_eq!(0, MASK_DISK_ID & MASK_OFFSET);
+        assert_eq!(
+            !0u64,
+            MASK_STORAGE_CLASS | MASK_DISK_ID_LOW | MASK_DISK_ID_HIGH | MASK_OFFSET
+        );
+        assert_eq!(0, MASK_STORAGE_CLASS & MASK_DISK_ID_LOW);
+        assert_eq!(0, MASK_DISK_ID_LOW & MASK_DISK_ID_HIGH);
+        assert_eq!(0, MASK_DISK_ID_HIGH & MASK_OFFSET);
         assert_eq!(0, MASK_STORAGE_CLASS & MASK_OFFSET);
     }
 
@@ -105,5 +139,23 @@ mod tests {
         assert_eq!(o.storage_class(), 1);
         assert_eq!(o.disk_id(), 42);
         assert_eq!(o.block_offset().to_bytes(), 4096 * 189631);
+
+        let o = DiskOffset::new(3, 12345, Block(MAX_DISK_BLOCKS - 1));
+        assert_eq!(o.storage_class(), 3);
+        assert_eq!(o.disk_id(), 12345);
+        assert_eq!(o.block_offset(), Block(MAX_DISK_BLOCKS - 1));
+    }
+
+    #[test]
+    fn legacy_layout() {
+        // class 2, disk 1000, block 77 in the layout of format version 5
+        let legacy = (2 << 62) | (1000 << 52) | 77;
+        let o = DiskOffset::new(2, 1000, Block(77));
+        assert_eq!(o.as_u64(), legacy);
+        assert_eq!(o.class_disk_id().as_u16(), (2 << 10) | 1000);
+        assert_ne!(
+            DiskOffset::new(2, 1000 + 1024, Block(77)).class_disk_id(),
+            o.class_disk_id()
+        );
     }
 }
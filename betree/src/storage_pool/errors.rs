@@ -1,5 +1,8 @@
 #![allow(missing_docs, unused_doc_comments)]
 error_chain! {
+    links {
+        Configuration(super::configuration::Error, super::configuration::ErrorKind);
+    }
     foreign_links {
         Io(std::io::Error);
     }
@@ -71,6 +71,10 @@ pub trait StoragePoolLayer: Clone + Send + Sync + 'static {
     /// Returns the size for a specific `Vdev`.
     fn size_in_blocks(&self, storage_class: u8, disk_id: u16) -> Block<u64>;
 
+    /// Re-reads the size of a specific `Vdev` after its backing devices have
+    /// been grown and returns the new size.
+    fn refresh_size(&self, storage_class: u8, disk_id: u16) -> VdevResult<Block<u64>>;
+
     /// Return the number of leaf vdevs for a specific `Vdev`.
     fn num_disks(&self, storage_class: u8, disk_id: u16) -> usize;
 
@@ -96,14 +100,21 @@ pub trait StoragePoolLayer: Clone + Send + Sync + 'static {
 
     /// Return a fitting [StoragePreference] to the given [PreferredAccessType].
     fn access_type_preference(&self, t: PreferredAccessType) -> StoragePreference;
+
+    /// Returns whether data written to `storage_class` is checksummed.
+    fn checksum_policy(&self, storage_class: u8) -> ChecksumPolicy;
 }
 
 mod disk_offset;
-pub use self::disk_offset::{DiskOffset, GlobalDiskId, LocalDiskId};
+pub(crate) use self::disk_offset::LEGACY_MAX_DISKS_PER_CLASS;
+pub use self::disk_offset::{
+    DiskOffset, GlobalDiskId, LocalDiskId, MAX_DISKS_PER_CLASS, MAX_DISK_BLOCKS,
+};
 
 pub mod configuration;
 pub use self::configuration::{
-    LeafVdev, PreferredAccessType, StoragePoolConfiguration, TierConfiguration, Vdev,
+    ChecksumPolicy, FailureDomainPolicy, LeafVdev, PreferredAccessType, StoragePoolConfiguration,
+    TierConfiguration, Vdev,
 };
 
 mod unit;
@@ -1,6 +1,8 @@
 use super::{
-    errors::Result as StoragePoolResult, DiskOffset, StoragePoolConfiguration, StoragePoolLayer,
-    NUM_STORAGE_CLASSES,
+    configuration::ErrorKind as ConfigurationErrorKind,
+    errors::{ErrorKind, Result as StoragePoolResult},
+    ChecksumPolicy, DiskOffset, StoragePoolConfiguration, StoragePoolLayer, TierConfiguration,
+    MAX_DISK_BLOCKS, NUM_STORAGE_CLASSES,
 };
 use crate::{
     bounded_future_queue::BoundedFutureQueue,
@@ -31,6 +33,7 @@ pub(super) type WriteBackQueue = BoundedFutureQueue<
 struct StorageTier {
     devs: Box<[Dev]>,
     preferred_access_type: PreferredAccessType,
+    checksum_policy: ChecksumPolicy,
 }
 
 impl StorageTier {
@@ -56,15 +59,17 @@ impl Default for StorageTier {
         Self {
             devs: Box::new([]),
             preferred_access_type: PreferredAccessType::Unknown,
+            checksum_policy: ChecksumPolicy::Full,
         }
     }
 }
 
-impl From<(Box<[Dev]>, PreferredAccessType)> for StorageTier {
-    fn from(item: (Box<[Dev]>, PreferredAccessType)) -> Self {
+impl From<(Box<[Dev]>, &TierConfiguration)> for StorageTier {
+    fn from((devs, cfg): (Box<[Dev]>, &TierConfiguration)) -> Self {
         Self {
-            devs: item.0,
-            preferred_access_type: item.1,
+            devs,
+            preferred_access_type: cfg.preferred_access_type,
+            checksum_policy: cfg.checksum_policy,
         }
     }
 }
@@ -88,15 +93,16 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
     type Metrics = StoragePoolMetrics;
 
     fn new(configuration: &Self::Configuration) -> StoragePoolResult<Self> {
+        configuration.check_failure_domains()?;
         let tiers: [StorageTier; NUM_STORAGE_CLASSES] = {
             let mut vec: Vec<StorageTier> = configuration
                 .tiers
                 .iter()
                 .map(|tier_cfg| {
                     tier_cfg
-                        .build()
+                        .build(configuration.read_only)
                         .map(Vec::into_boxed_slice)
-                        .map(|tier| (tier, tier_cfg.preferred_access_type).into())
+                        .map(|tier| (tier, tier_cfg).into())
                 })
                 .collect::<Result<Vec<_>, _>>()?;
 
@@ -106,6 +112,16 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
                 vec.into_boxed_slice().try_into().map_err(|_| ()).unwrap();
             *boxed
         };
+        // Offsets beyond this can not be encoded in a `DiskOffset`.
+        for (tier_id, tier) in tiers.iter().enumerate() {
+            for (vdev_id, dev) in tier.iter().enumerate() {
+                if dev.size().as_u64() > MAX_DISK_BLOCKS {
+                    bail!(ErrorKind::Configuration(
+                        ConfigurationErrorKind::VdevTooLarge(tier_id, vdev_id, dev.size().as_u64())
+                    ));
+                }
+            }
+        }
 
         let devices_len = tiers.iter().map(|tier| tier.len()).sum::<usize>();
         let queue_depth = configuration.queue_depth_factor as usize * devices_len;
@@ -211,7 +227,14 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
     }
 
     fn size_in_blocks(&self, storage_class: u8, disk_id: u16) -> Block<u64> {
-        self.inner.tiers[storage_class as usize][disk_id as usize].size()
+        let size = self.inner.tiers[storage_class as usize][disk_id as usize].size();
+        size.min(Block(MAX_DISK_BLOCKS))
+    }
+
+    fn refresh_size(&self, storage_class: u8, disk_id: u16) -> Result<Block<u64>, VdevError> {
+        let size = self.inner.tiers[storage_class as usize][disk_id as usize].refresh_size()?;
+        // A vdev grown beyond the addressable blocks only uses the first ones.
+        Ok(size.min(Block(MAX_DISK_BLOCKS)))
     }
 
     fn num_disks(&self, storage_class: u8, disk_id: u16) -> usize {
@@ -268,6 +291,10 @@ impl<C: Checksum> StoragePoolLayer for StoragePoolUnit<C> {
         }
         StoragePreference::NONE
     }
+
+    fn checksum_policy(&self, storage_class: u8) -> ChecksumPolicy {
+        self.inner.tiers[storage_class as usize].checksum_policy
+    }
 }
 
 #[derive(serde::Serialize)]
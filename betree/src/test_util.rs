@@ -0,0 +1,321 @@
+//! Helpers for testing code built on top of the database, enabled by the
+//! `test-util` feature.
+//!
+//! [InMemoryBuilder] builds databases which are kept in memory only,
+//! [rng] returns a generator whose sequence is fixed by its seed, and
+//! [ModelChecker] runs operation sequences against a dataset and a
+//! [BTreeMap] alike, reporting the first step at which they disagree.
+//!
+//! ```
+//! # use betree_storage_stack::test_util::*;
+//! let mut db = InMemoryBuilder::new().build().unwrap();
+//! let ops = Op::random_sequence(&mut rng(DEFAULT_SEED), 256, 64);
+//! let mut checker = ModelChecker::new(&mut db, b"model").unwrap();
+//! checker.run(&ops).unwrap();
+//! ```
+
+use crate::{
+    compression::CompressionConfiguration,
+    cow_bytes::{CowBytes, SlicedCowBytes},
+    database::{AccessMode, Error, Result},
+    storage_pool::{LeafVdev, StoragePoolConfiguration, TierConfiguration, Vdev},
+    Database, DatabaseConfiguration, Dataset,
+};
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use std::collections::BTreeMap;
+
+/// A seed for callers without a preference.
+pub const DEFAULT_SEED: u64 = 0x4841_5552_4142_4545;
+
+/// Returns a random number generator which yields the same sequence for the
+/// same `seed` on every platform and run, so that failing cases can be
+/// replayed.
+pub fn rng(seed: u64) -> XorShiftRng {
+    XorShiftRng::seed_from_u64(seed)
+}
+
+/// Builds a [Database] on memory vdevs, which is gone once it is dropped.
+///
+/// By default, a single tier of 64 MiB and a cache of 16 MiB are used, there
+/// is no compression and no periodic sync.
+#[derive(Debug)]
+pub struct InMemoryBuilder {
+    config: DatabaseConfiguration,
+}
+
+impl Default for InMemoryBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryBuilder {
+    /// Returns a builder with the default configuration.
+    pub fn new() -> Self {
+        InMemoryBuilder {
+            config: DatabaseConfiguration {
+                cache_size: 16 * 1024 * 1024,
+                compression: CompressionConfiguration::None,
+                ..DatabaseConfiguration::in_memory(64 * 1024 * 1024)
+            },
+        }
+    }
+
+    /// Uses `count` tiers of `size` bytes each.
+    pub fn tiers(mut self, count: usize, size: usize) -> Self {
+        self.config.storage = StoragePoolConfiguration {
+            tiers: (0..count)
+                .map(|_| TierConfiguration::new(vec![Vdev::Leaf(LeafVdev::Memory { mem: size })]))
+                .collect(),
+            ..Default::default()
+        };
+        self
+    }
+
+    /// Uses a cache of `size` bytes.
+    pub fn cache_size(mut self, size: usize) -> Self {
+        self.config.cache_size = size;
+        self
+    }
+
+    /// Adjusts any other option of the configuration.  The access mode is
+    /// always [AccessMode::AlwaysCreateNew].
+    pub fn configure(mut self, f: impl FnOnce(&mut DatabaseConfiguration)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Builds the database.
+    pub fn build(mut self) -> Result<Database> {
+        self.config.access_mode = AccessMode::AlwaysCreateNew;
+        Database::build(self.config)
+    }
+}
+
+/// An operation on a dataset, see [ModelChecker].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Inserts a key-value pair, see [Dataset::insert].
+    Insert(Vec<u8>, Vec<u8>),
+    /// Writes data at an offset of a value, see [Dataset::upsert].
+    Upsert(Vec<u8>, Vec<u8>, u32),
+    /// Deletes a key, see [Dataset::delete].
+    Delete(Vec<u8>),
+    /// Reads a key, see [Dataset::get].
+    Get(Vec<u8>),
+    /// Reads all keys in `from..to`, see [Dataset::range].  `from` may not
+    /// be greater than `to`.
+    Range(Vec<u8>, Vec<u8>),
+    /// Syncs the database, see [Database::sync].
+    Sync,
+}
+
+impl Op {
+    /// Returns a random operation on one of `key_space` keys.  Values are
+    /// never empty, and syncs are rare.
+    pub fn random<R: Rng>(rng: &mut R, key_space: u16) -> Self {
+        let mut key = || rng.gen_range(0..key_space.max(1)).to_be_bytes().to_vec();
+        let (a, b) = (key(), key());
+        let len = if rng.gen_ratio(1, 16) {
+            rng.gen_range(1..=64 * 1024)
+        } else {
+            rng.gen_range(1..=256)
+        };
+        let mut value = vec![0; len];
+        rng.fill(&mut value[..]);
+        match rng.gen_range(0..32) {
+            0..=11 => Op::Insert(a, value),
+            12..=15 => Op::Upsert(a, value, rng.gen_range(0..512)),
+            16..=21 => Op::Delete(a),
+            22..=27 => Op::Get(a),
+            28..=30 if a <= b => Op::Range(a, b),
+            28..=30 => Op::Range(b, a),
+            _ => Op::Sync,
+        }
+    }
+
+    /// Returns `count` random operations, see [Op::random].
+    pub fn random_sequence<R: Rng>(rng: &mut R, count: usize, key_space: u16) -> Vec<Self> {
+        (0..count).map(|_| Op::random(rng, key_space)).collect()
+    }
+}
+
+/// How a dataset has disagreed with its model, see [ModelChecker].
+#[derive(Debug, thiserror::Error)]
+pub enum CheckError {
+    /// The dataset has failed an operation.
+    #[error("Operation {step} failed: {source}")]
+    Database {
+        /// The failed step.
+        step: usize,
+        /// The error of the dataset.
+        source: Error,
+    },
+    /// The dataset has returned another value than the model.
+    #[error("Operation {step} returned {found:?} for key {key:?} instead of {expected:?}")]
+    Divergence {
+        /// The step which has read the value.
+        step: usize,
+        /// The first key whose value differs.
+        key: Vec<u8>,
+        /// The value of the model.
+        expected: Option<Vec<u8>>,
+        /// The value of the dataset.
+        found: Option<Vec<u8>>,
+    },
+}
+
+/// Applies operations to a dataset and to a [BTreeMap] modelling it, and
+/// compares every read.
+///
+/// Steps are counted from zero over all operations applied to a checker, the
+/// final comparison of [ModelChecker::verify] counts as one more step.
+pub struct ModelChecker<'db> {
+    db: &'db mut Database,
+    dataset: Dataset,
+    model: BTreeMap<Vec<u8>, Vec<u8>>,
+    step: usize,
+}
+
+impl<'db> ModelChecker<'db> {
+    /// Creates the dataset `name`, which must not exist yet.
+    pub fn new(db: &'db mut Database, name: &[u8]) -> Result<Self> {
+        db.create_dataset(name)?;
+        let dataset = db.open_dataset(name)?;
+        Ok(ModelChecker {
+            db,
+            dataset,
+            model: BTreeMap::new(),
+            step: 0,
+        })
+    }
+
+    /// Returns the dataset under test.
+    pub fn dataset(&self) -> &Dataset {
+        &self.dataset
+    }
+
+    /// Returns the expected contents of the dataset.
+    pub fn model(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.model
+    }
+
+    /// Applies all `ops` in order and compares the whole dataset afterwards.
+    pub fn run(&mut self, ops: &[Op]) -> std::result::Result<(), CheckError> {
+        for op in ops {
+            self.apply(op)?;
+        }
+        self.verify()
+    }
+
+    /// Applies `op` to the dataset and the model.
+    pub fn apply(&mut self, op: &Op) -> std::result::Result<(), CheckError> {
+        let step = self.step;
+        self.step += 1;
+        let db_err = |source| CheckError::Database { step, source };
+        match op {
+            Op::Insert(key, value) => {
+                self.dataset.insert(&key[..], value).map_err(db_err)?;
+                self.model.insert(key.clone(), value.clone());
+            }
+            Op::Upsert(key, data, offset) => {
+                self.dataset
+                    .upsert(&key[..], data, *offset)
+                    .map_err(db_err)?;
+                let value = self.model.entry(key.clone()).or_default();
+                let (start, end) = (*offset as usize, *offset as usize + data.len());
+                if value.len() < end {
+                    value.resize(end, 0);
+                }
+                value[start..end].copy_from_slice(data);
+            }
+            Op::Delete(key) => {
+                self.dataset.delete(&key[..]).map_err(db_err)?;
+                self.model.remove(key);
+            }
+            Op::Get(key) => {
+                let found = self.dataset.get(&key[..]).map_err(db_err)?;
+                compare(step, key, self.model.get(key), found)?;
+            }
+            Op::Range(from, to) => {
+                let found = self
+                    .dataset
+                    .range::<_, &[u8]>(&from[..]..&to[..])
+                    .map_err(db_err)?;
+                compare_all(step, self.model.range(from.clone()..to.clone()), found)?;
+            }
+            Op::Sync => {
+                self.db.sync().map_err(db_err)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compares the whole dataset to the model.
+    pub fn verify(&mut self) -> std::result::Result<(), CheckError> {
+        let step = self.step;
+        self.step += 1;
+        let found = self
+            .dataset
+            .range::<_, &[u8]>(..)
+            .map_err(|source| CheckError::Database { step, source })?;
+        compare_all(step, self.model.iter(), found)
+    }
+}
+
+fn compare(
+    step: usize,
+    key: &[u8],
+    expected: Option<&Vec<u8>>,
+    found: Option<SlicedCowBytes>,
+) -> std::result::Result<(), CheckError> {
+    if expected.map(|v| &v[..]) == found.as_deref() {
+        return Ok(());
+    }
+    Err(CheckError::Divergence {
+        step,
+        key: key.to_vec(),
+        expected: expected.cloned(),
+        found: found.map(|v| v.to_vec()),
+    })
+}
+
+fn compare_all<'a>(
+    step: usize,
+    mut expected: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>,
+    mut found: impl Iterator<Item = Result<(CowBytes, SlicedCowBytes)>>,
+) -> std::result::Result<(), CheckError> {
+    loop {
+        let next = found
+            .next()
+            .transpose()
+            .map_err(|source| CheckError::Database { step, source })?;
+        match (expected.next(), next) {
+            (None, None) => return Ok(()),
+            (Some((key, value)), Some((found_key, found_value))) if key[..] == found_key[..] => {
+                compare(step, key, Some(value), Some(found_value))?
+            }
+            // Report the smaller key, which is missing on the other side.
+            (Some((key, value)), other)
+                if other.as_ref().map_or(true, |(k, _)| key[..] < k[..]) =>
+            {
+                return Err(CheckError::Divergence {
+                    step,
+                    key: key.clone(),
+                    expected: Some(value.clone()),
+                    found: None,
+                })
+            }
+            (_, Some((key, value))) => {
+                return Err(CheckError::Divergence {
+                    step,
+                    key: key.to_vec(),
+                    expected: None,
+                    found: Some(value.to_vec()),
+                })
+            }
+            (Some(_), None) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,290 @@
+//! This module provides message actions for conflict-free replicated data
+//! types (CRDTs), whose messages can be applied in any order by any number of
+//! writers and still yield the same value.
+//!
+//! ## G-counter
+//!
+//! A grow-only counter with one entry per replica, stored as a sorted map of
+//! replica id to count.  The counter value is the sum of all entries.  A
+//! message maps every replica it touches to a pair `(add, floor)` and sets the
+//! entry `x` to `max(x + add, floor)`.  Increments only add, while joining the
+//! state of another instance raises the floors, so increments of different
+//! replicas commute, and joins are idempotent.  Two such functions compose into
+//! another one, so merged messages do not grow.
+//!
+//! ## OR-set
+//!
+//! An observed-remove set, stored as the set of added elements, each with a
+//! unique tag, and the set of removed tags.  Removing an element removes the
+//! tags of it which have been observed by the remover, so a concurrent add of
+//! the same element with a new tag survives.  A message has the same format as
+//! the state and is applied by forming the union of both, which makes applying
+//! and merging messages commutative, associative and idempotent.
+//!
+//! Datasets of these message actions can not be shipped by a
+//! [ReplicationSender](crate::database::ReplicationSender), which may apply
+//! messages twice.  Instances exchange their states with join messages instead.
+
+use super::MessageAction;
+use crate::cow_bytes::{CowBytes, SlicedCowBytes};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+fn encode<T: Serialize>(value: &T) -> SlicedCowBytes {
+    CowBytes::from(bincode::serialize(value).expect("Serializing to a Vec can not fail")).into()
+}
+
+fn decode<'a, T: Deserialize<'a> + Default>(data: Option<&'a [u8]>) -> T {
+    data.map_or_else(T::default, |data| {
+        bincode::deserialize(data).expect("Invalid CRDT encoding")
+    })
+}
+
+/// Message action for grow-only counters, see the module documentation.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct GCounterMessageAction;
+
+/// Replica id to `(add, floor)`.
+type GCounterMsg = BTreeMap<u32, (u64, u64)>;
+
+/// Replica id to count.
+type GCounterState = BTreeMap<u32, u64>;
+
+impl GCounterMessageAction {
+    /// Return a new message which increments the entry of `replica` by
+    /// `amount`.
+    pub fn increment_msg(replica: u32, amount: u64) -> SlicedCowBytes {
+        encode(&GCounterMsg::from([(replica, (amount, 0))]))
+    }
+
+    /// Return a new message which joins the given counter `state` of another
+    /// instance into the counter.
+    pub fn join_msg(state: &[u8]) -> SlicedCowBytes {
+        let state: GCounterState = decode(Some(state));
+        encode(
+            &state
+                .into_iter()
+                .map(|(replica, count)| (replica, (0, count)))
+                .collect::<GCounterMsg>(),
+        )
+    }
+
+    /// Returns the count of every replica in the given counter `state`.
+    pub fn entries(state: &[u8]) -> BTreeMap<u32, u64> {
+        decode(Some(state))
+    }
+
+    /// Returns the value of the given counter `state`.
+    pub fn value(state: &[u8]) -> u64 {
+        Self::entries(state)
+            .values()
+            .fold(0, |sum, count| sum.saturating_add(*count))
+    }
+}
+
+impl MessageAction for GCounterMessageAction {
+    fn apply(&self, _key: &[u8], msg: &SlicedCowBytes, data: &mut Option<SlicedCowBytes>) {
+        let msg: GCounterMsg = decode(Some(&msg[..]));
+        let mut state: GCounterState = decode(data.as_deref());
+        for (replica, (add, floor)) in msg {
+            let count = state.entry(replica).or_default();
+            *count = count.saturating_add(add).max(floor);
+        }
+        *data = Some(encode(&state));
+    }
+
+    fn merge(
+        &self,
+        _key: &[u8],
+        upper_msg: SlicedCowBytes,
+        lower_msg: SlicedCowBytes,
+    ) -> SlicedCowBytes {
+        let upper: GCounterMsg = decode(Some(&upper_msg[..]));
+        let mut lower: GCounterMsg = decode(Some(&lower_msg[..]));
+        // max(max(x + a1, f1) + a2, f2) = max(x + a1 + a2, max(f1 + a2, f2))
+        for (replica, (add, floor)) in upper {
+            let (lower_add, lower_floor) = lower.entry(replica).or_default();
+            *lower_add = lower_add.saturating_add(add);
+            *lower_floor = lower_floor.saturating_add(add).max(floor);
+        }
+        encode(&lower)
+    }
+}
+
+/// Unique tag of an element added to an OR-set.  Each replica has to use
+/// increasing sequence numbers for its adds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct OrSetTag {
+    /// The replica which added the element.
+    pub replica: u32,
+    /// Sequence number of the add on its replica.
+    pub seq: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct OrSetState {
+    adds: BTreeSet<(Vec<u8>, OrSetTag)>,
+    removed: BTreeSet<OrSetTag>,
+}
+
+impl OrSetState {
+    fn union(&mut self, other: OrSetState) {
+        self.removed.extend(other.removed);
+        self.adds.extend(other.adds);
+        let removed = &self.removed;
+        self.adds.retain(|(_, tag)| !removed.contains(tag));
+    }
+}
+
+/// Message action for observed-remove sets, see the module documentation.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct OrSetMessageAction;
+
+impl OrSetMessageAction {
+    /// Return a new message which adds `element` with the given unique `tag`.
+    pub fn add_msg(element: &[u8], tag: OrSetTag) -> SlicedCowBytes {
+        encode(&OrSetState {
+            adds: BTreeSet::from([(element.to_vec(), tag)]),
+            removed: BTreeSet::new(),
+        })
+    }
+
+    /// Return a new message which removes `element` as observed in the given
+    /// set `state`.  Adds of the element unknown to `state` are not affected.
+    pub fn remove_msg(state: &[u8], element: &[u8]) -> SlicedCowBytes {
+        let state: OrSetState = decode(Some(state));
+        encode(&OrSetState {
+            adds: BTreeSet::new(),
+            removed: state
+                .adds
+                .into_iter()
+                .filter(|(e, _)| e == element)
+                .map(|(_, tag)| tag)
+                .collect(),
+        })
+    }
+
+    /// Return a new message which joins the given set `state` of another
+    /// instance into the set.
+    pub fn join_msg(state: &[u8]) -> SlicedCowBytes {
+        CowBytes::from(state).into()
+    }
+
+    /// Returns the elements of the given set `state`.
+    pub fn elements(state: &[u8]) -> BTreeSet<Vec<u8>> {
+        let state: OrSetState = decode(Some(state));
+        state.adds.into_iter().map(|(element, _)| element).collect()
+    }
+}
+
+impl MessageAction for OrSetMessageAction {
+    fn apply(&self, _key: &[u8], msg: &SlicedCowBytes, data: &mut Option<SlicedCowBytes>) {
+        let mut state: OrSetState = decode(data.as_deref());
+        state.union(decode(Some(&msg[..])));
+        *data = Some(encode(&state));
+    }
+
+    fn merge(
+        &self,
+        _key: &[u8],
+        upper_msg: SlicedCowBytes,
+        lower_msg: SlicedCowBytes,
+    ) -> SlicedCowBytes {
+        let mut lower: OrSetState = decode(Some(&lower_msg[..]));
+        lower.union(decode(Some(&upper_msg[..])));
+        encode(&lower)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GCounterMessageAction, OrSetMessageAction, OrSetTag};
+    use crate::{cow_bytes::SlicedCowBytes, tree::MessageAction};
+
+    fn apply_all<M: MessageAction>(action: M, msgs: &[SlicedCowBytes]) -> SlicedCowBytes {
+        let mut data = None;
+        for msg in msgs {
+            action.apply(&[], msg, &mut data);
+        }
+        data.unwrap()
+    }
+
+    #[test]
+    fn g_counter_is_order_independent() {
+        let mut other = None;
+        GCounterMessageAction.apply(&[], &GCounterMessageAction::increment_msg(2, 7), &mut other);
+        let msgs = [
+            GCounterMessageAction::increment_msg(1, 3),
+            GCounterMessageAction::join_msg(&other.unwrap()),
+            GCounterMessageAction::increment_msg(1, 4),
+        ];
+        let forward = apply_all(GCounterMessageAction, &msgs);
+        let backward = apply_all(
+            GCounterMessageAction,
+            &msgs.iter().rev().cloned().collect::<Vec<_>>(),
+        );
+        assert_eq!(forward, backward);
+        assert_eq!(GCounterMessageAction::value(&forward), 14);
+
+        // Joining the same state twice does not count it twice.
+        let mut data = Some(forward.clone());
+        GCounterMessageAction.apply(&[], &msgs[1], &mut data);
+        assert_eq!(data.unwrap(), forward);
+    }
+
+    #[test]
+    fn g_counter_merge_matches_apply() {
+        let lower = GCounterMessageAction.merge(
+            &[],
+            GCounterMessageAction::join_msg(&apply_all(
+                GCounterMessageAction,
+                &[GCounterMessageAction::increment_msg(1, 10)],
+            )),
+            GCounterMessageAction::increment_msg(1, 2),
+        );
+        let upper = GCounterMessageAction::increment_msg(1, 5);
+        let merged = GCounterMessageAction.merge(&[], upper.clone(), lower.clone());
+
+        let initial = apply_all(
+            GCounterMessageAction,
+            &[GCounterMessageAction::increment_msg(1, 1)],
+        );
+        let mut expected = Some(initial.clone());
+        GCounterMessageAction.apply(&[], &lower, &mut expected);
+        GCounterMessageAction.apply(&[], &upper, &mut expected);
+        let mut actual = Some(initial);
+        GCounterMessageAction.apply(&[], &merged, &mut actual);
+        assert_eq!(actual, expected);
+        assert_eq!(GCounterMessageAction::value(&actual.unwrap()), 15);
+    }
+
+    #[test]
+    fn or_set_keeps_concurrent_add() {
+        let tag = |replica, seq| OrSetTag { replica, seq };
+        let state = apply_all(
+            OrSetMessageAction,
+            &[OrSetMessageAction::add_msg(b"a", tag(1, 0))],
+        );
+        let msgs = [
+            OrSetMessageAction::add_msg(b"a", tag(1, 0)),
+            OrSetMessageAction::remove_msg(&state, b"a"),
+            OrSetMessageAction::add_msg(b"a", tag(2, 0)),
+            OrSetMessageAction::add_msg(b"b", tag(2, 1)),
+        ];
+        let forward = apply_all(OrSetMessageAction, &msgs);
+        let backward = apply_all(
+            OrSetMessageAction,
+            &msgs.iter().rev().cloned().collect::<Vec<_>>(),
+        );
+        assert_eq!(forward, backward);
+        assert_eq!(
+            OrSetMessageAction::elements(&forward),
+            [b"a".to_vec(), b"b".to_vec()].into()
+        );
+
+        let merged = msgs[1..].iter().fold(msgs[0].clone(), |lower, upper| {
+            OrSetMessageAction.merge(&[], upper.clone(), lower)
+        });
+        assert_eq!(apply_all(OrSetMessageAction, &[merged]), forward);
+    }
+}
@@ -1,5 +1,5 @@
 //! This module provides the default message action, capable of inserts, deletes, and upserts with
-//! byte-granularity.
+//! byte-granularity, as well as conditional inserts and compare-and-swaps.
 //!
 //! To avoid CPU-intensive serialisation/deserialisation, the message is modified in place, without
 //! deserialising the rest.
@@ -10,6 +10,9 @@
 //! Delete => [<0, u8>]
 //! Insert => [<1, u8>, <bytes to be inserted>] # no length marker, encoded externally
 //! Upsert => [<2, u8>, <upserts>]
+//! InsertIfAbsent => [<3, u8>, <bytes to be inserted>] # no length marker, encoded externally
+//! CompareAndSwap => [<4, u8>, <expected value>, <new value>]
+//! Sequence => [<5, u8>, <messages>]
 //!
 //! An upsert is encoded as
 //!
@@ -27,6 +30,16 @@
 //!
 //! - if bit set mode:
 //!     - number of bits to set: LE u32
+//!
+//! A compare-and-swap is encoded as
+//!
+//! - expected value: LE u32 length, u32::MAX if the key is expected to be absent,
+//!   followed by as many bytes
+//! - new value: u8 0 to delete the key, or u8 1 followed by the bytes to be inserted
+//!
+//! A sequence holds messages which are applied in order, each preceded by its
+//! length as LE u32.  Sequences are created when conditional messages can not be
+//! merged into a single message, they are never nested.
 //! ```
 
 use super::MessageAction;
@@ -45,6 +58,9 @@ enum MsgType {
     OverwriteNone = 0,
     OverwriteSome = 1,
     Upsert = 2,
+    InsertIfAbsent = 3,
+    CompareAndSwap = 4,
+    Sequence = 5,
 }
 
 impl MsgType {
@@ -53,6 +69,9 @@ impl MsgType {
             0 => Self::OverwriteNone,
             1 => Self::OverwriteSome,
             2 => Self::Upsert,
+            3 => Self::InsertIfAbsent,
+            4 => Self::CompareAndSwap,
+            5 => Self::Sequence,
             _ => unreachable!(),
         }
     }
@@ -94,6 +113,43 @@ impl<'a> Upsert<'a> {
             Self::Bits { .. } => mem::size_of::<u32>() + mem::size_of::<u32>(),
         }
     }
+
+    /// The range of bits written by this upsert.
+    fn bit_range(&self) -> (u64, u64) {
+        match *self {
+            Self::Bytes { offset_bytes, data } => (
+                8 * offset_bytes as u64,
+                8 * (offset_bytes as u64 + data.len() as u64),
+            ),
+            Self::Bits {
+                offset_bits,
+                amount_bits,
+                ..
+            } => (offset_bits as u64, offset_bits as u64 + amount_bits as u64),
+        }
+    }
+
+    /// Moves the target range of this upsert by `offset_bytes`.
+    fn shifted(self, offset_bytes: u32) -> Self {
+        match self {
+            Self::Bytes {
+                offset_bytes: offset,
+                data,
+            } => Self::Bytes {
+                offset_bytes: offset + offset_bytes,
+                data,
+            },
+            Self::Bits {
+                offset_bits,
+                amount_bits,
+                value,
+            } => Self::Bits {
+                offset_bits: offset_bits + 8 * offset_bytes,
+                amount_bits,
+                value,
+            },
+        }
+    }
 }
 
 fn as_overwrite(b: SlicedCowBytes) -> Option<Option<SlicedCowBytes>> {
@@ -108,6 +164,38 @@ fn as_overwrite(b: SlicedCowBytes) -> Option<Option<SlicedCowBytes>> {
     }
 }
 
+/// Returns the expected and the new value of a compare-and-swap.
+fn as_compare_and_swap(b: &SlicedCowBytes) -> (Option<SlicedCowBytes>, Option<SlicedCowBytes>) {
+    debug_assert_eq!(b[0], MsgType::CompareAndSwap as u8);
+    let len = LittleEndian::read_u32(&b[1..5]);
+    let (expected, new_pos) = if len == u32::MAX {
+        (None, 5)
+    } else {
+        (Some(b.clone().subslice(5, len)), 5 + len)
+    };
+    let new = match b[new_pos as usize] {
+        0 => None,
+        _ => Some(b.clone().slice_from(new_pos + 1)),
+    };
+    (expected, new)
+}
+
+/// Returns the position and length of all messages of a sequence, or of the
+/// message itself if it is not a sequence.
+fn sequence_parts(b: &[u8]) -> Vec<(u32, u32)> {
+    if b[0] != MsgType::Sequence as u8 {
+        return vec![(0, b.len() as u32)];
+    }
+    let mut parts = Vec::new();
+    let mut pos = 1;
+    while pos + 4 <= b.len() {
+        let len = LittleEndian::read_u32(&b[pos..pos + 4]);
+        parts.push((pos as u32 + 4, len));
+        pos += 4 + len as usize;
+    }
+    parts
+}
+
 fn iter_upserts(mut b: &[u8]) -> Option<impl Iterator<Item = Upsert>> {
     if b.first() != Some(&(MsgType::Upsert as u8)) {
         return None;
@@ -195,9 +283,11 @@ impl DefaultMessageAction {
     ) {
         let mut n_upserts = 0;
 
+        // Reuse the buffer of the current value if possible, so that an upsert
+        // only copies the whole value if it is still referenced elsewhere.
         let mut data = msg_data
-            .as_ref()
-            .map(|b| CowBytes::from(&b[..]))
+            .take()
+            .map(SlicedCowBytes::into_cow_bytes)
             .unwrap_or_default();
 
         for upsert in upserts {
@@ -256,6 +346,19 @@ impl DefaultMessageAction {
         CowBytes::from(v).into()
     }
 
+    /// Builds a sequence of the messages of `lower` followed by those of `upper`.
+    fn build_sequence_msg(lower: &[u8], upper: &[u8]) -> SlicedCowBytes {
+        let mut v = Vec::with_capacity(lower.len() + upper.len() + 8);
+        v.push(MsgType::Sequence as u8);
+        for msg in [lower, upper] {
+            for (pos, len) in sequence_parts(msg) {
+                v.write_u32::<LittleEndian>(len).unwrap();
+                v.extend_from_slice(&msg[pos as usize..(pos + len) as usize]);
+            }
+        }
+        CowBytes::from(v).into()
+    }
+
     fn build_upsert_msg(upserts: &[Upsert]) -> SlicedCowBytes {
         let estimated_size = 1 + upserts.iter().map(Upsert::estimate_size).sum::<usize>();
         let mut v = Vec::with_capacity(estimated_size);
@@ -284,6 +387,39 @@ impl DefaultMessageAction {
         Self::build_upsert_msg(&[Upsert::Bytes { offset_bytes, data }])
     }
 
+    /// Return a new message which inserts the given `data` only if there is
+    /// no value yet.
+    pub fn insert_if_absent_msg(data: &[u8]) -> SlicedCowBytes {
+        let mut v = Vec::with_capacity(1 + data.len());
+        v.push(MsgType::InsertIfAbsent as u8);
+        v.extend_from_slice(data);
+        CowBytes::from(v).into()
+    }
+
+    /// Return a new message which replaces the value by `new` only if it is
+    /// equal to `expected`.  `None` stands for an absent value in both cases.
+    pub fn compare_and_swap_msg(expected: Option<&[u8]>, new: Option<&[u8]>) -> SlicedCowBytes {
+        let mut v = Vec::with_capacity(
+            1 + 4 + expected.map_or(0, |b| b.len()) + 1 + new.map_or(0, |b| b.len()),
+        );
+        v.push(MsgType::CompareAndSwap as u8);
+        match expected {
+            Some(b) => {
+                v.write_u32::<LittleEndian>(b.len() as u32).unwrap();
+                v.extend_from_slice(b);
+            }
+            None => v.write_u32::<LittleEndian>(u32::MAX).unwrap(),
+        }
+        match new {
+            Some(b) => {
+                v.push(1);
+                v.extend_from_slice(b);
+            }
+            None => v.push(0),
+        }
+        CowBytes::from(v).into()
+    }
+
     /// Return a new message which will set the specified bit range to `value`.
     pub fn upsert_bits_msg(offset_bits: u32, amount_bits: u32, value: bool) -> SlicedCowBytes {
         Self::build_upsert_msg(&[Upsert::Bits {
@@ -295,7 +431,7 @@ impl DefaultMessageAction {
 }
 
 impl MessageAction for DefaultMessageAction {
-    fn apply(&self, _key: &[u8], msg: &SlicedCowBytes, data: &mut Option<SlicedCowBytes>) {
+    fn apply(&self, key: &[u8], msg: &SlicedCowBytes, data: &mut Option<SlicedCowBytes>) {
         match MsgType::from(msg[0]) {
             MsgType::OverwriteNone | MsgType::OverwriteSome => {
                 let new_data = as_overwrite(msg.clone()).expect("Message was not an overwrite");
@@ -308,12 +444,79 @@ impl MessageAction for DefaultMessageAction {
                     Self::apply_upserts(upserts, data);
                 }
             }
+            MsgType::InsertIfAbsent => {
+                if data.is_none() {
+                    *data = Some(msg.clone().slice_from(1));
+                }
+            }
+            MsgType::CompareAndSwap => {
+                let (expected, new) = as_compare_and_swap(msg);
+                if *data == expected {
+                    *data = new;
+                }
+            }
+            MsgType::Sequence => {
+                for (pos, len) in sequence_parts(msg) {
+                    self.apply(key, &msg.clone().subslice(pos, len), data);
+                }
+            }
         }
     }
 
+    fn overwrites(&self, _key: &[u8], msg: &SlicedCowBytes) -> bool {
+        matches!(
+            MsgType::from(msg[0]),
+            MsgType::OverwriteNone | MsgType::OverwriteSome
+        )
+    }
+
+    fn apply_to_len(&self, key: &[u8], msg: &SlicedCowBytes, len: &mut Option<usize>) -> bool {
+        match MsgType::from(msg[0]) {
+            MsgType::OverwriteNone => *len = None,
+            MsgType::OverwriteSome => *len = Some(msg.len() - 1),
+            MsgType::InsertIfAbsent => {
+                if len.is_none() {
+                    *len = Some(msg.len() - 1);
+                }
+            }
+            MsgType::Upsert => {
+                let upserts = iter_upserts(msg).expect("Message was not an upsert");
+                let end = upserts
+                    .map(|upsert| {
+                        let (_, end_bit) = upsert.bit_range();
+                        (end_bit / 8 + if end_bit % 8 == 0 { 0 } else { 1 }) as usize
+                    })
+                    .max()
+                    .unwrap_or(0);
+                *len = Some(len.unwrap_or(0).max(end));
+            }
+            MsgType::CompareAndSwap => {
+                let (expected, new) = as_compare_and_swap(msg);
+                match (*len, expected) {
+                    (None, None) => *len = new.map(|b| b.len()),
+                    // Data of another length can not be equal.
+                    (Some(len), Some(expected)) if len != expected.len() => {}
+                    (Some(_), Some(_)) => return false,
+                    (None, Some(_)) | (Some(_), None) => {}
+                }
+            }
+            MsgType::Sequence => {
+                let mut new_len = *len;
+                for (pos, part_len) in sequence_parts(msg) {
+                    let part = msg.clone().subslice(pos, part_len);
+                    if !self.apply_to_len(key, &part, &mut new_len) {
+                        return false;
+                    }
+                }
+                *len = new_len;
+            }
+        }
+        true
+    }
+
     fn merge(
         &self,
-        _key: &[u8],
+        key: &[u8],
         upper_msg: SlicedCowBytes,
         lower_msg: SlicedCowBytes,
     ) -> SlicedCowBytes {
@@ -321,6 +524,21 @@ impl MessageAction for DefaultMessageAction {
             // upper overwrite always wins
             (MsgType::OverwriteNone, _) => upper_msg,
             (MsgType::OverwriteSome, _) => upper_msg,
+            // The outcome of conditional messages is known once the value
+            // below them is.
+            (
+                MsgType::InsertIfAbsent | MsgType::CompareAndSwap | MsgType::Sequence,
+                MsgType::OverwriteNone | MsgType::OverwriteSome,
+            ) => {
+                let mut data = as_overwrite(lower_msg).expect("Message was not an overwrite");
+                self.apply(key, &upper_msg, &mut data);
+                Self::build_overwrite_msg(data.as_ref().map(|b| &b[..]))
+            }
+            (MsgType::InsertIfAbsent | MsgType::CompareAndSwap | MsgType::Sequence, _)
+            | (
+                MsgType::Upsert,
+                MsgType::InsertIfAbsent | MsgType::CompareAndSwap | MsgType::Sequence,
+            ) => Self::build_sequence_msg(&lower_msg, &upper_msg),
             (MsgType::Upsert, lower_type) => {
                 if upper_msg.len() <= 1 {
                     // no upserts in message
@@ -330,22 +548,66 @@ impl MessageAction for DefaultMessageAction {
                 let upper_upserts = iter_upserts(&upper_msg).expect("Message was not an upsert");
 
                 match lower_type {
-                    MsgType::OverwriteNone | MsgType::OverwriteSome => {
-                        let mut data =
-                            as_overwrite(lower_msg).expect("Message was not an overwrite");
-
+                    MsgType::OverwriteNone => {
+                        let mut data = None;
                         Self::apply_upserts(upper_upserts, &mut data);
                         Self::build_overwrite_msg(data.as_ref().map(|b| &b[..]))
                     }
+                    MsgType::OverwriteSome => {
+                        // Patch the lower message in place instead of copying
+                        // its value out and back into a new message, the
+                        // upserts are shifted past the message type.
+                        let mut data = Some(lower_msg);
+                        Self::apply_upserts(
+                            upper_upserts.map(|upsert| upsert.shifted(1)),
+                            &mut data,
+                        );
+                        data.unwrap()
+                    }
                     MsgType::Upsert => {
-                        // Upserts can simply be appended, (-1) because we only need one MsgType u8
+                        // Lower upserts whose range is overwritten by an upper
+                        // byte upsert anyway are dropped, so that repeated
+                        // small writes to the same range, as done to plain
+                        // object stores, do not make the message grow.
+                        let covering = upper_upserts
+                            .filter(|upsert| matches!(upsert, Upsert::Bytes { .. }))
+                            .map(|upsert| upsert.bit_range())
+                            .collect::<Vec<_>>();
+                        let is_covered = |upsert: &Upsert| {
+                            let (start, end) = upsert.bit_range();
+                            covering
+                                .iter()
+                                .any(|&(c_start, c_end)| c_start <= start && end <= c_end)
+                        };
+                        let lower_upserts =
+                            iter_upserts(&lower_msg).expect("Message was not an upsert");
+                        let mut shadowed = false;
+                        let kept = lower_upserts
+                            .filter(|upsert| {
+                                let covered = is_covered(upsert);
+                                shadowed |= covered;
+                                !covered
+                            })
+                            .collect::<Vec<_>>();
+
+                        // (-1) because we only need one MsgType u8
                         let mut v = Vec::with_capacity(lower_msg.len() + upper_msg.len() - 1);
-
-                        v.extend_from_slice(&lower_msg[..]);
+                        if shadowed {
+                            v.push(MsgType::Upsert as u8);
+                            for upsert in kept.iter() {
+                                append_upsert(&mut v, upsert);
+                            }
+                        } else {
+                            // Upserts can simply be appended
+                            v.extend_from_slice(&lower_msg[..]);
+                        }
                         v.extend_from_slice(&upper_msg[1..]);
 
                         CowBytes::from(v).into()
                     }
+                    MsgType::InsertIfAbsent | MsgType::CompareAndSwap | MsgType::Sequence => {
+                        unreachable!("Conditional messages are merged into sequences")
+                    }
                 }
             }
         }
@@ -358,7 +620,11 @@ pub use self::tests::DefaultMessageActionMsg;
 #[cfg(test)]
 mod tests {
     use super::{DefaultMessageAction, MsgType, Upsert};
-    use crate::{arbitrary::GenExt, cow_bytes::SlicedCowBytes};
+    use crate::{
+        arbitrary::GenExt,
+        cow_bytes::{CowBytes, SlicedCowBytes},
+        tree::MessageAction,
+    };
     use quickcheck::{Arbitrary, Gen};
     use rand::Rng;
 
@@ -389,6 +655,121 @@ mod tests {
                     let data: Vec<_> = Arbitrary::arbitrary(g);
                     DefaultMessageActionMsg(DefaultMessageAction::insert_msg(&data))
                 }
+                MsgType::InsertIfAbsent | MsgType::CompareAndSwap | MsgType::Sequence => {
+                    unreachable!()
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn upsert_patches_unique_value_in_place() {
+        let value = SlicedCowBytes::from(CowBytes::from(vec![0; 64]));
+        let ptr = value.as_ptr();
+        let mut data = Some(value);
+        let msg = DefaultMessageAction::upsert_msg(8, &[1, 2, 3]);
+        DefaultMessageAction.apply(&[], &msg, &mut data);
+
+        let data = data.unwrap();
+        assert_eq!(data.as_ptr(), ptr);
+        assert_eq!(data.len(), 64);
+        assert_eq!(&data[7..12], &[0, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn merge_drops_overwritten_upserts() {
+        let mut merged = DefaultMessageAction::upsert_msg(16, &[1; 8]);
+        let mut expected = None;
+        DefaultMessageAction.apply(&[], &merged, &mut expected);
+        for i in 0..100u8 {
+            let upper = DefaultMessageAction::upsert_msg(16 + (i as u32 % 2), &[i; 7]);
+            DefaultMessageAction.apply(&[], &upper, &mut expected);
+            merged = DefaultMessageAction.merge(&[], upper, merged);
+        }
+        let mut actual = None;
+        DefaultMessageAction.apply(&[], &merged, &mut actual);
+        assert_eq!(actual, expected);
+        // the first upsert and two of the overwriting ones
+        assert!(merged.len() < 1 + 3 * (8 + 8));
+    }
+
+    #[test]
+    fn merge_upsert_into_insert() {
+        let value = vec![0; 64];
+        let lower = DefaultMessageAction::insert_msg(&value);
+        let upper = DefaultMessageAction::build_upsert_msg(&[
+            Upsert::Bytes {
+                offset_bytes: 62,
+                data: &[1, 2, 3, 4],
+            },
+            Upsert::Bits {
+                offset_bits: 0,
+                amount_bits: 4,
+                value: true,
+            },
+        ]);
+        let merged = DefaultMessageAction.merge(&[], upper.clone(), lower.clone());
+
+        let mut expected = None;
+        DefaultMessageAction.apply(&[], &lower, &mut expected);
+        DefaultMessageAction.apply(&[], &upper, &mut expected);
+        let mut actual = None;
+        DefaultMessageAction.apply(&[], &merged, &mut actual);
+
+        assert_eq!(actual, expected);
+        let actual = actual.unwrap();
+        assert_eq!(actual.len(), 66);
+        assert_eq!(actual[0], 0b1111);
+        assert_eq!(&actual[62..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn conditional_messages() {
+        let mut data = None;
+        let cas = DefaultMessageAction::compare_and_swap_msg(Some(&b"a"[..]), Some(&b"b"[..]));
+        DefaultMessageAction.apply(&[], &cas, &mut data);
+        assert_eq!(data, None);
+
+        let insert = DefaultMessageAction::insert_if_absent_msg(b"a");
+        DefaultMessageAction.apply(&[], &insert, &mut data);
+        assert_eq!(data.as_deref(), Some(&b"a"[..]));
+        let insert = DefaultMessageAction::insert_if_absent_msg(b"c");
+        DefaultMessageAction.apply(&[], &insert, &mut data);
+        assert_eq!(data.as_deref(), Some(&b"a"[..]));
+
+        DefaultMessageAction.apply(&[], &cas, &mut data);
+        assert_eq!(data.as_deref(), Some(&b"b"[..]));
+        let delete = DefaultMessageAction::compare_and_swap_msg(Some(&b"b"[..]), None);
+        DefaultMessageAction.apply(&[], &delete, &mut data);
+        assert_eq!(data, None);
+    }
+
+    #[test]
+    fn merge_conditional_messages() {
+        let msgs = [
+            DefaultMessageAction::delete_msg(),
+            DefaultMessageAction::insert_msg(b"a"),
+            DefaultMessageAction::upsert_msg(1, b"x"),
+            DefaultMessageAction::insert_if_absent_msg(b"a"),
+            DefaultMessageAction::compare_and_swap_msg(None, Some(&b"b"[..])),
+            DefaultMessageAction::compare_and_swap_msg(Some(&b"a"[..]), Some(&b"b"[..])),
+            DefaultMessageAction::compare_and_swap_msg(Some(&b"ax"[..]), None),
+        ];
+        for base in [None, Some(SlicedCowBytes::from(CowBytes::from(&b"a"[..])))] {
+            for lower in msgs.iter() {
+                for upper in msgs.iter() {
+                    for top in msgs.iter() {
+                        let mut expected = base.clone();
+                        for msg in [lower, upper, top] {
+                            DefaultMessageAction.apply(&[], msg, &mut expected);
+                        }
+                        let merged = DefaultMessageAction.merge(&[], upper.clone(), lower.clone());
+                        let merged = DefaultMessageAction.merge(&[], top.clone(), merged);
+                        let mut actual = base.clone();
+                        DefaultMessageAction.apply(&[], &merged, &mut actual);
+                        assert_eq!(actual, expected);
+                    }
+                }
             }
         }
     }
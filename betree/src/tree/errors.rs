@@ -1,4 +1,5 @@
 #![allow(missing_docs, unused_doc_comments)]
+use crate::database::ErrorCategory;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -15,4 +16,17 @@ pub enum Error {
     EmptyKey,
     #[error("Invalid range specification")]
     InvalidRange,
+    #[error("Mutating operation called on a read only tree")]
+    ReadOnly,
+}
+
+impl Error {
+    /// Returns the cause of this error, see [ErrorCategory].
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::DmuError { source } => source.category(),
+            Error::ReadOnly => ErrorCategory::ReadOnly,
+            Error::EmptyKey | Error::InvalidRange => ErrorCategory::Other,
+        }
+    }
 }
@@ -0,0 +1,89 @@
+//! Sampling of the keys read from a leaf, which guides the choice of split
+//! points when [TreeConfiguration::access_aware_splits] is enabled.
+//!
+//! [TreeConfiguration::access_aware_splits]: super::TreeConfiguration::access_aware_splits
+use crate::cow_bytes::CowBytes;
+use parking_lot::Mutex;
+use std::{collections::VecDeque, mem};
+
+/// The number of recently read keys kept per leaf.
+const SAMPLE_SIZE: usize = 32;
+
+/// The most recently read keys of a leaf.  Samples are only kept in memory
+/// and are lost when a leaf is evicted.
+#[derive(Debug, Default)]
+pub(super) struct AccessSample(Mutex<VecDeque<CowBytes>>);
+
+impl AccessSample {
+    /// Records a read of `key`.  Reads are dropped instead of waiting for a
+    /// concurrent reader.
+    pub(super) fn record(&self, key: &[u8]) {
+        if let Some(mut keys) = self.0.try_lock() {
+            if keys.len() == SAMPLE_SIZE {
+                keys.pop_front();
+            }
+            keys.push_back(CowBytes::from(key));
+        }
+    }
+
+    /// Returns the smallest and the largest sampled key.
+    pub(super) fn bounds(&self) -> Option<(CowBytes, CowBytes)> {
+        let keys = self.0.lock();
+        Some((keys.iter().min()?.clone(), keys.iter().max()?.clone()))
+    }
+
+    /// Takes all samples, leaving this sample empty.
+    pub(super) fn take(&self) -> Self {
+        AccessSample(Mutex::new(mem::take(&mut *self.0.lock())))
+    }
+
+    /// Moves all samples of keys starting with `key` to a new sample.
+    pub(super) fn split_off(&self, key: &[u8]) -> Self {
+        let mut keys = self.0.lock();
+        let (left, right) = keys.drain(..).partition(|sample| &sample[..] < key);
+        *keys = left;
+        AccessSample(Mutex::new(right))
+    }
+}
+
+impl Clone for AccessSample {
+    fn clone(&self) -> Self {
+        AccessSample(Mutex::new(self.0.lock().clone()))
+    }
+}
+
+// Samples are not part of the contents of a leaf.
+#[cfg(test)]
+impl PartialEq for AccessSample {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_sample() {
+        let sample = AccessSample::default();
+        assert_eq!(sample.bounds(), None);
+        for key in 0..SAMPLE_SIZE as u8 + 2 {
+            sample.record(&[key]);
+        }
+        assert_eq!(
+            sample.bounds(),
+            Some((CowBytes::from(&[2][..]), CowBytes::from(&[33][..])))
+        );
+
+        let right = sample.split_off(&[10]);
+        assert_eq!(
+            sample.bounds(),
+            Some((CowBytes::from(&[2][..]), CowBytes::from(&[9][..])))
+        );
+        assert_eq!(
+            right.bounds(),
+            Some((CowBytes::from(&[10][..]), CowBytes::from(&[33][..])))
+        );
+    }
+}
@@ -27,10 +27,99 @@ pub(super) struct ChildBuffer<N: 'static> {
     pub(super) system_storage_preference: AtomicSystemStoragePreference,
     buffer_entries_size: usize,
     pub(super) buffer: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
+    // Stored behind the internal node, see `Node::pack`, so that buffers
+    // without tombstones keep the layout of older format versions.
+    #[serde(skip)]
+    range_tombstones: Vec<RangeTombstone>,
     #[serde(with = "ser_np")]
     pub(super) node_pointer: RwLock<N>,
 }
 
+/// A pending deletion of all keys in `start..end`.
+///
+/// Range tombstones are buffered like regular messages and travel down the
+/// tree on flushes. While buffered, a tombstone masks every entry of the
+/// subtree below its buffer but none of the messages stored next to it, as
+/// these are always younger. Once a tombstone reaches a leaf, the covered
+/// entries are removed for good.
+///
+/// Tombstones stored in a [ChildBuffer] are always restricted to the key
+/// range of the buffer's child.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct RangeTombstone {
+    start: CowBytes,
+    end: Option<CowBytes>,
+}
+
+impl RangeTombstone {
+    pub fn new(start: CowBytes, end: Option<CowBytes>) -> Self {
+        RangeTombstone { start, end }
+    }
+
+    /// The first key covered by this tombstone.
+    pub fn start(&self) -> &[u8] {
+        &self.start
+    }
+
+    /// The first key after `start` which is not covered anymore, `None` if
+    /// the range is unbounded.
+    pub fn end(&self) -> Option<&[u8]> {
+        self.end.as_deref()
+    }
+
+    /// Returns whether `key` is deleted by this tombstone.
+    pub fn covers(&self, key: &[u8]) -> bool {
+        key >= self.start() && self.end().map_or(true, |end| key < end)
+    }
+
+    /// Returns whether this tombstone covers no key at all.
+    pub fn is_empty(&self) -> bool {
+        self.end().map_or(false, |end| end <= self.start())
+    }
+
+    fn contains(&self, other: &Self) -> bool {
+        other.start() >= self.start()
+            && match (self.end(), other.end()) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(end), Some(other_end)) => other_end <= end,
+            }
+    }
+
+    /// Restricts this tombstone to the keys in `(lower, upper]`, which is the
+    /// key range of a child between two pivot keys. Returns `None` if no key
+    /// of this range remains.
+    pub fn clip(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Option<Self> {
+        // Appending a zero byte yields the smallest key greater than the pivot.
+        let successor = |pivot: &[u8]| {
+            let mut key = pivot.to_vec();
+            key.push(0);
+            CowBytes::from(key)
+        };
+
+        let mut start = self.start.clone();
+        if let Some(lower) = lower.map(successor) {
+            if lower > start {
+                start = lower;
+            }
+        }
+        let mut end = self.end.clone();
+        if let Some(upper) = upper.map(successor) {
+            if end.as_ref().map_or(true, |end| upper < *end) {
+                end = Some(upper);
+            }
+        }
+
+        Some(RangeTombstone { start, end }).filter(|tombstone| !tombstone.is_empty())
+    }
+}
+
+impl Size for RangeTombstone {
+    fn size(&self) -> usize {
+        self.start.size() + 1 + self.end.as_ref().map_or(0, |end| end.size())
+    }
+}
+
 impl Size for (KeyInfo, SlicedCowBytes) {
     fn size(&self) -> usize {
         let (_keyinfo, data) = self;
@@ -125,7 +214,8 @@ impl<N: StaticSize> Size for ChildBuffer<N> {
                     .buffer
                     .iter()
                     .map(|(key, msg)| key.size() + msg.size())
-                    .sum::<usize>(),
+                    .sum::<usize>()
+                + self.range_tombstones.iter().map(Size::size).sum::<usize>(),
         )
     }
 }
@@ -139,9 +229,22 @@ impl<N> ChildBuffer<N> {
         self.buffer_entries_size
     }
 
-    /// Returns whether there is no message in this buffer for the given `key`.
+    /// Returns whether there is no message in this buffer for the given `key`
+    /// and the key is not covered by a range tombstone.
     pub fn is_empty(&self, key: &[u8]) -> bool {
-        !self.buffer.contains_key(key)
+        !self.buffer.contains_key(key) && !self.is_range_deleted(key)
+    }
+
+    /// Returns whether a buffered range tombstone covers the given `key`.
+    pub fn is_range_deleted(&self, key: &[u8]) -> bool {
+        self.range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(key))
+    }
+
+    /// Returns all buffered range tombstones.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
     }
 
     pub fn get(&self, key: &[u8]) -> Option<&(KeyInfo, SlicedCowBytes)> {
@@ -173,8 +276,22 @@ impl<N> ChildBuffer<N> {
         )
     }
 
+    /// Restores the range tombstones of a buffer read from disk, whose
+    /// serialized entry size already accounts for them.
+    pub fn set_range_tombstones(&mut self, range_tombstones: Vec<RangeTombstone>) {
+        self.range_tombstones = range_tombstones;
+    }
+
+    /// Takes the range tombstones out of this `ChildBuffer`.
+    pub fn take_range_tombstones(&mut self) -> (Vec<RangeTombstone>, usize) {
+        let size = self.range_tombstones.iter().map(Size::size).sum::<usize>();
+        self.buffer_entries_size -= size;
+        (std::mem::take(&mut self.range_tombstones), size)
+    }
+
     pub fn append(&mut self, other: &mut Self) {
         self.buffer.append(&mut other.buffer);
+        self.range_tombstones.append(&mut other.range_tombstones);
         self.buffer_entries_size += other.buffer_entries_size;
         self.messages_preference
             .upgrade_atomic(&other.messages_preference);
@@ -184,10 +301,11 @@ impl<N> ChildBuffer<N> {
     /// so that `self` contains all entries up to (and including) `pivot_key`
     /// and the returned `Self` contains the other entries and `node_pointer`.
     pub fn split_at(&mut self, pivot: &CowBytes, node_pointer: N) -> Self {
-        let (buffer, buffer_entries_size) = self.split_off(pivot);
+        let (buffer, range_tombstones, buffer_entries_size) = self.split_off(pivot);
         ChildBuffer {
             messages_preference: AtomicStoragePreference::unknown(),
             buffer,
+            range_tombstones,
             buffer_entries_size,
             node_pointer: RwLock::new(node_pointer),
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
@@ -197,26 +315,49 @@ impl<N> ChildBuffer<N> {
     fn split_off(
         &mut self,
         pivot: &CowBytes,
-    ) -> (BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>, usize) {
+    ) -> (
+        BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
+        Vec<RangeTombstone>,
+        usize,
+    ) {
         // `split_off` puts the split-key into the right buffer.
         let mut next_key = pivot.to_vec();
         next_key.push(0);
         let right_buffer = self.buffer.split_off(&next_key[..]);
         self.messages_preference.invalidate();
 
-        let right_entry_size = right_buffer
+        let mut right_entry_size = right_buffer
             .iter()
             .map(|(key, value)| key.size() + value.size())
             .sum();
         self.buffer_entries_size -= right_entry_size;
-        (right_buffer, right_entry_size)
+
+        // Tombstones spanning the pivot are cut in two, one for each side.
+        let mut right_tombstones = Vec::new();
+        for tombstone in std::mem::take(&mut self.range_tombstones) {
+            self.buffer_entries_size -= tombstone.size();
+            if let Some(left) = tombstone.clip(None, Some(pivot)) {
+                self.buffer_entries_size += left.size();
+                self.range_tombstones.push(left);
+            }
+            if let Some(right) = tombstone.clip(Some(pivot), None) {
+                right_entry_size += right.size();
+                right_tombstones.push(right);
+            }
+        }
+        (right_buffer, right_tombstones, right_entry_size)
     }
 
-    pub fn rebalance(&mut self, right_sibling: &mut Self, new_pivot_key: &CowBytes) {
+    /// Redistributes the messages of `self` and `right_sibling` along
+    /// `new_pivot_key`. Returns the change in size of both buffers combined.
+    pub fn rebalance(&mut self, right_sibling: &mut Self, new_pivot_key: &CowBytes) -> isize {
+        let size_before = self.buffer_entries_size + right_sibling.buffer_entries_size;
         self.append(right_sibling);
-        let (buffer, buffer_entries_size) = self.split_off(new_pivot_key);
+        let (buffer, range_tombstones, buffer_entries_size) = self.split_off(new_pivot_key);
         right_sibling.buffer = buffer;
+        right_sibling.range_tombstones = range_tombstones;
         right_sibling.buffer_entries_size = buffer_entries_size;
+        (self.buffer_entries_size + buffer_entries_size) as isize - size_before as isize
     }
 
     /// Inserts a message to this buffer for the given `key`.
@@ -262,6 +403,7 @@ impl<N> ChildBuffer<N> {
         ChildBuffer {
             messages_preference: AtomicStoragePreference::known(StoragePreference::NONE),
             buffer: BTreeMap::new(),
+            range_tombstones: Vec::new(),
             buffer_entries_size: 0,
             node_pointer: RwLock::new(node_pointer),
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
@@ -298,6 +440,27 @@ impl<N> ChildBuffer<N> {
         self.messages_preference.invalidate();
         size_delta
     }
+
+    /// Buffers a range tombstone, which has to be restricted to the key range
+    /// of this buffer's child. All messages in the covered range are dropped
+    /// as they are older than the tombstone.
+    pub fn insert_range_tombstone(&mut self, tombstone: RangeTombstone) -> isize {
+        if tombstone.is_empty() {
+            return 0;
+        }
+        let removed_size = self.range_delete(tombstone.start(), tombstone.end()) as isize;
+        if self
+            .range_tombstones
+            .iter()
+            .any(|existing| existing.contains(&tombstone))
+        {
+            return -removed_size;
+        }
+        let tombstone_size = tombstone.size();
+        self.range_tombstones.push(tombstone);
+        self.buffer_entries_size += tombstone_size;
+        tombstone_size as isize - removed_size
+    }
 }
 
 #[cfg(test)]
@@ -314,6 +477,7 @@ mod tests {
                 messages_preference: self.messages_preference.clone(),
                 buffer_entries_size: self.buffer_entries_size,
                 buffer: self.buffer.clone(),
+                range_tombstones: self.range_tombstones.clone(),
                 node_pointer: RwLock::new(self.node_pointer.read().clone()),
                 system_storage_preference: self.system_storage_preference.clone(),
             }
@@ -324,6 +488,7 @@ mod tests {
         fn eq(&self, other: &Self) -> bool {
             self.buffer_entries_size == other.buffer_entries_size
                 && self.buffer == other.buffer
+                && self.range_tombstones == other.range_tombstones
                 && *self.node_pointer.read() == *other.node_pointer.read()
         }
     }
@@ -350,6 +515,7 @@ mod tests {
                     .map(|(key, value)| key.size() + value.size())
                     .sum::<usize>(),
                 buffer,
+                range_tombstones: Vec::new(),
                 node_pointer: RwLock::new(Arbitrary::arbitrary(g)),
                 system_storage_preference: AtomicSystemStoragePreference::from(
                     StoragePreference::NONE,
@@ -401,4 +567,44 @@ mod tests {
         buffer.append(&mut sibling.take().0);
         assert_eq!(this.buffer, buffer);
     }
+
+    #[quickcheck]
+    fn check_range_tombstone(
+        mut child_buffer: ChildBuffer<()>,
+        start: CowBytes,
+        end: Option<CowBytes>,
+        pivot_key: CowBytes,
+    ) {
+        let size_before = child_buffer.size();
+        let size_delta =
+            child_buffer.insert_range_tombstone(RangeTombstone::new(start.clone(), end.clone()));
+        assert_eq!(
+            (size_before as isize + size_delta) as usize,
+            child_buffer.size()
+        );
+        assert_eq!(
+            child_buffer.size(),
+            serialized_size(&child_buffer).unwrap() as usize
+        );
+        assert!(child_buffer
+            .buffer
+            .keys()
+            .all(|key| !child_buffer.is_range_deleted(key)));
+
+        let this = child_buffer.clone();
+        let sibling = child_buffer.split_at(&pivot_key, ());
+        assert_eq!(
+            child_buffer.size(),
+            serialized_size(&child_buffer).unwrap() as usize
+        );
+        assert_eq!(sibling.size(), serialized_size(&sibling).unwrap() as usize);
+        for key in [&start, &pivot_key] {
+            let side = if *key <= pivot_key {
+                &child_buffer
+            } else {
+                &sibling
+            };
+            assert_eq!(this.is_range_deleted(key), side.is_range_deleted(key));
+        }
+    }
 }
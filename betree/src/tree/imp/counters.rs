@@ -0,0 +1,114 @@
+//! Counters of the hot paths of a tree, to localize performance regressions
+//! without an external profiler.
+//!
+//! The counters are only kept with the `profiling-counters` feature, otherwise
+//! recording compiles to nothing.
+
+#[cfg(feature = "profiling-counters")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The counters of a single tree.
+#[cfg(feature = "profiling-counters")]
+#[derive(Debug, Default)]
+pub(super) struct Counters {
+    gets: AtomicU64,
+    get_node_fetches: AtomicU64,
+    get_replayed_messages: AtomicU64,
+    inserts: AtomicU64,
+    insert_flush_levels: AtomicU64,
+    max_insert_flush_levels: AtomicU64,
+}
+
+#[cfg(feature = "profiling-counters")]
+impl Counters {
+    pub(super) fn record_get(&self, node_fetches: u64, replayed_messages: u64) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        self.get_node_fetches
+            .fetch_add(node_fetches, Ordering::Relaxed);
+        self.get_replayed_messages
+            .fetch_add(replayed_messages, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_insert(&self, flush_levels: u64) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        self.insert_flush_levels
+            .fetch_add(flush_levels, Ordering::Relaxed);
+        self.max_insert_flush_levels
+            .fetch_max(flush_levels, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> TreeCounters {
+        TreeCounters {
+            gets: self.gets.load(Ordering::Relaxed),
+            get_node_fetches: self.get_node_fetches.load(Ordering::Relaxed),
+            get_replayed_messages: self.get_replayed_messages.load(Ordering::Relaxed),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            insert_flush_levels: self.insert_flush_levels.load(Ordering::Relaxed),
+            max_insert_flush_levels: self.max_insert_flush_levels.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(super) fn reset(&self) {
+        for counter in [
+            &self.gets,
+            &self.get_node_fetches,
+            &self.get_replayed_messages,
+            &self.inserts,
+            &self.insert_flush_levels,
+            &self.max_insert_flush_levels,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Stands in for the counters if they are disabled.
+#[cfg(not(feature = "profiling-counters"))]
+#[derive(Debug, Default)]
+pub(super) struct Counters;
+
+#[cfg(not(feature = "profiling-counters"))]
+impl Counters {
+    #[inline(always)]
+    pub(super) fn record_get(&self, _node_fetches: u64, _replayed_messages: u64) {}
+
+    #[inline(always)]
+    pub(super) fn record_insert(&self, _flush_levels: u64) {}
+}
+
+/// The counters of a tree since it has been opened or they have been reset,
+/// see [Dataset::counters](crate::database::Dataset::counters).
+#[cfg(feature = "profiling-counters")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct TreeCounters {
+    /// Point lookups, including those answered without reading any node.
+    pub gets: u64,
+    /// Nodes fetched by point lookups, from the cache or from disk.
+    pub get_node_fetches: u64,
+    /// Buffered messages applied to values by point lookups.
+    pub get_replayed_messages: u64,
+    /// Messages inserted, including deletions and upserts.
+    pub inserts: u64,
+    /// Levels descended by the flushes which followed inserts.
+    pub insert_flush_levels: u64,
+    /// The most levels descended by the flush following a single insert.
+    pub max_insert_flush_levels: u64,
+}
+
+#[cfg(feature = "profiling-counters")]
+impl TreeCounters {
+    /// Returns the average number of nodes fetched per point lookup.
+    pub fn node_fetches_per_get(&self) -> f64 {
+        self.get_node_fetches as f64 / self.gets.max(1) as f64
+    }
+
+    /// Returns the average number of messages applied per point lookup.
+    pub fn replayed_messages_per_get(&self) -> f64 {
+        self.get_replayed_messages as f64 / self.gets.max(1) as f64
+    }
+
+    /// Returns the average flush depth per insert.
+    pub fn flush_levels_per_insert(&self) -> f64 {
+        self.insert_flush_levels as f64 / self.inserts.max(1) as f64
+    }
+}
@@ -3,7 +3,12 @@
 //! Calling [Tree::rebalance_tree] is not only possible with the root node but may be
 //! applied to a variety of nodes given that their parent node is correctly
 //! given. Use with caution.
-use std::borrow::Borrow;
+use parking_lot::RwLock;
+use std::{
+    borrow::Borrow,
+    thread,
+    time::{Duration, Instant},
+};
 
 use super::{
     child_buffer::ChildBuffer, derivate_ref::DerivateRef, internal::TakeChildBuffer, FillUpResult,
@@ -11,11 +16,37 @@ use super::{
 };
 use crate::{
     cache::AddSize,
-    data_management::{Dml, HasStoragePreference, ObjectReference},
+    data_management::{Dml, HasStoragePreference, NodeEventKind, ObjectReference},
     size::Size,
     tree::{errors::*, imp::internal::MergeChildResult, MessageAction},
 };
 
+/// Limits how long a long-running operation holds the locks of a tree before
+/// releasing them at its next safe point, so that readers and writers of the
+/// same tree are not stalled for the whole operation.
+struct LockBudget {
+    budget: Option<Duration>,
+    since: Instant,
+}
+
+impl LockBudget {
+    fn new(budget: Option<Duration>) -> Self {
+        LockBudget {
+            budget,
+            since: Instant::now(),
+        }
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.budget
+            .map_or(false, |budget| self.since.elapsed() >= budget)
+    }
+
+    fn renew(&mut self) {
+        self.since = Instant::now();
+    }
+}
+
 impl<X, R, M, I> Tree<X, M, I>
 where
     X: Dml<Object = Node<R>, ObjectRef = R>,
@@ -48,16 +79,20 @@ where
     /// 8: If node is still too large, goto 1.
     /// 9: Set child as node, goto 1.
     /// ```
+    ///
+    /// Returns the number of levels the flush has descended below `node`.
     pub(super) fn rebalance_tree(
         &self,
         mut node: X::CacheValueRefMut,
         mut parent: Option<
             DerivateRef<X::CacheValueRefMut, TakeChildBuffer<'static, ChildBuffer<R>>>,
         >,
-    ) -> Result<(), Error> {
+    ) -> Result<u64, Error> {
+        let config = self.config();
+        let mut levels = 0;
         loop {
             if !node.is_too_large() {
-                return Ok(());
+                return Ok(levels);
             }
             debug!(
                 "{}, {:?}, lvl: {}, size: {}, actual: {:?}",
@@ -69,12 +104,12 @@ where
             );
             // 1. Select the largest child buffer which can be flushed.
             let mut child_buffer =
-                match DerivateRef::try_new(node, |node| node.try_find_flush_candidate()) {
+                match DerivateRef::try_new(node, |node| node.try_find_flush_candidate(config)) {
                     // 1.1. If there is none we have to split the node.
                     Err(_node) => match parent {
                         None => {
                             self.split_root_node(_node);
-                            return Ok(());
+                            return Ok(levels);
                         }
                         Some(ref mut parent) => {
                             let (next_node, size_delta) = self.split_node(_node, parent)?;
@@ -86,16 +121,18 @@ where
                     // 1.2. If successful we flush in the following steps to this node.
                     Ok(selected_child_buffer) => selected_child_buffer,
                 };
+            let start = Instant::now();
             let mut child = self.get_mut_node(child_buffer.node_pointer_mut())?;
             // 2. Iterate down to child if too large
             if !child.is_leaf() && child.is_too_large() {
                 warn!("Aborting flush, child is too large already");
                 parent = Some(child_buffer);
                 node = child;
+                levels += 1;
                 continue;
             }
             // 3. If child is internal, small and has not many children -> merge the children of node.
-            if child.has_too_low_fanout() {
+            if child.has_too_low_fanout(config) {
                 let size_delta = {
                     let mut m = child_buffer.prepare_merge();
                     let mut sibling = self.get_mut_node(m.sibling_node_pointer())?;
@@ -113,23 +150,40 @@ where
                         child.add_size(size_delta);
                     }
                     self.dml.remove(old_np);
+                    let merged = if is_right_sibling { &child } else { &sibling };
+                    self.emit_restructure_event(
+                        NodeEventKind::Merged,
+                        merged.level(),
+                        merged.size(),
+                        start,
+                    );
                     size_delta
                 };
                 child_buffer.add_size(size_delta);
                 node = child_buffer.into_owner();
                 continue;
             }
-            // 4. Remove messages from the child buffer.
+            // 4. Remove messages and range tombstones from the child buffer.
+            let (range_tombstones, size_delta) = child_buffer.take_range_tombstones();
+            child_buffer.add_size(size_delta);
             let (buffer, size_delta) = child_buffer.take_buffer();
             child_buffer.add_size(size_delta);
+            #[cfg(feature = "cache-paranoia")]
             self.dml.verify_cache();
-            // 5. Insert messages from the child buffer into the child.
+            // 5. Apply range tombstones to the child and insert messages from
+            // the child buffer afterwards, as these are younger.
+            for tombstone in range_tombstones {
+                let size_delta_child = child.range_delete(tombstone.start(), tombstone.end());
+                child.add_size(size_delta_child);
+            }
             let size_delta_child = child.insert_msg_buffer(buffer, self.msg_action());
             child.add_size(size_delta_child);
+            self.emit_restructure_event(NodeEventKind::Flushed, child.level(), child.size(), start);
 
             // 6. Check if minimal leaf size is fulfilled, otherwise merge again.
             if child.is_too_small_leaf() {
                 let size_delta = {
+                    let start = Instant::now();
                     let mut m = child_buffer.prepare_merge();
                     let mut sibling = self.get_mut_node(m.sibling_node_pointer())?;
                     let left;
@@ -149,6 +203,12 @@ where
                                 old_np, size_delta, ..
                             } = m.merge_children();
                             self.dml.remove(old_np);
+                            self.emit_restructure_event(
+                                NodeEventKind::Merged,
+                                left.level(),
+                                left.size(),
+                                start,
+                            );
                             size_delta
                         }
                         FillUpResult::Rebalanced {
@@ -183,6 +243,251 @@ where
             // Drop old parent here.
             parent = Some(child_buffer);
             node = child;
+            levels += 1;
+        }
+    }
+
+    /// Flushes all buffered messages and range tombstones down to the leaves.
+    ///
+    /// Regular flushes only empty the largest buffers of nodes which have
+    /// grown too large, so a tree usually keeps messages in its internal
+    /// nodes, which reads have to apply to the values of the leaves.  This
+    /// empties every buffer and splits nodes which become too large on the
+    /// way.  Leaves which become too small are left to
+    /// [Self::merge_underfull_leaves] on the next sync.
+    ///
+    /// Once the locks of the flushed nodes have been held for `budget`, they
+    /// are released after the current child buffer and the flush starts over
+    /// at the root, skipping the subtrees which have been flushed already.
+    pub(crate) fn flush_buffers(&self, budget: Option<Duration>) -> Result<(), Error> {
+        let mut budget = LockBudget::new(budget);
+        // Avoid modifying the root of a tree which has nothing to flush.
+        while self.has_buffered_messages(&self.inner.borrow().root_node)? {
+            let finished = {
+                let mut root = self.get_mut_root_node()?;
+                budget.renew();
+                let finished = self.flush_node(&mut root, &budget, None)?;
+                if root.is_too_large() {
+                    self.split_root_node(root);
+                }
+                finished
+            };
+            if finished {
+                break;
+            }
+            thread::yield_now();
+        }
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the buffered messages and range tombstones which may concern
+    /// keys within `start..=end` down to the leaves, like
+    /// [Self::flush_buffers] does for the whole tree.  Buffers are emptied as
+    /// a whole, so messages of keys beyond the range may be flushed as well.
+    pub(crate) fn flush_range(&self, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        if !self.has_buffered_messages(&self.inner.borrow().root_node)? {
+            return Ok(());
+        }
+        {
+            let mut root = self.get_mut_root_node()?;
+            self.flush_node(&mut root, &LockBudget::new(None), Some((start, end)))?;
+            if root.is_too_large() {
+                self.split_root_node(root);
+            }
+        }
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the given node or any internal node below it holds
+    /// messages.
+    fn has_buffered_messages(&self, np: &RwLock<R>) -> Result<bool, Error> {
+        let node = self.get_node(np)?;
+        if node.has_buffered_messages() {
+            return Ok(true);
+        }
+        if node.level() > 1 {
+            if let Some(children) = node.child_pointer_iter() {
+                for np in children {
+                    if self.has_buffered_messages(np)? {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns whether the subtree has been flushed completely, or whether the
+    /// flush has stopped early as `budget` is exhausted.  With a `range`, only
+    /// the children which may hold keys within it are flushed.
+    fn flush_node(
+        &self,
+        node: &mut X::CacheValueRefMut,
+        budget: &LockBudget,
+        range: Option<(&[u8], &[u8])>,
+    ) -> Result<bool, Error> {
+        let leaves_below = node.level() == 1;
+        let mut idx = match range {
+            Some((start, end)) => node
+                .child_idx_range(start, end)
+                .map_or(0, |(first, _)| first),
+            None => 0,
+        };
+        loop {
+            // Splits of flushed children move the end of the range.
+            if let Some((start, end)) = range {
+                if node
+                    .child_idx_range(start, end)
+                    .map_or(true, |(_, last)| idx > last)
+                {
+                    return Ok(true);
+                }
+            }
+            let (size_delta, finished) = {
+                let mut child_buffer = match node.take_child_buffer(idx) {
+                    Some(child_buffer) => child_buffer,
+                    None => return Ok(true),
+                };
+                idx += 1;
+                let (range_tombstones, mut size_delta) = child_buffer.take_range_tombstones();
+                let (buffer, buffer_delta) = child_buffer.take_buffer();
+                size_delta += buffer_delta;
+                if range_tombstones.is_empty()
+                    && buffer.is_empty()
+                    && (leaves_below
+                        || !self.has_buffered_messages(child_buffer.node_pointer_mut())?)
+                {
+                    continue;
+                }
+
+                let start = Instant::now();
+                let mut child = self.get_mut_node(child_buffer.node_pointer_mut())?;
+                // Range tombstones are older than the messages of the buffer.
+                for tombstone in range_tombstones {
+                    let size_delta_child = child.range_delete(tombstone.start(), tombstone.end());
+                    child.add_size(size_delta_child);
+                }
+                let size_delta_child = child.insert_msg_buffer(buffer, self.msg_action());
+                child.add_size(size_delta_child);
+                self.emit_restructure_event(
+                    NodeEventKind::Flushed,
+                    child.level(),
+                    child.size(),
+                    start,
+                );
+                let finished = child.is_leaf() || self.flush_node(&mut child, budget, range)?;
+                // Siblings split off here have empty buffers and are skipped.
+                while child.is_too_large() {
+                    let (next_node, split_delta) = self.split_node(child, &mut child_buffer)?;
+                    size_delta += split_delta;
+                    child = next_node;
+                }
+                (size_delta, finished)
+            };
+            node.add_size(size_delta);
+            // The node is consistent again, so its lock may be released.
+            if !finished || budget.is_exhausted() {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Merges under-full leaves with their siblings before the tree is
+    /// written back.
+    ///
+    /// Leaves which shrink below [super::MIN_LEAF_NODE_SIZE] are otherwise
+    /// only merged if a flush happens to touch them. To keep the occupancy
+    /// of written leaves high, all modified nodes are visited here and
+    /// adjacent leaves are merged or rebalanced if one of them is too small
+    /// and at least one of them is modified. Only leaves which are present in
+    /// the cache are considered, so this does not cause additional reads.
+    pub(super) fn merge_underfull_leaves(&self) -> Result<(), Error> {
+        let root = match self.dml.try_get_mut(&self.inner.borrow().root_node.read()) {
+            Some(root) => root,
+            // An unmodified tree has nothing to write back.
+            None => return Ok(()),
+        };
+        let mut nodes = vec![root];
+        while let Some(mut node) = nodes.pop() {
+            if node.level() == 1 {
+                self.merge_underfull_children(&mut node)?;
+            } else if let Some(children) = node.child_pointer_iter_mut() {
+                nodes.extend(children.filter_map(|np| self.dml.try_get_mut(np)));
+            }
         }
+        Ok(())
+    }
+
+    fn merge_underfull_children(&self, node: &mut X::CacheValueRefMut) -> Result<(), Error> {
+        let mut idx = 0;
+        loop {
+            // Never reduce the fanout far enough to require a merge of `node`
+            // itself.
+            match node.fanout() {
+                Some(fanout) if fanout > self.config().min_fanout && idx + 1 < fanout => {}
+                _ => return Ok(()),
+            }
+            let size_delta = {
+                let mut child_buffer = node.take_child_buffer(idx).unwrap();
+                let left_state = self.leaf_merge_state(child_buffer.node_pointer_mut());
+                let right_state =
+                    self.leaf_merge_state(child_buffer.prepare_merge().sibling_node_pointer());
+                match (left_state, right_state) {
+                    (Some((left_modified, left_small)), Some((right_modified, right_small)))
+                        if (left_modified || right_modified) && (left_small || right_small) => {}
+                    _ => {
+                        idx += 1;
+                        continue;
+                    }
+                }
+
+                let start = Instant::now();
+                let mut left = self.get_mut_node(child_buffer.node_pointer_mut())?;
+                let mut m = child_buffer.prepare_merge();
+                let mut right = self.get_mut_node(m.sibling_node_pointer())?;
+                match left.leaf_rebalance(&mut right) {
+                    FillUpResult::Merged { size_delta } => {
+                        left.add_size(size_delta);
+                        right.add_size(-size_delta);
+                        let MergeChildResult {
+                            old_np, size_delta, ..
+                        } = m.merge_children();
+                        self.dml.remove(old_np);
+                        self.emit_restructure_event(
+                            NodeEventKind::Merged,
+                            left.level(),
+                            left.size(),
+                            start,
+                        );
+                        size_delta
+                    }
+                    FillUpResult::Rebalanced {
+                        pivot_key,
+                        size_delta,
+                    } => {
+                        left.add_size(size_delta);
+                        right.add_size(-size_delta);
+                        idx += 1;
+                        m.rebalanced(pivot_key)
+                    }
+                }
+            };
+            node.add_size(size_delta);
+        }
+    }
+
+    /// Returns whether the given leaf is modified and whether it is too small,
+    /// or `None` if it is not cached.
+    fn leaf_merge_state(&self, np: &mut RwLock<R>) -> Option<(bool, bool)> {
+        let modified = np.get_mut().get_unmodified().is_none();
+        self.dml
+            .try_get(np.get_mut())
+            .map(|node| (modified, node.is_too_small_leaf()))
     }
 }
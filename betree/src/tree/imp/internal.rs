@@ -1,6 +1,6 @@
 //! Implementation of the [InternalNode] node type.
 use super::{
-    child_buffer::ChildBuffer,
+    child_buffer::{ChildBuffer, RangeTombstone},
     node::{PivotGetMutResult, PivotGetResult},
     PivotKey,
 };
@@ -157,6 +157,13 @@ impl<T> InternalNode<T> {
         self.level
     }
 
+    /// Returns whether any child buffer holds messages or range tombstones.
+    pub fn has_buffered_messages(&self) -> bool {
+        self.children
+            .iter()
+            .any(|child| child.buffer_size() > 0 || !child.range_tombstones().is_empty())
+    }
+
     /// Returns the index of the child buffer
     /// corresponding to the given `key`.
     fn idx(&self, key: &[u8]) -> usize {
@@ -201,6 +208,12 @@ impl<N> InternalNode<ChildBuffer<N>> {
         (&child.node_pointer, msg)
     }
 
+    /// Returns whether the subtree below the child buffer of `key` is masked
+    /// by a pending range deletion.
+    pub fn is_range_deleted(&self, key: &[u8]) -> bool {
+        self.children[self.idx(key)].is_range_deleted(key)
+    }
+
     pub fn pivot_get(&self, pk: &PivotKey) -> PivotGetResult<N> {
         // Exact pivot matches are required only
         debug_assert!(!pk.is_root());
@@ -272,6 +285,7 @@ impl<N> InternalNode<ChildBuffer<N>> {
         left_pivot_key: &mut Option<CowBytes>,
         right_pivot_key: &mut Option<CowBytes>,
         all_msgs: &mut BTreeMap<CowBytes, Vec<(KeyInfo, SlicedCowBytes)>>,
+        range_tombstones: &mut Vec<RangeTombstone>,
     ) -> &RwLock<N> {
         let idx = self.idx(key);
         if idx > 0 {
@@ -282,15 +296,53 @@ impl<N> InternalNode<ChildBuffer<N>> {
         }
         let child = &self.children[idx];
         for (key, msg) in child.get_all_messages() {
+            // Messages below a tombstone of an upper level are stale.
+            if range_tombstones
+                .iter()
+                .any(|tombstone| tombstone.covers(key))
+            {
+                continue;
+            }
             all_msgs
                 .entry(key.clone())
                 .or_insert_with(Vec::new)
                 .push(msg.clone());
         }
+        range_tombstones.extend(child.range_tombstones().iter().cloned());
 
         &child.node_pointer
     }
 
+    /// Returns the indices of the first and the last child which may contain
+    /// keys within `start..=end`.
+    pub(super) fn idx_range(&self, start: &[u8], end: Option<&[u8]>) -> (usize, usize) {
+        let first = self.idx(start);
+        let last = end
+            .map_or(self.children.len() - 1, |end| self.idx(end))
+            .max(first);
+        (first, last)
+    }
+
+    /// Returns the node pointers of all children which may contain keys
+    /// within `start..=end`.
+    pub fn children_in_range(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> impl Iterator<Item = &RwLock<N>> + '_ {
+        let (first, last) = self.idx_range(start, end);
+        self.children[first..=last]
+            .iter()
+            .map(|child| &child.node_pointer)
+    }
+
+    /// Returns the pivots separating the children returned by
+    /// `children_in_range`.
+    pub fn pivots_in_range(&self, start: &[u8], end: Option<&[u8]>) -> &[CowBytes] {
+        let (first, last) = self.idx_range(start, end);
+        &self.pivot[first..last]
+    }
+
     pub fn get_next_node(&self, key: &[u8]) -> Option<&RwLock<N>> {
         let idx = self.idx(key) + 1;
         self.children.get(idx).map(|child| &child.node_pointer)
@@ -342,6 +394,31 @@ impl<N> InternalNode<ChildBuffer<N>> {
         added_size
     }
 
+    /// Buffers a deletion of all keys in `start..end` in every child buffer
+    /// overlapping with this range.
+    pub fn insert_range_tombstone(&mut self, start: &[u8], end: Option<&[u8]>) -> isize {
+        self.pref.invalidate();
+        let tombstone = RangeTombstone::new(start.into(), end.map(CowBytes::from));
+        let start_idx = self.idx(start);
+        let end_idx = end.map_or(self.children.len() - 1, |end| self.idx(end));
+        let mut added_size = 0;
+
+        for idx in start_idx..=end_idx {
+            let lower = idx.checked_sub(1).map(|idx| &self.pivot[idx][..]);
+            let upper = self.pivot.get(idx).map(|pivot| &pivot[..]);
+            if let Some(tombstone) = tombstone.clip(lower, upper) {
+                added_size += self.children[idx].insert_range_tombstone(tombstone);
+            }
+        }
+
+        if added_size > 0 {
+            self.entries_size += added_size as usize;
+        } else {
+            self.entries_size -= -added_size as usize;
+        }
+        added_size
+    }
+
     pub fn drain_children(&mut self) -> impl Iterator<Item = N> + '_ {
         self.pref.invalidate();
         self.entries_size = 0;
@@ -492,6 +569,20 @@ where
         }
     }
 
+    pub fn take_child_buffer(
+        &mut self,
+        child_idx: usize,
+    ) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
+        if child_idx < self.children.len() {
+            Some(TakeChildBuffer {
+                node: self,
+                child_idx,
+            })
+        } else {
+            None
+        }
+    }
+
     pub fn try_find_flush_candidate(
         &mut self,
         min_flush_size: usize,
@@ -541,8 +632,12 @@ impl<'a, N: StaticSize + HasStoragePreference> TakeChildBuffer<'a, ChildBuffer<N
         // is added to self, the overall entries don't change, so this node doesn't need to be
         // invalidated
 
+        let size_before = self.node.children[self.child_idx].size();
         let sibling = self.node.children[self.child_idx].split_at(&pivot_key, sibling_np);
-        let size_delta = sibling.size() + pivot_key.size();
+        // Range tombstones spanning the pivot key are duplicated, so the
+        // buffer may grow in total.
+        let size_after = self.node.children[self.child_idx].size();
+        let size_delta = size_after + sibling.size() + pivot_key.size() - size_before;
         self.node.children.insert(self.child_idx + 1, sibling);
         self.node.pivot.insert(self.child_idx, pivot_key);
         self.node.entries_size += size_delta;
@@ -628,13 +723,18 @@ impl<'a, N: Size + HasStoragePreference> PrepareMergeChild<'a, ChildBuffer<N>> {
     }
 
     pub(super) fn rebalanced(&mut self, new_pivot_key: CowBytes) -> isize {
-        {
+        let buffer_size_delta = {
             // Move messages around
             let (left_child, right_child) = self.get_children();
-            left_child.rebalance(right_child, &new_pivot_key);
+            left_child.rebalance(right_child, &new_pivot_key)
+        };
+        if buffer_size_delta > 0 {
+            self.node.entries_size += buffer_size_delta as usize;
+        } else {
+            self.node.entries_size -= -buffer_size_delta as usize;
         }
 
-        let mut size_delta = new_pivot_key.size() as isize;
+        let mut size_delta = new_pivot_key.size() as isize + buffer_size_delta;
         let old_pivot_key = replace(&mut self.node.pivot[self.pivot_key_idx], new_pivot_key);
         size_delta -= old_pivot_key.size() as isize;
 
@@ -651,11 +751,17 @@ impl<'a, N: Size + HasStoragePreference> TakeChildBuffer<'a, ChildBuffer<N>> {
         self.node.entries_size -= size_delta;
         (buffer, -(size_delta as isize))
     }
+
+    pub fn take_range_tombstones(&mut self) -> (Vec<RangeTombstone>, isize) {
+        let (range_tombstones, size_delta) =
+            self.node.children[self.child_idx].take_range_tombstones();
+        self.node.entries_size -= size_delta;
+        (range_tombstones, -(size_delta as isize))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    
 
     use super::*;
     use crate::{
@@ -664,7 +770,7 @@ mod tests {
         tree::default_message_action::{DefaultMessageAction, DefaultMessageActionMsg},
     };
     use bincode::serialized_size;
-    
+
     use quickcheck::{Arbitrary, Gen, TestResult};
     use rand::Rng;
     use serde::Serialize;
@@ -4,10 +4,19 @@ use crate::{
     data_management::HasStoragePreference,
     size::Size,
     storage_pool::AtomicSystemStoragePreference,
-    tree::{imp::packed, pivot_key::LocalPivotKey, KeyInfo, MessageAction},
+    tree::{
+        imp::{access::AccessSample, packed},
+        pivot_key::LocalPivotKey,
+        KeyInfo, MessageAction,
+    },
     AtomicStoragePreference, StoragePreference,
 };
-use std::{borrow::Borrow, collections::BTreeMap, iter::FromIterator};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, Bound},
+    iter::FromIterator,
+    mem,
+};
 
 /// A leaf node of the tree holds pairs of keys values which are plain data.
 #[derive(Debug, Clone)]
@@ -18,6 +27,7 @@ pub(super) struct LeafNode {
     system_storage_preference: AtomicSystemStoragePreference,
     entries_size: usize,
     entries: BTreeMap<CowBytes, (KeyInfo, SlicedCowBytes)>,
+    accesses: AccessSample,
 }
 
 /// Case-dependent outcome of a rebalance operation.
@@ -123,6 +133,7 @@ impl<'a> FromIterator<(&'a [u8], (KeyInfo, SlicedCowBytes))> for LeafNode {
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
             entries_size,
             entries,
+            accesses: AccessSample::default(),
         }
     }
 }
@@ -135,6 +146,7 @@ impl LeafNode {
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
             entries_size: 0,
             entries: BTreeMap::new(),
+            accesses: AccessSample::default(),
         }
     }
 
@@ -155,33 +167,98 @@ impl LeafNode {
         self.entries.get_mut(key).map(|e| &mut e.0)
     }
 
+    pub(super) fn accesses(&self) -> &AccessSample {
+        &self.accesses
+    }
+
+    pub(super) fn set_accesses(&mut self, accesses: AccessSample) {
+        self.accesses = accesses;
+    }
+
+    /// Returns the first key of the right sibling such that it holds at least
+    /// `min_size` bytes.
+    fn size_split_key(&self, min_size: usize) -> CowBytes {
+        let mut sibling_size = 0;
+        for (k, (_keyinfo, v)) in self.entries.iter().rev() {
+            sibling_size += packed::ENTRY_LEN + k.len() + v.len();
+            if packed::HEADER_FIXED_LEN + sibling_size >= min_size {
+                return k.clone();
+            }
+        }
+        unreachable!("leaf to split is smaller than min_size")
+    }
+
+    /// Returns the first key of the right sibling such that all recently read
+    /// keys end up in the smaller of both nodes, which is then likely to stay
+    /// cached.  Returns `None` if there are no samples or if the read keys are
+    /// spread too far for a node of at least `min_size` bytes to hold them.
+    fn hot_split_key(&self, min_size: usize) -> Option<CowBytes> {
+        let (lo, hi) = self.accesses.bounds()?;
+        let entry_size = |k: &CowBytes, v: &SlicedCowBytes| packed::ENTRY_LEN + k.len() + v.len();
+        // Candidates as the size of the node holding the hot keys, the size of
+        // the other node and the split key.
+        let mut candidates = Vec::with_capacity(2);
+
+        // The hot keys in the left node.
+        let mut hot_size = 0;
+        let mut iter = self.entries.iter().peekable();
+        while let Some((k, (_keyinfo, v))) = iter.next() {
+            hot_size += entry_size(k, v);
+            if *k >= hi && packed::HEADER_FIXED_LEN + hot_size >= min_size {
+                if let Some((next, _)) = iter.peek() {
+                    candidates.push((hot_size, self.entries_size - hot_size, (*next).clone()));
+                }
+                break;
+            }
+        }
+
+        // The hot keys in the right node.
+        let mut hot_size = 0;
+        for (k, (_keyinfo, v)) in self.entries.iter().rev() {
+            hot_size += entry_size(k, v);
+            if *k <= lo && packed::HEADER_FIXED_LEN + hot_size >= min_size {
+                candidates.push((hot_size, self.entries_size - hot_size, k.clone()));
+                break;
+            }
+        }
+
+        candidates
+            .into_iter()
+            .filter(|(hot_size, cold_size, _)| {
+                packed::HEADER_FIXED_LEN + cold_size >= min_size && hot_size <= cold_size
+            })
+            .min_by_key(|(hot_size, ..)| *hot_size)
+            .map(|(.., split_key)| split_key)
+    }
+
     /// Split the node and transfer entries to a given other node `right_sibling`.
     /// Use entries which are, when summed up in-order, above the `min_size` limit.
+    /// If `access_aware` is set, the split point is chosen to keep the recently
+    /// read keys together in the smaller node, if possible.
     /// Returns new pivot key and size delta to the left sibling.
     fn do_split_off(
         &mut self,
         right_sibling: &mut Self,
         min_size: usize,
         max_size: usize,
+        access_aware: bool,
     ) -> (CowBytes, isize) {
         debug_assert!(self.size() > max_size);
         debug_assert!(right_sibling.entries_size == 0);
 
+        let split_key = access_aware
+            .then(|| self.hot_split_key(min_size))
+            .flatten()
+            .unwrap_or_else(|| self.size_split_key(min_size));
+
+        right_sibling.entries = self.entries.split_off(&split_key);
+        right_sibling.accesses = self.accesses.split_off(&split_key);
         let mut sibling_size = 0;
         let mut sibling_pref = StoragePreference::NONE;
-        let mut split_key = None;
-        for (k, (keyinfo, v)) in self.entries.iter().rev() {
+        for (k, (keyinfo, v)) in right_sibling.entries.iter() {
             sibling_size += packed::ENTRY_LEN + k.len() + v.len();
             sibling_pref.upgrade(keyinfo.storage_preference);
-
-            if packed::HEADER_FIXED_LEN + sibling_size >= min_size {
-                split_key = Some(k.clone());
-                break;
-            }
         }
-        let split_key = split_key.unwrap();
-
-        right_sibling.entries = self.entries.split_off(&split_key);
         self.entries_size -= sibling_size;
         right_sibling.entries_size = sibling_size;
         right_sibling.storage_preference.set(sibling_pref);
@@ -191,8 +268,9 @@ impl LeafNode {
 
         let size_delta = -(sibling_size as isize);
 
-        let pivot_key = self.entries.keys().next_back().cloned().unwrap();
-        (pivot_key, size_delta)
+        let left_max = self.entries.keys().next_back().unwrap();
+        let right_min = right_sibling.entries.keys().next().unwrap();
+        (shortest_separator(left_max, right_min), size_delta)
     }
 
     pub fn apply<K>(&mut self, key: K, pref: StoragePreference) -> Option<KeyInfo>
@@ -220,7 +298,13 @@ impl LeafNode {
     {
         let size_before = self.entries_size as isize;
         let key_size = key.borrow().len();
-        let mut data = self.get(key.borrow());
+        // Take the current value out of the entry so that the message action
+        // holds the only reference and may patch it without copying.
+        let mut data = self.entries.get_mut(key.borrow()).map(|entry| {
+            let data = mem::take(&mut entry.1);
+            self.entries_size -= data.len();
+            data
+        });
         msg_action.apply_to_leaf(key.borrow(), msg, &mut data);
 
         if let Some(data) = data {
@@ -280,12 +364,19 @@ impl LeafNode {
     }
 
     /// Splits this `LeafNode` into to two leaf nodes.
+    /// The pivot key is the shortest key separating both nodes, which is not
+    /// necessarily contained in either of them.
+    /// If `access_aware` is set, the split point is chosen by the recently read
+    /// keys instead of by size alone, see [TreeConfiguration::access_aware_splits].
     /// Returns a new right sibling, the corresponding pivot key, and the size
     /// delta of this node.
+    ///
+    /// [TreeConfiguration::access_aware_splits]: super::TreeConfiguration::access_aware_splits
     pub fn split(
         &mut self,
         min_size: usize,
         max_size: usize,
+        access_aware: bool,
     ) -> (Self, CowBytes, isize, LocalPivotKey) {
         // assert!(self.size() > S::MAX);
         let mut right_sibling = LeafNode {
@@ -295,10 +386,12 @@ impl LeafNode {
             system_storage_preference: AtomicSystemStoragePreference::from(StoragePreference::NONE),
             entries_size: 0,
             entries: BTreeMap::new(),
+            accesses: AccessSample::default(),
         };
 
         // This adjusts sibling's size and pref according to its new entries
-        let (pivot_key, size_delta) = self.do_split_off(&mut right_sibling, min_size, max_size);
+        let (pivot_key, size_delta) =
+            self.do_split_off(&mut right_sibling, min_size, max_size, access_aware);
 
         (
             right_sibling,
@@ -343,7 +436,7 @@ impl LeafNode {
         } else {
             // First size_delta is from the merge operation where we split
             let (pivot_key, split_size_delta) =
-                self.do_split_off(right_sibling, min_size, max_size);
+                self.do_split_off(right_sibling, min_size, max_size, false);
             FillUpResult::Rebalanced {
                 pivot_key,
                 size_delta: size_delta + split_size_delta,
@@ -351,23 +444,53 @@ impl LeafNode {
         }
     }
 
-    /*pub fn range_delete(&mut self, start: &[u8], end: Option<&[u8]>) -> usize {
-        // https://github.com/rust-lang/rust/issues/42849
+    /// Removes all entries in `start..end` and returns the number of bytes
+    /// freed. This is where pending range tombstones are finally compacted.
+    pub fn range_delete(&mut self, start: &[u8], end: Option<&[u8]>) -> usize {
+        if end.map_or(false, |end| end <= start) {
+            return 0;
+        }
         let size_before = self.entries_size;
         let range = (
             Bound::Included(start),
             end.map_or(Bound::Unbounded, Bound::Excluded),
         );
-        let mut keys = Vec::new();
-        for (key, (_keyinfo, value)) in self.entries.range_mut::<[u8], _>(range) {
-            self.entries_size -= key.len() + value.len();
-            keys.push(key.clone());
-        }
+        let keys: Vec<CowBytes> = self
+            .entries
+            .range::<[u8], _>(range)
+            .map(|(key, _)| key.clone())
+            .collect();
         for key in keys {
-            self.entries.remove(&key);
+            if let Some((_keyinfo, value)) = self.entries.remove(&key) {
+                self.entries_size -= packed::ENTRY_LEN + key.len() + value.len();
+            }
+        }
+        if size_before != self.entries_size {
+            self.storage_preference.invalidate();
         }
         size_before - self.entries_size
-    }*/
+    }
+}
+
+/// Returns the shortest key `pivot` with `left <= pivot < right`, so that
+/// pivots of internal nodes do not have to store the full keys of the leaves.
+/// `left` has to be smaller than `right`.
+fn shortest_separator(left: &[u8], right: &[u8]) -> CowBytes {
+    debug_assert!(left < right);
+    let common = left.iter().zip(right).take_while(|(l, r)| l == r).count();
+    // Any key beginning with a prefix of `left` whose last byte is increased
+    // is larger than `left`, and smaller than `right` if they still differ.
+    for len in common + 1..left.len() {
+        if left[len - 1] == u8::MAX {
+            continue;
+        }
+        let mut pivot = left[..len].to_vec();
+        pivot[len - 1] += 1;
+        if &pivot[..] < right {
+            return CowBytes::from(pivot);
+        }
+    }
+    CowBytes::from(left)
 }
 
 #[cfg(test)]
@@ -493,7 +616,8 @@ mod tests {
             return TestResult::discard();
         }
 
-        let (sibling, _, size_delta, _pivot_key) = leaf_node.split(MIN_LEAF_SIZE, MAX_LEAF_SIZE);
+        let (sibling, _, size_delta, _pivot_key) =
+            leaf_node.split(MIN_LEAF_SIZE, MAX_LEAF_SIZE, false);
         assert_eq!({ serialized_size(&leaf_node) }, leaf_node.size());
         assert_eq!({ serialized_size(&sibling) }, sibling.size());
         assert_eq!(
@@ -506,13 +630,88 @@ mod tests {
         TestResult::passed()
     }
 
+    #[quickcheck]
+    fn check_split_pivot(mut leaf_node: LeafNode) -> TestResult {
+        if leaf_node.size() <= MAX_LEAF_SIZE {
+            return TestResult::discard();
+        }
+        let (sibling, pivot, ..) = leaf_node.split(MIN_LEAF_SIZE, MAX_LEAF_SIZE, false);
+        assert!(leaf_node.entries().keys().all(|key| key <= &pivot));
+        assert!(sibling.entries().keys().all(|key| key > &pivot));
+        TestResult::passed()
+    }
+
+    #[quickcheck]
+    fn check_access_aware_split(mut leaf_node: LeafNode, hot: usize) -> TestResult {
+        let size_before = leaf_node.size();
+        if size_before <= MAX_LEAF_SIZE {
+            return TestResult::discard();
+        }
+        let keys: Vec<CowBytes> = leaf_node.entries().keys().cloned().collect();
+        let hot_keys = &keys[hot % keys.len()..][..2.min(keys.len() - hot % keys.len())];
+        for key in hot_keys {
+            leaf_node.accesses().record(key);
+        }
+        let (_, size_pivot, ..) = leaf_node.clone().split(MIN_LEAF_SIZE, MAX_LEAF_SIZE, false);
+
+        let (sibling, pivot, size_delta, _pivot_key) =
+            leaf_node.split(MIN_LEAF_SIZE, MAX_LEAF_SIZE, true);
+        assert_eq!({ serialized_size(&leaf_node) }, leaf_node.size());
+        assert_eq!({ serialized_size(&sibling) }, sibling.size());
+        assert_eq!(
+            (size_before as isize + size_delta) as usize,
+            leaf_node.size()
+        );
+        assert!(sibling.size() >= MIN_LEAF_SIZE);
+        assert!(leaf_node.size() >= MIN_LEAF_SIZE);
+        if pivot != size_pivot {
+            // The hot keys have been kept together in the smaller node.
+            let (hot_node, cold_node) = if hot_keys[0] > pivot {
+                (&sibling, &leaf_node)
+            } else {
+                (&leaf_node, &sibling)
+            };
+            assert!(hot_keys
+                .iter()
+                .all(|key| hot_node.entries().contains_key(key)));
+            assert!(hot_node.size() <= cold_node.size());
+            assert!(cold_node.accesses().bounds().is_none());
+        }
+        TestResult::passed()
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(&shortest_separator(b"abc", b"abd")[..], b"abc");
+        assert_eq!(&shortest_separator(b"abcdef", b"abd")[..], b"abce");
+        assert_eq!(&shortest_separator(b"abcdef", b"abzz")[..], b"abd");
+        assert_eq!(&shortest_separator(b"ab", b"abc")[..], b"ab");
+        assert_eq!(&shortest_separator(b"a\xff\xffx", b"b")[..], b"a\xff\xffx");
+        assert_eq!(
+            &shortest_separator(b"user0001-long", b"user0002")[..],
+            b"user0001."
+        );
+    }
+
+    #[quickcheck]
+    fn check_range_delete(mut leaf_node: LeafNode, start: CowBytes, end: Option<CowBytes>) {
+        let size_before = leaf_node.size();
+        let size_delta = leaf_node.range_delete(&start, end.as_deref());
+        assert_eq!(size_before - size_delta, leaf_node.size());
+        assert_eq!({ serialized_size(&leaf_node) }, leaf_node.size());
+        assert!(leaf_node
+            .entries()
+            .keys()
+            .all(|key| key < &start || end.as_ref().map_or(false, |end| key >= end)));
+    }
+
     #[quickcheck]
     fn check_split_merge_idempotent(mut leaf_node: LeafNode) -> TestResult {
         if leaf_node.size() <= MAX_LEAF_SIZE {
             return TestResult::discard();
         }
         let this = leaf_node.clone();
-        let (mut sibling, ..) = leaf_node.split(MIN_LEAF_SIZE, MAX_LEAF_SIZE);
+        let (mut sibling, ..) = leaf_node.split(MIN_LEAF_SIZE, MAX_LEAF_SIZE, false);
         leaf_node.recalculate();
         leaf_node.merge(&mut sibling);
         assert_eq!(this, leaf_node);
@@ -1,6 +1,8 @@
 //! Implementation of tree structures.
 use self::{
+    counters::Counters,
     derivate_ref::DerivateRef,
+    negative_cache::NegativeCache,
     node::{ApplyResult, GetResult, PivotGetMutResult, PivotGetResult},
 };
 use super::{
@@ -11,17 +13,23 @@ use super::{
 use crate::{
     cache::AddSize,
     cow_bytes::{CowBytes, SlicedCowBytes},
-    data_management::{Dml, HasStoragePreference, ObjectReference},
+    data_management::{Dml, HasStoragePreference, NodeEvent, NodeEventKind, ObjectReference},
     database::DatasetId,
     range_validation::is_inclusive_non_empty,
-    size::StaticSize,
+    size::{Size, StaticSize},
     tree::MessageAction,
     StoragePreference,
 };
 use leaf::FillUpResult;
 use owning_ref::OwningRef;
 use parking_lot::{RwLock, RwLockWriteGuard};
-use std::{borrow::Borrow, marker::PhantomData, mem, ops::RangeBounds};
+use std::{
+    borrow::Borrow,
+    marker::PhantomData,
+    mem,
+    ops::{Bound, RangeBounds},
+    time::Instant,
+};
 
 /// Additional information for a single entry. Concerns meta information like
 /// the desired storage level of a key.
@@ -58,6 +66,41 @@ const MIN_LEAF_NODE_SIZE: usize = 1024 * 1024;
 const MAX_LEAF_NODE_SIZE: usize = MAX_INTERNAL_NODE_SIZE;
 pub(crate) const MAX_MESSAGE_SIZE: usize = 512 * 1024;
 
+/// Tunables of the buffering of a single tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TreeConfiguration {
+    /// Internal nodes with fewer children are merged with a sibling.  A higher
+    /// fanout keeps the tree shallow, which favors reads.
+    pub min_fanout: usize,
+    /// Buffers of internal nodes are flushed to their child once they hold
+    /// this many bytes.  Larger buffers batch more messages per flush, which
+    /// favors writes.
+    pub min_flush_size: usize,
+    /// Leaves are split such that the recently read keys stay together in the
+    /// smaller node, instead of purely by size.  Reads are sampled per leaf
+    /// while it is cached, which adds a little overhead to every read.
+    pub access_aware_splits: bool,
+}
+
+impl Default for TreeConfiguration {
+    fn default() -> Self {
+        TreeConfiguration {
+            min_fanout: MIN_FANOUT,
+            min_flush_size: MIN_FLUSH_SIZE,
+            access_aware_splits: false,
+        }
+    }
+}
+
+impl TreeConfiguration {
+    /// The largest `min_flush_size` allowed for the given `min_fanout`.
+    /// Internal nodes which are too large and have too few children to be
+    /// split have to contain a buffer of at least this size.
+    pub fn max_flush_size(min_fanout: usize) -> usize {
+        MAX_INTERNAL_NODE_SIZE / (4 * min_fanout.max(1))
+    }
+}
+
 /// The actual tree type.
 pub struct Tree<X: Dml, M, I: Borrow<Inner<X::ObjectRef, M>>> {
     inner: I,
@@ -84,14 +127,20 @@ pub struct Inner<R, M> {
     root_node: RwLock<R>,
     tree_id: Option<DatasetId>,
     msg_action: M,
+    config: TreeConfiguration,
+    negative_cache: NegativeCache,
+    counters: Counters,
 }
 
 impl<R, M> Inner<R, M> {
-    fn new(tree_id: DatasetId, root_node: R, msg_action: M) -> Self {
+    fn new(tree_id: DatasetId, root_node: R, msg_action: M, config: TreeConfiguration) -> Self {
         Inner {
             tree_id: Some(tree_id),
             root_node: RwLock::new(root_node),
             msg_action,
+            config,
+            negative_cache: NegativeCache::new(),
+            counters: Counters::default(),
         }
     }
 
@@ -101,12 +150,24 @@ impl<R, M> Inner<R, M> {
             tree_id: None,
             root_node: RwLock::new(root_node),
             msg_action,
+            config: TreeConfiguration::default(),
+            negative_cache: NegativeCache::new(),
+            counters: Counters::default(),
         }
     }
 
     /// Sets a new root node.
     pub fn update_root_node(&mut self, root_node: R) {
         *self.root_node.get_mut() = root_node;
+        self.negative_cache.clear();
+    }
+}
+
+impl<R: ObjectReference, M> Inner<R, M> {
+    /// Returns the pointer to the root node, unless it has been modified
+    /// since it has been written.
+    pub fn root_ptr(&self) -> Option<R::ObjectPointer> {
+        self.root_node.read().get_unmodified().cloned()
     }
 }
 
@@ -125,7 +186,14 @@ where
         storage_preference: StoragePreference,
     ) -> Self {
         let root_node = dml.insert(Node::empty_leaf(), tree_id, PivotKey::Root(tree_id));
-        Tree::new(root_node, tree_id, msg_action, dml, storage_preference)
+        Tree::new(
+            root_node,
+            tree_id,
+            msg_action,
+            dml,
+            storage_preference,
+            TreeConfiguration::default(),
+        )
     }
 
     /// Opens a tree identified by the given root node.
@@ -135,6 +203,26 @@ where
         msg_action: M,
         dml: X,
         storage_preference: StoragePreference,
+    ) -> Self {
+        Tree::open_with_config(
+            tree_id,
+            root_node_ptr,
+            msg_action,
+            dml,
+            storage_preference,
+            TreeConfiguration::default(),
+        )
+    }
+
+    /// Opens a tree identified by the given root node, which buffers messages
+    /// according to `config`.
+    pub fn open_with_config(
+        tree_id: DatasetId,
+        root_node_ptr: X::ObjectPointer,
+        msg_action: M,
+        dml: X,
+        storage_preference: StoragePreference,
+        config: TreeConfiguration,
     ) -> Self {
         Tree::new(
             X::root_ref_from_ptr(root_node_ptr),
@@ -142,18 +230,40 @@ where
             msg_action,
             dml,
             storage_preference,
+            config,
         )
     }
 
+    /// Opens a read-only tree identified by the given root node, modifications
+    /// fail with [Error::ReadOnly].
+    pub fn open_ro(
+        root_node_ptr: X::ObjectPointer,
+        msg_action: M,
+        dml: X,
+        storage_preference: StoragePreference,
+    ) -> Self {
+        Tree {
+            inner: I::from(Inner::new_ro(
+                X::root_ref_from_ptr(root_node_ptr),
+                msg_action,
+            )),
+            dml,
+            evict: true,
+            marker: PhantomData,
+            storage_preference,
+        }
+    }
+
     fn new(
         root_node: R,
         tree_id: DatasetId,
         msg_action: M,
         dml: X,
         storage_preference: StoragePreference,
+        config: TreeConfiguration,
     ) -> Self {
         Tree {
-            inner: I::from(Inner::new(tree_id, root_node, msg_action)),
+            inner: I::from(Inner::new(tree_id, root_node, msg_action, config)),
             dml,
             evict: true,
             marker: PhantomData,
@@ -203,6 +313,18 @@ where
             .try_map(|guard| guard.get_unmodified().ok_or(()))
             .ok()
     }
+
+    /// Returns the profiling counters of this tree.
+    #[cfg(feature = "profiling-counters")]
+    pub fn counters(&self) -> TreeCounters {
+        self.inner.borrow().counters.snapshot()
+    }
+
+    /// Resets the profiling counters of this tree to zero.
+    #[cfg(feature = "profiling-counters")]
+    pub fn reset_counters(&self) {
+        self.inner.borrow().counters.reset()
+    }
 }
 
 impl<X, R, M, I> Tree<X, M, I>
@@ -223,7 +345,28 @@ where
         &self.inner.borrow().msg_action
     }
 
+    fn config(&self) -> &TreeConfiguration {
+        &self.inner.borrow().config
+    }
+
+    /// Reports a flush, split or merge of a node at `level` which has been
+    /// started at `start`.
+    fn emit_restructure_event(&self, kind: NodeEventKind, level: u32, size: usize, start: Instant) {
+        self.dml.emit_node_event(|| NodeEvent {
+            kind,
+            dataset: self.tree_id(),
+            size: size as u64,
+            storage_class: None,
+            level: Some(level),
+            duration: Some(start.elapsed()),
+        });
+    }
+
     fn get_mut_root_node(&self) -> Result<X::CacheValueRefMut, Error> {
+        // All modifications start at the root node.
+        if self.inner.borrow().tree_id.is_none() {
+            return Err(Error::ReadOnly);
+        }
         if let Some(node) = self.dml.try_get_mut(&self.inner.borrow().root_node.read()) {
             return Ok(node);
         }
@@ -260,6 +403,156 @@ where
         })
     }
 
+    /// Prefetches all leaves which may contain keys within `start..=end`, so
+    /// that a following range query over these keys is served from the
+    /// cache.  The leaves are read concurrently, as far as the prefetch queue
+    /// of the Dml allows.  Nothing is prefetched if `start` is greater than
+    /// `end`.
+    pub(crate) fn prefetch_range(&self, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        if start > end {
+            return Ok(());
+        }
+        let mut prefetches = Vec::new();
+        let mut nodes = vec![self.get_root_node()?];
+        while let Some(node) = nodes.pop() {
+            let level = node.level();
+            let children = match node.children_in_range(start, Some(end)) {
+                Some(children) => children,
+                None => continue,
+            };
+            for np in children {
+                if level == 1 {
+                    prefetches.extend(self.dml.prefetch(&np.read())?);
+                } else {
+                    nodes.push(self.get_node(np)?);
+                }
+            }
+        }
+        for prefetch in prefetches {
+            self.dml.finish_prefetch(prefetch)?;
+        }
+        Ok(())
+    }
+
+    /// Returns up to `count` keys which split `start..end` into parts of
+    /// similar size, with `None` as end denoting an unbounded range.  The keys
+    /// are the pivots of the topmost level of internal nodes which has enough
+    /// of them within the range, they are sorted and unique.  Like pivots, a
+    /// split key belongs to the part left of it.
+    pub(crate) fn split_points(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        count: usize,
+    ) -> Result<Vec<CowBytes>, Error> {
+        let mut pivots = Vec::new();
+        let mut nodes = vec![self.get_root_node()?];
+        loop {
+            pivots.clear();
+            let mut level = 0;
+            for node in nodes.iter() {
+                level = node.level();
+                pivots.extend_from_slice(node.pivots_in_range(start, end));
+            }
+            if pivots.len() >= count || level <= 1 {
+                break;
+            }
+            let mut children = Vec::new();
+            for node in nodes.iter() {
+                for np in node.children_in_range(start, end).into_iter().flatten() {
+                    children.push(self.get_node(np)?);
+                }
+            }
+            nodes = children;
+        }
+
+        if pivots.len() <= count {
+            return Ok(pivots);
+        }
+        Ok((1..=count)
+            .map(|i| pivots[i * pivots.len() / (count + 1)].clone())
+            .collect())
+    }
+
+    /// Returns the leaves which may contain keys within `start..=end` in key
+    /// order, with the last key each of them may contain, `None` for the
+    /// rightmost leaf, and their pointer unless they have been modified since
+    /// they have been written.  Only internal nodes are fetched.
+    pub(crate) fn leaf_pointers(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<Vec<(Option<CowBytes>, Option<X::ObjectPointer>)>, Error> {
+        let root = self.get_root_node()?;
+        if root.level() == 0 {
+            return Ok(vec![(None, self.inner.borrow().root_ptr())]);
+        }
+        let mut leaves = Vec::new();
+        let mut nodes = vec![(root, None)];
+        while !nodes.is_empty() {
+            let mut children = Vec::new();
+            for (node, last_key) in nodes.iter() {
+                let level = node.level();
+                let pivots = node.pivots_in_range(start, Some(end));
+                let in_range = node.children_in_range(start, Some(end));
+                for (idx, np) in in_range.into_iter().flatten().enumerate() {
+                    // A child ends at the pivot right of it, the last one
+                    // where its parent ends.
+                    let child_last_key = pivots.get(idx).cloned().or_else(|| last_key.clone());
+                    if level == 1 {
+                        leaves.push((child_last_key, np.read().get_unmodified().cloned()));
+                    } else {
+                        children.push((self.get_node(np)?, child_last_key));
+                    }
+                }
+            }
+            nodes = children;
+        }
+        Ok(leaves)
+    }
+
+    /// Marks all nodes whose pointer satisfies `outdated` as modified, so that
+    /// they are written again, together with the nodes above them, on the
+    /// next write back.  Only internal nodes are fetched to find them.
+    /// Returns the number of marked nodes.
+    pub(crate) fn rewrite_nodes<F>(&self, outdated: F) -> Result<usize, Error>
+    where
+        F: Fn(&X::ObjectPointer) -> bool,
+    {
+        let root_outdated = self
+            .inner
+            .borrow()
+            .root_ptr()
+            .map_or(false, |ptr| outdated(&ptr));
+        // The nodes are collected first, so that none is held mutably while
+        // the tree is walked.
+        let mut pivots = Vec::new();
+        let mut nodes = vec![self.get_root_node()?];
+        while let Some(node) = nodes.pop() {
+            let level = node.level();
+            for np in node.child_pointer_iter().into_iter().flatten() {
+                if np.read().get_unmodified().map_or(false, &outdated) {
+                    pivots.push(np.read().index().clone());
+                }
+                if level > 1 {
+                    nodes.push(self.get_node(np)?);
+                }
+            }
+        }
+
+        let mut rewritten = 0;
+        if root_outdated {
+            self.get_mut_root_node()?;
+            rewritten += 1;
+        }
+        for pivot in pivots {
+            if self.get_mut_node_pivot(&pivot)?.is_some() {
+                rewritten += 1;
+            }
+        }
+        Ok(rewritten)
+    }
+
     pub(crate) fn get_mut_node_pivot(
         &self,
         pivot: &PivotKey,
@@ -297,64 +590,6 @@ where
         self.get_mut_node_mut(np_ref.get_mut())
     }
 
-    /*fn walk_tree(
-        &self,
-        mut node: X::CacheValueRefMut,
-        start: &[u8],
-        end: Option<&[u8]>,
-    ) -> Result<(), Error> {
-        // T0D0 rebalance/merge nodes
-        loop {
-            let (size_delta, next_node) = {
-                let level = node.level();
-                let (size_delta, children) = node.range_delete(start, end);
-                let (l_np, r_np, dead) = match children {
-                    None => return Ok(()),
-                    Some((l_np, r_np, dead)) => (l_np, r_np, dead),
-                };
-                if level == 1 {
-                    // is leaf, has no references
-                    for np in dead {
-                        self.dml.remove(np);
-                    }
-                } else {
-                    // is internal, has children
-                    for np in dead {
-                        self.remove_subtree(np)?;
-                    }
-                }
-                if let Some(np) = r_np {
-                    self.walk_tree(self.get_mut_node_mut(np)?, start, end)?;
-                }
-                (size_delta, self.get_mut_node_mut(l_np)?)
-            };
-            node.add_size(size_delta);
-            node = next_node;
-        }
-    }
-
-    fn remove_subtree(&self, np: X::ObjectRef) -> Result<(), Error> {
-        let mut nps = vec![np];
-        while let Some(np) = nps.pop() {
-            {
-                let mut node = self.dml.get_and_remove(np)?;
-                let level = node.level();
-                let result = node.drain_children();
-                if let Some(child_nps) = result {
-                    if level == 1 {
-                        // No need to fetch the leaves
-                        for np in child_nps {
-                            self.dml.remove(np);
-                        }
-                    } else {
-                        nps.extend(child_nps);
-                    }
-                }
-            }
-        }
-        Ok(())
-    }*/
-
     #[allow(missing_docs)]
     #[cfg(feature = "internal-api")]
     pub fn tree_dump(&self) -> Result<NodeInfo, Error>
@@ -370,39 +605,109 @@ where
     //        self.inner.borrow_mut().root_node.is_modified()
     //    }
 
+    /// Returns the value of `key` and the info of its newest entry.  If
+    /// `promote` is set, the leaf holding the value is moved to this storage
+    /// preference the next time it is written, in case it is stored on a
+    /// slower tier, see [Dml::hint_storage_preference].
     pub(crate) fn get_with_info<K: Borrow<[u8]>>(
         &self,
         key: K,
+        promote: StoragePreference,
     ) -> Result<Option<(KeyInfo, SlicedCowBytes)>, Error> {
         let key = key.borrow();
+        let negative_cache = &self.inner.borrow().negative_cache;
+        let counters = &self.inner.borrow().counters;
+        let token = match negative_cache.lookup(key) {
+            Some(token) => token,
+            None => {
+                counters.record_get(0, 0);
+                return Ok(None);
+            }
+        };
         let mut msgs = Vec::new();
         let mut node = self.get_root_node()?;
+        let mut node_fetches = 1;
         let data = loop {
             let next_node = match node.get(key, &mut msgs) {
-                GetResult::NextNode(np) => self.get_node(np)?,
+                GetResult::NextNode(np) => {
+                    let next_node = self.get_node(np)?;
+                    node_fetches += 1;
+                    if promote != StoragePreference::NONE && next_node.level() == 0 {
+                        self.dml.hint_storage_preference(&np.read(), promote);
+                    }
+                    next_node
+                }
                 GetResult::Data(data) => break data,
             };
             node = next_node;
         };
+        if self.config().access_aware_splits {
+            node.record_access(key);
+        }
 
-        match data {
-            None => Ok(None),
-            Some((info, data)) => {
-                let mut tmp = Some(data);
-                for (_keyinfo, msg) in msgs.into_iter().rev() {
-                    self.msg_action().apply(key, &msg, &mut tmp);
-                }
+        // The leaf may hold no entry at all, either because the key has
+        // never been flushed down or because a range tombstone masked it. The
+        // buffered messages may still yield a value.
+        let (mut info, mut tmp) = match data {
+            Some((info, data)) => (Some(info), Some(data)),
+            None => (None, None),
+        };
+        counters.record_get(node_fetches, msgs.len() as u64);
+        for (keyinfo, msg) in msgs.into_iter().rev() {
+            info = info.or(Some(keyinfo));
+            self.msg_action().apply(key, &msg, &mut tmp);
+        }
 
-                // This may never be false.
-                let data = tmp.unwrap();
+        drop(node);
+        if tmp.is_none() {
+            negative_cache.insert(key, token);
+        }
+        if self.evict {
+            self.dml.evict()?;
+        }
+        // `info` is always set if any value exists.
+        Ok(tmp.map(|data| (info.unwrap(), data)))
+    }
 
-                drop(node);
-                if self.evict {
-                    self.dml.evict()?;
-                }
-                Ok(Some((info, data)))
+    /// Returns the length of the value of `key` if it exists.  The search
+    /// stops at the first buffered message which overwrites the value, and
+    /// the value is only reconstructed if the length depends on its contents.
+    pub(crate) fn value_len<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<usize>, Error> {
+        let key = key.borrow();
+        let negative_cache = &self.inner.borrow().negative_cache;
+        let token = match negative_cache.lookup(key) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let mut msgs = Vec::new();
+        let mut node = self.get_root_node()?;
+        let mut len = loop {
+            let overwritten = msgs
+                .iter()
+                .any(|(_, msg)| self.msg_action().overwrites(key, msg));
+            if overwritten {
+                break None;
             }
+            let next_node = match node.get(key, &mut msgs) {
+                GetResult::NextNode(np) => self.get_node(np)?,
+                GetResult::Data(data) => break data.map(|(_info, data)| data.len()),
+            };
+            node = next_node;
+        };
+        drop(node);
+
+        for (_info, msg) in msgs.iter().rev() {
+            if !self.msg_action().apply_to_len(key, msg, &mut len) {
+                return Ok(self.get(key)?.map(|data| data.len()));
+            }
+        }
+        if len.is_none() {
+            negative_cache.insert(key, token);
+        }
+        if self.evict {
+            self.dml.evict()?;
         }
+        Ok(len)
     }
 
     /// "Piercing" update, with insertion logic of a B-Tree.
@@ -447,7 +752,7 @@ where
     I: Borrow<Inner<X::ObjectRef, M>>,
 {
     fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>, Error> {
-        self.get_with_info(key)
+        self.get_with_info(key, StoragePreference::NONE)
             .map(|res| res.map(|(_info, data)| data))
     }
 
@@ -482,6 +787,7 @@ where
             }
         };
 
+        self.inner.borrow().negative_cache.invalidate(key.borrow());
         let op_preference = storage_preference.or(self.storage_preference);
         let added_size = node.insert(key, msg, self.msg_action(), op_preference);
         node.add_size(added_size);
@@ -493,7 +799,8 @@ where
             unimplemented!();
         }
 
-        self.rebalance_tree(node, parent)?;
+        let flush_levels = self.rebalance_tree(node, parent)?;
+        self.inner.borrow().counters.record_insert(flush_levels);
 
         // All non-root trees will start the eviction process.
         // TODO: Is the eviction on root trees harmful? Evictions started by
@@ -504,6 +811,43 @@ where
         Ok(())
     }
 
+    fn range_delete<K, T>(&self, range: T) -> Result<(), Error>
+    where
+        T: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+    {
+        if !is_inclusive_non_empty(&range) {
+            return Err(Error::InvalidRange);
+        }
+        // Normalize the range to `start..end`, appending a zero byte yields
+        // the smallest key greater than the given one.
+        let successor = |key: &[u8]| {
+            let mut key = key.to_vec();
+            key.push(0);
+            key
+        };
+        let start = match range.start_bound() {
+            Bound::Included(key) => key.borrow().to_vec(),
+            Bound::Excluded(key) => successor(key.borrow()),
+            Bound::Unbounded => Vec::new(),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Some(successor(key.borrow())),
+            Bound::Excluded(key) => Some(key.borrow().to_vec()),
+            Bound::Unbounded => None,
+        };
+
+        let mut node = self.get_mut_root_node()?;
+        let added_size = node.range_delete(&start, end.as_deref());
+        node.add_size(added_size);
+        self.rebalance_tree(node, None)?;
+
+        if self.evict {
+            self.dml.evict()?;
+        }
+        Ok(())
+    }
+
     fn depth(&self) -> Result<u32, Error> {
         Ok(self.get_root_node()?.level() + 1)
     }
@@ -524,8 +868,23 @@ where
         Ok(RangeIterator::new(range, self.clone()))
     }
 
+    type KeyRange = KeyRangeIterator<X, M, I>;
+
+    fn range_keys<K, T>(&self, range: T) -> Result<Self::KeyRange, Error>
+    where
+        T: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+        Self: Clone,
+    {
+        if !is_inclusive_non_empty(&range) {
+            return Err(Error::InvalidRange);
+        }
+        Ok(KeyRangeIterator::new(range, self.clone()))
+    }
+
     fn sync(&self) -> Result<Self::Pointer, Error> {
         trace!("sync: Enter");
+        self.merge_underfull_leaves()?;
         let obj_ptr = self
             .dml
             .write_back(|| self.inner.borrow().root_node.write())?;
@@ -551,13 +910,32 @@ where
     ) -> Option<OwningRef<RwLockWriteGuard<Self::ObjectRef>, Self::Pointer>> {
         self.try_lock_root()
     }
+    fn erased_set_system_storage_preference(
+        &self,
+        pivot: &PivotKey,
+        pref: StoragePreference,
+    ) -> Result<Option<usize>, Error> {
+        Ok(self.get_mut_node_pivot(pivot)?.map(|mut node| {
+            node.set_system_storage_preference(pref);
+            node.size()
+        }))
+    }
+    fn erased_rewrite_nodes(
+        &self,
+        outdated: &dyn Fn(&Self::Pointer) -> bool,
+    ) -> Result<usize, Error> {
+        self.rewrite_nodes(outdated)
+    }
 }
 
+mod access;
 mod child_buffer;
+mod counters;
 mod derivate_ref;
 mod flush;
 mod internal;
 mod leaf;
+mod negative_cache;
 mod node;
 mod packed;
 mod range;
@@ -565,5 +943,7 @@ mod split;
 
 pub use self::{
     node::{Node, NodeInfo},
-    range::RangeIterator,
+    range::{KeyRangeIterator, RangeIterator},
 };
+#[cfg(feature = "profiling-counters")]
+pub use counters::TreeCounters;
@@ -0,0 +1,104 @@
+//! A small cache of keys known to be absent from a tree, which answers
+//! repeated lookups of missing keys without traversing to a leaf.
+//!
+//! Keys are hashed to a fixed number of slots, each of which remembers the
+//! last missing key looked up for it.  Colliding lookups simply replace each
+//! other, so the cache only absorbs misses which repeat within a short time.
+//! Every message inserted for a key clears its slot and bumps the version of
+//! the slot, so that a lookup which has traversed the tree concurrently does
+//! not record a stale miss.
+use crate::cow_bytes::CowBytes;
+use parking_lot::Mutex;
+use std::hash::Hasher;
+use twox_hash::XxHash64;
+
+const SLOTS: usize = 1024;
+
+#[derive(Default)]
+struct Slot {
+    version: u64,
+    key: Option<CowBytes>,
+}
+
+pub(super) struct NegativeCache {
+    slots: Box<[Mutex<Slot>]>,
+}
+
+impl NegativeCache {
+    pub(super) fn new() -> Self {
+        NegativeCache {
+            slots: (0..SLOTS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn slot(&self, key: &[u8]) -> &Mutex<Slot> {
+        let mut hasher = XxHash64::default();
+        hasher.write(key);
+        &self.slots[hasher.finish() as usize % SLOTS]
+    }
+
+    /// Returns `None` if `key` is known to be absent, otherwise a token to
+    /// record a miss of the following lookup with [NegativeCache::insert].
+    pub(super) fn lookup(&self, key: &[u8]) -> Option<u64> {
+        let slot = self.slot(key).lock();
+        match slot.key {
+            Some(ref absent) if &absent[..] == key => None,
+            _ => Some(slot.version),
+        }
+    }
+
+    /// Records that `key` is absent, unless a message has been inserted for a
+    /// key of the same slot since `token` has been obtained.
+    pub(super) fn insert(&self, key: &[u8], token: u64) {
+        let mut slot = self.slot(key).lock();
+        if slot.version == token {
+            slot.key = Some(CowBytes::from(key));
+        }
+    }
+
+    /// Invalidates `key` before a message is inserted for it.  The node taking
+    /// the message has to be locked already, so that lookups which still see
+    /// the old version can not pass it before the message has been added.
+    pub(super) fn invalidate(&self, key: &[u8]) {
+        let mut slot = self.slot(key).lock();
+        slot.version = slot.version.wrapping_add(1);
+        slot.key = None;
+    }
+
+    /// Invalidates all keys, e.g. after the root node has been replaced.
+    pub(super) fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            let slot = slot.get_mut();
+            slot.version = slot.version.wrapping_add(1);
+            slot.key = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NegativeCache;
+
+    #[test]
+    fn invalidation() {
+        let mut cache = NegativeCache::new();
+        let token = cache.lookup(b"absent").unwrap();
+        cache.insert(b"absent", token);
+        assert_eq!(cache.lookup(b"absent"), None);
+        assert!(cache.lookup(b"other").is_some());
+
+        cache.invalidate(b"absent");
+        assert!(cache.lookup(b"absent").is_some());
+
+        // A miss observed before a concurrent insert is not recorded.
+        let token = cache.lookup(b"raced").unwrap();
+        cache.invalidate(b"raced");
+        cache.insert(b"raced", token);
+        assert!(cache.lookup(b"raced").is_some());
+
+        let token = cache.lookup(b"absent").unwrap();
+        cache.insert(b"absent", token);
+        cache.clear();
+        assert!(cache.lookup(b"absent").is_some());
+    }
+}
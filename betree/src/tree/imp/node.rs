@@ -1,14 +1,15 @@
 //! Implementation of the generic node wrapper.
 use self::Inner::*;
 use super::{
-    child_buffer::ChildBuffer,
+    child_buffer::{ChildBuffer, RangeTombstone},
     internal::{InternalNode, TakeChildBuffer},
     leaf::LeafNode,
     packed::PackedMap,
-    FillUpResult, KeyInfo, PivotKey, MAX_INTERNAL_NODE_SIZE, MAX_LEAF_NODE_SIZE, MIN_FANOUT,
-    MIN_FLUSH_SIZE, MIN_LEAF_NODE_SIZE,
+    FillUpResult, KeyInfo, PivotKey, TreeConfiguration, MAX_INTERNAL_NODE_SIZE, MAX_LEAF_NODE_SIZE,
+    MIN_LEAF_NODE_SIZE,
 };
 use crate::{
+    cache::{Classify, EntryKind},
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::{Dml, HasStoragePreference, Object, ObjectReference},
     database::DatasetId,
@@ -17,7 +18,7 @@ use crate::{
     tree::{pivot_key::LocalPivotKey, MessageAction},
     StoragePreference,
 };
-use bincode::{deserialize, serialize_into};
+use bincode::{deserialize, deserialize_from, serialize_into};
 use parking_lot::RwLock;
 use std::{
     borrow::Borrow,
@@ -26,6 +27,14 @@ use std::{
     mem::replace,
 };
 
+/// Marks an internal node at the beginning of a packed node, all other nodes
+/// are packed leaves.
+const INTERNAL_TAG: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+/// Marks an internal node which is followed by the range tombstones of its
+/// child buffers, written since
+/// [RANGE_TOMBSTONE_VERSION](crate::database::RANGE_TOMBSTONE_VERSION).
+const INTERNAL_RANGE_TOMBSTONE_TAG: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFE];
+
 /// The tree node type.
 #[derive(Debug)]
 pub struct Node<N: 'static>(Inner<N>);
@@ -84,19 +93,49 @@ impl<R: ObjectReference + HasStoragePreference> Object<R> for Node<R> {
             PackedLeaf(ref map) => writer.write_all(map.inner()),
             Leaf(ref leaf) => PackedMap::pack(leaf, writer),
             Internal(ref internal) => {
-                writer.write_all(&[0xFFu8, 0xFF, 0xFF, 0xFF] as &[u8])?;
-                serialize_into(writer, internal)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                // Range tombstones are appended to the node, so that nodes
+                // without them can still be read by older format versions,
+                // which never buffer range tombstones.
+                let range_tombstones: Vec<&[RangeTombstone]> =
+                    internal.iter().map(ChildBuffer::range_tombstones).collect();
+                if range_tombstones
+                    .iter()
+                    .all(|tombstones| tombstones.is_empty())
+                {
+                    writer.write_all(&INTERNAL_TAG)?;
+                    serialize_into(writer, internal)
+                } else {
+                    writer.write_all(&INTERNAL_RANGE_TOMBSTONE_TAG)?;
+                    serialize_into(&mut writer, internal)
+                        .and_then(|()| serialize_into(writer, &range_tombstones))
+                }
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
             }
         }
     }
 
     fn unpack_at(_offset: DiskOffset, d_id: DatasetId, data: Box<[u8]>) -> Result<Self, io::Error> {
-        if data[..4] == [0xFFu8, 0xFF, 0xFF, 0xFF] {
+        if data[..4] == INTERNAL_TAG {
             match deserialize::<InternalNode<_>>(&data[4..]) {
                 Ok(internal) => Ok(Node(Internal(internal.complete_object_refs(d_id)))),
                 Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
             }
+        } else if data[..4] == INTERNAL_RANGE_TOMBSTONE_TAG {
+            let mut reader = &data[4..];
+            let mut internal: InternalNode<ChildBuffer<R>> = deserialize_from(&mut reader)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let range_tombstones: Vec<Vec<RangeTombstone>> =
+                deserialize(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if range_tombstones.len() != internal.fanout() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "range tombstones do not match the children of the node",
+                ));
+            }
+            for (child, tombstones) in internal.iter_mut().zip(range_tombstones) {
+                child.set_range_tombstones(tombstones);
+            }
+            Ok(Node(Internal(internal.complete_object_refs(d_id))))
         } else {
             // storage_preference is not preserved for packed leaves,
             // because they will not be written back to disk until modified,
@@ -128,6 +167,30 @@ impl<R: ObjectReference + HasStoragePreference> Object<R> for Node<R> {
         }
         Ok(())
     }
+
+    fn try_for_each_child_ref<F>(&self, mut f: F) -> bool
+    where
+        F: FnMut(&R),
+    {
+        if let Some(iter) = self.child_pointer_iter() {
+            for np in iter {
+                match np.try_read() {
+                    Some(np) => f(&np),
+                    None => return false,
+                }
+            }
+        }
+        true
+    }
+}
+
+impl<N> Classify for Node<N> {
+    fn entry_kind(&mut self) -> EntryKind {
+        match self.0 {
+            PackedLeaf(_) | Leaf(_) => EntryKind::Leaf,
+            Internal(_) => EntryKind::Internal,
+        }
+    }
 }
 
 impl<N: StaticSize> Size for Node<N> {
@@ -156,13 +219,26 @@ impl<N: StaticSize + HasStoragePreference> Node<N> {
         }
     }
 
-    pub(super) fn try_find_flush_candidate(&mut self) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
+    pub(super) fn take_child_buffer(
+        &mut self,
+        child_idx: usize,
+    ) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
+        match self.0 {
+            Leaf(_) | PackedLeaf(_) => None,
+            Internal(ref mut internal) => internal.take_child_buffer(child_idx),
+        }
+    }
+
+    pub(super) fn try_find_flush_candidate(
+        &mut self,
+        config: &TreeConfiguration,
+    ) -> Option<TakeChildBuffer<ChildBuffer<N>>> {
         match self.0 {
             Leaf(_) | PackedLeaf(_) => None,
             Internal(ref mut internal) => internal.try_find_flush_candidate(
-                MIN_FLUSH_SIZE,
+                config.min_flush_size,
                 MAX_INTERNAL_NODE_SIZE,
-                MIN_FANOUT,
+                config.min_fanout,
             ),
         }
     }
@@ -191,6 +267,25 @@ impl<N: HasStoragePreference + StaticSize> Node<N> {
         }
     }
 
+    /// Returns the system storage preference to be recorded in the pointer
+    /// to this node.  Leaves store their preference in their own encoding, so
+    /// only the preference of internal nodes, which is not serialized, has to
+    /// be recorded.
+    pub(crate) fn pointer_storage_preference(&self) -> StoragePreference {
+        match self.0 {
+            Leaf(_) | PackedLeaf(_) => StoragePreference::NONE,
+            Internal(ref internal) => internal.system_storage_preference(),
+        }
+    }
+
+    /// Restores the system storage preference recorded in the pointer to this
+    /// node, see [Self::pointer_storage_preference].
+    pub(crate) fn restore_storage_preference(&mut self, pref: StoragePreference) {
+        if let Internal(ref mut internal) = self.0 {
+            internal.set_system_storage_preference(pref);
+        }
+    }
+
     fn ensure_unpacked(&mut self) -> isize {
         let before = self.size();
 
@@ -209,10 +304,10 @@ impl<N: HasStoragePreference + StaticSize> Node<N> {
         replace(self, Self::empty_leaf())
     }
 
-    pub(super) fn has_too_low_fanout(&self) -> bool {
+    pub(super) fn has_too_low_fanout(&self, config: &TreeConfiguration) -> bool {
         match self.0 {
             Leaf(_) | PackedLeaf(_) => false,
-            Internal(ref internal) => internal.fanout() < MIN_FANOUT,
+            Internal(ref internal) => internal.fanout() < config.min_fanout,
         }
     }
 
@@ -239,11 +334,18 @@ impl<N: HasStoragePreference + StaticSize> Node<N> {
         }
     }
 
+    pub(super) fn has_buffered_messages(&self) -> bool {
+        match self.0 {
+            Leaf(_) | PackedLeaf(_) => false,
+            Internal(ref internal) => internal.has_buffered_messages(),
+        }
+    }
+
     pub(super) fn empty_leaf() -> Self {
         Node(Leaf(LeafNode::new()))
     }
 
-    pub(super) fn level(&self) -> u32 {
+    pub(crate) fn level(&self) -> u32 {
         match self.0 {
             Leaf(_) | PackedLeaf(_) => 0,
             Internal(ref internal) => internal.level(),
@@ -259,7 +361,7 @@ impl<N: HasStoragePreference + StaticSize> Node<N> {
 }
 
 impl<N: ObjectReference + StaticSize + HasStoragePreference> Node<N> {
-    pub(super) fn split_root_mut<F>(&mut self, allocate_obj: F) -> isize
+    pub(super) fn split_root_mut<F>(&mut self, config: &TreeConfiguration, allocate_obj: F) -> isize
     where
         F: Fn(Self, LocalPivotKey) -> N,
     {
@@ -270,8 +372,11 @@ impl<N: ObjectReference + StaticSize + HasStoragePreference> Node<N> {
         let (right_sibling, pivot_key, cur_level) = match left_sibling.0 {
             PackedLeaf(_) => unreachable!(),
             Leaf(ref mut leaf) => {
-                let (right_sibling, pivot_key, _, _pk) =
-                    leaf.split(MIN_LEAF_NODE_SIZE, MAX_LEAF_NODE_SIZE);
+                let (right_sibling, pivot_key, _, _pk) = leaf.split(
+                    MIN_LEAF_NODE_SIZE,
+                    MAX_LEAF_NODE_SIZE,
+                    config.access_aware_splits,
+                );
                 (Node(Leaf(right_sibling)), pivot_key, 0)
             }
             Internal(ref mut internal) => {
@@ -326,6 +431,16 @@ pub(super) enum GetRangeResult<'a, T, N: 'a> {
 }
 
 impl<N: HasStoragePreference> Node<N> {
+    /// Samples a read of `key` from this leaf, see
+    /// [TreeConfiguration::access_aware_splits].
+    pub(super) fn record_access(&self, key: &[u8]) {
+        match self.0 {
+            PackedLeaf(ref map) => map.accesses().record(key),
+            Leaf(ref leaf) => leaf.accesses().record(key),
+            Internal(_) => {}
+        }
+    }
+
     pub(super) fn get(
         &self,
         key: &[u8],
@@ -339,7 +454,13 @@ impl<N: HasStoragePreference> Node<N> {
                 if let Some(msg) = msg {
                     msgs.push(msg);
                 }
-                GetResult::NextNode(child_np)
+                if internal.is_range_deleted(key) {
+                    // Whatever is stored below has been deleted, only the
+                    // messages gathered so far are relevant.
+                    GetResult::Data(None)
+                } else {
+                    GetResult::NextNode(child_np)
+                }
             }
         }
     }
@@ -350,6 +471,7 @@ impl<N: HasStoragePreference> Node<N> {
         left_pivot_key: &mut Option<CowBytes>,
         right_pivot_key: &mut Option<CowBytes>,
         all_msgs: &mut BTreeMap<CowBytes, Vec<(KeyInfo, SlicedCowBytes)>>,
+        range_tombstones: &mut Vec<RangeTombstone>,
     ) -> GetRangeResult<Box<dyn Iterator<Item = (&'a [u8], (KeyInfo, SlicedCowBytes))> + 'a>, N>
     {
         match self.0 {
@@ -363,7 +485,13 @@ impl<N: HasStoragePreference> Node<N> {
                 } else {
                     None
                 };
-                let np = internal.get_range(key, left_pivot_key, right_pivot_key, all_msgs);
+                let np = internal.get_range(
+                    key,
+                    left_pivot_key,
+                    right_pivot_key,
+                    all_msgs,
+                    range_tombstones,
+                );
                 GetRangeResult::NextNode {
                     prefetch_option,
                     np,
@@ -372,6 +500,45 @@ impl<N: HasStoragePreference> Node<N> {
         }
     }
 
+    /// Returns the keys of all entries of a leaf without reading their values,
+    /// nothing for internal nodes.
+    pub(super) fn leaf_keys(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        match self.0 {
+            PackedLeaf(ref map) => Box::new(map.get_all_keys()),
+            Leaf(ref leaf) => Box::new(leaf.entries().keys().map(|k| &k[..])),
+            Internal(_) => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Returns the children which may contain keys within `start..=end`, or
+    /// `None` for leaves.
+    pub(super) fn children_in_range<'a>(
+        &'a self,
+        start: &'a [u8],
+        end: Option<&'a [u8]>,
+    ) -> Option<impl Iterator<Item = &'a RwLock<N>> + 'a> {
+        match self.0 {
+            PackedLeaf(_) | Leaf(_) => None,
+            Internal(ref internal) => Some(internal.children_in_range(start, end)),
+        }
+    }
+
+    /// Returns the indices of the first and the last child which may hold keys
+    /// within `start..=end`.
+    pub(super) fn child_idx_range(&self, start: &[u8], end: &[u8]) -> Option<(usize, usize)> {
+        match self.0 {
+            PackedLeaf(_) | Leaf(_) => None,
+            Internal(ref internal) => Some(internal.idx_range(start, Some(end))),
+        }
+    }
+
+    pub(super) fn pivots_in_range(&self, start: &[u8], end: Option<&[u8]>) -> &[CowBytes] {
+        match self.0 {
+            PackedLeaf(_) | Leaf(_) => &[],
+            Internal(ref internal) => internal.pivots_in_range(start, end),
+        }
+    }
+
     pub(super) fn pivot_get(&self, pk: &PivotKey) -> Option<PivotGetResult<N>> {
         if pk.is_root() {
             return Some(PivotGetResult::Target(None));
@@ -479,18 +646,24 @@ impl<N: HasStoragePreference> Node<N> {
 }
 
 impl<N: ObjectReference + StaticSize + HasStoragePreference> Node<N> {
-    pub(super) fn split(&mut self) -> (Self, CowBytes, isize, LocalPivotKey) {
+    pub(super) fn split(
+        &mut self,
+        config: &TreeConfiguration,
+    ) -> (Self, CowBytes, isize, LocalPivotKey) {
         self.ensure_unpacked();
         match self.0 {
             PackedLeaf(_) => unreachable!(),
             Leaf(ref mut leaf) => {
-                let (node, pivot_key, size_delta, pk) =
-                    leaf.split(MIN_LEAF_NODE_SIZE, MAX_LEAF_NODE_SIZE);
+                let (node, pivot_key, size_delta, pk) = leaf.split(
+                    MIN_LEAF_NODE_SIZE,
+                    MAX_LEAF_NODE_SIZE,
+                    config.access_aware_splits,
+                );
                 (Node(Leaf(node)), pivot_key, size_delta, pk)
             }
             Internal(ref mut internal) => {
                 debug_assert!(
-                    internal.fanout() >= 2 * MIN_FANOUT,
+                    internal.fanout() >= 2 * config.min_fanout,
                     "internal split failed due to low fanout: {}, size: {}, actual_size: {:?}",
                     internal.fanout(),
                     internal.size(),
@@ -525,25 +698,17 @@ impl<N: ObjectReference + StaticSize + HasStoragePreference> Node<N> {
         }
     }
 
-    /*pub(super) fn range_delete(
-        &mut self,
-        start: &[u8],
-        end: Option<&[u8]>,
-    ) -> (isize, Option<(&mut N, Option<&mut N>, Vec<N>)>) {
-        self.ensure_unpacked();
-        match self.0 {
-            PackedLeaf(_) => unreachable!(),
-            Leaf(ref mut leaf) => {
-                let size_delta = leaf.range_delete(start, end);
-                (-(size_delta as isize), None)
-            }
-            Internal(ref mut internal) => {
-                let mut dead = Vec::new();
-                let (size_delta, l, r) = internal.range_delete(start, end, &mut dead);
-                (-(size_delta as isize), Some((l, r, dead)))
-            }
-        }
-    }*/
+    /// Deletes all keys in `start..end`. Leaves drop the affected entries
+    /// immediately, internal nodes buffer a range tombstone for their children.
+    pub(super) fn range_delete(&mut self, start: &[u8], end: Option<&[u8]>) -> isize {
+        let size_delta = self.ensure_unpacked();
+        size_delta
+            + (match self.0 {
+                PackedLeaf(_) => unreachable!(),
+                Leaf(ref mut leaf) => -(leaf.range_delete(start, end) as isize),
+                Internal(ref mut internal) => internal.insert_range_tombstone(start, end),
+            })
+    }
 }
 
 #[derive(serde::Serialize)]
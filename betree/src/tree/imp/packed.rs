@@ -1,7 +1,7 @@
 //! On-disk representation of a node.
 //!
 //! Can be used for read-only access to avoid deserialization.
-use super::leaf::LeafNode;
+use super::{access::AccessSample, leaf::LeafNode};
 use crate::{
     cow_bytes::{CowBytes, SlicedCowBytes},
     data_management::HasStoragePreference,
@@ -63,6 +63,7 @@ pub(crate) struct PackedMap {
     entry_count: u32,
     system_preference: u8,
     data: CowBytes,
+    accesses: AccessSample,
 }
 
 /// New type for safe-handling of data offsets u32s.
@@ -83,6 +84,7 @@ impl PackedMap {
             data: data.into(),
             entry_count,
             system_preference,
+            accesses: AccessSample::default(),
         }
     }
 
@@ -220,13 +222,22 @@ impl PackedMap {
         }
     }
 
+    pub fn get_all_keys(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        (0..self.entry_count).map(move |idx| self.get_slice(self.key_pos(idx)))
+    }
+
     pub(super) fn unpack_leaf(&self) -> LeafNode {
         let mut leaf: LeafNode = self.get_all().collect();
         // Restore system storage preference state
         leaf.set_system_storage_preference(StoragePreference::from_u8(self.system_preference));
+        leaf.set_accesses(self.accesses.take());
         leaf
     }
 
+    pub(super) fn accesses(&self) -> &AccessSample {
+        &self.accesses
+    }
+
     pub(super) fn pack<W: Write>(leaf: &LeafNode, mut writer: W) -> io::Result<()> {
         let entries = leaf.entries();
         let entries_cnt = entries.len() as u32;
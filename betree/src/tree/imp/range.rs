@@ -1,6 +1,7 @@
 //! Iterator over a range of keys in a [Tree].
 use super::{
-    node::{GetRangeResult, Node},
+    child_buffer::RangeTombstone,
+    node::{GetRangeResult, GetResult, Node},
     Inner, Tree,
 };
 use crate::{
@@ -31,13 +32,16 @@ enum Bounded<T> {
 /// utilization of underlying resources. It is advised to use [RangeIterator]
 /// and methods utilizing it in almost all cases.
 pub struct RangeIterator<X: Dml, M, I: Borrow<Inner<X::ObjectRef, M>>> {
-    buffer: VecDeque<(Key, (KeyInfo, Value))>,
+    /// Values are `None` if only keys are collected.
+    buffer: VecDeque<(Key, Option<Value>)>,
     min_key: Bounded<Vec<u8>>,
     /// Always inclusive
     max_key: Option<Vec<u8>>,
     tree: Tree<X, M, I>,
     finished: bool,
     prefetch: Option<X::Prefetch>,
+    keys_only: bool,
+    last_key: Option<Key>,
 }
 
 impl<X, R, M, I> Iterator for RangeIterator<X, M, I>
@@ -50,16 +54,48 @@ where
     type Item = Result<(Key, Value), Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if let Some((key, (_keyinfo, data))) = self.buffer.pop_front() {
-                return Some(Ok((key, data)));
-            } else if self.finished {
-                return None;
-            } else if let Err(e) = self.fill_buffer() {
-                self.finished = true;
-                return Some(Err(e));
-            }
-        }
+        self.next_entry().map(|entry| {
+            entry.map(|(key, data)| (key, data.expect("Values are collected for ranges")))
+        })
+    }
+}
+
+/// The range iterator over the keys of a tree.
+///
+/// In contrast to [RangeIterator], values are only looked at if messages for
+/// their key are buffered above the leaf, which have to be applied to learn
+/// whether the key still exists.
+pub struct KeyRangeIterator<X: Dml, M, I: Borrow<Inner<X::ObjectRef, M>>>(RangeIterator<X, M, I>);
+
+impl<X, R, M, I> Iterator for KeyRangeIterator<X, M, I>
+where
+    X: Dml<Object = Node<R>, ObjectRef = R>,
+    R: ObjectReference<ObjectPointer = X::ObjectPointer> + HasStoragePreference,
+    M: MessageAction,
+    I: Borrow<Inner<X::ObjectRef, M>>,
+{
+    type Item = Result<Key, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_entry().map(|entry| entry.map(|(key, _)| key))
+    }
+}
+
+impl<X, R, M, I> KeyRangeIterator<X, M, I>
+where
+    X: Dml<Object = Node<R>, ObjectRef = R>,
+    R: ObjectReference<ObjectPointer = X::ObjectPointer> + HasStoragePreference,
+    M: MessageAction,
+    I: Borrow<Inner<X::ObjectRef, M>>,
+{
+    pub(super) fn new<K, T>(range: T, tree: Tree<X, M, I>) -> Self
+    where
+        T: RangeBounds<K>,
+        K: Borrow<[u8]>,
+    {
+        let mut iter = RangeIterator::new(range, tree);
+        iter.keys_only = true;
+        KeyRangeIterator(iter)
     }
 }
 
@@ -97,6 +133,29 @@ where
             finished: false,
             buffer: VecDeque::new(),
             prefetch: None,
+            keys_only: false,
+            last_key: None,
+        }
+    }
+
+    /// Returns the last key returned by this iterator.  A new iterator over
+    /// the range starting after it continues where this one has stopped,
+    /// without holding on to the tree in between.
+    pub fn last_key(&self) -> Option<&[u8]> {
+        self.last_key.as_deref()
+    }
+
+    fn next_entry(&mut self) -> Option<Result<(Key, Option<Value>), Error>> {
+        loop {
+            if let Some(entry) = self.buffer.pop_front() {
+                self.last_key = Some(entry.0.clone());
+                return Some(Ok(entry));
+            } else if self.finished {
+                return None;
+            } else if let Err(e) = self.fill_buffer() {
+                self.finished = true;
+                return Some(Err(e));
+            }
         }
     }
 
@@ -105,8 +164,12 @@ where
             let min_key = match self.min_key {
                 Bounded::Included(ref x) | Bounded::Excluded(ref x) => x,
             };
-            self.tree
-                .leaf_range_query(min_key, &mut self.buffer, &mut self.prefetch)?
+            self.tree.leaf_range_query(
+                min_key,
+                &mut self.buffer,
+                &mut self.prefetch,
+                self.keys_only,
+            )?
         };
 
         // Strip entries which are out of bounds from the buffer.
@@ -167,13 +230,15 @@ where
     fn leaf_range_query(
         &self,
         key: &[u8],
-        data: &mut VecDeque<(CowBytes, (KeyInfo, SlicedCowBytes))>,
+        data: &mut VecDeque<(CowBytes, Option<SlicedCowBytes>)>,
         prefetch: &mut Option<X::Prefetch>,
+        keys_only: bool,
     ) -> Result<Option<CowBytes>, Error> {
         let result = {
             let mut left_pivot_key = None;
             let mut right_pivot_key = None;
             let mut messages = BTreeMap::new();
+            let mut range_tombstones = Vec::new();
 
             // First, we gather all messages for the given key and its value in the leaf.
             let mut node = self.get_root_node()?;
@@ -184,6 +249,7 @@ where
                     &mut left_pivot_key,
                     &mut right_pivot_key,
                     &mut messages,
+                    &mut range_tombstones,
                 ) {
                     GetRangeResult::NextNode {
                         prefetch_option,
@@ -200,11 +266,23 @@ where
                         }
                         self.get_node(np)?
                     }
+                    GetRangeResult::Data(_) if keys_only => {
+                        self.apply_messages_to_keys(
+                            &left_pivot_key,
+                            &right_pivot_key,
+                            messages,
+                            &range_tombstones,
+                            &node,
+                            data,
+                        );
+                        break Ok(right_pivot_key);
+                    }
                     GetRangeResult::Data(leaf_entries) => {
                         self.apply_messages(
                             &left_pivot_key,
                             &right_pivot_key,
                             messages,
+                            &range_tombstones,
                             leaf_entries,
                             data,
                         );
@@ -226,52 +304,91 @@ where
         left_pivot_key: &Option<CowBytes>,
         right_pivot_key: &Option<CowBytes>,
         messages: BTreeMap<CowBytes, Vec<(KeyInfo, SlicedCowBytes)>>,
+        range_tombstones: &[RangeTombstone],
         leaf_entries: J,
-        data: &mut VecDeque<(CowBytes, (KeyInfo, SlicedCowBytes))>,
+        data: &mut VecDeque<(CowBytes, Option<SlicedCowBytes>)>,
     ) where
         J: Iterator<Item = (&'a [u8], (KeyInfo, SlicedCowBytes))>,
     {
-        // disregard any messages with keys outside of
-        // left_pivot_key..right_pivot_key.
-        let msgs_iter = messages
-            .into_iter()
-            .skip_while(|(key, _)| match *left_pivot_key {
-                None => false,
-                Some(ref min_key) => key < min_key,
-            })
-            .take_while(|(key, _)| match *right_pivot_key {
-                None => true,
-                Some(ref max_key) => key <= max_key,
-            });
-        let leaf_entries = leaf_entries.map(|(k, v)| (CowBytes::from(k), v));
-
-        for (key, msgs, value) in MergeByKeyIterator::new(msgs_iter, leaf_entries) {
-            let (mut keyinfo, mut value) = match value {
-                Some((keyinfo, value)) => (Some(keyinfo), Some(value)),
-                None => (None, None),
-            };
+        // Leaf entries covered by a buffered range tombstone are deleted.
+        let leaf_entries = leaf_entries
+            .filter(|(k, _)| !range_tombstones.iter().any(|tombstone| tombstone.covers(k)))
+            .map(|(k, (_keyinfo, v))| (CowBytes::from(k), v));
 
+        for (key, msgs, mut value) in MergeByKeyIterator::new(
+            messages_within(left_pivot_key, right_pivot_key, messages),
+            leaf_entries,
+        ) {
             if let Some(msgs) = msgs {
-                for (new_keyinfo, msg) in msgs.into_iter().rev() {
-                    if let Some(previous_keyinfo) = keyinfo {
-                        keyinfo = Some(previous_keyinfo.merge_with_upper(new_keyinfo));
-                    } else {
-                        keyinfo = Some(new_keyinfo)
-                    }
-
+                for (_keyinfo, msg) in msgs.into_iter().rev() {
                     self.msg_action().apply(&key, &msg, &mut value);
                 }
             }
-            if let Some(value) = value {
-                // Unwrap is safe here, keyinfo can only be initially None if value
-                // is also None. And on every occasion where value can become Some,
-                // keyinfo has already been set to Some.
-                data.push_back((key, (keyinfo.unwrap(), value)));
+            if value.is_some() {
+                data.push_back((key, value));
+            }
+        }
+    }
+
+    /// Like [Self::apply_messages], but only collects the keys of `leaf`.
+    /// Values are only read for keys with buffered messages.
+    fn apply_messages_to_keys(
+        &self,
+        left_pivot_key: &Option<CowBytes>,
+        right_pivot_key: &Option<CowBytes>,
+        messages: BTreeMap<CowBytes, Vec<(KeyInfo, SlicedCowBytes)>>,
+        range_tombstones: &[RangeTombstone],
+        leaf: &Node<R>,
+        data: &mut VecDeque<(CowBytes, Option<SlicedCowBytes>)>,
+    ) {
+        let leaf_keys = leaf
+            .leaf_keys()
+            .filter(|k| !range_tombstones.iter().any(|tombstone| tombstone.covers(k)))
+            .map(|k| (CowBytes::from(k), ()));
+
+        for (key, msgs, stored) in MergeByKeyIterator::new(
+            messages_within(left_pivot_key, right_pivot_key, messages),
+            leaf_keys,
+        ) {
+            let exists = match msgs {
+                None => stored.is_some(),
+                Some(msgs) => {
+                    let mut value = match stored.map(|()| leaf.get(&key, &mut Vec::new())) {
+                        Some(GetResult::Data(entry)) => entry.map(|(_keyinfo, value)| value),
+                        _ => None,
+                    };
+                    for (_keyinfo, msg) in msgs.into_iter().rev() {
+                        self.msg_action().apply(&key, &msg, &mut value);
+                    }
+                    value.is_some()
+                }
+            };
+            if exists {
+                data.push_back((key, None));
             }
         }
     }
 }
 
+/// Disregards any messages with keys outside of
+/// `left_pivot_key..=right_pivot_key`.
+fn messages_within<'a>(
+    left_pivot_key: &'a Option<CowBytes>,
+    right_pivot_key: &'a Option<CowBytes>,
+    messages: BTreeMap<CowBytes, Vec<(KeyInfo, SlicedCowBytes)>>,
+) -> impl Iterator<Item = (CowBytes, Vec<(KeyInfo, SlicedCowBytes)>)> + 'a {
+    messages
+        .into_iter()
+        .skip_while(move |(key, _)| match *left_pivot_key {
+            None => false,
+            Some(ref min_key) => key < min_key,
+        })
+        .take_while(move |(key, _)| match *right_pivot_key {
+            None => true,
+            Some(ref max_key) => key <= max_key,
+        })
+}
+
 struct MergeByKeyIterator<I: Iterator, J: Iterator> {
     i: I,
     j: J,
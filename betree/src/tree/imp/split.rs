@@ -2,11 +2,11 @@
 use super::{child_buffer::ChildBuffer, internal::TakeChildBuffer, Inner, Node, Tree};
 use crate::{
     cache::AddSize,
-    data_management::{Dml, HasStoragePreference, ObjectReference},
+    data_management::{Dml, HasStoragePreference, NodeEventKind, ObjectReference},
     size::Size,
     tree::{errors::*, MessageAction},
 };
-use std::borrow::Borrow;
+use std::{borrow::Borrow, time::Instant};
 
 impl<X, R, M, I> Tree<X, M, I>
 where
@@ -16,8 +16,11 @@ where
     I: Borrow<Inner<X::ObjectRef, M>>,
 {
     pub(super) fn split_root_node(&self, mut root_node: X::CacheValueRefMut) {
+        #[cfg(feature = "cache-paranoia")]
         self.dml.verify_cache();
+        let start = Instant::now();
         let before = root_node.size();
+        let level = root_node.level();
         debug!(
             "Splitting root. {}, {:?}, {}, {:?}",
             root_node.kind(),
@@ -25,7 +28,7 @@ where
             root_node.size(),
             root_node.actual_size()
         );
-        let size_delta = root_node.split_root_mut(|node, pk| {
+        let size_delta = root_node.split_root_mut(self.config(), |node, pk| {
             debug!(
                 "Root split child: {}, {:?}, {}, {:?}",
                 node.kind(),
@@ -39,6 +42,8 @@ where
         info!("Root split done. {}, {}", root_node.size(), size_delta);
         debug_assert!(before as isize + size_delta == root_node.size() as isize);
         root_node.finish(size_delta);
+        self.emit_restructure_event(NodeEventKind::Split, level, before, start);
+        #[cfg(feature = "cache-paranoia")]
         self.dml.verify_cache();
     }
 
@@ -47,10 +52,13 @@ where
         mut node: X::CacheValueRefMut,
         parent: &mut TakeChildBuffer<ChildBuffer<R>>,
     ) -> Result<(X::CacheValueRefMut, isize), Error> {
+        #[cfg(feature = "cache-paranoia")]
         self.dml.verify_cache();
 
+        let start = Instant::now();
         let before = node.size();
-        let (sibling, pivot_key, size_delta, lpk) = node.split();
+        let level = node.level();
+        let (sibling, pivot_key, size_delta, lpk) = node.split(self.config());
         let pk = lpk.to_global(self.tree_id());
         let select_right = sibling.size() > node.size();
         debug!(
@@ -71,6 +79,7 @@ where
         };
 
         let size_delta = parent.split_child(sibling_np, pivot_key, select_right);
+        self.emit_restructure_event(NodeEventKind::Split, level, before, start);
 
         Ok((node, size_delta))
     }
@@ -1,5 +1,5 @@
 //! Interface traits for the tree layer of *Haura*.
-use super::{Key, MessageAction, Value};
+use super::{Key, MessageAction, PivotKey, Value};
 use crate::{
     cow_bytes::{CowBytes, SlicedCowBytes},
     StoragePreference,
@@ -27,6 +27,16 @@ pub trait TreeLayer<M: MessageAction> {
     /// Gets the entry for the given `key` if it exists.
     fn get<K: Borrow<[u8]>>(&self, key: K) -> Result<Option<SlicedCowBytes>, Error>;
 
+    /// Deletes all entries within the given key range.
+    ///
+    /// Instead of visiting each entry, a range tombstone is buffered at the
+    /// root node. Covered entries are hidden from `get` and `range` right
+    /// away and removed once the tombstone is flushed to the leaves.
+    fn range_delete<K, R>(&self, range: R) -> Result<(), Error>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>;
+
     /// Returns the depth of the tree.
     fn depth(&self) -> Result<u32, Error>;
     /// The range query iterator.
@@ -47,6 +57,18 @@ pub trait TreeLayer<M: MessageAction> {
         K: Borrow<[u8]> + Into<CowBytes>,
         Self: Clone;
 
+    /// The range query iterator over keys only.
+    type KeyRange: Iterator<Item = Result<Key, Error>>;
+    /// Issues a range query for the given key range like [TreeLayer::range],
+    /// but returns only the keys.  Values are not read from the leaves unless
+    /// buffered messages have to be applied to them, which makes this cheaper
+    /// for counting entries or building indices.
+    fn range_keys<K, R>(&self, range: R) -> Result<Self::KeyRange, Error>
+    where
+        R: RangeBounds<K>,
+        K: Borrow<[u8]> + Into<CowBytes>,
+        Self: Clone;
+
     /// Tree pointer type that represents a synced tree.
     type Pointer: Serialize + DeserializeOwned;
 
@@ -64,4 +86,19 @@ pub(crate) trait ErasedTreeSync {
     fn erased_try_lock_root(
         &self,
     ) -> Option<OwningRef<RwLockWriteGuard<Self::ObjectRef>, Self::Pointer>>;
+    /// Sets the system storage preference of the node at `pivot`, so that it
+    /// is moved on its next write back.  Returns the size of the node, or
+    /// `None` if it does not exist anymore.
+    fn erased_set_system_storage_preference(
+        &self,
+        pivot: &PivotKey,
+        pref: StoragePreference,
+    ) -> Result<Option<usize>, Error>;
+    /// Marks all nodes whose pointer satisfies `outdated` as modified, so
+    /// that they are written again on the next sync.  Returns the number of
+    /// marked nodes.
+    fn erased_rewrite_nodes(
+        &self,
+        outdated: &dyn Fn(&Self::Pointer) -> bool,
+    ) -> Result<usize, Error>;
 }
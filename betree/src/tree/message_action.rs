@@ -24,6 +24,21 @@ pub trait MessageAction: Debug + Send + Sync {
         upper_msg: SlicedCowBytes,
         lower_msg: SlicedCowBytes,
     ) -> SlicedCowBytes;
+
+    /// Returns whether `msg` replaces the current data regardless of what it
+    /// is, so that older messages and the leaf entry do not have to be looked
+    /// at to determine its effect.
+    fn overwrites(&self, _key: &[u8], _msg: &SlicedCowBytes) -> bool {
+        false
+    }
+
+    /// Applies the message `msg` to the length of the current data instead of
+    /// the data itself, `len` is `None` for absent data.  Returns `false` and
+    /// leaves `len` untouched if the resulting length depends on the contents
+    /// of the data.
+    fn apply_to_len(&self, _key: &[u8], _msg: &SlicedCowBytes, _len: &mut Option<usize>) -> bool {
+        false
+    }
 }
 
 impl<T: Deref + Debug + Send + Sync> MessageAction for T
@@ -44,4 +59,10 @@ where
     ) -> SlicedCowBytes {
         (**self).merge(key, upper_msg, lower_msg)
     }
+    fn overwrites(&self, key: &[u8], msg: &SlicedCowBytes) -> bool {
+        (**self).overwrites(key, msg)
+    }
+    fn apply_to_len(&self, key: &[u8], msg: &SlicedCowBytes, len: &mut Option<usize>) -> bool {
+        (**self).apply_to_len(key, msg, len)
+    }
 }
@@ -1,6 +1,7 @@
 //! This module provides a B<sup>e</sup>-Tree on top of the Data Management
 //! Layer.
 
+mod crdt_message_action;
 mod default_message_action;
 mod errors;
 mod imp;
@@ -11,8 +12,9 @@ mod pivot_key;
 use crate::cow_bytes::{CowBytes, SlicedCowBytes};
 
 pub use self::{
+    crdt_message_action::{GCounterMessageAction, OrSetMessageAction, OrSetTag},
     default_message_action::DefaultMessageAction,
-    imp::{Inner, Node, Tree},
+    imp::{Inner, Node, RangeIterator, Tree, TreeConfiguration},
     layer::TreeLayer,
     message_action::MessageAction,
 };
@@ -23,6 +25,9 @@ pub(crate) use self::{imp::NodeInfo, pivot_key::PivotKey};
 #[cfg(feature = "internal-api")]
 pub use self::{imp::NodeInfo, pivot_key::PivotKey};
 
+#[cfg(feature = "profiling-counters")]
+pub use self::imp::TreeCounters;
+
 type Key = CowBytes;
 type Value = SlicedCowBytes;
 
@@ -1,5 +1,6 @@
 #![allow(missing_docs, unused_doc_comments)]
 
+use crate::database::ErrorCategory;
 use std::sync::Arc;
 
 #[derive(thiserror::Error, Debug, Clone)]
@@ -16,6 +17,20 @@ pub enum VdevError {
     Spawn(Arc<futures::task::SpawnError>),
 }
 
+impl VdevError {
+    /// Returns the cause of this error, see [ErrorCategory].
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            VdevError::Io(_) => ErrorCategory::Io { vdev: None },
+            VdevError::Read(id) | VdevError::Write(id) => ErrorCategory::Io {
+                vdev: Some(id.clone()),
+            },
+            VdevError::Checksum(_) => ErrorCategory::ChecksumMismatch,
+            VdevError::Spawn(_) => ErrorCategory::Other,
+        }
+    }
+}
+
 impl From<std::io::Error> for VdevError {
     fn from(io_err: std::io::Error) -> Self {
         VdevError::Io(Arc::new(io_err))
@@ -11,40 +11,44 @@ use std::{
         fs::{FileExt, FileTypeExt},
         io::AsRawFd,
     },
-    sync::atomic::Ordering,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 /// `LeafVdev` that is backed by a file.
 pub struct File {
     file: fs::File,
     id: String,
-    size: Block<u64>,
+    size: AtomicU64,
     stats: AtomicStatistics,
 }
 
 impl File {
     /// Creates a new `File`.
     pub fn new(file: fs::File, id: String) -> io::Result<Self> {
-        let file_type = file.metadata()?.file_type();
-        let size = if file_type.is_file() {
-            Block::from_bytes(file.metadata()?.len())
-        } else if file_type.is_block_device() {
-            get_block_device_size(&file)?
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported file type: {file_type:?}"),
-            ));
-        };
+        let size = get_size(&file)?;
         Ok(File {
             file,
             id,
-            size,
+            size: AtomicU64::new(size.as_u64()),
             stats: Default::default(),
         })
     }
 }
 
+fn get_size(file: &fs::File) -> io::Result<Block<u64>> {
+    let file_type = file.metadata()?.file_type();
+    if file_type.is_file() {
+        Ok(Block::from_bytes(file.metadata()?.len()))
+    } else if file_type.is_block_device() {
+        get_block_device_size(file)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unsupported file type: {file_type:?}"),
+        ))
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn get_block_device_size(file: &fs::File) -> io::Result<Block<u64>> {
     const BLKGETSIZE64: c_ulong = 2148012658;
@@ -170,7 +174,25 @@ impl Vdev for File {
     }
 
     fn size(&self) -> Block<u64> {
-        self.size
+        Block(self.size.load(Ordering::Acquire))
+    }
+
+    fn refresh_size(&self) -> io::Result<Block<u64>> {
+        let size = get_size(&self.file)?;
+        let old = Block(self.size.load(Ordering::Acquire));
+        if size < old {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} shrunk from {} to {} blocks, shrinking is not supported",
+                    self.id,
+                    old.as_u64(),
+                    size.as_u64()
+                ),
+            ));
+        }
+        self.size.store(size.as_u64(), Ordering::Release);
+        Ok(size)
     }
 
     fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64> {
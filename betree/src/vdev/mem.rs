@@ -8,29 +8,214 @@ use crate::{
 };
 use async_trait::async_trait;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::{
-    io::{self, Write},
+    fs,
+    io::{self, Read, Write},
     ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    ptr::NonNull,
+    slice,
     sync::atomic::Ordering,
 };
 
+/// Size of the explicit hugepages a [Memory] vdev can be backed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HugePageSize {
+    /// 2 MiB pages.
+    #[serde(rename = "2M")]
+    Huge2M,
+    /// 1 GiB pages.
+    #[serde(rename = "1G")]
+    Huge1G,
+}
+
+impl HugePageSize {
+    /// Size of a single page in bytes.
+    pub fn bytes(&self) -> usize {
+        match self {
+            HugePageSize::Huge2M => 2 * 1024 * 1024,
+            HugePageSize::Huge1G => 1024 * 1024 * 1024,
+        }
+    }
+
+    fn mmap_flag(&self) -> libc::c_int {
+        match self {
+            HugePageSize::Huge2M => libc::MAP_HUGE_2MB,
+            HugePageSize::Huge1G => libc::MAP_HUGE_1GB,
+        }
+    }
+}
+
+/// Backing buffer of a [Memory] vdev.
+enum Region {
+    Heap(Box<[u8]>),
+    /// Anonymous mapping of `mapped` bytes, of which the first `len` are used.
+    Mapped {
+        ptr: NonNull<u8>,
+        len: usize,
+        mapped: usize,
+    },
+}
+
+// The mapping is exclusively owned by the region, like the heap buffer.
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    fn heap(size: usize) -> Self {
+        Region::Heap(vec![0; size].into_boxed_slice())
+    }
+
+    fn hugepages(size: usize, page_size: HugePageSize) -> io::Result<Self> {
+        let page = page_size.bytes();
+        let mapped = (size + page - 1) / page * page;
+        // Anonymous mappings are zero-filled, like the heap variant.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped.max(page),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | page_size.mmap_flag(),
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            return Err(io::Error::new(
+                err.kind(),
+                format!(
+                    "could not map {} bytes of {:?} hugepages, are enough pages reserved? ({})",
+                    mapped, page_size, err
+                ),
+            ));
+        }
+        Ok(Region::Mapped {
+            ptr: NonNull::new(ptr as *mut u8).expect("mmap returned a null mapping"),
+            len: size,
+            mapped: mapped.max(page),
+        })
+    }
+}
+
+impl Deref for Region {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Region::Heap(buf) => buf,
+            Region::Mapped { ptr, len, .. } => unsafe { slice::from_raw_parts(ptr.as_ptr(), *len) },
+        }
+    }
+}
+
+impl DerefMut for Region {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Region::Heap(buf) => buf,
+            Region::Mapped { ptr, len, .. } => unsafe {
+                slice::from_raw_parts_mut(ptr.as_ptr(), *len)
+            },
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        if let Region::Mapped { ptr, mapped, .. } = self {
+            unsafe { libc::munmap(ptr.as_ptr() as *mut libc::c_void, *mapped) };
+        }
+    }
+}
+
 /// `LeafVdev` that is backed by memory.
 pub struct Memory {
-    mem: RwLock<Box<[u8]>>,
+    mem: RwLock<Region>,
     id: String,
     size: Block<u64>,
     stats: AtomicStatistics,
+    persist: Option<PathBuf>,
 }
 
 impl Memory {
-    /// Creates a new `File`.
+    /// Creates a new `Memory` vdev of `size` bytes on the heap.
     pub fn new(size: usize, id: String) -> io::Result<Self> {
-        Ok(Memory {
-            mem: RwLock::new(vec![0; size].into_boxed_slice()),
+        Ok(Self::with_region(Region::heap(size), size, id))
+    }
+
+    /// Creates a new `Memory` vdev of `size` bytes backed by explicit
+    /// hugepages of the given size.  Fails if the system does not have
+    /// enough hugepages of this size reserved.
+    pub fn with_hugepages(size: usize, id: String, page_size: HugePageSize) -> io::Result<Self> {
+        Ok(Self::with_region(
+            Region::hugepages(size, page_size)?,
+            size,
+            id,
+        ))
+    }
+
+    fn with_region(region: Region, size: usize, id: String) -> Self {
+        Memory {
+            mem: RwLock::new(region),
             id,
             size: Block::from_bytes(size as u64),
             stats: Default::default(),
-        })
+            persist: None,
+        }
+    }
+
+    /// Restores the contents of this vdev from the snapshot at `path`, if
+    /// there is one, and writes a new snapshot there when the vdev is dropped
+    /// outside of a panic.  The snapshot copies all blocks as of the drop,
+    /// including those written since the last sync, but a database opened
+    /// from it only recovers the last completed sync, as after a crash.  If
+    /// the vdev is never dropped, e.g. as the process aborts, the previous
+    /// snapshot is kept.
+    pub fn persist_to(self, path: PathBuf) -> io::Result<Self> {
+        let mut vdev = self.restore_from(&path)?;
+        vdev.persist = Some(path);
+        Ok(vdev)
+    }
+
+    /// Restores the contents of this vdev from the snapshot at `path`, if
+    /// there is one, without writing it back, as for read-only openers.
+    pub fn restore_from(mut self, path: &Path) -> io::Result<Self> {
+        match fs::File::open(path) {
+            Ok(mut file) => {
+                let len = file.metadata()?.len();
+                let mem = self.mem.get_mut();
+                if len != mem.len() as u64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "snapshot {} has {} bytes, but the vdev has {}",
+                            path.display(),
+                            len,
+                            mem.len()
+                        ),
+                    ));
+                }
+                file.read_exact(mem)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(self)
+    }
+
+    fn write_snapshot(&self, path: &Path) -> io::Result<()> {
+        // Write to a temporary file next to it first, so that a crash while
+        // writing leaves the previous snapshot intact.  The full file name is
+        // kept, so that snapshots which only differ in their extension do not
+        // share it.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&self.mem.read())?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
     }
 
     fn slice(&self, size: usize, offset: usize) -> Result<impl Deref<Target = [u8]> + '_> {
@@ -92,6 +277,23 @@ impl Memory {
     }
 }
 
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Some(path) = &self.persist {
+            if std::thread::panicking() {
+                warn!("Not persisting memory vdev {} while panicking", self.id);
+            } else if let Err(e) = self.write_snapshot(path) {
+                error!(
+                    "Could not persist memory vdev {} to {}: {}",
+                    self.id,
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl VdevRead for Memory {
     async fn read<C: Checksum>(
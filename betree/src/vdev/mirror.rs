@@ -8,7 +8,7 @@ use futures::{
     prelude::*,
     stream::{FuturesOrdered, FuturesUnordered},
 };
-use std::sync::atomic::Ordering;
+use std::{io, sync::atomic::Ordering};
 
 /// This `vdev` will mirror all data to its child vdevs.
 pub struct Mirror<V> {
@@ -246,6 +246,13 @@ impl<V: Vdev> Vdev for Mirror<V> {
             f(vdev);
         }
     }
+
+    fn refresh_size(&self) -> io::Result<Block<u64>> {
+        for vdev in self.vdevs.iter() {
+            vdev.refresh_size()?;
+        }
+        Ok(self.size())
+    }
 }
 
 #[cfg(test)]
@@ -5,7 +5,10 @@
 use crate::{buffer::Buf, checksum::Checksum};
 use async_trait::async_trait;
 use enum_dispatch::enum_dispatch;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 /// Internal block size (4KiB)
 pub const BLOCK_SIZE: usize = 4096;
@@ -157,6 +160,13 @@ pub trait Vdev: Send + Sync {
     /// Returns the total size of this vdev.
     fn size(&self) -> Block<u64>;
 
+    /// Re-reads the size of the underlying devices, e.g. after a backing file
+    /// has been enlarged, and returns the new total size.  Vdevs can only
+    /// grow.
+    fn refresh_size(&self) -> io::Result<Block<u64>> {
+        Ok(self.size())
+    }
+
     /// Returns the effective free size which may be smaller due to parity data.
     fn effective_free_size(&self, free_size: Block<u64>) -> Block<u64>;
 
@@ -241,7 +251,7 @@ mod mirror;
 pub use self::mirror::Mirror;
 
 mod mem;
-pub use self::mem::Memory;
+pub use self::mem::{HugePageSize, Memory};
 
 #[cfg(feature = "nvm")]
 mod pmemfile;
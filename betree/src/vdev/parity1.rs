@@ -9,6 +9,7 @@ use futures::{
     stream::{FuturesOrdered, FuturesUnordered},
 };
 use std::{
+    io,
     iter::{once, repeat},
     sync::atomic::Ordering,
 };
@@ -89,6 +90,13 @@ impl<V: Vdev + VdevLeafRead + VdevLeafWrite> Vdev for Parity1<V> {
             f(vdev);
         }
     }
+
+    fn refresh_size(&self) -> io::Result<Block<u64>> {
+        for vdev in self.vdevs.iter() {
+            vdev.refresh_size()?;
+        }
+        Ok(self.size())
+    }
 }
 
 #[async_trait]
@@ -19,6 +19,7 @@ pub fn access_specific_config() -> DatabaseConfiguration {
                     })],
                     preferred_access_type:
                         betree_storage_stack::PreferredAccessType::RandomReadWrite,
+                    ..Default::default()
                 },
                 TierConfiguration {
                     top_level_vdevs: vec![Vdev::Leaf(LeafVdev::Memory {
@@ -26,6 +27,7 @@ pub fn access_specific_config() -> DatabaseConfiguration {
                     })],
                     preferred_access_type:
                         betree_storage_stack::PreferredAccessType::SequentialReadWrite,
+                    ..Default::default()
                 },
             ],
             ..Default::default()
@@ -71,6 +73,7 @@ fn migration_config_lfu(mode: LfuMode) -> DatabaseConfiguration {
             grace_period: std::time::Duration::from_millis(0),
             migration_threshold: [0.7; 4],
             update_period: std::time::Duration::from_secs(1),
+            cold_compression: None,
             policy_config: LfuConfig {
                 mode,
                 ..LfuConfig::default()
@@ -105,6 +108,7 @@ pub(crate) fn migration_config_rl() -> DatabaseConfiguration {
             grace_period: std::time::Duration::from_millis(0),
             migration_threshold: [0.7; 4],
             update_period: std::time::Duration::from_millis(100),
+            cold_compression: None,
             policy_config: None,
         })),
         default_storage_class: 1,
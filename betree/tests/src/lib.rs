@@ -7,10 +7,11 @@ mod util;
 
 use betree_storage_stack::{
     compression::CompressionConfiguration,
-    database::AccessMode,
+    database::{AccessMode, ErrorCategory},
     env_logger,
     object::{ObjectHandle, ObjectStore},
     storage_pool::{LeafVdev, TierConfiguration, Vdev},
+    tree::{NodeInfo, TreeConfiguration},
     Database, DatabaseConfiguration, StoragePoolConfiguration, StoragePreference,
 };
 use std::{
@@ -26,8 +27,12 @@ use insta::assert_json_snapshot;
 use serde_json::json;
 
 fn test_db(tiers: u32, mb_per_tier: u32) -> Database {
+    Database::build(test_config(tiers, mb_per_tier)).expect("Database initialisation failed")
+}
+
+fn test_config(tiers: u32, mb_per_tier: u32) -> DatabaseConfiguration {
     let tier_size = mb_per_tier as usize * 1024 * 1024;
-    let cfg = DatabaseConfiguration {
+    DatabaseConfiguration {
         storage: StoragePoolConfiguration {
             tiers: (0..tiers)
                 .map(|_| TierConfiguration {
@@ -40,9 +45,7 @@ fn test_db(tiers: u32, mb_per_tier: u32) -> Database {
         compression: CompressionConfiguration::None,
         access_mode: AccessMode::AlwaysCreateNew,
         ..Default::default()
-    };
-
-    Database::build(cfg).expect("Database initialisation failed")
+    }
 }
 
 // List of sizes for each tier is attached
@@ -392,7 +395,7 @@ fn write_overfull(#[case] tier_size_mb: u32, #[case] par_space: f32) {
     // NOTE: Test multiple times if the error persist as it should in this case.
     // In practice this allos us to treat errors by removing some data if necessary.
     let mut sync = || {
-        db.sync().expect_err(
+        let err = db.sync().expect_err(
             format!(
                 "Sync succeeded ({}MB of {}MB)",
                 tier_size_mb as f32 * par_space,
@@ -400,6 +403,10 @@ fn write_overfull(#[case] tier_size_mb: u32, #[case] par_space: f32) {
             )
             .as_str(),
         );
+        assert!(
+            matches!(err.category(), ErrorCategory::OutOfSpace { .. }),
+            "{err:?}"
+        );
     };
     sync();
     sync();
@@ -665,6 +672,745 @@ fn object_migrate_down(#[case] tier_size_mb: u32) {
     dbg!(db.free_space_tier());
 }
 
+#[rstest]
+fn read_your_writes() {
+    // Enough data to cause flushes from internal nodes while writing, every
+    // write has to be visible right away nonetheless.
+    let mut db = test_db(1, 256);
+    let ds = db.open_or_create_dataset(b"session").unwrap();
+    let other_handle = ds.clone();
+    let value = vec![7u8; 16 * 1024];
+    for i in 0..4096u32 {
+        let key = i.to_be_bytes();
+        ds.insert(&key[..], &value).unwrap();
+        assert_eq!(
+            other_handle.get(&key[..]).unwrap().as_deref(),
+            Some(&value[..])
+        );
+        if i % 512 == 0 {
+            ds.delete(&key[..]).unwrap();
+            assert!(other_handle.get(&key[..]).unwrap().is_none());
+        }
+    }
+    let count = other_handle
+        .range::<_, &[u8]>(..)
+        .unwrap()
+        .map(|res| res.unwrap())
+        .count();
+    assert_eq!(count, 4096 - 8);
+}
+
+#[rstest]
+#[case::single(1)]
+#[case::many(8)]
+fn par_range(#[case] shards: usize) {
+    let mut db = test_db(1, 256);
+    let ds = db.open_or_create_dataset(b"par_range").unwrap();
+    let value = vec![3u8; 8 * 1024];
+    for i in 0..8192u32 {
+        ds.insert(&i.to_be_bytes()[..], &value).unwrap();
+    }
+    db.sync().unwrap();
+
+    let start = 100u32.to_be_bytes();
+    let end = 8000u32.to_be_bytes();
+    let mut keys = ds
+        .par_range(&start[..]..&end[..], shards)
+        .unwrap()
+        .map(|res| res.unwrap().0)
+        .collect::<Vec<_>>();
+    keys.sort();
+    let expected = ds
+        .range(&start[..]..&end[..])
+        .unwrap()
+        .map(|res| res.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(keys.len(), 7900);
+    assert_eq!(keys, expected);
+}
+
+#[rstest]
+fn change_feed() {
+    use betree_storage_stack::database::Mutation;
+
+    let mut db = test_db(1, 32);
+    let ds = db.open_or_create_dataset(b"changes").unwrap();
+    ds.insert(&b"before"[..], b"x").unwrap();
+    let changes = ds.subscribe();
+    ds.insert(&b"a"[..], b"1").unwrap();
+    ds.delete(&b"b"[..]).unwrap();
+    ds.range_delete(&b"c"[..]..&b"d"[..]).unwrap();
+    // Nothing is delivered before a sync has committed the mutations.
+    assert!(changes.try_recv().is_err());
+
+    db.sync().unwrap();
+    let received = changes.try_iter().collect::<Vec<_>>();
+    assert_eq!(received.len(), 3);
+    assert!(received
+        .iter()
+        .all(|change| change.generation == received[0].generation));
+    match &received[0].mutation {
+        Mutation::Message { key, .. } => assert_eq!(&key[..], b"a"),
+        other => panic!("unexpected mutation {:?}", other),
+    }
+    match &received[1].mutation {
+        Mutation::Message { key, .. } => assert_eq!(&key[..], b"b"),
+        other => panic!("unexpected mutation {:?}", other),
+    }
+    assert!(matches!(received[2].mutation, Mutation::RangeDelete { .. }));
+
+    ds.insert(&b"e"[..], b"2").unwrap();
+    db.sync().unwrap();
+    let next = changes.try_recv().unwrap();
+    assert!(next.generation > received[0].generation);
+
+    db.close_dataset(ds).unwrap();
+    db.sync().unwrap();
+    assert!(changes.recv().is_err());
+}
+
+#[rstest]
+fn watch_prefix() {
+    use betree_storage_stack::database::WatchEvent;
+
+    let mut db = test_db(1, 32);
+    let ds = db.open_or_create_dataset(b"watched").unwrap();
+    let events = ds.watch(b"user/");
+    let other_handle = ds.clone();
+
+    other_handle.insert(&b"user/1"[..], b"a").unwrap();
+    ds.insert(&b"group/1"[..], b"b").unwrap();
+    ds.delete(&b"user/2"[..]).unwrap();
+    ds.range_delete(&b"a"[..]..&b"group/2"[..]).unwrap();
+    ds.range_delete(&b"u"[..]..&b"v"[..]).unwrap();
+
+    let received = events.try_iter().collect::<Vec<_>>();
+    assert_eq!(received.len(), 3);
+    assert!(matches!(&received[0], WatchEvent::Modified(key) if &key[..] == b"user/1"));
+    assert!(matches!(&received[1], WatchEvent::Modified(key) if &key[..] == b"user/2"));
+    assert_eq!(received[2], WatchEvent::RangeDeleted);
+
+    drop(events);
+    ds.insert(&b"user/3"[..], b"c").unwrap();
+}
+
+#[rstest]
+fn replication_catch_up() {
+    use betree_storage_stack::database::{ReplicationReceiver, ReplicationSender};
+    use std::net::TcpListener;
+
+    let mut db = test_db(1, 32);
+    let ds = db.open_or_create_dataset(b"replicated").unwrap();
+    ds.insert(&b"a"[..], b"1").unwrap();
+    let mut sender = ReplicationSender::new(ds.clone());
+
+    let replica = Database::build_threaded(test_config(1, 32)).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let receiver = ReplicationReceiver::new(replica.clone());
+    let server = std::thread::spawn(move || {
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().unwrap();
+            receiver.receive(stream).unwrap();
+        }
+    });
+
+    // The first connection copies the dataset, the second one only ships the
+    // changes since.
+    sender.catch_up(addr).unwrap();
+    ds.insert(&b"b"[..], b"2").unwrap();
+    ds.delete(&b"a"[..]).unwrap();
+    db.sync().unwrap();
+    sender.catch_up(addr).unwrap();
+    server.join().unwrap();
+
+    let mut replica = replica.write();
+    let copy = replica.open_dataset(b"replicated").unwrap();
+    let entries = copy
+        .range::<_, &[u8]>(..)
+        .unwrap()
+        .map(|res| {
+            let (key, value) = res.unwrap();
+            (key.to_vec(), value.to_vec())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(entries, vec![(b"b".to_vec(), b"2".to_vec())]);
+}
+
+#[rstest]
+fn replication_concurrent_senders() {
+    use betree_storage_stack::database::{ReplicationReceiver, ReplicationSender};
+    use std::{net::TcpListener, time::Duration};
+
+    let mut db = test_db(1, 32);
+    let first = db.open_or_create_dataset(b"first").unwrap();
+    let second = db.open_or_create_dataset(b"second").unwrap();
+    first.insert(&b"a"[..], b"1").unwrap();
+    second.insert(&b"b"[..], b"2").unwrap();
+    let mut follower = ReplicationSender::new(first.clone());
+    let mut sender = ReplicationSender::new(second.clone());
+
+    let replica = Database::build_threaded(test_config(1, 32)).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let receiver = ReplicationReceiver::new(replica.clone());
+    std::thread::spawn(move || receiver.serve(&listener));
+
+    // The follower never ends its connection, which must not keep the other
+    // sender from being served.
+    std::thread::spawn(move || follower.replicate(addr));
+    sender.catch_up(addr).unwrap();
+
+    // The connection of the sender is closed in the background.
+    let copy = loop {
+        match replica.write().open_dataset(b"second") {
+            Ok(copy) => break copy,
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    assert_eq!(&copy.get(&b"b"[..]).unwrap().unwrap()[..], b"2");
+}
+
+#[rstest]
+fn admin_interface() {
+    use betree_storage_stack::database::AdminServer;
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+    };
+
+    let db = Database::build_threaded(test_config(1, 64)).unwrap();
+    {
+        let mut db = db.write();
+        let ds = db.open_or_create_dataset(b"listed").unwrap();
+        ds.insert(&b"key"[..], b"value").unwrap();
+        db.close_dataset(ds).unwrap();
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = AdminServer::new(&db);
+    let requests = [
+        "GET /datasets HTTP/1.1\r\n\r\n",
+        "POST /sync HTTP/1.1\r\n\r\n",
+        "POST /scrub HTTP/1.1\r\n\r\n",
+        "PUT /migration/threshold HTTP/1.1\r\nContent-Length: 30\r\n\r\n{\"class\": 0, \"threshold\": 0.5}",
+        "DELETE /sync HTTP/1.1\r\n\r\n",
+    ];
+    let handle = std::thread::spawn(move || {
+        for _ in 0..requests.len() {
+            let (stream, _) = listener.accept().unwrap();
+            server.handle(stream).unwrap();
+        }
+    });
+
+    let responses = requests
+        .iter()
+        .map(|request| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let status = head.split(' ').nth(1).unwrap().to_owned();
+            (
+                status,
+                serde_json::from_str::<serde_json::Value>(body).unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    handle.join().unwrap();
+
+    assert_eq!(responses[0].0, "200");
+    assert!(responses[0]
+        .1
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|ds| ds["name"] == "listed"));
+    assert_eq!(responses[1], ("200".to_owned(), serde_json::Value::Null));
+    assert_eq!(responses[2].0, "200");
+    assert_eq!(responses[2].1["failures"], json!([]));
+    assert!(responses[2].1["objects"].as_u64().unwrap() > 0);
+    // No migration policy is running.
+    assert_eq!(responses[3].0, "409");
+    assert_eq!(responses[4].0, "405");
+
+    // The server neither keeps the database open nor outlives it.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = AdminServer::new(&db);
+    let serving = std::thread::spawn(move || server.serve(&listener).is_ok());
+    drop(db);
+    assert!(serving.join().unwrap());
+}
+
+#[rstest]
+fn amplification() {
+    let mut db = test_db(2, 64);
+    let ds = db.open_or_create_dataset(b"amplified").unwrap();
+    for idx in 0..1024u32 {
+        ds.insert(&idx.to_be_bytes()[..], &[idx as u8; 512])
+            .unwrap();
+    }
+    db.sync().unwrap();
+    assert_eq!(ds.range::<_, &[u8]>(..).unwrap().count(), 1024);
+    assert!(ds.get(&0u32.to_be_bytes()[..]).unwrap().is_some());
+
+    let report = db.amplification_report();
+    let amplified = report
+        .datasets
+        .values()
+        .find(|ds| ds.write.logical_written > 0)
+        .unwrap();
+    assert!(amplified.write.logical_written >= 1024 * 516);
+    assert!(amplified.write.physical_written > 0);
+    assert!(report.total.write.physical_written > amplified.write.physical_written);
+    assert_eq!(amplified.read.logical_read, 1024 * 516 + 512);
+    assert_eq!(
+        amplified.read.physical_read,
+        amplified.read.fetched + amplified.read.prefetched
+    );
+    assert!(amplified.read.prefetch_wasted <= amplified.read.prefetched);
+
+    assert_eq!(
+        report
+            .tiers
+            .iter()
+            .map(|tier| tier.write.physical_written)
+            .sum::<u64>(),
+        report.total.write.physical_written
+    );
+    assert_eq!(
+        report
+            .tiers
+            .iter()
+            .map(|tier| tier.read.physical_read)
+            .sum::<u64>(),
+        report.total.read.physical_read
+    );
+    assert!(report.tiers.iter().all(|tier| tier.write.logical_written
+        == report.total.write.logical_written
+        && tier.read.logical_read == report.total.read.logical_read));
+}
+
+#[rstest]
+fn cold_compression() {
+    use betree_storage_stack::{
+        compression::Zstd,
+        migration::{LfuConfig, MigrationConfig, MigrationPolicies},
+    };
+
+    let mut db = Database::build(DatabaseConfiguration {
+        migration_policy: Some(MigrationPolicies::Lfu(MigrationConfig {
+            cold_compression: Some(CompressionConfiguration::Zstd(Zstd { level: 19 })),
+            ..MigrationConfig::<LfuConfig>::default()
+        })),
+        ..test_config(2, 64)
+    })
+    .unwrap();
+    let hot = db.open_or_create_dataset(b"hot").unwrap();
+    let cold = db.open_or_create_dataset(b"cold").unwrap();
+    for idx in 0..1024u32 {
+        let key = idx.to_be_bytes();
+        hot.insert_with_pref(&key[..], &[0; 1024], StoragePreference::FASTEST)
+            .unwrap();
+        cold.insert_with_pref(&key[..], &[0; 1024], StoragePreference::FAST)
+            .unwrap();
+    }
+    db.sync().unwrap();
+
+    // Only the slowest tier is compressed.
+    let report = db.amplification_report();
+    assert!(report.tiers[0].write.physical_written > 1024 * 1024);
+    assert!(report.tiers[1].write.physical_written < 256 * 1024);
+    assert_eq!(
+        cold.get(&7u32.to_be_bytes()[..]).unwrap().unwrap()[..],
+        [0; 1024]
+    );
+}
+
+#[rstest]
+#[case(16 * 1024 * 1024)]
+#[case(0)]
+fn decompressed_cache(#[case] decompressed_cache_size: usize) {
+    use betree_storage_stack::compression::Zstd;
+
+    let mut db = Database::build(DatabaseConfiguration {
+        compression: CompressionConfiguration::Zstd(Zstd { level: 1 }),
+        decompressed_cache_size,
+        ..test_config(1, 64)
+    })
+    .unwrap();
+    let ds = db.open_or_create_dataset(b"compressed").unwrap();
+    for idx in 0..1024u32 {
+        ds.insert(&idx.to_be_bytes()[..], &[idx as u8; 512])
+            .unwrap();
+    }
+    db.sync().unwrap();
+
+    let mut physical_read = Vec::new();
+    for _ in 0..2 {
+        db.drop_cache().unwrap();
+        for idx in 0..1024u32 {
+            assert_eq!(
+                ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+                [idx as u8; 512]
+            );
+        }
+        physical_read.push(db.amplification_report().total.read.physical_read);
+    }
+    // Refetched nodes are served from the decompressed cache without reading
+    // them again.
+    if decompressed_cache_size > 0 {
+        assert_eq!(physical_read[0], physical_read[1]);
+    } else {
+        assert!(physical_read[0] < physical_read[1]);
+    }
+}
+
+#[rstest]
+fn unchecked_tier() {
+    use betree_storage_stack::storage_pool::ChecksumPolicy;
+
+    let mut cfg = test_config(2, 64);
+    cfg.storage.tiers[0].checksum_policy = ChecksumPolicy::Disabled;
+    // A small cache forces the nodes to be read back from the tiers.
+    cfg.cache_size = 512 * 1024;
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"unchecked").unwrap();
+    for (tier, pref) in [StoragePreference::FASTEST, StoragePreference::FAST]
+        .into_iter()
+        .enumerate()
+    {
+        for key in 0..512u32 {
+            let key = [&[tier as u8][..], &key.to_be_bytes()].concat();
+            ds.insert_with_pref(&key[..], &[key[4]; 4096], pref)
+                .unwrap();
+        }
+    }
+    db.sync().unwrap();
+
+    let mut entries = 0;
+    for entry in ds.range::<_, &[u8]>(..).unwrap() {
+        let (key, value) = entry.unwrap();
+        assert_eq!(&value[..], &[key[4]; 4096][..]);
+        entries += 1;
+    }
+    assert_eq!(entries, 1024);
+}
+
+#[rstest]
+fn transaction_commit() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"accounts").unwrap();
+    let other = db.open_or_create_dataset(b"log").unwrap();
+    ds.insert(&b"alice"[..], &[10]).unwrap();
+
+    let mut txn = db.transaction();
+    let balance = txn.get(&ds, &b"alice"[..]).unwrap().unwrap();
+    txn.insert(&ds, &b"alice"[..], &[balance[0] - 1]);
+    txn.insert(&other, &b"entry"[..], &[1]);
+    // Reads observe the writes of the transaction, but no one else does
+    assert_eq!(&txn.get(&ds, &b"alice"[..]).unwrap().unwrap()[..], &[9]);
+    assert_eq!(&ds.get(&b"alice"[..]).unwrap().unwrap()[..], &[10]);
+    txn.commit().unwrap();
+
+    assert_eq!(&ds.get(&b"alice"[..]).unwrap().unwrap()[..], &[9]);
+    assert_eq!(&other.get(&b"entry"[..]).unwrap().unwrap()[..], &[1]);
+}
+
+#[rstest]
+fn transaction_conflict() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"accounts").unwrap();
+    ds.insert(&b"alice"[..], &[10]).unwrap();
+
+    let mut first = db.transaction();
+    let mut second = db.transaction();
+    first.get(&ds, &b"alice"[..]).unwrap();
+    second.get(&ds, &b"alice"[..]).unwrap();
+    first.insert(&ds, &b"alice"[..], &[5]);
+    second.insert(&ds, &b"alice"[..], &[7]);
+    second.delete(&ds, &b"bob"[..]);
+    first.commit().unwrap();
+    assert!(matches!(
+        second.commit(),
+        Err(betree_storage_stack::database::Error::TransactionConflict)
+    ));
+    assert_eq!(&ds.get(&b"alice"[..]).unwrap().unwrap()[..], &[5]);
+}
+
+#[rstest]
+fn transaction_refused_write() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"accounts").unwrap();
+    ds.insert(&b"alice"[..], &[10]).unwrap();
+
+    // A write which would be refused fails the commit before any is applied.
+    let mut txn = db.transaction();
+    txn.insert(&ds, &b"alice"[..], &[5]);
+    txn.insert(&ds, &b"bob"[..], &vec![0; 1024 * 1024]);
+    assert!(matches!(
+        txn.commit(),
+        Err(betree_storage_stack::database::Error::MessageTooLarge)
+    ));
+    assert_eq!(&ds.get(&b"alice"[..]).unwrap().unwrap()[..], &[10]);
+    assert!(ds.get(&b"bob"[..]).unwrap().is_none());
+}
+
+#[rstest]
+fn tree_configuration() {
+    use betree_storage_stack::Error;
+    let mut db = test_db(1, 256);
+    db.create_dataset(b"write-heavy").unwrap();
+    let config = TreeConfiguration {
+        min_fanout: 2,
+        min_flush_size: 512 * 1024,
+        ..TreeConfiguration::default()
+    };
+    db.set_tree_configuration(b"write-heavy", config).unwrap();
+    assert!(matches!(
+        db.set_tree_configuration(
+            b"write-heavy",
+            TreeConfiguration {
+                min_fanout: 0,
+                ..config
+            }
+        ),
+        Err(Error::InvalidConfiguration(_))
+    ));
+    assert_eq!(
+        db.set_tree_configuration(
+            b"write-heavy",
+            TreeConfiguration {
+                min_fanout: 0,
+                ..config
+            }
+        )
+        .unwrap_err()
+        .category(),
+        ErrorCategory::Configuration
+    );
+
+    let ds = db.open_dataset(b"write-heavy").unwrap();
+    assert!(matches!(
+        db.set_tree_configuration(b"write-heavy", config),
+        Err(Error::InUse)
+    ));
+    let value = vec![42u8; 4096];
+    for idx in 0..4096u32 {
+        ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+    }
+    db.sync().unwrap();
+    for idx in 0..4096u32 {
+        assert_eq!(
+            &ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            &value[..]
+        );
+    }
+}
+
+#[rstest]
+fn node_events() {
+    use betree_storage_stack::data_management::NodeEventKind;
+    let mut db = test_db(1, 256);
+    let events = db.subscribe_node_events();
+    let ds = db.open_or_create_dataset(b"events").unwrap();
+    let value = vec![42u8; 1024];
+    for idx in 0..256u32 {
+        ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+    }
+    db.sync().unwrap();
+    let written: Vec<_> = events.try_iter().collect();
+    assert!(written
+        .iter()
+        .any(|event| event.kind == NodeEventKind::WrittenBack && event.storage_class == Some(0)));
+
+    db.drop_cache().unwrap();
+    let evicted: Vec<_> = events.try_iter().collect();
+    assert!(!evicted.is_empty());
+    assert!(evicted
+        .iter()
+        .all(|event| event.kind == NodeEventKind::Evicted));
+
+    // Reading the nodes in again and modifying them steals them.
+    ds.insert(&0u32.to_be_bytes()[..], &[1]).unwrap();
+    assert!(events
+        .try_iter()
+        .any(|event| event.kind == NodeEventKind::Stolen));
+}
+
+#[rstest]
+fn tree_events() {
+    use betree_storage_stack::data_management::NodeEventKind;
+    let mut db = test_db(1, 256);
+    let events = db.subscribe_node_events();
+    let ds = db.open_or_create_dataset(b"events").unwrap();
+    let value = vec![42u8; 32 * 1024];
+    for idx in 0..1024u32 {
+        ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+    }
+    db.sync().unwrap();
+    let restructured: Vec<_> = events
+        .try_iter()
+        .filter(|event| {
+            matches!(
+                event.kind,
+                NodeEventKind::Flushed | NodeEventKind::Split | NodeEventKind::Merged
+            )
+        })
+        .collect();
+    for kind in [NodeEventKind::Split, NodeEventKind::Flushed] {
+        assert!(restructured.iter().any(|event| event.kind == kind));
+    }
+    assert!(restructured.iter().all(|event| event.level.is_some()
+        && event.duration.is_some()
+        && event.storage_class.is_none()
+        && event.size > 0));
+    // The first split is the one of the root leaf.
+    assert!(restructured
+        .iter()
+        .any(|event| event.kind == NodeEventKind::Split && event.level == Some(0)));
+}
+
+#[rstest]
+fn drop_cache_for_dataset() {
+    use betree_storage_stack::data_management::NodeEventKind;
+    let mut db = test_db(1, 256);
+    let value = vec![42u8; 1024];
+    for name in [&b"first"[..], b"second"] {
+        let ds = db.open_or_create_dataset(name).unwrap();
+        for idx in 0..256u32 {
+            ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+        }
+        db.close_dataset(ds).unwrap();
+    }
+    db.sync().unwrap();
+
+    let events = db.subscribe_node_events();
+    db.drop_cache_for(b"first").unwrap();
+    let first: Vec<_> = events
+        .try_iter()
+        .filter(|event| event.kind == NodeEventKind::Evicted)
+        .collect();
+    assert!(!first.is_empty());
+    assert!(first.iter().all(|event| event.dataset == first[0].dataset));
+
+    db.drop_cache().unwrap();
+    let rest: Vec<_> = events
+        .try_iter()
+        .filter(|event| event.kind == NodeEventKind::Evicted)
+        .collect();
+    assert!(!rest.is_empty());
+    assert!(rest.iter().all(|event| event.dataset != first[0].dataset));
+}
+
+#[rstest]
+fn verify_cache() {
+    let mut db = test_db(1, 256);
+    let ds = db.open_or_create_dataset(b"verified").unwrap();
+    let value = vec![42u8; 4096];
+    for idx in 0..1024u32 {
+        ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+    }
+    let report = db.verify_cache();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(report.checked > 0);
+
+    db.sync().unwrap();
+    let report = db.verify_cache();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(report.checked > 0);
+}
+
+#[rstest]
+fn numa_sharded_cache() {
+    use betree_storage_stack::data_management::NumaSharding;
+    let mut db = Database::build(DatabaseConfiguration {
+        cache_size: 4 * TO_MEBIBYTE,
+        numa_sharding: NumaSharding::Fixed(3),
+        ..test_config(1, 256)
+    })
+    .unwrap();
+    let ds = db.open_or_create_dataset(b"sharded").unwrap();
+    let value = vec![42u8; 4096];
+    for idx in 0..2048u32 {
+        ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+    }
+    db.sync().unwrap();
+    db.drop_cache().unwrap();
+
+    // Fetched nodes are stolen from whichever shard holds them.
+    for idx in (0..2048u32).step_by(7) {
+        assert_eq!(
+            &ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            &value[..]
+        );
+        ds.insert(&idx.to_be_bytes()[..], &[idx as u8; 64]).unwrap();
+    }
+    let report = db.verify_cache();
+    assert!(report.is_ok(), "{:?}", report.problems);
+    db.sync().unwrap();
+    for idx in 0..2048u32 {
+        let expected = if idx % 7 == 0 {
+            vec![idx as u8; 64]
+        } else {
+            value.clone()
+        };
+        assert_eq!(
+            ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            expected[..]
+        );
+    }
+    let report = db.verify_cache();
+    assert!(report.is_ok(), "{:?}", report.problems);
+}
+
+#[rstest]
+fn negative_lookups() {
+    let mut db = test_db(1, 256);
+    let ds = db.open_or_create_dataset(b"misses").unwrap();
+    for _ in 0..2 {
+        assert!(ds.get(&b"absent"[..]).unwrap().is_none());
+    }
+    ds.insert(&b"absent"[..], &[1]).unwrap();
+    assert_eq!(&ds.get(&b"absent"[..]).unwrap().unwrap()[..], &[1]);
+
+    ds.delete(&b"absent"[..]).unwrap();
+    assert!(ds.get(&b"absent"[..]).unwrap().is_none());
+    ds.upsert(&b"absent"[..], &[2], 0).unwrap();
+    assert_eq!(&ds.get(&b"absent"[..]).unwrap().unwrap()[..], &[2]);
+}
+
+#[rstest]
+fn access_aware_splits() {
+    let mut db = test_db(1, 256);
+    db.create_dataset(b"hot-prefix").unwrap();
+    let config = TreeConfiguration {
+        access_aware_splits: true,
+        ..TreeConfiguration::default()
+    };
+    db.set_tree_configuration(b"hot-prefix", config).unwrap();
+
+    let ds = db.open_dataset(b"hot-prefix").unwrap();
+    let value = vec![42u8; 4096];
+    for idx in 0..4096u32 {
+        ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+        // Keep reading a small range of keys while the leaves are split.
+        let hot = idx % 16;
+        assert_eq!(
+            &ds.get(&hot.to_be_bytes()[..]).unwrap().unwrap()[..],
+            &value[..]
+        );
+    }
+    db.sync().unwrap();
+    for idx in 0..4096u32 {
+        assert_eq!(
+            &ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            &value[..]
+        );
+    }
+}
+
 #[rstest]
 #[case::a(32)]
 fn dataset_migrate_up(#[case] tier_size_mb: u32) {
@@ -765,6 +1511,57 @@ fn object_migrate_nochange(#[case] tier_size_mb: u32) {
     db.sync().unwrap();
 }
 
+#[rstest]
+fn object_migrate_partial_range() {
+    const CHUNK: usize = 128 * 1024;
+    let mut db = test_db(2, 64);
+    let os = db
+        .open_named_object_store(b"test", StoragePreference::FASTEST)
+        .unwrap();
+    let obj = os.open_or_create_object(b"foobar").unwrap();
+    let buf: Vec<u8> = (0..16 * CHUNK).map(|idx| (idx / CHUNK) as u8).collect();
+    obj.write_at(&buf, 0).unwrap();
+    db.sync().unwrap();
+
+    let prefs = || -> Vec<StoragePreference> {
+        let data = os.data_tree();
+        data.range::<_, &[u8]>(..)
+            .unwrap()
+            .map(|entry| {
+                let (key, _) = entry.unwrap();
+                data.test_storage_preference(&key[..]).unwrap().unwrap()
+            })
+            .collect()
+    };
+    let before = prefs();
+    assert_eq!(before.len(), 16);
+    assert!(before.iter().all(|pref| *pref != StoragePreference::FAST));
+
+    // Ends within chunk 7, which has to be migrated as well.
+    obj.migrate_range(
+        3 * CHUNK as u64,
+        4 * CHUNK as u64 + 100,
+        StoragePreference::FAST,
+    )
+    .unwrap();
+    // Ends at the start of chunk 12, which is not covered.
+    obj.migrate_range(2 * CHUNK as u64, 10 * CHUNK as u64, StoragePreference::FAST)
+        .unwrap();
+    db.sync().unwrap();
+
+    let after = prefs();
+    for (chunk, pref) in after.iter().enumerate() {
+        if (4..8).contains(&chunk) || (10..12).contains(&chunk) {
+            assert_eq!(*pref, StoragePreference::FAST, "chunk {chunk}");
+        } else {
+            assert_eq!(*pref, before[chunk], "chunk {chunk}");
+        }
+    }
+    let mut read = vec![0; buf.len()];
+    obj.read_at(&mut read, 0).unwrap();
+    assert!(read == buf);
+}
+
 #[rstest]
 fn space_accounting_smoke() {
     // env_logger::init();
@@ -787,6 +1584,27 @@ fn space_accounting_smoke() {
     // assert_eq!(after[0].free.as_u64(), expected_free_size_after);
 }
 
+#[rstest]
+fn space_report_smoke() {
+    let mut db = test_db(2, 64);
+    let os = db
+        .open_named_object_store(b"test", StoragePreference::FASTEST)
+        .unwrap();
+    let obj = os.open_or_create_object(b"foobar").unwrap();
+    obj.write_at(&vec![42u8; 2 * TO_MEBIBYTE], 0).unwrap();
+    db.sync().unwrap();
+
+    let report = db.space_report().unwrap();
+    let fastest = &report.tiers[0];
+    assert!(fastest.live.as_u64() > 0);
+    assert_eq!(fastest.dead.as_u64(), 0);
+    assert_eq!(
+        fastest.live.as_u64() + fastest.free.as_u64(),
+        fastest.total.as_u64()
+    );
+    assert!(fastest.free_extents.extents() > 0);
+}
+
 #[rstest]
 fn space_accounting_persistence(
     file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>,
@@ -820,54 +1638,372 @@ fn space_accounting_persistence(
     assert!(previous[0].free > after[0].free);
 }
 
-#[fixture]
-fn file_backed_config() -> RwLockWriteGuard<'static, DatabaseConfiguration> {
-    configs::file_backed()
-}
-
-fn migration_policy_smoke(cfg: DatabaseConfiguration) {
-    let shared_db = Database::build_threaded(cfg).unwrap();
-    let ds;
+#[rstest]
+fn allocation_checkpoint_persistence(
+    file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>,
+) {
+    let key = |idx: u32| idx.to_be_bytes();
     {
+        // Enough syncs of rewritten nodes to checkpoint the bitmaps
+        let cfg = file_backed_config.clone();
+        let shared_db = Database::build_threaded(cfg).unwrap();
         let mut db = shared_db.write();
-        ds = db
-            .open_named_object_store(b"test", StoragePreference::NONE)
-            .unwrap();
+        let ds = db.open_or_create_dataset(b"test").unwrap();
+        for round in 0..64u32 {
+            for idx in 0..256 {
+                ds.insert(&key(idx)[..], &round.to_le_bytes()).unwrap();
+            }
+            db.sync().unwrap();
+        }
+        db.close_dataset(ds).unwrap();
         db.sync().unwrap();
     }
-    let obj = ds.open_or_create_object(b"foobar").unwrap();
-    let buf = vec![42u8; 1024 * TO_MEBIBYTE];
-    dbg!(obj.write_at(&buf, 0).unwrap());
     {
+        let mut cfg = file_backed_config.clone();
+        cfg.access_mode = AccessMode::OpenIfExists;
+        let shared_db = Database::build_threaded(cfg).unwrap();
         let mut db = shared_db.write();
-        db.close_object_store(ds);
+        let ds = db.open_dataset(b"test").unwrap();
+        for idx in 0..256 {
+            ds.insert(&key(idx)[..], b"reopened").unwrap();
+        }
         db.sync().unwrap();
+        for idx in 0..256 {
+            assert_eq!(&ds.get(&key(idx)[..]).unwrap().unwrap()[..], b"reopened");
+        }
+        db.close_dataset(ds).unwrap();
     }
-    // Rest to let all messages be updated and synchronization proceed
-    std::thread::sleep(std::time::Duration::from_secs(5));
 }
 
 #[rstest]
-fn migration_policy_smoke_rl() {
-    // env_logger::init();
-    migration_policy_smoke(configs::migration_config_rl());
+fn ditto_metadata_fallback(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    use std::os::unix::fs::FileExt;
+    {
+        let mut db = Database::build(DatabaseConfiguration {
+            ditto_metadata: true,
+            ..file_backed_config.clone()
+        })
+        .unwrap();
+        let ds = db.open_or_create_dataset(b"test").unwrap();
+        ds.insert(&b"key"[..], b"value").unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::ReadOnly;
+    let root_ptr = Database::build(cfg.clone())
+        .unwrap()
+        .root_tree()
+        .inner()
+        .root_ptr()
+        .unwrap();
+    assert!(root_ptr.ditto_offset().is_some());
+
+    // Damage the first copy of the root node of the root tree.
+    let disk = std::fs::OpenOptions::new()
+        .write(true)
+        .open("test_disk_tier_fastest")
+        .unwrap();
+    let garbage = vec![0xA5; root_ptr.size().to_bytes() as usize];
+    disk.write_all_at(&garbage, root_ptr.offset().block_offset().to_bytes())
+        .unwrap();
+    drop(disk);
+
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_dataset(b"test").unwrap();
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"value");
+    let report = db.scrub().unwrap();
+    assert_eq!(report.failures.len(), 1);
 }
 
 #[rstest]
-fn migration_policy_smoke_lfu() {
-    // env_logger::init();
-    migration_policy_smoke(configs::migration_config_lfu_node());
+fn upgrade_rewrites_old_nodes() {
+    use betree_storage_stack::database::FORMAT_VERSION;
+
+    let mut db = test_db(1, 128);
+    db.set_format_version(10);
+    let ds = db.open_or_create_dataset(b"upgrade").unwrap();
+    for i in 0..2000u32 {
+        ds.insert(&i.to_be_bytes()[..], &[i as u8; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    assert_eq!(db.format_version(), 10);
+    let root_ptr = db.root_tree().inner().root_ptr().unwrap();
+    assert_eq!(root_ptr.format_version(), 0);
+
+    let rewritten = db.upgrade().unwrap();
+    assert!(rewritten > 2);
+    assert_eq!(db.format_version(), FORMAT_VERSION);
+    let root_ptr = db.root_tree().inner().root_ptr().unwrap();
+    assert_eq!(root_ptr.format_version(), FORMAT_VERSION);
+    // All nodes of older format versions have been rewritten.
+    assert_eq!(db.upgrade().unwrap(), 0);
+    for i in (0..2000u32).step_by(7) {
+        let value = ds.get(&i.to_be_bytes()[..]).unwrap().unwrap();
+        assert_eq!(&value[..], &[i as u8; 4096][..]);
+    }
+    assert!(db.scrub().unwrap().is_ok());
 }
 
 #[rstest]
-fn migration_policy_single_node() {
-    // env_logger::init();
-    let shared_db = Database::build_threaded(configs::migration_config_lfu_node()).unwrap();
-    let ds;
+fn torn_sync_fallback(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    use std::os::unix::fs::FileExt;
     {
-        let mut db = shared_db.write();
-        ds = db.open_or_create_dataset(b"test").unwrap();
-        db.sync().unwrap();
+        let mut db = Database::build(file_backed_config.clone()).unwrap();
+        for value in [&b"first"[..], b"second"] {
+            let ds = db.open_or_create_dataset(b"test").unwrap();
+            ds.insert(&b"key"[..], value).unwrap();
+            db.close_dataset(ds).unwrap();
+            db.sync().unwrap();
+        }
+    }
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::ReadOnly;
+    let root_ptr = Database::build(cfg.clone())
+        .unwrap()
+        .root_tree()
+        .inner()
+        .root_ptr()
+        .unwrap();
+
+    // Damage the root node of the root tree written by the last sync.
+    let disk = std::fs::OpenOptions::new()
+        .write(true)
+        .open("test_disk_tier_fastest")
+        .unwrap();
+    let garbage = vec![0xA5; root_ptr.size().to_bytes() as usize];
+    disk.write_all_at(&garbage, root_ptr.offset().block_offset().to_bytes())
+        .unwrap();
+    drop(disk);
+
+    let mut db = Database::build(cfg).unwrap();
+    let fallback_ptr = db.root_tree().inner().root_ptr().unwrap();
+    assert!(fallback_ptr.generation() < root_ptr.generation());
+    let ds = db.open_dataset(b"test").unwrap();
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"first");
+}
+
+#[rstest]
+fn range_tombstone_persistence(
+    file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>,
+) {
+    let key = |idx: u32| idx.to_be_bytes();
+    {
+        let cfg = file_backed_config.clone();
+        let shared_db = Database::build_threaded(cfg).unwrap();
+        let mut db = shared_db.write();
+        let ds = db.open_or_create_dataset(b"test").unwrap();
+        // Enough data for several leaves below an internal root node
+        for idx in 0..8192 {
+            ds.insert(&key(idx)[..], &[42; 1024]).unwrap();
+        }
+        db.sync().unwrap();
+        // The tombstone stays buffered in the internal node written now.
+        ds.range_delete(&key(100)[..]..&key(3000)[..]).unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+    {
+        let mut cfg = file_backed_config.clone();
+        cfg.access_mode = AccessMode::OpenIfExists;
+        let shared_db = Database::build_threaded(cfg).unwrap();
+        let mut db = shared_db.write();
+        let ds = db.open_dataset(b"test").unwrap();
+        assert!(ds.get(&key(99)[..]).unwrap().is_some());
+        assert!(ds.get(&key(2000)[..]).unwrap().is_none());
+        assert!(ds.get(&key(3000)[..]).unwrap().is_some());
+        assert_eq!(ds.range::<_, &[u8]>(..).unwrap().count(), 8192 - 2900);
+        db.close_dataset(ds).unwrap();
+    }
+}
+
+#[rstest]
+fn open_with_repair(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    {
+        let mut db = Database::build(file_backed_config.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"test").unwrap();
+        ds.insert(&b"key"[..], b"value").unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+    // A pool which can be opened regularly is left untouched.
+    let (mut db, report) = Database::open_with_repair(file_backed_config.clone()).unwrap();
+    assert_eq!(report.failure, None);
+    assert!(report.discarded_generations.is_empty());
+    let ds = db.open_dataset(b"test").unwrap();
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"value");
+    db.close_dataset(ds).unwrap();
+    drop(db);
+
+    let mut cfg = file_backed_config.clone();
+    cfg.cache_size = 0;
+    assert_eq!(
+        Database::open_with_repair(cfg).unwrap_err().category(),
+        ErrorCategory::Configuration
+    );
+}
+
+#[fixture]
+fn file_backed_config() -> RwLockWriteGuard<'static, DatabaseConfiguration> {
+    configs::file_backed()
+}
+
+#[rstest]
+fn sync_object_store(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    let mut db = Database::build(file_backed_config.clone()).unwrap();
+    let os = db
+        .open_named_object_store(b"store", StoragePreference::NONE)
+        .unwrap();
+    let ds = db.open_or_create_dataset(b"other").unwrap();
+    db.sync().unwrap();
+
+    let obj = os.create_object(b"obj").unwrap();
+    obj.write_at(b"synced", 0).unwrap();
+    ds.insert(&b"key"[..], b"pending").unwrap();
+    obj.fsync(&mut db).unwrap();
+    obj.write_at(b"again", 6).unwrap();
+    os.sync(&mut db).unwrap();
+
+    // Only the data and meta trees of the store have been committed.
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::ReadOnly;
+    let read = |cfg: &DatabaseConfiguration| {
+        let mut secondary = Database::build(cfg.clone()).unwrap();
+        let data = secondary.open_dataset(b"store\0data").unwrap();
+        let chunk = data.get(&[0u8; 8 + 4][..]).unwrap().map(|v| v.to_vec());
+        let other = secondary.open_dataset(b"other").unwrap();
+        let value = other.get(&b"key"[..]).unwrap().map(|v| v.to_vec());
+        (chunk, value)
+    };
+    assert_eq!(read(&cfg), (Some(b"syncedagain".to_vec()), None));
+
+    db.sync().unwrap();
+    assert_eq!(
+        read(&cfg),
+        (Some(b"syncedagain".to_vec()), Some(b"pending".to_vec()))
+    );
+}
+
+#[rstest]
+fn sync_object_store_space(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    let mut db = Database::build(file_backed_config.clone()).unwrap();
+    let os = db
+        .open_named_object_store(b"store", StoragePreference::NONE)
+        .unwrap();
+    let ds = db.open_or_create_dataset(b"other").unwrap();
+    ds.insert(&b"key"[..], &[1; 64 * 1024]).unwrap();
+    db.sync().unwrap();
+
+    // Releases the leaf of the other dataset, which is still referenced by
+    // its committed tree.
+    ds.insert(&b"key"[..], &[2; 64 * 1024]).unwrap();
+    let obj = os.create_object(b"obj").unwrap();
+    obj.write_at(b"synced", 0).unwrap();
+    os.sync(&mut db).unwrap();
+
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::ReadOnly;
+    let secondary = Database::build(cfg).unwrap();
+    assert!(
+        secondary.free_space_tier()[0].free.as_u64() + 16 <= db.free_space_tier()[0].free.as_u64()
+    );
+}
+
+#[rstest]
+fn snapshot_all() {
+    use betree_storage_stack::{Dataset, Error};
+    let mut db = test_db(1, 64);
+    let os = db
+        .open_named_object_store(b"store", StoragePreference::NONE)
+        .unwrap();
+    let obj = os.create_object(b"obj").unwrap();
+    obj.write_at(b"before", 0).unwrap();
+    let mut a = db.open_or_create_dataset(b"a").unwrap();
+    let mut b = db.open_or_create_dataset(b"b").unwrap();
+    a.insert(&b"key"[..], b"a1").unwrap();
+    b.insert(&b"key"[..], b"b1").unwrap();
+    db.snapshot_all(b"backup").unwrap();
+    assert!(matches!(
+        db.snapshot_all(b"backup"),
+        Err(Error::AlreadyExists)
+    ));
+
+    // `b` is not modified, so it shares its snapshot with the next one.
+    a.insert(&b"key"[..], b"a2").unwrap();
+    obj.write_at(b"after!", 0).unwrap();
+    db.snapshot_all(b"later").unwrap();
+    a.insert(&b"key"[..], b"a3").unwrap();
+    b.insert(&b"key"[..], b"b3").unwrap();
+    drop(obj);
+    db.close_object_store(os);
+    db.sync().unwrap();
+
+    let read = |db: &Database, ds: &mut Dataset, name: &[u8], key: &[u8]| {
+        let snapshot = db.open_snapshot(ds, name).unwrap();
+        snapshot.get(key).unwrap().map(|v| v.to_vec())
+    };
+    let mut data = db.open_dataset(b"store\0data").unwrap();
+    let chunk = [0u8; 8 + 4];
+    assert_eq!(read(&db, &mut a, b"backup", b"key"), Some(b"a1".to_vec()));
+    assert_eq!(read(&db, &mut a, b"later", b"key"), Some(b"a2".to_vec()));
+    assert_eq!(read(&db, &mut b, b"backup", b"key"), Some(b"b1".to_vec()));
+    assert_eq!(
+        read(&db, &mut data, b"backup", &chunk),
+        Some(b"before".to_vec())
+    );
+    assert_eq!(
+        read(&db, &mut data, b"later", &chunk),
+        Some(b"after!".to_vec())
+    );
+
+    db.delete_snapshot(&mut b, b"backup").unwrap();
+    assert_eq!(read(&db, &mut b, b"later", b"key"), Some(b"b1".to_vec()));
+    assert_eq!(&b.get(&b"key"[..]).unwrap().unwrap()[..], b"b3");
+}
+
+fn migration_policy_smoke(cfg: DatabaseConfiguration) {
+    let shared_db = Database::build_threaded(cfg).unwrap();
+    let ds;
+    {
+        let mut db = shared_db.write();
+        ds = db
+            .open_named_object_store(b"test", StoragePreference::NONE)
+            .unwrap();
+        db.sync().unwrap();
+    }
+    let obj = ds.open_or_create_object(b"foobar").unwrap();
+    let buf = vec![42u8; 1024 * TO_MEBIBYTE];
+    dbg!(obj.write_at(&buf, 0).unwrap());
+    {
+        let mut db = shared_db.write();
+        db.close_object_store(ds);
+        db.sync().unwrap();
+    }
+    // Rest to let all messages be updated and synchronization proceed
+    std::thread::sleep(std::time::Duration::from_secs(5));
+}
+
+#[rstest]
+fn migration_policy_smoke_rl() {
+    // env_logger::init();
+    migration_policy_smoke(configs::migration_config_rl());
+}
+
+#[rstest]
+fn migration_policy_smoke_lfu() {
+    // env_logger::init();
+    migration_policy_smoke(configs::migration_config_lfu_node());
+}
+
+#[rstest]
+fn migration_policy_single_node() {
+    // env_logger::init();
+    let shared_db = Database::build_threaded(configs::migration_config_lfu_node()).unwrap();
+    let ds;
+    {
+        let mut db = shared_db.write();
+        ds = db.open_or_create_dataset(b"test").unwrap();
+        db.sync().unwrap();
     }
 
     let sync = move || {
@@ -922,3 +2058,1536 @@ fn migration_policy_single_object() {
     let free = shared_db.read().free_space_tier();
     assert!(free[1].free > free[0].free);
 }
+
+#[rstest]
+fn migration_threshold_at_runtime() {
+    use betree_storage_stack::{
+        migration::{LfuConfig, LfuMode, MigrationConfig, MigrationPolicies},
+        storage_pool::NUM_STORAGE_CLASSES,
+    };
+    use std::time::{Duration, Instant};
+
+    let shared_db = Database::build_threaded(DatabaseConfiguration {
+        migration_policy: Some(MigrationPolicies::Lfu(MigrationConfig {
+            grace_period: Duration::from_millis(0),
+            migration_threshold: [0.9; 4],
+            update_period: Duration::from_millis(100),
+            cold_compression: None,
+            policy_config: LfuConfig {
+                mode: LfuMode::Object,
+                ..LfuConfig::default()
+            },
+        })),
+        ..test_config(2, 64)
+    })
+    .unwrap();
+    let os = shared_db
+        .write()
+        .open_named_object_store(b"store", StoragePreference::NONE)
+        .unwrap();
+    let obj = os
+        .open_or_create_object_with_pref(b"obj", StoragePreference::FASTEST)
+        .unwrap()
+        .0;
+    let mut buf = vec![42; 24 * TO_MEBIBYTE];
+    obj.write_at_with_pref(&buf, 0, StoragePreference::FASTEST)
+        .unwrap();
+    for _ in 0..3 {
+        obj.read_at(&mut buf, 0).unwrap();
+    }
+
+    let used = |class: usize| {
+        let info = shared_db.read().free_space_tier()[class];
+        info.total.to_bytes() - info.free.to_bytes()
+    };
+    // Syncs until `cond` holds, returns whether it did within some seconds.
+    let sync_until = |cond: &dyn Fn() -> bool| {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while Instant::now() < deadline {
+            shared_db.write().sync().unwrap();
+            if cond() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    };
+    let on_fastest = || used(0) >= 16 * TO_MEBIBYTE as u64 && used(1) < TO_MEBIBYTE as u64;
+
+    // The fastest tier is filled below its threshold, nothing moves.
+    assert!(sync_until(&on_fastest));
+    for _ in 0..10 {
+        std::thread::sleep(Duration::from_millis(100));
+        shared_db.write().sync().unwrap();
+        assert!(on_fastest());
+    }
+
+    assert!(shared_db.write().set_migration_threshold(0, 1.5).is_err());
+    assert!(shared_db
+        .write()
+        .set_migration_threshold(NUM_STORAGE_CLASSES as u8, 0.2)
+        .is_err());
+
+    // Below the new threshold the object is demoted without a restart.
+    shared_db.write().set_migration_threshold(0, 0.2).unwrap();
+    assert!(sync_until(&|| used(1) >= 16 * TO_MEBIBYTE as u64));
+
+    // Back at the old threshold, demotions stop and the object returns.
+    shared_db.write().set_migration_threshold(0, 0.9).unwrap();
+    assert!(sync_until(&on_fastest));
+    for _ in 0..10 {
+        std::thread::sleep(Duration::from_millis(100));
+        shared_db.write().sync().unwrap();
+        assert!(on_fastest());
+    }
+
+    obj.read_at(&mut buf, 0).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 42));
+    shared_db.write().close_object_store(os);
+}
+
+#[rstest]
+fn space_reserve() {
+    use betree_storage_stack::Error;
+    let mut db = Database::build(DatabaseConfiguration {
+        space_reserve_percent: 50,
+        ..test_config(1, 64)
+    })
+    .unwrap();
+    let ds = db.open_or_create_dataset(b"filling").unwrap();
+    let value = vec![42u8; 128 * 1024];
+    let mut inserted = 0u32;
+    let err = loop {
+        if let Err(err) = ds.insert(&inserted.to_be_bytes()[..], &value) {
+            break err;
+        }
+        inserted += 1;
+        if inserted % 64 == 0 {
+            db.sync().unwrap();
+        }
+    };
+    assert!(matches!(err, Error::OutOfSpace { class: 0 }), "{err:?}");
+
+    // Accepted data and deletions may still use the reserve.
+    db.sync().unwrap();
+    for idx in 0..inserted {
+        ds.delete(&idx.to_be_bytes()[..]).unwrap();
+    }
+    db.sync().unwrap();
+}
+
+#[rstest]
+fn grow_vdev() {
+    use betree_storage_stack::Error;
+    let path = env::temp_dir().join(format!("haura-grow-vdev-{}", std::process::id()));
+    let disk = std::fs::File::create(&path).unwrap();
+    disk.set_len(32 * TO_MEBIBYTE as u64).unwrap();
+    let mut db = Database::build(DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::FileWithOpts {
+                    path: path.clone(),
+                    direct: Some(false),
+                    failure_domain: None,
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        compression: CompressionConfiguration::None,
+        access_mode: AccessMode::AlwaysCreateNew,
+        space_reserve_percent: 50,
+        ..Default::default()
+    })
+    .unwrap();
+    let ds = db.open_or_create_dataset(b"growing").unwrap();
+    let value = vec![42u8; 128 * 1024];
+    let mut inserted = 0u32;
+    // Fills the disk until only its reserve is left.
+    let fill = |db: &mut Database, inserted: &mut u32| loop {
+        match ds.insert(&inserted.to_be_bytes()[..], &value) {
+            Ok(()) => *inserted += 1,
+            Err(err) => {
+                assert!(matches!(err, Error::OutOfSpace { class: 0 }), "{err:?}");
+                db.sync().unwrap();
+                break;
+            }
+        }
+        if *inserted % 32 == 0 {
+            db.sync().unwrap();
+        }
+    };
+    fill(&mut db, &mut inserted);
+    let before = db.free_space_tier()[0];
+    let filled = inserted;
+
+    // Nothing changed yet.
+    assert_eq!(db.grow_vdev(0, 0).unwrap(), before);
+    assert!(matches!(db.grow_vdev(0, 1), Err(Error::VdevNotFound(0, 1))));
+
+    disk.set_len(96 * TO_MEBIBYTE as u64).unwrap();
+    let grown = db.grow_vdev(0, 0).unwrap();
+    let added = 64 * TO_MEBIBYTE as u64;
+    assert_eq!(grown.total.to_bytes(), before.total.to_bytes() + added);
+    assert_eq!(grown.free.to_bytes(), before.free.to_bytes() + added);
+    assert_eq!(db.free_space_tier()[0], grown);
+
+    // The old region has no space beyond the reserve left, so the data
+    // accepted now has to be placed in the added one.
+    fill(&mut db, &mut inserted);
+    assert!(inserted > filled);
+    let after = db.free_space_tier()[0];
+    assert!(after.total.to_bytes() - after.free.to_bytes() > before.total.to_bytes());
+    for idx in 0..inserted {
+        assert_eq!(
+            &ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            &value[..]
+        );
+    }
+    drop(ds);
+    drop(db);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[rstest]
+fn backpressure() {
+    use betree_storage_stack::Error;
+    use std::time::{Duration, Instant};
+    let mut db = Database::build(DatabaseConfiguration {
+        cache_size: 32 * TO_MEBIBYTE,
+        dirty_watermark_percent: 5,
+        ..test_config(1, 256)
+    })
+    .unwrap();
+    let ds = db.open_or_create_dataset(b"ingest").unwrap();
+    let value = vec![42u8; 128 * 1024];
+    let mut inserted = 0u32;
+    let err = loop {
+        if let Err(err) = ds.try_insert(&inserted.to_be_bytes()[..], &value) {
+            break err;
+        }
+        inserted += 1;
+        assert!(inserted < 1024, "the dirty watermark has never been hit");
+    };
+    assert!(matches!(err, Error::Backpressure { .. }), "{err:?}");
+    assert_eq!(err.category(), ErrorCategory::Backpressure);
+    assert!(ds.get(&inserted.to_be_bytes()[..]).unwrap().is_none());
+
+    let deadline = Instant::now() + Duration::from_millis(20);
+    let err = ds
+        .insert_with_deadline(&b"late"[..], &value, StoragePreference::NONE, deadline)
+        .unwrap_err();
+    assert!(matches!(err, Error::Backpressure { .. }), "{err:?}");
+    assert!(Instant::now() >= deadline);
+
+    // Plain inserts are not held back, and a sync makes room again.
+    ds.insert(&b"plain"[..], &value).unwrap();
+    db.sync().unwrap();
+    ds.try_insert(&inserted.to_be_bytes()[..], &value).unwrap();
+    ds.insert_with_deadline(
+        &b"late"[..],
+        &value,
+        StoragePreference::NONE,
+        Instant::now(),
+    )
+    .unwrap();
+}
+
+#[rstest]
+#[case::roomy(16 * TO_MEBIBYTE)]
+#[case::evicting(TO_MEBIBYTE)]
+fn model_check(#[case] cache_size: usize) {
+    use betree_storage_stack::test_util::{rng, InMemoryBuilder, ModelChecker, Op};
+    for seed in 0..4 {
+        let mut db = InMemoryBuilder::new()
+            .cache_size(cache_size)
+            .build()
+            .unwrap();
+        let ops = Op::random_sequence(&mut rng(seed), 2048, 128);
+        let mut checker = ModelChecker::new(&mut db, b"model").unwrap();
+        if let Err(err) = checker.run(&ops) {
+            panic!("seed {seed}: {err}");
+        }
+    }
+}
+
+#[rstest]
+fn in_memory() {
+    let mut db = Database::in_memory(64 * TO_MEBIBYTE).unwrap();
+    let ds = db.open_or_create_dataset(b"ephemeral").unwrap();
+    ds.insert(&b"key"[..], b"value").unwrap();
+    db.sync().unwrap();
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"value");
+    assert_eq!(db.free_space_tier().len(), 4);
+    assert!(db.free_space_tier()[0].total.as_u64() > 0);
+}
+
+#[rstest]
+fn memory_vdev_snapshot() {
+    let dir = env::temp_dir().join(format!("haura-memory-snapshot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let snapshot = dir.join("pool.img");
+    let backup = dir.join("backup.img");
+    // Only shares the stem with the snapshot and is left alone.
+    let sibling = dir.join("pool.tmp");
+    std::fs::write(&sibling, b"unrelated").unwrap();
+    let config = |access_mode| DatabaseConfiguration {
+        storage: StoragePoolConfiguration {
+            tiers: vec![TierConfiguration {
+                top_level_vdevs: vec![Vdev::Leaf(LeafVdev::MemoryWithOpts {
+                    mem: 64 * TO_MEBIBYTE,
+                    hugepages: None,
+                    persist: Some(snapshot.clone()),
+                })],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        compression: CompressionConfiguration::None,
+        access_mode,
+        ..Default::default()
+    };
+
+    {
+        let mut db = Database::build(config(AccessMode::AlwaysCreateNew)).unwrap();
+        let ds = db.open_or_create_dataset(b"persisted").unwrap();
+        ds.insert(&b"first"[..], b"value").unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+    assert_eq!(
+        std::fs::metadata(&snapshot).unwrap().len(),
+        64 * TO_MEBIBYTE as u64
+    );
+    assert_eq!(std::fs::read(&sibling).unwrap(), b"unrelated");
+    assert!(!dir.join("pool.img.tmp").exists());
+
+    // Read-only openers restore the snapshot, but do not write one.
+    {
+        let mut db = Database::build(config(AccessMode::ReadOnly)).unwrap();
+        std::fs::rename(&snapshot, &backup).unwrap();
+        let ds = db.open_dataset(b"persisted").unwrap();
+        assert_eq!(&ds.get(&b"first"[..]).unwrap().unwrap()[..], b"value");
+    }
+    assert!(!snapshot.exists());
+    std::fs::rename(&backup, &snapshot).unwrap();
+
+    {
+        let mut db = Database::build(config(AccessMode::OpenIfExists)).unwrap();
+        let ds = db.open_dataset(b"persisted").unwrap();
+        assert_eq!(&ds.get(&b"first"[..]).unwrap().unwrap()[..], b"value");
+        ds.insert(&b"second"[..], b"value").unwrap();
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+    }
+    {
+        let mut db = Database::build(config(AccessMode::OpenIfExists)).unwrap();
+        let ds = db.open_dataset(b"persisted").unwrap();
+        for key in [&b"first"[..], b"second"] {
+            assert_eq!(&ds.get(key).unwrap().unwrap()[..], b"value");
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[rstest]
+fn virtual_clock() {
+    use betree_storage_stack::{
+        clock::{Clock, VirtualClock},
+        data_management::NodeEventKind,
+    };
+    use std::{sync::Arc, time::Duration};
+
+    let clock = Arc::new(VirtualClock::new());
+    let shared_db = Database::build_threaded(DatabaseConfiguration {
+        sync_interval_ms: Some(60_000),
+        object_gc_interval_ms: Some(600_000),
+        clock: Clock::Virtual(Arc::clone(&clock)),
+        ..DatabaseConfiguration::in_memory(64 * TO_MEBIBYTE)
+    })
+    .unwrap();
+    // The periodic sync and the reclamation of orphaned chunks.
+    clock.wait_for_sleepers(2);
+
+    let ds = shared_db.write().open_or_create_dataset(b"test").unwrap();
+    ds.insert(&b"key"[..], b"value").unwrap();
+    let events = shared_db.read().subscribe_node_events();
+
+    clock.advance(Duration::from_secs(59));
+    assert!(events.try_iter().next().is_none());
+    clock.advance(Duration::from_secs(1));
+    assert!(events
+        .try_iter()
+        .any(|event| event.kind == NodeEventKind::WrittenBack));
+    assert_eq!(clock.elapsed(), Duration::from_secs(60));
+    assert_eq!(clock.sleepers(), 2);
+
+    // The background threads return once the database is gone and must not
+    // hold back the clock afterwards.
+    drop(ds);
+    drop(shared_db);
+    clock.advance(Duration::from_secs(600));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(clock.sleepers(), 0);
+}
+
+#[rstest]
+fn metrics_csv_rotation() {
+    use betree_storage_stack::metrics::{
+        FileSink, MetricsConfiguration, MetricsFormat, MetricsSink,
+    };
+    use std::{fs, path::PathBuf};
+
+    let db = Database::in_memory(64 * TO_MEBIBYTE).unwrap();
+    let cfg = MetricsConfiguration {
+        enabled: true,
+        interval_ms: 1000,
+        output_path: PathBuf::from("test_metrics.csv"),
+        format: MetricsFormat::Csv,
+        rotate_size: Some(1),
+        rotated_files: 1,
+        sink: None,
+    };
+    let mut sink = FileSink::new(&cfg).unwrap();
+    for _ in 0..3 {
+        sink.record(&db.metrics_snapshot()).unwrap();
+    }
+
+    // Each snapshot exceeds the rotation size, so only the last two are kept.
+    for path in ["test_metrics.csv", "test_metrics.csv.1"] {
+        let content = fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let columns: Vec<&str> = lines[0].split(',').collect();
+        assert_eq!(columns.len(), lines[1].split(',').count());
+        assert!(columns.contains(&"epoch_ms"));
+        assert!(columns.contains(&"usage.0.free"));
+        fs::remove_file(path).unwrap();
+    }
+    assert!(!std::path::Path::new("test_metrics.csv.2").exists());
+}
+
+#[rstest]
+fn metrics_custom_sink() {
+    use betree_storage_stack::{
+        clock::{Clock, VirtualClock},
+        metrics::{MetricsConfiguration, MetricsFormat, MetricsSink, MetricsSnapshot, SharedSink},
+    };
+    use std::{
+        io,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    struct Counter(Arc<AtomicUsize>);
+    impl MetricsSink for Counter {
+        fn record(&mut self, _: &MetricsSnapshot) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    let clock = Arc::new(VirtualClock::new());
+    let records = Arc::new(AtomicUsize::new(0));
+    let _db = Database::build(DatabaseConfiguration {
+        metrics: Some(MetricsConfiguration {
+            enabled: true,
+            interval_ms: 1000,
+            output_path: "test_metrics_unused.jsonl".into(),
+            format: MetricsFormat::Jsonl,
+            rotate_size: None,
+            rotated_files: 0,
+            sink: Some(SharedSink::new(Counter(Arc::clone(&records)))),
+        }),
+        clock: Clock::Virtual(Arc::clone(&clock)),
+        ..DatabaseConfiguration::in_memory(64 * TO_MEBIBYTE)
+    })
+    .unwrap();
+    // The first snapshot is taken right away.
+    clock.wait_for_sleepers(1);
+    assert_eq!(records.load(Ordering::Relaxed), 1);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(records.load(Ordering::Relaxed), 2);
+    assert!(!Path::new("test_metrics_unused.jsonl").exists());
+}
+
+#[rstest]
+fn range_keys() {
+    let mut db = test_db(1, 128);
+    let ds = db.open_or_create_dataset(b"keys").unwrap();
+    for idx in 0u32..2000 {
+        ds.insert(&idx.to_be_bytes()[..], &[42; 512]).unwrap();
+    }
+    db.sync().unwrap();
+
+    // Buffered deletions, inserts and range tombstones have to be respected.
+    for idx in (0u32..2000).step_by(7) {
+        ds.delete(&idx.to_be_bytes()[..]).unwrap();
+    }
+    ds.insert(&5000u32.to_be_bytes()[..], b"new").unwrap();
+    ds.range_delete(&100u32.to_be_bytes()[..]..&300u32.to_be_bytes()[..])
+        .unwrap();
+
+    let keys: Vec<_> = ds
+        .range_keys::<_, &[u8]>(..)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let expected: Vec<_> = ds
+        .range::<_, &[u8]>(..)
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys, expected);
+    let contains = |idx: u32| keys.iter().any(|key| key[..] == idx.to_be_bytes());
+    assert!(contains(5000));
+    assert!(!contains(7));
+    assert!(!contains(200));
+
+    let from = 1000u32.to_be_bytes();
+    assert_eq!(
+        ds.range_keys(&from[..]..).unwrap().count(),
+        expected.iter().filter(|key| key[..] >= from[..]).count()
+    );
+}
+
+#[rstest]
+fn prefetch_range() {
+    let mut db = test_db(1, 256);
+    let ds = db.open_or_create_dataset(b"prefetch").unwrap();
+    let value = vec![42u8; 1024];
+    let count = 32 * 1024u32;
+    for idx in 0..count {
+        ds.insert(&idx.to_be_bytes()[..], &value).unwrap();
+    }
+    db.sync().unwrap();
+    db.drop_cache().unwrap();
+    let read = |db: &Database| db.amplification_report().total.read;
+
+    // An empty range prefetches nothing.
+    ds.test_prefetch_range(&1000u32.to_be_bytes(), &10u32.to_be_bytes())
+        .unwrap();
+    assert_eq!(read(&db).prefetched, 0);
+
+    // A range past the last key at most prefetches the last leaf.
+    ds.test_prefetch_range(&count.to_be_bytes(), &u32::MAX.to_be_bytes())
+        .unwrap();
+    let past_end = read(&db);
+    assert!(past_end.prefetched > 0);
+    assert!(past_end.prefetched < (count as usize * value.len()) as u64 / 2);
+    assert_eq!(past_end.prefetch_wasted, 0);
+    assert_eq!(ds.range(&count.to_be_bytes()[..]..).unwrap().count(), 0);
+
+    // Afterwards the whole range is served from the cache.
+    ds.test_prefetch_range(&0u32.to_be_bytes(), &(count - 1).to_be_bytes())
+        .unwrap();
+    let prefetched = read(&db);
+    assert!(prefetched.prefetched > past_end.prefetched);
+    assert_eq!(prefetched.prefetch_wasted, 0);
+    let mut entries = 0;
+    for (idx, entry) in ds.range::<_, &[u8]>(..).unwrap().enumerate() {
+        let (key, data) = entry.unwrap();
+        assert_eq!(&key[..], &(idx as u32).to_be_bytes());
+        assert_eq!(&data[..], &value[..]);
+        entries += 1;
+    }
+    assert_eq!(entries, count);
+    let after = read(&db);
+    assert_eq!(after.fetched, prefetched.fetched);
+    assert_eq!(after.prefetched, prefetched.prefetched);
+}
+
+#[rstest]
+fn conditional_writes() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"conditional").unwrap();
+    assert!(ds.insert_if_absent(&b"key"[..], b"first").unwrap());
+    assert!(!ds.insert_if_absent(&b"key"[..], b"second").unwrap());
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"first");
+    db.sync().unwrap();
+
+    assert!(!ds
+        .compare_and_swap(&b"key"[..], Some(&b"other"[..]), Some(&b"third"[..]))
+        .unwrap());
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"first");
+    assert!(ds
+        .compare_and_swap(&b"key"[..], Some(&b"first"[..]), Some(&b"third"[..]))
+        .unwrap());
+    ds.upsert(&b"key"[..], b"T", 0).unwrap();
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"Third");
+    db.sync().unwrap();
+
+    assert!(ds
+        .compare_and_swap(&b"key"[..], Some(&b"Third"[..]), None)
+        .unwrap());
+    assert!(ds
+        .compare_and_swap(&b"key"[..], None, Some(&b"fourth"[..]))
+        .unwrap());
+    db.sync().unwrap();
+    assert_eq!(&ds.get(&b"key"[..]).unwrap().unwrap()[..], b"fourth");
+}
+
+#[rstest]
+fn value_len() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"value_len").unwrap();
+    assert_eq!(ds.value_len(&b"key"[..]).unwrap(), None);
+    assert!(!ds.contains_key(&b"key"[..]).unwrap());
+
+    ds.insert(&b"key"[..], &[1; 300]).unwrap();
+    assert_eq!(ds.value_len(&b"key"[..]).unwrap(), Some(300));
+    db.sync().unwrap();
+    assert_eq!(ds.value_len(&b"key"[..]).unwrap(), Some(300));
+
+    ds.upsert(&b"key"[..], &[2; 100], 250).unwrap();
+    assert_eq!(ds.value_len(&b"key"[..]).unwrap(), Some(350));
+    assert!(!ds
+        .compare_and_swap(&b"key"[..], Some(&b"short"[..]), None)
+        .unwrap());
+    assert_eq!(ds.value_len(&b"key"[..]).unwrap(), Some(350));
+
+    ds.delete(&b"key"[..]).unwrap();
+    assert!(!ds.contains_key(&b"key"[..]).unwrap());
+    db.sync().unwrap();
+    assert!(!ds.contains_key(&b"key"[..]).unwrap());
+    assert!(ds.insert_if_absent(&b"key"[..], b"back").unwrap());
+    assert_eq!(ds.value_len(&b"key"[..]).unwrap(), Some(4));
+}
+
+#[rstest]
+fn content_hash() {
+    use betree_storage_stack::checksum::{Builder, Checksum, State, XxHash};
+    let hash = |data: &[u8]| {
+        let mut state = XxHash::builder().build();
+        state.ingest(data);
+        state.finish()
+    };
+
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"content_hash").unwrap();
+    assert_eq!(ds.content_hash(&b"key"[..]).unwrap(), None);
+    ds.insert(&b"key"[..], b"value").unwrap();
+    assert_eq!(ds.content_hash(&b"key"[..]).unwrap(), Some(hash(b"value")));
+
+    let os = db
+        .open_named_object_store(b"content_hash", StoragePreference::NONE)
+        .unwrap();
+    let mut contents = vec![0; 300 * 1024];
+    contents[200 * 1024..].fill(7);
+    let sparse = os.create_object(b"sparse").unwrap();
+    sparse
+        .write_at(&contents[200 * 1024..], 200 * 1024)
+        .unwrap();
+    let dense = os.create_object(b"dense").unwrap();
+    dense.write_at(&contents, 0).unwrap();
+    let expected = Some(hash(&contents));
+    assert_eq!(sparse.content_hash().unwrap(), expected);
+    assert_eq!(dense.content_hash().unwrap(), expected);
+    assert_eq!(
+        os.create_object(b"empty").unwrap().content_hash().unwrap(),
+        Some(hash(&[]))
+    );
+}
+
+#[rstest]
+fn object_deduplication() {
+    let mut db = test_db(1, 128);
+    let os = db
+        .open_named_object_store(b"dedup", StoragePreference::NONE)
+        .unwrap();
+    os.enable_deduplication().unwrap();
+    assert!(os.is_deduplicated());
+
+    let mut contents = vec![1; 200 * 1024];
+    contents[150 * 1024..].fill(2);
+    let a = os.create_object(b"a").unwrap();
+    a.write_at(&contents, 0).unwrap();
+    let b = os.create_object(b"b").unwrap();
+    b.write_at(&contents, 0).unwrap();
+    a.write_at(&[3], 10).unwrap();
+
+    let mut buf = vec![0; contents.len()];
+    b.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, contents);
+    a.read_at(&mut buf, 0).unwrap();
+    contents[10] = 3;
+    assert_eq!(buf, contents);
+    assert_eq!(os.collect_orphaned_chunks().unwrap(), 0);
+
+    b.delete().unwrap();
+    assert_eq!(os.collect_orphaned_chunks().unwrap(), 1);
+    a.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, contents);
+    assert!(os.fsck(false).unwrap().is_clean());
+    a.delete().unwrap();
+    assert_eq!(os.collect_orphaned_chunks().unwrap(), 2);
+    db.close_object_store(os);
+
+    let os = db
+        .open_named_object_store(b"dedup", StoragePreference::NONE)
+        .unwrap();
+    assert!(os.is_deduplicated());
+    let plain = db.open_object_store().unwrap();
+    plain
+        .create_object(b"c")
+        .unwrap()
+        .write_at(b"c", 0)
+        .unwrap();
+    assert!(matches!(
+        plain.enable_deduplication(),
+        Err(betree_storage_stack::database::Error::NotEmpty)
+    ));
+}
+
+#[rstest]
+#[case::deduplicated(true)]
+#[case::plain(false)]
+fn object_copy(#[case] deduplicated: bool) {
+    let mut db = test_db(1, 128);
+    let os = db
+        .open_named_object_store(b"copy", StoragePreference::NONE)
+        .unwrap();
+    if deduplicated {
+        os.enable_deduplication().unwrap();
+    }
+    let contents = vec![5; 300 * 1024];
+    let original = os.create_object(b"original").unwrap();
+    original.write_at(&contents, 0).unwrap();
+    original.set_metadata(b"tag", b"value").unwrap();
+
+    let copy = original.copy_to(b"copy").unwrap();
+    assert!(matches!(
+        original.copy_to(b"copy"),
+        Err(betree_storage_stack::database::Error::AlreadyExists)
+    ));
+    assert_eq!(copy.info().unwrap().unwrap().size, contents.len() as u64);
+    assert_eq!(&copy.get_metadata(b"tag").unwrap().unwrap()[..], b"value");
+
+    copy.write_at(&[6], 0).unwrap();
+    let mut buf = vec![0; contents.len()];
+    original.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, contents);
+    assert_eq!(os.collect_orphaned_chunks().unwrap(), 0);
+
+    original.delete().unwrap();
+    assert_eq!(os.collect_orphaned_chunks().unwrap(), 0);
+    copy.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf[0], 6);
+    assert_eq!(buf[1..], contents[1..]);
+    copy.delete().unwrap();
+    // Two contents were shared, the third is the modified first chunk of the
+    // copy.
+    let expected = if deduplicated { 3 } else { 0 };
+    assert_eq!(os.collect_orphaned_chunks().unwrap(), expected);
+}
+
+#[rstest]
+fn object_residency() {
+    let mut db = test_db(2, 64);
+    let os = db
+        .open_named_object_store(b"residency", StoragePreference::FASTEST)
+        .unwrap();
+    let mut obj = os.create_object(b"obj").unwrap();
+    obj.write_at(&vec![42; 4 * TO_MEBIBYTE], 0).unwrap();
+    let chunks = obj.residency(0).unwrap().chunks;
+    assert_eq!(chunks, 32);
+    db.sync().unwrap();
+
+    let on_fastest = os.objects_on_class(0).unwrap();
+    assert_eq!(on_fastest.len(), 1);
+    assert_eq!(&on_fastest[0].0[..], b"obj");
+    assert_eq!(on_fastest[0].1.fraction(), 1.0);
+    assert!(os.objects_on_class(1).unwrap().is_empty());
+
+    obj.migrate(StoragePreference::FAST).unwrap();
+    db.sync().unwrap();
+    // The leaf holding the object id counter stays on the fastest class.
+    let on_fast = obj.residency(1).unwrap();
+    assert!(on_fast.resident > 0);
+    assert_eq!(
+        on_fast.resident + obj.residency(0).unwrap().resident,
+        chunks
+    );
+    assert_eq!(os.objects_on_class(1).unwrap()[0].1, on_fast);
+}
+
+#[rstest]
+fn object_content_defined_chunking() {
+    use betree_storage_stack::{database::Error, object::ChunkingConfig};
+
+    let mut db = test_db(1, 128);
+    let os = db
+        .open_named_object_store(b"cdc", StoragePreference::NONE)
+        .unwrap();
+    let config = ChunkingConfig {
+        min_size: 4 * 1024,
+        avg_size: 16 * 1024,
+        max_size: 64 * 1024,
+    };
+    let invalid = ChunkingConfig {
+        min_size: 32 * 1024,
+        ..config
+    };
+    assert!(matches!(
+        os.enable_content_defined_chunking(invalid),
+        Err(Error::InvalidConfiguration(_))
+    ));
+    os.enable_content_defined_chunking(config).unwrap();
+    assert!(os.is_deduplicated());
+    assert_eq!(os.chunking(), Some(config));
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(42);
+    let mut contents = vec![0; 1024 * 1024];
+    rng.fill(&mut contents[..]);
+    let a = os.create_object(b"a").unwrap();
+    for (i, piece) in contents.chunks(100_000).enumerate() {
+        a.write_at(piece, i as u64 * 100_000).unwrap();
+    }
+    // The same contents behind a few inserted bytes share all but the first
+    // chunks.
+    let mut shifted = b"prefix".to_vec();
+    shifted.extend_from_slice(&contents);
+    let b = os.create_object(b"b").unwrap();
+    b.write_at(&shifted, 0).unwrap();
+
+    let mut buf = vec![0; contents.len()];
+    a.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, contents);
+    let mut buf = vec![0; shifted.len()];
+    b.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, shifted);
+    let mut buf = vec![0; 1000];
+    b.read_at(&mut buf, 500_006).unwrap();
+    assert_eq!(buf[..], contents[500_000..501_000]);
+    assert!(os.fsck(false).unwrap().is_clean());
+
+    a.delete().unwrap();
+    assert!(os.collect_orphaned_chunks().unwrap() <= 2);
+    b.read_at(&mut buf, 500_006).unwrap();
+    assert_eq!(buf[..], contents[500_000..501_000]);
+
+    // Overwriting the front only rechunks until the cuts resynchronize.
+    let mut patch = vec![0; 4096];
+    rng.fill(&mut patch[..]);
+    b.write_at(&patch, 1000).unwrap();
+    shifted[1000..1000 + patch.len()].copy_from_slice(&patch);
+    let mut buf = vec![0; shifted.len()];
+    b.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, shifted);
+    b.write_at(&patch, shifted.len() as u64 - 100).unwrap();
+    shifted.truncate(shifted.len() - 100);
+    shifted.extend_from_slice(&patch);
+    let mut buf = vec![0; shifted.len()];
+    b.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, shifted);
+    assert!(os.fsck(false).unwrap().is_clean());
+    db.close_object_store(os);
+
+    let os = db
+        .open_named_object_store(b"cdc", StoragePreference::NONE)
+        .unwrap();
+    assert_eq!(os.chunking(), Some(config));
+    let plain = db.open_object_store().unwrap();
+    plain
+        .create_object(b"c")
+        .unwrap()
+        .write_at(b"c", 0)
+        .unwrap();
+    assert!(matches!(
+        plain.enable_content_defined_chunking(config),
+        Err(Error::NotEmpty)
+    ));
+}
+
+#[rstest]
+#[case::deduplicated(false)]
+#[case::content_defined(true)]
+fn object_journaled_writes(#[case] content_defined: bool) {
+    use betree_storage_stack::object::ChunkingConfig;
+
+    let journaled = |os: &ObjectStore| {
+        os.data_tree()
+            .range::<_, &[u8]>(&b"\0journal"[..]..&b"\0journam"[..])
+            .unwrap()
+            .count()
+    };
+
+    let mut db = test_db(1, 128);
+    let os = db
+        .open_named_object_store(b"journal", StoragePreference::NONE)
+        .unwrap();
+    if content_defined {
+        os.enable_content_defined_chunking(ChunkingConfig::default())
+            .unwrap();
+    } else {
+        os.enable_deduplication().unwrap();
+    }
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(7);
+    let mut contents = vec![0; 512 * 1024];
+    rng.fill(&mut contents[..]);
+    let obj = os.create_object(b"obj").unwrap();
+    obj.write_at(&contents, 0).unwrap();
+
+    // Small writes are journaled instead of rewriting their chunks.
+    fn write(rng: &mut Xoshiro256PlusPlus, contents: &mut [u8], obj: &ObjectHandle) {
+        for _ in 0..200 {
+            let offset = rng.gen_range(0..contents.len() - 100);
+            let mut patch = vec![0; rng.gen_range(1..100)];
+            rng.fill(&mut patch[..]);
+            obj.write_at(&patch, offset as u64).unwrap();
+            contents[offset..offset + patch.len()].copy_from_slice(&patch);
+        }
+    }
+    write(&mut rng, &mut contents, &obj);
+    assert_eq!(journaled(&os), 200);
+
+    // The journal is recovered after reopening the store.
+    db.sync().unwrap();
+    db.close_object_store(os);
+    let os = db
+        .open_named_object_store(b"journal", StoragePreference::NONE)
+        .unwrap();
+    let obj = os.open_object(b"obj").unwrap().unwrap();
+    write(&mut rng, &mut contents, &obj);
+    assert_eq!(journaled(&os), 400);
+
+    let mut buf = vec![0; contents.len()];
+    obj.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, contents);
+    assert_eq!(journaled(&os), 0);
+
+    // Larger writes are applied directly after the journal.
+    write(&mut rng, &mut contents, &obj);
+    let patch = vec![1; 64 * 1024];
+    obj.write_at(&patch, 1000).unwrap();
+    contents[1000..1000 + patch.len()].copy_from_slice(&patch);
+    assert_eq!(journaled(&os), 0);
+    obj.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, contents);
+    assert!(os.fsck(false).unwrap().is_clean());
+}
+
+#[rstest]
+#[case::deduplicated(true)]
+#[case::plain(false)]
+fn object_store_usage(#[case] deduplicated: bool) {
+    use betree_storage_stack::{database::Error, object::Usage};
+
+    let mut db = test_db(1, 128);
+    let os = db
+        .open_named_object_store(b"usage", StoragePreference::NONE)
+        .unwrap();
+    if deduplicated {
+        os.enable_deduplication().unwrap();
+    }
+    let contents = vec![7; 300 * 1024];
+    for key in [&b"a/1"[..], b"a/2", b"b/1"] {
+        os.create_object(key)
+            .unwrap()
+            .write_at(&contents, 0)
+            .unwrap();
+    }
+    // A sparse object only stores its last chunk.
+    os.create_object(b"b/sparse")
+        .unwrap()
+        .write_at(&[1], 1024 * 1024 - 1)
+        .unwrap();
+
+    let stored = if deduplicated {
+        // The objects share a full and a partial chunk of sevens.
+        128 * 1024 + 44 * 1024 + 128 * 1024
+    } else {
+        3 * 300 * 1024 + 128 * 1024
+    };
+    assert_eq!(
+        os.usage().unwrap(),
+        Usage {
+            objects: 4,
+            logical_bytes: 3 * 300 * 1024 + 1024 * 1024,
+            chunk_bytes: stored,
+        }
+    );
+    let a = os.usage_of_prefix(b"a/").unwrap();
+    assert_eq!(a.objects, 2);
+    assert_eq!(a.logical_bytes, 2 * 300 * 1024);
+    let expected = if deduplicated { 128 + 44 } else { 600 };
+    assert_eq!(a.chunk_bytes, expected * 1024);
+    assert_eq!(os.usage_of_prefix(b"c/").unwrap(), Usage::default());
+
+    assert_eq!(os.quota(), None);
+    let used = 3 * 300 * 1024 + 1024 * 1024;
+    os.set_quota(Some(used + 1000)).unwrap();
+    assert_eq!(os.quota(), Some((used + 1000, used)));
+    let c = os.create_object(b"c/1").unwrap();
+    c.write_at(&[2; 1000], 0).unwrap();
+    // Overwriting does not grow the object.
+    c.write_at(&[3; 1000], 0).unwrap();
+    assert!(matches!(
+        c.write_at(&[4], 1000),
+        Err((0, Error::QuotaExceeded { .. }))
+    ));
+    c.delete().unwrap();
+    assert_eq!(os.quota(), Some((used + 1000, used)));
+    db.close_object_store(os);
+
+    let os = db
+        .open_named_object_store(b"usage", StoragePreference::NONE)
+        .unwrap();
+    assert_eq!(os.quota(), Some((used + 1000, used)));
+    let a = os.open_object(b"a/1").unwrap().unwrap();
+    assert!(matches!(
+        a.copy_to(b"a/3"),
+        Err(Error::QuotaExceeded { .. })
+    ));
+    os.set_quota(None).unwrap();
+    a.copy_to(b"a/3").unwrap();
+    assert_eq!(os.quota(), None);
+}
+
+#[rstest]
+fn replicate_hot_nodes() {
+    use betree_storage_stack::{
+        clock::{Clock, VirtualClock},
+        migration::{LfuConfig, LfuMode, MigrationConfig, MigrationPolicies},
+    };
+    use std::{sync::Arc, time::Duration};
+
+    let clock = Arc::new(VirtualClock::new());
+    let shared_db = Database::build_threaded(DatabaseConfiguration {
+        sync_interval_ms: Some(600_000),
+        object_gc_interval_ms: Some(600_000),
+        clock: Clock::Virtual(Arc::clone(&clock)),
+        migration_policy: Some(MigrationPolicies::Lfu(MigrationConfig {
+            policy_config: LfuConfig {
+                mode: LfuMode::Replicate,
+                ..LfuConfig::default()
+            },
+            ..MigrationConfig::default()
+        })),
+        ..test_config(2, 64)
+    })
+    .unwrap();
+    // The periodic sync, the reclamation of orphaned chunks and the policy.
+    clock.wait_for_sleepers(3);
+
+    let ds = shared_db.write().open_or_create_dataset(b"cold").unwrap();
+    for idx in 0..256u32 {
+        ds.insert_with_pref(
+            &idx.to_be_bytes()[..],
+            &[idx as u8; 1024],
+            StoragePreference::FAST,
+        )
+        .unwrap();
+    }
+    shared_db.write().sync().unwrap();
+
+    let slow_reads = || shared_db.read().amplification_report().tiers[1].read;
+    // Reads all keys from disk, returns the bytes fetched from the slow tier.
+    let read_all = || {
+        shared_db.read().drop_cache().unwrap();
+        let before = slow_reads().fetched;
+        for idx in 0..256u32 {
+            assert_eq!(
+                ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+                [idx as u8; 1024]
+            );
+        }
+        slow_reads().fetched - before
+    };
+
+    let uncached = read_all();
+    let free = shared_db.read().free_space_tier()[0].free;
+    // Past the grace period and the first update of the policy.
+    clock.advance(Duration::from_secs(330));
+
+    // The requested replicas are written on the next fetch and serve all
+    // later ones.
+    assert_eq!(read_all(), uncached);
+    assert!(shared_db.read().free_space_tier()[0].free < free);
+    assert!(read_all() < uncached);
+
+    shared_db.read().drop_replicas();
+    assert_eq!(shared_db.read().free_space_tier()[0].free, free);
+    assert_eq!(read_all(), uncached);
+}
+
+#[rstest]
+fn get_with_pref() {
+    let mut db = test_db(2, 64);
+    let ds = db.open_or_create_dataset(b"promote").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert_with_pref(&idx.to_be_bytes()[..], &[7; 4096], StoragePreference::FAST)
+            .unwrap();
+    }
+    db.sync().unwrap();
+    let slow_free = db.free_space_tier()[1].free.as_u64();
+
+    // The leaf of the key is moved on its next fetch.
+    db.drop_cache().unwrap();
+    let key = 42u32.to_be_bytes();
+    let value = ds
+        .get_with_pref(&key[..], StoragePreference::FASTEST)
+        .unwrap()
+        .unwrap();
+    assert_eq!(value[..], [7; 4096]);
+    db.drop_cache().unwrap();
+    assert_eq!(ds.get(&key[..]).unwrap().unwrap()[..], [7; 4096]);
+    db.sync().unwrap();
+
+    // Leaves hold at least one MiB.
+    assert!(db.free_space_tier()[1].free.as_u64() >= slow_free + 256);
+    db.drop_cache().unwrap();
+    assert_eq!(ds.get(&key[..]).unwrap().unwrap()[..], [7; 4096]);
+}
+
+fn leaf_entries(info: &NodeInfo) -> usize {
+    match info {
+        NodeInfo::Internal { children, .. } => {
+            children.iter().map(|c| leaf_entries(&c.child)).sum()
+        }
+        NodeInfo::Leaf { entry_count, .. } => *entry_count,
+        NodeInfo::Packed { entry_count, .. } => *entry_count as usize,
+    }
+}
+
+#[rstest]
+fn flush_buffers() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"flush").unwrap();
+    // Enough to split the root, but not to flush all of its buffers.
+    for idx in 0..8192u32 {
+        ds.insert(&idx.to_be_bytes()[..], &[idx as u8; 1024])
+            .unwrap();
+    }
+    assert!(leaf_entries(&ds.tree_dump().unwrap()) < 8192);
+
+    db.flush_buffers(&ds).unwrap();
+    assert_eq!(leaf_entries(&ds.tree_dump().unwrap()), 8192);
+    db.sync().unwrap();
+    db.drop_cache().unwrap();
+    for idx in 0..8192u32 {
+        assert_eq!(
+            ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            [idx as u8; 1024]
+        );
+    }
+    // Nothing is left to flush.
+    db.flush_buffers(&ds).unwrap();
+}
+
+#[rstest]
+fn flush_buffers_with_budget() {
+    use std::time::Duration;
+
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"flush").unwrap();
+    for idx in 0..8192u32 {
+        ds.insert(&idx.to_be_bytes()[..], &[idx as u8; 1024])
+            .unwrap();
+    }
+    // Starts over after every child buffer, but still flushes everything.
+    db.flush_buffers_with_budget(&ds, Some(Duration::ZERO))
+        .unwrap();
+    assert_eq!(leaf_entries(&ds.tree_dump().unwrap()), 8192);
+    for idx in 0..8192u32 {
+        assert_eq!(
+            ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap()[..],
+            [idx as u8; 1024]
+        );
+    }
+}
+
+fn leaf_sizes(info: &NodeInfo, sizes: &mut Vec<usize>) {
+    match info {
+        NodeInfo::Internal { children, .. } => {
+            for c in children {
+                leaf_sizes(&c.child, sizes);
+            }
+        }
+        NodeInfo::Leaf { entry_count, .. } => sizes.push(*entry_count),
+        NodeInfo::Packed { entry_count, .. } => sizes.push(*entry_count as usize),
+    }
+}
+
+#[rstest]
+fn merge_underfull_leaves() {
+    let mut db = test_db(1, 128);
+    let ds = db.open_or_create_dataset(b"merge").unwrap();
+    let count = 32 * 1024u32;
+    for idx in 0..count {
+        ds.insert(&idx.to_be_bytes()[..], &[idx as u8; 1024])
+            .unwrap();
+    }
+    db.flush_buffers(&ds).unwrap();
+    db.sync().unwrap();
+    let mut full = Vec::new();
+    leaf_sizes(&ds.tree_dump().unwrap(), &mut full);
+
+    // Thin out the first three quarters of the keys.  Flushing the deletions
+    // leaves the leaves of these keys far below the minimum leaf size.
+    let kept = |idx: u32| idx >= count / 4 * 3 || idx % 16 == 0;
+    for idx in (0..count).filter(|idx| !kept(*idx)) {
+        ds.delete(&idx.to_be_bytes()[..]).unwrap();
+    }
+    db.flush_buffers(&ds).unwrap();
+    let mut thinned = Vec::new();
+    leaf_sizes(&ds.tree_dump().unwrap(), &mut thinned);
+    assert_eq!(thinned.len(), full.len());
+    // Values are 1 KiB, leaves should hold at least 1 MiB.
+    let underfull = |sizes: &[usize]| sizes.iter().filter(|size| **size < 1024).count();
+    assert!(underfull(&thinned) > 1);
+
+    // The sync merges the adjacent under-full leaves.
+    db.sync().unwrap();
+    let mut merged = Vec::new();
+    leaf_sizes(&ds.tree_dump().unwrap(), &mut merged);
+    assert!(merged.len() < thinned.len());
+    assert!(underfull(&merged) < underfull(&thinned));
+    let remaining = (0..count).filter(|idx| kept(*idx)).count();
+    assert_eq!(merged.iter().sum::<usize>(), remaining);
+
+    db.drop_cache().unwrap();
+    for idx in 0..count {
+        let value = ds.get(&idx.to_be_bytes()[..]).unwrap();
+        if kept(idx) {
+            assert_eq!(value.unwrap()[..], [idx as u8; 1024]);
+        } else {
+            assert!(value.is_none());
+        }
+    }
+}
+
+#[rstest]
+fn profiling_counters() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"counters").unwrap();
+    ds.insert(&b"single"[..], &b"value"[..]).unwrap();
+    // A single node, found without any flush.
+    assert_eq!(ds.get(&b"single"[..]).unwrap().unwrap()[..], b"value"[..]);
+    let counters = ds.counters();
+    assert_eq!(counters.inserts, 1);
+    assert_eq!(counters.insert_flush_levels, 0);
+    assert_eq!(counters.gets, 1);
+    assert_eq!(counters.get_node_fetches, 1);
+
+    for idx in 0..8192u32 {
+        ds.insert(&idx.to_be_bytes()[..], &[idx as u8; 1024])
+            .unwrap();
+    }
+    ds.reset_counters();
+    assert_eq!(ds.counters(), Default::default());
+    for idx in 0..8192u32 {
+        ds.get(&idx.to_be_bytes()[..]).unwrap().unwrap();
+    }
+    let counters = ds.counters();
+    assert_eq!(counters.gets, 8192);
+    // The root has been split, so every get has to descend to a leaf.
+    assert!(counters.node_fetches_per_get() >= 2.0);
+    assert!(counters.get_replayed_messages > 0);
+
+    db.flush_buffers(&ds).unwrap();
+    ds.reset_counters();
+    ds.get(&0u32.to_be_bytes()[..]).unwrap().unwrap();
+    assert_eq!(ds.counters().get_replayed_messages, 0);
+}
+
+#[rstest]
+fn write_budget() {
+    use betree_storage_stack::clock::{Clock, VirtualClock};
+    use std::{sync::Arc, time::Duration};
+
+    let clock = Arc::new(VirtualClock::new());
+    let mut cfg = test_config(2, 64);
+    cfg.storage.tiers[0].write_budget = Some(4 * TO_MEBIBYTE as u64);
+    cfg.alloc_strategy[0] = vec![0, 1];
+    cfg.clock = Clock::Virtual(Arc::clone(&clock));
+    let mut db = Database::build(cfg).unwrap();
+    let ds = db.open_or_create_dataset(b"endurance").unwrap();
+
+    let write = |db: &mut Database, round: u32| {
+        for idx in 0..2048u32 {
+            let key = [round.to_be_bytes(), idx.to_be_bytes()].concat();
+            ds.insert(&key[..], &[7; 4096]).unwrap();
+        }
+        db.sync().unwrap();
+    };
+
+    // Once the budget is used up, the rest goes to the next tier.
+    let slow_free = db.free_space_tier()[1].free;
+    write(&mut db, 0);
+    assert!(db.write_budgets()[0].is_exhausted());
+    assert!(db.free_space_tier()[1].free < slow_free);
+
+    // The budget is renewed on the next day.
+    clock.advance(Duration::from_secs(24 * 60 * 60));
+    assert_eq!(db.write_budgets()[0].written, 0);
+    let fast_free = db.free_space_tier()[0].free;
+    write(&mut db, 1);
+    assert!(db.free_space_tier()[0].free < fast_free);
+}
+
+#[rstest]
+fn write_budget_persistence(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    use betree_storage_stack::clock::{Clock, VirtualClock};
+    use std::{sync::Arc, time::Duration};
+
+    let clock = Arc::new(VirtualClock::new());
+    let mut cfg = file_backed_config.clone();
+    cfg.storage.tiers[0].write_budget = Some(64 * TO_MEBIBYTE as u64);
+    cfg.clock = Clock::Virtual(Arc::clone(&clock));
+    let written = {
+        let mut db = Database::build(cfg.clone()).unwrap();
+        let ds = db.open_or_create_dataset(b"endurance").unwrap();
+        for idx in 0..256u32 {
+            ds.insert(&idx.to_be_bytes()[..], &[7; 4096]).unwrap();
+        }
+        db.close_dataset(ds).unwrap();
+        db.sync().unwrap();
+        db.write_budgets()[0].written
+    };
+
+    // The counters of the current day survive a restart, only the writes of
+    // the root tree during the last sync are missing.
+    cfg.access_mode = AccessMode::OpenIfExists;
+    {
+        let db = Database::build(cfg.clone()).unwrap();
+        let restored = db.write_budgets()[0].written;
+        assert!(restored >= 256 * 4096);
+        assert!(restored <= written);
+    }
+
+    // Counters of a past day are not restored.
+    clock.advance(Duration::from_secs(24 * 60 * 60));
+    let db = Database::build(cfg).unwrap();
+    assert_eq!(db.write_budgets()[0].written, 0);
+}
+
+#[rstest]
+fn apply_storage_hints() {
+    use betree_storage_stack::vdev::Block;
+
+    let mut db = test_db(2, 64);
+    let ds = db.open_or_create_dataset(b"hinted").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert_with_pref(&idx.to_be_bytes()[..], &[7; 4096], StoragePreference::FAST)
+            .unwrap();
+    }
+    db.sync().unwrap();
+    let slow_free = db.free_space_tier()[1].free.as_u64();
+
+    // Hints the leaf of the key to be moved, which is not read again.
+    let key = 42u32.to_be_bytes();
+    db.drop_cache().unwrap();
+    ds.get_with_pref(&key[..], StoragePreference::FASTEST)
+        .unwrap()
+        .unwrap();
+    assert_eq!(db.metrics_snapshot().pending_migration_hints, 1);
+
+    assert!(db.apply_storage_hints(Block(u64::MAX)).unwrap() > Block(0));
+    assert_eq!(db.metrics_snapshot().pending_migration_hints, 0);
+    assert!(db.free_space_tier()[1].free.as_u64() >= slow_free + 256);
+    db.drop_cache().unwrap();
+    assert_eq!(ds.get(&key[..]).unwrap().unwrap()[..], [7; 4096]);
+}
+
+#[test]
+fn storage_hints_expire() {
+    use betree_storage_stack::data_management::MAX_HINT_AGE;
+
+    let mut db = test_db(2, 64);
+    let ds = db.open_or_create_dataset(b"hinted").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert_with_pref(&idx.to_be_bytes()[..], &[7; 4096], StoragePreference::FAST)
+            .unwrap();
+    }
+    db.sync().unwrap();
+
+    db.drop_cache().unwrap();
+    ds.get_with_pref(&42u32.to_be_bytes()[..], StoragePreference::FASTEST)
+        .unwrap()
+        .unwrap();
+    assert_eq!(db.metrics_snapshot().pending_migration_hints, 1);
+
+    // Each sync starts a new generation, the hinted leaf stays clean.
+    for _ in 0..MAX_HINT_AGE {
+        db.sync().unwrap();
+    }
+    assert_eq!(db.metrics_snapshot().pending_migration_hints, 1);
+    db.sync().unwrap();
+    assert_eq!(db.metrics_snapshot().pending_migration_hints, 0);
+}
+
+#[test]
+fn watch_free_space() {
+    let mut db = test_db(2, 64);
+    let changes = db.watch_free_space(0.05);
+    let ds = db.open_or_create_dataset(b"filled").unwrap();
+    for idx in 0..2048u32 {
+        ds.insert_with_pref(
+            &idx.to_be_bytes()[..],
+            &[7; 4096],
+            StoragePreference::FASTEST,
+        )
+        .unwrap();
+    }
+    db.sync().unwrap();
+
+    let received: Vec<_> = changes.try_iter().collect();
+    assert!(!received.is_empty());
+    for change in received.iter() {
+        assert_eq!(change.class, 0);
+        assert!((change.current.percent_full() - change.previous.percent_full()).abs() >= 0.05);
+    }
+    let last = received.last().unwrap();
+    assert!(last.current.free < last.previous.free);
+    assert!(db.free_space_tier()[0].percent_full() - last.current.percent_full() < 0.05);
+}
+
+#[test]
+fn sync_returns_generation() {
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"generations").unwrap();
+    ds.insert(&b"key"[..], &b"value"[..]).unwrap();
+
+    let pending = db.current_generation();
+    let committed = db.sync().unwrap();
+    assert_eq!(committed, pending);
+    assert!(db.current_generation() > committed);
+    assert!(db.sync().unwrap() > committed);
+}
+
+#[test]
+fn savepoints() {
+    use betree_storage_stack::database::Error;
+
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"saved").unwrap();
+    for idx in 0..1024u32 {
+        ds.insert(&idx.to_be_bytes()[..], &[1; 4096]).unwrap();
+    }
+    let generation = db.create_savepoint(b"before").unwrap();
+    assert!(matches!(
+        db.create_savepoint(b"before"),
+        Err(Error::AlreadyExists)
+    ));
+    assert_eq!(
+        db.iter_savepoints().unwrap(),
+        vec![(Box::from(&b"before"[..]), generation)]
+    );
+
+    for idx in 0..1024u32 {
+        ds.insert(&idx.to_be_bytes()[..], &[2; 4096]).unwrap();
+    }
+    db.sync().unwrap();
+    let pinned_free = db.free_space_tier()[0].free;
+
+    let key = 42u32.to_be_bytes();
+    let view = db.open_savepoint(&ds, b"before").unwrap();
+    assert_eq!(view.get(&key[..]).unwrap().unwrap()[..], [1; 4096]);
+    assert_eq!(ds.get(&key[..]).unwrap().unwrap()[..], [2; 4096]);
+    assert!(matches!(db.release_savepoint(b"before"), Err(Error::InUse)));
+    drop(view);
+
+    db.release_savepoint(b"before").unwrap();
+    db.sync().unwrap();
+    assert!(db.iter_savepoints().unwrap().is_empty());
+    assert!(db.free_space_tier()[0].free.as_u64() >= pinned_free.as_u64() + 1024);
+}
+
+#[rstest]
+fn pool_in_use(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    use betree_storage_stack::database::Error;
+
+    let mut cfg = file_backed_config.clone();
+    cfg.host_id = Some(1);
+    let mut db = Database::build(cfg.clone()).unwrap();
+    db.sync().unwrap();
+    // The vdev is locked by the first instance.
+    cfg.access_mode = AccessMode::OpenIfExists;
+    assert!(Database::build(cfg.clone()).is_err());
+    drop(db);
+
+    let mut other = cfg.clone();
+    other.host_id = Some(2);
+    assert!(matches!(
+        Database::build(other.clone()),
+        Err(Error::PoolInUse { host_id: 1 })
+    ));
+    other.force_open = true;
+    Database::build(other).unwrap().export().unwrap();
+    // An exported pool can be opened by any host.
+    Database::build(cfg).unwrap();
+}
+
+#[rstest]
+fn read_only_secondary(file_backed_config: RwLockWriteGuard<'static, DatabaseConfiguration>) {
+    use betree_storage_stack::database::Error;
+
+    let mut db = Database::build(file_backed_config.clone()).unwrap();
+    let ds = db.open_or_create_dataset(b"test").unwrap();
+    ds.insert(&b"key"[..], b"synced").unwrap();
+    db.sync().unwrap();
+    ds.insert(&b"key"[..], b"pending").unwrap();
+
+    let mut cfg = file_backed_config.clone();
+    cfg.access_mode = AccessMode::ReadOnly;
+    let mut secondary = Database::build(cfg.clone()).unwrap();
+    assert!(secondary.is_read_only());
+    let view = secondary.open_dataset(b"test").unwrap();
+    assert_eq!(&view.get(&b"key"[..]).unwrap().unwrap()[..], b"synced");
+    assert_eq!(
+        view.insert(&b"key"[..], b"other").unwrap_err().category(),
+        ErrorCategory::ReadOnly
+    );
+    assert!(matches!(secondary.sync(), Err(Error::ReadOnly)));
+    secondary.close_dataset(view).unwrap();
+    drop(secondary);
+
+    db.sync().unwrap();
+    let mut secondary = Database::build(cfg).unwrap();
+    let view = secondary.open_dataset(b"test").unwrap();
+    assert_eq!(&view.get(&b"key"[..]).unwrap().unwrap()[..], b"pending");
+    secondary.close_dataset(view).unwrap();
+    db.close_dataset(ds).unwrap();
+}
+
+#[test]
+fn resumable_range() {
+    use betree_storage_stack::database::ResumeToken;
+
+    let mut db = test_db(1, 64);
+    let ds = db.open_or_create_dataset(b"pages").unwrap();
+    for idx in 0..1000u32 {
+        ds.insert(&idx.to_be_bytes()[..], &idx.to_le_bytes())
+            .unwrap();
+    }
+    let end = 900u32.to_be_bytes();
+
+    let mut keys = Vec::new();
+    let mut token = ds
+        .resumable_range(&[][..]..&end[..])
+        .unwrap()
+        .resume_token();
+    loop {
+        let mut page = ds.resume_range(&token).unwrap();
+        let before = keys.len();
+        for entry in page.by_ref().take(64) {
+            keys.push(entry.unwrap().0);
+        }
+        if keys.len() == before {
+            break;
+        }
+        // Tokens survive being handed out as bytes.
+        token = ResumeToken::from_bytes(&page.resume_token().to_bytes().unwrap()).unwrap();
+        // Pairs before the position are not returned again once changed.
+        ds.insert(&0u32.to_be_bytes()[..], b"changed").unwrap();
+    }
+    let expected: Vec<_> = (0..900u32).map(|idx| idx.to_be_bytes()).collect();
+    assert_eq!(keys.len(), expected.len());
+    assert!(keys.iter().zip(&expected).all(|(a, b)| &a[..] == b));
+    db.close_dataset(ds).unwrap();
+}
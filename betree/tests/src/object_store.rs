@@ -136,3 +136,88 @@ fn object_store_reinit_from_id() {
         .internal_open_object_store_with_id(osl.next().unwrap().unwrap())
         .unwrap();
 }
+
+#[test]
+fn object_store_access_time() {
+    let mut db = test_db(2, 64);
+    let mut os = db.open_object_store().unwrap();
+    let obj = os.create_object(b"untracked").unwrap();
+    obj.write_at(&[1, 2, 3], 0).unwrap();
+    obj.read_at(&mut [0; 3], 0).unwrap();
+    assert!(obj.info().unwrap().unwrap().atime.is_none());
+
+    os.set_access_time_interval(Some(std::time::Duration::ZERO));
+    let obj = os.open_object(b"untracked").unwrap().unwrap();
+    obj.read_at(&mut [0; 3], 0).unwrap();
+    let first = obj.info().unwrap().unwrap().atime.unwrap();
+    obj.read_at(&mut [0; 3], 0).unwrap();
+    assert!(obj.info().unwrap().unwrap().atime.unwrap() >= first);
+
+    // Within the interval the recorded access time is kept.
+    os.set_access_time_interval(Some(std::time::Duration::from_secs(3600)));
+    let obj = os.open_object(b"untracked").unwrap().unwrap();
+    let before = obj.info().unwrap().unwrap().atime;
+    obj.read_at(&mut [0; 3], 0).unwrap();
+    assert_eq!(obj.info().unwrap().unwrap().atime, before);
+}
+
+#[test]
+fn object_store_fsck() {
+    use betree_storage_stack::object::FsckProblem;
+
+    fn chunk_key(object_id: u64, chunk_id: u32) -> Vec<u8> {
+        let mut key = object_id.to_be_bytes().to_vec();
+        key.extend_from_slice(&chunk_id.to_be_bytes());
+        key
+    }
+
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"hewo").unwrap();
+    obj.write_at(&[1, 2, 3], 0).unwrap();
+    let id = obj.info().unwrap().unwrap().object_id.as_u64();
+    let report = os.fsck(false).unwrap();
+    assert!(report.is_clean());
+    assert_eq!((report.objects, report.chunks), (1, 1));
+
+    // Data beyond the end of the object and chunks without an object
+    os.data_tree().insert(chunk_key(id, 1), &[4; 8]).unwrap();
+    os.data_tree().insert(chunk_key(id + 100, 0), &[5]).unwrap();
+    let report = os.fsck(true).unwrap();
+    assert_eq!(report.problems.len(), 2);
+    assert!(report
+        .problems
+        .iter()
+        .any(|p| matches!(p, FsckProblem::OrphanedChunks { chunks: 1, .. })));
+    assert!(report
+        .problems
+        .iter()
+        .any(|p| matches!(p, FsckProblem::SizeMismatch { size: 3, .. })));
+
+    assert!(os.fsck(false).unwrap().is_clean());
+    let mut buf = vec![0; 3];
+    obj.read_at(&mut buf, 0).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+}
+
+#[test]
+fn object_store_collect_orphaned_chunks() {
+    let mut db = test_db(2, 64);
+    let os = db.open_object_store().unwrap();
+    let obj = os.open_or_create_object(b"hewo").unwrap();
+    obj.write_at(&[1, 2, 3], 0).unwrap();
+    let orphan = obj.info().unwrap().unwrap().object_id.as_u64() + 100;
+    let mut key = orphan.to_be_bytes().to_vec();
+    key.extend_from_slice(&0u32.to_be_bytes());
+    os.data_tree().insert(key, &[5]).unwrap();
+
+    // Open stores are left alone
+    assert_eq!(db.collect_orphaned_chunks().unwrap(), 0);
+    db.close_object_store(os);
+    assert_eq!(db.collect_orphaned_chunks().unwrap(), 1);
+
+    let os = db.open_object_store().unwrap();
+    let report = os.fsck(false).unwrap();
+    assert!(report.is_clean());
+    assert_eq!(report.chunks, 1);
+}